@@ -0,0 +1,75 @@
+//! Throughput of the chunk path for each cipher and MAC across block sizes: `cargo bench`, or `cargo bench -- encrypt/XChaCha20` to select some of them.
+
+use std::io;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use doby::{
+    crypto::{CipherAlgorithm, DobyCipher, EncryptionParams, MacAlgorithm},
+    decrypt,
+    encrypt,
+    read_header,
+};
+
+const PLAINTEXT_LEN: usize = 4 << 20;
+const BLOCK_SIZES: [usize; 4] = [4096, 65536, 1 << 20, 4 << 20];
+const ALGORITHMS: [(CipherAlgorithm, MacAlgorithm); 6] = [
+    (CipherAlgorithm::AesCtr, MacAlgorithm::Blake2b),
+    (CipherAlgorithm::AesCtr, MacAlgorithm::Blake3),
+    (CipherAlgorithm::XChaCha20, MacAlgorithm::Blake2b),
+    (CipherAlgorithm::XChaCha20, MacAlgorithm::Blake3),
+    (CipherAlgorithm::AesGcm, MacAlgorithm::Blake2b),
+    (CipherAlgorithm::XChaCha20Poly1305, MacAlgorithm::Blake2b),
+];
+const KEY: [u8; 32] = [0; 32];
+
+fn name(cipher: CipherAlgorithm, mac: MacAlgorithm) -> String {
+    if cipher.is_aead() {
+        cipher.to_string()
+    } else {
+        format!("{}-{}", cipher, mac)
+    }
+}
+
+fn params(cipher: CipherAlgorithm, mac: MacAlgorithm) -> EncryptionParams {
+    let mut params = EncryptionParams::with_raw_key(cipher);
+    params.mac = mac;
+    params
+}
+
+fn encryption(c: &mut Criterion) {
+    let plaintext = vec![42; PLAINTEXT_LEN];
+    let mut group = c.benchmark_group("encrypt");
+    group.throughput(Throughput::Bytes(PLAINTEXT_LEN as u64));
+    for (cipher, mac) in ALGORITHMS {
+        let params = params(cipher, mac);
+        for block_size in BLOCK_SIZES {
+            group.bench_with_input(BenchmarkId::new(name(cipher, mac), block_size), &block_size, |b, &block_size| b.iter(|| {
+                let cipher = DobyCipher::with_master_key(&KEY, &params).unwrap();
+                encrypt(&mut plaintext.as_slice(), &mut io::sink(), &params, cipher, block_size, None).unwrap();
+            }));
+        }
+    }
+    group.finish();
+}
+
+fn decryption(c: &mut Criterion) {
+    let plaintext = vec![42; PLAINTEXT_LEN];
+    let mut group = c.benchmark_group("decrypt");
+    group.throughput(Throughput::Bytes(PLAINTEXT_LEN as u64));
+    for (cipher, mac) in ALGORITHMS {
+        let params = params(cipher, mac);
+        let mut ciphertext = Vec::new();
+        encrypt(&mut plaintext.as_slice(), &mut ciphertext, &params, DobyCipher::with_master_key(&KEY, &params).unwrap(), 65536, None).unwrap();
+        let mut reader = ciphertext.as_slice();
+        read_header(&mut reader).unwrap();
+        for block_size in BLOCK_SIZES {
+            group.bench_with_input(BenchmarkId::new(name(cipher, mac), block_size), &block_size, |b, &block_size| b.iter(|| {
+                let cipher = DobyCipher::with_master_key(&KEY, &params).unwrap();
+                decrypt(&mut &reader[..], &mut io::sink(), cipher, block_size).unwrap();
+            }));
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encryption, decryption);
+criterion_main!(benches);
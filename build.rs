@@ -0,0 +1,21 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "capi")]
+    generate_header();
+}
+
+//include/doby.h is kept in the repository, so that building the C API doesn't require cbindgen elsewhere
+#[cfg(feature = "capi")]
+fn generate_header() {
+    use std::{env, path::PathBuf};
+    println!("cargo:rerun-if-changed=src/capi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(crate_dir.join("src/capi.rs"))
+        .generate()
+        .expect("can't generate the C header")
+        .write_to_file(crate_dir.join("include/doby.h"));
+}
@@ -0,0 +1,9 @@
+#![no_main]
+use std::io::Read;
+use libfuzzer_sys::fuzz_target;
+use doby::{ArmorReader, is_armored};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = is_armored(data);
+    let _ = ArmorReader::new(data).read_to_end(&mut Vec::new());
+});
@@ -0,0 +1,29 @@
+#![no_main]
+use std::convert::TryFrom;
+use libfuzzer_sys::fuzz_target;
+use doby::crypto::{CipherAlgorithm, DobyCipher, EncryptionParams, MacAlgorithm};
+
+//the first byte selects the cipher and MAC, the second one the size of the decryption buffer, the rest is the ciphertext
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let cipher = match CipherAlgorithm::try_from(data[0] & 0x03) {
+        Ok(cipher) => cipher,
+        Err(_) => return,
+    };
+    let mut params = EncryptionParams::with_raw_key(cipher);
+    if data[0] & 0x04 != 0 {
+        params.mac = MacAlgorithm::Blake3;
+    }
+    let mut cipher = DobyCipher::with_master_key(&[0; 32], &params).unwrap();
+    let mut buff = vec![0; data[1] as usize + 1];
+    let mut reader = &data[2..];
+    loop {
+        match cipher.decrypt_chunk(&mut reader, &mut buff) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => assert!(n <= buff.len()),
+        }
+    }
+    let _ = cipher.verify();
+});
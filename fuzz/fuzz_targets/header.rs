@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use doby::crypto::EncryptionParams;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(params) = EncryptionParams::read(&mut &data[..]) {
+        //whatever was accepted must be written back as it was read
+        let mut encoded = Vec::new();
+        params.write(&mut encoded).unwrap();
+        assert_eq!(EncryptionParams::read(&mut encoded.as_slice()).unwrap(), params);
+    }
+});
@@ -0,0 +1,150 @@
+/* Generated by cbindgen from src/capi.rs when building with the capi feature: don't edit. */
+
+#ifndef DOBY_H
+#define DOBY_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Result of the functions of the C API.
+typedef enum DobyStatus {
+  DOBY_STATUS_OK = 0,
+  // A required pointer is NULL.
+  DOBY_STATUS_INVALID_ARGUMENT = 1,
+  // I/O error, including failures of the read and write callbacks.
+  DOBY_STATUS_IO = 2,
+  // The input isn't in doby format, or was written by a newer version.
+  DOBY_STATUS_UNKNOWN_FORMAT = 3,
+  DOBY_STATUS_WRONG_PASSWORD = 4,
+  // The ciphertext has been corrupted or altered. Plaintext already returned must be discarded.
+  DOBY_STATUS_AUTHENTICATION = 5,
+  DOBY_STATUS_OTHER = 6,
+} DobyStatus;
+
+// Streaming decryption context, reading the ciphertext through a callback.
+typedef struct DobyDecryptor DobyDecryptor;
+
+// Streaming encryption context, writing the ciphertext through a callback.
+typedef struct DobyEncryptor DobyEncryptor;
+
+// Writes `len` bytes of `data` and returns 0 on success.
+typedef int (*DobyWriteFn)(void *user_data, const uint8_t *data, size_t len);
+
+// Reads up to `len` bytes into `buffer` and returns how many were read, 0 at the end of the input, or a negative value on error.
+typedef ptrdiff_t (*DobyReadFn)(void *user_data, uint8_t *buffer, size_t len);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Message of the last error that occurred on this thread, or NULL if none did. It remains valid until the next failing call on this thread.
+const char *doby_last_error(void);
+
+// Encrypts `plaintext` with `password` (normalized to Unicode NFC if it is UTF-8), using the default Argon2 costs and cipher of the command line.
+//
+// On success, `*output` points to `*output_len` bytes that must be released with `doby_free`.
+//
+// # Safety
+//
+// `password` and `plaintext` must point to buffers of `password_len` and `plaintext_len` bytes, or be NULL if empty. `output` and `output_len` must be valid for writes.
+enum DobyStatus doby_encrypt(const uint8_t *password,
+                             size_t password_len,
+                             const uint8_t *plaintext,
+                             size_t plaintext_len,
+                             uint8_t **output,
+                             size_t *output_len);
+
+// Decrypts `ciphertext` with `password`. It must have been encrypted with a password, possibly among others.
+//
+// On success, `*output` points to the `*output_len` bytes of the plaintext, that must be released with `doby_free`. Nothing is returned if authentication fails.
+//
+// # Safety
+//
+// `password` and `ciphertext` must point to buffers of `password_len` and `ciphertext_len` bytes, or be NULL if empty. `output` and `output_len` must be valid for writes.
+enum DobyStatus doby_decrypt(const uint8_t *password,
+                             size_t password_len,
+                             const uint8_t *ciphertext,
+                             size_t ciphertext_len,
+                             uint8_t **output,
+                             size_t *output_len);
+
+// Releases a buffer returned by `doby_encrypt` or `doby_decrypt`, after overwriting it with zeros. Does nothing if `buffer` is NULL.
+//
+// # Safety
+//
+// `buffer` and `len` must come from the same successful call, and `buffer` must not be used afterwards.
+void doby_free(uint8_t *buffer,
+               size_t len);
+
+// Creates an encryption context whose ciphertext is passed to `write`, with `user_data`. The password is derived like in `doby_encrypt`.
+//
+// # Safety
+//
+// `password` must point to `password_len` bytes, or be NULL if empty. `encryptor` must be valid for writes. `write` must be safe to call with `user_data` until the context is released.
+enum DobyStatus doby_encryptor_new(const uint8_t *password,
+                                   size_t password_len,
+                                   DobyWriteFn write,
+                                   void *user_data,
+                                   struct DobyEncryptor **encryptor);
+
+// Encrypts `len` bytes of `data`. The header is written on the first call.
+//
+// # Safety
+//
+// `encryptor` must come from `doby_encryptor_new` and not have been released. `data` must point to `len` bytes, or be NULL if empty.
+enum DobyStatus doby_encryptor_write(struct DobyEncryptor *encryptor,
+                                     const uint8_t *data,
+                                     size_t len);
+
+// Writes the authentication data and releases the context, even if it fails. Without it, the ciphertext is incomplete.
+//
+// # Safety
+//
+// `encryptor` must come from `doby_encryptor_new` and must not be used afterwards.
+enum DobyStatus doby_encryptor_finish(struct DobyEncryptor *encryptor);
+
+// Releases an encryption context without finishing the ciphertext. Does nothing if `encryptor` is NULL.
+//
+// # Safety
+//
+// `encryptor` must come from `doby_encryptor_new` and must not be used afterwards.
+void doby_encryptor_free(struct DobyEncryptor *encryptor);
+
+// Creates a decryption context reading the ciphertext from `read`, with `user_data`. The header is read and the password checked before returning, when the format allows it.
+//
+// # Safety
+//
+// `password` must point to `password_len` bytes, or be NULL if empty. `decryptor` must be valid for writes. `read` must be safe to call with `user_data` until the context is released.
+enum DobyStatus doby_decryptor_new(const uint8_t *password,
+                                   size_t password_len,
+                                   DobyReadFn read,
+                                   void *user_data,
+                                   struct DobyDecryptor **decryptor);
+
+// Decrypts up to `len` bytes into `buffer` and sets `*read_len` to their number, 0 meaning that the whole ciphertext has been read and authenticated.
+//
+// Authentication happens at the end with the AES-CTR and XChaCha20 ciphers: until then, the plaintext must not be trusted.
+//
+// # Safety
+//
+// `decryptor` must come from `doby_decryptor_new` and not have been released. `buffer` must be valid for writes of `len` bytes, and `read_len` for writes.
+enum DobyStatus doby_decryptor_read(struct DobyDecryptor *decryptor,
+                                    uint8_t *buffer,
+                                    size_t len,
+                                    size_t *read_len);
+
+// Releases a decryption context. Does nothing if `decryptor` is NULL.
+//
+// # Safety
+//
+// `decryptor` must come from `doby_decryptor_new` and must not be used afterwards.
+void doby_decryptor_free(struct DobyDecryptor *decryptor);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* DOBY_H */
@@ -0,0 +1,289 @@
+use std::{collections::VecDeque, fs::{self, File, OpenOptions}, io::{self, Read, Write}, path::{Component, Path, PathBuf}};
+
+pub const ARCHIVE_MAGIC: &[u8; 7] = b"dbyarch";
+pub const ARCHIVE_VERSION: u8 = 1;
+
+const ENTRY_END: u8 = 0;
+const ENTRY_DIR: u8 = 1;
+const ENTRY_FILE: u8 = 2;
+const ENTRY_SYMLINK: u8 = 3;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+    if metadata.permissions().readonly() {
+        mode & !0o222
+    } else {
+        mode
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "symbolic links are not supported on this platform"))
+}
+
+/// Paths are stored relative to the archive root, with '/' as separator.
+fn encode_path(path: &Path) -> io::Result<Vec<u8>> {
+    let components = path.components().map(|c| c.as_os_str().to_str()).collect::<Option<Vec<&str>>>()
+        .ok_or_else(|| invalid_data("non UTF-8 path"))?;
+    let path = components.join("/").into_bytes();
+    if path.len() > u16::MAX as usize {
+        return Err(invalid_data("path too long"));
+    }
+    Ok(path)
+}
+
+/// Rejects absolute paths and ".." components so that extraction can't escape the destination.
+fn decode_path(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    let path = PathBuf::from(String::from_utf8(bytes).map_err(|_| invalid_data("non UTF-8 path"))?);
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(invalid_data("unsafe path in archive"));
+    }
+    Ok(path)
+}
+
+fn walk(root: &Path, relative: &Path, entries: &mut VecDeque<PathBuf>) -> io::Result<()> {
+    let mut children = fs::read_dir(root.join(relative))?
+        .map(|entry| entry.map(|e| relative.join(e.file_name())))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    children.sort();
+    for child in children {
+        let is_dir = fs::symlink_metadata(root.join(&child))?.is_dir();
+        entries.push_back(child.clone());
+        if is_dir {
+            walk(root, &child, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Serializes a directory tree (paths, permissions, file contents and symbolic links) as a stream that can be passed to `encrypt`.
+///
+/// Symbolic links are stored as links and never followed.
+pub struct ArchiveReader {
+    root: PathBuf,
+    entries: VecDeque<PathBuf>,
+    buffer: Vec<u8>,
+    pos: usize,
+    file: Option<(File, u64)>,
+    finished: bool,
+}
+
+impl ArchiveReader {
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        let mut entries = VecDeque::new();
+        walk(&root, Path::new(""), &mut entries)?;
+        let mut buffer = ARCHIVE_MAGIC.to_vec();
+        buffer.push(ARCHIVE_VERSION);
+        Ok(Self {
+            root,
+            entries,
+            buffer,
+            pos: 0,
+            file: None,
+            finished: false,
+        })
+    }
+
+    fn next_entry(&mut self) -> io::Result<()> {
+        self.buffer.clear();
+        self.pos = 0;
+        let relative = match self.entries.pop_front() {
+            Some(relative) => relative,
+            None => {
+                self.buffer.push(ENTRY_END);
+                self.finished = true;
+                return Ok(());
+            }
+        };
+        let path = self.root.join(&relative);
+        let metadata = fs::symlink_metadata(&path)?;
+        let kind = if metadata.file_type().is_symlink() {
+            ENTRY_SYMLINK
+        } else if metadata.is_dir() {
+            ENTRY_DIR
+        } else if metadata.is_file() {
+            ENTRY_FILE
+        } else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{}: unsupported file type", path.display())));
+        };
+        let encoded_path = encode_path(&relative)?;
+        self.buffer.push(kind);
+        self.buffer.extend_from_slice(&(encoded_path.len() as u16).to_be_bytes());
+        self.buffer.extend_from_slice(&encoded_path);
+        self.buffer.extend_from_slice(&mode(&metadata).to_be_bytes());
+        match kind {
+            ENTRY_FILE => {
+                self.buffer.extend_from_slice(&metadata.len().to_be_bytes());
+                self.file = Some((File::open(&path)?, metadata.len()));
+            }
+            ENTRY_SYMLINK => {
+                let target = fs::read_link(&path)?;
+                let target = target.to_str().ok_or_else(|| invalid_data("non UTF-8 path"))?.as_bytes();
+                if target.len() > u16::MAX as usize {
+                    return Err(invalid_data("path too long"));
+                }
+                self.buffer.extend_from_slice(&(target.len() as u16).to_be_bytes());
+                self.buffer.extend_from_slice(target);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.buffer.len() {
+                let n = buf.len().min(self.buffer.len() - self.pos);
+                buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos+n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if let Some((file, remaining)) = self.file.as_mut() {
+                if *remaining > 0 {
+                    let len = buf.len().min(*remaining as usize);
+                    let n = file.read(&mut buf[..len])?;
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrunk while being archived"));
+                    }
+                    *remaining -= n as u64;
+                    return Ok(n);
+                }
+                self.file = None;
+            }
+            if self.finished {
+                return Ok(0);
+            }
+            self.next_entry()?;
+        }
+    }
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buff = [0; 2];
+    reader.read_exact(&mut buff)?;
+    Ok(u16::from_be_bytes(buff))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buff = [0; 4];
+    reader.read_exact(&mut buff)?;
+    Ok(u32::from_be_bytes(buff))
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut buff = vec![0; read_u16(reader)? as usize];
+    reader.read_exact(&mut buff)?;
+    Ok(buff)
+}
+
+/// Recreates the tree serialized by `ArchiveReader` inside `dest`. Existing files are never overwritten.
+///
+/// The reader is read until EOF after the end of the archive, so that a `DecryptReader` gets the chance to verify the HMAC. Everything extracted is removed if that fails, or if the archive is invalid.
+pub fn extract_archive<R: Read, P: AsRef<Path>>(reader: &mut R, dest: P) -> io::Result<()> {
+    let dest = dest.as_ref();
+    let mut magic = [0; ARCHIVE_MAGIC.len()+1];
+    reader.read_exact(&mut magic)?;
+    if &magic[..ARCHIVE_MAGIC.len()] != ARCHIVE_MAGIC {
+        return Err(invalid_data("not a doby archive"));
+    }
+    if magic[ARCHIVE_MAGIC.len()] != ARCHIVE_VERSION {
+        return Err(invalid_data("unsupported archive version"));
+    }
+    let mut created = Vec::new();
+    if !dest.exists() {
+        fs::create_dir_all(dest)?;
+        created.push(dest.to_path_buf());
+    }
+    let result = extract_entries(reader, dest, &mut created);
+    if result.is_err() {
+        //children were created after their parent
+        for path in created.iter().rev() {
+            let is_dir = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir());
+            let _ = if is_dir { fs::remove_dir(path) } else { fs::remove_file(path) };
+        }
+    }
+    result
+}
+
+fn extract_entries<R: Read>(reader: &mut R, dest: &Path, created: &mut Vec<PathBuf>) -> io::Result<()> {
+    //symbolic links and directory permissions are applied last so that no file is written through a link or into a read-only directory
+    let mut symlinks = Vec::new();
+    let mut dirs = Vec::new();
+    loop {
+        let mut kind = [0; 1];
+        reader.read_exact(&mut kind)?;
+        if kind[0] == ENTRY_END {
+            break;
+        }
+        let path = dest.join(decode_path(read_bytes(reader)?)?);
+        let mode = read_u32(reader)?;
+        match kind[0] {
+            ENTRY_DIR => {
+                fs::create_dir(&path)?;
+                created.push(path.clone());
+                dirs.push((path, mode));
+            }
+            ENTRY_FILE => {
+                let mut size = [0; 8];
+                reader.read_exact(&mut size)?;
+                let size = u64::from_be_bytes(size);
+                let mut file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+                created.push(path.clone());
+                if io::copy(&mut reader.take(size), &mut file)? != size {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated archive"));
+                }
+                file.flush()?;
+                set_mode(&path, mode)?;
+            }
+            ENTRY_SYMLINK => {
+                let target = String::from_utf8(read_bytes(reader)?).map_err(|_| invalid_data("non UTF-8 path"))?;
+                symlinks.push((target, path));
+            }
+            _ => return Err(invalid_data("unknown archive entry")),
+        }
+    }
+    if reader.read(&mut [0; 1])? != 0 {
+        return Err(invalid_data("trailing data after the archive"));
+    }
+    for (target, path) in symlinks {
+        symlink(&target, &path)?;
+        created.push(path);
+    }
+    for (path, mode) in dirs.into_iter().rev() {
+        set_mode(&path, mode)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+pub const ARMOR_BEGIN: &str = "-----BEGIN DOBY ENCRYPTED FILE-----";
+pub const ARMOR_END: &str = "-----END DOBY ENCRYPTED FILE-----";
+//48 bytes give 64 base64 characters per line
+const LINE_BYTES: usize = 48;
+
+pub fn is_armored(first_bytes: &[u8]) -> bool {
+    first_bytes.starts_with(ARMOR_BEGIN.as_bytes())
+}
+
+/// Base64-encodes everything written to it between BEGIN/END markers. The END marker is written by `finish`.
+pub struct ArmorWriter<W: Write> {
+    writer: W,
+    pending: Vec<u8>,
+    header_written: bool,
+}
+
+impl<W: Write> ArmorWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            pending: Vec::with_capacity(LINE_BYTES),
+            header_written: false,
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            writeln!(self.writer, "{}", ARMOR_BEGIN)?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    /// Encodes the remaining bytes, writes the END marker and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_header()?;
+        if !self.pending.is_empty() {
+            writeln!(self.writer, "{}", base64::encode(&self.pending))?;
+        }
+        writeln!(self.writer, "{}", ARMOR_END)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for ArmorWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_header()?;
+        let n = buf.len().min(LINE_BYTES - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        if self.pending.len() == LINE_BYTES {
+            writeln!(self.writer, "{}", base64::encode(&self.pending))?;
+            self.pending.clear();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decodes the output of `ArmorWriter`. Lines can be wrapped differently, but the BEGIN and END markers must be present.
+pub struct ArmorReader<R: Read> {
+    reader: BufReader<R>,
+    line: String,
+    //base64 characters not forming a complete 4-characters group yet
+    remainder: Vec<u8>,
+    decoded: Vec<u8>,
+    pos: usize,
+    header_read: bool,
+    finished: bool,
+}
+
+impl<R: Read> ArmorReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            line: String::new(),
+            remainder: Vec::new(),
+            decoded: Vec::new(),
+            pos: 0,
+            header_read: false,
+            finished: false,
+        }
+    }
+
+    fn invalid_data(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+    }
+
+    /// Returns `false` once the END marker has been reached.
+    fn decode_line(&mut self) -> io::Result<bool> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing armor END marker"));
+        }
+        let line = self.line.trim();
+        if !self.header_read {
+            if line == ARMOR_BEGIN {
+                self.header_read = true;
+            } else if !line.is_empty() {
+                return Err(Self::invalid_data("missing armor BEGIN marker"));
+            }
+            return Ok(true);
+        }
+        if line == ARMOR_END {
+            if !self.remainder.is_empty() {
+                return Err(Self::invalid_data("truncated base64 data"));
+            }
+            return Ok(false);
+        }
+        self.remainder.extend(line.bytes().filter(|b| !b.is_ascii_whitespace()));
+        let len = self.remainder.len() - self.remainder.len() % 4;
+        self.decoded = base64::decode(&self.remainder[..len]).map_err(|_| Self::invalid_data("invalid base64 data"))?;
+        self.remainder.drain(..len);
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for ArmorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.decoded.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            if !self.decode_line()? {
+                self.finished = true;
+            }
+        }
+        let n = buf.len().min(self.decoded.len() - self.pos);
+        buf[..n].copy_from_slice(&self.decoded[self.pos..self.pos+n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
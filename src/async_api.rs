@@ -0,0 +1,71 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::{
+    Error,
+    MAGIC_BYTES,
+    crypto::{DobyCipher, EncryptionParams},
+};
+
+pub async fn encrypt_async<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W, params: &EncryptionParams, mut cipher: DobyCipher, block_size: usize) -> Result<(), Error> {
+    let mut output = MAGIC_BYTES.to_vec();
+    params.write(&mut output)?;
+    writer.write_all(&output).await?;
+    let mut buff = vec![0; block_size];
+    loop {
+        let n = reader.read(&mut buff).await?;
+        if n == 0 {
+            break;
+        }
+        output.clear();
+        cipher.encrypt_chunk(&mut buff[..n], &mut output)?;
+        writer.write_all(&output).await?;
+    }
+    output.clear();
+    cipher.write_hmac(&mut output)?;
+    writer.write_all(&output).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// `reader` must be positioned right after the header the cipher was created from.
+pub async fn decrypt_async<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W, mut cipher: DobyCipher, block_size: usize) -> Result<(), Error> {
+    let mut buff = vec![0; block_size];
+    let mut plaintext = Vec::with_capacity(block_size);
+    loop {
+        let n = reader.read(&mut buff).await?;
+        if n == 0 {
+            break;
+        }
+        plaintext.clear();
+        let authentic = cipher.decrypt_update(&buff[..n], &mut plaintext);
+        writer.write_all(&plaintext).await?;
+        if !authentic {
+            return Err(Error::HmacMismatch);
+        }
+    }
+    plaintext.clear();
+    let truncated = cipher.truncated();
+    let verified = cipher.decrypt_finalize(&mut plaintext);
+    writer.write_all(&plaintext).await?;
+    writer.flush().await?;
+    if let Some(expected) = truncated {
+        Err(Error::Truncated { expected })
+    } else if verified {
+        Ok(())
+    } else {
+        Err(Error::HmacMismatch)
+    }
+}
+
+/// Reads the magic bytes and the encryption parameters from an async source.
+pub async fn read_header_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<EncryptionParams, Error> {
+    let mut header = vec![0; MAGIC_BYTES.len()];
+    reader.read_exact(&mut header).await?;
+    let fields_len = if header == MAGIC_BYTES {
+        EncryptionParams::LEN
+    } else {
+        EncryptionParams::LEN - 1 //legacy headers have no version byte
+    };
+    let mut fields = vec![0; fields_len];
+    reader.read_exact(&mut fields).await?;
+    crate::read_params(&header, &mut fields.as_slice())
+}
@@ -0,0 +1,134 @@
+//! Measurements of `doby bench`: cipher throughput, Argon2 time and the block size giving the fastest encryption and decryption of a file on this machine.
+
+use std::{fs::{self, File}, io::{self, BufReader, BufWriter, Read, Write}, path::{Path, PathBuf}, time::{Duration, Instant}};
+use rand::{RngCore, rngs::OsRng};
+use crate::{
+    Error,
+    cli::{cipher_name, mac_name},
+    config::Config,
+    crypto::{CipherAlgorithm, DobyCipher, EncryptionParams, MacAlgorithm, KEY_LEN},
+    decrypt,
+    encrypt,
+    read_header,
+    DEFAULT_BLOCK_SIZE,
+};
+
+/// Cipher and MAC combinations measured by `cipher_throughput`. The MAC is ignored by AEAD ciphers.
+pub const BENCH_ALGORITHMS: [(CipherAlgorithm, MacAlgorithm); 6] = [
+    (CipherAlgorithm::AesCtr, MacAlgorithm::Blake2b),
+    (CipherAlgorithm::AesCtr, MacAlgorithm::Blake3),
+    (CipherAlgorithm::XChaCha20, MacAlgorithm::Blake2b),
+    (CipherAlgorithm::XChaCha20, MacAlgorithm::Blake3),
+    (CipherAlgorithm::AesGcm, MacAlgorithm::Blake2b),
+    (CipherAlgorithm::XChaCha20Poly1305, MacAlgorithm::Blake2b),
+];
+/// Block sizes tried by `block_size_time`.
+pub const BENCH_BLOCK_SIZES: [usize; 7] = [4096, 16384, 65536, 262144, 1 << 20, 4 << 20, 16 << 20];
+/// Largest part of the input loaded in memory to measure the ciphers without I/O.
+const MAX_IN_MEMORY: u64 = 64 << 20;
+const KEY: [u8; KEY_LEN] = [0; KEY_LEN];
+
+fn raw_key_cipher(cipher: CipherAlgorithm, mac: MacAlgorithm) -> Result<(EncryptionParams, DobyCipher), Error> {
+    let mut params = EncryptionParams::with_raw_key(cipher);
+    params.mac = mac;
+    let cipher = DobyCipher::with_master_key(&KEY, &params)?;
+    Ok((params, cipher))
+}
+
+/// Temporary file of `size` random bytes in `dir`, removed when dropped.
+pub fn random_file(dir: &Path, size: u64) -> io::Result<tempfile::NamedTempFile> {
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    let mut writer = BufWriter::new(file.as_file_mut());
+    let mut buff = vec![0; DEFAULT_BLOCK_SIZE];
+    let mut written = 0;
+    while written < size {
+        let n = buff.len().min((size - written) as usize);
+        OsRng.fill_bytes(&mut buff[..n]);
+        writer.write_all(&buff[..n])?;
+        written += n as u64;
+    }
+    writer.flush()?;
+    drop(writer);
+    file.as_file().sync_data()?;
+    Ok(file)
+}
+
+/// Reads up to 64MiB of `input`, used by `cipher_throughput`.
+pub fn load_sample(input: &Path) -> io::Result<Vec<u8>> {
+    let mut sample = Vec::new();
+    File::open(input)?.take(MAX_IN_MEMORY).read_to_end(&mut sample)?;
+    Ok(sample)
+}
+
+/// Bytes encrypted per second, in memory.
+pub fn cipher_throughput(sample: &[u8], cipher: CipherAlgorithm, mac: MacAlgorithm) -> Result<f64, Error> {
+    let (params, cipher) = raw_key_cipher(cipher, mac)?;
+    let start = Instant::now();
+    encrypt(&mut &sample[..], &mut io::sink(), &params, cipher, DEFAULT_BLOCK_SIZE, None)?;
+    Ok(sample.len() as f64 / start.elapsed().as_secs_f64())
+}
+
+/// Time needed to derive a key from a password with these Argon2 parameters.
+pub fn kdf_time(argon2_params: &argon2::Params) -> Result<Duration, Error> {
+    let start = Instant::now();
+    EncryptionParams::with_password(b"doby bench", argon2_params.clone(), CipherAlgorithm::XChaCha20Poly1305)?;
+    Ok(start.elapsed())
+}
+
+/// Time needed to encrypt `input` to a temporary file in `output_dir`, committed to disk, and to decrypt it back, reading and writing `block_size` bytes at a time.
+pub fn block_size_time(input: &Path, output_dir: &Path, cipher: CipherAlgorithm, mac: MacAlgorithm, block_size: usize) -> Result<Duration, Error> {
+    let output = tempfile::NamedTempFile::new_in(output_dir)?;
+    let start = Instant::now();
+    let (params, encryption_cipher) = raw_key_cipher(cipher, mac)?;
+    let mut reader = BufReader::with_capacity(block_size, File::open(input)?);
+    let mut writer = BufWriter::with_capacity(block_size, output.as_file());
+    encrypt(&mut reader, &mut writer, &params, encryption_cipher, block_size, None)?;
+    writer.flush()?;
+    drop(writer);
+    output.as_file().sync_data()?;
+    let mut reader = BufReader::with_capacity(block_size, File::open(output.path())?);
+    let params = read_header(&mut reader)?;
+    decrypt(&mut reader, &mut io::sink(), DobyCipher::with_master_key(&KEY, &params)?, block_size)?;
+    Ok(start.elapsed())
+}
+
+/// Sets `cipher`, `mac` (unless the cipher is an AEAD) and `block_size` in the configuration file, creating it if needed. Other lines, including comments, are kept. Returns the path of the file.
+pub fn write_config(cipher: CipherAlgorithm, mac: MacAlgorithm, block_size: usize) -> Result<PathBuf, Error> {
+    let mut options = vec![("cipher", format!("\"{}\"", cipher_name(cipher)))];
+    if !cipher.is_aead() {
+        options.push(("mac", format!("\"{}\"", mac_name(mac))));
+    }
+    options.push(("block_size", block_size.to_string()));
+    let path = Config::path().ok_or(Error::Usage("no configuration file: neither DOBY_CONFIG nor HOME is set"))?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(Error::Path { path: path.display().to_string(), error }),
+    };
+    let content = set_options(&content, &options);
+    //refuse to write a file that couldn't be read back
+    Config::parse(&content, &path.display().to_string())?;
+    let path_error = |error| Error::Path { path: path.display().to_string(), error };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(path_error)?;
+    }
+    fs::write(&path, content).map_err(path_error)?;
+    Ok(path)
+}
+
+/// Replaces the `key = value` lines of `options` in `content`, or appends them.
+fn set_options(content: &str, options: &[(&str, String)]) -> String {
+    let mut missing: Vec<_> = options.iter().collect();
+    let mut lines: Vec<String> = content.lines().map(|line| {
+        let key = line.split_once('=').map(|(key, _)| key.trim()).filter(|_| !line.trim_start().starts_with('#'));
+        match missing.iter().position(|(option, _)| Some(*option) == key) {
+            Some(i) => {
+                let (key, value) = missing.remove(i);
+                format!("{} = {}", key, value)
+            }
+            None => line.to_string(),
+        }
+    }).collect();
+    lines.extend(missing.into_iter().map(|(key, value)| format!("{} = {}", key, value)));
+    lines.join("\n") + "\n"
+}
@@ -0,0 +1,229 @@
+//! High-level API: `Encryptor` and `Decryptor` write and read the header, run Argon2 and process the chunks, so that library users don't have to coordinate `EncryptionParams`, `DobyCipher` and `encrypt` themselves.
+//!
+//! ```
+//! use doby::{Decryptor, Encryptor, crypto::{Argon2Profile, CipherAlgorithm}};
+//!
+//! let encryptor = Encryptor::builder().cipher(CipherAlgorithm::XChaCha20Poly1305).argon2_profile(Argon2Profile::Fast).build();
+//! let mut ciphertext = Vec::new();
+//! encryptor.encrypt(b"password", &mut &b"plaintext"[..], &mut ciphertext).unwrap();
+//!
+//! let mut plaintext = Vec::new();
+//! Decryptor::builder().build().decrypt(b"password", &mut ciphertext.as_slice(), &mut plaintext).unwrap();
+//! assert_eq!(plaintext, b"plaintext");
+//! ```
+
+use std::io::{Read, Write};
+#[cfg(feature = "os")]
+use std::{fs::File, io::BufReader, path::Path};
+use zeroize::Zeroizing;
+use crate::{
+    Error,
+    DigestCheckWriter,
+    EncryptWriter,
+    DecryptReader,
+    crypto::{Argon2Limits, Argon2Profile, CipherAlgorithm, DobyCipher, EncryptionParams, MacAlgorithm, nfc_password},
+    decrypt,
+    encrypt,
+    read_header,
+};
+#[cfg(feature = "os")]
+use crate::WrappedWriter;
+
+const DEFAULT_BLOCK_SIZE: usize = 65536;
+
+/// Options of an `Encryptor`. The defaults are those of the command line.
+#[derive(Clone)]
+pub struct EncryptorBuilder {
+    cipher: CipherAlgorithm,
+    mac: MacAlgorithm,
+    argon2_params: argon2::Params,
+    nfc: bool,
+    block_size: usize,
+}
+
+impl Default for EncryptorBuilder {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "os")]
+            cipher: crate::default_cipher(),
+            #[cfg(not(feature = "os"))]
+            cipher: CipherAlgorithm::XChaCha20,
+            mac: MacAlgorithm::Blake2b,
+            argon2_params: Argon2Profile::default().params(),
+            nfc: true,
+            block_size: DEFAULT_BLOCK_SIZE,
+        }
+    }
+}
+
+impl EncryptorBuilder {
+    /// AES-CTR if the CPU has AES instructions, XChaCha20 otherwise (always XChaCha20 without the `os` feature).
+    pub fn cipher(mut self, cipher: CipherAlgorithm) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Only used by stream ciphers. BLAKE2b by default.
+    pub fn mac(mut self, mac: MacAlgorithm) -> Self {
+        self.mac = mac;
+        self
+    }
+
+    /// Replaces the costs set with `argon2_params`.
+    pub fn argon2_profile(mut self, profile: Argon2Profile) -> Self {
+        self.argon2_params = profile.params();
+        self
+    }
+
+    /// Replaces the costs of the profile.
+    pub fn argon2_params(mut self, params: argon2::Params) -> Self {
+        self.argon2_params = params;
+        self
+    }
+
+    /// Normalize passwords to Unicode NFC, as the command line does unless `--no-nfc`. Enabled by default.
+    pub fn nfc(mut self, nfc: bool) -> Self {
+        self.nfc = nfc;
+        self
+    }
+
+    /// Size of the buffer used by `Encryptor::encrypt`.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn build(self) -> Encryptor {
+        Encryptor(self)
+    }
+}
+
+/// Encrypts with a password. Argon2 runs once per file, with a new salt.
+#[derive(Clone)]
+pub struct Encryptor(EncryptorBuilder);
+
+impl Encryptor {
+    pub fn builder() -> EncryptorBuilder {
+        EncryptorBuilder::default()
+    }
+
+    fn cipher(&self, password: &[u8]) -> Result<(EncryptionParams, DobyCipher), Error> {
+        let options = &self.0;
+        //as the command line, only flag the passwords that NFC may change
+        let normalize = options.nfc && !password.is_ascii();
+        let password = if normalize { nfc_password(password) } else { Zeroizing::new(password.to_vec()) };
+        let (mut params, master_key) = EncryptionParams::with_password(&password, options.argon2_params.clone(), options.cipher)?;
+        params.mac = options.mac;
+        if normalize {
+            params.set_nfc_passwords()?;
+        }
+        let cipher = DobyCipher::with_master_key(&master_key, &params)?;
+        Ok((params, cipher))
+    }
+
+    /// Encrypts everything read from `reader` and writes the ciphertext, header included, to `writer`.
+    pub fn encrypt<R: Read, W: Write>(&self, password: &[u8], reader: &mut R, writer: &mut W) -> Result<(), Error> {
+        let (params, cipher) = self.cipher(password)?;
+        encrypt(reader, writer, &params, cipher, self.0.block_size, None)
+    }
+
+    /// Same as `encrypt`, but the plaintext is written to the returned `EncryptWriter`, which must be finished with `EncryptWriter::finish`.
+    pub fn writer<W: Write>(&self, password: &[u8], writer: W) -> Result<EncryptWriter<W>, Error> {
+        let (params, cipher) = self.cipher(password)?;
+        Ok(EncryptWriter::new(writer, params, cipher))
+    }
+}
+
+/// Options of a `Decryptor`.
+#[derive(Clone)]
+pub struct DecryptorBuilder {
+    argon2_limits: Argon2Limits,
+    block_size: usize,
+}
+
+impl Default for DecryptorBuilder {
+    fn default() -> Self {
+        Self {
+            argon2_limits: Argon2Limits::default(),
+            block_size: DEFAULT_BLOCK_SIZE,
+        }
+    }
+}
+
+impl DecryptorBuilder {
+    /// Largest Argon2 costs accepted from the header: files requiring more fail with `Error::Argon2LimitExceeded` before running Argon2. See `Argon2Limits::default`.
+    pub fn argon2_limits(mut self, limits: Argon2Limits) -> Self {
+        self.argon2_limits = limits;
+        self
+    }
+
+    /// Size of the buffer used by `Decryptor::decrypt`.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn build(self) -> Decryptor {
+        Decryptor(self)
+    }
+}
+
+/// Decrypts files encrypted with a password, possibly among other passwords or recipients.
+#[derive(Clone)]
+pub struct Decryptor(DecryptorBuilder);
+
+impl Decryptor {
+    pub fn builder() -> DecryptorBuilder {
+        DecryptorBuilder::default()
+    }
+
+    fn cipher<R: Read>(&self, password: &[u8], reader: &mut R) -> Result<(EncryptionParams, DobyCipher), Error> {
+        let params = read_header(reader)?;
+        self.0.argon2_limits.check(&params)?;
+        let cipher = DobyCipher::try_with_password(password, &params)?;
+        Ok((params, cipher))
+    }
+
+    /// Decrypts the ciphertext read from `reader`, header included, to `writer`, and checks the plaintext against its digest if the header holds one. The plaintext is written before being authenticated: it must be discarded if an error is returned.
+    pub fn decrypt<R: Read, W: Write>(&self, password: &[u8], reader: &mut R, writer: &mut W) -> Result<(), Error> {
+        let (params, cipher) = self.cipher(password, reader)?;
+        match params.plaintext_digest() {
+            Some(digest) => {
+                let mut writer = DigestCheckWriter::new(writer, digest);
+                decrypt(reader, &mut writer, cipher, self.0.block_size)?;
+                writer.finish().map(|_| ())
+            }
+            None => decrypt(reader, writer, cipher, self.0.block_size),
+        }
+    }
+
+    /// Reads the header from `reader` and returns a `DecryptReader` of the plaintext. Unlike `decrypt`, the plaintext isn't checked against its digest.
+    pub fn reader<R: Read>(&self, password: &[u8], mut reader: R) -> Result<DecryptReader<R>, Error> {
+        let (_, cipher) = self.cipher(password, &mut reader)?;
+        Ok(DecryptReader::with_cipher(reader, cipher))
+    }
+}
+
+#[cfg(feature = "os")]
+fn open_paths(src: &Path, dst: &Path, block_size: usize) -> Result<(BufReader<File>, crate::OutputWriter), Error> {
+    let file = File::open(src).map_err(|error| Error::Path { path: src.display().to_string(), error })?;
+    //as with the command line, dst is written to a temporary file first, so src may also be dst
+    let writer = WrappedWriter::from_path(dst.display().to_string()).into_buf_writer_with_block_size(block_size)?;
+    Ok((BufReader::new(file), writer))
+}
+
+/// Encrypts the file `src` to `dst`. The ciphertext is written to a temporary file next to `dst`, synced, and renamed to `dst` once complete: `dst` is left untouched if an error is returned.
+#[cfg(feature = "os")]
+pub fn encrypt_file<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q, password: &[u8], options: &Encryptor) -> Result<(), Error> {
+    let (mut reader, mut writer) = open_paths(src.as_ref(), dst.as_ref(), options.0.block_size)?;
+    options.encrypt(password, &mut reader, &mut writer)?;
+    writer.finish(true)
+}
+
+/// Decrypts the file `src` to `dst`. The plaintext only replaces `dst` once the whole file has been authenticated, so no unauthenticated plaintext is ever left at `dst`.
+#[cfg(feature = "os")]
+pub fn decrypt_file<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q, password: &[u8], options: &Decryptor) -> Result<(), Error> {
+    let (mut reader, mut writer) = open_paths(src.as_ref(), dst.as_ref(), options.0.block_size)?;
+    options.decrypt(password, &mut reader, &mut writer)?;
+    writer.finish(true)
+}
@@ -0,0 +1,308 @@
+//! C API, built as a cdylib with the `capi` feature. The header is generated in include/doby.h.
+//!
+//! Functions return a `DobyStatus`. On failure, `doby_last_error` describes the error.
+
+use std::{cell::RefCell, ffi::{CString, c_char, c_int, c_void}, io::{self, Read, Write}, ptr, slice};
+use zeroize::Zeroize;
+use crate::{
+    DecryptReader,
+    EncryptWriter,
+    Error,
+    crypto::{DobyCipher, EncryptionParams, default_argon2_params, nfc_password},
+    default_cipher,
+    read_header,
+};
+
+/// Result of the functions of the C API.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DobyStatus {
+    Ok = 0,
+    /// A required pointer is NULL.
+    InvalidArgument = 1,
+    /// I/O error, including failures of the read and write callbacks.
+    Io = 2,
+    /// The input isn't in doby format, or was written by a newer version.
+    UnknownFormat = 3,
+    WrongPassword = 4,
+    /// The ciphertext has been corrupted or altered. Plaintext already returned must be discarded.
+    Authentication = 5,
+    Other = 6,
+}
+
+impl From<&Error> for DobyStatus {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::Io(_) | Error::Path { .. } | Error::Truncated { .. } => DobyStatus::Io,
+            Error::UnknownFormat | Error::InvalidHeader | Error::UnsupportedVersion(_) | Error::UnsupportedExtension(_) => DobyStatus::UnknownFormat,
+            Error::WrongPassword | Error::NoMatchingKeySlot => DobyStatus::WrongPassword,
+            Error::HmacMismatch => DobyStatus::Authentication,
+            _ => DobyStatus::Other,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = CString::new(message).ok());
+}
+
+fn status(result: Result<(), Error>) -> DobyStatus {
+    match result {
+        Ok(()) => DobyStatus::Ok,
+        Err(e) => {
+            set_last_error(e.to_string());
+            DobyStatus::from(&e)
+        }
+    }
+}
+
+fn invalid_argument(name: &str) -> DobyStatus {
+    set_last_error(format!("{} is NULL", name));
+    DobyStatus::InvalidArgument
+}
+
+/// Message of the last error that occurred on this thread, or NULL if none did. It remains valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn doby_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+//NULL is allowed for empty buffers
+unsafe fn input<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(data, len))
+    }
+}
+
+fn encryption_cipher(password: &[u8]) -> Result<(EncryptionParams, DobyCipher), Error> {
+    let (mut params, master_key) = EncryptionParams::with_password(&nfc_password(password), default_argon2_params(), default_cipher())?;
+    if !password.is_ascii() {
+        //the only extension
+        params.set_nfc_passwords().unwrap();
+    }
+    let cipher = DobyCipher::with_master_key(&master_key, &params)?;
+    Ok((params, cipher))
+}
+
+unsafe fn into_raw_buffer(data: Vec<u8>, output: *mut *mut u8, output_len: *mut usize) {
+    let data = data.into_boxed_slice();
+    *output_len = data.len();
+    *output = Box::into_raw(data) as *mut u8;
+}
+
+/// Encrypts `plaintext` with `password` (normalized to Unicode NFC if it is UTF-8), using the default Argon2 costs and cipher of the command line.
+///
+/// On success, `*output` points to `*output_len` bytes that must be released with `doby_free`.
+///
+/// # Safety
+///
+/// `password` and `plaintext` must point to buffers of `password_len` and `plaintext_len` bytes, or be NULL if empty. `output` and `output_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn doby_encrypt(password: *const u8, password_len: usize, plaintext: *const u8, plaintext_len: usize, output: *mut *mut u8, output_len: *mut usize) -> DobyStatus {
+    let (Some(password), Some(plaintext)) = (input(password, password_len), input(plaintext, plaintext_len)) else {
+        return invalid_argument("password or plaintext");
+    };
+    if output.is_null() || output_len.is_null() {
+        return invalid_argument("output");
+    }
+    status(encryption_cipher(password).and_then(|(params, cipher)| {
+        let mut writer = EncryptWriter::new(Vec::with_capacity(plaintext_len+EncryptionParams::LEN+64), params, cipher);
+        writer.write_all(plaintext)?;
+        writer.finish()
+    }).map(|ciphertext| into_raw_buffer(ciphertext, output, output_len)))
+}
+
+/// Decrypts `ciphertext` with `password`. It must have been encrypted with a password, possibly among others.
+///
+/// On success, `*output` points to the `*output_len` bytes of the plaintext, that must be released with `doby_free`. Nothing is returned if authentication fails.
+///
+/// # Safety
+///
+/// `password` and `ciphertext` must point to buffers of `password_len` and `ciphertext_len` bytes, or be NULL if empty. `output` and `output_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn doby_decrypt(password: *const u8, password_len: usize, ciphertext: *const u8, ciphertext_len: usize, output: *mut *mut u8, output_len: *mut usize) -> DobyStatus {
+    let (Some(password), Some(mut ciphertext)) = (input(password, password_len), input(ciphertext, ciphertext_len)) else {
+        return invalid_argument("password or ciphertext");
+    };
+    if output.is_null() || output_len.is_null() {
+        return invalid_argument("output");
+    }
+    status((|| {
+        let params = read_header(&mut ciphertext)?;
+        let mut reader = DecryptReader::with_cipher(ciphertext, DobyCipher::try_with_password(password, &params)?);
+        let mut plaintext = Vec::with_capacity(ciphertext.len());
+        if let Err(e) = reader.read_to_end(&mut plaintext) {
+            plaintext.zeroize();
+            return Err(e.into());
+        }
+        into_raw_buffer(plaintext, output, output_len);
+        Ok(())
+    })())
+}
+
+/// Releases a buffer returned by `doby_encrypt` or `doby_decrypt`, after overwriting it with zeros. Does nothing if `buffer` is NULL.
+///
+/// # Safety
+///
+/// `buffer` and `len` must come from the same successful call, and `buffer` must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn doby_free(buffer: *mut u8, len: usize) {
+    if !buffer.is_null() {
+        let mut data = Box::from_raw(ptr::slice_from_raw_parts_mut(buffer, len));
+        data.zeroize();
+    }
+}
+
+/// Writes `len` bytes of `data` and returns 0 on success.
+pub type DobyWriteFn = Option<unsafe extern "C" fn(user_data: *mut c_void, data: *const u8, len: usize) -> c_int>;
+/// Reads up to `len` bytes into `buffer` and returns how many were read, 0 at the end of the input, or a negative value on error.
+pub type DobyReadFn = Option<unsafe extern "C" fn(user_data: *mut c_void, buffer: *mut u8, len: usize) -> isize>;
+
+struct CallbackWriter {
+    write: unsafe extern "C" fn(*mut c_void, *const u8, usize) -> c_int,
+    user_data: *mut c_void,
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match unsafe { (self.write)(self.user_data, buf.as_ptr(), buf.len()) } {
+            0 => Ok(buf.len()),
+            _ => Err(io::Error::other("write callback failed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct CallbackReader {
+    read: unsafe extern "C" fn(*mut c_void, *mut u8, usize) -> isize,
+    user_data: *mut c_void,
+}
+
+impl Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe { (self.read)(self.user_data, buf.as_mut_ptr(), buf.len()) };
+        usize::try_from(n).ok().filter(|n| *n <= buf.len()).ok_or_else(|| io::Error::other("read callback failed"))
+    }
+}
+
+/// Streaming encryption context, writing the ciphertext through a callback.
+pub struct DobyEncryptor(EncryptWriter<CallbackWriter>);
+
+/// Streaming decryption context, reading the ciphertext through a callback.
+pub struct DobyDecryptor(DecryptReader<CallbackReader>);
+
+/// Creates an encryption context whose ciphertext is passed to `write`, with `user_data`. The password is derived like in `doby_encrypt`.
+///
+/// # Safety
+///
+/// `password` must point to `password_len` bytes, or be NULL if empty. `encryptor` must be valid for writes. `write` must be safe to call with `user_data` until the context is released.
+#[no_mangle]
+pub unsafe extern "C" fn doby_encryptor_new(password: *const u8, password_len: usize, write: DobyWriteFn, user_data: *mut c_void, encryptor: *mut *mut DobyEncryptor) -> DobyStatus {
+    let (Some(password), Some(write)) = (input(password, password_len), write) else {
+        return invalid_argument("password or write");
+    };
+    if encryptor.is_null() {
+        return invalid_argument("encryptor");
+    }
+    status(encryption_cipher(password).map(|(params, cipher)| {
+        *encryptor = Box::into_raw(Box::new(DobyEncryptor(EncryptWriter::new(CallbackWriter { write, user_data }, params, cipher))));
+    }))
+}
+
+/// Encrypts `len` bytes of `data`. The header is written on the first call.
+///
+/// # Safety
+///
+/// `encryptor` must come from `doby_encryptor_new` and not have been released. `data` must point to `len` bytes, or be NULL if empty.
+#[no_mangle]
+pub unsafe extern "C" fn doby_encryptor_write(encryptor: *mut DobyEncryptor, data: *const u8, len: usize) -> DobyStatus {
+    let (Some(encryptor), Some(data)) = (encryptor.as_mut(), input(data, len)) else {
+        return invalid_argument("encryptor or data");
+    };
+    status(encryptor.0.write_all(data).map_err(Error::from))
+}
+
+/// Writes the authentication data and releases the context, even if it fails. Without it, the ciphertext is incomplete.
+///
+/// # Safety
+///
+/// `encryptor` must come from `doby_encryptor_new` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn doby_encryptor_finish(encryptor: *mut DobyEncryptor) -> DobyStatus {
+    if encryptor.is_null() {
+        return invalid_argument("encryptor");
+    }
+    status(Box::from_raw(encryptor).0.finish().map(|_| ()))
+}
+
+/// Releases an encryption context without finishing the ciphertext. Does nothing if `encryptor` is NULL.
+///
+/// # Safety
+///
+/// `encryptor` must come from `doby_encryptor_new` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn doby_encryptor_free(encryptor: *mut DobyEncryptor) {
+    if !encryptor.is_null() {
+        drop(Box::from_raw(encryptor));
+    }
+}
+
+/// Creates a decryption context reading the ciphertext from `read`, with `user_data`. The header is read and the password checked before returning, when the format allows it.
+///
+/// # Safety
+///
+/// `password` must point to `password_len` bytes, or be NULL if empty. `decryptor` must be valid for writes. `read` must be safe to call with `user_data` until the context is released.
+#[no_mangle]
+pub unsafe extern "C" fn doby_decryptor_new(password: *const u8, password_len: usize, read: DobyReadFn, user_data: *mut c_void, decryptor: *mut *mut DobyDecryptor) -> DobyStatus {
+    let (Some(password), Some(read)) = (input(password, password_len), read) else {
+        return invalid_argument("password or read");
+    };
+    if decryptor.is_null() {
+        return invalid_argument("decryptor");
+    }
+    let mut reader = CallbackReader { read, user_data };
+    status(read_header(&mut reader).and_then(|params| DobyCipher::try_with_password(password, &params)).map(|cipher| {
+        *decryptor = Box::into_raw(Box::new(DobyDecryptor(DecryptReader::with_cipher(reader, cipher))));
+    }))
+}
+
+/// Decrypts up to `len` bytes into `buffer` and sets `*read_len` to their number, 0 meaning that the whole ciphertext has been read and authenticated.
+///
+/// Authentication happens at the end with the AES-CTR and XChaCha20 ciphers: until then, the plaintext must not be trusted.
+///
+/// # Safety
+///
+/// `decryptor` must come from `doby_decryptor_new` and not have been released. `buffer` must be valid for writes of `len` bytes, and `read_len` for writes.
+#[no_mangle]
+pub unsafe extern "C" fn doby_decryptor_read(decryptor: *mut DobyDecryptor, buffer: *mut u8, len: usize, read_len: *mut usize) -> DobyStatus {
+    let Some(decryptor) = decryptor.as_mut() else {
+        return invalid_argument("decryptor");
+    };
+    if buffer.is_null() || read_len.is_null() {
+        return invalid_argument("buffer or read_len");
+    }
+    status(decryptor.0.read(slice::from_raw_parts_mut(buffer, len)).map(|n| *read_len = n).map_err(Error::from))
+}
+
+/// Releases a decryption context. Does nothing if `decryptor` is NULL.
+///
+/// # Safety
+///
+/// `decryptor` must come from `doby_decryptor_new` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn doby_decryptor_free(decryptor: *mut DobyDecryptor) {
+    if !decryptor.is_null() {
+        drop(Box::from_raw(decryptor));
+    }
+}
@@ -0,0 +1,156 @@
+//! External programs streaming INPUT or OUTPUT, such as `curl` for URLs. They are started before the sandbox is applied, as no program can be run afterwards.
+
+use std::{io::{self, BufRead, Read, Write}, process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio}, sync::{Mutex, MutexGuard, PoisonError}};
+
+//process IDs of the unfinished ChildSinks and of their follow-ups, still reserved as they are only waited for once removed
+static SINKS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+fn sinks() -> MutexGuard<'static, Vec<u32>> {
+    SINKS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn untrack(child: &Child) {
+    let mut sinks = sinks();
+    if let Some(i) = sinks.iter().position(|id| *id == child.id()) {
+        sinks.swap_remove(i);
+    }
+}
+
+fn kill(mut child: Child) {
+    untrack(&child);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Kills the programs of all the `ChildSink`s not finished yet, e.g. from a signal handler exiting without dropping them: they would otherwise see the end of their input and complete, e.g. the upload of a truncated output.
+#[cfg(unix)]
+pub fn abort_child_sinks() {
+    for id in sinks().drain(..) {
+        //SAFETY: kill has no memory safety requirements
+        unsafe { libc::kill(id as libc::pid_t, libc::SIGKILL) };
+    }
+}
+
+#[cfg(not(unix))]
+pub fn abort_child_sinks() {}
+
+fn program(command: &Command) -> String {
+    command.get_program().to_string_lossy().into_owned()
+}
+
+fn spawn(command: &mut Command) -> io::Result<Child> {
+    command.spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("can't run {}: {}", program(command), e)))
+}
+
+fn check(program: &str, status: ExitStatus) -> io::Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("{} {}", program, status)))
+    }
+}
+
+/// Output of a program, read as it runs. Reaching the end fails if the program failed, so that a truncated output isn't mistaken for a complete one.
+pub struct ChildReader {
+    child: Child,
+    stdout: ChildStdout,
+    program: String,
+    done: bool,
+}
+
+impl ChildReader {
+    pub fn spawn(command: &mut Command) -> io::Result<Self> {
+        let mut child = spawn(command.stdin(Stdio::null()).stdout(Stdio::piped()))?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(Self { child, stdout, program: program(command), done: false })
+    }
+}
+
+impl Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() && !self.done {
+            self.done = true;
+            check(&self.program, self.child.wait()?)?;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for ChildReader {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Program consuming the data written to the `ChildStdin` returned by `ChildSink::spawn`. It only completes on `finish`: dropping an unfinished `ChildSink` kills the program, so that it doesn't take a truncated output for a complete one.
+pub struct ChildSink {
+    child: Option<Child>,
+    program: String,
+    follow_up: Option<Child>,
+}
+
+impl ChildSink {
+    pub fn spawn(command: &mut Command) -> io::Result<(Self, ChildStdin)> {
+        //what the program prints isn't part of the output
+        let mut child = spawn(command.stdin(Stdio::piped()).stdout(Stdio::null()))?;
+        let stdin = child.stdin.take().unwrap();
+        sinks().push(child.id());
+        Ok((Self { child: Some(child), program: program(command), follow_up: None }, stdin))
+    }
+
+    /// Also starts `command`, which reads a line from its stdin once the `ChildSink` is finished successfully. `finish` then waits for it to print a line, and leaves it running. If the `ChildSink` fails, it's killed.
+    pub fn with_follow_up(mut self, command: &mut Command) -> io::Result<Self> {
+        //it outlives doby, which a pipe reading the stderr of doby would wait for
+        let child = spawn(command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null()))?;
+        sinks().push(child.id());
+        self.follow_up = Some(child);
+        Ok(self)
+    }
+
+    /// Replaces the broken pipe error of writing to a program that gave up with its exit status, which tells more.
+    pub fn explain(&mut self, error: io::Error) -> io::Error {
+        match self.child.as_mut().map(Child::try_wait) {
+            Some(Ok(Some(status))) if error.kind() == io::ErrorKind::BrokenPipe => {
+                untrack(self.child.as_ref().unwrap());
+                self.child = None;
+                check(&self.program, status).err().unwrap_or(error)
+            }
+            _ => error,
+        }
+    }
+
+    /// Waits for the program to complete, then releases the follow-up. The `ChildStdin` must have been dropped before, to end the data.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.child.take() {
+            Some(mut child) => {
+                untrack(&child);
+                check(&self.program, child.wait()?)?;
+            }
+            //already failed, see explain
+            None => return Err(io::Error::new(io::ErrorKind::BrokenPipe, format!("{} exited early", self.program))),
+        }
+        if let Some(mut follow_up) = self.follow_up.take() {
+            untrack(&follow_up);
+            follow_up.stdin.take().unwrap().write_all(b"\n")?;
+            let mut line = Vec::new();
+            io::BufReader::new(follow_up.stdout.take().unwrap()).read_until(b'\n', &mut line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ChildSink {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.take() {
+            kill(child);
+        }
+        if let Some(follow_up) = self.follow_up.take() {
+            kill(follow_up);
+        }
+    }
+}
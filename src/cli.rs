@@ -1,191 +1,2711 @@
-use std::{fs::File, io::{self, Read, stdin, stdout}, path::Path, str::FromStr};
-use clap::{crate_name, crate_version, App, Arg, AppSettings};
-use crate::{WrappedWriter, WrappedPassword, crypto::CipherAlgorithm};
+use std::{env, fs::{self, File}, io::{self, BufReader, IsTerminal, Read, stdin, stdout}, path::Path, process, str::FromStr, sync::Arc};
+use clap::{crate_name, crate_version, App, Arg, ArgMatches, AppSettings, SubCommand};
+use crate::{ArchiveReader, DEFAULT_BLOCK_SIZE, DEFAULT_PARITY_SHARDS, Error, MAX_PARITY_SHARDS, NameTemplate, RateLimiter, is_same_file, is_url, recipient::{Identity, Recipient}, signature::{SigningKey, VerifyKey}, WrappedReader, WrappedWriter, crypto::{Argon2Limits, CipherAlgorithm, EncryptionParams, MacAlgorithm, KEY_LEN, MAX_ARGON2_MEMORY_COST, MAX_COMMENT_LEN, SALT_LEN}, memlock::Locked, openssl::DEFAULT_PBKDF2_ITERATIONS, pkcs11::Pkcs11Key, qr::QrFormat, ssh_agent::SshKey, tpm::TpmPolicy, auto_block_size, default_cipher, read_header};
+use log::LevelFilter;
+use regex::bytes::{Regex, RegexBuilder};
+use rand::{RngCore, rngs::OsRng};
+use zeroize::Zeroizing;
+use crate::{config::Config, keys::{self, KeyKind}, report, sandbox::Sandbox};
+pub use crate::crypto::{ARGON2_PROFILES, DEFAULT_ARGON2_PROFILE};
+use crate::crypto::decode_hex;
 
-cpufeatures::new!(aes_ni, "aes");
+/// Source of the salts of the outputs: `OsRng`, unless a salt was given with the hidden `--test-salt-hex` option (`test-salt` feature) to write reproducible test vectors.
+#[derive(Default)]
+pub struct SaltRng(Option<[u8; SALT_LEN]>);
+
+impl RngCore for SaltRng {
+    fn next_u32(&mut self) -> u32 {
+        OsRng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        OsRng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self.0 {
+            Some(salt) if dest.len() == SALT_LEN => dest.copy_from_slice(&salt),
+            _ => OsRng.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+//like sudo, which gives 3 tries
+const DEFAULT_RETRIES: u32 = 2;
+
+/// Password given on the command line, or prompted for. It is locked in memory (see `memlock`).
+#[derive(Default)]
+pub struct WrappedPassword(Option<Locked<String>>);
+
+impl WrappedPassword {
+    /// Whether the password was given on the command line, i.e. `get` won't prompt.
+    pub fn is_provided(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn get(self, ask_confirm: bool) -> Result<Locked<String>, Error> {
+        self.get_with_prompt("Password", ask_confirm)
+    }
+
+    /// Same as `get` but `name` is used in the prompts instead of "Password".
+    pub fn get_with_prompt(self, name: &str, ask_confirm: bool) -> Result<Locked<String>, Error> {
+        match self.0 {
+            Some(password) => Ok(password),
+            None => {
+                let password = Locked::new(rpassword::read_password_from_tty(Some(&format!("{}: ", name)))?);
+                if ask_confirm {
+                    let password_confirm = Locked::new(rpassword::read_password_from_tty(Some(&format!("{} (confirm): ", name)))?);
+                    if *password == *password_confirm {
+                        Ok(password)
+                    } else {
+                        Err(Error::PasswordMismatch)
+                    }
+                } else {
+                    Ok(password)
+                }
+            }
+        }
+    }
+}
+
+impl From<Option<&str>> for WrappedPassword {
+    fn from(s: Option<&str>) -> Self {
+        Self(s.map(|s| Locked::new(String::from(s))))
+    }
+}
+
+impl From<Option<String>> for WrappedPassword {
+    fn from(s: Option<String>) -> Self {
+        Self(s.map(Locked::new))
+    }
+}
+
+impl From<Option<Locked<String>>> for WrappedPassword {
+    fn from(password: Option<Locked<String>>) -> Self {
+        Self(password)
+    }
+}
+
+/// Plaintext source to delete after a successful encryption.
+pub struct RemoveInput {
+    pub path: String,
+    pub shred: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    /// Decrypt if the input is in doby format, encrypt otherwise.
+    Auto,
+    Encrypt,
+    Decrypt,
+    /// Re-encrypt a file in place with a new password.
+    Rekey,
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum Command {
+    /// Encrypt, decrypt or rekey.
+    Run(CliArgs),
+    Inspect {
+        path: String,
+        json: bool,
+        /// Plaintext to check against the digest stored in `path` (`--verify-digest`).
+        verify_digest: Option<String>,
+    },
+    /// Encrypt several files.
+    Batch(BatchArgs),
+    /// Encrypt the files appearing in a directory until interrupted.
+    Watch(WatchArgs),
+    /// Encrypt a directory tree file by file into another one, or restore it.
+    Mirror(MirrorArgs),
+    /// Serve encryption and decryption requests on a Unix socket until interrupted.
+    Serve(ServeArgs),
+    /// Send a file to `doby receive`.
+    Send(SendArgs),
+    /// Receive a file from `doby send`.
+    Receive(ReceiveArgs),
+    /// Encrypt or decrypt `input` with the `doby serve` listening on `socket`. Stdin and stdout are used if `input` and `output` are `None`.
+    Client {
+        socket: String,
+        decrypt: bool,
+        input: Option<String>,
+        output: Option<String>,
+    },
+    /// Copy the header of `input` to `output`, or to stdout if `None`.
+    HeaderBackup {
+        input: String,
+        output: Option<String>,
+    },
+    /// Write the header stored in `backup` over the header of `file`.
+    HeaderRestore {
+        backup: String,
+        file: String,
+        /// Restore even if the backup seems to belong to another file.
+        force: bool,
+    },
+    /// Decrypt a file, open it in the editor and encrypt it back.
+    Edit(EditArgs),
+    /// Re-encrypt the files of directory trees with a new password.
+    Rotate(RotateArgs),
+    /// Decrypt files to stdout.
+    Cat(CatArgs),
+    /// Decrypt files in memory and print their lines matching a pattern.
+    Grep(GrepArgs),
+    /// Rebuild the damaged shards of a file written with `--ecc`.
+    Repair {
+        path: String,
+    },
+    /// Encrypt files into a container.
+    Pack(ContainerArgs),
+    /// Append files to a container.
+    Add(ContainerArgs),
+    /// List the entries of a container.
+    List(ContainerArgs),
+    /// Decrypt entries of a container.
+    Extract(ContainerArgs),
+    /// Generate an X25519 identity, or an Ed25519 signing key if `sign` is set, written to stdout if `output` is `None`.
+    Keygen {
+        output: Option<String>,
+        sign: bool,
+    },
+    /// Generate a key stored as `name`, protected with `passphrase` if `Some`.
+    KeyGenerate {
+        name: String,
+        sign: bool,
+        passphrase: Option<WrappedPassword>,
+    },
+    /// Generate a symmetric key stored as `name`, protected with `passphrase` if `Some`.
+    KeyAdd {
+        name: String,
+        passphrase: Option<WrappedPassword>,
+    },
+    /// List the stored keys.
+    KeyList,
+    /// Print the public key of the stored key `name`, or its file if `secret` is set, to `output` or stdout if `None`.
+    KeyExport {
+        name: String,
+        secret: bool,
+        output: Option<String>,
+    },
+    /// Store the key file `file` as `name`, protecting it with `passphrase` if `Some`.
+    KeyImport {
+        name: String,
+        file: String,
+        passphrase: Option<WrappedPassword>,
+    },
+    /// List the `doby-plugin-*` programs found in PATH.
+    Plugins,
+    /// Measure the ciphers, Argon2 and block sizes on this machine.
+    Bench(BenchArgs),
+    /// Run the known-answer tests, or print them as JSON if `export` is set.
+    SelfTest {
+        export: bool,
+    },
+}
+
+/// Key slots wrapping the master key of the outputs, besides the one of the password.
+pub struct KeySlotOptions {
+    /// Argon2 parameters of the password key slots, or of the password when it is the only key.
+    pub argon2_params: argon2::Params,
+    /// Normalize the passwords to NFC (unless `--no-nfc`).
+    pub nfc: bool,
+    /// Repeated `--password` (or `--new-password` when rekeying) values. Each one gets its own key slot.
+    pub additional_passwords: Vec<String>,
+    /// Each one gets its own key slot.
+    pub recipients: Vec<Recipient>,
+    /// Each one gets its own key slot. Decrypting uses ssh-agent without being asked.
+    pub ssh_keys: Vec<SshKey>,
+    /// Gets its own key slot when encrypting, used to open it when decrypting.
+    pub pkcs11: Option<Pkcs11Key>,
+    /// PCRs the master key is sealed to in an additional TPM key slot (`--tpm`). TPM key slots are opened without being asked.
+    pub tpm: Option<TpmPolicy>,
+}
+
+/// How the outputs are encrypted, besides their key slots.
+pub struct EncryptionOptions {
+    pub cipher: CipherAlgorithm,
+    pub mac: MacAlgorithm,
+    /// KMS key generating and encrypting the master key (`--kms-key-id`).
+    pub kms_key_id: Option<String>,
+    /// YubiKey slot whose response is mixed with the password (`--yubikey`).
+    pub yubikey: Option<u8>,
+}
 
 pub struct CliArgs {
     pub password: WrappedPassword,
+    /// Number of times to prompt again for a wrong password (`--retries`).
+    pub retries: u32,
+    /// Only used by `Mode::Rekey`.
+    pub new_password: WrappedPassword,
+    /// Used when encrypting or rekeying. The PKCS#11 key also opens its key slot when decrypting.
+    pub key_slots: KeySlotOptions,
+    /// Used to decrypt files encrypted to recipients.
+    pub identities: Vec<Identity>,
+    /// Used as master key instead of any password or key slot.
+    pub raw_key: Option<Zeroizing<[u8; KEY_LEN]>>,
+    pub mode: Mode,
     pub force_encrypt: bool,
+    pub recursive: bool,
+    pub armor: bool,
+    /// Render the armored ciphertext as QR codes (`--qr`).
+    pub qr: Option<QrFormat>,
+    /// Number of parity shards per group when writing error correction data (`--ecc`).
+    pub ecc: Option<u8>,
+    pub remove_input: Option<RemoveInput>,
+    /// Commit the output file to disk before succeeding (`--fsync`, implied by `remove_input`).
+    pub fsync: bool,
+    /// Encrypt a memory map of INPUT in place instead of reading it, if it's a regular file.
+    pub mmap: bool,
+    /// Only encrypt the data of INPUT and the position of its holes, if it's a sparse file (unless `--no-sparse`).
+    pub sparse: bool,
+    /// Save the progress of the encryption to continue it if interrupted (`--resume`).
+    pub resume: bool,
+    /// Applied once the keys are ready and the output is open.
+    pub sandbox: Sandbox,
+    pub verify_first: bool,
+    /// Keep the output of a failed decryption instead of deleting it.
+    pub keep_unverified: bool,
+    /// Store the input file attributes when encrypting, restore them when decrypting.
+    pub preserve: bool,
+    /// File name of INPUT to store in the ciphertext (`--store-name`).
+    pub store_name: Option<String>,
+    /// Directory in which to write the file name stored in the ciphertext (`--restore-name`).
+    pub restore_name: Option<String>,
+    pub interactive: bool,
+    /// `--format tar`: the plaintext must be a tar stream.
+    pub tar: bool,
+    /// Unencrypted label stored in the header.
+    pub comment: Option<String>,
+    /// Store the digest of the plaintext in the header when encrypting (`--digest`).
+    pub digest: bool,
+    pub salt_rng: SaltRng,
+    /// Signs the output when encrypting or rekeying.
+    pub sign_key: Option<SigningKey>,
+    /// Required signer of INPUT when decrypting or rekeying.
+    pub verify_key: Option<VerifyKey>,
+    /// Where to write the header instead of the beginning of the output.
+    pub detach_header: Option<String>,
+    /// Header read from a separate file, INPUT then only contains the ciphertext.
+    pub header: Option<EncryptionParams>,
+    /// PBKDF2 iterations used to decrypt `openssl enc` inputs.
+    pub openssl_iterations: u32,
+    pub progress: bool,
+    /// Print the sizes, timings and throughput on completion (`--stats`).
+    pub stats: bool,
+    /// Used when encrypting or rekeying.
+    pub encryption: EncryptionOptions,
+    /// Checked against the header of INPUT when decrypting or rekeying.
+    pub argon2_limits: Argon2Limits,
+    /// `--block-size`, or chosen according to INPUT by `auto_block_size`.
+    pub block_size: usize,
+    pub threads: usize,
+    /// `--rate-limit`, applied to INPUT.
+    pub rate_limit: Option<Arc<RateLimiter>>,
+    /// Path of INPUT, or `None` for stdin.
+    pub input: Option<String>,
+    pub reader: WrappedReader,
+    pub writer: WrappedWriter<String>,
+}
+
+/// Options of the `bench` subcommand.
+pub struct BenchArgs {
+    /// File used to measure the block sizes, or `None` to generate `size` random bytes in the current directory.
+    pub file: Option<String>,
+    pub size: u64,
+    /// Argon2 costs whose duration is measured.
     pub argon2_params: argon2::Params,
+    /// Store the fastest cipher and block size in the configuration file.
+    pub write_config: bool,
+}
+
+/// Options of the `batch` subcommand.
+pub struct BatchArgs {
+    pub inputs: Vec<String>,
+    pub decrypt: bool,
+    /// Where outputs are written instead of next to their input.
+    pub output_dir: Option<String>,
+    /// Output file names when encrypting, stripped from the input file names when decrypting.
+    pub name_template: NameTemplate,
+    pub password: WrappedPassword,
+    /// Used when encrypting.
+    pub key_slots: KeySlotOptions,
+    pub identities: Vec<Identity>,
+    pub raw_key: Option<Zeroizing<[u8; KEY_LEN]>>,
+    pub force_encrypt: bool,
+    pub interactive: bool,
+    pub armor: bool,
+    pub ecc: Option<u8>,
+    pub preserve: bool,
+    pub store_name: bool,
+    pub comment: Option<String>,
+    pub digest: bool,
+    pub sign_key: Option<SigningKey>,
+    pub verify_key: Option<VerifyKey>,
+    /// Delete each input once encrypted (`--rm` or `--shred`).
+    pub remove_inputs: bool,
+    pub shred: bool,
+    /// Commit the output files to disk (`--fsync`, implied by `remove_inputs`).
+    pub fsync: bool,
+    /// Encrypt memory maps of the inputs in place instead of reading them.
+    pub mmap: bool,
+    /// Only encrypt the data and the position of the holes of sparse inputs.
+    pub sparse: bool,
+    /// Used when encrypting.
+    pub encryption: EncryptionOptions,
+    /// Inputs exceeding them fail without asking, as they are decrypted concurrently.
+    pub argon2_limits: Argon2Limits,
+    /// `None` to choose it for each input with `auto_block_size`.
+    pub block_size: Option<usize>,
+    pub threads: usize,
+    /// `--rate-limit`, shared by all the inputs.
+    pub rate_limit: Option<Arc<RateLimiter>>,
+    /// Number of inputs processed at the same time.
+    pub jobs: usize,
+}
+
+/// Options of the `watch` subcommand.
+pub struct WatchArgs {
+    /// Directory whose new files are encrypted.
+    pub dir: String,
+    /// Where the originals are moved once encrypted, instead of being deleted.
+    pub move_to: Option<String>,
+    /// Encryption options. `inputs` is empty and `output_dir` is set.
+    pub batch: BatchArgs,
+}
+
+/// Options of the `mirror` subcommand.
+pub struct MirrorArgs {
+    /// Directory tree to encrypt, or mirror to restore with `decrypt`.
+    pub src: String,
+    /// Mirror to create or update, or directory in which the tree is restored with `decrypt`.
+    pub dst: String,
+    pub decrypt: bool,
+    /// Name the files of a new mirror after a keyed hash of their path.
+    pub encrypt_names: bool,
+    /// Keys and encryption options. `inputs` is empty, and outputs are replaced without asking.
+    pub batch: BatchArgs,
+}
+
+/// Options of the `serve` subcommand.
+pub struct ServeArgs {
+    /// Path of the Unix socket to listen on.
+    pub socket: String,
+    /// Keys and encryption options. `inputs` is empty.
+    pub batch: BatchArgs,
+}
+
+/// Options of the `send` subcommand.
+pub struct SendArgs {
+    /// `None` for stdin, whose data is sent without a name.
+    pub input: Option<String>,
+    pub listen: String,
+    /// Generated if `None`.
+    pub code: Option<String>,
     pub cipher: CipherAlgorithm,
+    pub mac: MacAlgorithm,
+    pub block_size: Option<usize>,
+}
+
+/// Options of the `receive` subcommand.
+pub struct ReceiveArgs {
+    pub address: String,
+    /// Prompted for if not given.
+    pub code: WrappedPassword,
+    /// `None` to write the file under the name it was sent with, in the current directory.
+    pub output: Option<WrappedWriter<String>>,
+    pub interactive: bool,
+    /// Replace an existing file with the name the file was sent with, without asking (`--force`).
+    pub force: bool,
+    pub fsync: bool,
+}
+
+/// Options of the `edit` subcommand. The file is encrypted back with its own key and header options, so only what is needed to open it is given.
+pub struct EditArgs {
+    pub path: String,
+    pub password: WrappedPassword,
+    pub retries: u32,
+    pub identities: Vec<Identity>,
+    pub pkcs11: Option<Pkcs11Key>,
+    pub raw_key: Option<Zeroizing<[u8; KEY_LEN]>>,
+    /// Required to edit a signed file, which is signed again.
+    pub sign_key: Option<SigningKey>,
+    pub verify_key: Option<VerifyKey>,
+    pub argon2_limits: Argon2Limits,
     pub block_size: usize,
-    pub reader: Box<dyn Read>,
-    pub writer: WrappedWriter<String>,
 }
 
-pub struct ParseResult {
-    pub error: bool,
-    pub cli_args: Option<CliArgs>,
+/// Options of the `rotate` subcommand. Each file keeps its cipher, MAC, comment, digest and armor, so only the passwords, the Argon2 costs of the new password and what is needed to check or renew signatures are given.
+pub struct RotateArgs {
+    /// Directories walked for files ending with `.doby`.
+    pub dirs: Vec<String>,
+    /// Only report what would be done.
+    pub dry_run: bool,
+    pub old_password: WrappedPassword,
+    /// Only used to recognize the files already rotated with `dry_run`, where it isn't asked for.
+    pub new_password: WrappedPassword,
+    /// Normalize the new password to NFC.
+    pub nfc: bool,
+    /// Required to rotate signed files, which are signed again.
+    pub sign_key: Option<SigningKey>,
+    pub verify_key: Option<VerifyKey>,
+    pub argon2_params: argon2::Params,
+    /// Files exceeding them fail without asking.
+    pub argon2_limits: Argon2Limits,
+    /// `None` to choose it for each file with `auto_block_size`.
+    pub block_size: Option<usize>,
 }
 
-impl ParseResult {
-    fn exited() -> Self {
-        Self { error: false, cli_args: None }
-    }
+/// Options of the `cat` subcommand, also used by `grep` to open its files.
+pub struct CatArgs {
+    pub inputs: Vec<String>,
+    pub password: WrappedPassword,
+    pub identities: Vec<Identity>,
+    pub pkcs11: Option<Pkcs11Key>,
+    pub raw_key: Option<Zeroizing<[u8; KEY_LEN]>>,
+    pub verify_key: Option<VerifyKey>,
+    pub argon2_limits: Argon2Limits,
+    /// `None` to choose it for each input with `auto_block_size`.
+    pub block_size: Option<usize>,
+    pub threads: usize,
+    /// Write plaintexts to a terminal as they are, even binary or containing control characters.
+    pub raw: bool,
 }
 
-impl From<CliArgs> for ParseResult {
-    fn from(args: CliArgs) -> Self {
-        ParseResult { error: false, cli_args: Some(args) }
-    }
+/// Options of the `grep` subcommand.
+pub struct GrepArgs {
+    pub pattern: Regex,
+    /// Select the lines that don't match instead.
+    pub invert: bool,
+    pub line_number: bool,
+    /// Only print the paths of the files with a selected line.
+    pub files_with_matches: bool,
+    /// Only print the number of selected lines of each file.
+    pub count: bool,
+    pub files: CatArgs,
 }
 
-pub fn app<'a>() -> App<'a, 'a> {
-    App::new(crate_name!())
-        .version(crate_version!())
-        .setting(AppSettings::ColoredHelp)
-        .about("Secure symmetric encryption from the command line.")
+/// Options of the `pack`, `list` and `extract` subcommands.
+pub struct ContainerArgs {
+    pub archive: String,
+    /// Files and directories to pack or add, or entries to extract (all of them if empty).
+    pub paths: Vec<String>,
+    /// Where entries are extracted.
+    pub output_dir: String,
+    pub password: WrappedPassword,
+    pub retries: u32,
+    /// Used when packing.
+    pub key_slots: KeySlotOptions,
+    pub identities: Vec<Identity>,
+    pub raw_key: Option<Zeroizing<[u8; KEY_LEN]>>,
+    pub interactive: bool,
+    /// Restore the modification time and permissions of extracted files.
+    pub preserve: bool,
+    /// Commit the container, or the extracted files, to disk.
+    pub fsync: bool,
+    pub comment: Option<String>,
+    /// Used when packing.
+    pub encryption: EncryptionOptions,
+    pub argon2_limits: Argon2Limits,
+    pub block_size: usize,
+}
+
+/// INPUT and OUTPUT, accepted by the top-level command and the `encrypt`/`decrypt` subcommands.
+fn with_positionals<'a>(app: App<'a, 'a>) -> App<'a, 'a> {
+    app
         .arg(Arg::with_name("INPUT").help("<PATH> | \"-\" or empty for stdin"))
         .arg(Arg::with_name("OUTPUT").help("<PATH> | \"-\" or empty for stdout"))
+}
+
+#[cfg(feature = "yubikey")]
+fn with_yubikey<'a>(app: App<'a, 'a>) -> App<'a, 'a> {
+    app.arg(
+        Arg::with_name("yubikey")
+            .global(true)
+            .long("yubikey")
+            .value_name("slot")
+            .min_values(0)
+            .require_equals(true)
+            .possible_values(&["1", "2"])
+            .help("Also require the response of a YubiKey to encrypt and decrypt (slot 2 by default)")
+            .long_help("Send a challenge derived from the Argon2 output to the HMAC-SHA1 challenge-response slot of a YubiKey, and mix its response into the key derivation, so that decrypting requires both the password and the token. The slot (2 by default) is stored in the header: decrypting doesn't need this option. Requires ykchalresp from yubikey-personalization.")
+    )
+}
+
+#[cfg(not(feature = "yubikey"))]
+fn with_yubikey<'a>(app: App<'a, 'a>) -> App<'a, 'a> {
+    app
+}
+
+#[cfg(feature = "kms")]
+fn with_kms<'a>(app: App<'a, 'a>) -> App<'a, 'a> {
+    app.arg(
+        Arg::with_name("kms_key_id")
+            .global(true)
+            .long("kms-key-id")
+            .value_name("key id")
+            .conflicts_with_all(&["5_key_hex", "6_key", "6_key_file_raw", "yubikey"])
+            .help("Have the master key generated and encrypted by an AWS KMS key")
+            .long_help("Have the master key generated by AWS KMS with the key given as ID, ARN or alias, and store it encrypted in the header, so that only principals allowed to use this KMS key can decrypt. Decrypting doesn't need this option. Can be combined with passwords and recipients, which then also get key slots. Requires the AWS CLI (aws), configured as usual.")
+    )
+}
+
+#[cfg(not(feature = "kms"))]
+fn with_kms<'a>(app: App<'a, 'a>) -> App<'a, 'a> {
+    app
+}
+
+#[cfg(feature = "test-salt")]
+fn with_test_salt<'a>(app: App<'a, 'a>) -> App<'a, 'a> {
+    app.arg(
+        Arg::with_name("test_salt_hex")
+            .global(true)
+            .long("test-salt-hex")
+            .value_name("hex")
+            .hidden(true)
+            .help("Use this 64 bytes hexadecimal salt instead of a random one, to write test vectors")
+    )
+}
+
+#[cfg(not(feature = "test-salt"))]
+fn with_test_salt<'a>(app: App<'a, 'a>) -> App<'a, 'a> {
+    app
+}
+
+/// Options are global so that they can be passed before or after the subcommand.
+fn with_options<'a>(app: App<'a, 'a>) -> App<'a, 'a> {
+    with_test_salt(with_kms(with_yubikey(app)))
         .arg(
             Arg::with_name("1_force_encrypt")
+                .global(true)
                 .short("f")
                 .long("force-encrypt")
                 .help(concat!("Encrypt even if ", crate_name!(), " format is recognized"))
         )
+        .arg(
+            Arg::with_name("1_recursive")
+                .global(true)
+                .short("r")
+                .long("recursive")
+                .help("Encrypt a whole directory, or decrypt one into OUTPUT")
+                .long_help("If INPUT is a directory, encrypt its whole tree (paths, permissions, file contents and symbolic links) into a single ciphertext. Otherwise, decrypt INPUT and recreate the tree in the OUTPUT directory.")
+        )
+        .arg(
+            Arg::with_name("1_armor")
+                .global(true)
+                .short("a")
+                .long("armor")
+                .help("Output base64 text between BEGIN/END markers")
+                .long_help("Encode the ciphertext in base64 between BEGIN/END markers so that it can be pasted as text. Armored inputs are detected automatically when decrypting.")
+        )
+        .arg(
+            Arg::with_name("ecc")
+                .global(true)
+                .long("ecc")
+                .help("Add Reed-Solomon parity data to repair corruption")
+                .long_help("Add Reed-Solomon parity data to the output so that bit rot (e.g. on archival media) can be repaired before the ciphertext is authenticated. The output is cut in groups of 16 shards of 4KiB, each followed by --ecc-parity parity shards: a group can be recovered as long as no more of its shards are damaged. Such files are detected automatically when decrypting, and can be fixed in place with the repair subcommand.")
+                .conflicts_with("1_armor")
+        )
+        .arg(
+            Arg::with_name("ecc_parity")
+                .global(true)
+                .long("ecc-parity")
+                .value_name("shards")
+                .help("Number of parity shards per 16 data shards with --ecc [default: 2]")
+                .requires("ecc")
+        )
         .arg(
             Arg::with_name("2_interactive")
+                .global(true)
                 .short("i")
                 .long("interactive")
                 .help("Prompt before overwriting files")
         )
+        .arg(
+            Arg::with_name("3_verify_first")
+                .global(true)
+                .long("verify-first")
+                .help("Don't output anything before the whole ciphertext is authenticated")
+                .long_help("Authenticate the whole ciphertext before writing any plaintext. Non-seekable inputs (like stdin) are first copied to a temporary file.")
+        )
+        .arg(
+            Arg::with_name("7_keep_unverified")
+                .global(true)
+                .long("keep-unverified")
+                .help("Keep the output as OUTPUT.unverified if authentication fails")
+                .long_help("If authentication fails while decrypting to a file, the output is moved to OUTPUT.unverified instead of being deleted, or to OUTPUT.unverified.1, OUTPUT.unverified.2... if it already exists. Its content must not be trusted.")
+        )
+        .arg(
+            Arg::with_name("7_no_sandbox")
+                .global(true)
+                .long("no-sandbox")
+                .help("Don't restrict file, network and process access once the keys are ready")
+                .long_help(concat!("Don't sandbox ", crate_name!(), " while it encrypts or decrypts a file. By default, once the input and output are open and the keys derived, only the directories of the output files can still be written to (and the input directory with --rm), nothing else can be read, and network access and program execution are denied, using Landlock and seccomp on Linux or unveil and pledge on OpenBSD. Batch mode and containers aren't sandboxed."))
+        )
+        .arg(
+            Arg::with_name("8_preserve")
+                .global(true)
+                .long("preserve")
+                .help("Store INPUT's modification time and permissions, and restore them when decrypting")
+                .long_help("When encrypting, store the modification time and permissions of INPUT in the ciphertext, where they are encrypted and authenticated like the content. When decrypting, restore them on OUTPUT.")
+        )
+        .arg(
+            Arg::with_name("8_store_name")
+                .global(true)
+                .long("store-name")
+                .help("Store INPUT's file name in the ciphertext")
+                .long_help("Store the file name of INPUT in the ciphertext, where it is encrypted and authenticated like the content, so that it can be restored with --restore-name.")
+        )
+        .arg(
+            Arg::with_name("8_restore_name")
+                .global(true)
+                .long("restore-name")
+                .help("Decrypt to the file name stored in the ciphertext, inside the OUTPUT directory")
+                .long_help("Decrypt to the file name stored with --store-name, inside the OUTPUT directory or the current directory if OUTPUT is omitted.")
+        )
+        .arg(
+            Arg::with_name("4_progress")
+                .global(true)
+                .long("progress")
+                .help("Show bytes processed, throughput and ETA on stderr")
+        )
+        .arg(
+            Arg::with_name("4_verbose")
+                .global(true)
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .help("Also print debug messages on stderr (repeat for more)")
+                .long_help("Also print timestamped debug messages on stderr: chosen algorithms, Argon2 parameters and duration, header size, number of blocks processed... Useful to find out why an operation is slow or fails. Repeat the flag for even more messages.")
+        )
+        .arg(
+            Arg::with_name("4_quiet")
+                .global(true)
+                .short("q")
+                .long("quiet")
+                .conflicts_with("4_verbose")
+                .help("Only print errors on stderr")
+        )
+        .arg(
+            Arg::with_name("4_json")
+                .global(true)
+                .long("json")
+                .conflicts_with("4_progress")
+                .help("Report results, warnings and errors as JSON lines on stderr")
+                .long_help("Instead of human-readable messages, write one JSON object per line on stderr: a \"result\" event for each processed file (operation, input, output, bytes written, verification status), \"warning\" events, and a final \"error\" event with a stable error kind if the command fails. With inspect, the parameters are printed as a JSON object on stdout.")
+        )
+        .arg(
+            Arg::with_name("4_stats")
+                .long("stats")
+                .help("Print the amount of data processed, timings and throughput on completion")
+                .long_help("Once done, print the number of bytes read and written, the total time, the time spent deriving keys with Argon2 and the time spent encrypting/decrypting, and the resulting throughput. Useful when tuning the Argon2 costs and the block size. With --json, they are reported in a \"stats\" event.")
+        )
+        .arg(
+            Arg::with_name("5_fsync")
+                .global(true)
+                .long("fsync")
+                .help("Commit OUTPUT to disk before reporting success (implied by --rm and --shred)")
+                .long_help("Flush OUTPUT and commit it to disk, along with its parent directory, before reporting success, so that it survives a power failure. This is always done with --rm and --shred, which only delete INPUT afterwards.")
+        )
+        .arg(
+            Arg::with_name("5_mmap")
+                .global(true)
+                .long("mmap")
+                .help("Map INPUT in memory instead of reading it when encrypting")
+                .long_help("When encrypting a regular file, map it in memory instead of reading it, which saves the read calls and a copy per block. The cipher must be aes-gcm or xchacha20-poly1305 (the default with this option): stream ciphers encrypt in place, so they would have to copy the mapped plaintext anyway. INPUT must not be truncated meanwhile: doby would be killed by SIGBUS. Pipes, stdin, sparse files and --format tar are read as usual, and --threads doesn't apply.")
+        )
+        .arg(
+            Arg::with_name("5_no_sparse")
+                .global(true)
+                .long("no-sparse")
+                .help("Encrypt the holes of sparse files as zeros")
+                .long_help("By default, when encrypting a regular file with holes (ranges of zeros not stored on disk, as in disk images), only its data is read and encrypted, along with the position of the holes. Decryption then recreates them, or writes zeros when OUTPUT isn't a file. With this option, holes are read and encrypted as zeros like any other data, so that the ciphertext can be decrypted by versions of doby without sparse file support. Sparse files are always read as zeros with --digest, --format tar and --resume.")
+        )
+        .arg(
+            Arg::with_name("5_resume")
+                .long("resume")
+                .help("Continue an interrupted encryption of INPUT to OUTPUT instead of restarting it")
+                .long_help("Save the progress of the encryption every second in OUTPUT.resume, after committing what was written to disk, and keep the partial output in its temporary file if doby is interrupted. Running the same command again then asks for the password of the partial output and continues where the last progress was saved, unless INPUT changed meanwhile. INPUT and OUTPUT must be files, and the cipher aes-gcm or xchacha20-poly1305 (the default with this option).")
+        )
+        .arg(
+            Arg::with_name("5_clipboard_in")
+                .long("clipboard-in")
+                .help("Read INPUT from the clipboard")
+                .long_help("Encrypt or decrypt the content of the clipboard instead of INPUT, which must be omitted or \"-\", e.g. to encrypt a password without writing it to a file. Uses wl-paste on Wayland, xclip on X11 and pbpaste on macOS.")
+        )
+        .arg(
+            Arg::with_name("5_clipboard_out")
+                .long("clipboard-out")
+                .value_name("seconds")
+                .min_values(0)
+                .require_equals(true)
+                .help("Copy OUTPUT to the clipboard, and clear it after 45 seconds by default")
+                .long_help("Copy the output to the clipboard instead of writing it to OUTPUT, which must be omitted or \"-\". The clipboard is only replaced once the whole output has been written and authenticated, then cleared after the given number of seconds (45 by default, 0 to keep it) by a background process, unless something else was copied meanwhile. Uses wl-copy on Wayland, xclip on X11 and pbcopy on macOS.")
+        )
+        .arg(
+            Arg::with_name("5_qr")
+                .long("qr")
+                .value_name("format")
+                .possible_values(&["text", "png"])
+                .min_values(0)
+                .require_equals(true)
+                .conflicts_with("ecc")
+                .help("Output the armored ciphertext as QR codes, on the terminal by default")
+                .long_help("Render the armored ciphertext as QR codes for paper backups of keys and short secrets: as text for a terminal (the default), or as a PNG image with --qr=png. Ciphertexts longer than about 900 characters once armored are split in several codes, up to 64KiB in total. To decrypt, give doby the text scanned from all the codes in any order, e.g. the output of zbarimg.")
+        )
+        .arg(
+            Arg::with_name("5_rm")
+                .global(true)
+                .long("rm")
+                .help("Delete INPUT after successful encryption")
+                .long_help("Delete INPUT once the ciphertext has been fully written and committed to disk. OUTPUT must be a file.")
+        )
+        .arg(
+            Arg::with_name("6_shred")
+                .global(true)
+                .long("shred")
+                .conflicts_with("5_rm")
+                .help("Overwrite INPUT with random data then delete it after successful encryption")
+                .long_help("Same as --rm, but INPUT is first overwritten with random data. This is not reliable on copy-on-write filesystems, SSDs or if backups/snapshots exist.")
+        )
         .arg(
             Arg::with_name("1_password")
+                .global(true)
                 .long("password")
                 .value_name("password")
-                .help("Password used to derive encryption keys")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Password used to derive encryption keys (can be repeated to add key slots)")
+        )
+        .arg(
+            Arg::with_name("1_password_env")
+                .global(true)
+                .long("password-env")
+                .value_name("variable")
+                .conflicts_with_all(&["1_password", "1_password_file"])
+                .help("Read the password from an environment variable")
+        )
+        .arg(
+            Arg::with_name("1_password_file")
+                .global(true)
+                .long("password-file")
+                .value_name("file")
+                .conflicts_with_all(&["1_password", "1_password_fd"])
+                .help("Read the password from the first line of a file")
+        )
+        .arg(
+            Arg::with_name("1_password_fd")
+                .global(true)
+                .long("password-fd")
+                .value_name("fd")
+                .conflicts_with_all(&["1_password", "1_password_env"])
+                .help("Read the password from the first line of an inherited file descriptor")
+        )
+        .arg(
+            Arg::with_name("1_password_command")
+                .global(true)
+                .long("password-command")
+                .value_name("command")
+                .conflicts_with_all(&["1_password", "1_password_env", "1_password_file", "1_password_fd"])
+                .help("Run a shell command and use the first line of its output as password")
+        )
+        .arg(
+            Arg::with_name("1_no_nfc")
+                .global(true)
+                .long("no-nfc")
+                .help("Don't normalize passwords to Unicode NFC when encrypting")
+                .long_help("By default, non-ASCII passwords are normalized to Unicode NFC before deriving keys when encrypting, so that the same characters typed on different systems (e.g. an accented letter on macOS and on Linux) give the same key. The header records it, and decryption follows what the header says whatever this option. Use this option to derive keys from the password bytes as typed, like versions of doby without normalization.")
+        )
+        .arg(
+            Arg::with_name("1_retries")
+                .global(true)
+                .long("retries")
+                .value_name("n")
+                .help("Prompt again up to n times if the password typed is wrong [default: 2]")
+                .long_help("When decrypting, if the password typed at the prompt is wrong (which can only be detected early in files with a key check value or key slots), prompt for it again up to n times instead of exiting, like sudo. Passwords that aren't typed, or read while stdin isn't a terminal, are never retried. 0 disables retrying. [default: 2]")
+        )
+        .arg(
+            Arg::with_name("2_new_password")
+                .global(true)
+                .long("new-password")
+                .value_name("password")
+                .multiple(true)
+                .number_of_values(1)
+                .help("New password when using rekey (can be repeated to add key slots)")
+        )
+        .arg(
+            Arg::with_name("3_recipient")
+                .global(true)
+                .short("R")
+                .long("recipient")
+                .value_name("public key")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Encrypt to an X25519 public key instead of a password (can be repeated)")
+                .long_help("Encrypt to an X25519 public key (doby-pk-...) instead of a password. Recipients starting with doby-plugin-pk-<name>: are handled by the doby-plugin-<name> program found in PATH. Can be repeated.")
+        )
+        .arg(
+            Arg::with_name("4_identity")
+                .global(true)
+                .short("I")
+                .long("identity")
+                .value_name("file")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Identity file used to decrypt files encrypted to recipients (can be repeated)")
+        )
+        .arg(
+            Arg::with_name("3_ssh_key")
+                .global(true)
+                .long("ssh-key")
+                .value_name("public key file")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Encrypt to an SSH key held in ssh-agent (can be repeated)")
+                .long_help("Encrypt to an SSH key held in ssh-agent, given as an OpenSSH public key file (e.g. ~/.ssh/id_ed25519.pub). The key is derived from the signature of a random challenge stored in the header, so the agent must hold the key when encrypting too. Only Ed25519 and RSA keys can be used, as their signatures are deterministic. When decrypting, the agent is used automatically, including when it is forwarded. Can be repeated, and combined with passwords and recipients.")
+        )
+        .arg(
+            Arg::with_name("4_pkcs11_key")
+                .global(true)
+                .long("pkcs11-key")
+                .value_name("key ID")
+                .requires("4_pkcs11_module")
+                .help("Encrypt to, or decrypt with, an RSA key pair stored in a PKCS#11 token")
+                .long_help("Encrypt the file key to the public key of an RSA key pair stored in a PKCS#11 token (smartcard, HSM), identified by its hexadecimal CKA_ID. When decrypting, the token decrypts it after asking for its PIN. Can be combined with passwords and recipients. Requires pkcs11-tool from OpenSC.")
+        )
+        .arg(
+            Arg::with_name("4_pkcs11_module")
+                .global(true)
+                .long("pkcs11-module")
+                .value_name("library")
+                .requires("4_pkcs11_key")
+                .help("PKCS#11 module (shared library) giving access to the token of --pkcs11-key")
+        )
+        .arg(
+            Arg::with_name("4_tpm")
+                .global(true)
+                .long("tpm")
+                .value_name("PCRs")
+                .min_values(0)
+                .require_equals(true)
+                .help("Also seal the file key with the TPM of this machine (PCRs 0,7 by default)")
+                .long_help("Add a key slot sealing the file key with the TPM 2.0 of this machine, bound to the current values of the given PCRs of the sha256 bank (0,7 by default: firmware and Secure Boot state). When decrypting on this machine in the same boot configuration, the slot is opened automatically, without this option. The password or other key slots remain usable, e.g. after a firmware update. Requires tpm2-tools.")
+        )
+        .arg(
+            Arg::with_name("5_key_hex")
+                .global(true)
+                .long("key-hex")
+                .value_name("hex")
+                .conflicts_with_all(&["1_password", "1_password_env", "1_password_file", "1_password_fd", "1_password_command", "2_new_password", "3_recipient", "3_ssh_key", "4_identity", "4_pkcs11_key", "4_tpm", "6_key", "6_key_file_raw"])
+                .help("Use a 32 bytes hexadecimal key directly instead of a password")
+                .long_help("Use a 32 bytes hexadecimal key directly instead of a password. No key derivation function is applied, so the key must be uniformly random.")
+        )
+        .arg(
+            Arg::with_name("6_key_file_raw")
+                .global(true)
+                .long("key-file-raw")
+                .value_name("file")
+                .conflicts_with_all(&["1_password", "1_password_env", "1_password_file", "1_password_fd", "1_password_command", "2_new_password", "3_recipient", "3_ssh_key", "4_identity", "4_pkcs11_key", "4_tpm"])
+                .help("Same as --key-hex but read the 32 bytes of the key from a file")
+        )
+        .arg(
+            Arg::with_name("6_key")
+                .global(true)
+                .long("key")
+                .value_name("label")
+                .conflicts_with_all(&["1_password", "1_password_env", "1_password_file", "1_password_fd", "1_password_command", "2_new_password", "3_recipient", "3_ssh_key", "4_identity", "4_pkcs11_key", "4_tpm", "6_key_file_raw"])
+                .help("Use the symmetric key stored as LABEL by key add as raw key")
+                .long_help("Use the symmetric key stored as LABEL by key add as raw key. When decrypting, the stored key matching the key ID in the header is used instead if LABEL doesn't match it, so that files encrypted before a key rotation can still be decrypted by the same command.")
+        )
+        .arg(
+            Arg::with_name("1_profile")
+                .global(true)
+                .long("profile")
+                .value_name("profile")
+                .help("Argon2 costs preset")
+                .long_help("Argon2 costs preset: \"fast\" is suited for scripting, \"paranoid\" uses 512MB of memory and is much slower. Individual costs can still be set with -t, -m and -p.")
+                .possible_values(&["fast", "balanced", "paranoid"])
+                .default_value(DEFAULT_ARGON2_PROFILE)
         )
         .arg(
             Arg::with_name("2_t_cost")
+                .global(true)
                 .short("t")
                 .long("time-cost")
                 .value_name("iterations")
-                .help("Argon2 time cost")
-                .default_value("10")
+                .help("Argon2 time cost (overrides --profile)")
         )
         .arg(
             Arg::with_name("3_m_cost")
+                .global(true)
                 .short("m")
                 .long("memory-cost")
                 .value_name("memory size")
-                .help("Argon2 memory cost (in kilobytes)")
-                .default_value("4096")
+                .help("Argon2 memory cost (in kilobytes) (overrides --profile)")
         )
         .arg(
             Arg::with_name("4_p_cost")
+                .global(true)
                 .short("p")
                 .long("parallelism")
                 .value_name("threads")
-                .help("Argon2 parallelism cost")
-                .default_value("4")
+                .help("Argon2 parallelism cost (overrides --profile)")
+        )
+        .arg(
+            Arg::with_name("5_max_t_cost")
+                .global(true)
+                .long("max-time-cost")
+                .value_name("iterations")
+                .help("Largest Argon2 time cost accepted from INPUT without asking")
+                .long_help("Largest Argon2 time cost accepted from INPUT without asking. The costs are chosen by whoever encrypted the file, so a malicious one could make decryption run for hours. If they are higher, doby asks whether to continue when stdin is a terminal, and fails otherwise.")
+                .default_value("100")
+        )
+        .arg(
+            Arg::with_name("6_max_m_cost")
+                .global(true)
+                .long("max-memory-cost")
+                .value_name("memory size")
+                .help("Largest Argon2 memory cost (in kilobytes) accepted from INPUT without asking")
+                .long_help("Largest Argon2 memory cost (in kilobytes) accepted from INPUT without asking. The costs are chosen by whoever encrypted the file, so a malicious one could make decryption use all the memory of the machine. If they are higher, doby asks whether to continue when stdin is a terminal, and fails otherwise. Memory costs above 4GiB are always rejected.")
+                .default_value("1048576")
         )
         .arg(
             Arg::with_name("blocksize")
+                .global(true)
                 .short("b")
                 .long("block-size")
-                .help("Size of the I/O buffer (in bytes)")
-                .default_value("65536")
+                .value_name("blocksize")
+                .help("Size of the I/O buffer (in bytes) [default: chosen according to INPUT]")
+                .long_help("Size of the I/O buffer (in bytes). By default, it is chosen according to INPUT: files up to 1MiB are read at once, files of 64MiB or more stored on SSDs get 4MiB buffers, and other inputs 64KiB ones. With aes and xchacha20, buffers of 256KiB or more are encrypted using all the CPU cores.")
+        )
+        .arg(
+            Arg::with_name("threads")
+                .global(true)
+                .long("threads")
+                .value_name("threads")
+                .help("Number of threads used to encrypt/decrypt")
+                .long_help("Number of threads used to encrypt/decrypt. With 2 or more, reading, encryption/decryption and writing are performed concurrently, and large blocks are encrypted/decrypted by this many threads.")
+                .default_value("1")
+        )
+        .arg(
+            Arg::with_name("rate_limit")
+                .global(true)
+                .long("rate-limit")
+                .value_name("rate")
+                .help("Read INPUT at most this many bytes per second (with an optional K, M or G suffix)")
+                .long_help("Read INPUT at most this many bytes per second, with an optional K, M or G suffix (e.g. 50M), so that encrypting large backups in the background doesn't starve other workloads of disk bandwidth. With batch, the limit is shared by all the inputs processed at the same time.")
         )
         .arg(
             Arg::with_name("cipher")
+                .global(true)
                 .short("c")
                 .long("cipher")
                 .value_name("cipher")
                 .help("Encryption cipher to use")
                 .long_help("Encryption cipher to use. By default, AES is selected if AES-NI is supported. Otherwise, XChaCha20 is used.")
-                .possible_values(&["aes", "xchacha20"])
+                .possible_values(&["aes", "aes-gcm", "xchacha20", "xchacha20-poly1305"])
                 .case_insensitive(true)
         )
+        .arg(
+            Arg::with_name("mac")
+                .global(true)
+                .long("mac")
+                .value_name("hash")
+                .help("Hash function used to authenticate the ciphertext of aes and xchacha20")
+                .long_help("Hash function used to authenticate the ciphertext of aes and xchacha20. BLAKE3 is faster, especially with large block sizes where it uses several threads. AEAD ciphers don't need it.")
+                .possible_values(&["blake2b", "blake3"])
+                .case_insensitive(true)
+                .default_value("blake2b")
+        )
+        .arg(
+            Arg::with_name("detach_header")
+                .global(true)
+                .long("detach-header")
+                .value_name("file")
+                .help("Write the header to a separate file, leaving only random-looking data in OUTPUT")
+                .long_help("Write the header (salt, parameters and key slots) to a separate file instead of the beginning of OUTPUT. OUTPUT then can't be told apart from random data, and can't be decrypted without the header file, which must be given with --header.")
+                .conflicts_with("header")
+        )
+        .arg(
+            Arg::with_name("header")
+                .global(true)
+                .long("header")
+                .value_name("file")
+                .help("Read the header from a file written with --detach-header, and decrypt INPUT")
+        )
+        .arg(
+            Arg::with_name("openssl_iter")
+                .global(true)
+                .long("openssl-iter")
+                .value_name("iterations")
+                .help("PBKDF2 iterations of an OpenSSL input (openssl enc -iter) [default: 10000]")
+                .long_help("Number of PBKDF2 iterations used to encrypt an input written by openssl enc -aes-256-cbc -pbkdf2, i.e. its -iter value (10000 by default, like openssl).")
+        )
+        .arg(
+            Arg::with_name("format")
+                .global(true)
+                .long("format")
+                .value_name("format")
+                .help("Check that the plaintext is in this format")
+                .long_help("Check that the plaintext is in this format, e.g. to encrypt the output of tar c from stdin or decrypt to the input of tar x. doby fails if INPUT (when encrypting) or the decrypted data doesn't start with a valid tar header, and warns when a decrypted archive would be written to the terminal.")
+                .possible_values(&["tar"])
+                .conflicts_with("1_recursive")
+        )
+        .arg(
+            Arg::with_name("comment")
+                .global(true)
+                .long("comment")
+                .value_name("comment")
+                .help("Unencrypted label stored in the header, shown by inspect")
+                .long_help("Short unencrypted label (up to 255 bytes) stored in the header, where it can be read by inspect without the password. It is authenticated like the rest of the header, so it can't be changed without the decryption failing. rekey keeps the existing comment unless a new one is given.")
+        )
+        .arg(
+            Arg::with_name("digest")
+                .global(true)
+                .long("digest")
+                .help("Store a digest of the plaintext in the header, checked after decryption")
+                .long_help("Store a keyed BLAKE2b digest of the plaintext in the header. Decryption then checks that the decrypted data is exactly what was encrypted, and inspect --verify-digest can check a plaintext against the file without the password. As the header isn't encrypted, anyone holding a candidate plaintext can check it too. INPUT is read twice, so it must be a regular file. rekey keeps the existing digest.")
+        )
+        .arg(
+            Arg::with_name("sign_key")
+                .global(true)
+                .long("sign-key")
+                .value_name("file")
+                .help("Sign the output with the Ed25519 key of this file (see keygen --sign)")
+                .long_help("Sign the output with the Ed25519 key of this file, generated with keygen --sign. The signature covers the header and the whole ciphertext, so that files can't be forged by someone who only knows the password. The public key is stored in the header.")
+        )
+        .arg(
+            Arg::with_name("verify_key")
+                .global(true)
+                .long("verify-key")
+                .value_name("public key")
+                .help("Fail to decrypt INPUT unless it was signed by this Ed25519 public key")
+                .long_help("Fail to decrypt INPUT unless it was signed by this Ed25519 public key, as printed by keygen --sign. The signature of a signed file is always checked, but without --verify-key, anyone could have made it.")
+        )
 }
 
-pub fn parse() -> Option<ParseResult> {
-    let app = app().get_matches();
-
-    let params = {
-        let t_cost = number(app.value_of("2_t_cost").unwrap())?;
-        let m_cost =  number(app.value_of("3_m_cost").unwrap())?;
-        let p_cost =  number(app.value_of("4_p_cost").unwrap())?;
+pub fn app<'a>() -> App<'a, 'a> {
+    with_positionals(with_options(
+        App::new(crate_name!())
+            .version(crate_version!())
+            .setting(AppSettings::ColoredHelp)
+            .about("Secure symmetric encryption from the command line.")
+            .after_help("Without subcommand, the operation is chosen according to whether INPUT is in doby format.")
+    ))
+        .arg(
+            Arg::with_name("1_decrypt")
+                .short("d")
+                .long("decrypt")
+                .conflicts_with("1_force_encrypt")
+                .help("Decrypt, and fail if INPUT isn't in doby format")
+        )
+        .subcommand(with_positionals(
+            SubCommand::with_name("encrypt")
+                .setting(AppSettings::ColoredHelp)
+                .about("Encrypt INPUT. Fails if INPUT is already in doby format, unless -f is given")
+        ))
+        .subcommand(with_positionals(
+            SubCommand::with_name("decrypt")
+                .setting(AppSettings::ColoredHelp)
+                .about("Decrypt INPUT. Fails if INPUT isn't in doby format")
+        ))
+        .subcommand(
+            SubCommand::with_name("rekey")
+                .setting(AppSettings::ColoredHelp)
+                .about("Re-encrypt INPUT with a new password, without writing the plaintext anywhere")
+                .long_about("Re-encrypt INPUT with a new password, without writing the plaintext anywhere. The new ciphertext uses the encryption options given on the command line and atomically replaces INPUT once fully written.")
+                .arg(Arg::with_name("INPUT").required(true).help("<PATH>"))
+        )
+        .subcommand(
+            SubCommand::with_name("edit")
+                .setting(AppSettings::ColoredHelp)
+                .about("Decrypt FILE, open it in $EDITOR and encrypt it back once the editor exits")
+                .long_about("Decrypt FILE to a private temporary directory, on a RAM-backed filesystem ($XDG_RUNTIME_DIR or /dev/shm) when there is one, open it in $VISUAL or $EDITOR (vi by default) and wait for the editor to exit. If the plaintext was changed, it's encrypted back with the same key, cipher and header options (key slots, comment, metadata, armor) and atomically replaces FILE. The temporary directory is then removed, along with any file the editor left in it.")
+                .arg(Arg::with_name("FILE").required(true).help("<PATH>"))
+        )
+        .subcommand(
+            SubCommand::with_name("rotate")
+                .setting(AppSettings::ColoredHelp)
+                .about("Re-encrypt the files ending with .doby found in each DIR with a new password")
+                .long_about("Walk each DIR recursively, without following symbolic links, and re-encrypt the files ending with .doby that are encrypted with the old password only, as after a password compromise. A new random key, wrapped with the new password and shared by all the files (each one keeping its own salt), replaces their key, so nothing the old password gave access to opens them anymore. Each file keeps its cipher, MAC, comment, digest and armor, the Argon2 costs of the new password being taken from the command line, and atomically replaces the original once fully written and authenticated. The plaintext is never written anywhere. Files with other key slots (recipients, SSH keys, several passwords...), written with --ecc or --qr, and those already opening with the new password are skipped. A summary is printed at the end, and the command fails if any file couldn't be rotated.")
+                .arg(Arg::with_name("DIR").multiple(true).required(true).help("<PATH>..."))
+                .arg(
+                    Arg::with_name("old_password_file")
+                        .long("old-password-file")
+                        .value_name("file")
+                        .conflicts_with_all(&["1_password", "1_password_env", "1_password_file", "1_password_fd", "1_password_command"])
+                        .help("Read the old password from the first line of a file")
+                        .long_help("Read the old password from the first line of a file. The other password options (--password, --password-file...) can be used instead, and it is prompted for otherwise.")
+                )
+                .arg(
+                    Arg::with_name("new_password_file")
+                        .long("new-password-file")
+                        .value_name("file")
+                        .conflicts_with("2_new_password")
+                        .help("Read the new password from the first line of a file")
+                        .long_help("Read the new password from the first line of a file. --new-password can be used instead, and it is prompted for otherwise.")
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Only check which files would be rotated, without changing anything")
+                        .long_help("Only check which files would be rotated, without changing anything. The old password is still checked against each file. The new password isn't asked for: if given, it's used to recognize the files already rotated.")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .setting(AppSettings::ColoredHelp)
+                .about("Decrypt each FILE to stdout")
+                .long_about("Decrypt each FILE to stdout, one after the other. When stdout is a terminal, each plaintext is authenticated in memory before being printed, binary plaintexts are refused and control characters (which could rewrite what is shown or send commands to the terminal) are escaped, unless --raw is given. Key slots shared by several files, such as those of the outputs of batch and watch, are only opened once.")
+                .arg(Arg::with_name("FILE").multiple(true).required(true).help("<PATH>..."))
+                .arg(
+                    Arg::with_name("raw")
+                        .long("raw")
+                        .help("Print plaintexts to a terminal as they are, even binary")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("grep")
+                .setting(AppSettings::ColoredHelp)
+                .about("Decrypt each FILE in memory and print the lines matching PATTERN")
+                .long_about("Decrypt each FILE in memory, without writing the plaintext anywhere, and print the lines matching the regular expression PATTERN once the file has been authenticated. Lines are prefixed with the path of their file when several FILEs are given. Binary files only get a \"Binary file FILE matches\" line. Fails if no line matches. Key slots shared by several files, such as those of the outputs of batch and watch, are only opened once.")
+                .arg(Arg::with_name("PATTERN").required(true).help("<REGEX>"))
+                .arg(Arg::with_name("FILE").multiple(true).required(true).help("<PATH>..."))
+                .arg(
+                    Arg::with_name("fixed_strings")
+                        .short("F")
+                        .long("fixed-strings")
+                        .help("Search PATTERN as a plain string instead of a regular expression")
+                )
+                .arg(
+                    Arg::with_name("ignore_case")
+                        .long("ignore-case")
+                        .help("Ignore case distinctions")
+                )
+                .arg(
+                    Arg::with_name("invert_match")
+                        .long("invert-match")
+                        .help("Select the lines that don't match")
+                )
+                .arg(
+                    Arg::with_name("line_number")
+                        .short("n")
+                        .long("line-number")
+                        .help("Prefix lines with their line number")
+                )
+                .arg(
+                    Arg::with_name("files_with_matches")
+                        .short("l")
+                        .long("files-with-matches")
+                        .conflicts_with("count")
+                        .help("Only print the paths of the files with a selected line")
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .help("Only print the number of selected lines of each file")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .setting(AppSettings::ColoredHelp)
+                .about("Encrypt each INPUT to <INPUT>.doby, or decrypt them with -d")
+                .long_about("Encrypt each INPUT to <INPUT>.doby, or decrypt them with -d. A random key is wrapped once in key slots shared by all the outputs, so Argon2 only runs once per password. Each output still gets its own salt, from which its encryption keys are derived.")
+                .arg(Arg::with_name("INPUT").multiple(true).required_unless("files_from").help("<PATH>..."))
+                .arg(
+                    Arg::with_name("decrypt")
+                        .short("d")
+                        .long("decrypt")
+                        .conflicts_with("1_force_encrypt")
+                        .help("Decrypt each INPUT, removing the suffix from its name")
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .value_name("jobs")
+                        .help("Number of files processed at the same time")
+                        .default_value("1")
+                )
+                .arg(
+                    Arg::with_name("output_dir")
+                        .long("output-dir")
+                        .value_name("dir")
+                        .help("Write outputs in this directory instead of next to their input")
+                )
+                .arg(
+                    Arg::with_name("suffix")
+                        .long("suffix")
+                        .value_name("suffix")
+                        .help("Suffix added to encrypted file names [default: .doby]")
+                )
+                .arg(
+                    Arg::with_name("name_template")
+                        .long("name-template")
+                        .value_name("template")
+                        .conflicts_with("suffix")
+                        .help("Name of encrypted files, like \"{name}.{ext}.doby\"")
+                        .long_help("Name of encrypted files: {file} is replaced by the input file name, {name} by the file name without its extension and {ext} by the extension. When decrypting, the text around the placeholders is removed from the input file name.")
+                )
+                .arg(
+                    Arg::with_name("files_from")
+                        .long("files-from")
+                        .value_name("file")
+                        .help("Read INPUT paths from a file (\"-\" for stdin)")
+                        .long_help("Read INPUT paths from a file, or from stdin if \"-\". Paths are separated by NUL characters (like the output of find -print0), or by newlines if there is no NUL character.")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .setting(AppSettings::ColoredHelp)
+                .about("Encrypt the files appearing in DIR, then delete or move them, until interrupted")
+                .long_about("Watch DIR and encrypt each file written or moved into it to the --output-dir directory, then delete it (or shred it with --shred, or move it with --move-to). Files already in DIR are encrypted first. Subdirectories, symbolic links and hidden files (whose name starts with a dot, like the temporary files of many programs) are ignored: to drop a file, write it under a hidden name then rename it. Like batch, a random key is wrapped once in key slots shared by all the outputs, so Argon2 only runs once per password. Runs until interrupted with SIGINT or SIGTERM.")
+                .arg(Arg::with_name("DIR").required(true).help("<PATH>"))
+                .arg(
+                    Arg::with_name("output_dir")
+                        .long("output-dir")
+                        .value_name("dir")
+                        .required(true)
+                        .help("Directory in which encrypted files are written")
+                )
+                .arg(
+                    Arg::with_name("move_to")
+                        .long("move-to")
+                        .value_name("dir")
+                        .help("Move the originals to this directory once encrypted instead of deleting them")
+                )
+                .arg(
+                    Arg::with_name("suffix")
+                        .long("suffix")
+                        .value_name("suffix")
+                        .help("Suffix added to encrypted file names [default: .doby]")
+                )
+                .arg(
+                    Arg::with_name("name_template")
+                        .long("name-template")
+                        .value_name("template")
+                        .conflicts_with("suffix")
+                        .help("Name of encrypted files, like \"{name}.{ext}.doby\"")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("mirror")
+                .setting(AppSettings::ColoredHelp)
+                .about("Encrypt each file of SRC into the same tree in DST, or restore it with -d")
+                .long_about("Encrypt each file of the directory tree SRC to <DST>/<path>.doby, so that only the files that changed have to be synced or backed up. With --encrypt-names, files are named after a keyed hash of their path instead, which is stored in the file. Like batch, a random key is wrapped once in key slots shared by all the files. It is kept in DST/.doby-mirror along with the settings of the mirror and a manifest of the size, modification time and digest of each file, and reused by the next runs, which must be able to open it (with the password, an identity, etc.) and only encrypt the files that changed. Symbolic links aren't followed, and files already in DST are replaced. With -d, the mirror SRC is restored into DST.")
+                .arg(Arg::with_name("SRC").required(true).help("<PATH>"))
+                .arg(Arg::with_name("DST").required(true).help("<PATH>"))
+                .arg(
+                    Arg::with_name("decrypt")
+                        .short("d")
+                        .long("decrypt")
+                        .help("Restore the mirror SRC into DST")
+                )
+                .arg(
+                    Arg::with_name("encrypt_names")
+                        .long("encrypt-names")
+                        .conflicts_with("decrypt")
+                        .help("Hide the file names and the directory structure of a new mirror")
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .value_name("jobs")
+                        .help("Number of files processed at the same time")
+                        .default_value("1")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .setting(AppSettings::ColoredHelp)
+                .about("Keep the keys in memory and encrypt or decrypt the data sent to a Unix socket, until interrupted")
+                .long_about("Listen on the Unix socket --socket (only accessible to its owner) and encrypt or decrypt the data sent by doby client, so that scripts neither pay for Argon2 on each file nor pass passwords on command lines. Like batch, a random key is wrapped once in key slots shared by all the outputs, so Argon2 only runs once per password, at startup. The key slots of the files to decrypt are opened with the password, --identity or --pkcs11-key given at startup, and their keys are kept in locked memory, so that files sharing their key slots (such as the outputs of batch, watch and serve) only cost one Argon2. Requests are held in memory, and limited to 1GiB. Runs until interrupted with SIGINT or SIGTERM.")
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .value_name("path")
+                        .required(true)
+                        .help("Path of the Unix socket to listen on")
+                )
+        )
+        .subcommand(with_positionals(
+            SubCommand::with_name("client")
+                .setting(AppSettings::ColoredHelp)
+                .about("Encrypt or decrypt INPUT with doby serve")
+                .long_about("Send INPUT to the doby serve listening on --socket and write the data it encrypted (or decrypted with -d) to OUTPUT. No password or key is needed: those of the server are used.")
+                .arg(
+                    Arg::with_name("socket")
+                        .long("socket")
+                        .value_name("path")
+                        .required(true)
+                        .help("Path of the Unix socket doby serve listens on")
+                )
+                .arg(
+                    Arg::with_name("decrypt")
+                        .short("d")
+                        .long("decrypt")
+                        .help("Decrypt INPUT instead of encrypting it")
+                )
+        ))
+        .subcommand(
+            SubCommand::with_name("send")
+                .setting(AppSettings::ColoredHelp)
+                .about("Send INPUT to doby receive over the network, with a short code instead of a password")
+                .long_about("Listen on --listen and wait for doby receive. A short code, to give to the receiving user, is printed on stderr, or set with --code. Both sides establish a key from it (CPace key exchange): someone who doesn't know the code can neither read nor alter the transfer, and only gets a single guess as doby send gives up after a failed key exchange. INPUT is then encrypted with this key and streamed in doby format with its file name. Exits once the receiver confirmed that it received and authenticated the whole file.")
+                .arg(Arg::with_name("INPUT").help("<PATH> | \"-\" or empty for stdin"))
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .value_name("address")
+                        .default_value("0.0.0.0:0")
+                        .help("Address and port to listen on (a random port by default)")
+                )
+                .arg(
+                    Arg::with_name("code")
+                        .long("code")
+                        .value_name("code")
+                        .help("Code to use instead of a random one")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("receive")
+                .setting(AppSettings::ColoredHelp)
+                .about("Receive a file from doby send")
+                .long_about("Connect to the doby send listening on ADDRESS (host:port), establish a key from the code it printed (asked for if --code isn't given), and decrypt the file it sends to OUTPUT, or to the name it was sent with in the current directory if OUTPUT is omitted. The output is only written once the whole file has been authenticated.")
+                .arg(Arg::with_name("ADDRESS").required(true).help("<HOST:PORT>"))
+                .arg(Arg::with_name("OUTPUT").help("<PATH> | \"-\" for stdout"))
+                .arg(
+                    Arg::with_name("code")
+                        .long("code")
+                        .value_name("code")
+                        .help("Code printed by doby send")
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Replace an existing file with the name the file was sent with")
+                        .long_help("When OUTPUT is omitted, replace an existing file with the name the file was sent with. Without this option, doby asks first if stdin is a terminal, and fails otherwise, as the name is chosen by the sender.")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("pack")
+                .setting(AppSettings::ColoredHelp)
+                .about("Encrypt files into a container whose entries can be listed and extracted separately")
+                .long_about("Encrypt files into ARCHIVE, directories being walked recursively. Each file is encrypted separately, with its path, modification time and permissions, and followed by an encrypted index so that entries can be listed and extracted without decrypting the whole container. Like batch, a random key is wrapped once in key slots shared by all the entries.")
+                .arg(Arg::with_name("ARCHIVE").required(true).help("<PATH>"))
+                .arg(Arg::with_name("INPUT").multiple(true).required(true).help("<PATH>..."))
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .setting(AppSettings::ColoredHelp)
+                .about("Append files to a container written by pack")
+                .long_about("Append files to ARCHIVE, directories being walked recursively, followed by a new index. Existing entries are neither decrypted nor rewritten, and new ones are encrypted with the key and cipher of the container, so the password (or an identity) is needed. If anything fails, ARCHIVE is truncated back to its previous content.")
+                .arg(Arg::with_name("ARCHIVE").required(true).help("<PATH>"))
+                .arg(Arg::with_name("INPUT").multiple(true).required(true).help("<PATH>..."))
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .setting(AppSettings::ColoredHelp)
+                .about("List the entries of a container written by pack")
+                .long_about("Print the size and path of each entry of a container written by pack. Paths are encrypted, so the password is needed, but only the index is decrypted.")
+                .arg(Arg::with_name("ARCHIVE").required(true).help("<PATH>"))
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .setting(AppSettings::ColoredHelp)
+                .about("Decrypt entries of a container written by pack")
+                .long_about("Decrypt the entries of ARCHIVE matching the given paths (a directory path matches all the entries inside it), or all of them if none is given. Only these entries are read and decrypted.")
+                .arg(Arg::with_name("ARCHIVE").required(true).help("<PATH>"))
+                .arg(Arg::with_name("PATH").multiple(true).help("<PATH>... | empty for all entries"))
+                .arg(
+                    Arg::with_name("output_dir")
+                        .long("output-dir")
+                        .value_name("dir")
+                        .help("Directory in which entries are extracted")
+                        .default_value(".")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .setting(AppSettings::ColoredHelp)
+                .about("Generate an X25519 identity to receive files encrypted with --recipient")
+                .long_about("Generate an X25519 identity to receive files encrypted with --recipient, or an Ed25519 signing key for --sign-key with --sign. The key is written to OUTPUT (which must not exist) or to stdout, and its public key is printed.")
+                .arg(Arg::with_name("OUTPUT").help("<PATH> | empty for stdout"))
+                .arg(
+                    Arg::with_name("sign")
+                        .long("sign")
+                        .help("Generate an Ed25519 signing key instead")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("key")
+                .setting(AppSettings::ColoredHelp)
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .about("Manage the identities, signing keys and symmetric keys stored in ~/.config/doby/keys")
+                .long_about("Manage the identities, signing keys and symmetric keys stored in $XDG_CONFIG_HOME/doby/keys (~/.config/doby/keys by default). Stored keys can be given by name to --identity, --sign-key, --recipient, --verify-key and --key. Private key files can be protected with a passphrase, asked for when they are used (or read from DOBY_KEY_PASSPHRASE).")
+                .subcommand(
+                    SubCommand::with_name("generate")
+                        .setting(AppSettings::ColoredHelp)
+                        .about("Generate an identity stored as NAME")
+                        .long_about("Generate an X25519 identity stored as NAME, or an Ed25519 signing key with --sign, and print its public key.")
+                        .arg(Arg::with_name("NAME").required(true).help("<NAME>"))
+                        .arg(
+                            Arg::with_name("sign")
+                                .long("sign")
+                                .help("Generate an Ed25519 signing key instead")
+                        )
+                        .arg(
+                            Arg::with_name("protect")
+                                .long("protect")
+                                .help("Encrypt the private key file with a passphrase")
+                                .long_help("Encrypt the private key file with a passphrase, given with the password options or prompted for.")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .setting(AppSettings::ColoredHelp)
+                        .about("Generate a symmetric key stored as NAME")
+                        .long_about("Generate a random 32 bytes symmetric key stored as NAME, to be used with --key NAME, and print its key ID. Files encrypted with it record this ID, so that they can be decrypted after the key used by --key NAME has been replaced (e.g. by adding backup-2025 and switching scripts to it, or by re-adding NAME after renaming the old key file).")
+                        .arg(Arg::with_name("NAME").required(true).help("<NAME>"))
+                        .arg(
+                            Arg::with_name("protect")
+                                .long("protect")
+                                .help("Encrypt the key file with a passphrase")
+                                .long_help("Encrypt the key file with a passphrase, given with the password options or prompted for.")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .setting(AppSettings::ColoredHelp)
+                        .about("List the stored keys")
+                        .long_about("List the stored keys with their kind and public key. The public key of protected keys is shown without asking for their passphrase.")
+                )
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .setting(AppSettings::ColoredHelp)
+                        .about("Print the public key of NAME")
+                        .long_about("Print the public key of NAME, or its private key file with --secret (still encrypted if the key is protected), to OUTPUT (which must not exist) or to stdout.")
+                        .arg(Arg::with_name("NAME").required(true).help("<NAME>"))
+                        .arg(Arg::with_name("OUTPUT").help("<PATH> | empty for stdout"))
+                        .arg(
+                            Arg::with_name("secret")
+                                .long("secret")
+                                .help("Export the private key file")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .setting(AppSettings::ColoredHelp)
+                        .about("Store the key file FILE as NAME")
+                        .long_about("Store the identity or signing key file FILE (as written by keygen, or protected by key export --secret) as NAME.")
+                        .arg(Arg::with_name("NAME").required(true).help("<NAME>"))
+                        .arg(Arg::with_name("FILE").required(true).help("<PATH>"))
+                        .arg(
+                            Arg::with_name("protect")
+                                .long("protect")
+                                .help("Encrypt the private key file with a passphrase")
+                                .long_help("Encrypt the private key file with a passphrase, given with the password options or prompted for. Files that are already protected are stored as is.")
+                        )
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("plugins")
+                .setting(AppSettings::ColoredHelp)
+                .about("List the plugins found in PATH")
+                .long_about("List the doby-plugin-<name> programs found in PATH. Plugins handle the recipients and identities starting with doby-plugin-pk-<name>: and doby-plugin-sk-<name>:, so that keys held elsewhere (TPM, secure enclave, custom KMS...) can be used with --recipient and --identity.")
+        )
+        .subcommand(
+            SubCommand::with_name("header")
+                .setting(AppSettings::ColoredHelp)
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .about("Back up or restore the header of an encrypted file")
+                .long_about("Back up or restore the header of an encrypted file. A damaged header (salt, parameters and key slots) makes a file impossible to decrypt, even with the right password.")
+                .subcommand(
+                    SubCommand::with_name("backup")
+                        .setting(AppSettings::ColoredHelp)
+                        .about("Copy the header of INPUT to OUTPUT")
+                        .long_about("Copy the header of INPUT to OUTPUT, or to stdout if omitted. The backup can also be used with --header to decrypt INPUT.")
+                        .arg(Arg::with_name("INPUT").required(true).help("<PATH>"))
+                        .arg(Arg::with_name("OUTPUT").help("<PATH> | \"-\" or empty for stdout"))
+                )
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .setting(AppSettings::ColoredHelp)
+                        .about("Write the header stored in BACKUP over the header of FILE")
+                        .long_about("Write the header stored in BACKUP over the header of FILE. To avoid restoring the header of another file, doby refuses to do it if most of the salt of FILE differs from BACKUP, unless --force is given.")
+                        .arg(Arg::with_name("BACKUP").required(true).help("<PATH>"))
+                        .arg(Arg::with_name("FILE").required(true).help("<PATH>"))
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .help("Restore even if BACKUP seems to belong to another file")
+                        )
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .setting(AppSettings::ColoredHelp)
+                .about("Repair a file written with --ecc")
+                .long_about("Rebuild the damaged shards of a file written with --ecc from its parity data, and rewrite it in place. The file is left untouched if no damage is found.")
+                .arg(Arg::with_name("FILE").required(true).help("<PATH>"))
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .setting(AppSettings::ColoredHelp)
+                .about("Print the public parameters of an encrypted file")
+                .long_about("Print the public parameters of an encrypted file: format version, file size, salt fingerprint, Argon2 parameters and cipher. No password is needed.")
+                .arg(Arg::with_name("INPUT").required(true).help("<PATH>"))
+                .arg(
+                    Arg::with_name("verify_digest")
+                        .long("verify-digest")
+                        .value_name("plaintext")
+                        .help("Check a plaintext against the digest stored with --digest")
+                        .long_help("Check whether this file is the plaintext of INPUT, using the digest stored in the header with --digest. No password is needed. doby fails if the file doesn't match, or if INPUT has no digest.")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .setting(AppSettings::ColoredHelp)
+                .about("Measure the speed of the ciphers, Argon2 and block sizes on this machine")
+                .long_about("Measure the throughput of each cipher in memory, the time taken by Argon2 with the current costs (see --profile), and the block size encrypting and decrypting a file the fastest. Files are written next to the input file, or in the current directory with --size, so run it on the disk where your files are usually encrypted.")
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .value_name("path")
+                        .conflicts_with("size")
+                        .help("Measure with this file instead of random data")
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .value_name("size")
+                        .help("Size of the random data written to the current directory, with an optional K, M or G suffix [default: 64M]")
+                )
+                .arg(
+                    Arg::with_name("write_config")
+                        .long("write-config")
+                        .help("Store the fastest cipher and block size in the configuration file")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("selftest")
+                .setting(AppSettings::ColoredHelp)
+                .about("Check this build of doby against known-answer tests")
+                .long_about("Encrypt and decrypt fixed plaintexts with fixed keys and salts, for each cipher, and compare the results with the expected ciphertexts and MACs. Run it to validate doby on a new platform or compiler: it fails if any test gives a different result.")
+                .arg(
+                    Arg::with_name("export")
+                        .long("export")
+                        .help("Print the test vectors as JSON instead")
+                        .long_help("Print the test vectors as JSON, one per line, instead of running them: key or password, Argon2 parameters, salt, plaintext, whole encrypted file and MAC, in hexadecimal. They can be used to test other implementations of the doby format.")
+                )
+        )
+}
 
-        match argon2::Params::new(m_cost, t_cost, p_cost, None) {
-            Ok(params) => Some(params),
-            Err(e) => {
-                eprintln!("Invalid Argon2 parameters: {}", e);
-                None
+/// Returns `Ok(None)` if there is nothing to do (e.g. the user refused to overwrite the output file).
+pub fn parse() -> Result<Option<Command>, Error> {
+    let matches = app().get_matches();
+    report::init(any_level(&matches, |matches| matches.is_present("4_json")), log_level(&matches));
+    let config = Config::load()?;
+    let (mode, app) = match matches.subcommand() {
+        ("keygen", Some(sub_matches)) => return Ok(Some(Command::Keygen {
+            output: sub_matches.value_of("OUTPUT").map(String::from),
+            sign: sub_matches.is_present("sign"),
+        })),
+        ("key", Some(sub_matches)) => return Ok(Some(match sub_matches.subcommand() {
+            ("generate", Some(matches)) => Command::KeyGenerate {
+                name: matches.value_of("NAME").unwrap().to_string(),
+                sign: matches.is_present("sign"),
+                passphrase: matches.is_present("protect").then(|| read_password(matches).map(WrappedPassword::from)).transpose()?,
+            },
+            ("add", Some(matches)) => Command::KeyAdd {
+                name: matches.value_of("NAME").unwrap().to_string(),
+                passphrase: matches.is_present("protect").then(|| read_password(matches).map(WrappedPassword::from)).transpose()?,
+            },
+            ("list", Some(_)) => Command::KeyList,
+            ("export", Some(matches)) => Command::KeyExport {
+                name: matches.value_of("NAME").unwrap().to_string(),
+                secret: matches.is_present("secret"),
+                output: matches.value_of("OUTPUT").map(String::from),
+            },
+            ("import", Some(matches)) => Command::KeyImport {
+                name: matches.value_of("NAME").unwrap().to_string(),
+                file: matches.value_of("FILE").unwrap().to_string(),
+                passphrase: matches.is_present("protect").then(|| read_password(matches).map(WrappedPassword::from)).transpose()?,
+            },
+            _ => unreachable!(),
+        })),
+        ("plugins", Some(_)) => return Ok(Some(Command::Plugins)),
+        ("bench", Some(sub_matches)) => return Ok(Some(Command::Bench(BenchArgs {
+            file: sub_matches.value_of("file").map(String::from),
+            size: sub_matches.value_of("size").map(size).unwrap_or(Ok(64 << 20))?,
+            argon2_params: argon2_params(sub_matches, &config)?,
+            write_config: sub_matches.is_present("write_config"),
+        }))),
+        ("selftest", Some(sub_matches)) => return Ok(Some(Command::SelfTest {
+            export: sub_matches.is_present("export"),
+        })),
+        ("inspect", Some(sub_matches)) => return Ok(Some(Command::Inspect {
+            path: sub_matches.value_of("INPUT").unwrap().to_string(),
+            json: report::is_json(),
+            verify_digest: sub_matches.value_of("verify_digest").map(String::from),
+        })),
+        ("header", Some(sub_matches)) => return Ok(Some(match sub_matches.subcommand() {
+            ("backup", Some(matches)) => {
+                let output = matches.value_of("OUTPUT").filter(|path| *path != "-");
+                if let Some(output) = output {
+                    if !confirm_overwrite(output, interactive(matches, &config))? {
+                        return Ok(None);
+                    }
+                }
+                Command::HeaderBackup {
+                    input: matches.value_of("INPUT").unwrap().to_string(),
+                    output: output.map(String::from),
+                }
             }
+            ("restore", Some(matches)) => Command::HeaderRestore {
+                backup: matches.value_of("BACKUP").unwrap().to_string(),
+                file: matches.value_of("FILE").unwrap().to_string(),
+                force: matches.is_present("force"),
+            },
+            _ => unreachable!(),
+        })),
+        ("repair", Some(sub_matches)) => return Ok(Some(Command::Repair {
+            path: sub_matches.value_of("FILE").unwrap().to_string(),
+        })),
+        ("batch", Some(sub_matches)) => return parse_batch(sub_matches, &config).map(|args| Some(Command::Batch(args))),
+        ("watch", Some(sub_matches)) => return parse_watch(sub_matches, &config).map(|args| Some(Command::Watch(args))),
+        ("mirror", Some(sub_matches)) => return parse_mirror(sub_matches, &config).map(|args| Some(Command::Mirror(args))),
+        ("serve", Some(sub_matches)) => return parse_serve(sub_matches, &config).map(|args| Some(Command::Serve(args))),
+        ("send", Some(sub_matches)) => {
+            let (cipher, mac) = algorithms(sub_matches, &config)?;
+            return Ok(Some(Command::Send(SendArgs {
+                input: sub_matches.value_of("INPUT").filter(|path| *path != "-").map(String::from),
+                listen: sub_matches.value_of("listen").unwrap().to_string(),
+                code: sub_matches.value_of("code").map(String::from),
+                cipher,
+                mac,
+                block_size: block_size(sub_matches, &config)?,
+            })));
         }
-    }?;
-
-    let cipher = app
-        .value_of("cipher")
-        .map(|s| if s.to_lowercase() == "aes" {
-                CipherAlgorithm::AesCtr
-            } else {
-                CipherAlgorithm::XChaCha20
+        ("receive", Some(sub_matches)) => {
+            let interactive = interactive(sub_matches, &config);
+            let output = match sub_matches.value_of("OUTPUT") {
+                Some("-") => Some(WrappedWriter::from_writer(stdout())),
+                Some(path) => {
+                    if !confirm_overwrite(path, interactive)? {
+                        return Ok(None);
+                    }
+                    Some(WrappedWriter::from_path(path.to_string()))
+                }
+                None => None,
+            };
+            return Ok(Some(Command::Receive(ReceiveArgs {
+                address: sub_matches.value_of("ADDRESS").unwrap().to_string(),
+                code: sub_matches.value_of("code").into(),
+                output,
+                interactive,
+                force: sub_matches.is_present("force"),
+                fsync: sub_matches.is_present("5_fsync"),
+            })));
+        }
+        ("client", Some(sub_matches)) => {
+            let output = sub_matches.value_of("OUTPUT").filter(|path| *path != "-");
+            if let Some(output) = output {
+                if !confirm_overwrite(output, interactive(sub_matches, &config))? {
+                    return Ok(None);
+                }
             }
-        )
-        .unwrap_or_else(|| if aes_ni::get() {
-                CipherAlgorithm::AesCtr
+            return Ok(Some(Command::Client {
+                socket: sub_matches.value_of("socket").unwrap().to_string(),
+                decrypt: sub_matches.is_present("decrypt"),
+                input: sub_matches.value_of("INPUT").filter(|path| *path != "-").map(String::from),
+                output: output.map(String::from),
+            }));
+        }
+        ("pack", Some(sub_matches)) => {
+            let args = parse_container(sub_matches, "INPUT", &config)?;
+            return Ok(if confirm_overwrite(&args.archive, args.interactive)? {
+                Some(Command::Pack(args))
             } else {
-                CipherAlgorithm::XChaCha20
+                None
+            });
+        }
+        ("add", Some(sub_matches)) => {
+            if sub_matches.is_present("comment") {
+                return Err(Error::Usage("--comment can't be used with add: new entries keep the comment of the container"));
             }
-        );
+            if sub_matches.is_present("kms_key_id") {
+                return Err(Error::Usage("--kms-key-id can't be used with add: new entries use the master key of the container"));
+            }
+            if sub_matches.is_present("4_tpm") {
+                return Err(Error::Usage("--tpm can't be used with add: new entries use the master key of the container"));
+            }
+            return parse_container(sub_matches, "INPUT", &config).map(|args| Some(Command::Add(args)));
+        }
+        ("list", Some(sub_matches)) => return parse_container(sub_matches, "PATH", &config).map(|args| Some(Command::List(args))),
+        ("extract", Some(sub_matches)) => return parse_container(sub_matches, "PATH", &config).map(|args| Some(Command::Extract(args))),
+        ("edit", Some(sub_matches)) => return parse_edit(sub_matches, &config).map(|args| Some(Command::Edit(args))),
+        ("rotate", Some(sub_matches)) => return parse_rotate(sub_matches, &config).map(|args| Some(Command::Rotate(args))),
+        ("cat", Some(sub_matches)) => return parse_cat(sub_matches, &config).map(|args| Some(Command::Cat(args))),
+        ("grep", Some(sub_matches)) => return parse_grep(sub_matches, &config).map(|args| Some(Command::Grep(args))),
+        ("encrypt", Some(sub_matches)) => (Mode::Encrypt, sub_matches),
+        ("decrypt", Some(sub_matches)) => (Mode::Decrypt, sub_matches),
+        ("rekey", Some(sub_matches)) => (Mode::Rekey, sub_matches),
+        _ => (if matches.is_present("1_decrypt") { Mode::Decrypt } else { Mode::Auto }, &matches),
+    };
+    if mode == Mode::Decrypt && app.is_present("1_force_encrypt") {
+        return Err(Error::Usage("--force-encrypt can't be used when decrypting"));
+    }
+    if mode == Mode::Rekey && ["1_force_encrypt", "1_recursive", "5_rm", "6_shred", "8_store_name", "8_restore_name", "detach_header", "header", "format", "digest"].iter().any(|arg| app.is_present(arg)) {
+        return Err(Error::Usage("--force-encrypt, --recursive, --rm, --shred, --store-name, --restore-name, --detach-header, --header, --format and --digest can't be used with rekey"));
+    }
+    let dash = |arg| app.value_of(arg).unwrap_or("-") == "-";
+    if app.is_present("5_clipboard_in") && !dash("INPUT") {
+        return Err(Error::Usage("--clipboard-in reads INPUT from the clipboard: INPUT must be omitted or \"-\""));
+    }
+    if app.is_present("5_clipboard_out") {
+        if !dash("OUTPUT") {
+            return Err(Error::Usage("--clipboard-out writes OUTPUT to the clipboard: OUTPUT must be omitted or \"-\""));
+        }
+        if app.is_present("1_recursive") || app.is_present("8_restore_name") {
+            return Err(Error::Usage("--recursive and --restore-name can't be used with --clipboard-out"));
+        }
+    }
+    if app.is_present("5_qr") && (mode == Mode::Decrypt || mode == Mode::Rekey) {
+        return Err(Error::Usage("--qr only applies to encryption"));
+    }
+    if app.is_present("5_resume") {
+        if mode == Mode::Decrypt || mode == Mode::Rekey {
+            return Err(Error::Usage("--resume only applies to encryption"));
+        }
+        if ["1_recursive", "1_armor", "5_qr", "ecc", "detach_header", "sign_key", "5_mmap", "format"].iter().any(|arg| app.is_present(arg)) {
+            return Err(Error::Usage("--recursive, --armor, --qr, --ecc, --detach-header, --sign-key, --mmap and --format can't be used with --resume"));
+        }
+        let regular = |arg| app.value_of(arg).is_some_and(|s| s != "-" && Path::new(s).is_file());
+        if !regular("INPUT") || app.value_of("OUTPUT").unwrap_or("-") == "-" {
+            return Err(Error::Usage("--resume requires INPUT to be a regular file and OUTPUT a file"));
+        }
+    }
+    if app.is_present("header") && (mode == Mode::Encrypt || app.is_present("1_force_encrypt")) {
+        return Err(Error::Usage("--header only applies to decryption"));
+    }
+    if app.is_present("1_recursive") && (app.is_present("8_store_name") || app.is_present("8_restore_name")) {
+        return Err(Error::Usage("--store-name and --restore-name can't be used with --recursive"));
+    }
 
-    let block_size = number(app.value_of("blocksize").unwrap())?;
+    let key_slots = key_slot_options(app, &config, if mode == Mode::Rekey { "2_new_password" } else { "1_password" })?;
+    let encryption = encryption_options(app, &config)?;
+    let block_size = block_size(app, &config)?;
+    let threads = threads(app, &config)?;
+    let identities = identities(app)?;
+    let raw_key = raw_key(app)?;
 
+    let recursive = app.is_present("1_recursive");
+    let mut force_encrypt = app.is_present("1_force_encrypt");
     let input = match app
         .value_of("INPUT")
         .and_then(|s| if s == "-" { None } else { Some(s) })
         {
-            Some(s) => 
-                Box::new(
-                    File::open(s)
-                        .map_err(|e| eprintln!("{}: {}", s, e))
-                        .ok()?
-                ) as Box<dyn Read>
-            ,
-            None => Box::new(stdin())
+            Some(s) if mode == Mode::Rekey && is_url(s) => return Err(Error::Usage("rekey rewrites INPUT in place, which can't be a URL")),
+            Some(s) if is_url(s) => remote_reader(s)?,
+            Some(s) if recursive && mode != Mode::Decrypt && Path::new(s).is_dir() => {
+                force_encrypt = true;
+                WrappedReader::from_reader(
+                    ArchiveReader::new(s).map_err(|error| Error::Path { path: s.to_string(), error })?
+                )
+            }
+            Some(s) => WrappedReader::from_file(
+                File::open(s)
+                    .map_err(|error| Error::Path { path: s.to_string(), error })?
+            ),
+            None if app.is_present("5_clipboard_in") => clipboard_reader()?,
+            None => WrappedReader::from_reader(stdin())
         };
 
-    let wrapped_writer = match app
-        .value_of("OUTPUT")
+    let remove_input = if app.is_present("5_rm") || app.is_present("6_shred") {
+        let path = match app.value_of("INPUT") {
+            Some(s) if s != "-" && Path::new(s).is_file() => s.to_string(),
+            _ => return Err(Error::Usage("--rm and --shred require INPUT to be a regular file")),
+        };
+        if app.value_of("OUTPUT").unwrap_or("-") == "-" {
+            return Err(Error::Usage("--rm and --shred require OUTPUT to be a file"));
+        }
+        Some(RemoveInput { path, shred: app.is_present("6_shred") })
+    } else {
+        None
+    };
+
+    if let Some(header) = app.value_of("detach_header") {
+        let same = |path: Option<&str>| path.is_some_and(|path| path == header || is_same_file(path, header));
+        if same(app.value_of("INPUT")) || same(app.value_of("OUTPUT")) {
+            return Err(Error::Usage("the --detach-header file must be different from INPUT and OUTPUT"));
+        }
+        if !confirm_overwrite(header, interactive(app, &config))? {
+            return Ok(None);
+        }
+    }
+
+    let store_name = if app.is_present("8_store_name") {
+        match app.value_of("INPUT") {
+            Some(s) if s != "-" && Path::new(s).is_file() => Some(
+                Path::new(s).file_name().and_then(|name| name.to_str()).ok_or(Error::Usage("--store-name requires INPUT's file name to be valid UTF-8"))?.to_string()
+            ),
+            _ => return Err(Error::Usage("--store-name requires INPUT to be a regular file")),
+        }
+    } else {
+        None
+    };
+
+    let restore_name = if app.is_present("8_restore_name") {
+        let dir = match app.value_of("OUTPUT") {
+            Some(s) if s != "-" => s,
+            _ => ".",
+        };
+        if !Path::new(dir).is_dir() {
+            return Err(Error::Usage("--restore-name requires OUTPUT to be a directory"));
+        }
+        Some(dir.to_string())
+    } else {
+        None
+    };
+
+    if mode != Mode::Rekey && restore_name.is_none() {
+        if let (Some(input), Some(output)) = (app.value_of("INPUT"), app.value_of("OUTPUT")) {
+            if input != "-" && output != "-" {
+                check_output(input, output, remove_input.is_some())?;
+            }
+        }
+    }
+
+    let wrapped_writer = match &restore_name {
+        //written next to the final output until the stored name is known
+        Some(dir) => {
+            let placeholder = match app.value_of("INPUT") {
+                Some(s) if s != "-" => Path::new(s).file_name().map(|name| name.to_string_lossy().into_owned()),
+                _ => None,
+            };
+            WrappedWriter::from_path(Path::new(dir).join(placeholder.unwrap_or_else(|| String::from("doby"))).to_string_lossy().into_owned())
+        }
+        None => match app
+        .value_of(if mode == Mode::Rekey { "INPUT" } else { "OUTPUT" })
         .and_then(|s| if s == "-" { None } else { Some(s) }) {
+            Some(url) if is_url(url) => remote_writer(url)?,
             Some(path) => {
-                if {
-                    if app.is_present("2_interactive") && Path::new(path).exists() {
-                        eprint!("Warning: {} already exists. Overwrite [y/N]? ", path);
-                        let mut c = String::with_capacity(2);
-                        io::stdin().read_line(&mut c).unwrap();
-                        !c.is_empty() && c.chars().nth(0).unwrap() == 'y'
-                    } else {
-                        true
-                    }
-                } {
+                if confirm_overwrite(path, interactive(app, &config) && mode != Mode::Rekey)? {
                     WrappedWriter::from_path(path.to_string())
                 } else {
-                    return Some(ParseResult::exited())
+                    return Ok(None)
                 }
             }
+            None if app.is_present("5_clipboard_out") => clipboard_writer(app)?,
+            None if recursive && (!force_encrypt || mode == Mode::Decrypt) => return Err(Error::Usage("an OUTPUT directory is required to decrypt recursively")),
             None => WrappedWriter::from_writer(stdout())
-        };
+        },
+    };
 
-    Some(CliArgs {
-        password: app.value_of("1_password").into(),
-        force_encrypt: app.is_present("1_force_encrypt"),
-        argon2_params: params,
-        cipher,
-        block_size,
+    Ok(Some(Command::Run(CliArgs {
+        password: read_password(app)?.into(),
+        retries: retries(app)?,
+        new_password: app.value_of("2_new_password").into(),
+        key_slots,
+        identities,
+        raw_key,
+        mode,
+        force_encrypt,
+        recursive,
+        armor: app.is_present("1_armor"),
+        qr: qr_format(app),
+        ecc: ecc(app)?,
+        fsync: app.is_present("5_fsync") || remove_input.is_some(),
+        mmap: app.is_present("5_mmap"),
+        sparse: !app.is_present("5_no_sparse"),
+        resume: app.is_present("5_resume"),
+        sandbox: sandbox(app, wrapped_writer.path(), remove_input.as_ref()),
+        remove_input,
+        verify_first: app.is_present("3_verify_first"),
+        keep_unverified: app.is_present("7_keep_unverified"),
+        preserve: app.is_present("8_preserve"),
+        store_name,
+        restore_name,
+        interactive: interactive(app, &config),
+        tar: app.is_present("format"),
+        comment: comment(app)?,
+        digest: app.is_present("digest"),
+        salt_rng: salt_rng(app)?,
+        sign_key: sign_key(app)?,
+        verify_key: verify_key(app)?,
+        detach_header: app.value_of("detach_header").map(String::from),
+        header: app.value_of("header").map(|path| {
+            File::open(path).map_err(|error| Error::Path { path: path.to_string(), error })
+                .and_then(|file| read_header(&mut BufReader::new(file)))
+        }).transpose()?,
+        openssl_iterations: openssl_iterations(app)?,
+        progress: app.is_present("4_progress"),
+        stats: app.is_present("4_stats"),
+        encryption,
+        argon2_limits: argon2_limits(app)?,
+        block_size: block_size.unwrap_or_else(|| auto_block_size(input.file_metadata().as_ref())),
+        threads,
+        rate_limit: rate_limit(app)?,
+        input: app.value_of("INPUT").filter(|s| *s != "-").map(String::from),
         reader: input,
         writer: wrapped_writer,
-    }.into())
+    })))
+}
+
+/// Starts downloading INPUT when it's a URL.
+#[cfg(feature = "remote")]
+fn remote_reader(url: &str) -> Result<WrappedReader, Error> {
+    crate::remote::download(url)
+        .map(WrappedReader::from_reader)
+        .map_err(|error| Error::Path { path: url.to_string(), error })
+}
+
+#[cfg(not(feature = "remote"))]
+fn remote_reader(_url: &str) -> Result<WrappedReader, Error> {
+    Err(Error::RemoteRequired)
+}
+
+/// Starts uploading to OUTPUT when it's a URL, before the sandbox forbids running `curl` or `aws`.
+#[cfg(feature = "remote")]
+fn remote_writer(url: &str) -> Result<WrappedWriter<String>, Error> {
+    WrappedWriter::from_url(url)
+}
+
+#[cfg(not(feature = "remote"))]
+fn remote_writer(_url: &str) -> Result<WrappedWriter<String>, Error> {
+    Err(Error::RemoteRequired)
 }
 
-fn number<T: FromStr>(val: &str) -> Option<T> {
-    match val.parse::<T>() {
-        Ok(n) => Some(n),
-        Err(_) => {
-            eprintln!("Error: '{}' is not a number", val);
-            None
+#[cfg(unix)]
+fn clipboard_reader() -> Result<WrappedReader, Error> {
+    crate::clipboard::paste().map(WrappedReader::from_reader)
+}
+
+#[cfg(not(unix))]
+fn clipboard_reader() -> Result<WrappedReader, Error> {
+    Err(Error::Clipboard(String::from("not supported on this platform")))
+}
+
+fn qr_format(app: &ArgMatches) -> Option<QrFormat> {
+    match app.value_of("5_qr") {
+        _ if !app.is_present("5_qr") => None,
+        Some("png") => Some(QrFormat::Png),
+        _ => Some(QrFormat::Text),
+    }
+}
+
+/// Starts the program copying to the clipboard (`--clipboard-out`), before the sandbox forbids running it.
+#[cfg(unix)]
+fn clipboard_writer(app: &ArgMatches) -> Result<WrappedWriter<String>, Error> {
+    let delay = match app.value_of("5_clipboard_out") {
+        Some(delay) => number(delay)?,
+        None => crate::clipboard::DEFAULT_CLEAR_DELAY,
+    };
+    let (sink, writer) = crate::clipboard::copy(Some(delay).filter(|delay| *delay > 0))?;
+    Ok(WrappedWriter::from_child_sink(sink, writer))
+}
+
+#[cfg(not(unix))]
+fn clipboard_writer(_app: &ArgMatches) -> Result<WrappedWriter<String>, Error> {
+    Err(Error::Clipboard(String::from("not supported on this platform")))
+}
+
+/// Access still needed once the keys are ready: writing the outputs (and removing INPUT with `--rm`), and reading the directory tree to encrypt with `--recursive`.
+fn sandbox(app: &ArgMatches, output: Option<&str>, remove_input: Option<&RemoveInput>) -> Sandbox {
+    if app.is_present("7_no_sandbox") {
+        return Sandbox::disabled();
+    }
+    let mut sandbox = Sandbox::default();
+    if let Some(output) = output {
+        sandbox.allow_write_next_to(output);
+    }
+    if let Some(header) = app.value_of("detach_header") {
+        sandbox.allow_write_next_to(header);
+    }
+    if let Some(remove_input) = remove_input {
+        sandbox.allow_write_next_to(&remove_input.path);
+    }
+    if ["INPUT", "OUTPUT"].iter().any(|arg| app.value_of(arg).is_some_and(is_url)) || app.is_present("5_clipboard_in") || app.is_present("5_clipboard_out") {
+        sandbox.allow_child_processes();
+    }
+    if app.is_present("1_recursive") {
+        if let Some(input) = app.value_of("INPUT").filter(|s| *s != "-") {
+            sandbox.allow_read(input);
         }
     }
+    sandbox
+}
+
+fn parse_batch(app: &ArgMatches, config: &Config) -> Result<BatchArgs, Error> {
+    if ["1_recursive", "3_verify_first", "7_keep_unverified", "4_progress", "2_new_password", "8_restore_name", "detach_header", "header", "format", "openssl_iter", "1_retries"].iter().any(|arg| app.is_present(arg)) {
+        return Err(Error::Usage("--recursive, --verify-first, --keep-unverified, --progress, --new-password, --restore-name, --detach-header, --header, --format, --openssl-iter and --retries can't be used with batch"));
+    }
+    if app.is_present("yubikey") {
+        return Err(Error::Usage("--yubikey can't be used with batch"));
+    }
+    if app.is_present("test_salt_hex") {
+        return Err(Error::Usage("--test-salt-hex can't be used with batch: the outputs would share the same salt"));
+    }
+    let decrypt = app.is_present("decrypt");
+    if decrypt && (app.is_present("5_rm") || app.is_present("6_shred")) {
+        return Err(Error::Usage("--rm and --shred only apply to encryption"));
+    }
+    if decrypt && (app.is_present("8_store_name") || app.is_present("comment") || app.is_present("digest") || app.is_present("sign_key") || app.is_present("kms_key_id") || app.is_present("4_tpm")) {
+        return Err(Error::Usage("--store-name, --comment, --digest, --sign-key, --kms-key-id and --tpm only apply to encryption"));
+    }
+    if !decrypt && app.is_present("verify_key") {
+        return Err(Error::Usage("--verify-key only applies to decryption"));
+    }
+    if decrypt && app.is_present("4_pkcs11_key") {
+        return Err(Error::Usage("--pkcs11-key can't be used to decrypt with batch: the token would ask for its PIN for each file"));
+    }
+    let mut inputs: Vec<String> = app.values_of("INPUT").map(|values| values.map(String::from).collect()).unwrap_or_default();
+    if let Some(path) = app.value_of("files_from") {
+        inputs.append(&mut read_file_list(path)?);
+    }
+    let encryption = encryption_options(app, config)?;
+    let name_template = match (app.value_of("name_template"), app.value_of("suffix")) {
+        (Some(template), _) => NameTemplate::parse(template)?,
+        (None, Some(suffix)) => NameTemplate::with_suffix(suffix)?,
+        (None, None) => NameTemplate::default(),
+    };
+    Ok(BatchArgs {
+        inputs,
+        decrypt,
+        output_dir: app.value_of("output_dir").map(String::from),
+        name_template,
+        password: read_password(app)?.into(),
+        key_slots: key_slot_options(app, config, "1_password")?,
+        identities: identities(app)?,
+        raw_key: raw_key(app)?,
+        force_encrypt: app.is_present("1_force_encrypt"),
+        interactive: interactive(app, config),
+        armor: app.is_present("1_armor"),
+        ecc: ecc(app)?,
+        preserve: app.is_present("8_preserve"),
+        store_name: app.is_present("8_store_name"),
+        comment: comment(app)?,
+        digest: app.is_present("digest"),
+        sign_key: sign_key(app)?,
+        verify_key: verify_key(app)?,
+        remove_inputs: app.is_present("5_rm") || app.is_present("6_shred"),
+        shred: app.is_present("6_shred"),
+        fsync: ["5_fsync", "5_rm", "6_shred"].iter().any(|arg| app.is_present(arg)),
+        mmap: app.is_present("5_mmap"),
+        sparse: !app.is_present("5_no_sparse"),
+        encryption,
+        argon2_limits: argon2_limits(app)?,
+        block_size: block_size(app, config)?,
+        threads: threads(app, config)?,
+        rate_limit: rate_limit(app)?,
+        jobs: app.value_of("jobs").map(number).transpose()?.unwrap_or(1),
+    })
+}
+
+fn parse_watch(app: &ArgMatches, config: &Config) -> Result<WatchArgs, Error> {
+    if ["1_recursive", "3_verify_first", "7_keep_unverified", "4_progress", "2_new_password", "8_restore_name", "detach_header", "header", "format", "openssl_iter", "1_retries", "verify_key", "yubikey", "test_salt_hex"].iter().any(|arg| app.is_present(arg)) {
+        return Err(Error::Usage("--recursive, --verify-first, --keep-unverified, --progress, --new-password, --restore-name, --detach-header, --header, --format, --openssl-iter, --retries, --verify-key, --yubikey and --test-salt-hex can't be used with watch"));
+    }
+    let dir = app.value_of("DIR").unwrap().to_string();
+    if !Path::new(&dir).is_dir() {
+        return Err(Error::Usage("DIR must be a directory"));
+    }
+    let move_to = app.value_of("move_to").map(String::from);
+    if move_to.is_some() && (app.is_present("5_rm") || app.is_present("6_shred")) {
+        return Err(Error::Usage("--move-to can't be used with --rm and --shred"));
+    }
+    //the outputs and the moved originals would be encrypted again
+    let same = |path: Option<&str>| path.is_some_and(|path| path == dir || is_same_file(path, &dir));
+    if same(app.value_of("output_dir")) || same(move_to.as_deref()) {
+        return Err(Error::Usage("--output-dir and --move-to must be different from DIR"));
+    }
+    let mut batch = parse_batch(app, config)?;
+    //originals are always removed from DIR once encrypted
+    batch.remove_inputs = move_to.is_none();
+    batch.fsync = true;
+    Ok(WatchArgs { dir, move_to, batch })
+}
+
+fn parse_mirror(app: &ArgMatches, config: &Config) -> Result<MirrorArgs, Error> {
+    if ["1_recursive", "5_rm", "6_shred", "8_store_name", "yubikey", "test_salt_hex"].iter().any(|arg| app.is_present(arg)) {
+        return Err(Error::Usage("--recursive, --rm, --shred, --store-name, --yubikey and --test-salt-hex can't be used with mirror"));
+    }
+    let src = app.value_of("SRC").unwrap().to_string();
+    let dst = app.value_of("DST").unwrap().to_string();
+    if !Path::new(&src).is_dir() {
+        return Err(Error::Usage("SRC must be a directory"));
+    }
+    if is_same_file(&src, &dst) {
+        return Err(Error::Usage("SRC and DST must be different"));
+    }
+    let mut batch = parse_batch(app, config)?;
+    //the outputs belong to the mirror, and its files are doby files when restoring it
+    batch.interactive = false;
+    batch.force_encrypt = true;
+    Ok(MirrorArgs {
+        src,
+        dst,
+        decrypt: batch.decrypt,
+        encrypt_names: app.is_present("encrypt_names"),
+        batch,
+    })
+}
+
+fn parse_serve(app: &ArgMatches, config: &Config) -> Result<ServeArgs, Error> {
+    if ["1_recursive", "1_armor", "ecc", "3_verify_first", "7_keep_unverified", "4_progress", "5_rm", "6_shred", "5_mmap", "2_new_password", "8_preserve", "8_store_name", "8_restore_name", "detach_header", "header", "format", "openssl_iter", "1_retries", "sign_key", "yubikey", "test_salt_hex"].iter().any(|arg| app.is_present(arg)) {
+        return Err(Error::Usage("--recursive, --armor, --ecc, --verify-first, --keep-unverified, --progress, --rm, --shred, --mmap, --new-password, --preserve, --store-name, --restore-name, --detach-header, --header, --format, --openssl-iter, --retries, --sign-key, --yubikey and --test-salt-hex can't be used with serve"));
+    }
+    let socket = app.value_of("socket").unwrap().to_string();
+    let mut batch = parse_batch(app, config)?;
+    //the data of requests isn't read from files
+    batch.sparse = false;
+    Ok(ServeArgs { socket, batch })
+}
+
+fn parse_edit(app: &ArgMatches, config: &Config) -> Result<EditArgs, Error> {
+    if ["1_force_encrypt", "1_recursive", "1_armor", "ecc", "3_verify_first", "7_keep_unverified", "5_rm", "6_shred", "5_mmap", "2_new_password", "3_recipient", "3_ssh_key", "4_tpm", "8_preserve", "8_store_name", "8_restore_name", "detach_header", "header", "format", "comment", "digest", "kms_key_id", "yubikey", "cipher", "mac", "1_profile", "2_t_cost", "3_m_cost", "4_p_cost", "test_salt_hex"].iter().any(|arg| app.occurrences_of(arg) > 0) {
+        return Err(Error::Usage("edit keeps the key and the options of FILE: only the options needed to open it can be given"));
+    }
+    if app.values_of("1_password").is_some_and(|values| values.count() > 1) {
+        return Err(Error::Usage("edit keeps the key slots of FILE: only one --password can be given"));
+    }
+    Ok(EditArgs {
+        path: app.value_of("FILE").unwrap().to_string(),
+        password: read_password(app)?.into(),
+        retries: retries(app)?,
+        identities: identities(app)?,
+        pkcs11: pkcs11(app)?,
+        raw_key: raw_key(app)?,
+        sign_key: sign_key(app)?,
+        verify_key: verify_key(app)?,
+        argon2_limits: argon2_limits(app)?,
+        block_size: block_size(app, config)?.unwrap_or(DEFAULT_BLOCK_SIZE),
+    })
+}
+
+fn parse_rotate(app: &ArgMatches, config: &Config) -> Result<RotateArgs, Error> {
+    if ["1_force_encrypt", "1_recursive", "1_armor", "ecc", "3_verify_first", "7_keep_unverified", "4_progress", "5_mmap", "5_resume", "5_clipboard_in", "5_clipboard_out", "5_rm", "6_shred", "1_retries", "rate_limit", "3_recipient", "3_ssh_key", "4_identity", "4_pkcs11_key", "4_tpm", "5_key_hex", "6_key", "6_key_file_raw", "8_preserve", "8_store_name", "8_restore_name", "detach_header", "header", "format", "comment", "digest", "kms_key_id", "yubikey", "cipher", "mac", "test_salt_hex"].iter().any(|arg| app.occurrences_of(arg) > 0) {
+        return Err(Error::Usage("rotate keeps the options of each file: only the passwords, the Argon2 costs and the signing options can be given"));
+    }
+    if app.values_of("1_password").is_some_and(|values| values.count() > 1) || app.values_of("2_new_password").is_some_and(|values| values.count() > 1) {
+        return Err(Error::Usage("rotate only handles files encrypted with a single password: only one --password and --new-password can be given"));
+    }
+    let old_password = match app.value_of("old_password_file") {
+        Some(path) => Some(read_first_line(path)?),
+        None => read_password(app)?,
+    };
+    let new_password = match app.value_of("new_password_file") {
+        Some(path) => Some(read_first_line(path)?),
+        None => app.value_of("2_new_password").map(String::from),
+    };
+    Ok(RotateArgs {
+        dirs: app.values_of("DIR").unwrap().map(String::from).collect(),
+        dry_run: app.is_present("dry_run"),
+        old_password: old_password.into(),
+        new_password: new_password.into(),
+        nfc: !app.is_present("1_no_nfc"),
+        sign_key: sign_key(app)?,
+        verify_key: verify_key(app)?,
+        argon2_params: argon2_params(app, config)?,
+        argon2_limits: argon2_limits(app)?,
+        block_size: block_size(app, config)?,
+    })
+}
+
+fn parse_cat(app: &ArgMatches, config: &Config) -> Result<CatArgs, Error> {
+    if ["1_force_encrypt", "1_recursive", "1_armor", "ecc", "3_verify_first", "7_keep_unverified", "8_preserve", "8_store_name", "8_restore_name", "4_progress", "5_fsync", "5_mmap", "5_resume", "5_clipboard_in", "5_clipboard_out", "5_rm", "6_shred", "1_retries", "2_new_password", "3_recipient", "3_ssh_key", "4_tpm", "rate_limit", "detach_header", "header", "format", "comment", "digest", "sign_key", "kms_key_id", "yubikey", "cipher", "mac", "1_profile", "2_t_cost", "3_m_cost", "4_p_cost", "test_salt_hex"].iter().any(|arg| app.occurrences_of(arg) > 0) {
+        return Err(Error::Usage("cat and grep only decrypt to stdout: only the options needed to open FILE can be given"));
+    }
+    Ok(CatArgs {
+        inputs: app.values_of("FILE").unwrap().map(String::from).collect(),
+        password: read_password(app)?.into(),
+        identities: identities(app)?,
+        pkcs11: pkcs11(app)?,
+        raw_key: raw_key(app)?,
+        verify_key: verify_key(app)?,
+        argon2_limits: argon2_limits(app)?,
+        block_size: block_size(app, config)?,
+        threads: threads(app, config)?,
+        raw: app.is_present("raw"),
+    })
+}
+
+fn parse_grep(app: &ArgMatches, config: &Config) -> Result<GrepArgs, Error> {
+    let pattern = app.value_of("PATTERN").unwrap();
+    let pattern = if app.is_present("fixed_strings") { regex::escape(pattern) } else { pattern.to_string() };
+    let pattern = RegexBuilder::new(&pattern)
+        .case_insensitive(app.is_present("ignore_case"))
+        .build()
+        .map_err(|e| Error::InvalidPattern(e.to_string()))?;
+    Ok(GrepArgs {
+        pattern,
+        invert: app.is_present("invert_match"),
+        line_number: app.is_present("line_number"),
+        files_with_matches: app.is_present("files_with_matches"),
+        count: app.is_present("count"),
+        files: parse_cat(app, config)?,
+    })
+}
+
+/// `paths` is the name of the argument holding the files to pack or the entries to extract.
+fn parse_container(app: &ArgMatches, paths: &str, config: &Config) -> Result<ContainerArgs, Error> {
+    if ["1_force_encrypt", "1_recursive", "1_armor", "ecc", "3_verify_first", "7_keep_unverified", "4_progress", "5_rm", "6_shred", "2_new_password", "8_store_name", "8_restore_name", "detach_header", "header", "format", "digest", "sign_key", "verify_key"].iter().any(|arg| app.is_present(arg)) {
+        return Err(Error::Usage("--force-encrypt, --recursive, --armor, --ecc, --verify-first, --keep-unverified, --progress, --rm, --shred, --new-password, --store-name, --restore-name, --detach-header, --header, --format, --digest, --sign-key and --verify-key can't be used with containers"));
+    }
+    if app.is_present("yubikey") {
+        return Err(Error::Usage("--yubikey can't be used with containers"));
+    }
+    if app.is_present("test_salt_hex") {
+        return Err(Error::Usage("--test-salt-hex can't be used with containers: their entries would share the same salt"));
+    }
+    let encryption = encryption_options(app, config)?;
+    Ok(ContainerArgs {
+        archive: app.value_of("ARCHIVE").unwrap().to_string(),
+        paths: app.values_of(paths).map(|values| values.map(String::from).collect()).unwrap_or_default(),
+        output_dir: app.value_of("output_dir").unwrap_or(".").to_string(),
+        password: read_password(app)?.into(),
+        retries: retries(app)?,
+        key_slots: key_slot_options(app, config, "1_password")?,
+        identities: identities(app)?,
+        raw_key: raw_key(app)?,
+        interactive: interactive(app, config),
+        preserve: app.is_present("8_preserve"),
+        fsync: app.is_present("5_fsync"),
+        comment: comment(app)?,
+        encryption,
+        argon2_limits: argon2_limits(app)?,
+        block_size: block_size(app, config)?.unwrap_or(DEFAULT_BLOCK_SIZE),
+    })
+}
+
+/// Paths separated by NUL characters, or by newlines if there isn't any. Empty paths are ignored.
+fn read_file_list(path: &str) -> Result<Vec<String>, Error> {
+    let content = if path == "-" {
+        let mut content = Vec::new();
+        stdin().read_to_end(&mut content)?;
+        content
+    } else {
+        fs::read(path).map_err(|error| Error::Path { path: path.to_string(), error })?
+    };
+    let separator = if content.contains(&0) { 0 } else { b'\n' };
+    content
+        .split(|b| *b == separator)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8(name.to_vec()).map_err(|_| Error::Path {
+            path: String::from_utf8_lossy(name).into_owned(),
+            error: io::Error::new(io::ErrorKind::InvalidData, "path is not valid UTF-8"),
+        }))
+        .collect()
+}
+
+/// Costs given on the command line, then in the configuration file, then those of the profile. A profile given on the command line replaces the costs of the configuration file.
+fn argon2_params(app: &ArgMatches, config: &Config) -> Result<argon2::Params, Error> {
+    let (profile, config) = match (app.occurrences_of("1_profile"), config.profile) {
+        (0, Some(profile)) => (profile, Some(config)),
+        (0, None) => (app.value_of("1_profile").unwrap(), Some(config)),
+        _ => (app.value_of("1_profile").unwrap(), None),
+    };
+    let (_, t_cost, m_cost, p_cost) = *ARGON2_PROFILES.iter().find(|(name, ..)| *name == profile).unwrap();
+    let t_cost = config.and_then(|config| config.time_cost).unwrap_or(t_cost);
+    let m_cost = config.and_then(|config| config.memory_cost).unwrap_or(m_cost);
+    let p_cost = config.and_then(|config| config.parallelism).unwrap_or(p_cost);
+    let t_cost = app.value_of("2_t_cost").map(number).unwrap_or(Ok(t_cost))?;
+    let m_cost = app.value_of("3_m_cost").map(number).unwrap_or(Ok(m_cost))?;
+    let p_cost = app.value_of("4_p_cost").map(number).unwrap_or(Ok(p_cost))?;
+    //fail before prompting for the password rather than when deriving the key
+    if m_cost > MAX_ARGON2_MEMORY_COST {
+        return Err(Error::InvalidParams(argon2::Error::MemoryTooMuch));
+    }
+
+    Ok(argon2::Params::new(m_cost, t_cost, p_cost, None)?)
+}
+
+fn argon2_limits(app: &ArgMatches) -> Result<Argon2Limits, Error> {
+    Ok(Argon2Limits {
+        time_cost: number(app.value_of("5_max_t_cost").unwrap())?,
+        memory_cost: number(app.value_of("6_max_m_cost").unwrap())?,
+    })
+}
+
+/// Cipher named as with `--cipher`, case-insensitively.
+pub(crate) fn cipher_by_name(name: &str) -> Option<CipherAlgorithm> {
+    match name.to_lowercase().as_str() {
+        "aes" => Some(CipherAlgorithm::AesCtr),
+        "aes-gcm" => Some(CipherAlgorithm::AesGcm),
+        "xchacha20" => Some(CipherAlgorithm::XChaCha20),
+        "xchacha20-poly1305" => Some(CipherAlgorithm::XChaCha20Poly1305),
+        _ => None,
+    }
+}
+
+/// Name of the cipher as given to `--cipher`.
+pub(crate) fn cipher_name(cipher: CipherAlgorithm) -> &'static str {
+    match cipher {
+        CipherAlgorithm::AesCtr => "aes",
+        CipherAlgorithm::AesGcm => "aes-gcm",
+        CipherAlgorithm::XChaCha20 => "xchacha20",
+        CipherAlgorithm::XChaCha20Poly1305 => "xchacha20-poly1305",
+    }
+}
+
+/// Hash function named as with `--mac`, case-insensitively.
+pub(crate) fn mac_by_name(name: &str) -> Option<MacAlgorithm> {
+    match name.to_lowercase().as_str() {
+        "blake2b" => Some(MacAlgorithm::Blake2b),
+        "blake3" => Some(MacAlgorithm::Blake3),
+        _ => None,
+    }
+}
+
+/// Name of the hash function as given to `--mac`.
+pub(crate) fn mac_name(mac: MacAlgorithm) -> &'static str {
+    match mac {
+        MacAlgorithm::Blake2b => "blake2b",
+        MacAlgorithm::Blake3 => "blake3",
+    }
+}
+
+fn algorithms(app: &ArgMatches, config: &Config) -> Result<(CipherAlgorithm, MacAlgorithm), Error> {
+    let cipher = match app.value_of("cipher").map(|s| cipher_by_name(s).unwrap()).or(config.cipher) {
+        Some(cipher) => cipher,
+        //only chunks can be resumed, and memory maps are only encrypted without being copied first by the AEAD ciphers
+        None if app.is_present("5_resume") || app.is_present("5_mmap") => match default_cipher() {
+            CipherAlgorithm::AesCtr => CipherAlgorithm::AesGcm,
+            _ => CipherAlgorithm::XChaCha20Poly1305,
+        },
+        None => default_cipher(),
+    };
+    if app.is_present("5_resume") && !cipher.is_aead() {
+        return Err(Error::Usage("--resume requires a chunked cipher (aes-gcm or xchacha20-poly1305)"));
+    }
+    if app.is_present("5_mmap") && !cipher.is_aead() {
+        return Err(Error::Usage("--mmap requires a chunked cipher (aes-gcm or xchacha20-poly1305)"));
+    }
+
+    let mac = match config.mac {
+        //a default hash function doesn't prevent from choosing an AEAD cipher
+        Some(mac) if app.occurrences_of("mac") == 0 => if cipher.is_aead() { MacAlgorithm::Blake2b } else { mac },
+        _ => mac_by_name(app.value_of("mac").unwrap()).unwrap(),
+    };
+    if mac != MacAlgorithm::Blake2b && cipher.is_aead() {
+        return Err(Error::Usage("--mac can't be used with AEAD ciphers"));
+    }
+    Ok((cipher, mac))
+}
+
+/// `None` if neither `--block-size` nor the config file gives it.
+fn block_size(app: &ArgMatches, config: &Config) -> Result<Option<usize>, Error> {
+    match (app.value_of("blocksize"), config.block_size) {
+        (Some(block_size), _) => match number(block_size)? {
+            0 => Err(Error::Usage("--block-size must not be 0")),
+            block_size => Ok(Some(block_size)),
+        },
+        (None, block_size) => Ok(block_size),
+    }
+}
+
+fn threads(app: &ArgMatches, config: &Config) -> Result<usize, Error> {
+    let threads = match (app.occurrences_of("threads"), config.threads) {
+        (0, Some(threads)) => threads,
+        _ => number(app.value_of("threads").unwrap())?,
+    };
+    if threads == 0 {
+        return Err(Error::Usage("--threads must not be 0"));
+    }
+    Ok(threads)
+}
+
+/// Whether `is_present` is true for the matches of the app or of one of the subcommands, so that global flags can be given before or after them.
+fn any_level(matches: &ArgMatches, is_present: impl Fn(&ArgMatches) -> bool + Copy) -> bool {
+    is_present(matches) || matches.subcommand().1.is_some_and(|matches| any_level(matches, is_present))
+}
+
+fn log_level(matches: &ArgMatches) -> LevelFilter {
+    let mut verbosity = 0;
+    let mut level = Some(matches);
+    while let Some(matches) = level {
+        verbosity += matches.occurrences_of("4_verbose");
+        level = matches.subcommand().1;
+    }
+    match verbosity {
+        _ if any_level(matches, |matches| matches.is_present("4_quiet")) => LevelFilter::Error,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+fn interactive(app: &ArgMatches, config: &Config) -> bool {
+    app.is_present("2_interactive") || config.interactive
+}
+
+/// Parses `value`, or uses the public key of the stored identity of that name.
+fn recipient(value: &str) -> Result<Recipient, Error> {
+    Recipient::parse(value).or_else(|e| keys::public_key(value, KeyKind::Identity).map_or(Err(e), |public_key| Recipient::parse(&public_key)))
+}
+
+/// `passwords` is the argument whose values after the first one get their own key slot.
+fn key_slot_options(app: &ArgMatches, config: &Config, passwords: &str) -> Result<KeySlotOptions, Error> {
+    Ok(KeySlotOptions {
+        argon2_params: argon2_params(app, config)?,
+        nfc: !app.is_present("1_no_nfc"),
+        additional_passwords: app
+            .values_of(passwords)
+            .map(|values| values.skip(1).map(String::from).collect())
+            .unwrap_or_default(),
+        recipients: recipients(app)?,
+        ssh_keys: ssh_keys(app)?,
+        pkcs11: pkcs11(app)?,
+        tpm: tpm(app)?,
+    })
+}
+
+fn encryption_options(app: &ArgMatches, config: &Config) -> Result<EncryptionOptions, Error> {
+    let (cipher, mac) = algorithms(app, config)?;
+    Ok(EncryptionOptions {
+        cipher,
+        mac,
+        kms_key_id: app.value_of("kms_key_id").map(String::from),
+        yubikey: yubikey(app),
+    })
+}
+
+fn recipients(app: &ArgMatches) -> Result<Vec<Recipient>, Error> {
+    app.values_of("3_recipient").map(|values| values.map(recipient).collect()).unwrap_or_else(|| Ok(Vec::new()))
+}
+
+fn ssh_keys(app: &ArgMatches) -> Result<Vec<SshKey>, Error> {
+    app.values_of("3_ssh_key").map(|values| values.map(SshKey::read_file).collect()).unwrap_or_else(|| Ok(Vec::new()))
+}
+
+fn pkcs11(app: &ArgMatches) -> Result<Option<Pkcs11Key>, Error> {
+    match (app.value_of("4_pkcs11_module"), app.value_of("4_pkcs11_key")) {
+        (Some(module), Some(key_id)) => Pkcs11Key::new(module, key_id).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn tpm(app: &ArgMatches) -> Result<Option<TpmPolicy>, Error> {
+    app.is_present("4_tpm").then(|| app.value_of("4_tpm").map_or_else(|| Ok(TpmPolicy::default()), TpmPolicy::parse)).transpose()
+}
+
+fn identities(app: &ArgMatches) -> Result<Vec<Identity>, Error> {
+    let mut identities = Vec::new();
+    for path in app.values_of("4_identity").into_iter().flatten() {
+        identities.append(&mut Identity::parse_file_content(&keys::read(path)?)?);
+    }
+    Ok(identities)
+}
+
+#[cfg(feature = "yubikey")]
+fn yubikey(app: &ArgMatches) -> Option<u8> {
+    app.is_present("yubikey").then(|| app.value_of("yubikey").map_or(crate::yubikey::DEFAULT_YUBIKEY_SLOT, |slot| slot.parse().unwrap()))
+}
+
+#[cfg(not(feature = "yubikey"))]
+fn yubikey(_app: &ArgMatches) -> Option<u8> {
+    None
+}
+
+#[cfg(feature = "test-salt")]
+fn salt_rng(app: &ArgMatches) -> Result<SaltRng, Error> {
+    let salt = match app.value_of("test_salt_hex") {
+        Some(_) if app.is_present("yubikey") => return Err(Error::Usage("--test-salt-hex can't be used with --yubikey")),
+        Some(hex) => Some(*decode_hex(hex).ok_or(Error::Usage("--test-salt-hex must be 64 bytes in hexadecimal"))?),
+        None => None,
+    };
+    Ok(SaltRng(salt))
+}
+
+#[cfg(not(feature = "test-salt"))]
+fn salt_rng(_app: &ArgMatches) -> Result<SaltRng, Error> {
+    Ok(SaltRng::default())
+}
+
+fn sign_key(app: &ArgMatches) -> Result<Option<SigningKey>, Error> {
+    app.value_of("sign_key").map(|path| SigningKey::parse_file_content(&keys::read(path)?)).transpose()
+}
+
+fn verify_key(app: &ArgMatches) -> Result<Option<VerifyKey>, Error> {
+    app.value_of("verify_key")
+        .map(|value| VerifyKey::parse(value).or_else(|e| keys::public_key(value, KeyKind::Signing).map_or(Err(e), |public_key| VerifyKey::parse(&public_key))))
+        .transpose()
+}
+
+fn comment(app: &ArgMatches) -> Result<Option<String>, Error> {
+    match app.value_of("comment") {
+        Some(comment) if comment.len() > MAX_COMMENT_LEN => Err(Error::Usage("--comment can't be longer than 255 bytes")),
+        comment => Ok(comment.map(String::from)),
+    }
+}
+
+fn openssl_iterations(app: &ArgMatches) -> Result<u32, Error> {
+    match app.value_of("openssl_iter").map(number).transpose()? {
+        Some(0) => Err(Error::Usage("--openssl-iter must be at least 1")),
+        iterations => Ok(iterations.unwrap_or(DEFAULT_PBKDF2_ITERATIONS)),
+    }
+}
+
+fn ecc(app: &ArgMatches) -> Result<Option<u8>, Error> {
+    if !app.is_present("ecc") {
+        return Ok(None);
+    }
+    let parity_shards = app.value_of("ecc_parity").map(number).transpose()?.unwrap_or(DEFAULT_PARITY_SHARDS);
+    if parity_shards == 0 || parity_shards > MAX_PARITY_SHARDS {
+        return Err(Error::Usage("--ecc-parity must be between 1 and 239"));
+    }
+    Ok(Some(parity_shards))
+}
+
+fn raw_key(app: &ArgMatches) -> Result<Option<Zeroizing<[u8; KEY_LEN]>>, Error> {
+    Ok(if let Some(hex) = app.value_of("5_key_hex") {
+        Some(decode_hex(hex).ok_or(Error::InvalidRawKey)?)
+    } else if let Some(path) = app.value_of("6_key_file_raw") {
+        let content = Zeroizing::new(fs::read(path).map_err(|error| Error::Path { path: path.to_string(), error })?);
+        Some(Zeroizing::new(content.as_slice().try_into().map_err(|_| Error::InvalidRawKey)?))
+    } else if let Some(label) = app.value_of("6_key") {
+        Some(keys::parse_symmetric(&keys::read_stored(label)?)?)
+    } else {
+        None
+    })
+}
+
+/// Makes sure that writing `output` won't destroy `input`.
+///
+/// `input` and `output` can be the same file since the output is first written to a temporary file, but then `input` must not be removed afterwards.
+pub fn check_output(input: &str, output: &str, remove_input: bool) -> Result<(), Error> {
+    if remove_input && is_same_file(input, output) {
+        Err(Error::SameFile(input.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Asks the user whether `path` can be overwritten if `interactive` is set and it already exists.
+#[allow(clippy::iter_nth_zero)]
+pub fn confirm_overwrite(path: &str, interactive: bool) -> Result<bool, Error> {
+    Ok(if interactive && Path::new(path).exists() {
+        eprint!("Warning: {} already exists. Overwrite [y/N]? ", path);
+        let mut c = String::with_capacity(2);
+        io::stdin().read_line(&mut c)?;
+        !c.is_empty() && c.chars().nth(0).unwrap() == 'y'
+    } else {
+        true
+    })
+}
+
+/// Same as `Argon2Limits::check`, but asks the user whether to continue instead of failing if stdin is a terminal.
+pub fn confirm_argon2_costs(params: &EncryptionParams, limits: &Argon2Limits) -> Result<(), Error> {
+    match limits.check(params) {
+        Err(Error::Argon2LimitExceeded { time_cost, memory_cost }) if io::stdin().is_terminal() => {
+            eprint!("Warning: opening this file requires Argon2 with a time cost of {} and {}KiB of memory. Continue [y/N]? ", time_cost, memory_cost);
+            let mut c = String::with_capacity(2);
+            io::stdin().read_line(&mut c)?;
+            if c.starts_with('y') {
+                Ok(())
+            } else {
+                Err(Error::Argon2LimitExceeded { time_cost, memory_cost })
+            }
+        }
+        result => result,
+    }
+}
+
+/// Password given with `--password`, `--password-env`, `--password-file`, `--password-fd` or `--password-command`, if any.
+fn read_password(app: &ArgMatches) -> Result<Option<String>, Error> {
+    if let Some(name) = app.value_of("1_password_env") {
+        env::var(name).map(Some).map_err(|_| Error::MissingEnvVar(name.to_string()))
+    } else if let Some(path) = app.value_of("1_password_file") {
+        read_first_line(path).map(Some)
+    } else if let Some(fd) = app.value_of("1_password_fd") {
+        read_password_fd(number(fd)?).map(Some)
+    } else if let Some(command) = app.value_of("1_password_command") {
+        run_password_command(command).map(Some)
+    } else {
+        Ok(app.value_of("1_password").map(String::from))
+    }
+}
+
+/// Reads the first line of the file at `path`, holding a password.
+fn read_first_line(path: &str) -> Result<String, Error> {
+    let content = Zeroizing::new(fs::read_to_string(path).map_err(|error| Error::Path { path: path.to_string(), error })?);
+    Ok(content.lines().next().unwrap_or_default().to_string())
+}
+
+/// Reads the first line of an inherited file descriptor, like gpg's `--passphrase-fd`.
+#[cfg(unix)]
+fn read_password_fd(fd: i32) -> Result<String, Error> {
+    use std::{mem::ManuallyDrop, os::unix::io::FromRawFd};
+
+    //the descriptor isn't ours: don't close it
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut line = Zeroizing::new(Vec::new());
+    //read byte by byte to not consume anything after the first line
+    let mut byte = [0];
+    while (&*file).read(&mut byte).map_err(|error| Error::Path { path: format!("file descriptor {}", fd), error })? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    std::str::from_utf8(&line).map(String::from).map_err(|_| Error::Path {
+        path: format!("file descriptor {}", fd),
+        error: io::Error::new(io::ErrorKind::InvalidData, "password is not valid UTF-8"),
+    })
+}
+
+#[cfg(not(unix))]
+fn read_password_fd(_fd: i32) -> Result<String, Error> {
+    Err(Error::Usage("--password-fd is only supported on Unix"))
+}
+
+/// Runs `command` through the shell. Its stdin and stderr are inherited so that it can prompt the user.
+fn run_password_command(command: &str) -> Result<String, Error> {
+    #[cfg(unix)]
+    let (shell, flag) = ("sh", "-c");
+    #[cfg(not(unix))]
+    let (shell, flag) = ("cmd", "/C");
+    let mut child = process::Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .map_err(|error| Error::Path { path: command.to_string(), error })?;
+    let mut output = Zeroizing::new(Vec::new());
+    child.stdout.take().unwrap().read_to_end(&mut output)?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::PasswordCommand(command.to_string()));
+    }
+    let output = std::str::from_utf8(&output).map_err(|_| Error::PasswordCommand(command.to_string()))?;
+    Ok(output.lines().next().unwrap_or_default().to_string())
+}
+
+fn retries(app: &ArgMatches) -> Result<u32, Error> {
+    app.value_of("1_retries").map(number).unwrap_or(Ok(DEFAULT_RETRIES))
+}
+
+fn rate_limit(app: &ArgMatches) -> Result<Option<Arc<RateLimiter>>, Error> {
+    Ok(app.value_of("rate_limit").map(size).transpose()?.map(|rate| Arc::new(RateLimiter::new(rate))))
+}
+
+/// Number of bytes, with an optional K, M or G suffix (powers of 1024).
+fn size(val: &str) -> Result<u64, Error> {
+    let (number, shift) = match val.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&val[..i], 10),
+        Some((i, 'm' | 'M')) => (&val[..i], 20),
+        Some((i, 'g' | 'G')) => (&val[..i], 30),
+        _ => (val, 0),
+    };
+    number.parse::<u64>().ok().and_then(|n| n.checked_mul(1 << shift)).filter(|n| *n > 0).ok_or_else(|| Error::InvalidNumber(val.to_string()))
+}
+
+fn number<T: FromStr>(val: &str) -> Result<T, Error> {
+    val.parse::<T>().map_err(|_| Error::InvalidNumber(val.to_string()))
 }
@@ -0,0 +1,75 @@
+//! System clipboard, accessed through wl-clipboard on Wayland, xclip on X11 and pbcopy/pbpaste on macOS.
+
+use std::{env, process::{ChildStdin, Command}};
+use crate::{Error, child::{ChildReader, ChildSink}};
+
+/// Seconds after which `--clipboard-out` clears the clipboard if no delay is given.
+pub const DEFAULT_CLEAR_DELAY: u64 = 45;
+
+struct Tool {
+    copy: &'static [&'static str],
+    paste: &'static [&'static str],
+    //shell command
+    clear: &'static str,
+}
+
+const WAYLAND: Tool = Tool {
+    copy: &["wl-copy"],
+    paste: &["wl-paste", "--no-newline"],
+    clear: "wl-copy --clear",
+};
+
+const X11: Tool = Tool {
+    copy: &["xclip", "-selection", "clipboard", "-in"],
+    paste: &["xclip", "-selection", "clipboard", "-out"],
+    clear: "xclip -selection clipboard -in < /dev/null",
+};
+
+const MACOS: Tool = Tool {
+    copy: &["pbcopy"],
+    paste: &["pbpaste"],
+    clear: "pbcopy < /dev/null",
+};
+
+fn tool() -> Result<&'static Tool, Error> {
+    if cfg!(target_os = "macos") {
+        Ok(&MACOS)
+    } else if env::var_os("WAYLAND_DISPLAY").is_some() {
+        Ok(&WAYLAND)
+    } else if env::var_os("DISPLAY").is_some() {
+        Ok(&X11)
+    } else {
+        Err(Error::Clipboard(String::from("no clipboard found (neither WAYLAND_DISPLAY nor DISPLAY is set)")))
+    }
+}
+
+fn command(args: &[&str]) -> Command {
+    let mut command = Command::new(args[0]);
+    command.args(&args[1..]);
+    command
+}
+
+/// Starts reading the content of the clipboard.
+pub fn paste() -> Result<ChildReader, Error> {
+    ChildReader::spawn(&mut command(tool()?.paste)).map_err(|e| Error::Clipboard(e.to_string()))
+}
+
+/// Starts copying to the clipboard what is written to the `ChildStdin`, which only replaces its content once the `ChildSink` is finished.
+///
+/// With `clear_after`, a background shell then waits for this many seconds and clears the clipboard, unless something else was copied meanwhile. It's left running when doby exits.
+pub fn copy(clear_after: Option<u64>) -> Result<(ChildSink, ChildStdin), Error> {
+    let tool = tool()?;
+    let (mut sink, stdin) = ChildSink::spawn(&mut command(tool.copy)).map_err(|e| Error::Clipboard(e.to_string()))?;
+    if let Some(delay) = clear_after {
+        let paste = tool.paste.join(" ");
+        //the first line only comes once the copy succeeded, the second one tells that the content was read to compare it (through its checksum) later
+        let script = format!(
+            "trap '' HUP INT; read -r _ || exit 0; sum=$({paste} | cksum); echo; exec > /dev/null; sleep \"$1\"; [ \"$({paste} | cksum)\" = \"$sum\" ] && {clear}",
+            paste = paste,
+            clear = tool.clear,
+        );
+        sink = sink.with_follow_up(Command::new("sh").arg("-c").arg(script).arg("sh").arg(delay.to_string()))
+            .map_err(|e| Error::Clipboard(e.to_string()))?;
+    }
+    Ok((sink, stdin))
+}
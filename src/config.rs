@@ -0,0 +1,139 @@
+use std::{env, fs, io, path::PathBuf};
+use crate::{Error, cli::{cipher_by_name, mac_by_name}, crypto::{ARGON2_PROFILES, CipherAlgorithm, MacAlgorithm}};
+
+/// Environment variables overriding the keys of the configuration file.
+pub const ENV_VARS: [(&str, &str); 9] = [
+    ("DOBY_CIPHER", "cipher"),
+    ("DOBY_MAC", "mac"),
+    ("DOBY_ARGON2_PROFILE", "profile"),
+    ("DOBY_ARGON2_TIME_COST", "time_cost"),
+    ("DOBY_ARGON2_MEMORY_COST", "memory_cost"),
+    ("DOBY_ARGON2_PARALLELISM", "parallelism"),
+    ("DOBY_BLOCK_SIZE", "block_size"),
+    ("DOBY_THREADS", "threads"),
+    ("DOBY_INTERACTIVE", "interactive"),
+];
+
+/// `$XDG_CONFIG_HOME/doby`, or `~/.config/doby` by default.
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("doby"))
+}
+
+/// Default options read from the configuration file, then from the `DOBY_*` environment variables. Options given on the command line take precedence.
+///
+/// The file uses a subset of TOML: `key = value` lines with strings, integers and booleans, and `#` comments.
+#[derive(Default)]
+pub struct Config {
+    pub cipher: Option<CipherAlgorithm>,
+    /// Ignored with AEAD ciphers.
+    pub mac: Option<MacAlgorithm>,
+    pub profile: Option<&'static str>,
+    pub time_cost: Option<u32>,
+    pub memory_cost: Option<u32>,
+    pub parallelism: Option<u32>,
+    pub block_size: Option<usize>,
+    pub threads: Option<usize>,
+    /// Prompt before overwriting files, like `--interactive`.
+    pub interactive: bool,
+}
+
+enum Value {
+    String(String),
+    Integer(u64),
+    Boolean(bool),
+}
+
+fn parse_value(s: &str) -> Option<Value> {
+    if let Some(rest) = s.strip_prefix('"') {
+        let end = rest.find('"')?;
+        let (string, tail) = rest.split_at(end);
+        if string.contains('\\') || !(tail[1..].trim().is_empty() || tail[1..].trim_start().starts_with('#')) {
+            return None;
+        }
+        return Some(Value::String(string.to_string()));
+    }
+    let s = s.split('#').next().unwrap().trim();
+    match s {
+        "true" => Some(Value::Boolean(true)),
+        "false" => Some(Value::Boolean(false)),
+        //TOML allows underscores between digits
+        _ => s.replace('_', "").parse().ok().map(Value::Integer),
+    }
+}
+
+impl Config {
+    /// `$DOBY_CONFIG`, or config.toml in `config_dir()`.
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("DOBY_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        config_dir().map(|dir| dir.join("config.toml"))
+    }
+
+    /// Reads the configuration file and the environment variables. A missing file is only an error if it was given with `DOBY_CONFIG`.
+    pub fn load() -> Result<Self, Error> {
+        let mut config = match Self::path().map(|path| (fs::read_to_string(&path), path)) {
+            Some((Ok(content), path)) => Self::parse(&content, &path.display().to_string())?,
+            Some((Err(e), _)) if e.kind() == io::ErrorKind::NotFound && env::var_os("DOBY_CONFIG").is_none() => Self::default(),
+            Some((Err(error), path)) => return Err(Error::Path { path: path.display().to_string(), error }),
+            None => Self::default(),
+        };
+        for (name, key) in ENV_VARS {
+            if let Some(value) = env::var_os(name) {
+                let value = value.into_string().map_err(|_| Error::InvalidEnvVar { name: name.to_string(), message: String::from("not valid UTF-8") })?;
+                //strings don't need quotes
+                let value = parse_value(value.trim()).unwrap_or(Value::String(value));
+                config.set(key, value).map_err(|message| Error::InvalidEnvVar { name: name.to_string(), message })?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// `path` is only used in error messages.
+    pub fn parse(content: &str, path: &str) -> Result<Self, Error> {
+        let mut config = Self::default();
+        for (i, line) in content.lines().enumerate() {
+            let error = |message: String| Error::InvalidConfig { path: path.to_string(), line: i+1, message };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| error(String::from("expected key = value")))?;
+            let key = key.trim();
+            if !ENV_VARS.iter().any(|(_, known)| *known == key) {
+                return Err(error(format!("unknown option \"{}\"", key)));
+            }
+            let value = parse_value(value.trim()).ok_or_else(|| error(format!("{}: invalid value", key)))?;
+            config.set(key, value).map_err(|message| error(format!("{}: {}", key, message)))?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match (key, value) {
+            ("cipher", Value::String(name)) => self.cipher = Some(cipher_by_name(&name).ok_or_else(|| format!("unknown cipher \"{}\"", name))?),
+            ("mac", Value::String(name)) => self.mac = Some(mac_by_name(&name).ok_or_else(|| format!("unknown hash function \"{}\"", name))?),
+            ("profile", Value::String(name)) => self.profile = Some(
+                ARGON2_PROFILES.iter().find(|(profile, ..)| *profile == name).map(|(profile, ..)| *profile).ok_or_else(|| format!("unknown profile \"{}\"", name))?
+            ),
+            ("time_cost", Value::Integer(n)) => self.time_cost = Some(integer(n)?),
+            ("memory_cost", Value::Integer(n)) => self.memory_cost = Some(integer(n)?),
+            ("parallelism", Value::Integer(n)) => self.parallelism = Some(integer(n)?),
+            ("block_size", Value::Integer(n)) => self.block_size = Some(integer(n).and_then(|n| if n == 0 { Err(String::from("must not be 0")) } else { Ok(n) })?),
+            ("threads", Value::Integer(n)) => self.threads = Some(integer(n)?),
+            ("interactive", Value::Boolean(b)) => self.interactive = b,
+            ("cipher" | "mac" | "profile", _) => return Err(String::from("expected a string")),
+            ("interactive", _) => return Err(String::from("expected true or false")),
+            _ => return Err(String::from("expected an integer")),
+        }
+        Ok(())
+    }
+}
+
+fn integer<T: TryFrom<u64>>(n: u64) -> Result<T, String> {
+    T::try_from(n).map_err(|_| String::from("too large"))
+}
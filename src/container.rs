@@ -0,0 +1,290 @@
+use std::{io::{self, Read, Seek, SeekFrom, Write}, path::{Component, Path}};
+use zeroize::Zeroizing;
+use crate::{
+    Error,
+    Metadata,
+    MetadataWriter,
+    ProgressReader,
+    crypto::{DobyCipher, EncryptionParams, KEY_LEN},
+    decrypt,
+    encrypt,
+    read_header,
+};
+
+pub const CONTAINER_MAGIC: &[u8; 7] = b"dobybox";
+pub const CONTAINER_VERSION: u8 = 1;
+const MAGIC_LEN: u64 = CONTAINER_MAGIC.len() as u64 + 1;
+const SEGMENT_LEN_SIZE: u64 = 8;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Path of `path` inside a container: its normal components joined with '/'. Root and "." components are dropped, like tar does, while ".." is rejected.
+pub fn entry_name<P: AsRef<Path>>(path: P) -> Result<String, Error> {
+    let path = path.as_ref();
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_str().ok_or_else(|| Error::InvalidEntryName(path.display().to_string()))?),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return Err(Error::InvalidEntryName(path.display().to_string())),
+        }
+    }
+    let name = components.join("/");
+    if name.is_empty() || name.len() > u16::MAX as usize {
+        return Err(Error::InvalidEntryName(path.display().to_string()));
+    }
+    Ok(name)
+}
+
+/// A file stored in a container, as listed in its index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContainerEntry {
+    /// Relative path, with '/' as separator.
+    pub name: String,
+    /// Plaintext size.
+    pub size: u64,
+    offset: u64,
+    len: u64,
+}
+
+fn encode_index(entries: &[ContainerEntry]) -> Vec<u8> {
+    let mut index = (entries.len() as u32).to_be_bytes().to_vec();
+    for entry in entries {
+        index.extend_from_slice(&(entry.name.len() as u16).to_be_bytes());
+        index.extend_from_slice(entry.name.as_bytes());
+        index.extend_from_slice(&entry.size.to_be_bytes());
+        index.extend_from_slice(&entry.offset.to_be_bytes());
+        index.extend_from_slice(&entry.len.to_be_bytes());
+    }
+    index
+}
+
+fn decode_index(mut index: &[u8]) -> io::Result<Vec<ContainerEntry>> {
+    fn take<'a>(index: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+        if index.len() < len {
+            return Err(invalid_data("truncated container index"));
+        }
+        let (value, rest) = index.split_at(len);
+        *index = rest;
+        Ok(value)
+    }
+    let u64_at = |index: &mut &[u8]| take(index, 8).map(|b| u64::from_be_bytes(b.try_into().unwrap()));
+    let count = u32::from_be_bytes(take(&mut index, 4)?.try_into().unwrap());
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let name_len = u16::from_be_bytes(take(&mut index, 2)?.try_into().unwrap()) as usize;
+        let name = String::from_utf8(take(&mut index, name_len)?.to_vec()).map_err(|_| invalid_data("non UTF-8 entry name"))?;
+        //extraction must not escape the destination
+        if entry_name(&name).ok().as_deref() != Some(name.as_str()) {
+            return Err(invalid_data("unsafe path in container"));
+        }
+        entries.push(ContainerEntry {
+            name,
+            size: u64_at(&mut index)?,
+            offset: u64_at(&mut index)?,
+            len: u64_at(&mut index)?,
+        });
+    }
+    if !index.is_empty() {
+        return Err(invalid_data("trailing data after the container index"));
+    }
+    Ok(entries)
+}
+
+struct CountingWriter<'a, W: Write> {
+    writer: &'a mut W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes a container: several files encrypted separately, followed by an encrypted index of their names, sizes and positions, so that any of them can be listed and extracted without decrypting the others.
+///
+/// Each file and the index is a complete doby ciphertext (called segment) followed by its length as 8 bytes big-endian, the last one being the current index. When entries are appended, the previous index is left in place and a new one is written after them. All segments share the master key and the key slots of `params`, but each one gets its own salt, from which its encryption keys and nonce are derived.
+pub struct ContainerWriter<W: Write> {
+    writer: W,
+    position: u64,
+    params: EncryptionParams,
+    master_key: Zeroizing<[u8; KEY_LEN]>,
+    block_size: usize,
+    entries: Vec<ContainerEntry>,
+}
+
+impl<W: Write> ContainerWriter<W> {
+    /// `params` must use key slots or a raw key: password-derived parameters would require running Argon2 for each segment.
+    pub fn new(mut writer: W, params: EncryptionParams, master_key: &[u8; KEY_LEN], block_size: usize) -> Result<Self, Error> {
+        let mut params = params;
+        params.renew_salt()?;
+        params.metadata = false;
+        writer.write_all(CONTAINER_MAGIC)?;
+        writer.write_all(&[CONTAINER_VERSION])?;
+        Ok(Self {
+            writer,
+            position: MAGIC_LEN,
+            params,
+            master_key: Zeroizing::new(*master_key),
+            block_size,
+            entries: Vec::new(),
+        })
+    }
+
+    pub fn entries(&self) -> &[ContainerEntry] {
+        &self.entries
+    }
+
+    //returns the position and length of the segment
+    fn write_segment<R: Read>(&mut self, reader: &mut R, metadata: bool) -> Result<(u64, u64), Error> {
+        let mut params = self.params.clone();
+        params.renew_salt()?;
+        params.metadata = metadata;
+        let cipher = DobyCipher::with_master_key(&self.master_key, &params)?;
+        let mut writer = CountingWriter { writer: &mut self.writer, count: 0 };
+        encrypt(reader, &mut writer, &params, cipher, self.block_size, None)?;
+        let len = writer.count;
+        self.writer.write_all(&len.to_be_bytes())?;
+        let offset = self.position;
+        self.position += len + SEGMENT_LEN_SIZE;
+        Ok((offset, len))
+    }
+
+    /// Encrypts the content of `reader` as a new entry. `metadata` is stored with it, its name being replaced by `name`.
+    pub fn add<R: Read>(&mut self, name: &str, metadata: Metadata, reader: &mut R) -> Result<(), Error> {
+        let name = entry_name(name)?;
+        if self.entries.iter().any(|entry| entry.name == name) {
+            return Err(Error::DuplicateEntry(name));
+        }
+        let mut metadata = metadata;
+        metadata.name = Some(name.clone());
+        let mut size = 0;
+        let (offset, len) = self.write_segment(&mut metadata.to_bytes().as_slice().chain(ProgressReader::new(reader, |n| size = n)), true)?;
+        self.entries.push(ContainerEntry { name, size, offset, len });
+        Ok(())
+    }
+
+    /// Writes the index and returns the inner writer. Dropping a `ContainerWriter` without calling `finish` produces an unreadable container.
+    pub fn finish(mut self) -> Result<W, Error> {
+        let index = encode_index(&self.entries);
+        self.write_segment(&mut index.as_slice(), false)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+pub fn is_container(first_bytes: &[u8]) -> bool {
+    first_bytes.starts_with(CONTAINER_MAGIC)
+}
+
+/// Reads a container written by `ContainerWriter`. Only the segments of the index and of the extracted entries are read and decrypted.
+pub struct Container<R: Read + Seek> {
+    reader: R,
+    index_offset: u64,
+    index_len: u64,
+    params: EncryptionParams,
+}
+
+impl<R: Read + Seek> Container<R> {
+    pub fn open(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0; MAGIC_LEN as usize];
+        reader.rewind()?;
+        match reader.read_exact(&mut magic) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(Error::UnknownFormat),
+            result => result?,
+        }
+        if !is_container(&magic) {
+            return Err(Error::UnknownFormat);
+        }
+        if magic[CONTAINER_MAGIC.len()] != CONTAINER_VERSION {
+            return Err(Error::UnsupportedVersion(magic[CONTAINER_MAGIC.len()]));
+        }
+        let end = reader.seek(SeekFrom::End(0))?;
+        if end < MAGIC_LEN + SEGMENT_LEN_SIZE {
+            return Err(invalid_data("truncated container").into());
+        }
+        reader.seek(SeekFrom::Start(end - SEGMENT_LEN_SIZE))?;
+        let mut len = [0; SEGMENT_LEN_SIZE as usize];
+        reader.read_exact(&mut len)?;
+        let index_len = u64::from_be_bytes(len);
+        let index_offset = (end - SEGMENT_LEN_SIZE).checked_sub(index_len)
+            .filter(|offset| *offset >= MAGIC_LEN)
+            .ok_or_else(|| invalid_data("invalid container index position"))?;
+        reader.seek(SeekFrom::Start(index_offset))?;
+        let params = read_header(&mut (&mut reader).take(index_len))?;
+        Ok(Self {
+            reader,
+            index_offset,
+            index_len,
+            params,
+        })
+    }
+
+    /// Encryption parameters of the index, holding the key slots from which the master key can be unwrapped.
+    pub fn params(&self) -> &EncryptionParams {
+        &self.params
+    }
+
+    /// Decrypts and authenticates the segment at `offset`, checking that it uses the same master key.
+    fn decrypt_segment<W: Write>(&mut self, offset: u64, len: u64, master_key: &[u8; KEY_LEN], writer: &mut W, block_size: usize) -> Result<EncryptionParams, Error> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut reader = (&mut self.reader).take(len);
+        let params = read_header(&mut reader)?;
+        if params.key_derivation != self.params.key_derivation {
+            return Err(invalid_data("container segment encrypted with other keys").into());
+        }
+        let cipher = DobyCipher::with_master_key(master_key, &params)?;
+        decrypt(&mut reader, writer, cipher, block_size)?;
+        Ok(params)
+    }
+
+    pub fn entries(&mut self, master_key: &[u8; KEY_LEN]) -> Result<Vec<ContainerEntry>, Error> {
+        let mut index = Vec::new();
+        let params = self.decrypt_segment(self.index_offset, self.index_len, master_key, &mut index, 65536)?;
+        if params.metadata {
+            return Err(invalid_data("invalid container index").into());
+        }
+        let entries = decode_index(&index)?;
+        if entries.iter().any(|entry| entry.offset.checked_add(entry.len).is_none_or(|end| end > self.index_offset)) {
+            return Err(invalid_data("invalid container entry position").into());
+        }
+        Ok(entries)
+    }
+
+    /// Decrypts the content of `entry` to `writer` and returns its metadata. As usual, the output must be discarded if an error is returned.
+    pub fn extract<W: Write>(&mut self, entry: &ContainerEntry, master_key: &[u8; KEY_LEN], writer: &mut W, block_size: usize) -> Result<Metadata, Error> {
+        let mut writer = MetadataWriter::new(writer);
+        let params = self.decrypt_segment(entry.offset, entry.len, master_key, &mut writer, block_size)?;
+        let metadata = writer.into_metadata()?;
+        //the index could point to another entry
+        if !params.metadata || metadata.name.as_deref() != Some(entry.name.as_str()) {
+            return Err(invalid_data("container entry doesn't match the index").into());
+        }
+        Ok(metadata)
+    }
+}
+
+impl<W: Read + Write + Seek> Container<W> {
+    /// Returns a `ContainerWriter` appending entries after the current index. The existing segments are left untouched, so if appending fails, truncating the container back to its previous length restores it.
+    pub fn append(mut self, master_key: &[u8; KEY_LEN], block_size: usize) -> Result<ContainerWriter<W>, Error> {
+        let entries = self.entries(master_key)?;
+        let position = self.reader.seek(SeekFrom::End(0))?;
+        Ok(ContainerWriter {
+            writer: self.reader,
+            position,
+            params: self.params,
+            master_key: Zeroizing::new(*master_key),
+            block_size,
+            entries,
+        })
+    }
+}
@@ -1,25 +1,128 @@
-use std::{convert::TryFrom, fmt::{self, Display, Formatter}, io::{self, Read, Write}};
-use blake2::{Blake2b, VarBlake2b, digest::{Update, VariableOutput}};
+use std::{convert::TryFrom, fmt::{self, Display, Formatter}, io::{self, IoSlice, Read, Write}, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use blake2::Blake2b;
 use num_enum::TryFromPrimitive;
 use chacha20::XChaCha20;
-use aes::{Aes256Ctr, cipher::{NewCipher, StreamCipher}};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305, Tag, aead::{Aead, AeadInPlace, NewAead, generic_array::GenericArray}};
+use aes_gcm::Aes256Gcm;
+use aes::{Aes256Ctr, cipher::{NewCipher, StreamCipher, StreamCipherSeek}};
 use subtle::ConstantTimeEq;
-use rand::{Rng, rngs::OsRng};
-use argon2::{Argon2, Version, Algorithm};
+use rand::{Rng, RngCore, rngs::OsRng};
+#[cfg(feature = "parallel")]
+use rayon::{iter::{IndexedParallelIterator, ParallelIterator}, slice::ParallelSliceMut};
+use argon2::{Argon2, Block, Version, Algorithm};
 use hkdf::Hkdf;
-use zeroize::Zeroize;
+use log::debug;
+use unicode_normalization::UnicodeNormalization;
+use zeroize::{Zeroize, Zeroizing};
+use crate::{Error, PlaintextDigest, memlock::Locked};
 
+pub const FORMAT_VERSION: u8 = 1;
+const LEGACY_FORMAT_VERSION: u8 = 0;
+//random master key wrapped in one or more key slots
+pub const KEY_SLOTS_FORMAT_VERSION: u8 = 2;
+//master key given directly by the user, without any KDF
+pub const RAW_KEY_FORMAT_VERSION: u8 = 3;
+//same as FORMAT_VERSION followed by a key check value, to detect wrong passwords before decrypting
+pub const KEY_CHECK_FORMAT_VERSION: u8 = 4;
+pub const LATEST_FORMAT_VERSION: u8 = KEY_CHECK_FORMAT_VERSION;
+pub const KEY_CHECK_LEN: usize = 8;
 pub const SALT_LEN: usize = 64;
 const AES_NONCE_LEN: usize = 16;
 const XCHACHA20_NONCE_LEN: usize = 24;
+const AES_GCM_NONCE_LEN: usize = 12;
+const AEAD_NONCE_SUFFIX_LEN: usize = 5; //4 bytes counter + 1 byte last flag
 pub const HMAC_LEN: usize = 32;
-const KEY_LEN: usize = 32;
+pub const AEAD_TAG_LEN: usize = 16;
+pub const AEAD_CHUNK_SIZE: usize = 65536;
+pub const KEY_LEN: usize = 32;
+/// Key passed to `DobyCipher::with_master_key`, locked in memory and zeroized when dropped.
+pub type MasterKey = Locked<[u8; KEY_LEN]>;
+pub const X25519_KEY_LEN: usize = 32;
+pub const WRAPPED_KEY_LEN: usize = KEY_LEN + AEAD_TAG_LEN;
+const KEY_SLOT_X25519: u8 = 1;
+const KEY_SLOT_PASSWORD: u8 = 2;
+const KEY_SLOT_PKCS11: u8 = 3;
+pub const MAX_PKCS11_KEY_ID_LEN: usize = 255;
+const KEY_SLOT_SSH_AGENT: u8 = 4;
+pub const SSH_CHALLENGE_LEN: usize = 32;
+const KEY_SLOT_PLUGIN: u8 = 5;
+pub const MAX_PLUGIN_NAME_LEN: usize = 255;
+const KEY_SLOT_TPM: u8 = 6;
+pub const MAX_TPM_PCR: u8 = 23;
+
+/// Argon2 (time cost, memory cost, parallelism) presets selectable with `--profile`.
+pub const ARGON2_PROFILES: [(&str, u32, u32, u32); 3] = [
+    ("fast", 2, 4096, 4),
+    ("balanced", 10, 4096, 4),
+    ("paranoid", 40, 524288, 16),
+];
+/// Preset used when `--profile` isn't given.
+pub const DEFAULT_ARGON2_PROFILE: &str = "balanced";
+
+/// One of the `ARGON2_PROFILES`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Argon2Profile {
+    Fast,
+    #[default]
+    Balanced,
+    Paranoid,
+}
+
+impl Argon2Profile {
+    pub fn name(self) -> &'static str {
+        match self {
+            Argon2Profile::Fast => "fast",
+            Argon2Profile::Balanced => "balanced",
+            Argon2Profile::Paranoid => "paranoid",
+        }
+    }
+
+    pub fn params(self) -> argon2::Params {
+        let (_, t_cost, m_cost, p_cost) = *ARGON2_PROFILES.iter().find(|(name, ..)| *name == self.name()).unwrap();
+        argon2::Params::new(m_cost, t_cost, p_cost, None).unwrap()
+    }
+}
+/// Largest Argon2 memory cost accepted, in KiB (4 GiB), so that a hostile header can't make key derivation allocate arbitrary amounts of memory.
+pub const MAX_ARGON2_MEMORY_COST: u32 = 4 * 1024 * 1024;
+
+/// Largest Argon2 costs accepted from a file (`--max-time-cost` and `--max-memory-cost`), since they are chosen by whoever wrote it. The defaults are above those of the "paranoid" profile.
+#[derive(Clone, Copy, Debug)]
+pub struct Argon2Limits {
+    pub time_cost: u32,
+    /// In KiB.
+    pub memory_cost: u32,
+}
+
+impl Default for Argon2Limits {
+    fn default() -> Self {
+        Self {
+            time_cost: 100,
+            memory_cost: 1024 * 1024,
+        }
+    }
+}
+
+impl Argon2Limits {
+    /// Fails with `Error::Argon2LimitExceeded` if opening a file with `params` requires higher costs.
+    pub fn check(&self, params: &EncryptionParams) -> Result<(), Error> {
+        let argon2_params = params.argon2_params();
+        let time_cost = argon2_params.iter().map(|argon2| argon2.t_cost()).max().unwrap_or(0);
+        let memory_cost = argon2_params.iter().map(|argon2| argon2.m_cost()).max().unwrap_or(0);
+        if time_cost > self.time_cost || memory_cost > self.memory_cost {
+            Err(Error::Argon2LimitExceeded { time_cost, memory_cost })
+        } else {
+            Ok(())
+        }
+    }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum CipherAlgorithm {
     AesCtr = 0,
     XChaCha20 = 1,
+    XChaCha20Poly1305 = 2,
+    AesGcm = 3,
 }
 
 impl CipherAlgorithm {
@@ -27,153 +130,1564 @@ impl CipherAlgorithm {
         match self {
             CipherAlgorithm::AesCtr => AES_NONCE_LEN,
             CipherAlgorithm::XChaCha20 => XCHACHA20_NONCE_LEN,
+            CipherAlgorithm::XChaCha20Poly1305 => XCHACHA20_NONCE_LEN - AEAD_NONCE_SUFFIX_LEN,
+            CipherAlgorithm::AesGcm => AES_GCM_NONCE_LEN - AEAD_NONCE_SUFFIX_LEN,
+        }
+    }
+
+    pub fn is_aead(&self) -> bool {
+        matches!(self, CipherAlgorithm::XChaCha20Poly1305 | CipherAlgorithm::AesGcm)
+    }
+}
+
+impl Display for CipherAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CipherAlgorithm::AesCtr => "AES-CTR",
+            CipherAlgorithm::XChaCha20 => "XChaCha20",
+            CipherAlgorithm::XChaCha20Poly1305 => "XChaCha20-Poly1305",
+            CipherAlgorithm::AesGcm => "AES-GCM",
+        })
+    }
+}
+
+/// Hash function used to authenticate the ciphertext of non-AEAD ciphers. Stored in bits 4 and 5 of the cipher byte.
+///
+/// BLAKE3 requires the `blake3` feature: without it, headers of files authenticated with BLAKE3 are invalid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum MacAlgorithm {
+    Blake2b = 0,
+    #[cfg(feature = "blake3")]
+    Blake3 = 1,
+}
+
+impl Display for MacAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MacAlgorithm::Blake2b => "BLAKE2b",
+            #[cfg(feature = "blake3")]
+            MacAlgorithm::Blake3 => "BLAKE3",
+        })
+    }
+}
+
+//bit 7 of the cipher byte
+const METADATA_FLAG: u8 = 0x80;
+//bit 6 of the cipher byte: the header ends with extensions
+const EXTENSIONS_FLAG: u8 = 0x40;
+//extension types with this bit set can't be ignored by decoders that don't know them
+const CRITICAL_EXTENSION: u8 = 0x80;
+/// Header extension holding the comment given with `--comment`.
+pub const COMMENT_EXTENSION: u8 = 1;
+pub const MAX_COMMENT_LEN: usize = 255;
+/// Header extension holding the Ed25519 public key of the signer given with `--sign-key`. Critical because the signature is appended after the ciphertext.
+pub const SIGNATURE_EXTENSION: u8 = 2 | CRITICAL_EXTENSION;
+pub const SIGNER_KEY_LEN: usize = 32;
+/// Length of the Ed25519 signature following the ciphertext of signed files.
+pub const SIGNATURE_LEN: usize = 64;
+/// Header extension holding the YubiKey slot given with `--yubikey`. Critical because the master key also depends on the response of the token.
+pub const YUBIKEY_EXTENSION: u8 = 3 | CRITICAL_EXTENSION;
+pub const YUBIKEY_CHALLENGE_LEN: usize = 32;
+/// Header extension holding the master key encrypted by a cloud KMS with `--kms-key-id`. Not critical: files that also have key slots can still be decrypted without the KMS.
+pub const KMS_EXTENSION: u8 = 4;
+/// Empty header extension telling that passwords were normalized to Unicode NFC before being fed to Argon2. Only needed for non-ASCII passwords, which NFC can change. Not critical: older versions still decrypt files whose password was already in NFC form.
+pub const NFC_PASSWORDS_EXTENSION: u8 = 5;
+/// Header extension holding the digest of the plaintext computed with `--digest`. Not critical: the ciphertext is still authenticated without it.
+pub const PLAINTEXT_DIGEST_EXTENSION: u8 = 6;
+/// Empty header extension telling that the plaintext (after the metadata, if any) is encoded by `SparseReader`, so that the holes of the input aren't stored. Critical: older versions would output the encoding as is.
+pub const SPARSE_EXTENSION: u8 = 7 | CRITICAL_EXTENSION;
+/// Header extension holding the IDs (see `key_id`) of the X25519 recipients, in the order of their key slots, or of the raw key. Not critical: they only help finding the key to decrypt with.
+pub const KEY_IDS_EXTENSION: u8 = 8;
+pub const KEY_ID_LEN: usize = 4;
+//extension types understood by this version
+const KNOWN_EXTENSIONS: [u8; 8] = [COMMENT_EXTENSION, SIGNATURE_EXTENSION, YUBIKEY_EXTENSION, KMS_EXTENSION, NFC_PASSWORDS_EXTENSION, PLAINTEXT_DIGEST_EXTENSION, SPARSE_EXTENSION, KEY_IDS_EXTENSION];
+
+//(cipher, mac, metadata, extensions)
+fn decode_algorithms(byte: u8) -> Result<(CipherAlgorithm, MacAlgorithm, bool, bool), Error> {
+    let cipher = CipherAlgorithm::try_from(byte & 0x0f).map_err(|_| Error::InvalidHeader)?;
+    let mac = MacAlgorithm::try_from((byte & !(METADATA_FLAG | EXTENSIONS_FLAG)) >> 4).map_err(|_| Error::InvalidHeader)?;
+    Ok((cipher, mac, byte & METADATA_FLAG != 0, byte & EXTENSIONS_FLAG != 0))
+}
+
+/// Type-length-value record stored at the end of the header, and thus authenticated with the ciphertext.
+///
+/// Decoders skip the types they don't know, unless they are critical.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderExtension {
+    pub kind: u8,
+    pub value: Vec<u8>,
+}
+
+impl HeaderExtension {
+    pub fn is_critical(&self) -> bool {
+        self.kind & CRITICAL_EXTENSION != 0
+    }
+}
+
+//2 bytes total length followed by (type, 2 bytes length, value) records
+fn write_extensions<W: Write>(writer: &mut W, extensions: &[HeaderExtension]) -> io::Result<()> {
+    let len: usize = extensions.iter().map(|extension| 3 + extension.value.len()).sum();
+    writer.write_all(&(len as u16).to_be_bytes())?;
+    for extension in extensions {
+        writer.write_all(&[extension.kind])?;
+        writer.write_all(&(extension.value.len() as u16).to_be_bytes())?;
+        writer.write_all(&extension.value)?;
+    }
+    Ok(())
+}
+
+fn read_extensions<R: Read>(reader: &mut R) -> Result<Vec<HeaderExtension>, Error> {
+    let mut len = [0; 2];
+    reader.read_exact(&mut len)?;
+    let mut buff = vec![0; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut buff)?;
+    //the flag is only set when there are extensions, so that the header is encoded the same way when written back
+    if buff.is_empty() {
+        return Err(Error::InvalidHeader);
+    }
+    let mut records = buff.as_slice();
+    let mut extensions = Vec::new();
+    while !records.is_empty() {
+        if records.len() < 3 {
+            return Err(Error::InvalidHeader);
+        }
+        let len = u16::from_be_bytes([records[1], records[2]]) as usize;
+        let value = records.get(3..3+len).ok_or(Error::InvalidHeader)?;
+        let extension = HeaderExtension { kind: records[0], value: value.to_vec() };
+        if extension.is_critical() && !KNOWN_EXTENSIONS.contains(&extension.kind) {
+            return Err(Error::UnsupportedExtension(extension.kind));
+        }
+        extensions.push(extension);
+        records = &records[3+len..];
+    }
+    Ok(extensions)
+}
+
+fn write_argon2_params<W: Write>(writer: &mut W, argon2: &argon2::Params) -> io::Result<()> {
+    writer.write_all(&argon2.t_cost().to_be_bytes())?;
+    writer.write_all(&argon2.m_cost().to_be_bytes())?;
+    writer.write_all(&argon2.p_cost().to_be_bytes())
+}
+
+fn read_argon2_params<R: Read>(reader: &mut R) -> Result<argon2::Params, Error> {
+    let mut t_cost = [0; 4];
+    reader.read_exact(&mut t_cost)?;
+    let mut m_cost = [0; 4];
+    reader.read_exact(&mut m_cost)?;
+    let mut p_cost = [0; 4];
+    reader.read_exact(&mut p_cost)?;
+    argon2::Params::new(
+        u32::from_be_bytes(m_cost),
+        u32::from_be_bytes(t_cost),
+        u32::from_be_bytes(p_cost),
+        None
+    ).map_err(|_| Error::InvalidHeader)
+}
+
+//nanoseconds spent in argon2_hash, summed over all threads
+static ARGON2_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Total time this process spent running Argon2, e.g. to compare it to the time spent encrypting with `--stats`.
+pub fn argon2_time() -> Duration {
+    Duration::from_nanos(ARGON2_TIME.load(Ordering::Relaxed))
+}
+
+//the debug messages are timestamped by the command line, showing how long Argon2 takes
+fn argon2_hash(password: &[u8], salt: &[u8], argon2_params: &argon2::Params) -> Result<Locked<[u8; KEY_LEN]>, Error> {
+    if argon2_params.m_cost() > MAX_ARGON2_MEMORY_COST {
+        return Err(Error::InvalidParams(argon2::Error::MemoryTooMuch));
+    }
+    //allocated here rather than by the argon2 crate, so that running out of memory is an error instead of an abort
+    let block_count = argon2_params.m_cost().max(8 * argon2_params.p_cost()) as usize;
+    let mut blocks = Vec::new();
+    blocks.try_reserve_exact(block_count).map_err(|_| Error::InvalidParams(argon2::Error::MemoryTooMuch))?;
+    blocks.resize(block_count, Block::default());
+    debug!("running Argon2id (t={} m={}KiB p={})", argon2_params.t_cost(), argon2_params.m_cost(), argon2_params.p_cost());
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params.clone());
+    let mut key = Locked::new([0; KEY_LEN]);
+    //there is no clock in browsers
+    #[cfg(not(target_arch = "wasm32"))]
+    let start = std::time::Instant::now();
+    argon2.hash_password_into_with_memory(password, salt, &mut *key, &mut blocks)?;
+    #[cfg(not(target_arch = "wasm32"))]
+    ARGON2_TIME.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    debug!("Argon2id done");
+    Ok(key)
+}
+
+/// NFC form of UTF-8 passwords, so that the same characters give the same bytes whatever the system they were typed on (e.g. "é" is decomposed on macOS). Other passwords are returned as is.
+pub fn nfc_password(password: &[u8]) -> Zeroizing<Vec<u8>> {
+    match std::str::from_utf8(password) {
+        Ok(password) => Zeroizing::new(password.nfc().collect::<String>().into_bytes()),
+        Err(_) => Zeroizing::new(password.to_vec()),
+    }
+}
+
+//only fails if the output is longer than 255 BLAKE2b blocks
+fn hkdf_expand(hkdf: &Hkdf<Blake2b>, info: &[u8], output: &mut [u8]) -> Result<(), Error> {
+    hkdf.expand(info, output).map_err(|_| Error::InvalidHeader)
+}
+
+/// Expands the master key and the salt into the keys of a file: with HKDF-BLAKE2b, or with BLAKE3 in key derivation mode when the file is authenticated with BLAKE3.
+enum Kdf {
+    Blake2b(Box<Hkdf<Blake2b>>),
+    #[cfg(feature = "blake3")]
+    Blake3(Zeroizing<Vec<u8>>),
+}
+
+impl Kdf {
+    fn new(mac: MacAlgorithm, salt: &[u8], master_key: &[u8; KEY_LEN]) -> Self {
+        match mac {
+            MacAlgorithm::Blake2b => Kdf::Blake2b(Box::new(Hkdf::new(Some(salt), master_key))),
+            //the master key has a fixed length, so that the concatenation is unambiguous
+            #[cfg(feature = "blake3")]
+            MacAlgorithm::Blake3 => Kdf::Blake3(Zeroizing::new([master_key.as_slice(), salt].concat())),
+        }
+    }
+
+    fn expand(&self, info: &'static str, output: &mut [u8]) -> Result<(), Error> {
+        match self {
+            Kdf::Blake2b(hkdf) => hkdf_expand(hkdf, info.as_bytes(), output),
+            #[cfg(feature = "blake3")]
+            Kdf::Blake3(key_material) => {
+                blake3::Hasher::new_derive_key(info).update(key_material).finalize_xof().fill(output);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn key_check(master_key: &[u8; KEY_LEN], salt: &[u8]) -> [u8; KEY_CHECK_LEN] {
+    let mut key_check = [0; KEY_CHECK_LEN];
+    Hkdf::<Blake2b>::new(Some(salt), master_key).expand(b"doby_key_check", &mut key_check).unwrap();
+    key_check
+}
+
+/// Short non-secret ID of a public key or of a raw key. Derived with HKDF so that the ID of a raw key reveals nothing about it. With 32 bits, different keys can share an ID: it only tells which key to try.
+pub fn key_id(key: &[u8]) -> [u8; KEY_ID_LEN] {
+    let mut key_id = [0; KEY_ID_LEN];
+    Hkdf::<Blake2b>::new(None, key).expand(b"doby_key_id", &mut key_id).unwrap();
+    key_id
+}
+
+pub fn format_key_id(key_id: &[u8; KEY_ID_LEN]) -> String {
+    key_id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes exactly `N` bytes of hexadecimal, such as a key given on the command line.
+#[cfg(feature = "os")]
+pub(crate) fn decode_hex<const N: usize>(hex: &str) -> Option<Zeroizing<[u8; N]>> {
+    let hex = hex.as_bytes();
+    if hex.len() != N*2 || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let mut key = Zeroizing::new([0; N]);
+    for (i, pair) in hex.chunks(2).enumerate() {
+        key[i] = u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap();
+    }
+    Some(key)
+}
+
+/// Plugin names are made of lowercase ASCII letters, digits, '-' and '_', so that they can't point outside of PATH.
+pub fn is_valid_plugin_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_PLUGIN_NAME_LEN && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+/// Master key derived from the Argon2 output and the HMAC-SHA1 response of a YubiKey `slot`, obtained with `respond`. The challenge is derived from the Argon2 output, so it is different for each file but never reveals the key.
+fn mix_yubikey_response<F>(argon2_output: &[u8; KEY_LEN], salt: &[u8], slot: u8, respond: F) -> Result<MasterKey, Error>
+    where F: FnOnce(u8, &[u8; YUBIKEY_CHALLENGE_LEN]) -> Result<Vec<u8>, Error>
+{
+    let mut challenge = [0; YUBIKEY_CHALLENGE_LEN];
+    Hkdf::<Blake2b>::new(Some(salt), argon2_output).expand(b"doby_yubikey_challenge", &mut challenge).unwrap();
+    let mut response = respond(slot, &challenge)?;
+    let mut master_key = Locked::new([0; KEY_LEN]);
+    Hkdf::<Blake2b>::new(Some(&response), argon2_output).expand(b"doby_yubikey_master_key", &mut *master_key).unwrap();
+    response.zeroize();
+    Ok(master_key)
+}
+
+/// Argon2 parameters of `DEFAULT_ARGON2_PROFILE`, for front-ends that don't let the user choose.
+pub fn default_argon2_params() -> argon2::Params {
+    Argon2Profile::default().params()
+}
+
+/// Random key to be wrapped in key slots and passed to `DobyCipher::with_master_key`.
+pub fn generate_master_key() -> MasterKey {
+    let mut master_key = Locked::new([0; KEY_LEN]);
+    OsRng.fill(&mut *master_key);
+    master_key
+}
+
+/// Encrypts the master key with a key wrapping key. Each wrapping key must only be used once, as the nonce is constant.
+pub(crate) fn seal_key(wrapping_key: &[u8; KEY_LEN], master_key: &[u8; KEY_LEN]) -> [u8; WRAPPED_KEY_LEN] {
+    ChaCha20Poly1305::new(GenericArray::from_slice(wrapping_key))
+        .encrypt(GenericArray::from_slice(&[0; 12]), &master_key[..])
+        .unwrap()
+        .try_into()
+        .unwrap()
+}
+
+pub(crate) fn open_key(wrapping_key: &[u8; KEY_LEN], wrapped_key: &[u8; WRAPPED_KEY_LEN]) -> Option<[u8; KEY_LEN]> {
+    let mut master_key = ChaCha20Poly1305::new(GenericArray::from_slice(wrapping_key))
+        .decrypt(GenericArray::from_slice(&[0; 12]), &wrapped_key[..])
+        .ok()?;
+    let result = master_key.as_slice().try_into().ok();
+    master_key.zeroize();
+    result
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeySlot {
+    /// Master key encrypted to an X25519 public key.
+    X25519 {
+        ephemeral_public: [u8; X25519_KEY_LEN],
+        wrapped_key: [u8; WRAPPED_KEY_LEN],
+    },
+    /// Master key encrypted with a key derived from a password. Each slot has its own salt and Argon2 parameters.
+    Password {
+        salt: [u8; SALT_LEN],
+        argon2: argon2::Params,
+        wrapped_key: [u8; WRAPPED_KEY_LEN],
+    },
+    /// Master key encrypted with RSA-OAEP-SHA256 to a key pair stored in a PKCS#11 token, identified by its CKA_ID.
+    Pkcs11 {
+        key_id: Vec<u8>,
+        wrapped_key: Vec<u8>,
+    },
+    /// Master key encrypted with a key derived from the signature of `challenge` by an SSH key held in ssh-agent. `public_key` is the SSH wire encoding of the key.
+    SshAgent {
+        public_key: Vec<u8>,
+        challenge: [u8; SSH_CHALLENGE_LEN],
+        wrapped_key: [u8; WRAPPED_KEY_LEN],
+    },
+    /// Master key wrapped by the `doby-plugin-<plugin>` program. `data` is opaque to doby.
+    Plugin {
+        plugin: String,
+        data: Vec<u8>,
+    },
+    /// Master key sealed by a TPM 2.0 to the current values of `pcrs` in the sha256 bank. `public` and `private` are the TPM2B blobs of the sealed object, under the owner primary key.
+    Tpm {
+        pcrs: Vec<u8>,
+        public: Vec<u8>,
+        private: Vec<u8>,
+    },
+}
+
+impl KeySlot {
+    pub fn from_password(password: &[u8], argon2: argon2::Params, master_key: &[u8; KEY_LEN]) -> Result<Self, Error> {
+        let salt = EncryptionParams::random_salt(&mut OsRng);
+        let wrapping_key = argon2_hash(password, &salt, &argon2)?;
+        let wrapped_key = seal_key(&wrapping_key, master_key);
+        Ok(KeySlot::Password { salt, argon2, wrapped_key })
+    }
+
+    /// Returns the master key if this is a password slot and `password` is correct.
+    ///
+    /// Fails with `Error::InvalidParams` if the Argon2 parameters of the slot are out of bounds (see `MAX_ARGON2_MEMORY_COST`).
+    pub fn unwrap_password(&self, password: &[u8]) -> Result<Option<[u8; KEY_LEN]>, Error> {
+        match self {
+            KeySlot::Password { salt, argon2, wrapped_key } => {
+                let wrapping_key = argon2_hash(password, salt, argon2)?;
+                Ok(open_key(&wrapping_key, wrapped_key))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            KeySlot::X25519 { ephemeral_public, wrapped_key } => {
+                writer.write_all(&[KEY_SLOT_X25519])?;
+                writer.write_all(ephemeral_public)?;
+                writer.write_all(wrapped_key)
+            }
+            KeySlot::Password { salt, argon2, wrapped_key } => {
+                writer.write_all(&[KEY_SLOT_PASSWORD])?;
+                writer.write_all(salt)?;
+                write_argon2_params(writer, argon2)?;
+                writer.write_all(wrapped_key)
+            }
+            KeySlot::Pkcs11 { key_id, wrapped_key } => {
+                writer.write_all(&[KEY_SLOT_PKCS11, key_id.len() as u8])?;
+                writer.write_all(key_id)?;
+                writer.write_all(&(wrapped_key.len() as u16).to_be_bytes())?;
+                writer.write_all(wrapped_key)
+            }
+            KeySlot::SshAgent { public_key, challenge, wrapped_key } => {
+                writer.write_all(&[KEY_SLOT_SSH_AGENT])?;
+                writer.write_all(&(public_key.len() as u16).to_be_bytes())?;
+                writer.write_all(public_key)?;
+                writer.write_all(challenge)?;
+                writer.write_all(wrapped_key)
+            }
+            KeySlot::Plugin { plugin, data } => {
+                writer.write_all(&[KEY_SLOT_PLUGIN, plugin.len() as u8])?;
+                writer.write_all(plugin.as_bytes())?;
+                writer.write_all(&(data.len() as u16).to_be_bytes())?;
+                writer.write_all(data)
+            }
+            KeySlot::Tpm { pcrs, public, private } => {
+                writer.write_all(&[KEY_SLOT_TPM, pcrs.len() as u8])?;
+                writer.write_all(pcrs)?;
+                writer.write_all(&(public.len() as u16).to_be_bytes())?;
+                writer.write_all(public)?;
+                writer.write_all(&(private.len() as u16).to_be_bytes())?;
+                writer.write_all(private)
+            }
+        }
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let mut kind = [0; 1];
+        reader.read_exact(&mut kind)?;
+        match kind[0] {
+            KEY_SLOT_X25519 => {
+                let mut ephemeral_public = [0; X25519_KEY_LEN];
+                reader.read_exact(&mut ephemeral_public)?;
+                let mut wrapped_key = [0; WRAPPED_KEY_LEN];
+                reader.read_exact(&mut wrapped_key)?;
+                Ok(KeySlot::X25519 { ephemeral_public, wrapped_key })
+            }
+            KEY_SLOT_PASSWORD => {
+                let mut salt = [0; SALT_LEN];
+                reader.read_exact(&mut salt)?;
+                let argon2 = read_argon2_params(reader)?;
+                let mut wrapped_key = [0; WRAPPED_KEY_LEN];
+                reader.read_exact(&mut wrapped_key)?;
+                Ok(KeySlot::Password { salt, argon2, wrapped_key })
+            }
+            KEY_SLOT_PKCS11 => {
+                let mut len = [0; 1];
+                reader.read_exact(&mut len)?;
+                let mut key_id = vec![0; len[0] as usize];
+                reader.read_exact(&mut key_id)?;
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                let mut wrapped_key = vec![0; u16::from_be_bytes(len) as usize];
+                reader.read_exact(&mut wrapped_key)?;
+                Ok(KeySlot::Pkcs11 { key_id, wrapped_key })
+            }
+            KEY_SLOT_SSH_AGENT => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                let mut public_key = vec![0; u16::from_be_bytes(len) as usize];
+                reader.read_exact(&mut public_key)?;
+                let mut challenge = [0; SSH_CHALLENGE_LEN];
+                reader.read_exact(&mut challenge)?;
+                let mut wrapped_key = [0; WRAPPED_KEY_LEN];
+                reader.read_exact(&mut wrapped_key)?;
+                Ok(KeySlot::SshAgent { public_key, challenge, wrapped_key })
+            }
+            KEY_SLOT_PLUGIN => {
+                let mut len = [0; 1];
+                reader.read_exact(&mut len)?;
+                let mut plugin = vec![0; len[0] as usize];
+                reader.read_exact(&mut plugin)?;
+                //the name selects the program to run, it must not be a path
+                let plugin = String::from_utf8(plugin).ok().filter(|name| is_valid_plugin_name(name)).ok_or(Error::InvalidHeader)?;
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                let mut data = vec![0; u16::from_be_bytes(len) as usize];
+                reader.read_exact(&mut data)?;
+                Ok(KeySlot::Plugin { plugin, data })
+            }
+            KEY_SLOT_TPM => {
+                let mut len = [0; 1];
+                reader.read_exact(&mut len)?;
+                let mut pcrs = vec![0; len[0] as usize];
+                reader.read_exact(&mut pcrs)?;
+                if pcrs.iter().any(|pcr| *pcr > MAX_TPM_PCR) {
+                    return Err(Error::InvalidHeader);
+                }
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                let mut public = vec![0; u16::from_be_bytes(len) as usize];
+                reader.read_exact(&mut public)?;
+                reader.read_exact(&mut len)?;
+                let mut private = vec![0; u16::from_be_bytes(len) as usize];
+                reader.read_exact(&mut private)?;
+                Ok(KeySlot::Tpm { pcrs, public, private })
+            }
+            _ => Err(Error::InvalidHeader),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyDerivation {
+    /// The master key is derived from the password with Argon2 (format versions 0 and 1).
+    Password(argon2::Params),
+    /// The master key is random and stored encrypted in each slot (format version 2).
+    KeySlots(Vec<KeySlot>),
+    /// The master key is provided by the user (format version 3).
+    RawKey,
+}
+
+//counts the bytes of the header read so far to report where it was truncated
+struct HeaderReader<R: Read> {
+    reader: R,
+    position: u64,
+}
+
+impl<R: Read> Read for HeaderReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let expected = self.position + buf.len() as u64;
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read(&mut buf[filled..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, Error::Truncated { expected })),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncryptionParams {
+    version: u8,
+    salt: [u8; SALT_LEN],
+    key_check: Option<[u8; KEY_CHECK_LEN]>,
+    pub key_derivation: KeyDerivation,
+    pub cipher: CipherAlgorithm,
+    /// Ignored by AEAD ciphers.
+    pub mac: MacAlgorithm,
+    /// Whether the plaintext starts with `Metadata`. Stored in the high bit of the cipher byte.
+    pub metadata: bool,
+    /// Unknown non-critical extensions are kept so that the header can be written back identically.
+    extensions: Vec<HeaderExtension>,
+}
+
+impl EncryptionParams {
+    /// Length of password-based parameters without key check value or extensions. Key slots parameters are variable-length.
+    pub const LEN: usize = 1 + SALT_LEN + 4*3 + 1;
+
+    pub fn new(argon2_params: argon2::Params, cipher: CipherAlgorithm) -> EncryptionParams {
+        Self::new_with_rng(argon2_params, cipher, &mut OsRng)
+    }
+
+    /// Same as `new`, but the salt is drawn from `rng`, e.g. a seeded RNG to write reproducible test vectors. Production code must use `OsRng`: reusing a salt with the same password reuses the encryption keys and nonce.
+    pub fn new_with_rng<R: RngCore>(argon2_params: argon2::Params, cipher: CipherAlgorithm, rng: &mut R) -> EncryptionParams {
+        EncryptionParams {
+            version: FORMAT_VERSION,
+            salt: Self::random_salt(rng),
+            key_check: None,
+            key_derivation: KeyDerivation::Password(argon2_params),
+            cipher,
+            mac: MacAlgorithm::Blake2b,
+            metadata: false,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// Derives the master key from the password and stores a key check value so that a wrong password is detected before decrypting.
+    ///
+    /// The returned master key must be passed to `DobyCipher::with_master_key`.
+    pub fn with_password(password: &[u8], argon2_params: argon2::Params, cipher: CipherAlgorithm) -> Result<(EncryptionParams, MasterKey), Error> {
+        Self::with_password_and_rng(password, argon2_params, cipher, &mut OsRng)
+    }
+
+    /// Same as `with_password`, with the salt drawn from `rng` (see `new_with_rng`).
+    pub fn with_password_and_rng<R: RngCore>(password: &[u8], argon2_params: argon2::Params, cipher: CipherAlgorithm, rng: &mut R) -> Result<(EncryptionParams, MasterKey), Error> {
+        let salt = Self::random_salt(rng);
+        let master_key = argon2_hash(password, &salt, &argon2_params)?;
+        let params = EncryptionParams {
+            version: KEY_CHECK_FORMAT_VERSION,
+            salt,
+            key_check: Some(key_check(&master_key, &salt)),
+            key_derivation: KeyDerivation::Password(argon2_params),
+            cipher,
+            mac: MacAlgorithm::Blake2b,
+            metadata: false,
+            extensions: Vec::new(),
+        };
+        Ok((params, master_key))
+    }
+
+    /// Same as `with_password`, but the master key also depends on the response of the YubiKey `slot` to a challenge, which `respond` sends to the token. The slot is stored in the header.
+    pub fn with_password_and_yubikey<F>(password: &[u8], argon2_params: argon2::Params, cipher: CipherAlgorithm, slot: u8, respond: F) -> Result<(EncryptionParams, MasterKey), Error>
+        where F: FnOnce(u8, &[u8; YUBIKEY_CHALLENGE_LEN]) -> Result<Vec<u8>, Error>
+    {
+        let (mut params, argon2_output) = Self::with_password(password, argon2_params, cipher)?;
+        let master_key = mix_yubikey_response(&argon2_output, &params.salt, slot, respond)?;
+        params.key_check = Some(key_check(&master_key, &params.salt));
+        params.set_extension(YUBIKEY_EXTENSION, vec![slot])?;
+        Ok((params, master_key))
+    }
+
+    /// `key_slots` must contain the master key later passed to `DobyCipher::with_master_key`. There must be between 1 and 255 of them.
+    pub fn with_key_slots(key_slots: Vec<KeySlot>, cipher: CipherAlgorithm) -> Result<EncryptionParams, Error> {
+        Self::with_key_slots_and_rng(key_slots, cipher, &mut OsRng)
+    }
+
+    /// Same as `with_key_slots`, with the salt drawn from `rng` (see `new_with_rng`).
+    pub fn with_key_slots_and_rng<R: RngCore>(key_slots: Vec<KeySlot>, cipher: CipherAlgorithm, rng: &mut R) -> Result<EncryptionParams, Error> {
+        if key_slots.is_empty() {
+            return Err(Error::Usage("at least one key slot is required"));
+        }
+        if key_slots.len() > u8::MAX as usize {
+            return Err(Error::Usage("too many key slots (maximum: 255)"));
+        }
+        Ok(EncryptionParams {
+            version: KEY_SLOTS_FORMAT_VERSION,
+            salt: Self::random_salt(rng),
+            key_check: None,
+            key_derivation: KeyDerivation::KeySlots(key_slots),
+            cipher,
+            mac: MacAlgorithm::Blake2b,
+            metadata: false,
+            extensions: Vec::new(),
+        })
+    }
+
+    /// The master key later passed to `DobyCipher::with_master_key` is used as is.
+    pub fn with_raw_key(cipher: CipherAlgorithm) -> EncryptionParams {
+        Self::with_raw_key_and_rng(cipher, &mut OsRng)
+    }
+
+    /// Same as `with_raw_key`, with the salt drawn from `rng` (see `new_with_rng`).
+    pub fn with_raw_key_and_rng<R: RngCore>(cipher: CipherAlgorithm, rng: &mut R) -> EncryptionParams {
+        EncryptionParams {
+            version: RAW_KEY_FORMAT_VERSION,
+            salt: Self::random_salt(rng),
+            key_check: None,
+            key_derivation: KeyDerivation::RawKey,
+            cipher,
+            mac: MacAlgorithm::Blake2b,
+            metadata: false,
+            extensions: Vec::new(),
+        }
+    }
+
+    fn random_salt<R: RngCore>(rng: &mut R) -> [u8; SALT_LEN] {
+        let mut salt = [0; SALT_LEN];
+        rng.fill_bytes(&mut salt);
+        salt
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Replaces the salt, so that the same master key can encrypt another plaintext with different encryption keys and nonce. Fails with password-derived parameters, whose master key depends on the salt.
+    pub fn renew_salt(&mut self) -> Result<(), Error> {
+        if let KeyDerivation::Password(_) = self.key_derivation {
+            return Err(Error::Usage("the salt of password-derived parameters can't be replaced"));
+        }
+        self.salt = Self::random_salt(&mut OsRng);
+        Ok(())
+    }
+
+    pub fn salt(&self) -> &[u8; SALT_LEN] {
+        &self.salt
+    }
+
+    pub fn key_slots(&self) -> &[KeySlot] {
+        match &self.key_derivation {
+            KeyDerivation::KeySlots(key_slots) => key_slots,
+            _ => &[],
+        }
+    }
+
+    /// Argon2 parameters of the password, or of each password key slot. They are read from the file, so they can be checked before running Argon2.
+    pub fn argon2_params(&self) -> Vec<&argon2::Params> {
+        match &self.key_derivation {
+            KeyDerivation::Password(argon2_params) => vec![argon2_params],
+            KeyDerivation::KeySlots(key_slots) => key_slots.iter().filter_map(|key_slot| match key_slot {
+                KeySlot::Password { argon2, .. } => Some(argon2),
+                _ => None,
+            }).collect(),
+            KeyDerivation::RawKey => Vec::new(),
+        }
+    }
+
+    pub fn extensions(&self) -> &[HeaderExtension] {
+        &self.extensions
+    }
+
+    /// Value of the first extension of type `kind`.
+    pub fn extension(&self, kind: u8) -> Option<&[u8]> {
+        self.extensions.iter().find(|extension| extension.kind == kind).map(|extension| extension.value.as_slice())
+    }
+
+    /// Adds an extension to the header, replacing any other of the same type. Fails if the extensions don't fit in 65535 bytes.
+    pub fn set_extension(&mut self, kind: u8, value: Vec<u8>) -> Result<(), Error> {
+        self.remove_extension(kind);
+        let len: usize = self.extensions.iter().map(|extension| 3 + extension.value.len()).sum();
+        if len + 3 + value.len() > u16::MAX as usize {
+            return Err(Error::Usage("header extensions can't exceed 65535 bytes"));
+        }
+        self.extensions.push(HeaderExtension { kind, value });
+        Ok(())
+    }
+
+    pub fn remove_extension(&mut self, kind: u8) {
+        self.extensions.retain(|extension| extension.kind != kind);
+    }
+
+    /// Unencrypted label readable without the password, but authenticated like the rest of the header.
+    pub fn comment(&self) -> Option<String> {
+        self.extension(COMMENT_EXTENSION).map(|comment| String::from_utf8_lossy(comment).into_owned())
+    }
+
+    pub fn set_comment(&mut self, comment: &str) -> Result<(), Error> {
+        if comment.len() > MAX_COMMENT_LEN {
+            return Err(Error::Usage("comments can't be longer than 255 bytes"));
+        }
+        self.set_extension(COMMENT_EXTENSION, comment.as_bytes().to_vec())
+    }
+
+    /// Whether the passwords were normalized with `nfc_password` before deriving keys. Files written before normalization was introduced use them as typed.
+    pub fn nfc_passwords(&self) -> bool {
+        self.extension(NFC_PASSWORDS_EXTENSION).is_some()
+    }
+
+    /// Records that the passwords given to `with_password` or `KeySlot::from_password` were normalized with `nfc_password`, so that decryption normalizes them too.
+    pub fn set_nfc_passwords(&mut self) -> Result<(), Error> {
+        self.set_extension(NFC_PASSWORDS_EXTENSION, Vec::new())
+    }
+
+    /// `password` as it must be fed to Argon2 (or to `KeySlot::unwrap_password`) according to `nfc_passwords`.
+    pub fn password_bytes(&self, password: &[u8]) -> Zeroizing<Vec<u8>> {
+        if self.nfc_passwords() {
+            nfc_password(password)
+        } else {
+            Zeroizing::new(password.to_vec())
+        }
+    }
+
+    /// Digest of the plaintext to check once decrypted, if it was computed when encrypting.
+    pub fn plaintext_digest(&self) -> Option<PlaintextDigest> {
+        self.extension(PLAINTEXT_DIGEST_EXTENSION).and_then(PlaintextDigest::from_bytes)
+    }
+
+    pub fn set_plaintext_digest(&mut self, digest: &PlaintextDigest) -> Result<(), Error> {
+        self.set_extension(PLAINTEXT_DIGEST_EXTENSION, digest.to_bytes())
+    }
+
+    /// Whether the plaintext must be decoded with `SparseWriter`.
+    pub fn sparse(&self) -> bool {
+        self.extension(SPARSE_EXTENSION).is_some()
+    }
+
+    pub fn set_sparse(&mut self) -> Result<(), Error> {
+        self.set_extension(SPARSE_EXTENSION, Vec::new())
+    }
+
+    /// Ed25519 public key of the signer, if the ciphertext is followed by a signature.
+    pub fn signer(&self) -> Option<[u8; SIGNER_KEY_LEN]> {
+        self.extension(SIGNATURE_EXTENSION).and_then(|key| key.try_into().ok())
+    }
+
+    pub fn set_signer(&mut self, public_key: &[u8; SIGNER_KEY_LEN]) -> Result<(), Error> {
+        self.set_extension(SIGNATURE_EXTENSION, public_key.to_vec())
+    }
+
+    /// Slot of the YubiKey whose response is mixed with the password, if any.
+    pub fn yubikey_slot(&self) -> Option<u8> {
+        match self.extension(YUBIKEY_EXTENSION) {
+            Some([slot]) => Some(*slot),
+            _ => None,
+        }
+    }
+
+    /// IDs of the keys the file was encrypted for: one per X25519 key slot, in the same order, or the ID of the raw key.
+    pub fn key_ids(&self) -> Vec<[u8; KEY_ID_LEN]> {
+        self.extension(KEY_IDS_EXTENSION)
+            .map(|ids| ids.chunks_exact(KEY_ID_LEN).map(|id| id.try_into().unwrap()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn set_key_ids(&mut self, key_ids: &[[u8; KEY_ID_LEN]]) -> Result<(), Error> {
+        self.set_extension(KEY_IDS_EXTENSION, key_ids.concat())
+    }
+
+    /// Master key encrypted by a cloud KMS, opaque to doby.
+    pub fn kms_blob(&self) -> Option<&[u8]> {
+        self.extension(KMS_EXTENSION)
+    }
+
+    pub fn set_kms_blob(&mut self, blob: Vec<u8>) -> Result<(), Error> {
+        self.set_extension(KMS_EXTENSION, blob)
+    }
+
+    fn algorithms_byte(&self) -> u8 {
+        self.cipher as u8 | (self.mac as u8) << 4
+            | if self.metadata { METADATA_FLAG } else { 0 }
+            | if self.extensions.is_empty() { 0 } else { EXTENSIONS_FLAG }
+    }
+
+    //legacy headers (written before versioning) don't contain the version byte
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.version != LEGACY_FORMAT_VERSION {
+            writer.write_all(&[self.version])?;
+        }
+        writer.write_all(&self.salt)?;
+        match &self.key_derivation {
+            KeyDerivation::Password(argon2) => {
+                write_argon2_params(writer, argon2)?;
+                writer.write_all(&[self.algorithms_byte()])?;
+                if let Some(key_check) = &self.key_check {
+                    writer.write_all(key_check)?;
+                }
+            }
+            KeyDerivation::KeySlots(key_slots) => {
+                writer.write_all(&[self.algorithms_byte(), key_slots.len() as u8])?;
+                for key_slot in key_slots {
+                    key_slot.write(writer)?;
+                }
+            }
+            KeyDerivation::RawKey => writer.write_all(&[self.algorithms_byte()])?,
+        }
+        if !self.extensions.is_empty() {
+            write_extensions(writer, &self.extensions)?;
+        }
+        Ok(())
+    }
+
+    /// `reader` must be positioned right after the magic bytes. Fails with `Error::Truncated` if it ends before the end of the header.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let reader = &mut HeaderReader { reader, position: crate::MAGIC_BYTES.len() as u64 };
+        let mut version = [0; 1];
+        reader.read_exact(&mut version)?;
+        match version[0] {
+            FORMAT_VERSION | KEY_CHECK_FORMAT_VERSION => Self::read_fields(reader, version[0]),
+            KEY_SLOTS_FORMAT_VERSION => Self::read_key_slots(reader),
+            RAW_KEY_FORMAT_VERSION => Self::read_raw_key(reader),
+            _ => Err(Error::UnsupportedVersion(version[0])),
+        }
+    }
+
+    pub fn read_legacy<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        Self::read_fields(&mut HeaderReader { reader, position: crate::LEGACY_MAGIC_BYTES.len() as u64 }, LEGACY_FORMAT_VERSION)
+    }
+
+    fn read_key_slots<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let mut salt = [0; SALT_LEN];
+        reader.read_exact(&mut salt)?;
+        let mut buff = [0; 2];
+        reader.read_exact(&mut buff)?;
+        let (cipher, mac, metadata, extensions) = decode_algorithms(buff[0])?;
+        if buff[1] == 0 {
+            return Err(Error::InvalidHeader);
+        }
+        let key_slots = (0..buff[1]).map(|_| KeySlot::read(reader)).collect::<Result<Vec<KeySlot>, Error>>()?;
+        let extensions = if extensions { read_extensions(reader)? } else { Vec::new() };
+        Ok(EncryptionParams {
+            version: KEY_SLOTS_FORMAT_VERSION,
+            salt,
+            key_check: None,
+            key_derivation: KeyDerivation::KeySlots(key_slots),
+            cipher,
+            mac,
+            metadata,
+            extensions,
+        })
+    }
+
+    fn read_raw_key<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let mut salt = [0; SALT_LEN];
+        reader.read_exact(&mut salt)?;
+        let mut algorithms = [0; 1];
+        reader.read_exact(&mut algorithms)?;
+        let (cipher, mac, metadata, extensions) = decode_algorithms(algorithms[0])?;
+        let extensions = if extensions { read_extensions(reader)? } else { Vec::new() };
+        Ok(EncryptionParams {
+            version: RAW_KEY_FORMAT_VERSION,
+            salt,
+            key_check: None,
+            key_derivation: KeyDerivation::RawKey,
+            cipher,
+            mac,
+            metadata,
+            extensions,
+        })
+    }
+
+    fn read_fields<R: Read>(reader: &mut R, version: u8) -> Result<Self, Error> {
+        let mut salt = [0; SALT_LEN];
+        reader.read_exact(&mut salt)?;
+        let mut t_cost = [0; 4];
+        reader.read_exact(&mut t_cost)?;
+        let mut m_cost = [0; 4];
+        reader.read_exact(&mut m_cost)?;
+        let mut p_cost = [0; 4];
+        reader.read_exact(&mut p_cost)?;
+        let mut cipher_buff = [0; 1];
+        reader.read_exact(&mut cipher_buff)?;
+        if let Ok((cipher, mac, metadata, extensions)) = decode_algorithms(cipher_buff[0]) {
+            if let Ok(argon2_params) = argon2::Params::new(
+                u32::from_be_bytes(m_cost),
+                u32::from_be_bytes(t_cost),
+                u32::from_be_bytes(p_cost),
+                None
+            ) {
+                let key_check = if version == KEY_CHECK_FORMAT_VERSION {
+                    let mut key_check = [0; KEY_CHECK_LEN];
+                    reader.read_exact(&mut key_check)?;
+                    Some(key_check)
+                } else {
+                    None
+                };
+                let extensions = if extensions { read_extensions(reader)? } else { Vec::new() };
+                return Ok(EncryptionParams {
+                    version,
+                    salt,
+                    key_check,
+                    key_derivation: KeyDerivation::Password(argon2_params),
+                    cipher,
+                    mac,
+                    metadata,
+                    extensions,
+                });
+            }
+        }
+        Err(Error::InvalidHeader)
+    }
+}
+
+//below this size, generating the keystream with several threads is slower
+#[cfg(feature = "parallel")]
+const KEYSTREAM_RAYON_THRESHOLD: usize = 262144;
+//part of a large buffer encrypted by one thread, a multiple of the AES and ChaCha20 block sizes
+#[cfg(feature = "parallel")]
+const KEYSTREAM_RAYON_CHUNK: usize = 65536;
+
+#[allow(clippy::large_enum_variant)]
+enum KeyStreamCipher {
+    AesCtr(Aes256Ctr),
+    XChaCha20(XChaCha20),
+}
+
+//stream ciphers aren't Clone: keep the key to rebuild them at the same keystream position
+struct KeyStream {
+    key: Locked<[u8; KEY_LEN]>,
+    nonce: Vec<u8>,
+    cipher: KeyStreamCipher,
+}
+
+impl KeyStreamCipher {
+    fn new(algorithm: CipherAlgorithm, key: &[u8; KEY_LEN], nonce: &[u8]) -> Self {
+        match algorithm {
+            CipherAlgorithm::AesCtr => KeyStreamCipher::AesCtr(Aes256Ctr::new_from_slices(key, nonce).unwrap()),
+            _ => KeyStreamCipher::XChaCha20(XChaCha20::new_from_slices(key, nonce).unwrap()),
+        }
+    }
+
+    fn apply_keystream(&mut self, buff: &mut [u8]) {
+        match self {
+            KeyStreamCipher::AesCtr(cipher) => cipher.apply_keystream(buff),
+            KeyStreamCipher::XChaCha20(cipher) => cipher.apply_keystream(buff),
+        }
+    }
+
+    fn algorithm(&self) -> CipherAlgorithm {
+        match self {
+            KeyStreamCipher::AesCtr(_) => CipherAlgorithm::AesCtr,
+            KeyStreamCipher::XChaCha20(_) => CipherAlgorithm::XChaCha20,
+        }
+    }
+}
+
+impl KeyStream {
+    fn new(algorithm: CipherAlgorithm, key: Locked<[u8; KEY_LEN]>, nonce: Vec<u8>) -> Self {
+        let cipher = KeyStreamCipher::new(algorithm, &key, &nonce);
+        Self { key, nonce, cipher }
+    }
+
+    /// Large buffers are split in `KEYSTREAM_RAYON_CHUNK` parts encrypted by several threads, each one with its own cipher seeked to the position of its part.
+    #[cfg(feature = "parallel")]
+    fn apply_keystream(&mut self, buff: &mut [u8]) {
+        if buff.len() < KEYSTREAM_RAYON_THRESHOLD || rayon::current_num_threads() == 1 {
+            return self.cipher.apply_keystream(buff);
+        }
+        let pos = self.current_pos();
+        let (algorithm, key, nonce) = (self.cipher.algorithm(), &*self.key, &self.nonce);
+        buff.par_chunks_mut(KEYSTREAM_RAYON_CHUNK).enumerate().for_each(|(i, chunk)| {
+            let mut cipher = KeyStreamCipher::new(algorithm, key, nonce);
+            match &mut cipher {
+                KeyStreamCipher::AesCtr(cipher) => cipher.seek(pos + (i * KEYSTREAM_RAYON_CHUNK) as u64),
+                KeyStreamCipher::XChaCha20(cipher) => cipher.seek(pos + (i * KEYSTREAM_RAYON_CHUNK) as u64),
+            }
+            cipher.apply_keystream(chunk);
+        });
+        self.seek(pos + buff.len() as u64);
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn apply_keystream(&mut self, buff: &mut [u8]) {
+        self.cipher.apply_keystream(buff)
+    }
+
+    fn current_pos(&self) -> u64 {
+        match &self.cipher {
+            KeyStreamCipher::AesCtr(cipher) => cipher.current_pos(),
+            KeyStreamCipher::XChaCha20(cipher) => cipher.current_pos(),
+        }
+    }
+
+    fn seek(&mut self, pos: u64) {
+        match &mut self.cipher {
+            KeyStreamCipher::AesCtr(cipher) => cipher.seek(pos),
+            KeyStreamCipher::XChaCha20(cipher) => cipher.seek(pos),
+        }
+    }
+}
+
+impl Clone for KeyStream {
+    fn clone(&self) -> Self {
+        let mut new = Self::new(self.cipher.algorithm(), self.key.clone(), self.nonce.clone());
+        new.seek(self.current_pos());
+        new
+    }
+}
+
+#[derive(Clone)]
+enum AeadCipher {
+    XChaCha20Poly1305(XChaCha20Poly1305),
+    AesGcm(Box<Aes256Gcm>),
+}
+
+impl AeadCipher {
+    fn seal(&self, nonce: &[u8], associated_data: &[u8], buff: &mut [u8]) -> Option<Tag> {
+        match self {
+            AeadCipher::XChaCha20Poly1305(aead) => aead.encrypt_in_place_detached(GenericArray::from_slice(nonce), associated_data, buff),
+            AeadCipher::AesGcm(aead) => aead.encrypt_in_place_detached(GenericArray::from_slice(nonce), associated_data, buff),
+        }.ok()
+    }
+
+    fn open(&self, nonce: &[u8], associated_data: &[u8], buff: &mut [u8], tag: &Tag) -> bool {
+        match self {
+            AeadCipher::XChaCha20Poly1305(aead) => aead.decrypt_in_place_detached(GenericArray::from_slice(nonce), associated_data, buff, tag),
+            AeadCipher::AesGcm(aead) => aead.decrypt_in_place_detached(GenericArray::from_slice(nonce), associated_data, buff, tag),
+        }.is_ok()
+    }
+}
+
+#[derive(Clone)]
+struct AeadState {
+    aead: AeadCipher,
+    nonce_prefix: Vec<u8>,
+    counter: u32,
+    associated_data: Vec<u8>,
+    //tag of the last chunk opened, authenticated or not
+    last_tag: Option<Tag>,
+}
+
+impl AeadState {
+    fn chunk_nonce(&self, last: bool) -> Vec<u8> {
+        let mut nonce = Vec::with_capacity(self.nonce_prefix.len() + AEAD_NONCE_SUFFIX_LEN);
+        nonce.extend_from_slice(&self.nonce_prefix);
+        nonce.extend_from_slice(&self.counter.to_be_bytes());
+        nonce.push(last as u8);
+        nonce
+    }
+
+    fn seal(&mut self, chunk: &mut [u8], last: bool) -> io::Result<Tag> {
+        let nonce = self.chunk_nonce(last);
+        self.counter = self.counter.checked_add(1).ok_or_else(|| io::Error::other("too many chunks"))?;
+        self.aead.seal(&nonce, &self.associated_data, chunk).ok_or_else(|| io::Error::other("chunk encryption failed"))
+    }
+
+    //encrypts and writes the first `len` bytes of `buffer` with their tag, then removes them
+    fn seal_chunk<W: Write>(&mut self, buffer: &mut Vec<u8>, len: usize, last: bool, writer: &mut W) -> io::Result<()> {
+        let tag = self.seal(&mut buffer[..len], last)?;
+        write_all_vectored(writer, &mut [IoSlice::new(&buffer[..len]), IoSlice::new(&tag)])?;
+        buffer.drain(..len);
+        Ok(())
+    }
+
+    //seals the first `count` full chunks of `buffer` in place and writes them with their tags at once, instead of draining the buffer after each one
+    fn seal_chunks<W: Write>(&mut self, buffer: &mut Vec<u8>, count: usize, writer: &mut W) -> io::Result<()> {
+        let len = count * AEAD_CHUNK_SIZE;
+        let tags = buffer[..len].chunks_mut(AEAD_CHUNK_SIZE).map(|chunk| self.seal(chunk, false)).collect::<io::Result<Vec<_>>>()?;
+        let mut slices: Vec<IoSlice> = buffer[..len].chunks(AEAD_CHUNK_SIZE).zip(&tags)
+            .flat_map(|(chunk, tag)| [IoSlice::new(chunk), IoSlice::new(tag)])
+            .collect();
+        write_all_vectored(writer, &mut slices)?;
+        buffer.drain(..len);
+        Ok(())
+    }
+
+    //decrypts and removes the first `chunk_len` bytes of `buffer`, appending the plaintext to `plaintext` only if authentication succeeded
+    fn open_chunk(&mut self, buffer: &mut Vec<u8>, chunk_len: usize, last: bool, plaintext: &mut Vec<u8>) -> bool {
+        if chunk_len < AEAD_TAG_LEN {
+            return false;
+        }
+        let nonce = self.chunk_nonce(last);
+        let ciphertext_len = chunk_len - AEAD_TAG_LEN;
+        let tag = Tag::clone_from_slice(&buffer[ciphertext_len..chunk_len]);
+        let authentic = self.aead.open(&nonce, &self.associated_data, &mut buffer[..ciphertext_len], &tag);
+        self.last_tag = Some(tag);
+        if !authentic {
+            return false;
+        }
+        plaintext.extend(buffer.drain(..chunk_len).take(ciphertext_len));
+        self.counter = self.counter.wrapping_add(1);
+        true
+    }
+}
+
+//below this size, hashing with several threads is slower
+#[cfg(all(feature = "blake3", feature = "parallel"))]
+const BLAKE3_RAYON_THRESHOLD: usize = 131072;
+
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+enum Hmac {
+    //blake2b_simd picks AVX2 at runtime, about twice as fast as the scalar blake2 crate for the same output
+    Blake2b(blake2b_simd::State),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hmac {
+    fn new(algorithm: MacAlgorithm, key: &[u8; KEY_LEN]) -> Self {
+        match algorithm {
+            MacAlgorithm::Blake2b => Hmac::Blake2b(blake2b_simd::Params::new().hash_length(HMAC_LEN).key(key).to_state()),
+            #[cfg(feature = "blake3")]
+            MacAlgorithm::Blake3 => Hmac::Blake3(Box::new(blake3::Hasher::new_keyed(key))),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hmac::Blake2b(hasher) => { hasher.update(data); }
+            #[cfg(all(feature = "blake3", feature = "parallel"))]
+            Hmac::Blake3(hasher) if data.len() >= BLAKE3_RAYON_THRESHOLD => { hasher.update_rayon(data); }
+            #[cfg(feature = "blake3")]
+            Hmac::Blake3(hasher) => { hasher.update(data); }
+        }
+    }
+
+    fn finalize(self) -> Box<[u8]> {
+        match self {
+            Hmac::Blake2b(hasher) => hasher.finalize().as_bytes().into(),
+            #[cfg(feature = "blake3")]
+            Hmac::Blake3(hasher) => Box::new(*hasher.finalize().as_bytes()),
         }
     }
 }
 
-impl Display for CipherAlgorithm {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.write_str(match self {
-            CipherAlgorithm::AesCtr => "AES-CTR",
-            CipherAlgorithm::XChaCha20 => "XChaCha20",
-        })
+pub(crate) const AEAD_ENCRYPTED_CHUNK_LEN: usize = AEAD_CHUNK_SIZE + AEAD_TAG_LEN;
+
+//Write::write_all_vectored isn't stable yet
+fn write_all_vectored<W: Write>(writer: &mut W, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
     }
+    Ok(())
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct EncryptionParams {
-    salt: [u8; SALT_LEN],
-    pub argon2: argon2::Params,
-    pub cipher: CipherAlgorithm,
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+enum CipherMode {
+    Stream {
+        cipher: KeyStream,
+        hasher: Hmac,
+    },
+    Aead {
+        state: AeadState,
+        plaintext: Vec<u8>,
+        plaintext_offset: usize,
+        finished: bool,
+        failed: bool,
+    },
 }
 
-impl EncryptionParams {
-    pub const LEN: usize = SALT_LEN + 4*3 + 1;
+/// State of a decryption, for callers that need more than success or failure, e.g. to log the fingerprints of the files they check. See `DobyCipher::report`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecryptionReport {
+    /// Plaintext bytes output so far.
+    pub bytes: u64,
+    /// Whether the whole ciphertext has been authenticated.
+    pub verified: bool,
+    /// HMAC computed over the header and the ciphertext read so far with stream ciphers. AEAD ciphers don't expose the tags they compute: this is the tag of the last chunk once it's authenticated.
+    pub mac: Option<Box<[u8]>>,
+    /// HMAC, or tag of the last chunk read, found in the file. `None` if the file is too short to hold one.
+    pub expected_mac: Option<Box<[u8]>>,
+}
 
-    pub fn new(argon2_params: argon2::Params, cipher: CipherAlgorithm) -> EncryptionParams {
-        let mut salt = [0; SALT_LEN];
-        OsRng.fill(&mut salt);
-        EncryptionParams {
-            salt,
-            argon2: argon2_params,
-            cipher,
-        }
+#[derive(Clone)]
+pub struct DobyCipher {
+    mode: CipherMode,
+    buffer: Vec<u8>,
+    //magic bytes and encoded parameters, to report the expected length of truncated files
+    header_len: u64,
+    //plaintext output by decrypt_chunk or decrypt_update
+    decrypted: u64,
+}
+
+const RAW_KEY_PASSWORD: Error = Error::Usage("this file is encrypted with a raw key, not a password");
+
+impl DobyCipher {
+    /// The password is normalized first if the parameters say so (see `EncryptionParams::nfc_passwords`), as by all the other constructors taking a password.
+    ///
+    /// The master key and the derived keys are locked in memory while in use (see `memlock`).
+    ///
+    /// Fails with `Error::InvalidParams` if the Argon2 parameters are out of bounds (see `MAX_ARGON2_MEMORY_COST`) or if their memory can't be allocated, which a hostile header could otherwise use to abort the process. Files whose key isn't derived from the password fail with `Error::Usage`: the password must be tried on their key slots instead (see `try_with_password`), or isn't used.
+    pub fn new(password: &[u8], params: &EncryptionParams) -> Result<Self, Error> {
+        let master_key = match &params.key_derivation {
+            KeyDerivation::Password(argon2_params) => argon2_hash(&params.password_bytes(password), &params.salt, argon2_params)?,
+            KeyDerivation::KeySlots(_) => return Err(Error::Usage("the password of this file must be tried on its key slots")),
+            KeyDerivation::RawKey => return Err(RAW_KEY_PASSWORD),
+        };
+        Self::with_master_key(&master_key, params)
     }
 
-    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
-        writer.write_all(&self.salt)?;
-        writer.write_all(&self.argon2.t_cost().to_be_bytes())?;
-        writer.write_all(&self.argon2.m_cost().to_be_bytes())?;
-        writer.write_all(&self.argon2.p_cost().to_be_bytes())?;
-        writer.write_all(&(self.cipher as u8).to_be_bytes())?;
-        Ok(())
+    /// Same as `new` but fails immediately with `Error::WrongPassword` if the parameters contain a key check value that doesn't match.
+    ///
+    /// Fails with `Error::YubiKeyRequired` if the parameters were written by `EncryptionParams::with_password_and_yubikey`.
+    pub fn try_new(password: &[u8], params: &EncryptionParams) -> Result<Self, Error> {
+        if params.extension(YUBIKEY_EXTENSION).is_some() {
+            return Err(Error::YubiKeyRequired);
+        }
+        match (&params.key_derivation, &params.key_check) {
+            (KeyDerivation::Password(argon2_params), Some(_)) => {
+                let master_key = argon2_hash(&params.password_bytes(password), &params.salt, argon2_params)?;
+                Self::checked(&master_key, params)?.ok_or(Error::WrongPassword)
+            }
+            _ => Self::new(password, params),
+        }
     }
 
-    pub fn read<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
-        let mut salt = [0; SALT_LEN];
-        reader.read_exact(&mut salt)?;
-        let mut t_cost = [0; 4];
-        reader.read_exact(&mut t_cost)?;
-        let mut m_cost = [0; 4];
-        reader.read_exact(&mut m_cost)?;
-        let mut p_cost = [0; 4];
-        reader.read_exact(&mut p_cost)?;
-        let mut cipher_buff = [0; 1];
-        reader.read_exact(&mut cipher_buff)?;
-        if let Ok(cipher) = CipherAlgorithm::try_from(cipher_buff[0]) {
-            if let Ok(argon2_params) = argon2::Params::new(
-                u32::from_be_bytes(m_cost),
-                u32::from_be_bytes(t_cost),
-                u32::from_be_bytes(p_cost),
-                None
-            ) {
-                return Ok(Some(EncryptionParams {
-                    salt,
-                    argon2: argon2_params,
-                    cipher,
-                }));
+    /// Same as `try_new` but also opens the password slots of files encrypted with several passwords, failing with `Error::NoMatchingKeySlot` if none matches.
+    pub fn try_with_password(password: &[u8], params: &EncryptionParams) -> Result<Self, Error> {
+        let password_bytes = params.password_bytes(password);
+        match &params.key_derivation {
+            KeyDerivation::Password(_) => Self::try_new(password, params),
+            KeyDerivation::KeySlots(key_slots) => {
+                for key_slot in key_slots {
+                    if let Some(master_key) = key_slot.unwrap_password(&password_bytes)? {
+                        return Self::with_master_key(&Locked::new(master_key), params);
+                    }
+                }
+                Err(Error::NoMatchingKeySlot)
             }
+            KeyDerivation::RawKey => Err(RAW_KEY_PASSWORD),
         }
-        Ok(None)
     }
-}
 
-pub struct DobyCipher {
-    cipher: Box<dyn StreamCipher>,
-    hasher: VarBlake2b,
-    buffer: Vec<u8>,
-}
+    /// Same as `try_new`, for parameters written by `EncryptionParams::with_password_and_yubikey`. `respond` sends the challenge to the YubiKey slot stored in the header.
+    pub fn try_new_with_yubikey<F>(password: &[u8], params: &EncryptionParams, respond: F) -> Result<Self, Error>
+        where F: FnOnce(u8, &[u8; YUBIKEY_CHALLENGE_LEN]) -> Result<Vec<u8>, Error>
+    {
+        let (argon2_params, slot) = match (&params.key_derivation, params.yubikey_slot()) {
+            (KeyDerivation::Password(argon2_params), Some(slot)) => (argon2_params, slot),
+            _ => return Err(Error::InvalidHeader),
+        };
+        let argon2_output = argon2_hash(&params.password_bytes(password), &params.salt, argon2_params)?;
+        let master_key = mix_yubikey_response(&argon2_output, &params.salt, slot, respond)?;
+        Self::checked(&master_key, params)?.ok_or(Error::WrongPasswordOrYubiKey)
+    }
 
-impl DobyCipher {
-    pub fn new(password: &[u8], params: &EncryptionParams) -> Self {
-        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.argon2.clone());
-        let mut master_key = [0; KEY_LEN];
-        argon2.hash_password_into(password, &params.salt, &mut master_key).unwrap();
-        let hkdf = Hkdf::<Blake2b>::new(Some(&params.salt), &master_key);
-        master_key.zeroize();
+    //None if the key check value of the parameters doesn't match
+    fn checked(master_key: &[u8; KEY_LEN], params: &EncryptionParams) -> Result<Option<Self>, Error> {
+        let matches = params.key_check.is_none_or(|expected| bool::from(key_check(master_key, &params.salt).ct_eq(&expected)));
+        matches.then(|| Self::with_master_key(master_key, params)).transpose()
+    }
+
+    /// Creates a cipher from the master key, e.g. unwrapped from a key slot.
+    pub fn with_master_key(master_key: &[u8; KEY_LEN], params: &EncryptionParams) -> Result<Self, Error> {
+        let kdf = Kdf::new(params.mac, &params.salt, master_key);
         let mut nonce = vec![0; params.cipher.get_nonce_size()];
-        hkdf.expand(b"doby_nonce", &mut nonce).unwrap();
-        let mut encryption_key = [0; KEY_LEN];
-        hkdf.expand(b"doby_encryption_key", &mut encryption_key).unwrap();
-        let mut authentication_key = [0; KEY_LEN];
-        hkdf.expand(b"doby_authentication_key", &mut authentication_key).unwrap();
+        kdf.expand("doby_nonce", &mut nonce)?;
+        let mut encryption_key = Locked::new([0; KEY_LEN]);
+        kdf.expand("doby_encryption_key", &mut *encryption_key)?;
 
         let mut encoded_params = Vec::with_capacity(EncryptionParams::LEN);
-        params.write(&mut encoded_params).unwrap();
-        let mut hasher = VarBlake2b::new_keyed(&authentication_key, HMAC_LEN);
-        authentication_key.zeroize();
-        hasher.update(&encoded_params);
-
-        let cipher: Box<dyn StreamCipher> = match params.cipher {
-            CipherAlgorithm::AesCtr => Box::new(Aes256Ctr::new_from_slices(&encryption_key, &nonce).unwrap()),
-            CipherAlgorithm::XChaCha20 => Box::new(XChaCha20::new_from_slices(&encryption_key, &nonce).unwrap()),
+        params.write(&mut encoded_params)?;
+
+        let header_len = (crate::MAGIC_BYTES.len() + encoded_params.len()) as u64;
+        let mode = if params.cipher.is_aead() {
+            let key = GenericArray::from_slice(&*encryption_key);
+            let aead = match params.cipher {
+                CipherAlgorithm::AesGcm => AeadCipher::AesGcm(Box::new(Aes256Gcm::new(key))),
+                _ => AeadCipher::XChaCha20Poly1305(XChaCha20Poly1305::new(key)),
+            };
+            CipherMode::Aead {
+                state: AeadState {
+                    aead,
+                    nonce_prefix: nonce,
+                    counter: 0,
+                    associated_data: encoded_params,
+                    last_tag: None,
+                },
+                plaintext: Vec::new(),
+                plaintext_offset: 0,
+                finished: false,
+                failed: false,
+            }
+        } else {
+            let mut authentication_key = Locked::new([0; KEY_LEN]);
+            kdf.expand("doby_authentication_key", &mut *authentication_key)?;
+            let mut hasher = Hmac::new(params.mac, &authentication_key);
+            hasher.update(&encoded_params);
+
+            let cipher = KeyStream::new(params.cipher, encryption_key, nonce);
+            CipherMode::Stream { cipher, hasher }
         };
-        encryption_key.zeroize();
 
-        Self {
-            cipher,
-            hasher,
+        Ok(Self {
+            mode,
             buffer: Vec::new(),
-        }
+            header_len,
+            decrypted: 0,
+        })
     }
 
     pub fn encrypt_chunk<W: Write>(&mut self, buff: &mut [u8], writer: &mut W) -> io::Result<()> {
-        self.cipher.apply_keystream(buff);
-        self.hasher.update(&buff);
-        writer.write_all(buff)
+        match &mut self.mode {
+            CipherMode::Stream { cipher, hasher } => {
+                cipher.apply_keystream(buff);
+                hasher.update(buff);
+                writer.write_all(buff)
+            }
+            CipherMode::Aead { .. } => self.encrypt_slice(buff, writer),
+        }
+    }
+
+    /// Same as `encrypt_chunk`, for plaintexts that can't be encrypted in place, e.g. read-only memory maps. Stream ciphers copy them to an internal buffer first.
+    pub fn encrypt_slice<W: Write>(&mut self, plaintext: &[u8], writer: &mut W) -> io::Result<()> {
+        match &mut self.mode {
+            CipherMode::Stream { cipher, hasher } => {
+                self.buffer.clear();
+                self.buffer.extend_from_slice(plaintext);
+                cipher.apply_keystream(&mut self.buffer);
+                hasher.update(&self.buffer);
+                writer.write_all(&self.buffer)
+            }
+            CipherMode::Aead { state, .. } => {
+                self.buffer.extend_from_slice(plaintext);
+                //always keep the last chunk in the buffer: it must be sealed with the last flag set
+                let count = self.buffer.len().saturating_sub(1) / AEAD_CHUNK_SIZE;
+                if count > 0 {
+                    state.seal_chunks(&mut self.buffer, count, writer)?;
+                }
+                Ok(())
+            }
+        }
     }
 
-    pub fn write_hmac<W: Write>(self, writer: &mut W) -> io::Result<()> {
-        writer.write_all(&self.hasher.finalize_boxed())
+    pub fn write_hmac<W: Write>(mut self, writer: &mut W) -> io::Result<()> {
+        match self.mode {
+            CipherMode::Stream { hasher, .. } => writer.write_all(&hasher.finalize()),
+            CipherMode::Aead { mut state, .. } => {
+                let len = self.buffer.len();
+                state.seal_chunk(&mut self.buffer, len, true, writer)
+            }
+        }
     }
 
-    //buff size must be > to HASH_LEN
+    /// Returns 0 once the whole ciphertext has been decrypted. `buff` can be of any size, but stream ciphers decrypt buffers smaller than `HMAC_LEN` less efficiently.
     pub fn decrypt_chunk<R: Read>(&mut self, reader: &mut R, buff: &mut [u8]) -> io::Result<usize> {
-        let buffer_len = self.buffer.len();
-        buff[..buffer_len].clone_from_slice(&self.buffer);
-        let read = reader.read(&mut buff[buffer_len..])?;
+        match &mut self.mode {
+            CipherMode::Stream { cipher, hasher } => {
+                let n = if buff.len() > HMAC_LEN && self.buffer.len() < buff.len() {
+                    let buffer_len = self.buffer.len();
+                    buff[..buffer_len].clone_from_slice(&self.buffer);
+                    //short reads must not be mistaken for EOF: keep reading until something can be returned
+                    let mut filled = buffer_len;
+                    loop {
+                        let read = reader.read(&mut buff[filled..])?;
+                        filled += read;
+                        if read == 0 || filled > HMAC_LEN {
+                            break;
+                        }
+                    }
+                    let n = filled.saturating_sub(HMAC_LEN);
+                    self.buffer.clear();
+                    self.buffer.extend_from_slice(&buff[n..filled]);
+                    n
+                } else {
+                    //the last HMAC_LEN bytes read may be the HMAC, so small buffers are filled from self.buffer
+                    let needed = (buff.len() + HMAC_LEN).saturating_sub(self.buffer.len());
+                    reader.take(needed as u64).read_to_end(&mut self.buffer)?;
+                    let n = buff.len().min(self.buffer.len().saturating_sub(HMAC_LEN));
+                    buff[..n].copy_from_slice(&self.buffer[..n]);
+                    self.buffer.drain(..n);
+                    n
+                };
 
-        let n = if buffer_len + read >= HMAC_LEN {
-            self.buffer.clear();
-            buffer_len + read - HMAC_LEN
-        } else {
-            0
+                hasher.update(&buff[..n]);
+                cipher.apply_keystream(&mut buff[..n]);
+                self.decrypted += n as u64;
+                Ok(n)
+            }
+            CipherMode::Aead { state, plaintext, plaintext_offset, finished, failed } => {
+                if *plaintext_offset == plaintext.len() {
+                    if *finished || *failed {
+                        return Ok(0);
+                    }
+                    //read one byte more than a full chunk to know whether it's the last one
+                    let needed = AEAD_ENCRYPTED_CHUNK_LEN + 1 - self.buffer.len();
+                    reader.take(needed as u64).read_to_end(&mut self.buffer)?;
+                    let last = self.buffer.len() <= AEAD_ENCRYPTED_CHUNK_LEN;
+                    let chunk_len = if last { self.buffer.len() } else { AEAD_ENCRYPTED_CHUNK_LEN };
+                    plaintext.clear();
+                    *plaintext_offset = 0;
+                    if !state.open_chunk(&mut self.buffer, chunk_len, last, plaintext) {
+                        *failed = true;
+                        return Ok(0);
+                    }
+                    *finished = last;
+                }
+                let n = buff.len().min(plaintext.len() - *plaintext_offset);
+                buff[..n].copy_from_slice(&plaintext[*plaintext_offset..*plaintext_offset+n]);
+                *plaintext_offset += n;
+                self.decrypted += n as u64;
+                Ok(n)
+            }
+        }
+    }
+
+    /// Push-style alternative to `decrypt_chunk` for callers that can't provide a `Read` (e.g. async sources).
+    /// Decrypts as much of the ciphertext fed so far as possible, appending it to `plaintext`. Returns `false` as soon as an AEAD chunk fails authentication.
+    pub fn decrypt_update(&mut self, ciphertext: &[u8], plaintext: &mut Vec<u8>) -> bool {
+        self.buffer.extend_from_slice(ciphertext);
+        let len = plaintext.len();
+        let authentic = match &mut self.mode {
+            CipherMode::Stream { cipher, hasher } => {
+                //the last HMAC_LEN bytes may be the HMAC
+                if self.buffer.len() > HMAC_LEN {
+                    let n = self.buffer.len() - HMAC_LEN;
+                    hasher.update(&self.buffer[..n]);
+                    cipher.apply_keystream(&mut self.buffer[..n]);
+                    plaintext.extend(self.buffer.drain(..n));
+                }
+                true
+            }
+            CipherMode::Aead { state, failed, .. } => {
+                while !*failed && self.buffer.len() > AEAD_ENCRYPTED_CHUNK_LEN {
+                    *failed = !state.open_chunk(&mut self.buffer, AEAD_ENCRYPTED_CHUNK_LEN, false, plaintext);
+                }
+                !*failed
+            }
         };
-        self.buffer.extend_from_slice(&buff[n..buffer_len+read]);
-        
-        self.hasher.update(&buff[..n]);
-        self.cipher.apply_keystream(&mut buff[..n]);
-        Ok(n)
+        self.decrypted += (plaintext.len() - len) as u64;
+        authentic
+    }
+
+    /// Ends a `decrypt_update` sequence once the whole ciphertext has been fed. Returns whether the ciphertext is authentic.
+    pub fn decrypt_finalize(mut self, plaintext: &mut Vec<u8>) -> bool {
+        if let CipherMode::Aead { state, finished, failed, .. } = &mut self.mode {
+            if !*failed {
+                let len = self.buffer.len();
+                let plaintext_len = plaintext.len();
+                *failed = !state.open_chunk(&mut self.buffer, len, true, plaintext);
+                *finished = true;
+                self.decrypted += (plaintext.len() - plaintext_len) as u64;
+            }
+        }
+        self.verify_hmac()
     }
 
     pub fn verify_hmac(self) -> bool {
-        self.hasher.finalize_boxed().ct_eq(&self.buffer).into()
+        self.report().verified
+    }
+
+    /// Byte count and MACs of the decryption so far. Once the whole ciphertext has been read, `verified` tells whether it is authentic, as `verify_hmac`.
+    pub fn report(&self) -> DecryptionReport {
+        let (verified, mac, expected_mac) = match &self.mode {
+            CipherMode::Stream { hasher, .. } => {
+                let mac = hasher.clone().finalize();
+                let verified = mac.ct_eq(&self.buffer).into();
+                (verified, Some(mac), (self.buffer.len() == HMAC_LEN).then(|| self.buffer.clone().into_boxed_slice()))
+            }
+            CipherMode::Aead { state, finished, failed, .. } => {
+                let verified = *finished && !*failed;
+                let tag = state.last_tag.map(|tag| tag.to_vec().into_boxed_slice());
+                (verified, tag.clone().filter(|_| verified), tag)
+            }
+        };
+        DecryptionReport { bytes: self.decrypted, verified, mac, expected_mac }
+    }
+
+    /// Once the whole ciphertext has been read (or fed to `decrypt_update`), returns the minimum length the file should have had if it ended before the HMAC or the tag of its last chunk could even be read.
+    pub fn truncated(&self) -> Option<u64> {
+        match &self.mode {
+            CipherMode::Stream { .. } => (self.buffer.len() < HMAC_LEN).then(|| self.header_len + HMAC_LEN as u64),
+            //failed chunks stay in the buffer, so only a last chunk too short to hold a tag can be left here
+            CipherMode::Aead { state, finished, .. } => (!finished && self.buffer.len() < AEAD_TAG_LEN)
+                .then(|| self.header_len + state.counter as u64 * AEAD_ENCRYPTED_CHUNK_LEN as u64 + AEAD_TAG_LEN as u64),
+        }
+    }
+
+    /// Same as `verify_hmac` but distinguishes truncated files from altered ones.
+    pub fn verify(self) -> Result<(), Error> {
+        if let Some(expected) = self.truncated() {
+            Err(Error::Truncated { expected })
+        } else if self.verify_hmac() {
+            Ok(())
+        } else {
+            Err(Error::HmacMismatch)
+        }
+    }
+
+    //stream ciphers only: the HMAC must be verified separately
+    pub(crate) fn decrypt_unauthenticated_at(&mut self, offset: u64, buff: &mut [u8]) -> io::Result<()> {
+        match &mut self.mode {
+            CipherMode::Stream { cipher, .. } => {
+                cipher.seek(offset);
+                cipher.apply_keystream(buff);
+                Ok(())
+            }
+            CipherMode::Aead { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "AEAD chunks must be authenticated")),
+        }
+    }
+
+    /// Whether the ciphertext is made of independently authenticated chunks (AEAD ciphers), allowing random access with `seek_chunk`.
+    pub fn is_chunked(&self) -> bool {
+        matches!(self.mode, CipherMode::Aead { .. })
+    }
+
+    /// Restarts decryption at the AEAD chunk number `index`, i.e. at `index*(AEAD_CHUNK_SIZE+AEAD_TAG_LEN)` bytes after the header, where the reader given to the next `decrypt_chunk` calls must be positioned. `verify_hmac` then only covers the chunks decrypted since.
+    ///
+    /// When encrypting, continues an interrupted encryption with the chunk number `index`, i.e. with the plaintext at `index*AEAD_CHUNK_SIZE`: the chunks written before are kept as they are.
+    ///
+    /// Fails with `io::ErrorKind::Unsupported` for stream ciphers, whose HMAC can only be verified over the whole ciphertext.
+    pub fn seek_chunk(&mut self, index: u32) -> io::Result<()> {
+        match &mut self.mode {
+            CipherMode::Stream { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "random access requires a chunked cipher (aes-gcm or xchacha20-poly1305)")),
+            CipherMode::Aead { state, plaintext, plaintext_offset, finished, failed } => {
+                state.counter = index;
+                plaintext.clear();
+                *plaintext_offset = 0;
+                *finished = false;
+                *failed = false;
+                self.buffer.clear();
+                Ok(())
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{CipherAlgorithm, EncryptionParams, DobyCipher, HMAC_LEN};
+    use std::io::{self, IoSlice, Write};
+    use crate::Error;
+    #[cfg(feature = "parallel")]
+    use crate::memlock::Locked;
+    #[cfg(feature = "parallel")]
+    use super::{KeyStream, KeyStreamCipher, KEYSTREAM_RAYON_THRESHOLD};
+    #[cfg(all(feature = "blake3", feature = "parallel"))]
+    use super::{BLAKE3_RAYON_THRESHOLD, MacAlgorithm};
+    use super::{CipherAlgorithm, EncryptionParams, DobyCipher, KeyDerivation, KeySlot, KEY_LEN, FORMAT_VERSION, KEY_SLOTS_FORMAT_VERSION, RAW_KEY_FORMAT_VERSION, KEY_CHECK_FORMAT_VERSION, LATEST_FORMAT_VERSION, KEY_CHECK_LEN, SALT_LEN, HMAC_LEN, AEAD_CHUNK_SIZE, AEAD_TAG_LEN, YUBIKEY_CHALLENGE_LEN, MAX_ARGON2_MEMORY_COST, WRAPPED_KEY_LEN, KEY_ID_LEN, KEY_IDS_EXTENSION, format_key_id, key_id, nfc_password};
     #[test]
     fn encryption_params() {
         let params = EncryptionParams::new(
@@ -181,18 +1695,167 @@ mod tests {
             CipherAlgorithm::XChaCha20
         );
 
-        assert_eq!(EncryptionParams::LEN, 77);
+        assert_eq!(EncryptionParams::LEN, 78);
+
+        let mut buff = Vec::with_capacity(EncryptionParams::LEN);
+        params.write(&mut buff).unwrap();
+        assert_eq!(buff.len(), EncryptionParams::LEN);
+        assert_eq!(buff[0], FORMAT_VERSION);
+        assert_eq!(buff[1..65], params.salt);
+        assert_eq!(buff[65..69], vec![0, 0, 0, 0x01]); //t_cost
+        assert_eq!(buff[69..73], vec![0, 0, 0, 0x08]); //m_cost
+        assert_eq!(buff[73..77], vec![0, 0, 0, 0x01]); //p_cost
+        assert_eq!(buff[77], CipherAlgorithm::XChaCha20 as u8);
+
+        let new_params = EncryptionParams::read(&mut buff.as_slice()).unwrap();
+        assert_eq!(new_params, params);
+
+        buff[0] = LATEST_FORMAT_VERSION+1;
+        assert!(EncryptionParams::read(&mut buff.as_slice()).is_err());
+    }
+
+    #[test]
+    fn seeded_salt() {
+        use rand::{SeedableRng, rngs::StdRng};
+        let argon2_params = argon2::Params::new(8, 1, 1, None).unwrap();
+        let encrypt = |seed: u64| {
+            let (params, master_key) = EncryptionParams::with_password_and_rng(b"password", argon2_params.clone(), CipherAlgorithm::XChaCha20Poly1305, &mut StdRng::seed_from_u64(seed)).unwrap();
+            let mut cipher = DobyCipher::with_master_key(&master_key, &params).unwrap();
+            let mut ciphertext = Vec::new();
+            params.write(&mut ciphertext).unwrap();
+            cipher.encrypt_chunk(&mut b"plaintext".to_vec(), &mut ciphertext).unwrap();
+            cipher.write_hmac(&mut ciphertext).unwrap();
+            ciphertext
+        };
+        assert_eq!(encrypt(1), encrypt(1));
+        assert_ne!(encrypt(1), encrypt(2));
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let params = EncryptionParams::with_raw_key_and_rng(CipherAlgorithm::AesCtr, &mut rng);
+        assert_eq!(params.salt, EncryptionParams::new_with_rng(argon2_params, CipherAlgorithm::AesCtr, &mut StdRng::seed_from_u64(1)).salt);
+        assert_ne!(params.salt, EncryptionParams::with_raw_key_and_rng(CipherAlgorithm::AesCtr, &mut rng).salt);
+    }
+
+    #[test]
+    fn key_slots_encryption_params() {
+        let key_slots = vec![
+            KeySlot::X25519 { ephemeral_public: [1; 32], wrapped_key: [2; 48] },
+            KeySlot::X25519 { ephemeral_public: [3; 32], wrapped_key: [4; 48] },
+            KeySlot::from_password(b"password", argon2::Params::new(8, 1, 1, None).unwrap(), &[5; 32]).unwrap(),
+            KeySlot::Pkcs11 { key_id: vec![6; 3], wrapped_key: vec![7; 256] },
+            KeySlot::SshAgent { public_key: vec![8; 51], challenge: [9; 32], wrapped_key: [10; 48] },
+            KeySlot::Plugin { plugin: String::from("tpm"), data: vec![11; 100] },
+            KeySlot::Tpm { pcrs: vec![0, 7], public: vec![12; 78], private: vec![13; 160] },
+        ];
+        let params = EncryptionParams::with_key_slots(key_slots.clone(), CipherAlgorithm::AesGcm).unwrap();
+
+        let mut buff = Vec::new();
+        params.write(&mut buff).unwrap();
+        assert_eq!(buff.len(), 1+64+1+1+2*(1+32+48)+(1+64+4*3+48)+(1+1+3+2+256)+(1+2+51+32+48)+(1+1+3+2+100)+(1+1+2+2+78+2+160));
+        assert_eq!(buff[0], KEY_SLOTS_FORMAT_VERSION);
+        assert_eq!(buff[65], CipherAlgorithm::AesGcm as u8);
+        assert_eq!(buff[66], 7); //slot count
+
+        let new_params = EncryptionParams::read(&mut buff.as_slice()).unwrap();
+        assert_eq!(new_params, params);
+        assert_eq!(new_params.key_slots(), key_slots.as_slice());
+        assert_eq!(new_params.key_slots()[2].unwrap_password(b"password").unwrap(), Some([5; 32]));
+        assert_eq!(new_params.key_slots()[2].unwrap_password(b"wrong password").unwrap(), None);
+        assert_eq!(new_params.key_slots()[0].unwrap_password(b"password").unwrap(), None);
+        assert_eq!(new_params.key_slots()[3].unwrap_password(b"password").unwrap(), None);
+
+        buff[66] = 0;
+        assert!(EncryptionParams::read(&mut buff.as_slice()).is_err());
+
+        //the password can only open the key slots
+        assert!(matches!(DobyCipher::new(b"password", &params), Err(Error::Usage(_))));
+        assert!(matches!(EncryptionParams::with_key_slots(Vec::new(), CipherAlgorithm::AesGcm), Err(Error::Usage(_))));
+        assert!(matches!(EncryptionParams::with_key_slots(vec![key_slots[0].clone(); 256], CipherAlgorithm::AesGcm), Err(Error::Usage(_))));
+    }
+
+    #[test]
+    fn raw_key_encryption_params() {
+        let params = EncryptionParams::with_raw_key(CipherAlgorithm::XChaCha20Poly1305);
+
+        let mut buff = Vec::new();
+        params.write(&mut buff).unwrap();
+        assert_eq!(buff.len(), 1+64+1);
+        assert_eq!(buff[0], RAW_KEY_FORMAT_VERSION);
+        assert_eq!(buff[65], CipherAlgorithm::XChaCha20Poly1305 as u8);
+        assert_eq!(EncryptionParams::read(&mut buff.as_slice()).unwrap(), params);
+    }
+
+    #[test]
+    fn key_ids() {
+        let raw_key = [0x42; KEY_LEN];
+        assert_eq!(key_id(&raw_key), key_id(&raw_key));
+        assert_ne!(key_id(&raw_key), key_id(&[0x43; KEY_LEN]));
+        assert_eq!(format_key_id(&[0x01, 0xab, 0, 0xff]), "01ab00ff");
 
-        let mut buff = Vec::with_capacity(74);
+        let mut params = EncryptionParams::with_raw_key(CipherAlgorithm::XChaCha20Poly1305);
+        assert!(params.key_ids().is_empty());
+        params.set_key_ids(&[key_id(&raw_key), [1, 2, 3, 4]]).unwrap();
+        let mut buff = Vec::new();
         params.write(&mut buff).unwrap();
-        assert_eq!(buff[..64], params.salt);
-        assert_eq!(buff[64..68], vec![0, 0, 0, 0x01]); //t_cost
-        assert_eq!(buff[68..72], vec![0, 0, 0, 0x08]); //m_cost
-        assert_eq!(buff[72..76], vec![0, 0, 0, 0x01]); //p_cost
-        assert_eq!(buff[76], CipherAlgorithm::XChaCha20 as u8);
+        assert_eq!(&buff[buff.len()-KEY_ID_LEN*2-3..buff.len()-KEY_ID_LEN*2], &[KEY_IDS_EXTENSION, 0, 8]);
+        assert_eq!(EncryptionParams::read(&mut buff.as_slice()).unwrap().key_ids(), vec![key_id(&raw_key), [1, 2, 3, 4]]);
+    }
+
+    #[test]
+    fn legacy_encryption_params() {
+        let mut buff = vec![0x42; 64]; //salt
+        buff.extend_from_slice(&[0, 0, 0, 0x01, 0, 0, 0, 0x08, 0, 0, 0, 0x01, CipherAlgorithm::AesCtr as u8]);
+        let params = EncryptionParams::read_legacy(&mut buff.as_slice()).unwrap();
+        assert_eq!(params.version(), 0);
+        assert_eq!(params.salt, [0x42; 64]);
+        assert!(matches!(params.key_derivation, KeyDerivation::Password(ref argon2) if argon2.m_cost() == 8));
+        assert_eq!(params.cipher, CipherAlgorithm::AesCtr);
+
+        let mut encoded = Vec::with_capacity(buff.len());
+        params.write(&mut encoded).unwrap();
+        assert_eq!(encoded, buff);
+    }
 
-        let new_params = EncryptionParams::read(&mut buff.as_slice()).unwrap().unwrap();
+    #[test]
+    fn header_extensions() {
+        let (mut params, master_key) = EncryptionParams::with_password(b"password", argon2::Params::new(8, 1, 1, None).unwrap(), CipherAlgorithm::AesCtr).unwrap();
+        params.set_extension(0x10, b"unknown".to_vec()).unwrap();
+        params.set_extension(0x11, Vec::new()).unwrap();
+        assert!(params.set_extension(0x12, vec![0; 65535]).is_err());
+
+        let mut buff = Vec::new();
+        params.write(&mut buff).unwrap();
+        assert_eq!(buff.len(), EncryptionParams::LEN+KEY_CHECK_LEN+2+3+7+3);
+        assert_eq!(buff[77], CipherAlgorithm::AesCtr as u8 | 0x40);
+        //unknown non-critical extensions are kept
+        let new_params = EncryptionParams::read(&mut buff.as_slice()).unwrap();
         assert_eq!(new_params, params);
+        assert_eq!(new_params.extension(0x10), Some(&b"unknown"[..]));
+        let mut encoded = Vec::new();
+        new_params.write(&mut encoded).unwrap();
+        assert_eq!(encoded, buff);
+
+        //extensions are authenticated
+        let mut enc_cipher = DobyCipher::with_master_key(&master_key, &params).unwrap();
+        let mut ciphertext = Vec::new();
+        enc_cipher.encrypt_chunk(&mut [0; 10], &mut ciphertext).unwrap();
+        enc_cipher.write_hmac(&mut ciphertext).unwrap();
+        let mut tampered = buff.clone();
+        tampered[EncryptionParams::LEN+KEY_CHECK_LEN+5] ^= 1;
+        let tampered = EncryptionParams::read(&mut tampered.as_slice()).unwrap();
+        let mut dec_cipher = DobyCipher::try_new(b"password", &tampered).unwrap();
+        let mut decrypted = Vec::new();
+        dec_cipher.decrypt_update(&ciphertext, &mut decrypted);
+        assert!(!dec_cipher.decrypt_finalize(&mut decrypted));
+
+        //unknown critical extensions aren't
+        let mut critical = buff.clone();
+        critical[EncryptionParams::LEN+KEY_CHECK_LEN+2] = 0x90;
+        assert!(matches!(EncryptionParams::read(&mut critical.as_slice()), Err(Error::UnsupportedExtension(0x90))));
+        //the flag can't be set without extensions
+        buff.truncate(EncryptionParams::LEN+KEY_CHECK_LEN);
+        buff.extend_from_slice(&[0, 0]);
+        assert!(matches!(EncryptionParams::read(&mut buff.as_slice()), Err(Error::InvalidHeader)));
     }
 
     #[test]
@@ -206,20 +1869,436 @@ mod tests {
         let mut buff: [u8; 63] = *plaintext;
         let mut vec = Vec::with_capacity(buff.len()+HMAC_LEN);
 
-        let mut enc_cipher = DobyCipher::new(password.as_bytes(), &params);
+        let mut enc_cipher = DobyCipher::new(password.as_bytes(), &params).unwrap();
         enc_cipher.encrypt_chunk(&mut buff, &mut vec).unwrap();
         assert_ne!(buff, *plaintext);
         assert_eq!(buff, vec.as_slice());
         assert!(enc_cipher.write_hmac(&mut vec).is_ok());
         assert_eq!(vec.len(), buff.len()+HMAC_LEN);
 
-        let mut dec_cipher = DobyCipher::new(password.as_bytes(), &params);
+        let mut dec_cipher = DobyCipher::new(password.as_bytes(), &params).unwrap();
         let mut decrypted = vec![0; buff.len()+HMAC_LEN];
-        let mut n  = dec_cipher.decrypt_chunk(&mut vec.as_slice(), &mut decrypted[..]).unwrap();
+        let mut reader = vec.as_slice();
+        let mut n  = dec_cipher.decrypt_chunk(&mut reader, &mut decrypted[..]).unwrap();
         assert_eq!(n, buff.len());
-        n = dec_cipher.decrypt_chunk(&mut &vec[n..], &mut decrypted[n..]).unwrap();
+        n = dec_cipher.decrypt_chunk(&mut reader, &mut decrypted[n..]).unwrap();
         assert_eq!(n, 0);
         assert_eq!(decrypted[..buff.len()], *plaintext);
-        assert_eq!(dec_cipher.verify_hmac(), true);
+        assert!(dec_cipher.verify_hmac());
+    }
+
+    #[test]
+    fn key_check() {
+        let (params, master_key) = EncryptionParams::with_password(b"password", argon2::Params::new(8, 1, 1, None).unwrap(), CipherAlgorithm::AesCtr).unwrap();
+        let mut buff = Vec::new();
+        params.write(&mut buff).unwrap();
+        assert_eq!(buff.len(), EncryptionParams::LEN+KEY_CHECK_LEN);
+        assert_eq!(buff[0], KEY_CHECK_FORMAT_VERSION);
+        let params = EncryptionParams::read(&mut buff.as_slice()).unwrap();
+
+        let mut enc_cipher = DobyCipher::with_master_key(&master_key, &params).unwrap();
+        let mut ciphertext = Vec::new();
+        enc_cipher.encrypt_chunk(&mut [0; 10], &mut ciphertext).unwrap();
+        enc_cipher.write_hmac(&mut ciphertext).unwrap();
+
+        let mut dec_cipher = DobyCipher::try_new(b"password", &params).unwrap();
+        let mut decrypted = Vec::new();
+        assert!(dec_cipher.decrypt_update(&ciphertext, &mut decrypted));
+        assert!(dec_cipher.decrypt_finalize(&mut decrypted));
+        assert!(matches!(DobyCipher::try_new(b"wrong password", &params), Err(Error::WrongPassword)));
+    }
+
+    #[test]
+    fn argon2_memory_limit() {
+        let params = EncryptionParams::new(argon2::Params::new(MAX_ARGON2_MEMORY_COST+1, 1, 1, None).unwrap(), CipherAlgorithm::AesCtr);
+        let mut buff = Vec::new();
+        params.write(&mut buff).unwrap();
+        let params = EncryptionParams::read(&mut buff.as_slice()).unwrap();
+        assert!(matches!(DobyCipher::new(b"password", &params), Err(Error::InvalidParams(argon2::Error::MemoryTooMuch))));
+        let key_slots = vec![KeySlot::Password { salt: [0; SALT_LEN], argon2: argon2::Params::new(u32::MAX >> 4, 1, 1, None).unwrap(), wrapped_key: [0; WRAPPED_KEY_LEN] }];
+        let params = EncryptionParams::with_key_slots(key_slots, CipherAlgorithm::AesCtr).unwrap();
+        assert!(matches!(DobyCipher::try_with_password(b"password", &params), Err(Error::InvalidParams(_))));
+    }
+
+    #[test]
+    fn yubikey() {
+        let respond = |response: u8| move |slot: u8, challenge: &[u8; YUBIKEY_CHALLENGE_LEN]| -> Result<Vec<u8>, Error> {
+            assert_eq!(slot, 2);
+            Ok(challenge.iter().map(|b| b ^ response).take(20).collect())
+        };
+        let (params, master_key) = EncryptionParams::with_password_and_yubikey(b"password", argon2::Params::new(8, 1, 1, None).unwrap(), CipherAlgorithm::AesCtr, 2, respond(1)).unwrap();
+        let mut buff = Vec::new();
+        params.write(&mut buff).unwrap();
+        let params = EncryptionParams::read(&mut buff.as_slice()).unwrap();
+        assert_eq!(params.yubikey_slot(), Some(2));
+
+        let mut enc_cipher = DobyCipher::with_master_key(&master_key, &params).unwrap();
+        let mut ciphertext = Vec::new();
+        enc_cipher.encrypt_chunk(&mut [0; 10], &mut ciphertext).unwrap();
+        enc_cipher.write_hmac(&mut ciphertext).unwrap();
+
+        let mut dec_cipher = DobyCipher::try_new_with_yubikey(b"password", &params, respond(1)).unwrap();
+        let mut decrypted = Vec::new();
+        assert!(dec_cipher.decrypt_update(&ciphertext, &mut decrypted));
+        assert!(dec_cipher.decrypt_finalize(&mut decrypted));
+        //both the password and the token are required
+        assert!(matches!(DobyCipher::try_new_with_yubikey(b"password", &params, respond(2)), Err(Error::WrongPasswordOrYubiKey)));
+        assert!(matches!(DobyCipher::try_new_with_yubikey(b"wrong password", &params, respond(1)), Err(Error::WrongPasswordOrYubiKey)));
+        assert!(matches!(DobyCipher::try_new(b"password", &params), Err(Error::YubiKeyRequired)));
+    }
+
+    #[test]
+    #[cfg(all(feature = "blake3", feature = "parallel"))]
+    fn blake3_mac() {
+        let mut params = EncryptionParams::new(
+            argon2::Params::new(8, 1, 1, None).unwrap(),
+            CipherAlgorithm::XChaCha20
+        );
+        params.mac = MacAlgorithm::Blake3;
+        let mut buff = Vec::new();
+        params.write(&mut buff).unwrap();
+        assert_eq!(buff[77], 0x11);
+        assert_eq!(EncryptionParams::read(&mut buff.as_slice()).unwrap(), params);
+
+        let plaintext = vec![0x42; BLAKE3_RAYON_THRESHOLD+1];
+        let mut enc_cipher = DobyCipher::new(b"password", &params).unwrap();
+        let mut ciphertext = Vec::new();
+        enc_cipher.encrypt_chunk(&mut plaintext.clone(), &mut ciphertext).unwrap();
+        enc_cipher.write_hmac(&mut ciphertext).unwrap();
+
+        let mut dec_cipher = DobyCipher::new(b"password", &params).unwrap();
+        let mut decrypted = Vec::new();
+        assert!(dec_cipher.decrypt_update(&ciphertext, &mut decrypted));
+        assert!(dec_cipher.decrypt_finalize(&mut decrypted));
+        assert_eq!(decrypted, plaintext);
+
+        //the MAC is authenticated as part of the parameters
+        params.mac = MacAlgorithm::Blake2b;
+        let mut dec_cipher = DobyCipher::new(b"password", &params).unwrap();
+        let mut decrypted = Vec::new();
+        dec_cipher.decrypt_update(&ciphertext, &mut decrypted);
+        assert!(!dec_cipher.decrypt_finalize(&mut decrypted));
+    }
+
+    #[test]
+    fn cipher_clone() {
+        let params = EncryptionParams::new(
+            argon2::Params::new(8, 1, 1, None).unwrap(),
+            CipherAlgorithm::XChaCha20
+        );
+        let mut cipher = DobyCipher::new(b"password", &params).unwrap();
+        let mut first = [0; 100];
+        cipher.encrypt_chunk(&mut first, &mut Vec::new()).unwrap();
+        let mut clone = cipher.clone();
+        let (mut a, mut b) = ([0; 100], [0; 100]);
+        let (mut out_a, mut out_b) = (Vec::new(), Vec::new());
+        cipher.encrypt_chunk(&mut a, &mut out_a).unwrap();
+        clone.encrypt_chunk(&mut b, &mut out_b).unwrap();
+        assert_eq!(a, b);
+        cipher.write_hmac(&mut out_a).unwrap();
+        clone.write_hmac(&mut out_b).unwrap();
+        assert_eq!(out_a, out_b);
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn parallel_keystream() {
+        for algorithm in [CipherAlgorithm::AesCtr, CipherAlgorithm::XChaCha20] {
+            let len = KEYSTREAM_RAYON_THRESHOLD * 2 + 1000;
+            let mut sequential = vec![0; len];
+            KeyStreamCipher::new(algorithm, &[1; KEY_LEN], &vec![2; algorithm.get_nonce_size()]).apply_keystream(&mut sequential);
+
+            //start and end in the middle of cipher blocks
+            let mut parallel = vec![0; len];
+            let mut keystream = KeyStream::new(algorithm, Locked::new([1; KEY_LEN]), vec![2; algorithm.get_nonce_size()]);
+            rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap().install(|| {
+                keystream.apply_keystream(&mut parallel[..7]);
+                keystream.apply_keystream(&mut parallel[7..KEYSTREAM_RAYON_THRESHOLD*2+7]);
+                keystream.apply_keystream(&mut parallel[KEYSTREAM_RAYON_THRESHOLD*2+7..]);
+            });
+            assert!(parallel == sequential);
+        }
+    }
+
+    #[test]
+    fn decrypt_update() {
+        for cipher in [CipherAlgorithm::AesCtr, CipherAlgorithm::XChaCha20Poly1305] {
+            let params = EncryptionParams::new(
+                argon2::Params::new(8, 1, 1, None).unwrap(),
+                cipher
+            );
+            let plaintext: Vec<u8> = (0..AEAD_CHUNK_SIZE+100).map(|i| i as u8).collect();
+            let mut enc_cipher = DobyCipher::new(b"password", &params).unwrap();
+            let mut ciphertext = Vec::new();
+            enc_cipher.encrypt_chunk(&mut plaintext.clone(), &mut ciphertext).unwrap();
+            enc_cipher.write_hmac(&mut ciphertext).unwrap();
+
+            let mut dec_cipher = DobyCipher::new(b"password", &params).unwrap();
+            let mut decrypted = Vec::new();
+            for chunk in ciphertext.chunks(7) {
+                assert!(dec_cipher.decrypt_update(chunk, &mut decrypted));
+            }
+            assert!(dec_cipher.decrypt_finalize(&mut decrypted));
+            assert_eq!(decrypted, plaintext);
+
+            let mut dec_cipher = DobyCipher::new(b"password", &params).unwrap();
+            let mut decrypted = Vec::new();
+            assert!(dec_cipher.decrypt_update(&ciphertext[..ciphertext.len()-1], &mut decrypted));
+            assert!(!dec_cipher.decrypt_finalize(&mut decrypted));
+        }
+    }
+
+    #[test]
+    fn decryption_report() {
+        for cipher in [CipherAlgorithm::AesCtr, CipherAlgorithm::XChaCha20Poly1305] {
+            let params = EncryptionParams::new(
+                argon2::Params::new(8, 1, 1, None).unwrap(),
+                cipher
+            );
+            let plaintext: Vec<u8> = (0..AEAD_CHUNK_SIZE+100).map(|i| i as u8).collect();
+            let mut enc_cipher = DobyCipher::new(b"password", &params).unwrap();
+            let mut ciphertext = Vec::new();
+            enc_cipher.encrypt_chunk(&mut plaintext.clone(), &mut ciphertext).unwrap();
+            enc_cipher.write_hmac(&mut ciphertext).unwrap();
+            let mac_len = if cipher.is_aead() { AEAD_TAG_LEN } else { HMAC_LEN };
+            let expected_mac = &ciphertext[ciphertext.len()-mac_len..];
+
+            let mut dec_cipher = DobyCipher::new(b"password", &params).unwrap();
+            let mut decrypted = Vec::new();
+            assert!(dec_cipher.decrypt_update(&ciphertext[..100], &mut decrypted));
+            assert_eq!(dec_cipher.report().bytes, decrypted.len() as u64);
+            assert!(!dec_cipher.report().verified);
+            assert!(dec_cipher.decrypt_update(&ciphertext[100..], &mut decrypted));
+            assert!(dec_cipher.decrypt_finalize(&mut decrypted));
+            assert_eq!(decrypted, plaintext);
+
+            let mut reader = ciphertext.as_slice();
+            let mut dec_cipher = DobyCipher::new(b"password", &params).unwrap();
+            let mut buff = vec![0; 4096];
+            while dec_cipher.decrypt_chunk(&mut reader, &mut buff).unwrap() > 0 {}
+            let report = dec_cipher.report();
+            assert_eq!(report.bytes, plaintext.len() as u64);
+            assert!(report.verified);
+            assert_eq!(report.mac.as_deref(), Some(expected_mac));
+            assert_eq!(report.expected_mac.as_deref(), Some(expected_mac));
+
+            let mut corrupted = ciphertext.clone();
+            *corrupted.last_mut().unwrap() ^= 1;
+            let mut reader = corrupted.as_slice();
+            let mut dec_cipher = DobyCipher::new(b"password", &params).unwrap();
+            while dec_cipher.decrypt_chunk(&mut reader, &mut buff).unwrap() > 0 {}
+            let report = dec_cipher.report();
+            assert!(!report.verified);
+            assert_eq!(report.expected_mac.as_deref(), Some(&corrupted[corrupted.len()-mac_len..]));
+            if cipher.is_aead() {
+                assert_eq!(report.mac, None);
+            } else {
+                assert_eq!(report.mac.as_deref(), Some(expected_mac));
+            }
+        }
+    }
+
+    #[test]
+    fn tiny_buffers() {
+        let plaintext: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        for cipher in [CipherAlgorithm::AesCtr, CipherAlgorithm::XChaCha20, CipherAlgorithm::AesGcm, CipherAlgorithm::XChaCha20Poly1305] {
+            let params = EncryptionParams::with_raw_key(cipher);
+            let mut enc_cipher = DobyCipher::with_master_key(&[0; 32], &params).unwrap();
+            let mut ciphertext = Vec::new();
+            enc_cipher.encrypt_chunk(&mut plaintext.clone(), &mut ciphertext).unwrap();
+            enc_cipher.write_hmac(&mut ciphertext).unwrap();
+            //the buffer size can also change from one call to the next
+            for sizes in [&[1][..], &[8], &[HMAC_LEN], &[HMAC_LEN+1], &[100, 1, 50, 8]] {
+                let mut dec_cipher = DobyCipher::with_master_key(&[0; 32], &params).unwrap();
+                let mut reader = ciphertext.as_slice();
+                let mut decrypted = Vec::new();
+                for size in sizes.iter().cycle() {
+                    let mut buff = vec![0; *size];
+                    let n = dec_cipher.decrypt_chunk(&mut reader, &mut buff).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    decrypted.extend_from_slice(&buff[..n]);
+                }
+                assert_eq!(decrypted, plaintext);
+                assert!(dec_cipher.verify_hmac());
+            }
+        }
+    }
+
+    #[test]
+    fn aead_vectored_writes() {
+        struct VectoredWriter(Vec<u8>, usize);
+        impl Write for VectoredWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.write_vectored(&[IoSlice::new(buf)])
+            }
+            fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+                self.1 += 1;
+                bufs.iter().for_each(|buf| self.0.extend_from_slice(buf));
+                Ok(bufs.iter().map(|buf| buf.len()).sum())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let plaintext: Vec<u8> = (0..AEAD_CHUNK_SIZE*5+3).map(|i| i as u8).collect();
+        let params = EncryptionParams::with_raw_key(CipherAlgorithm::XChaCha20Poly1305);
+        let mut cipher = DobyCipher::with_master_key(&[0; 32], &params).unwrap();
+        let mut writer = VectoredWriter(Vec::new(), 0);
+        cipher.encrypt_slice(&plaintext, &mut writer).unwrap();
+        //the 5 full chunks and their tags at once, the last one is kept
+        assert_eq!(writer.1, 1);
+        assert_eq!(writer.0.len(), (AEAD_CHUNK_SIZE+AEAD_TAG_LEN)*5);
+        cipher.write_hmac(&mut writer).unwrap();
+
+        //same output as with small blocks
+        let mut cipher = DobyCipher::with_master_key(&[0; 32], &params).unwrap();
+        let mut ciphertext = Vec::new();
+        for block in plaintext.chunks(1000) {
+            cipher.encrypt_slice(block, &mut ciphertext).unwrap();
+        }
+        cipher.write_hmac(&mut ciphertext).unwrap();
+        assert!(writer.0 == ciphertext);
+    }
+
+    #[test]
+    fn aead_chunks() {
+        aead_chunks_with(CipherAlgorithm::XChaCha20Poly1305);
+        aead_chunks_with(CipherAlgorithm::AesGcm);
+    }
+
+    fn aead_chunks_with(cipher: CipherAlgorithm) {
+        let params = EncryptionParams::new(
+            argon2::Params::new(8, 1, 1, None).unwrap(),
+            cipher
+        );
+        let password = "I like spaghetti";
+        let plaintext: Vec<u8> = (0..AEAD_CHUNK_SIZE*2+10).map(|i| i as u8).collect();
+
+        let mut enc_cipher = DobyCipher::new(password.as_bytes(), &params).unwrap();
+        let mut ciphertext = Vec::new();
+        for chunk in plaintext.chunks(4096) {
+            enc_cipher.encrypt_chunk(&mut chunk.to_vec(), &mut ciphertext).unwrap();
+        }
+        enc_cipher.write_hmac(&mut ciphertext).unwrap();
+        assert_eq!(ciphertext.len(), plaintext.len()+3*AEAD_TAG_LEN);
+
+        let decrypt = |ciphertext: &[u8]| {
+            let mut dec_cipher = DobyCipher::new(password.as_bytes(), &params).unwrap();
+            let mut reader = ciphertext;
+            let mut buff = [0; 1000];
+            let mut decrypted = Vec::new();
+            loop {
+                let n = dec_cipher.decrypt_chunk(&mut reader, &mut buff).unwrap();
+                if n == 0 {
+                    break;
+                }
+                decrypted.extend_from_slice(&buff[..n]);
+            }
+            (decrypted, dec_cipher.verify_hmac())
+        };
+
+        let (decrypted, verified) = decrypt(&ciphertext);
+        assert_eq!(decrypted, plaintext);
+        assert!(verified);
+
+        //truncated at a chunk boundary: the first chunk wasn't sealed as the last one
+        let (decrypted, verified) = decrypt(&ciphertext[..AEAD_CHUNK_SIZE+AEAD_TAG_LEN]);
+        assert!(decrypted.is_empty());
+        assert!(!verified);
+
+        //truncated inside the last chunk
+        let (decrypted, verified) = decrypt(&ciphertext[..ciphertext.len()-1]);
+        assert_eq!(decrypted, plaintext[..AEAD_CHUNK_SIZE*2]);
+        assert!(!verified);
+
+        //corrupted first chunk: nothing is released
+        let mut corrupted = ciphertext.clone();
+        corrupted[10] ^= 1;
+        let (decrypted, verified) = decrypt(&corrupted);
+        assert!(decrypted.is_empty());
+        assert!(!verified);
+    }
+
+    #[test]
+    fn truncated() {
+        let params = EncryptionParams::new(
+            argon2::Params::new(8, 1, 1, None).unwrap(),
+            CipherAlgorithm::XChaCha20
+        );
+        let mut header = Vec::new();
+        params.write(&mut header).unwrap();
+        //magic bytes + version byte + salt
+        let expected = (crate::MAGIC_BYTES.len()+1+SALT_LEN) as u64;
+        assert!(matches!(EncryptionParams::read(&mut &header[..40]), Err(Error::Truncated { expected: e }) if e == expected));
+
+        for (cipher, tag_len) in [(CipherAlgorithm::AesCtr, HMAC_LEN), (CipherAlgorithm::XChaCha20Poly1305, AEAD_TAG_LEN)] {
+            let params = EncryptionParams::new(
+                argon2::Params::new(8, 1, 1, None).unwrap(),
+                cipher
+            );
+            let mut header = Vec::new();
+            params.write(&mut header).unwrap();
+            let header_len = (crate::MAGIC_BYTES.len()+header.len()) as u64;
+            let mut enc_cipher = DobyCipher::new(b"password", &params).unwrap();
+            let mut ciphertext = Vec::new();
+            enc_cipher.encrypt_chunk(&mut b"plaintext".to_vec(), &mut ciphertext).unwrap();
+            enc_cipher.write_hmac(&mut ciphertext).unwrap();
+
+            let decrypt = |ciphertext: &[u8]| {
+                let mut dec_cipher = DobyCipher::new(b"password", &params).unwrap();
+                let mut reader = ciphertext;
+                let mut buff = [0; 1000];
+                while dec_cipher.decrypt_chunk(&mut reader, &mut buff).unwrap() > 0 {}
+                dec_cipher
+            };
+            assert!(decrypt(&ciphertext).verify().is_ok());
+            let dec_cipher = decrypt(&ciphertext[..tag_len-1]);
+            assert_eq!(dec_cipher.truncated(), Some(header_len+tag_len as u64));
+            assert!(matches!(dec_cipher.verify(), Err(Error::Truncated { expected }) if expected == header_len+tag_len as u64));
+            //long enough to hold the tag: reported as an authentication failure
+            assert!(matches!(decrypt(&ciphertext[..ciphertext.len()-1]).verify(), Err(Error::HmacMismatch)));
+
+            let mut dec_cipher = DobyCipher::new(b"password", &params).unwrap();
+            assert!(dec_cipher.decrypt_update(&ciphertext[..5], &mut Vec::new()));
+            assert_eq!(dec_cipher.truncated(), Some(header_len+tag_len as u64));
+        }
+    }
+
+    #[test]
+    fn nfc_passwords() {
+        //"é" precomposed and decomposed
+        assert_eq!(*nfc_password("caf\u{e9}".as_bytes()), "caf\u{e9}".as_bytes());
+        assert_eq!(*nfc_password("cafe\u{301}".as_bytes()), "caf\u{e9}".as_bytes());
+        assert_eq!(*nfc_password(&[0xff, b'a']), [0xff, b'a']);
+
+        let argon2_params = argon2::Params::new(8, 1, 1, None).unwrap();
+        let (mut params, master_key) = EncryptionParams::with_password(&nfc_password("cafe\u{301}".as_bytes()), argon2_params.clone(), CipherAlgorithm::XChaCha20).unwrap();
+        assert!(matches!(DobyCipher::try_new("cafe\u{301}".as_bytes(), &params), Err(Error::WrongPassword)));
+        params.set_nfc_passwords().unwrap();
+        assert!(params.nfc_passwords());
+        let mut buff = Vec::new();
+        params.write(&mut buff).unwrap();
+        let params = EncryptionParams::read(&mut buff.as_slice()).unwrap();
+        assert!(params.nfc_passwords());
+        assert!(DobyCipher::try_new("cafe\u{301}".as_bytes(), &params).is_ok());
+        assert!(DobyCipher::try_new("caf\u{e9}".as_bytes(), &params).is_ok());
+
+        let key_slots = vec![KeySlot::from_password(&nfc_password("caf\u{e9}".as_bytes()), argon2_params, &master_key).unwrap()];
+        let mut params = EncryptionParams::with_key_slots(key_slots, CipherAlgorithm::XChaCha20).unwrap();
+        params.set_nfc_passwords().unwrap();
+        assert!(DobyCipher::try_with_password("cafe\u{301}".as_bytes(), &params).is_ok());
+    }
+
+    #[test]
+    fn locked() {
+        use crate::memlock::Locked;
+        let key = Locked::new([42; 32]);
+        assert_eq!(*key.clone(), [42; 32]);
+        assert!(!Locked::new(String::new()).is_locked());
+        let password = Locked::new(String::from("the password"));
+        assert_eq!(password.as_str(), "the password");
     }
 }
\ No newline at end of file
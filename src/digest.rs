@@ -0,0 +1,113 @@
+use std::{fmt::{self, Display, Formatter}, io::{self, Read, Write}};
+use blake2::{VarBlake2b, digest::{Update, VariableOutput}};
+use rand::{Rng, rngs::OsRng};
+use subtle::ConstantTimeEq;
+use crate::Error;
+
+pub const PLAINTEXT_DIGEST_LEN: usize = 32;
+const DIGEST_KEY_LEN: usize = 16;
+
+fn hasher(key: &[u8; DIGEST_KEY_LEN]) -> VarBlake2b {
+    VarBlake2b::new_keyed(key, PLAINTEXT_DIGEST_LEN)
+}
+
+fn finalize(hasher: VarBlake2b) -> [u8; PLAINTEXT_DIGEST_LEN] {
+    let mut hash = [0; PLAINTEXT_DIGEST_LEN];
+    hasher.finalize_variable(|result| hash.copy_from_slice(result));
+    hash
+}
+
+/// BLAKE2b digest of a plaintext, stored in the header by `--digest` to check the decrypted data (see `EncryptionParams::set_plaintext_digest`).
+///
+/// The header isn't encrypted: anyone holding a candidate plaintext can check whether it is the content of the file. The digest is keyed with a random key stored along with it, so that files with the same content can't be matched with each other.
+#[derive(Clone, Debug)]
+pub struct PlaintextDigest {
+    key: [u8; DIGEST_KEY_LEN],
+    hash: [u8; PLAINTEXT_DIGEST_LEN],
+}
+
+impl PlaintextDigest {
+    pub(crate) const ENCODED_LEN: usize = DIGEST_KEY_LEN + PLAINTEXT_DIGEST_LEN;
+
+    /// Reads `reader` to the end and hashes it with a new key.
+    pub fn compute<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let key = OsRng.gen();
+        let mut hasher = hasher(&key);
+        io::copy(reader, &mut HashWriter(&mut hasher))?;
+        Ok(Self { key, hash: finalize(hasher) })
+    }
+
+    /// Whether the content of `reader`, read to the end, is the plaintext hashed.
+    pub fn matches<R: Read>(&self, reader: &mut R) -> io::Result<bool> {
+        let mut hasher = hasher(&self.key);
+        io::copy(reader, &mut HashWriter(&mut hasher))?;
+        Ok(self.hash.ct_eq(&finalize(hasher)).into())
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        [&self.key[..], &self.hash[..]].concat()
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        (bytes.len() == Self::ENCODED_LEN).then(|| Self {
+            key: bytes[..DIGEST_KEY_LEN].try_into().unwrap(),
+            hash: bytes[DIGEST_KEY_LEN..].try_into().unwrap(),
+        })
+    }
+}
+
+impl Display for PlaintextDigest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for b in self.hash {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+struct HashWriter<'a>(&'a mut VarBlake2b);
+
+impl Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hashes the plaintext written through it, to be compared with the digest stored in the header by `finish`.
+pub struct DigestCheckWriter<W: Write> {
+    writer: W,
+    hasher: VarBlake2b,
+    expected: PlaintextDigest,
+}
+
+impl<W: Write> DigestCheckWriter<W> {
+    pub fn new(writer: W, expected: PlaintextDigest) -> Self {
+        Self { writer, hasher: hasher(&expected.key), expected }
+    }
+
+    /// Fails with `Error::DigestMismatch` if the plaintext written doesn't match the digest.
+    pub fn finish(self) -> Result<W, Error> {
+        if self.expected.hash.ct_eq(&finalize(self.hasher)).into() {
+            Ok(self.writer)
+        } else {
+            Err(Error::DigestMismatch)
+        }
+    }
+}
+
+impl<W: Write> Write for DigestCheckWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
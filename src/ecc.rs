@@ -0,0 +1,316 @@
+use std::io::{self, Read, Write};
+#[cfg(feature = "os")]
+use std::{fs::File, io::{BufRead, BufReader}, path::Path};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+#[cfg(feature = "os")]
+use crate::{Error, WrappedWriter};
+
+pub const ECC_MAGIC: &[u8; 7] = b"dobyecc";
+const ECC_VERSION: u8 = 1;
+pub const DATA_SHARDS: u8 = 16;
+pub const DEFAULT_PARITY_SHARDS: u8 = 2;
+pub const MAX_PARITY_SHARDS: u8 = 255-DATA_SHARDS;
+const SHARD_SIZE: u32 = 4096;
+const CHECKSUM_LEN: usize = 16;
+//magic bytes, version, data shards, parity shards, shard size, checksum
+const PARAMS_LEN: usize = ECC_MAGIC.len()+1+1+1+4;
+const PARAMS_COPY_LEN: usize = PARAMS_LEN+CHECKSUM_LEN;
+//the parameters aren't protected by the parity shards, so they are written several times
+const PARAMS_COPIES: usize = 3;
+//length of the data in the group, stored at the beginning of the first data shard
+const LEN_PREFIX: usize = 4;
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&blake3::hash(data).as_bytes()[..CHECKSUM_LEN]);
+    checksum
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Whether `first_bytes` starts with the parameters of an error correction container. Any of the copies of the parameters is enough.
+pub fn is_ecc(first_bytes: &[u8]) -> bool {
+    (0..PARAMS_COPIES).any(|i| first_bytes[(i*PARAMS_COPY_LEN).min(first_bytes.len())..].starts_with(ECC_MAGIC))
+}
+
+struct EccParams {
+    data_shards: usize,
+    parity_shards: usize,
+    shard_size: usize,
+}
+
+impl EccParams {
+    fn codec(&self) -> io::Result<ReedSolomon> {
+        ReedSolomon::new(self.data_shards, self.parity_shards).map_err(|e| invalid_data(format!("invalid error correction parameters: {:?}", e)))
+    }
+
+    //data bytes carried by a full group
+    fn capacity(&self) -> usize {
+        self.data_shards*self.shard_size-LEN_PREFIX
+    }
+
+    fn group_len(&self) -> usize {
+        (self.data_shards+self.parity_shards)*(self.shard_size+CHECKSUM_LEN)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PARAMS_COPY_LEN*PARAMS_COPIES);
+        bytes.extend_from_slice(ECC_MAGIC);
+        bytes.push(ECC_VERSION);
+        bytes.push(self.data_shards as u8);
+        bytes.push(self.parity_shards as u8);
+        bytes.extend_from_slice(&(self.shard_size as u32).to_be_bytes());
+        let checksum = checksum(&bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes.repeat(PARAMS_COPIES)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        //the first copy that isn't damaged
+        let copy = bytes.chunks_exact(PARAMS_COPY_LEN)
+            .find(|copy| checksum(&copy[..PARAMS_LEN]) == copy[PARAMS_LEN..] && copy.starts_with(ECC_MAGIC))
+            .ok_or_else(|| invalid_data(String::from("all the copies of the error correction parameters are damaged")))?;
+        let version = copy[ECC_MAGIC.len()];
+        if version != ECC_VERSION {
+            return Err(invalid_data(format!("unsupported error correction version: {}", version)));
+        }
+        let params = Self {
+            data_shards: copy[ECC_MAGIC.len()+1].into(),
+            parity_shards: copy[ECC_MAGIC.len()+2].into(),
+            shard_size: u32::from_be_bytes(copy[ECC_MAGIC.len()+3..PARAMS_LEN].try_into().unwrap()) as usize,
+        };
+        if params.shard_size == 0 || params.data_shards*params.shard_size <= LEN_PREFIX {
+            return Err(invalid_data(String::from("invalid error correction parameters")));
+        }
+        Ok(params)
+    }
+}
+
+/// Protects everything written to it with Reed-Solomon parity shards, so that damaged parts can be rebuilt before decryption.
+///
+/// Data is cut in groups of 16 data shards of 4KiB followed by `parity_shards` parity shards. Each shard has its own checksum so that damaged shards can be located: a group can be recovered as long as no more than `parity_shards` of its shards are damaged. The last group is written by `finish`.
+pub struct EccWriter<W: Write> {
+    writer: W,
+    params: EccParams,
+    codec: ReedSolomon,
+    group: Vec<u8>,
+    header_written: bool,
+    groups_written: u64,
+}
+
+impl<W: Write> EccWriter<W> {
+    /// Fails if `parity_shards` is 0 or above `MAX_PARITY_SHARDS`.
+    pub fn new(writer: W, parity_shards: u8) -> io::Result<Self> {
+        let params = EccParams {
+            data_shards: DATA_SHARDS.into(),
+            parity_shards: parity_shards.into(),
+            shard_size: SHARD_SIZE as usize,
+        };
+        if parity_shards == 0 || parity_shards > MAX_PARITY_SHARDS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("the number of parity shards must be between 1 and {}", MAX_PARITY_SHARDS)));
+        }
+        Ok(Self {
+            writer,
+            codec: params.codec()?,
+            group: Vec::with_capacity(params.capacity()),
+            params,
+            header_written: false,
+            groups_written: 0,
+        })
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.writer.write_all(&self.params.to_bytes())?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    fn write_group(&mut self) -> io::Result<()> {
+        let mut data = Vec::with_capacity(self.params.data_shards*self.params.shard_size);
+        data.extend_from_slice(&(self.group.len() as u32).to_be_bytes());
+        data.extend_from_slice(&self.group);
+        data.resize(self.params.data_shards*self.params.shard_size, 0);
+        let mut shards: Vec<Vec<u8>> = data.chunks_exact(self.params.shard_size).map(|shard| shard.to_vec()).collect();
+        shards.resize(self.params.data_shards+self.params.parity_shards, vec![0; self.params.shard_size]);
+        self.codec.encode(&mut shards).unwrap();
+        for shard in &shards {
+            self.writer.write_all(shard)?;
+        }
+        for shard in &shards {
+            self.writer.write_all(&checksum(shard))?;
+        }
+        self.group.clear();
+        self.groups_written += 1;
+        Ok(())
+    }
+
+    /// Writes the last group and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_header()?;
+        //an empty last group is only needed if nothing was written, to tell where the data ends
+        if !self.group.is_empty() || self.groups_written == 0 {
+            self.write_group()?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for EccWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_header()?;
+        if self.group.len() == self.params.capacity() {
+            self.write_group()?;
+        }
+        let n = buf.len().min(self.params.capacity()-self.group.len());
+        self.group.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decodes the output of `EccWriter`, rebuilding damaged shards on the fly. Fails if a group has more damaged shards than parity shards.
+pub struct EccReader<R: Read> {
+    reader: R,
+    params: EccParams,
+    codec: ReedSolomon,
+    group: Vec<u8>,
+    pos: usize,
+    group_index: u64,
+    damaged_shards: u64,
+    finished: bool,
+}
+
+impl<R: Read> EccReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut params = vec![0; PARAMS_COPY_LEN*PARAMS_COPIES];
+        reader.read_exact(&mut params)?;
+        let params = EccParams::from_bytes(&params)?;
+        Ok(Self {
+            reader,
+            codec: params.codec()?,
+            group: Vec::new(),
+            pos: 0,
+            params,
+            group_index: 0,
+            damaged_shards: 0,
+            finished: false,
+        })
+    }
+
+    pub fn parity_shards(&self) -> u8 {
+        self.params.parity_shards as u8
+    }
+
+    /// Number of damaged shards found (and rebuilt) so far.
+    pub fn damaged_shards(&self) -> u64 {
+        self.damaged_shards
+    }
+
+    //fills `buf` unless the end of the input is reached first
+    fn read_full(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.reader.read(&mut buf[n..]) {
+                Ok(0) => break,
+                Ok(read) => n += read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(n)
+    }
+
+    fn read_group(&mut self) -> io::Result<()> {
+        let mut raw = vec![0; self.params.group_len()];
+        let n = self.read_full(&mut raw)?;
+        if n == 0 && self.group_index > 0 {
+            //the last group was full
+            self.finished = true;
+            return Ok(());
+        }
+        if n < raw.len() {
+            return Err(invalid_data(format!("error correction group {} is truncated", self.group_index)));
+        }
+        let shard_count = self.params.data_shards+self.params.parity_shards;
+        let (shards, checksums) = raw.split_at(shard_count*self.params.shard_size);
+        let mut shards: Vec<Option<Vec<u8>>> = shards.chunks_exact(self.params.shard_size)
+            .zip(checksums.chunks_exact(CHECKSUM_LEN))
+            .map(|(shard, expected)| if checksum(shard) == expected {
+                Some(shard.to_vec())
+            } else {
+                None
+            }).collect();
+        let damaged = shards.iter().filter(|shard| shard.is_none()).count();
+        if damaged > self.params.parity_shards {
+            return Err(invalid_data(format!("error correction group {} has too many damaged shards to be repaired ({} damaged, {} parity shards)", self.group_index, damaged, self.params.parity_shards)));
+        }
+        if damaged > 0 {
+            self.codec.reconstruct_data(&mut shards).map_err(|e| invalid_data(format!("can't repair error correction group {}: {:?}", self.group_index, e)))?;
+            self.damaged_shards += damaged as u64;
+        }
+        self.group.clear();
+        for shard in shards.into_iter().take(self.params.data_shards) {
+            self.group.extend_from_slice(&shard.unwrap());
+        }
+        let len = u32::from_be_bytes(self.group[..LEN_PREFIX].try_into().unwrap()) as usize;
+        if len > self.params.capacity() {
+            return Err(invalid_data(format!("error correction group {} has an invalid length", self.group_index)));
+        }
+        self.group.truncate(LEN_PREFIX+len);
+        self.pos = LEN_PREFIX;
+        self.group_index += 1;
+        if len < self.params.capacity() {
+            //only the last group isn't full
+            self.finished = true;
+            if self.read_full(&mut [0])? != 0 {
+                return Err(invalid_data(String::from("unexpected data after the last error correction group")));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for EccReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.group.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_group()?;
+        }
+        let n = buf.len().min(self.group.len()-self.pos);
+        buf[..n].copy_from_slice(&self.group[self.pos..self.pos+n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Rebuilds the damaged shards of the error correction container at `path` and rewrites it. Returns the number of damaged shards, the file being left untouched if there is none.
+#[cfg(feature = "os")]
+pub fn repair<P: AsRef<Path>>(path: P) -> Result<u64, Error> {
+    let path_str = path.as_ref().display().to_string();
+    let path_error = |error| Error::Path { path: path_str.clone(), error };
+    let mut reader = BufReader::new(File::open(path.as_ref()).map_err(path_error)?);
+    if !is_ecc(reader.fill_buf().map_err(path_error)?) {
+        return Err(Error::Usage("the file doesn't contain error correction data (see --ecc)"));
+    }
+    let mut reader = EccReader::new(reader).map_err(path_error)?;
+    //parity shards are rebuilt by re-encoding the repaired data
+    let mut output = WrappedWriter::from_path(path_str.clone()).into_buf_writer()?;
+    let mut writer = EccWriter::new(&mut output, reader.parity_shards())?;
+    io::copy(&mut reader, &mut writer).map_err(path_error)?;
+    writer.finish()?;
+    let damaged_shards = reader.damaged_shards();
+    if damaged_shards > 0 {
+        output.finish(true)?;
+    }
+    Ok(damaged_shards)
+}
@@ -0,0 +1,104 @@
+//! Plaintext files edited by `doby edit`, kept in a private directory on a RAM-backed filesystem when there is one.
+//!
+//! Editors need a path to open, and most of them save by writing a new file and renaming it over the old one, often with swap or backup files next to it: an anonymous (O_TMPFILE) file isn't enough, so the whole directory is private and removed afterwards.
+
+use std::{env, ffi::OsString, fs, io, path::{Path, PathBuf}, process::Command};
+use tempfile::TempDir;
+use crate::{Error, os::{shred, track_temporary_dir, untrack_temporary_dir}};
+
+/// Directory only accessible to its owner, whose files are shredded and removed on drop, or by `remove_temporary_outputs` if doby is interrupted.
+pub struct EditDir {
+    dir: Option<TempDir>,
+    /// Whether the directory is on a RAM-backed filesystem.
+    pub in_memory: bool,
+}
+
+impl EditDir {
+    pub fn new() -> io::Result<Self> {
+        let (parent, in_memory) = match memory_dir() {
+            Some(dir) => (dir, true),
+            None => (env::temp_dir(), false),
+        };
+        let dir = tempfile::Builder::new().prefix("doby-edit-").tempdir_in(parent)?;
+        track_temporary_dir(dir.path().to_path_buf());
+        Ok(Self { dir: Some(dir), in_memory })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.as_ref().unwrap().path()
+    }
+}
+
+impl Drop for EditDir {
+    fn drop(&mut self) {
+        if let Some(dir) = self.dir.take() {
+            //the plaintext could stay on disk otherwise, and the editor may have left copies of it
+            if !self.in_memory {
+                if let Ok(entries) = fs::read_dir(dir.path()) {
+                    for entry in entries.flatten() {
+                        if entry.file_type().is_ok_and(|file_type| file_type.is_file()) {
+                            let _ = shred(entry.path());
+                        }
+                    }
+                }
+            }
+            untrack_temporary_dir(dir.path());
+            let _ = dir.close();
+        }
+    }
+}
+
+//$XDG_RUNTIME_DIR, which is private to the user, or /dev/shm, if they are tmpfs
+#[cfg(target_os = "linux")]
+fn memory_dir() -> Option<PathBuf> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    const TMPFS_MAGIC: libc::c_long = 0x01021994;
+    let is_tmpfs = |path: &Path| {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else { return false };
+        //SAFETY: statfs is plain old data, path is NUL-terminated and stat is written by statfs
+        unsafe {
+            let mut stat: libc::statfs = std::mem::zeroed();
+            libc::statfs(path.as_ptr(), &mut stat) == 0 && stat.f_type as libc::c_long == TMPFS_MAGIC
+        }
+    };
+    env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).into_iter()
+        .chain([PathBuf::from("/dev/shm")])
+        .find(|dir| dir.is_absolute() && is_tmpfs(dir))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn memory_dir() -> Option<PathBuf> {
+    None
+}
+
+/// `$VISUAL`, then `$EDITOR`, then `vi`. They may contain arguments (e.g. "code --wait"), so they are run by the shell.
+pub fn editor() -> OsString {
+    ["VISUAL", "EDITOR"].iter()
+        .filter_map(env::var_os)
+        .find(|editor| !editor.is_empty())
+        .unwrap_or_else(|| OsString::from("vi"))
+}
+
+/// Opens `path` in the editor and waits for it to exit.
+pub fn run_editor(path: &Path) -> Result<(), Error> {
+    let editor = editor();
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(&editor).arg(path);
+        command
+    } else {
+        let mut script = editor.clone();
+        script.push(" \"$1\"");
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script).arg("sh").arg(path);
+        command
+    };
+    let editor = editor.to_string_lossy();
+    let status = command.status().map_err(|e| Error::Editor(format!("can't run {}: {}", editor, e)))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Editor(format!("{} {}", editor, status)))
+    }
+}
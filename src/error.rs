@@ -0,0 +1,302 @@
+use std::{fmt::{self, Display, Formatter}, io};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Path {
+        path: String,
+        error: io::Error,
+    },
+    InvalidNumber(String),
+    Usage(&'static str),
+    InvalidParams(argon2::Error),
+    UnknownFormat,
+    AlreadyEncrypted,
+    InvalidHeader,
+    UnsupportedVersion(u8),
+    UnsupportedExtension(u8),
+    HmacMismatch,
+    Truncated {
+        expected: u64,
+    },
+    WrongPassword,
+    InvalidRecipient(String),
+    InvalidIdentity,
+    NoMatchingIdentity,
+    NoMatchingKeySlot,
+    InvalidRawKey,
+    PasswordMismatch,
+    MissingEnvVar(String),
+    PasswordCommand(String),
+    SameFile(String),
+    InvalidNameTemplate(String),
+    NameTemplateMismatch {
+        name: String,
+        template: String,
+    },
+    BatchFailed {
+        failed: usize,
+        total: usize,
+        decrypting: bool,
+    },
+    RotationFailed {
+        failed: usize,
+        total: usize,
+    },
+    MissingStoredName,
+    InvalidMirror,
+    HeaderMismatch(String),
+    InvalidStoredName(String),
+    InvalidEntryName(String),
+    DuplicateEntry(String),
+    EntryNotFound(String),
+    NotTarArchive {
+        decrypted: bool,
+    },
+    InvalidVerifyKey(String),
+    InvalidSigningKey,
+    InvalidSignature,
+    SignerMismatch(String),
+    MissingSignature,
+    YubiKey(String),
+    YubiKeyRequired,
+    WrongPasswordOrYubiKey,
+    InvalidPkcs11KeyId(String),
+    Pkcs11(String),
+    InvalidSshKey(String),
+    SshAgent(String),
+    Kms(String),
+    KmsRequired,
+    RemoteRequired,
+    Clipboard(String),
+    Qr(String),
+    Editor(String),
+    InvalidPattern(String),
+    NoMatch,
+    KeyNotFound(String),
+    KeyExists(String),
+    WrongRawKey(String),
+    OpenSslDecryption,
+    Plugin {
+        name: String,
+        message: String,
+    },
+    InvalidTpmPcrs(String),
+    Tpm(String),
+    InvalidConfig {
+        path: String,
+        line: usize,
+        message: String,
+    },
+    InvalidEnvVar {
+        name: String,
+        message: String,
+    },
+    /// Name of the signal
+    Interrupted(&'static str),
+    Sandbox(String),
+    /// Largest Argon2 costs required by the file, above `--max-time-cost` or `--max-memory-cost`
+    Argon2LimitExceeded {
+        time_cost: u32,
+        memory_cost: u32,
+    },
+    DigestMismatch,
+    MissingDigest,
+    /// Name of the known-answer test that failed
+    SelfTestFailed(String),
+    /// Error message of `doby serve`
+    Server(String),
+    PakeFailed,
+    TransferNotConfirmed,
+    /// Name the received file was sent with
+    ReceivedFileExists(String),
+    InputModified,
+}
+
+impl Error {
+    /// Stable identifier of the kind of error, reported by `--json`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Io(_) | Error::Path { .. } => "io",
+            Error::InvalidNumber(_) => "invalid_number",
+            Error::Usage(_) => "usage",
+            Error::InvalidParams(_) => "invalid_params",
+            Error::UnknownFormat => "unknown_format",
+            Error::AlreadyEncrypted => "already_encrypted",
+            Error::InvalidHeader => "invalid_header",
+            Error::UnsupportedVersion(_) => "unsupported_version",
+            Error::UnsupportedExtension(_) => "unsupported_extension",
+            Error::HmacMismatch => "hmac_mismatch",
+            Error::Truncated { .. } => "truncated",
+            Error::WrongPassword => "wrong_password",
+            Error::InvalidRecipient(_) => "invalid_recipient",
+            Error::InvalidIdentity => "invalid_identity",
+            Error::NoMatchingIdentity => "no_matching_identity",
+            Error::NoMatchingKeySlot => "no_matching_key_slot",
+            Error::InvalidRawKey => "invalid_raw_key",
+            Error::PasswordMismatch => "password_mismatch",
+            Error::MissingEnvVar(_) => "missing_env_var",
+            Error::PasswordCommand(_) => "password_command",
+            Error::SameFile(_) => "same_file",
+            Error::InvalidNameTemplate(_) => "invalid_name_template",
+            Error::NameTemplateMismatch { .. } => "name_template_mismatch",
+            Error::BatchFailed { .. } => "batch_failed",
+            Error::RotationFailed { .. } => "rotation_failed",
+            Error::MissingStoredName => "missing_stored_name",
+            Error::InvalidMirror => "invalid_mirror",
+            Error::HeaderMismatch(_) => "header_mismatch",
+            Error::InvalidStoredName(_) => "invalid_stored_name",
+            Error::InvalidEntryName(_) => "invalid_entry_name",
+            Error::DuplicateEntry(_) => "duplicate_entry",
+            Error::EntryNotFound(_) => "entry_not_found",
+            Error::NotTarArchive { .. } => "not_tar_archive",
+            Error::InvalidVerifyKey(_) => "invalid_verify_key",
+            Error::InvalidSigningKey => "invalid_signing_key",
+            Error::InvalidSignature => "invalid_signature",
+            Error::SignerMismatch(_) => "signer_mismatch",
+            Error::MissingSignature => "missing_signature",
+            Error::YubiKey(_) => "yubikey",
+            Error::YubiKeyRequired => "yubikey_required",
+            Error::WrongPasswordOrYubiKey => "wrong_password_or_yubikey",
+            Error::InvalidPkcs11KeyId(_) => "invalid_pkcs11_key_id",
+            Error::Pkcs11(_) => "pkcs11",
+            Error::InvalidSshKey(_) => "invalid_ssh_key",
+            Error::SshAgent(_) => "ssh_agent",
+            Error::Kms(_) => "kms",
+            Error::KmsRequired => "kms_required",
+            Error::RemoteRequired => "remote_required",
+            Error::Clipboard(_) => "clipboard",
+            Error::Qr(_) => "qr",
+            Error::Editor(_) => "editor",
+            Error::InvalidPattern(_) => "invalid_pattern",
+            Error::NoMatch => "no_match",
+            Error::KeyNotFound(_) => "key_not_found",
+            Error::KeyExists(_) => "key_exists",
+            Error::WrongRawKey(_) => "wrong_raw_key",
+            Error::OpenSslDecryption => "openssl_decryption",
+            Error::Plugin { .. } => "plugin",
+            Error::InvalidTpmPcrs(_) => "invalid_tpm_pcrs",
+            Error::Tpm(_) => "tpm",
+            Error::InvalidConfig { .. } => "invalid_config",
+            Error::InvalidEnvVar { .. } => "invalid_env_var",
+            Error::Interrupted(_) => "interrupted",
+            Error::Sandbox(_) => "sandbox",
+            Error::Argon2LimitExceeded { .. } => "argon2_limit_exceeded",
+            Error::DigestMismatch => "digest_mismatch",
+            Error::MissingDigest => "missing_digest",
+            Error::SelfTestFailed(_) => "self_test_failed",
+            Error::Server(_) => "server",
+            Error::PakeFailed => "pake_failed",
+            Error::TransferNotConfirmed => "transfer_not_confirmed",
+            Error::ReceivedFileExists(_) => "received_file_exists",
+            Error::InputModified => "input_modified",
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Path { path, error } => write!(f, "{}: {}", path, error),
+            Error::InvalidNumber(s) => write!(f, "'{}' is not a number", s),
+            Error::Usage(s) => f.write_str(s),
+            Error::InvalidParams(e) => write!(f, "invalid Argon2 parameters: {}", e),
+            Error::UnknownFormat => f.write_str("doby format not recognized"),
+            Error::AlreadyEncrypted => f.write_str("input is already in doby format (use -f to encrypt it anyway)"),
+            Error::InvalidHeader => f.write_str("invalid encryption parameters"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported format version {} (latest supported: {})", v, crate::crypto::LATEST_FORMAT_VERSION),
+            Error::UnsupportedExtension(kind) => write!(f, "unsupported critical header extension {} (a newer version of doby is needed)", kind),
+            Error::HmacMismatch => f.write_str("HMAC verification failed !\nEither your password is incorrect or the ciphertext has been corrupted.\nBe careful, the data could have been altered by an attacker."),
+            Error::Truncated { expected } => write!(f, "file appears truncated (expected at least {} bytes)", expected),
+            Error::WrongPassword => f.write_str("wrong password"),
+            Error::InvalidRecipient(s) => write!(f, "invalid recipient: {}", s),
+            Error::InvalidIdentity => f.write_str("invalid identity"),
+            Error::NoMatchingIdentity => f.write_str("no identity matches the recipients of this file"),
+            Error::NoMatchingKeySlot => f.write_str("no key slot can be opened with this password or these identities"),
+            Error::InvalidRawKey => write!(f, "raw keys must be {} bytes long ({} hexadecimal characters)", crate::crypto::KEY_LEN, crate::crypto::KEY_LEN*2),
+            Error::PasswordMismatch => f.write_str("passwords don't match"),
+            Error::MissingEnvVar(name) => write!(f, "environment variable {} is not set", name),
+            Error::PasswordCommand(command) => write!(f, "password command failed: {}", command),
+            Error::SameFile(path) => write!(f, "{} is both INPUT and OUTPUT: --rm and --shred would delete the output", path),
+            Error::InvalidNameTemplate(s) => write!(f, "invalid name template: {}", s),
+            Error::NameTemplateMismatch { name, template } => write!(f, "{} doesn't match the name template {}", name, template),
+            Error::HeaderMismatch(path) => write!(f, "the header of {} has a different salt: the backup seems to belong to another file (use --force to restore it anyway)", path),
+            Error::MissingStoredName => f.write_str("INPUT doesn't contain its original file name (it wasn't encrypted with --store-name)"),
+            Error::InvalidStoredName(name) => write!(f, "the file name stored in INPUT is invalid: {:?}", name),
+            Error::InvalidMirror => f.write_str("the .doby-mirror file of the mirror is invalid"),
+            Error::InvalidEntryName(path) => write!(f, "{} can't be stored in a container: only relative paths without \"..\" are allowed", path),
+            Error::DuplicateEntry(name) => write!(f, "{} is already in the container", name),
+            Error::EntryNotFound(name) => write!(f, "{} not found in the container", name),
+            Error::NotTarArchive { decrypted } => write!(f, "{} isn't a tar archive (--format tar)", if *decrypted { "the decrypted data" } else { "INPUT" }),
+            Error::InvalidVerifyKey(s) => write!(f, "invalid verify key: {}", s),
+            Error::InvalidSigningKey => f.write_str("invalid signing key"),
+            Error::InvalidSignature => f.write_str("signature verification failed !\nThe ciphertext has been altered since it was signed."),
+            Error::SignerMismatch(key) => write!(f, "INPUT was signed by another key: {}", key),
+            Error::MissingSignature => f.write_str("INPUT isn't signed (--verify-key)"),
+            Error::YubiKey(e) => write!(f, "YubiKey challenge-response failed: {}", e),
+            Error::YubiKeyRequired => f.write_str("this file also requires a YubiKey, which this build of doby doesn't support (enable the yubikey feature)"),
+            Error::WrongPasswordOrYubiKey => f.write_str("wrong password or YubiKey"),
+            Error::InvalidPkcs11KeyId(s) => write!(f, "invalid PKCS#11 key ID: {} (expected the hexadecimal CKA_ID)", s),
+            Error::Pkcs11(e) => write!(f, "PKCS#11 operation failed: {}", e),
+            Error::InvalidSshKey(s) => write!(f, "invalid SSH public key: {}", s),
+            Error::SshAgent(e) => write!(f, "ssh-agent: {}", e),
+            Error::Kms(e) => write!(f, "KMS request failed: {}", e),
+            Error::KmsRequired => f.write_str("the master key of this file is encrypted by a KMS, which this build of doby doesn't support (enable the kms feature)"),
+            Error::RemoteRequired => f.write_str("http(s):// and s3:// INPUT and OUTPUT aren't supported by this build of doby (enable the remote feature)"),
+            Error::Clipboard(e) => write!(f, "clipboard: {}", e),
+            Error::Qr(e) => write!(f, "QR code: {}", e),
+            Error::Editor(e) => write!(f, "editor: {}", e),
+            Error::InvalidPattern(e) => write!(f, "invalid pattern: {}", e),
+            Error::NoMatch => f.write_str("no line matches PATTERN"),
+            Error::KeyNotFound(name) => write!(f, "no key named {} (see doby key list)", name),
+            Error::KeyExists(name) => write!(f, "a key named {} already exists", name),
+            Error::WrongRawKey(key_id) => write!(f, "wrong key: this file was encrypted with the key of ID {}", key_id),
+            Error::OpenSslDecryption => f.write_str("OpenSSL decryption failed: wrong password, wrong iteration count (--openssl-iter) or corrupted file"),
+            Error::Plugin { name, message } => write!(f, "plugin {}: {}", name, message),
+            Error::InvalidTpmPcrs(s) => write!(f, "invalid TPM PCR list: {} (expected indices from 0 to {} separated by commas)", s, crate::crypto::MAX_TPM_PCR),
+            Error::Tpm(e) => write!(f, "TPM operation failed: {}", e),
+            Error::InvalidConfig { path, line, message } => write!(f, "{}:{}: {}", path, line, message),
+            Error::InvalidEnvVar { name, message } => write!(f, "{}: {}", name, message),
+            Error::Interrupted(signal) => write!(f, "interrupted by {}", signal),
+            Error::Sandbox(e) => write!(f, "sandboxing failed: {} (use --no-sandbox to run without it)", e),
+            Error::Argon2LimitExceeded { time_cost, memory_cost } => write!(f, "opening this file requires Argon2 with a time cost of {} and {}KiB of memory, above --max-time-cost or --max-memory-cost", time_cost, memory_cost),
+            Error::DigestMismatch => f.write_str("the decrypted data doesn't match the digest of the plaintext stored in the header"),
+            Error::MissingDigest => f.write_str("INPUT doesn't contain a digest of its plaintext (it wasn't encrypted with --digest)"),
+            Error::SelfTestFailed(name) => write!(f, "known-answer test {} failed: this build of doby can't be trusted on this platform", name),
+            Error::Server(message) => write!(f, "doby serve: {}", message),
+            Error::PakeFailed => f.write_str("the key exchange failed: the code is wrong, or someone tried to guess it (start again with a new code)"),
+            Error::TransferNotConfirmed => f.write_str("the receiver didn't confirm that it received the file"),
+            Error::ReceivedFileExists(path) => write!(f, "the file was sent as {}, which already exists: use --force to replace it, or give OUTPUT", path),
+            Error::InputModified => f.write_str("the file kept being modified while it was encrypted: try again once it isn't written to anymore"),
+            Error::BatchFailed { failed, total, decrypting } => write!(f, "{} out of {} files couldn't be {}", failed, total, if *decrypting { "decrypted" } else { "encrypted" }),
+            Error::RotationFailed { failed, total } => write!(f, "{} out of {} files couldn't be rotated", failed, total),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) | Error::Path { error: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        //unwrap errors reported through io::Error by the streaming adapters
+        if e.get_ref().map(|inner| inner.is::<Error>()).unwrap_or(false) {
+            *e.into_inner().unwrap().downcast::<Error>().unwrap()
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+impl From<argon2::Error> for Error {
+    fn from(e: argon2::Error) -> Self {
+        Error::InvalidParams(e)
+    }
+}
@@ -0,0 +1,356 @@
+//! Encryption and decryption of files by the doby binary, shared by its subcommands: threads, memory maps, rate limits, signatures, checks of the plaintext, and the key shared by the outputs of batch, watch, mirror and serve.
+
+use std::{fs::{self, File}, io::{self, BufRead, BufReader, Read, Write}, mem, path::Path, sync::{Arc, Mutex}, thread};
+use log::debug;
+use rand::rngs::OsRng;
+use crate::{
+    ArmorReader,
+    ArmorWriter,
+    EccReader,
+    EccWriter,
+    Error,
+    HeaderSplitter,
+    MAGIC_BYTES,
+    Metadata,
+    MetadataWriter,
+    OutputWriter,
+    PlaintextDigest,
+    RateLimitedReader,
+    RateLimiter,
+    SparseReader,
+    SparseWriter,
+    TarCheckWriter,
+    DigestCheckWriter,
+    WrappedReader,
+    WrappedWriter,
+    auto_block_size,
+    cli::BatchArgs,
+    crypto::{DobyCipher, EncryptionParams, KeySlot, MasterKey},
+    decrypt,
+    decrypt_pipelined,
+    encrypt,
+    encrypt_pipelined,
+    encrypt_slice_with_progress,
+    has_holes,
+    is_armored,
+    is_container,
+    is_doby_format,
+    is_ecc,
+    keying::{key_slots, new_master_key, wrapped_key_params},
+    memlock::Locked,
+    qr::{is_qr_text, read_scanned},
+    read_params,
+    shred,
+    signature::{SigningKey, SigningWriter},
+};
+
+pub const CONTAINER_INPUT: Error = Error::Usage("containers can't be decrypted as a whole: use the list and extract subcommands");
+
+/// Calls `write` on `writer`, then appends the signature of everything written if `signing_key` is given.
+pub fn signed<W: Write + Send>(writer: &mut W, signing_key: Option<&SigningKey>, write: impl FnOnce(&mut (dyn Write + Send)) -> Result<(), Error>) -> Result<(), Error> {
+    match signing_key {
+        Some(signing_key) => {
+            let mut writer = SigningWriter::new(writer);
+            write(&mut writer)?;
+            writer.finish(signing_key)?;
+            Ok(())
+        }
+        None => write(writer),
+    }
+}
+
+/// Memory map of INPUT encrypted instead of reading it (`--mmap`).
+pub struct MappedInput<'a> {
+    pub plaintext: &'a [u8],
+    pub progress: &'a mut dyn FnMut(u64),
+}
+
+/// Applies `--rate-limit` to `reader`.
+pub fn rate_limited<'a, R: Read + Send + 'a>(reader: R, limiter: Option<&Arc<RateLimiter>>) -> Box<dyn Read + Send + 'a> {
+    match limiter {
+        Some(limiter) => Box::new(RateLimitedReader::with_limiter(reader, limiter.clone())),
+        None => Box::new(reader),
+    }
+}
+
+/// Progress callback applying `--rate-limit` to a `MappedInput`, which isn't read.
+pub fn mapped_rate_limit(limiter: Option<&Arc<RateLimiter>>) -> impl FnMut(u64) + '_ {
+    let mut consumed = 0;
+    move |n| {
+        if let Some(limiter) = limiter {
+            limiter.consume(n - consumed);
+        }
+        consumed = n;
+    }
+}
+
+/// How the plaintext is read and split into blocks when encrypting.
+pub struct EncryptStream<'a, 'm> {
+    pub block_size: usize,
+    pub threads: usize,
+    /// Plaintext already read from the reader, encrypted first.
+    pub already_read: &'a [u8],
+    /// The reader isn't used if given.
+    pub mapped: Option<MappedInput<'m>>,
+}
+
+/// Encrypts to `writer`, except the header that is written to `header` if given. The signature, if any, covers the header too.
+pub fn encrypt_to<R: Read + Send, W: Write + Send>(reader: &mut R, writer: &mut W, header: Option<&mut OutputWriter>, params: &EncryptionParams, cipher: DobyCipher, stream: EncryptStream, signing_key: Option<&SigningKey>) -> Result<(), Error> {
+    signed(writer, signing_key, |mut writer| if let Some(header) = header {
+        let mut encoded = MAGIC_BYTES.to_vec();
+        params.write(&mut encoded)?;
+        encrypt_with_threads(reader, &mut HeaderSplitter::new(header, writer, encoded.len()), params, cipher, stream)
+    } else {
+        encrypt_with_threads(reader, &mut writer, params, cipher, stream)
+    })
+}
+
+/// Runs `f` in a pool of `threads` threads, which the ciphers use to process large blocks in parallel.
+fn in_thread_pool<T: Send>(threads: usize, f: impl FnOnce() -> Result<T, Error> + Send) -> Result<T, Error> {
+    rayon::ThreadPoolBuilder::new().num_threads(threads).build().map_err(io::Error::other)?.install(f)
+}
+
+pub fn encrypt_with_threads<R: Read + Send, W: Write + Send>(reader: &mut R, writer: &mut W, params: &EncryptionParams, cipher: DobyCipher, stream: EncryptStream) -> Result<(), Error> {
+    let EncryptStream { block_size, threads, already_read, mapped } = stream;
+    if let Some(mapped) = mapped {
+        //reading is replaced by page faults: there is nothing to do concurrently
+        encrypt_slice_with_progress(mapped.plaintext, writer, params, cipher, block_size, Some(already_read), mapped.progress)
+    } else if threads > 1 {
+        in_thread_pool(threads, || encrypt_pipelined(reader, writer, params, cipher, block_size, Some(already_read)))
+    } else {
+        encrypt(reader, writer, params, cipher, block_size, Some(already_read))
+    }
+}
+
+/// Also checks the plaintext against `digest` if given.
+pub fn decrypt_to<R: Read + Send, W: Write + Send>(reader: &mut R, writer: &mut W, cipher: DobyCipher, block_size: usize, threads: usize, digest: Option<PlaintextDigest>) -> Result<(), Error> {
+    if let Some(digest) = digest {
+        let mut writer = DigestCheckWriter::new(writer, digest);
+        decrypt_with_threads(reader, &mut writer, cipher, block_size, threads)?;
+        writer.finish().map(|_| ())
+    } else {
+        decrypt_with_threads(reader, writer, cipher, block_size, threads)
+    }
+}
+
+fn decrypt_with_threads<R: Read + Send, W: Write + Send>(reader: &mut R, writer: &mut W, cipher: DobyCipher, block_size: usize, threads: usize) -> Result<(), Error> {
+    if threads > 1 {
+        in_thread_pool(threads, || decrypt_pipelined(reader, writer, cipher, block_size))
+    } else {
+        decrypt(reader, writer, cipher, block_size)
+    }
+}
+
+/// Same as `decrypt_to`, but fails if the plaintext isn't a tar stream when `tar` is set.
+fn decrypt_checked<R: Read + Send, W: Write + Send>(reader: &mut R, writer: &mut W, cipher: DobyCipher, block_size: usize, threads: usize, tar: bool, digest: Option<PlaintextDigest>) -> Result<(), Error> {
+    if tar {
+        let mut writer = TarCheckWriter::new(writer);
+        decrypt_to(reader, &mut writer, cipher, block_size, threads, digest)?;
+        writer.finish().map(|_| ())
+    } else {
+        decrypt_to(reader, writer, cipher, block_size, threads, digest)
+    }
+}
+
+/// Same as `decrypt_metadata`, also decoding the plaintext of sparse files.
+pub fn decrypt_file<R: Read + Send>(reader: &mut R, writer: &mut OutputWriter, params: &EncryptionParams, cipher: DobyCipher, block_size: usize, threads: usize, tar: bool) -> Result<Option<Metadata>, Error> {
+    if params.sparse() {
+        let mut writer = SparseWriter::new(writer);
+        let metadata = decrypt_metadata(reader, &mut writer, params, cipher, block_size, threads, tar)?;
+        writer.finish()?;
+        Ok(metadata)
+    } else {
+        decrypt_metadata(reader, writer, params, cipher, block_size, threads, tar)
+    }
+}
+
+/// Same as `decrypt_checked`, checking the plaintext against the digest of `params` if there is one. Returns the `Metadata` at the beginning of the plaintext if `params` tells there is one.
+pub fn decrypt_metadata<R: Read + Send, W: Write + Send>(reader: &mut R, writer: &mut W, params: &EncryptionParams, cipher: DobyCipher, block_size: usize, threads: usize, tar: bool) -> Result<Option<Metadata>, Error> {
+    match (params.metadata, params.plaintext_digest()) {
+        //the digest covers the content, not the metadata before it
+        (true, Some(digest)) => {
+            let mut digest_writer = DigestCheckWriter::new(writer, digest);
+            let mut metadata_writer = MetadataWriter::new(&mut digest_writer);
+            decrypt_checked(reader, &mut metadata_writer, cipher, block_size, threads, tar, None)?;
+            let metadata = metadata_writer.into_metadata()?;
+            digest_writer.finish()?;
+            Ok(Some(metadata))
+        }
+        (true, None) => {
+            let mut metadata_writer = MetadataWriter::new(writer);
+            decrypt_checked(reader, &mut metadata_writer, cipher, block_size, threads, tar, None)?;
+            Ok(Some(metadata_writer.into_metadata()?))
+        }
+        (false, digest) => decrypt_checked(reader, writer, cipher, block_size, threads, tar, digest).map(|_| None),
+    }
+}
+
+/// Master key wrapped once in key slots shared by all the outputs of batch and watch, so that Argon2 only runs once per password. As each output has its own salt, its encryption keys and nonce are still unique.
+pub struct SharedKey {
+    pub master_key: MasterKey,
+    pub key_slots: Vec<KeySlot>,
+    pub kms_blob: Option<Vec<u8>>,
+    pub nfc_passwords: bool,
+}
+
+impl SharedKey {
+    pub fn new(args: &mut BatchArgs) -> Result<Self, Error> {
+        let (master_key, kms_blob) = match args.raw_key.as_deref() {
+            Some(raw_key) => (Locked::new(*raw_key), None),
+            None => new_master_key(args.encryption.kms_key_id.as_deref())?,
+        };
+        let (key_slots, nfc_passwords) = match args.raw_key {
+            Some(_) => (Vec::new(), false),
+            None => key_slots(&master_key, &args.key_slots, mem::take(&mut args.password), kms_blob.is_some(), "Password")?,
+        };
+        Ok(Self { master_key, key_slots, kms_blob, nfc_passwords })
+    }
+
+    /// Parameters of a new output, with its own salt.
+    pub fn params(&self, args: &BatchArgs, digest: Option<&PlaintextDigest>, sparse: bool) -> Result<EncryptionParams, Error> {
+        let mut params = wrapped_key_params(self.key_slots.clone(), self.kms_blob.as_deref(), args.encryption.cipher, self.nfc_passwords, &mut OsRng)?;
+        params.mac = args.encryption.mac;
+        params.metadata = args.preserve || args.store_name;
+        if let Some(comment) = &args.comment {
+            params.set_comment(comment)?;
+        }
+        if let Some(digest) = digest {
+            params.set_plaintext_digest(digest)?;
+        } else if sparse {
+            params.set_sparse()?;
+        }
+        if let Some(signing_key) = &args.sign_key {
+            params.set_signer(&signing_key.verify_key().to_bytes())?;
+        }
+        Ok(params)
+    }
+
+    /// Encrypts `input` with its own salt. `name` is stored instead of the file name of `input` with `store_name`. Returns the number of bytes written.
+    pub fn encrypt(&self, input: &str, writer: WrappedWriter<String>, args: &BatchArgs, name: Option<&str>) -> Result<u64, Error> {
+        let digest = if args.digest { Some(PlaintextDigest::compute(&mut File::open(input)?)?) } else { None };
+        let sparse = digest.is_none() && args.sparse && has_holes(&File::open(input)?)?;
+        let params = self.params(args, digest.as_ref(), sparse)?;
+        let cipher = DobyCipher::with_master_key(&self.master_key, &params)?;
+        encrypt_batch_file(input, writer, &params, cipher, args, name)
+    }
+}
+
+/// `name` is stored instead of the file name of `input` with `store_name`. Returns the number of bytes written.
+fn encrypt_batch_file(input: &str, writer: WrappedWriter<String>, params: &EncryptionParams, cipher: DobyCipher, args: &BatchArgs, name: Option<&str>) -> Result<u64, Error> {
+    let file = File::open(input)?;
+    let block_size = args.block_size.unwrap_or_else(|| auto_block_size(file.metadata().ok().as_ref()));
+    let metadata = if args.preserve || args.store_name {
+        let mut metadata = if args.preserve {
+            Metadata::from_file(&file.metadata()?)
+        } else {
+            Metadata::default()
+        };
+        if args.store_name {
+            let name = match name {
+                Some(name) => name,
+                None => Path::new(input).file_name().and_then(|name| name.to_str()).ok_or(Error::Usage("--store-name requires file names to be valid UTF-8"))?,
+            };
+            metadata.name = Some(name.to_string());
+        }
+        metadata.to_bytes()
+    } else {
+        Vec::new()
+    };
+    let mut reader = BufReader::new(WrappedReader::from_file(file));
+    let buff = reader.fill_buf()?;
+    if !args.force_encrypt && (is_armored(buff) || is_qr_text(buff) || is_ecc(buff) || is_doby_format(&buff[..buff.len().min(MAGIC_BYTES.len())])) {
+        return Err(Error::AlreadyEncrypted);
+    }
+    let sparse = if params.sparse() { Some(SparseReader::new(File::open(input)?)?) } else { None };
+    let map = if args.mmap && sparse.is_none() { reader.get_ref().map(0)? } else { None };
+    if sparse.is_some() {
+        debug!("{} is sparse: only its data is encrypted", input);
+    } else if map.is_some() {
+        debug!("{} mapped in memory", input);
+    }
+    let mut progress = mapped_rate_limit(args.rate_limit.as_ref());
+    let mapped = map.as_ref().map(|map| MappedInput { plaintext: map, progress: &mut progress });
+    let mut reader = match sparse {
+        Some(sparse) => rate_limited(sparse, args.rate_limit.as_ref()),
+        None => rate_limited(reader, args.rate_limit.as_ref()),
+    };
+    let mut writer = writer.into_buf_writer_with_block_size(block_size)?;
+    if args.armor {
+        let mut writer = ArmorWriter::new(&mut writer);
+        encrypt_to(&mut reader, &mut writer, None, params, cipher, EncryptStream { block_size, threads: args.threads, already_read: &metadata, mapped }, args.sign_key.as_ref())?;
+        writer.finish()?;
+    } else if let Some(parity_shards) = args.ecc {
+        let mut writer = EccWriter::new(&mut writer, parity_shards)?;
+        encrypt_to(&mut reader, &mut writer, None, params, cipher, EncryptStream { block_size, threads: args.threads, already_read: &metadata, mapped }, args.sign_key.as_ref())?;
+        writer.finish()?;
+    } else {
+        encrypt_to(&mut reader, &mut writer, None, params, cipher, EncryptStream { block_size, threads: args.threads, already_read: &metadata, mapped }, args.sign_key.as_ref())?;
+    }
+    let written = writer.written();
+    writer.finish(args.fsync)?;
+    if args.remove_inputs {
+        if args.shred {
+            shred(input)?;
+        } else {
+            fs::remove_file(input)?;
+        }
+    }
+    Ok(written)
+}
+
+/// Path of the file name stored in `metadata`, inside `dir`.
+pub fn restored_path(dir: &str, metadata: Option<&Metadata>) -> Result<String, Error> {
+    let name = metadata.and_then(|metadata| metadata.name.as_deref()).ok_or(Error::MissingStoredName)?;
+    //the ciphertext may come from someone else: never write outside of dir
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(Error::InvalidStoredName(name.to_string()));
+    }
+    Ok(Path::new(dir).join(name).to_string_lossy().into_owned())
+}
+
+/// Calls `process` on each input and output with `jobs` threads, returning the results in the same order as the inputs: the result of `process` (e.g. the number of bytes written), or `None` if the output was skipped.
+pub fn run_batch<T: Send, F>(inputs: &[String], outputs: Vec<Result<Option<WrappedWriter<String>>, Error>>, jobs: usize, process: F) -> Vec<Result<Option<T>, Error>>
+    where F: Fn(&str, WrappedWriter<String>) -> Result<T, Error> + Sync
+{
+    let queue = Mutex::new(inputs.iter().zip(outputs).enumerate());
+    let mut results: Vec<(usize, Result<Option<T>, Error>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.max(1)).map(|_| scope.spawn(|| {
+            let mut results = Vec::new();
+            loop {
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((i, (input, output))) => results.push((i, output.and_then(|output| match output {
+                        Some(writer) => process(input, writer).map(Some),
+                        None => Ok(None),
+                    }))),
+                    None => break results,
+                }
+            }
+        })).collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Reads the header of `file`, armored, scanned from QR codes or with parity data, and returns its parameters along with the reader of the ciphertext that follows.
+pub fn open_encrypted(file: File) -> Result<(EncryptionParams, BufReader<WrappedReader>), Error> {
+    let mut reader = BufReader::new(WrappedReader::from_file(file));
+    if is_armored(reader.fill_buf()?) {
+        reader = BufReader::new(WrappedReader::from_reader(ArmorReader::new(reader)));
+    } else if is_qr_text(reader.fill_buf()?) {
+        reader = BufReader::new(WrappedReader::from_reader(ArmorReader::new(io::Cursor::new(read_scanned(reader)?))));
+    } else if is_ecc(reader.fill_buf()?) {
+        reader = BufReader::new(WrappedReader::from_reader(EccReader::new(reader)?));
+    }
+    if is_container(reader.fill_buf()?) {
+        return Err(CONTAINER_INPUT);
+    }
+    let mut magic_bytes = vec![0; MAGIC_BYTES.len()];
+    let n = reader.read(&mut magic_bytes)?;
+    if !is_doby_format(&magic_bytes[..n]) {
+        return Err(Error::UnknownFormat);
+    }
+    let params = read_params(&magic_bytes, &mut reader)?;
+    Ok((params, reader))
+}
@@ -0,0 +1,88 @@
+use std::{fs::{File, OpenOptions}, io::{self, BufReader, Read, Seek, SeekFrom, Write}, path::Path};
+use crate::{EccReader, Error, crypto::SALT_LEN, is_armored, is_ecc, read_header};
+
+const ARMORED: Error = Error::Usage("armored files aren't supported by header backup and restore");
+const ECC: Error = Error::Usage("the header of files written with --ecc can't be restored, use repair instead");
+
+/// Keeps a copy of everything read from the inner reader.
+struct RecordingReader<R: Read> {
+    reader: R,
+    recorded: Vec<u8>,
+}
+
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Raw bytes of the header (magic bytes and encryption parameters) at the beginning of `reader`, as they were written.
+///
+/// This is also the content of a file written with `--detach-header`.
+pub fn read_raw_header<R: Read>(reader: R) -> Result<Vec<u8>, Error> {
+    let mut reader = RecordingReader { reader, recorded: Vec::new() };
+    read_header(&mut reader).map_err(|e| match e {
+        Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => Error::UnknownFormat,
+        e => e,
+    })?;
+    Ok(reader.recorded)
+}
+
+//whether the file starts with an armor or error correction data
+fn read_wrapping<R: Read>(reader: &mut R) -> io::Result<(bool, bool)> {
+    let mut start = [0; 128];
+    let n = reader.read(&mut start)?;
+    Ok((is_armored(&start[..n]), is_ecc(&start[..n])))
+}
+
+/// Copy of the header of the file at `path`, to be restored with `restore_header` if the file gets damaged.
+pub fn backup_header<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
+    let path_error = |error| Error::Path { path: path.as_ref().display().to_string(), error };
+    let mut file = File::open(path.as_ref()).map_err(path_error)?;
+    let (armored, ecc) = read_wrapping(&mut file).map_err(path_error)?;
+    if armored {
+        return Err(ARMORED);
+    }
+    file.seek(SeekFrom::Start(0)).map_err(path_error)?;
+    if ecc {
+        read_raw_header(EccReader::new(BufReader::new(file)).map_err(path_error)?)
+    } else {
+        read_raw_header(BufReader::new(file))
+    }
+}
+
+/// Writes the header found at the beginning of `backup` over the header of the file at `path`, like `cryptsetup luksHeaderRestore`.
+///
+/// Unless `force` is set, the current header isn't replaced if it can be read and most of its salt differs from the backup, since the backup then most likely belongs to another file. Returns `false` if the file already had this header.
+pub fn restore_header<P: AsRef<Path>>(backup: &[u8], path: P, force: bool) -> Result<bool, Error> {
+    let path_error = |error| Error::Path { path: path.as_ref().display().to_string(), error };
+    let header = read_raw_header(backup)?;
+    let backup_params = read_header(&mut header.as_slice())?;
+    let mut file = OpenOptions::new().read(true).write(true).open(path.as_ref()).map_err(path_error)?;
+    if file.metadata().map_err(path_error)?.len() < header.len() as u64 {
+        return Err(Error::Usage("the file is too short to contain the header of the backup"));
+    }
+    match read_wrapping(&mut file).map_err(path_error)? {
+        (true, _) => return Err(ARMORED),
+        (_, true) => return Err(ECC),
+        _ => {}
+    }
+    file.seek(SeekFrom::Start(0)).map_err(path_error)?;
+    if let Ok(current) = read_raw_header(&mut file) {
+        if current == header {
+            return Ok(false);
+        }
+        //damage only alters a few bytes, while two random salts differ almost everywhere
+        let current_params = read_header(&mut current.as_slice())?;
+        let differences = current_params.salt().iter().zip(backup_params.salt()).filter(|(a, b)| a != b).count();
+        if !force && differences > SALT_LEN/2 {
+            return Err(Error::HeaderMismatch(path.as_ref().display().to_string()));
+        }
+    }
+    file.seek(SeekFrom::Start(0)).map_err(path_error)?;
+    file.write_all(&header).map_err(path_error)?;
+    file.sync_all().map_err(path_error)?;
+    Ok(true)
+}
@@ -0,0 +1,257 @@
+use std::{fmt::{self, Display, Formatter}, fs::File, io::{self, BufRead, BufReader}, path::Path};
+use blake2::{VarBlake2b, digest::{Update, VariableOutput}};
+use crate::{ArmorReader, Container, DATA_SHARDS, EccReader, Error, crypto::{COMMENT_EXTENSION, EncryptionParams, KEY_ID_LEN, KEY_IDS_EXTENSION, KeyDerivation, KeySlot, KMS_EXTENSION, NFC_PASSWORDS_EXTENSION, PLAINTEXT_DIGEST_EXTENSION, SIGNATURE_EXTENSION, SPARSE_EXTENSION, YUBIKEY_EXTENSION, format_key_id}, is_armored, is_container, is_ecc, pkcs11::Pkcs11Key, read_header, signature::VerifyKey, ssh_agent::SshKey};
+
+const FINGERPRINT_LEN: usize = 8;
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len()+2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+//same syntax as --tpm, also valid inside a JSON array
+fn pcr_list(pcrs: &[u8]) -> String {
+    pcrs.iter().map(u8::to_string).collect::<Vec<String>>().join(",")
+}
+
+fn argon2_json(argon2: &argon2::Params) -> String {
+    format!("{{\"time_cost\":{},\"memory_cost\":{},\"parallelism\":{}}}", argon2.t_cost(), argon2.m_cost(), argon2.p_cost())
+}
+
+/// Public information about a doby file, readable without the password.
+pub struct Inspection {
+    pub file_size: u64,
+    pub armored: bool,
+    /// Written by `pack`, `params` being those of the index.
+    pub container: bool,
+    /// Number of parity shards per 16 data shards if the file was written with `--ecc`.
+    pub ecc_parity_shards: Option<u8>,
+    pub params: EncryptionParams,
+    /// Names of the known keys (e.g. stored by `doby key`) along with their ID, shown next to the key IDs of the header.
+    pub key_names: Vec<([u8; KEY_ID_LEN], String)>,
+}
+
+impl Inspection {
+    /// Short hash identifying the salt, e.g. to check whether two files come from the same encryption.
+    pub fn salt_fingerprint(&self) -> String {
+        let mut hasher = VarBlake2b::new(FINGERPRINT_LEN).unwrap();
+        hasher.update(self.params.salt());
+        let mut fingerprint = String::with_capacity(FINGERPRINT_LEN*2);
+        hasher.finalize_variable(|hash| {
+            for b in hash {
+                fingerprint += &format!("{:02x}", b);
+            }
+        });
+        fingerprint
+    }
+
+    /// Public key of the signer (`--sign-key`), if the ciphertext is signed.
+    pub fn signer(&self) -> Option<VerifyKey> {
+        self.params.signer().and_then(|key| VerifyKey::from_bytes(&key).ok())
+    }
+
+    /// ID of the key of each key slot (`None` if it isn't stored), or of the raw key.
+    pub fn slot_key_ids(&self) -> Vec<Option<[u8; KEY_ID_LEN]>> {
+        let mut key_ids = self.params.key_ids().into_iter();
+        match &self.params.key_derivation {
+            KeyDerivation::KeySlots(key_slots) => key_slots.iter().map(|key_slot| match key_slot {
+                KeySlot::X25519 { .. } => key_ids.next(),
+                _ => None,
+            }).collect(),
+            KeyDerivation::RawKey => vec![key_ids.next()],
+            KeyDerivation::Password(_) => Vec::new(),
+        }
+    }
+
+    /// Names of the known keys whose ID is `key_id`.
+    pub fn key_names(&self, key_id: &[u8; KEY_ID_LEN]) -> Vec<&str> {
+        self.key_names.iter().filter(|(id, _)| id == key_id).map(|(_, name)| name.as_str()).collect()
+    }
+
+    fn key_id_json(&self, key_id: Option<[u8; KEY_ID_LEN]>) -> String {
+        key_id.map(|key_id| {
+            let names = self.key_names(&key_id).into_iter().map(json_string).collect::<Vec<String>>();
+            format!(
+                ",\"key_id\":\"{}\"{}",
+                format_key_id(&key_id),
+                if names.is_empty() { String::new() } else { format!(",\"key_names\":[{}]", names.join(",")) },
+            )
+        }).unwrap_or_default()
+    }
+
+    fn key_id_text(&self, key_id: &[u8; KEY_ID_LEN]) -> String {
+        match self.key_names(key_id).as_slice() {
+            [] => format!("key ID {}", format_key_id(key_id)),
+            names => format!("key ID {}: {}", format_key_id(key_id), names.join(", ")),
+        }
+    }
+
+    //extensions that aren't shown otherwise
+    fn extension_types(&self) -> Vec<String> {
+        self.params.extensions().iter()
+            .filter(|extension| ![COMMENT_EXTENSION, SIGNATURE_EXTENSION, YUBIKEY_EXTENSION, KMS_EXTENSION, NFC_PASSWORDS_EXTENSION, PLAINTEXT_DIGEST_EXTENSION, SPARSE_EXTENSION, KEY_IDS_EXTENSION].contains(&extension.kind))
+            .map(|extension| extension.kind.to_string())
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        let key_ids = self.slot_key_ids();
+        let key_derivation = match &self.params.key_derivation {
+            KeyDerivation::Password(argon2) => format!(
+                "\"argon2\":{}{}",
+                argon2_json(argon2),
+                self.params.yubikey_slot().map(|slot| format!(",\"yubikey_slot\":{}", slot)).unwrap_or_default(),
+            ),
+            KeyDerivation::KeySlots(key_slots) => format!(
+                "\"key_slots\":[{}]",
+                key_slots.iter().zip(key_ids).map(|(key_slot, key_id)| match key_slot {
+                    KeySlot::X25519 { .. } => format!("{{\"type\":\"x25519\"{}}}", self.key_id_json(key_id)),
+                    KeySlot::Password { argon2, .. } => format!("{{\"type\":\"password\",\"argon2\":{}}}", argon2_json(argon2)),
+                    KeySlot::Pkcs11 { key_id, .. } => format!("{{\"type\":\"pkcs11\",\"key_id\":\"{}\"}}", Pkcs11Key::format_id(key_id)),
+                    KeySlot::SshAgent { public_key, .. } => format!("{{\"type\":\"ssh_agent\",\"fingerprint\":\"{}\"}}", SshKey::fingerprint(public_key)),
+                    KeySlot::Plugin { plugin, .. } => format!("{{\"type\":\"plugin\",\"plugin\":{}}}", json_string(plugin)),
+                    KeySlot::Tpm { pcrs, .. } => format!("{{\"type\":\"tpm\",\"pcrs\":[{}]}}", pcr_list(pcrs)),
+                }).collect::<Vec<String>>().join(","),
+            ),
+            KeyDerivation::RawKey => format!("\"raw_key\":true{}", self.key_id_json(key_ids[0])),
+        } + if self.params.kms_blob().is_some() { ",\"kms\":true" } else { "" }
+            + if self.params.nfc_passwords() { ",\"nfc_passwords\":true" } else { "" };
+        format!(
+            "{{\"format_version\":{},\"armored\":{},{}\"file_size\":{},{}{}{}{}\"salt_fingerprint\":\"{}\",{},\"cipher\":\"{}\",{}{}{}{}\"authenticated_encryption\":{}}}",
+            self.params.version(),
+            self.armored,
+            if self.container { "\"container\":true," } else { "" },
+            self.file_size,
+            self.ecc_parity_shards.map(|parity_shards| format!("\"error_correction\":{{\"data_shards\":{},\"parity_shards\":{}}},", DATA_SHARDS, parity_shards)).unwrap_or_default(),
+            self.params.comment().map(|comment| format!("\"comment\":{},", json_string(&comment))).unwrap_or_default(),
+            self.signer().map(|signer| format!("\"signer\":\"{}\",", signer)).unwrap_or_default(),
+            self.params.plaintext_digest().map(|digest| format!("\"plaintext_digest\":\"{}\",", digest)).unwrap_or_default(),
+            self.salt_fingerprint(),
+            key_derivation,
+            self.params.cipher,
+            if self.params.cipher.is_aead() { String::new() } else { format!("\"mac\":\"{}\",", self.params.mac) },
+            if self.params.metadata { "\"metadata\":true," } else { "" },
+            if self.params.sparse() { "\"sparse\":true," } else { "" },
+            if self.extension_types().is_empty() {
+                String::new()
+            } else {
+                format!("\"extensions\":[{}],", self.extension_types().join(","))
+            },
+            self.params.cipher.is_aead(),
+        )
+    }
+}
+
+impl Display for Inspection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Format version: {}{}", self.params.version(), if self.armored { " (armored)" } else if self.container { " (container)" } else { "" })?;
+        writeln!(f, "File size: {} bytes", self.file_size)?;
+        if let Some(parity_shards) = self.ecc_parity_shards {
+            writeln!(f, "Error correction: {} parity shards per {} data shards", parity_shards, DATA_SHARDS)?;
+        }
+        if let Some(comment) = self.params.comment() {
+            //the comment may come from someone else: don't let it send escape sequences to the terminal
+            writeln!(f, "Comment: {}", comment.escape_debug())?;
+        }
+        if let Some(signer) = self.signer() {
+            writeln!(f, "Signed by: {}", signer)?;
+        }
+        if let Some(digest) = self.params.plaintext_digest() {
+            writeln!(f, "Plaintext digest: {}", digest)?;
+        }
+        writeln!(f, "Salt fingerprint: {}", self.salt_fingerprint())?;
+        match &self.params.key_derivation {
+            KeyDerivation::Password(argon2) => {
+                writeln!(f, "Argon2 time cost: {}", argon2.t_cost())?;
+                writeln!(f, "Argon2 memory cost: {}KB", argon2.m_cost())?;
+                writeln!(f, "Argon2 parallelism cost: {}", argon2.p_cost())?;
+                if let Some(slot) = self.params.yubikey_slot() {
+                    writeln!(f, "YubiKey: challenge-response slot {}", slot)?;
+                }
+            }
+            KeyDerivation::KeySlots(key_slots) => {
+                for (i, (key_slot, key_id)) in key_slots.iter().zip(self.slot_key_ids()).enumerate() {
+                    match key_slot {
+                        KeySlot::X25519 { .. } => writeln!(f, "Key slot {}: X25519 recipient{}", i, key_id.map(|key_id| format!(" ({})", self.key_id_text(&key_id))).unwrap_or_default())?,
+                        KeySlot::Password { argon2, .. } => writeln!(
+                            f,
+                            "Key slot {}: password (Argon2 time cost: {}, memory cost: {}KB, parallelism cost: {})",
+                            i,
+                            argon2.t_cost(),
+                            argon2.m_cost(),
+                            argon2.p_cost(),
+                        )?,
+                        KeySlot::Pkcs11 { key_id, .. } => writeln!(f, "Key slot {}: PKCS#11 key {}", i, Pkcs11Key::format_id(key_id))?,
+                        KeySlot::SshAgent { public_key, .. } => writeln!(f, "Key slot {}: SSH key {}", i, SshKey::fingerprint(public_key))?,
+                        KeySlot::Plugin { plugin, .. } => writeln!(f, "Key slot {}: plugin {}", i, plugin)?,
+                        KeySlot::Tpm { pcrs, .. } => writeln!(f, "Key slot {}: TPM (PCRs {})", i, pcr_list(pcrs))?,
+                    }
+                }
+            }
+            KeyDerivation::RawKey => writeln!(f, "Key derivation: none (raw key{})", self.slot_key_ids()[0].map(|key_id| format!(", {}", self.key_id_text(&key_id))).unwrap_or_default())?,
+        }
+        if self.params.kms_blob().is_some() {
+            writeln!(f, "KMS: master key encrypted by a KMS key")?;
+        }
+        if self.params.nfc_passwords() {
+            writeln!(f, "Passwords: normalized to Unicode NFC")?;
+        }
+        write!(f, "Encryption cipher: {}", self.params.cipher)?;
+        if !self.params.cipher.is_aead() {
+            write!(f, "\nAuthentication: {} HMAC", self.params.mac)?;
+        }
+        if self.params.metadata {
+            write!(f, "\nMetadata: stored (encrypted)")?;
+        }
+        if self.params.sparse() {
+            write!(f, "\nSparse: holes of the plaintext not stored")?;
+        }
+        if !self.extension_types().is_empty() {
+            write!(f, "\nHeader extensions: {}", self.extension_types().join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn inspect<P: AsRef<Path>>(path: P) -> Result<Inspection, Error> {
+    let file = File::open(path.as_ref())
+        .map_err(|error| Error::Path { path: path.as_ref().display().to_string(), error })?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let armored = is_armored(reader.fill_buf()?);
+    let container = is_container(reader.fill_buf()?);
+    let mut ecc_parity_shards = None;
+    let params = if armored {
+        read_header(&mut ArmorReader::new(reader))
+    } else if container {
+        Container::open(reader).map(|container| container.params().clone())
+    } else if is_ecc(reader.fill_buf()?) {
+        let mut reader = EccReader::new(reader)?;
+        ecc_parity_shards = Some(reader.parity_shards());
+        read_header(&mut reader)
+    } else {
+        read_header(&mut reader)
+    }.map_err(|e| match e {
+        //too short to contain a header
+        Error::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => Error::UnknownFormat,
+        e => e,
+    })?;
+    Ok(Inspection {
+        file_size,
+        armored,
+        container,
+        ecc_parity_shards,
+        params,
+        key_names: Vec::new(),
+    })
+}
@@ -0,0 +1,205 @@
+//! Master keys of the command line: opened from the key slots of the inputs with whatever was given, and wrapped in the key slots of the outputs.
+
+use log::debug;
+use rand::RngCore;
+use zeroize::{Zeroize, Zeroizing};
+use crate::{
+    Error,
+    WrappedPassword,
+    cli::KeySlotOptions,
+    crypto::{CipherAlgorithm, EncryptionParams, KeyDerivation, KeySlot, MasterKey, KEY_ID_LEN, KEY_LEN, format_key_id, generate_master_key, key_id, nfc_password},
+    keys::{self, KeyKind},
+    memlock::Locked,
+    pkcs11::Pkcs11Key,
+    recipient::{Identity, unwrap_with_identities},
+    ssh_agent::unwrap_with_agent,
+    tpm::unseal,
+};
+#[cfg(feature = "yubikey")]
+pub use crate::yubikey::challenge_response;
+#[cfg(feature = "kms")]
+pub use crate::kms::{decrypt_data_key, generate_data_key};
+
+#[cfg(not(feature = "yubikey"))]
+pub fn challenge_response(_slot: u8, _challenge: &[u8; crate::crypto::YUBIKEY_CHALLENGE_LEN]) -> Result<Vec<u8>, Error> {
+    Err(Error::YubiKeyRequired)
+}
+
+#[cfg(not(feature = "kms"))]
+pub fn generate_data_key(_key_id: &str) -> Result<([u8; KEY_LEN], Vec<u8>), Error> {
+    Err(Error::KmsRequired)
+}
+
+#[cfg(not(feature = "kms"))]
+pub fn decrypt_data_key(_blob: &[u8]) -> Result<[u8; KEY_LEN], Error> {
+    Err(Error::KmsRequired)
+}
+
+/// Returns the raw key, or opens one of the key slots with the identities, the PKCS#11 key, ssh-agent, the TPM, the KMS or the password.
+pub fn master_key(params: &EncryptionParams, password: WrappedPassword, identities: &[Identity], pkcs11: Option<&Pkcs11Key>, raw_key: Option<&[u8; KEY_LEN]>, prompt: &str) -> Result<MasterKey, Error> {
+    match (&params.key_derivation, raw_key) {
+        (KeyDerivation::RawKey, Some(raw_key)) => match params.key_ids().first() {
+            //e.g. a file encrypted with a previous key of the keyring: detected before decrypting anything, like a wrong password
+            Some(expected) if *expected != key_id(raw_key) => stored_raw_key_for(expected)?.ok_or_else(|| Error::WrongRawKey(format_key_id(expected))),
+            _ => Ok(Locked::new(*raw_key)),
+        },
+        (KeyDerivation::RawKey, None) => match params.kms_blob() {
+            Some(blob) => decrypt_data_key(blob).map(Locked::new),
+            None => params.key_ids().first()
+                .map(stored_raw_key_for)
+                .transpose()?
+                .flatten()
+                .ok_or(Error::Usage("this file is encrypted with a raw key: --key, --key-hex or --key-file-raw is required")),
+        },
+        (_, Some(_)) => Err(Error::Usage("this file isn't encrypted with a raw key: --key, --key-hex and --key-file-raw can't be used")),
+        //the key derived from the password is specific to the salt
+        (KeyDerivation::Password(_), None) => Err(Error::InvalidHeader),
+        (KeyDerivation::KeySlots(key_slots), None) => {
+            let stored_identities;
+            let identities = if identities.is_empty() && !params.key_ids().is_empty() {
+                stored_identities = stored_identity_for(params, key_slots)?;
+                &stored_identities
+            } else {
+                identities
+            };
+            let has_password_slots = key_slots.iter().any(|key_slot| matches!(key_slot, KeySlot::Password { .. }));
+            let has_ssh_slots = key_slots.iter().any(|key_slot| matches!(key_slot, KeySlot::SshAgent { .. }));
+            let has_tpm_slots = key_slots.iter().any(|key_slot| matches!(key_slot, KeySlot::Tpm { .. }));
+            if !has_password_slots && !has_ssh_slots && !has_tpm_slots && params.kms_blob().is_none() && identities.is_empty() && pkcs11.is_none() {
+                return Err(Error::Usage("this file is encrypted to recipients: an --identity or --pkcs11-key is required"));
+            }
+            let mut master_key = unwrap_with_identities(identities, key_slots)?;
+            if let (None, Some(pkcs11)) = (master_key, pkcs11) {
+                master_key = pkcs11.unwrap(key_slots)?;
+            }
+            if master_key.is_none() {
+                match unwrap_with_agent(key_slots) {
+                    Ok(key) => master_key = key,
+                    //the password can still be used
+                    Err(_) if has_password_slots => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            if master_key.is_none() {
+                match unseal(key_slots) {
+                    Ok(key) => master_key = key,
+                    //e.g. the PCRs changed after a firmware update, or the file was sealed on another machine
+                    Err(_) if has_password_slots => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            if let (None, Some(blob)) = (master_key, params.kms_blob()) {
+                match decrypt_data_key(blob) {
+                    Ok(key) => master_key = Some(key),
+                    Err(_) if has_password_slots => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            //only ask for a password if nothing else worked
+            if master_key.is_none() && has_password_slots {
+                let mut password = password.get_with_prompt(prompt, false)?;
+                let password_bytes = params.password_bytes(password.as_bytes());
+                password.zeroize();
+                master_key = key_slots.iter().map(|key_slot| key_slot.unwrap_password(&password_bytes)).find_map(Result::transpose).transpose()?;
+            }
+            master_key.map(Locked::new).ok_or(if has_password_slots { Error::NoMatchingKeySlot } else { Error::NoMatchingIdentity })
+        }
+    }
+}
+
+/// First identity stored by `doby key` whose ID is among the key IDs of the header and that opens one of `key_slots`, so that `--identity` can be omitted.
+fn stored_identity_for(params: &EncryptionParams, key_slots: &[KeySlot]) -> Result<Vec<Identity>, Error> {
+    let key_ids = params.key_ids();
+    for (key_id, name) in keys::key_ids(Some(KeyKind::Identity)) {
+        if key_ids.contains(&key_id) {
+            let identities = Identity::parse_file_content(&keys::read_stored(&name)?)?;
+            if let Some(identity) = identities.into_iter().find(|identity| key_slots.iter().any(|key_slot| identity.unwrap(key_slot).is_some())) {
+                debug!("using the stored identity {}", name);
+                return Ok(vec![identity]);
+            }
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// Symmetric key of the keyring (see `doby key add`) whose ID is `expected`.
+fn stored_raw_key_for(expected: &[u8; KEY_ID_LEN]) -> Result<Option<MasterKey>, Error> {
+    for (stored_id, name) in keys::key_ids(Some(KeyKind::Symmetric)) {
+        if stored_id == *expected {
+            let key = keys::parse_symmetric(&keys::read_stored(&name)?)?;
+            //the ID of protected keys comes from the comment of their header
+            if key_id(&*key) == *expected {
+                debug!("using the stored key {}", name);
+                return Ok(Some(Locked::new(*key)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Random master key, or one generated by the KMS along with its encrypted blob if `kms_key_id` is given.
+pub fn new_master_key(kms_key_id: Option<&str>) -> Result<(MasterKey, Option<Vec<u8>>), Error> {
+    match kms_key_id {
+        Some(key_id) => generate_data_key(key_id).map(|(master_key, blob)| (Locked::new(master_key), Some(blob))),
+        None => Ok((generate_master_key(), None)),
+    }
+}
+
+/// Password as fed to Argon2 when encrypting: normalized to NFC if `nfc`. `normalized` is set if this has to be recorded in the header, i.e. if the password isn't ASCII.
+pub fn encryption_password(password: &str, nfc: bool, normalized: &mut bool) -> Zeroizing<Vec<u8>> {
+    if nfc {
+        *normalized |= !password.is_ascii();
+        nfc_password(password.as_bytes())
+    } else {
+        Zeroizing::new(password.as_bytes().to_vec())
+    }
+}
+
+/// Parameters of a master key wrapped in `key_slots` and/or by the KMS. Without key slots, the raw key format is used, the master key coming from the KMS.
+///
+/// `nfc_passwords` tells whether passwords of the key slots were normalized (see `encryption_password`).
+pub fn wrapped_key_params<R: RngCore>(key_slots: Vec<KeySlot>, kms_blob: Option<&[u8]>, cipher: CipherAlgorithm, nfc_passwords: bool, salt_rng: &mut R) -> Result<EncryptionParams, Error> {
+    let mut params = if key_slots.is_empty() {
+        EncryptionParams::with_raw_key_and_rng(cipher, salt_rng)
+    } else {
+        EncryptionParams::with_key_slots_and_rng(key_slots, cipher, salt_rng)?
+    };
+    if let Some(blob) = kms_blob {
+        params.set_kms_blob(blob.to_vec())?;
+    }
+    if nfc_passwords {
+        params.set_nfc_passwords()?;
+    }
+    Ok(params)
+}
+
+/// Wraps `master_key` in one key slot for each recipient, SSH key, PKCS#11 key, TPM policy and password of `options`. When encrypting to any of them but the TPM, which is only an additional slot, or with the KMS (`kms`), the password is only used if it was given on the command line.
+///
+/// Also returns whether the passwords were normalized to NFC, which has to be recorded in the header.
+pub fn key_slots(master_key: &[u8; KEY_LEN], options: &KeySlotOptions, password: WrappedPassword, kms: bool, prompt: &str) -> Result<(Vec<KeySlot>, bool), Error> {
+    let use_password = options.recipients.is_empty() && options.ssh_keys.is_empty() && options.pkcs11.is_none() && !kms || password.is_provided();
+    if options.recipients.len() + options.ssh_keys.len() + options.pkcs11.is_some() as usize + options.tpm.is_some() as usize + options.additional_passwords.len() + use_password as usize > u8::MAX as usize {
+        return Err(Error::Usage("too many key slots (maximum: 255)"));
+    }
+    let mut key_slots = options.recipients.iter().map(|recipient| recipient.wrap(master_key)).collect::<Result<Vec<KeySlot>, Error>>()?;
+    for ssh_key in &options.ssh_keys {
+        key_slots.push(ssh_key.wrap(master_key)?);
+    }
+    if let Some(pkcs11) = &options.pkcs11 {
+        key_slots.push(pkcs11.wrap(master_key)?);
+    }
+    if let Some(tpm) = &options.tpm {
+        key_slots.push(tpm.seal(master_key)?);
+    }
+    let mut normalized = false;
+    if use_password {
+        let mut password = password.get_with_prompt(prompt, true)?;
+        let key_slot = KeySlot::from_password(&encryption_password(&password, options.nfc, &mut normalized), options.argon2_params.clone(), master_key);
+        password.zeroize();
+        key_slots.push(key_slot?);
+    }
+    for password in &options.additional_passwords {
+        key_slots.push(KeySlot::from_password(&encryption_password(password, options.nfc, &mut normalized), options.argon2_params.clone(), master_key)?);
+    }
+    Ok((key_slots, normalized))
+}
@@ -0,0 +1,297 @@
+//! Keys managed by `doby key`: identities, signing keys and symmetric keys stored in `$XDG_CONFIG_HOME/doby/keys` (`~/.config/doby/keys` by default), one file named after each key.
+//!
+//! The files are regular identity or signing key files, only readable by their owner, or hold a symmetric key used as raw key with `--key`. A key protected with a passphrase is encrypted in armored doby format with Argon2, its public key (or the key ID of symmetric keys) being stored in the comment of the header so that it can be listed without the passphrase. Protected files are accepted by `--identity` and `--sign-key` like the others, and stored keys can be given to them by name instead of by path.
+
+use std::{env, fs, io, mem, path::{Path, PathBuf}};
+use rand::{Rng, rngs::OsRng};
+use zeroize::{Zeroize, Zeroizing};
+use crate::{
+    ArmorReader, ArmorWriter, DEFAULT_BLOCK_SIZE, Error, WrappedPassword, config::config_dir, decrypt, default_cipher, encrypt_slice, is_armored, read_header,
+    crypto::{DobyCipher, EncryptionParams, KEY_ID_LEN, KEY_LEN, decode_hex, default_argon2_params, format_key_id, key_id, nfc_password},
+    recipient::{Identity, Recipient},
+    signature::{SigningKey, SIGNING_KEY_PREFIX, VERIFY_KEY_PREFIX},
+};
+
+/// Comment of the header of protected keys, followed by their public key.
+pub const PUBLIC_KEY_COMMENT: &str = "public key: ";
+/// Same as `PUBLIC_KEY_COMMENT` for symmetric keys, followed by their key ID.
+pub const KEY_ID_COMMENT: &str = "key ID: ";
+pub const SYMMETRIC_KEY_PREFIX: &str = "doby-key-";
+pub const MAX_KEY_NAME_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    /// X25519 or plugin identity, used with `--identity`.
+    Identity,
+    /// Ed25519 signing key, used with `--sign-key`.
+    Signing,
+    /// 32 bytes key, used as raw key with `--key`.
+    Symmetric,
+}
+
+impl KeyKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Identity => "identity",
+            Self::Signing => "signing",
+            Self::Symmetric => "symmetric",
+        }
+    }
+}
+
+/// Public information about a stored key, readable without its passphrase.
+#[derive(Debug, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub kind: KeyKind,
+    /// Recipient of identities and verify key of signing keys. `None` for symmetric keys and plugin identities, whose recipient is only known to the plugin.
+    pub public_key: Option<String>,
+    /// ID stored in the header of the files encrypted to this identity or with this symmetric key (see `crypto::key_id`).
+    pub key_id: Option<[u8; KEY_ID_LEN]>,
+    pub protected: bool,
+}
+
+/// `keys` in `config_dir()`.
+pub fn keys_dir() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("keys"))
+}
+
+/// Key names are file names: letters, digits, "-", "_" and ".", not starting with ".".
+pub fn check_name(name: &str) -> Result<(), Error> {
+    let valid_chars = name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if name.is_empty() || name.len() > MAX_KEY_NAME_LEN || name.starts_with('.') || !valid_chars {
+        return Err(Error::Usage("key names are made of up to 64 letters, digits, \"-\", \"_\" and \".\", and can't start with \".\""));
+    }
+    Ok(())
+}
+
+/// Path of the stored key `name`, which may not exist.
+pub fn key_path(name: &str) -> Result<PathBuf, Error> {
+    check_name(name)?;
+    keys_dir().map(|dir| dir.join(name)).ok_or(Error::Usage("the key directory can't be found: neither XDG_CONFIG_HOME nor HOME is set"))
+}
+
+/// Path of the file `path_or_name` refers to: the file itself if it exists, or the stored key of that name if there is one.
+pub fn resolve(path_or_name: &str) -> PathBuf {
+    let path = Path::new(path_or_name);
+    if !path.exists() {
+        if let Ok(stored) = key_path(path_or_name) {
+            if stored.is_file() {
+                return stored;
+            }
+        }
+    }
+    path.to_path_buf()
+}
+
+fn into_string(mut content: Zeroizing<Vec<u8>>, invalid: Error) -> Result<Zeroizing<String>, Error> {
+    match String::from_utf8(mem::take(&mut *content)) {
+        Ok(content) => Ok(Zeroizing::new(content)),
+        Err(e) => {
+            e.into_bytes().zeroize();
+            Err(invalid)
+        }
+    }
+}
+
+/// Reads the key file at `path`, decrypting it with the passphrase returned by `passphrase` if it is protected.
+pub fn read_key_file<P: AsRef<Path>, F: FnOnce() -> Result<Zeroizing<String>, Error>>(path: P, passphrase: F) -> Result<Zeroizing<String>, Error> {
+    let path = path.as_ref();
+    let content = Zeroizing::new(fs::read(path).map_err(|error| Error::Path { path: path.display().to_string(), error })?);
+    if is_armored(&content) {
+        unprotect(&content, &passphrase()?)
+    } else {
+        into_string(content, Error::InvalidIdentity)
+    }
+}
+
+/// Passphrase of the protected key file `path`, from `DOBY_KEY_PASSPHRASE` or prompted for.
+pub fn passphrase(path: &Path) -> Result<Zeroizing<String>, Error> {
+    match env::var("DOBY_KEY_PASSPHRASE") {
+        Ok(passphrase) => Ok(Zeroizing::new(passphrase)),
+        Err(_) => {
+            let passphrase = WrappedPassword::default().get_with_prompt(&format!("Passphrase of {}", path.display()), false)?;
+            Ok(Zeroizing::new(passphrase.to_string()))
+        }
+    }
+}
+
+/// Reads the key file or stored key `path_or_name` (see `resolve`), asking for the passphrase of protected keys.
+pub fn read(path_or_name: &str) -> Result<Zeroizing<String>, Error> {
+    let path = resolve(path_or_name);
+    read_key_file(&path, || passphrase(&path))
+}
+
+/// Reads the stored key `name`, asking for its passphrase if it is protected. Unlike `read`, `name` can't be a path.
+pub fn read_stored(name: &str) -> Result<Zeroizing<String>, Error> {
+    let path = key_path(name)?;
+    if !path.is_file() {
+        return Err(Error::KeyNotFound(name.to_string()));
+    }
+    read_key_file(&path, || passphrase(&path))
+}
+
+/// Encrypts the key file content `content` with `passphrase`, storing the public key or the key ID of `info` in the comment of the header.
+pub fn protect(content: &str, passphrase: &str, info: &KeyInfo) -> Result<String, Error> {
+    let password = nfc_password(passphrase.as_bytes());
+    let (mut params, master_key) = EncryptionParams::with_password(&password, default_argon2_params(), default_cipher())?;
+    if !passphrase.is_ascii() {
+        params.set_nfc_passwords()?;
+    }
+    match (&info.public_key, &info.key_id) {
+        (Some(public_key), _) => params.set_comment(&format!("{}{}", PUBLIC_KEY_COMMENT, public_key))?,
+        (None, Some(key_id)) => params.set_comment(&format!("{}{}", KEY_ID_COMMENT, format_key_id(key_id)))?,
+        (None, None) => {}
+    }
+    let cipher = DobyCipher::with_master_key(&master_key, &params);
+    drop(master_key);
+    let mut writer = ArmorWriter::new(Vec::new());
+    encrypt_slice(content.as_bytes(), &mut writer, &params, cipher?, DEFAULT_BLOCK_SIZE, None)?;
+    //armor is ASCII
+    Ok(String::from_utf8(writer.finish()?).unwrap())
+}
+
+/// Decrypts a key file protected by `protect`.
+pub fn unprotect(content: &[u8], passphrase: &str) -> Result<Zeroizing<String>, Error> {
+    let mut reader = ArmorReader::new(content);
+    let params = read_header(&mut reader)?;
+    let cipher = DobyCipher::try_new(passphrase.as_bytes(), &params)?;
+    let mut plaintext = Zeroizing::new(Vec::new());
+    decrypt(&mut reader, &mut *plaintext, cipher, DEFAULT_BLOCK_SIZE)?;
+    into_string(plaintext, Error::InvalidIdentity)
+}
+
+fn first_key(content: &str) -> Result<&str, Error> {
+    content.lines().map(str::trim).find(|line| !line.is_empty() && !line.starts_with('#')).ok_or(Error::InvalidIdentity)
+}
+
+/// Symmetric key file content of a new random key: the key ID as a comment followed by the key.
+pub fn generate_symmetric() -> Zeroizing<String> {
+    let mut key = Zeroizing::new([0; KEY_LEN]);
+    OsRng.fill(&mut *key);
+    Zeroizing::new(format!(
+        "# {}{}\n{}{}\n",
+        KEY_ID_COMMENT,
+        format_key_id(&key_id(&*key)),
+        SYMMETRIC_KEY_PREFIX,
+        base64::encode_config(key.as_slice(), base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Key of a symmetric key file.
+pub fn parse_symmetric(content: &str) -> Result<Zeroizing<[u8; KEY_LEN]>, Error> {
+    let decoded = Zeroizing::new(
+        first_key(content)?
+            .strip_prefix(SYMMETRIC_KEY_PREFIX)
+            .and_then(|encoded| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok())
+            .ok_or(Error::InvalidRawKey)?
+    );
+    Ok(Zeroizing::new(decoded.as_slice().try_into().map_err(|_| Error::InvalidRawKey)?))
+}
+
+/// Public information of an unprotected key file.
+pub fn parse_key(content: &str) -> Result<KeyInfo, Error> {
+    let first = first_key(content)?;
+    let (kind, public_key, key_id) = if first.starts_with(SIGNING_KEY_PREFIX) {
+        (KeyKind::Signing, Some(SigningKey::parse(first)?.verify_key().to_string()), None)
+    } else if first.starts_with(SYMMETRIC_KEY_PREFIX) {
+        (KeyKind::Symmetric, None, Some(key_id(&*parse_symmetric(first)?)))
+    } else {
+        let recipient = Identity::parse(first)?.recipient();
+        (KeyKind::Identity, recipient.as_ref().map(Recipient::to_string), recipient.and_then(|recipient| recipient.key_id()))
+    };
+    Ok(KeyInfo { kind, public_key, key_id, protected: false })
+}
+
+/// Public information of the key file content `content`. The secret key of protected files isn't checked.
+pub fn key_info(content: &[u8]) -> Result<KeyInfo, Error> {
+    if is_armored(content) {
+        let params = read_header(&mut ArmorReader::new(content))?;
+        let comment = params.comment().unwrap_or_default();
+        let info = if let Some(key_id) = comment.strip_prefix(KEY_ID_COMMENT) {
+            KeyInfo { kind: KeyKind::Symmetric, public_key: None, key_id: decode_hex(key_id).map(|key_id| *key_id), protected: true }
+        } else {
+            let public_key = comment.strip_prefix(PUBLIC_KEY_COMMENT).map(String::from);
+            match public_key {
+                Some(public_key) if public_key.starts_with(VERIFY_KEY_PREFIX) => KeyInfo { kind: KeyKind::Signing, public_key: Some(public_key), key_id: None, protected: true },
+                public_key => {
+                    let key_id = public_key.as_deref().and_then(|public_key| Recipient::parse(public_key).ok()).and_then(|recipient| recipient.key_id());
+                    KeyInfo { kind: KeyKind::Identity, public_key, key_id, protected: true }
+                }
+            }
+        };
+        Ok(info)
+    } else {
+        let content = into_string(Zeroizing::new(content.to_vec()), Error::InvalidIdentity)?;
+        parse_key(&content)
+    }
+}
+
+/// Public key of the stored key `name` if there is one of this kind, so that it can be given by name to `--recipient` and `--verify-key`.
+pub fn public_key(name: &str, kind: KeyKind) -> Option<String> {
+    let content = Zeroizing::new(fs::read(key_path(name).ok()?).ok()?);
+    key_info(&content).ok().filter(|info| info.kind == kind).and_then(|info| info.public_key)
+}
+
+/// Name of a stored key, along with its information or the error preventing to read it.
+pub type StoredKey = (String, Result<KeyInfo, Error>);
+
+/// Stored keys sorted by name. Empty if the key directory doesn't exist.
+pub fn list() -> Result<Vec<StoredKey>, Error> {
+    let dir = keys_dir().ok_or(Error::Usage("the key directory can't be found: neither XDG_CONFIG_HOME nor HOME is set"))?;
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(Error::Path { path: dir.display().to_string(), error }),
+    };
+    let mut keys = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(String::from) else { continue };
+        if check_name(&name).is_err() || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let info = fs::read(entry.path())
+            .map_err(|error| Error::Path { path: entry.path().display().to_string(), error })
+            .and_then(|content| {
+                let content = Zeroizing::new(content);
+                key_info(&content)
+            });
+        keys.push((name, info));
+    }
+    keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(keys)
+}
+
+/// Stored identities or symmetric keys along with their key ID, to find those a file was encrypted for, or all of them if `kind` is `None`. Unprotected keys come first, so that they are tried before asking for a passphrase. Keys that can't be read are skipped.
+pub fn key_ids(kind: Option<KeyKind>) -> Vec<([u8; KEY_ID_LEN], String)> {
+    let mut keys: Vec<(bool, [u8; KEY_ID_LEN], String)> = list().unwrap_or_default().into_iter().filter_map(|(name, info)| {
+        let info = info.ok().filter(|info| kind.is_none_or(|kind| info.kind == kind))?;
+        Some((info.protected, info.key_id?, name))
+    }).collect();
+    keys.sort_by_key(|(protected, _, _)| *protected);
+    keys.into_iter().map(|(_, key_id, name)| (key_id, name)).collect()
+}
+
+/// Writes the key file `name`, creating the key directory if needed. Existing keys are never overwritten.
+pub fn store(name: &str, content: &[u8]) -> Result<PathBuf, Error> {
+    let path = key_path(name)?;
+    let dir = path.parent().unwrap();
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir).map_err(|error| Error::Path { path: dir.display().to_string(), error })?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let result = options.open(&path).and_then(|mut file| {
+        io::Write::write_all(&mut file, content)?;
+        file.sync_all()
+    });
+    match result {
+        Ok(()) => Ok(path),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(Error::KeyExists(name.to_string())),
+        Err(error) => Err(Error::Path { path: path.display().to_string(), error }),
+    }
+}
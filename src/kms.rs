@@ -0,0 +1,60 @@
+use std::{io::Write, process::{Command, Stdio}};
+use zeroize::Zeroize;
+use crate::{Error, crypto::KEY_LEN};
+
+//bound to every data key: decryption fails without it, and it shows up in the KMS audit logs
+const ENCRYPTION_CONTEXT: &str = "application=doby";
+
+/// Runs an AWS KMS request with `aws` (AWS CLI v2), which reads the credentials and region from its usual configuration, and returns its text output.
+fn aws_kms(args: &[&str]) -> Result<Vec<u8>, Error> {
+    let output = Command::new("aws")
+        .arg("kms")
+        .args(args)
+        .arg("--encryption-context")
+        .arg(ENCRYPTION_CONTEXT)
+        .arg("--output")
+        .arg("text")
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| Error::Kms(format!("can't run aws: {}", e)))?;
+    if !output.status.success() {
+        return Err(Error::Kms(format!("aws {}", output.status)));
+    }
+    Ok(output.stdout)
+}
+
+fn decode_key(encoded: &str) -> Result<[u8; KEY_LEN], Error> {
+    let mut key = base64::decode(encoded).map_err(|_| Error::Kms(String::from("invalid response")))?;
+    let result = key.as_slice().try_into().map_err(|_| Error::Kms(String::from("invalid data key length")));
+    key.zeroize();
+    result
+}
+
+/// Asks the KMS for a new data key protected by `key_id` (key ID, ARN or alias). Returns the data key, to be used as master key, and the encrypted blob to store in the header.
+pub fn generate_data_key(key_id: &str) -> Result<([u8; KEY_LEN], Vec<u8>), Error> {
+    let number_of_bytes = KEY_LEN.to_string();
+    let mut output = aws_kms(&["generate-data-key", "--key-id", key_id, "--number-of-bytes", &number_of_bytes, "--query", "[Plaintext,CiphertextBlob]"])?;
+    let result = match std::str::from_utf8(&output).map(|s| s.split_whitespace().collect::<Vec<&str>>()) {
+        Ok(fields) if fields.len() == 2 => decode_key(fields[0]).and_then(|data_key| {
+            let blob = base64::decode(fields[1]).map_err(|_| Error::Kms(String::from("invalid response")))?;
+            Ok((data_key, blob))
+        }),
+        _ => Err(Error::Kms(String::from("invalid response"))),
+    };
+    output.zeroize();
+    result
+}
+
+/// Asks the KMS to decrypt a blob returned by `generate_data_key`. The KMS key is identified by the blob itself.
+pub fn decrypt_data_key(blob: &[u8]) -> Result<[u8; KEY_LEN], Error> {
+    //the blob isn't secret, it can go through a temporary file
+    let mut input = tempfile::NamedTempFile::new()?;
+    input.write_all(blob)?;
+    let mut output = aws_kms(&["decrypt", "--ciphertext-blob", &format!("fileb://{}", input.path().display()), "--query", "Plaintext"])?;
+    let result = std::str::from_utf8(&output)
+        .map_err(|_| Error::Kms(String::from("invalid response")))
+        .and_then(|encoded| decode_key(encoded.trim()));
+    output.zeroize();
+    result
+}
@@ -1,80 +1,245 @@
-pub mod cli;
 pub mod crypto;
+pub mod memlock;
+#[cfg(feature = "openssl")]
+pub mod openssl;
+#[cfg(feature = "pake")]
+pub mod pake;
+#[cfg(feature = "recipients")]
+pub mod plugin;
+pub mod progress;
+pub mod qr;
+#[cfg(feature = "recipients")]
+pub mod recipient;
+#[cfg(feature = "signatures")]
+pub mod signature;
+pub mod test_vectors;
+mod archive;
+#[cfg(feature = "armor")]
+mod armor;
+mod builder;
+mod container;
+mod digest;
+#[cfg(feature = "ecc")]
+mod ecc;
+mod error;
+mod metadata;
+mod name_template;
+mod pipeline;
+mod rate_limit;
+mod stream;
+mod tar;
+#[cfg(feature = "cli")]
+pub mod bench;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "cli")]
+mod config;
+#[cfg(feature = "cli")]
+pub mod files;
+#[cfg(feature = "cli")]
+pub mod keying;
+#[cfg(feature = "cli")]
+pub mod keys;
+#[cfg(feature = "cli")]
+pub mod report;
+#[cfg(feature = "cli")]
+pub mod sandbox;
+#[cfg(feature = "cli")]
+pub mod transfer;
+#[cfg(feature = "os")]
+pub mod pkcs11;
+#[cfg(feature = "os")]
+pub mod ssh_agent;
+#[cfg(feature = "os")]
+pub mod tpm;
+#[cfg(all(feature = "os", unix))]
+pub mod serve;
+#[cfg(feature = "os")]
+pub mod child;
+#[cfg(feature = "os")]
+pub mod edit;
+#[cfg(all(feature = "os", unix))]
+pub mod clipboard;
+#[cfg(feature = "os")]
+mod header;
+#[cfg(feature = "os")]
+mod inspect;
+#[cfg(feature = "os")]
+mod mirror;
+#[cfg(feature = "os")]
+mod os;
+#[cfg(feature = "os")]
+mod resume;
+#[cfg(feature = "os")]
+mod sparse;
+#[cfg(feature = "os")]
+mod watch;
+#[cfg(feature = "async")]
+mod async_api;
+#[cfg(feature = "yubikey")]
+pub mod yubikey;
+#[cfg(feature = "kms")]
+pub mod kms;
+#[cfg(feature = "remote")]
+pub mod remote;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
-use std::{fmt::Display, fs::OpenOptions, io::{self, BufWriter, Read, Write}, path::Path};
-use crypto::{DobyCipher, EncryptionParams};
-use zeroize::Zeroize;
-
-pub const MAGIC_BYTES: &[u8; 4] = b"DOBY";
-
-pub struct WrappedPassword(Option<String>);
-
-impl WrappedPassword {
-    pub fn get(self, ask_confirm: bool) -> Option<String> {
-        self.0.or_else(|| {
-            let mut password = rpassword::read_password_from_tty(Some("Password: ")).ok()?;
-            if ask_confirm {
-                let mut password_confirm = rpassword::read_password_from_tty(Some("Password (confirm): ")).ok()?;
-                if password == password_confirm {
-                    password_confirm.zeroize();
-                    Some(password)
-                } else {
-                    password.zeroize();
-                    password_confirm.zeroize();
-                    eprintln!("Error: passwords don't match");
-                    None
-                }
-            } else {
-                Some(password)
-            }
-        })
-    }
+pub use archive::{ArchiveReader, extract_archive};
+pub use builder::{Decryptor, DecryptorBuilder, Encryptor, EncryptorBuilder};
+pub use container::{CONTAINER_MAGIC, Container, ContainerEntry, ContainerWriter, entry_name, is_container};
+pub use digest::{DigestCheckWriter, PLAINTEXT_DIGEST_LEN, PlaintextDigest};
+pub use error::Error;
+pub use metadata::{Metadata, MetadataWriter};
+pub use name_template::NameTemplate;
+pub use pipeline::{encrypt_pipelined, decrypt_pipelined};
+pub use progress::ProgressReader;
+pub use rate_limit::{RateLimitedReader, RateLimiter};
+pub use stream::{EncryptWriter, DecryptReader};
+pub use tar::{TAR_BLOCK_SIZE, TarCheckReader, TarCheckWriter, is_tar_header};
+#[cfg(feature = "armor")]
+pub use armor::{ArmorReader, ArmorWriter, is_armored};
+#[cfg(feature = "ecc")]
+pub use ecc::{DATA_SHARDS, DEFAULT_PARITY_SHARDS, EccReader, EccWriter, MAX_PARITY_SHARDS, is_ecc};
+#[cfg(feature = "os")]
+pub use builder::{decrypt_file, encrypt_file};
+#[cfg(feature = "os")]
+pub use ecc::repair;
+#[cfg(feature = "os")]
+pub use header::{backup_header, read_raw_header, restore_header};
+#[cfg(feature = "os")]
+pub use inspect::{Inspection, inspect};
+#[cfg(feature = "os")]
+pub use mirror::{MIRROR_FILE, MirrorEntry, MirrorManifest, encrypted_path, mirror_files};
+#[cfg(feature = "cli")]
+pub use mirror::{Mirrored, restore_mirror, update_mirror};
+#[cfg(feature = "os")]
+pub use os::{DEFAULT_BLOCK_SIZE, OutputWriter, WrappedReader, WrappedWriter, auto_block_size, default_cipher, is_same_file, is_url, remove_temporary_outputs, shred, spool, temporary_path};
+#[cfg(feature = "os")]
+pub use resume::{ResumeState, ResumeWriter};
+#[cfg(feature = "os")]
+pub use sparse::{SparseReader, SparseWriter, has_holes};
+#[cfg(feature = "os")]
+pub use watch::DirWatcher;
+#[cfg(feature = "cli")]
+pub use cli::WrappedPassword;
+#[cfg(feature = "async")]
+pub use async_api::{encrypt_async, decrypt_async, read_header_async};
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use log::debug;
+use zeroize::Zeroizing;
+use crypto::{AEAD_CHUNK_SIZE, DecryptionReport, DobyCipher, EncryptionParams, KeyDerivation};
+#[cfg(feature = "signatures")]
+use signature::{SignatureReader, VerifyKey};
+
+pub const MAGIC_BYTES: &[u8; 4] = b"doby";
+//files written before the format was versioned
+pub const LEGACY_MAGIC_BYTES: &[u8; 4] = b"DOBY";
+
+pub fn is_doby_format(magic_bytes: &[u8]) -> bool {
+    magic_bytes == MAGIC_BYTES || magic_bytes == LEGACY_MAGIC_BYTES
+}
+
+/// Reads the encryption parameters following the magic bytes.
+pub fn read_params<R: Read>(magic_bytes: &[u8], reader: &mut R) -> Result<EncryptionParams, Error> {
+    let params = if magic_bytes == MAGIC_BYTES {
+        EncryptionParams::read(reader)
+    } else if magic_bytes == LEGACY_MAGIC_BYTES {
+        EncryptionParams::read_legacy(reader)
+    } else {
+        Err(Error::UnknownFormat)
+    }?;
+    debug_header(&params);
+    Ok(params)
 }
 
-impl From<Option<&str>> for WrappedPassword {
-    fn from(s: Option<&str>) -> Self {
-        Self(s.map(String::from))
+fn debug_header(params: &EncryptionParams) {
+    if !log::log_enabled!(log::Level::Debug) {
+        return;
+    }
+    let mut encoded = MAGIC_BYTES.to_vec();
+    if params.write(&mut encoded).is_err() {
+        return;
+    }
+    let key_derivation = match &params.key_derivation {
+        KeyDerivation::Password(argon2) => format!("password (Argon2id t={} m={}KiB p={})", argon2.t_cost(), argon2.m_cost(), argon2.p_cost()),
+        KeyDerivation::KeySlots(key_slots) => format!("{} key slots", key_slots.len()),
+        KeyDerivation::RawKey => String::from("raw key"),
+    };
+    if params.cipher.is_aead() {
+        debug!("header: {} bytes, format version {}, {}, {}", encoded.len(), params.version(), params.cipher, key_derivation);
+    } else {
+        debug!("header: {} bytes, format version {}, {} with {}, {}", encoded.len(), params.version(), params.cipher, params.mac, key_derivation);
     }
 }
-pub enum WrappedWriter<P: AsRef<Path>> {
-    PATH {
-        path: P
-    },
-    WRITER {
-        writer: Box<dyn Write>
+
+/// Logs how much data went through the cipher: a block per read of `block_size` bytes, split into authenticated chunks by AEAD ciphers.
+fn debug_blocks(operation: &str, bytes: u64, blocks: u64, block_size: usize, chunked: bool) {
+    if chunked {
+        debug!("{} {} bytes in {} blocks of up to {} bytes, {} AEAD chunks", operation, bytes, blocks, block_size, bytes.div_ceil(AEAD_CHUNK_SIZE as u64).max(1));
+    } else {
+        debug!("{} {} bytes in {} blocks of up to {} bytes", operation, bytes, blocks, block_size);
     }
 }
 
-impl<P: AsRef<Path> + Display> WrappedWriter<P> {
-    fn from_path(path: P) -> Self {
-        Self::PATH { path }
+/// Reads the magic bytes and the encryption parameters.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<EncryptionParams, Error> {
+    let mut magic_bytes = [0; MAGIC_BYTES.len()];
+    reader.read_exact(&mut magic_bytes)?;
+    read_params(&magic_bytes, reader)
+}
+
+/// Writes the first `header_len` bytes to `header` and the rest to `writer`, so that the header of a ciphertext can be stored separately.
+pub struct HeaderSplitter<H: Write, W: Write> {
+    header: H,
+    writer: W,
+    remaining: usize,
+}
+
+impl<H: Write, W: Write> HeaderSplitter<H, W> {
+    /// `header_len` must be the length of the magic bytes followed by the encoded parameters.
+    pub fn new(header: H, writer: W, header_len: usize) -> Self {
+        Self { header, writer, remaining: header_len }
     }
 
-    fn from_writer<T: 'static + Write>(writer: T) -> Self {
-        Self::WRITER { writer: Box::new(writer) }
+    pub fn into_inner(self) -> (H, W) {
+        (self.header, self.writer)
     }
+}
 
-    pub fn into_buf_writer(self) -> Option<BufWriter<Box<dyn Write>>> {
-        Some(BufWriter::new(match self {
-            Self::PATH { path } => Box::new(
-                OpenOptions::new().write(true).create(true).truncate(true).open(path.as_ref())
-                    .map_err(|e| eprintln!("{}: {}", path, e))
-                    .ok()?
-            ) as Box<dyn Write>,
-            Self::WRITER { writer } => writer,
-        }))
+impl<H: Write, W: Write> Write for HeaderSplitter<H, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return self.writer.write(buf);
+        }
+        let n = self.header.write(&buf[..buf.len().min(self.remaining)])?;
+        self.remaining -= n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.header.flush()?;
+        self.writer.flush()
     }
 }
 
-pub fn encrypt<R: Read, W: Write>(reader: &mut R, writer: &mut W, params: &EncryptionParams, mut cipher: DobyCipher, block_size: usize, already_read: Option<&[u8]>) -> io::Result<()> {
+pub fn encrypt<R: Read, W: Write>(reader: &mut R, writer: &mut W, params: &EncryptionParams, mut cipher: DobyCipher, block_size: usize, already_read: Option<&[u8]>) -> Result<(), Error> {
+    debug_header(params);
     writer.write_all(MAGIC_BYTES)?;
     params.write(writer)?;
-    let mut buff = vec![0; block_size];
+    //the first block must hold what was already read plus at least one byte, so that reading 0 bytes after it means EOF
+    let mut buff = vec![0; block_size.max(already_read.map_or(0, |b| b.len()+1))];
     let mut n = 1;
+    let (mut bytes, mut blocks) = (0, 0);
     if let Some(already_read) = already_read {
-        buff[..already_read.len()].clone_from_slice(&already_read);
+        buff[..already_read.len()].clone_from_slice(already_read);
         n = reader.read(&mut buff[already_read.len()..])?;
         cipher.encrypt_chunk(&mut buff[..n+already_read.len()], writer)?;
+        bytes += (n+already_read.len()) as u64;
+        blocks += 1;
     }
     if n > 0 {
         loop {
@@ -83,22 +248,222 @@ pub fn encrypt<R: Read, W: Write>(reader: &mut R, writer: &mut W, params: &Encry
                 break;
             } else {
                 cipher.encrypt_chunk(&mut buff[..n], writer)?;
+                bytes += n as u64;
+                blocks += 1;
             }
         }
     }
+    debug_blocks("encrypted", bytes, blocks, block_size, cipher.is_chunked());
     cipher.write_hmac(writer)?;
     Ok(())
 }
 
-pub fn decrypt<R: Read, W: Write>(reader: &mut R, writer: &mut W, mut cipher: DobyCipher, block_size: usize) -> io::Result<bool> {
+pub fn decrypt<R: Read, W: Write>(reader: &mut R, writer: &mut W, mut cipher: DobyCipher, block_size: usize) -> Result<(), Error> {
+    decrypt_chunks(reader, writer, &mut cipher, block_size)?;
+    cipher.verify()
+}
+
+/// Same as `decrypt`, but returns the `DecryptionReport` of the whole ciphertext instead of failing if it isn't authentic, e.g. to log the MACs of altered files. Only I/O errors are returned. The plaintext must be discarded unless `verified` is set.
+pub fn decrypt_with_report<R: Read, W: Write>(reader: &mut R, writer: &mut W, mut cipher: DobyCipher, block_size: usize) -> Result<DecryptionReport, Error> {
+    decrypt_chunks(reader, writer, &mut cipher, block_size)?;
+    Ok(cipher.report())
+}
+
+fn decrypt_chunks<R: Read, W: Write>(reader: &mut R, writer: &mut W, cipher: &mut DobyCipher, block_size: usize) -> Result<(), Error> {
     let mut buff = vec![0; block_size];
+    let (mut bytes, mut blocks) = (0, 0);
     loop {
         let n = cipher.decrypt_chunk(reader, &mut buff)?;
         if n == 0 {
             break;
         } else {
            writer.write_all(&buff[..n])?;
+           bytes += n as u64;
+           blocks += 1;
+        }
+    }
+    debug_blocks("decrypted", bytes, blocks, block_size, cipher.is_chunked());
+    Ok(())
+}
+
+/// Same as `encrypt`, but takes the whole plaintext, `block_size` bytes at a time, instead of reading it, e.g. from a memory map of the input (see `WrappedReader::map`). This saves the read calls and, with AEAD ciphers, a copy per block: stream ciphers encrypt in place, so they still copy each block first (which is why `--mmap` requires an AEAD cipher).
+pub fn encrypt_slice<W: Write>(plaintext: &[u8], writer: &mut W, params: &EncryptionParams, cipher: DobyCipher, block_size: usize, already_read: Option<&[u8]>) -> Result<(), Error> {
+    encrypt_slice_with_progress(plaintext, writer, params, cipher, block_size, already_read, |_| {})
+}
+
+/// Same as `encrypt_slice` but calls `progress` with the number of plaintext bytes processed so far.
+pub fn encrypt_slice_with_progress<W: Write, F: FnMut(u64)>(plaintext: &[u8], writer: &mut W, params: &EncryptionParams, mut cipher: DobyCipher, block_size: usize, already_read: Option<&[u8]>, mut progress: F) -> Result<(), Error> {
+    debug_header(params);
+    writer.write_all(MAGIC_BYTES)?;
+    params.write(writer)?;
+    let (mut bytes, mut blocks) = (0, 0);
+    if let Some(already_read) = already_read {
+        cipher.encrypt_slice(already_read, writer)?;
+        bytes += already_read.len() as u64;
+    }
+    for block in plaintext.chunks(block_size) {
+        cipher.encrypt_slice(block, writer)?;
+        bytes += block.len() as u64;
+        blocks += 1;
+        progress(bytes);
+    }
+    debug_blocks("encrypted", bytes, blocks, block_size, cipher.is_chunked());
+    cipher.write_hmac(writer)?;
+    Ok(())
+}
+
+/// Same as `encrypt` but calls `progress` with the number of plaintext bytes processed so far.
+pub fn encrypt_with_progress<R: Read, W: Write, F: FnMut(u64)>(reader: &mut R, writer: &mut W, params: &EncryptionParams, cipher: DobyCipher, block_size: usize, already_read: Option<&[u8]>, progress: F) -> Result<(), Error> {
+    let mut reader = ProgressReader::starting_at(reader, already_read.map(|b| b.len() as u64).unwrap_or(0), progress);
+    encrypt(&mut reader, writer, params, cipher, block_size, already_read)
+}
+
+/// Same as `decrypt` but calls `progress` with the number of ciphertext bytes processed so far.
+pub fn decrypt_with_progress<R: Read, W: Write, F: FnMut(u64)>(reader: &mut R, writer: &mut W, cipher: DobyCipher, block_size: usize, progress: F) -> Result<(), Error> {
+    decrypt(&mut ProgressReader::new(reader, progress), writer, cipher, block_size)
+}
+
+/// Decrypts a ciphertext and encrypts the plaintext again with `new_cipher` in a single pass, without storing the plaintext anywhere.
+///
+/// `reader` must be positioned right after the header `old_cipher` was created from. An authentication failure is only detected at the end, so the output must be discarded if an error is returned.
+pub fn rekey<R: Read, W: Write>(reader: &mut R, writer: &mut W, old_cipher: DobyCipher, new_params: &EncryptionParams, new_cipher: DobyCipher, block_size: usize) -> Result<(), Error> {
+    encrypt(&mut DecryptReader::with_cipher(reader, old_cipher), writer, new_params, new_cipher, block_size, None)
+}
+
+/// Checks the ciphertext authenticity without producing any plaintext.
+pub fn verify<R: Read>(reader: &mut R, cipher: DobyCipher, block_size: usize) -> Result<(), Error> {
+    decrypt(reader, &mut io::sink(), cipher, block_size)
+}
+
+/// First pass of a fail-closed decryption: authenticates the rest of the ciphertext and, if it is signed, its signature (see `SignatureReader`), without producing any plaintext. `reader` is then rewound to where it was, ready to be decrypted.
+#[cfg(feature = "signatures")]
+pub fn verify_first<R: Read + Seek>(reader: &mut R, params: &EncryptionParams, cipher: &DobyCipher, block_size: usize, verify_key: Option<&VerifyKey>) -> Result<(), Error> {
+    let start = reader.stream_position()?;
+    let mut signature_reader = SignatureReader::new(&mut *reader, params, verify_key)?;
+    verify(&mut signature_reader, cipher.clone(), block_size)?;
+    signature_reader.finish()?;
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(())
+}
+
+/// Verifies the whole ciphertext with `verify_first`, then rewinds the reader and decrypts it. Nothing is written if the verification fails.
+#[cfg(feature = "signatures")]
+pub fn decrypt_verify_first<R: Read + Seek, W: Write>(reader: &mut R, writer: &mut W, params: &EncryptionParams, cipher: DobyCipher, block_size: usize, verify_key: Option<&VerifyKey>) -> Result<(), Error> {
+    verify_first(reader, params, &cipher, block_size, verify_key)?;
+    //the signature is held back from the ciphertext
+    let mut reader = SignatureReader::new(reader, params, verify_key)?;
+    decrypt(&mut reader, writer, cipher, block_size)?;
+    reader.finish()
+}
+
+/// Same as `decrypt_verify_first` for inputs that can't seek, such as pipes: the rest of the ciphertext is copied to an anonymous temporary file first.
+#[cfg(feature = "os")]
+pub fn decrypt_verify_first_spooled<R: Read, W: Write>(reader: &mut R, writer: &mut W, params: &EncryptionParams, cipher: DobyCipher, block_size: usize, verify_key: Option<&VerifyKey>) -> Result<(), Error> {
+    let mut spooled = io::BufReader::new(spool(reader)?);
+    decrypt_verify_first(&mut spooled, writer, params, cipher, block_size, verify_key)
+}
+
+/// Ciphertext of `reader` up to `end`, leaving out the signature of signed files, that also keeps a copy of the bytes read at the positions of `captures`.
+struct RangeReader<R> {
+    reader: R,
+    position: u64,
+    end: u64,
+    //position, length and copy of the bytes read
+    captures: Vec<(u64, usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> RangeReader<R> {
+    /// `reader` must be positioned right after the header of a file with the parameters `params`.
+    fn new(mut reader: R, params: &EncryptionParams) -> io::Result<Self> {
+        let position = reader.stream_position()?;
+        let signature_len = if params.signer().is_some() { crypto::SIGNATURE_LEN } else { 0 };
+        let end = reader.seek(SeekFrom::End(0))?.saturating_sub(signature_len as u64).max(position);
+        reader.seek(SeekFrom::Start(position))?;
+        Ok(Self { reader, position, end, captures: Vec::new() })
+    }
+
+    /// Keeps a copy of the `len` bytes at `position` once they are read.
+    fn capture(&mut self, position: u64, len: usize) {
+        self.captures.push((position, len, Vec::with_capacity(len)));
+    }
+}
+
+impl<R: Read> Read for RangeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.end.saturating_sub(self.position).min(buf.len() as u64) as usize;
+        let n = self.reader.read(&mut buf[..max])?;
+        for (start, len, captured) in &mut self.captures {
+            let from = self.position.max(*start);
+            let to = (self.position + n as u64).min(*start + *len as u64);
+            if from < to && from == *start + captured.len() as u64 {
+                captured.extend_from_slice(&buf[(from - self.position) as usize..(to - self.position) as usize]);
+            }
         }
+        self.position += n as u64;
+        Ok(n)
     }
-    Ok(cipher.verify_hmac())
-}
\ No newline at end of file
+}
+
+impl<R: Seek> Seek for RangeReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.end.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        self.position = self.reader.seek(SeekFrom::Start(target))?;
+        Ok(self.position)
+    }
+}
+
+/// Decrypts `len` bytes of plaintext starting at `offset`, without decrypting the rest of the ciphertext, and returns the number of bytes written: less than `len` if the plaintext ends before.
+///
+/// `reader` must be positioned right after the header `params` were read from and `cipher` created from. `offset` is counted from the start of the content, after the metadata stored with it if any, and the signature of signed files is left out (but not verified: use `SignatureReader` for that). With chunked (AEAD) ciphers, only the chunks overlapping the range are read and authenticated. The HMAC of stream ciphers covers the whole ciphertext, so it is verified over all of it first, while the ciphertext of the range is kept in memory: the range is then decrypted from this copy, by moving to `offset` in the keystream, so that a file modified in the meantime can't make it decrypt something that wasn't authenticated. Nothing is written if the authentication fails.
+pub fn decrypt_range<R: Read + Seek, W: Write>(reader: &mut R, writer: &mut W, params: &EncryptionParams, mut cipher: DobyCipher, offset: u64, len: u64, block_size: usize) -> Result<u64, Error> {
+    let mut reader = RangeReader::new(reader, params)?;
+    if cipher.is_chunked() {
+        //chunks are only output once authenticated
+        let mut reader = DecryptReader::with_cipher(reader, cipher);
+        let result = (|| {
+            let mut skip = 0;
+            if params.metadata {
+                let mut prefix = [0; metadata::LEN_SIZE];
+                reader.read_exact(&mut prefix)?;
+                skip = Metadata::encoded_len(prefix) as u64;
+            }
+            reader.seek(SeekFrom::Start(skip.saturating_add(offset)))?;
+            io::copy(&mut reader.take(len), writer)
+        })();
+        return result.map_err(|e| match e.get_ref().and_then(|inner| inner.downcast_ref::<Error>()) {
+            Some(Error::HmacMismatch) => Error::HmacMismatch,
+            _ => e.into(),
+        });
+    }
+    let start = reader.position;
+    let plaintext_len = reader.end.saturating_sub(start + crypto::HMAC_LEN as u64);
+    //the length of the metadata is read before the HMAC is verified, and checked against the authenticated copy after
+    let mut prefix = [0; metadata::LEN_SIZE];
+    let skip = if params.metadata && plaintext_len >= prefix.len() as u64 {
+        reader.read_exact(&mut prefix)?;
+        reader.seek(SeekFrom::Start(start))?;
+        reader.capture(start, prefix.len());
+        let mut decrypted = prefix;
+        cipher.decrypt_unauthenticated_at(0, &mut decrypted)?;
+        Metadata::encoded_len(decrypted) as u64
+    } else {
+        0
+    };
+    let range_start = skip.saturating_add(offset).min(plaintext_len);
+    let len = len.min(plaintext_len - range_start);
+    let len: usize = len.try_into().map_err(|_| Error::Usage("the range doesn't fit in memory"))?;
+    reader.capture(start + range_start, len);
+    verify(&mut reader, cipher.clone(), block_size)?;
+    let mut captures = reader.captures.into_iter().map(|(_, _, captured)| captured);
+    if skip > 0 && captures.next().as_deref() != Some(&prefix[..]) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the ciphertext was modified while being read").into());
+    }
+    let mut plaintext = Zeroizing::new(captures.next().unwrap_or_default());
+    cipher.decrypt_unauthenticated_at(range_start, &mut plaintext)?;
+    writer.write_all(&plaintext)?;
+    Ok(len as u64)
+}
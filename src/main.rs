@@ -1,86 +1,2081 @@
-use std::{process, io::{BufReader, Read}};
+use std::{borrow::Cow, collections::HashSet, fs::{self, File, OpenOptions}, mem, panic, path::{Path, PathBuf}, process, io::{self, BufRead, BufReader, IsTerminal, Read, Seek, SeekFrom, Write}, thread, time::{Duration, Instant, SystemTime}};
 use doby::{
-    cli,
-    crypto::{EncryptionParams, DobyCipher},
+    bench,
+    cli::{self, BatchArgs, BenchArgs, CatArgs, Command, ContainerArgs, EditArgs, EncryptionOptions, GrepArgs, KeySlotOptions, MirrorArgs, Mode, RotateArgs, SaltRng, WatchArgs},
+    ArmorReader,
+    ArmorWriter,
+    Container,
+    ContainerEntry,
+    ContainerWriter,
+    edit::{EditDir, run_editor},
+    files::{CONTAINER_INPUT, EncryptStream, MappedInput, SharedKey, decrypt_file, decrypt_metadata, encrypt_to, encrypt_with_threads, mapped_rate_limit, open_encrypted, rate_limited, restored_path, run_batch, signed},
+    keying::{challenge_response, encryption_password, key_slots, master_key, new_master_key, wrapped_key_params},
+    keys::{self, KeyKind},
+    EccReader,
+    EccWriter,
+    crypto::{EncryptionParams, DobyCipher, KeyDerivation, KeySlot, MasterKey, KEY_LEN, SIGNATURE_EXTENSION, argon2_time, format_key_id, key_id},
+    memlock::{Locked, disable_core_dumps, zeroize_secrets},
+    pkcs11::Pkcs11Key,
+    plugin::discover as discover_plugins,
+    recipient::{Identity, Recipient},
+    ssh_agent::agent_available,
+    tpm::tpm_available,
+    test_vectors::test_vectors,
+    transfer::{receive, send},
+    signature::{SignatureReader, SigningKey, VerifyKey, check_signer},
+    Error,
     MAGIC_BYTES,
-    decrypt,
-    encrypt,
+    Metadata,
+    HeaderSplitter,
+    ProgressReader,
+    ResumeState,
+    ResumeWriter,
+    SparseReader,
+    TarCheckReader,
+    TarCheckWriter,
+    WrappedPassword,
+    WrappedReader,
+    WrappedWriter,
+    auto_block_size,
+    DecryptReader,
+    DirWatcher,
+    PlaintextDigest,
+    backup_header,
+    remove_temporary_outputs,
+    restore_header,
+    extract_archive,
+    entry_name,
+    inspect,
+    is_same_file,
+    is_armored,
+    qr::{QrWriter, is_qr_text, read_scanned},
+    is_container,
+    is_ecc,
+    is_doby_format,
+    openssl::{OpenSslReader, is_openssl},
+    progress::{ProgressBar, format_size},
+    read_params,
+    rekey,
+    restore_mirror,
+    repair,
+    report::{self, Event},
+    shred,
+    spool,
+    temporary_path,
+    update_mirror,
 };
-use zeroize::Zeroize;
-
-fn run() -> bool {
-    let mut success = false;
-    if let Some(result) = cli::parse() {
-        if let Some(cli_args) = result.cli_args {
-            let mut reader = BufReader::new(cli_args.reader);
-
-            let mut magic_bytes = vec![0; MAGIC_BYTES.len()];
-            match reader.read(&mut magic_bytes) {
-                Ok(n) => {
-                    if magic_bytes == MAGIC_BYTES && !cli_args.force_encrypt { //we probably want to decrypt
-                        match EncryptionParams::read(&mut reader) {
-                            Ok(params) => {
-                                if let Some(params) = params {
-                                    if let Some(mut password) = cli_args.password.get(false) {
-                                        if let Some(mut writer) = cli_args.writer.into_buf_writer() {
-                                            let cipher = DobyCipher::new(password.as_bytes(), &params);
-                                            password.zeroize();
-                                            match decrypt(&mut reader, &mut writer, cipher, cli_args.block_size) {
-                                                Ok(verified) => {
-                                                    if verified {
-                                                        success = true
-                                                    } else {
-                                                        eprintln!("Warning: HMAC verification failed !\nEither your password is incorrect or the ciphertext has been corrupted.\nBe careful, the data could have been altered by an attacker.");
-                                                    }
-                                                }
-                                                Err(e) => eprintln!("I/O error while decrypting: {}", e)
-                                            }
-                                        } else {
-                                            password.zeroize();
-                                        }
-                                    }
-                                } else {
-                                    eprintln!("Error: invalid encryption parameters")
-                                }
-                            }
-                            Err(e) => eprintln!("I/O error while reading headers: {}", e)
-                        }
-                    } else { //otherwise, encrypt
-                        let params = EncryptionParams::new(cli_args.argon2_params, cli_args.cipher);
-                        if let Some(mut password) = cli_args.password.get(true) {
-                            if let Some(mut writer) = cli_args.writer.into_buf_writer() {
-                                let cipher = DobyCipher::new(password.as_bytes(), &params);
-                                password.zeroize();
-                                match encrypt(
-                                    &mut reader,
-                                    &mut writer,
-                                    &params,
-                                    cipher,
-                                    cli_args.block_size,
-                                    Some(&magic_bytes[..n])
-                                ) {
-                                    Ok(_) => success = true,
-                                    Err(e) => eprintln!("I/O error while encrypting: {}", e)
-                                }
-                            } else {
-                                password.zeroize();
-                            }
+use log::{debug, error, info, warn};
+use rand::rngs::OsRng;
+use zeroize::{Zeroize, Zeroizing};
+
+#[cfg(unix)]
+use doby::serve::{self, Operation, serve};
+
+//how often the progress of --resume is saved
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Spools INPUT if it can't seek, so that `doby::verify_first` can read it twice.
+fn verify_first(reader: &mut BufReader<WrappedReader>, params: &EncryptionParams, cipher: &DobyCipher, block_size: usize, verify_key: Option<&VerifyKey>) -> Result<(), Error> {
+    if !reader.get_ref().is_seekable() {
+        let spooled = spool(reader)?;
+        *reader = BufReader::new(WrappedReader::from_file(spooled));
+    }
+    doby::verify_first(reader, params, cipher, block_size, verify_key)
+}
+
+/// Hashes the plaintext, `already_read` followed by the rest of `reader`, then rewinds the reader. INPUT must be a regular file, as the plaintext is never copied to disk to be read twice.
+fn plaintext_digest(reader: &mut BufReader<WrappedReader>, already_read: &[u8]) -> Result<PlaintextDigest, Error> {
+    if reader.get_ref().file_metadata().is_none() {
+        return Err(Error::Usage("--digest requires INPUT to be a regular file when encrypting"));
+    }
+    let start = reader.stream_position()?;
+    let digest = PlaintextDigest::compute(&mut already_read.chain(&mut *reader))?;
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(digest)
+}
+
+/// Progress and parameters of the interrupted `--resume` encryption to `output`, if it can continue: INPUT and the options stored in the header must not have changed since. `None` to start over.
+fn interrupted_encryption(output: &str, input: &fs::Metadata, prefix_len: usize, digest: bool) -> Result<Option<(ResumeState, EncryptionParams)>, Error> {
+    let state_path = ResumeState::path(output);
+    let state = match ResumeState::load(&state_path).map_err(|error| Error::Path { path: state_path.display().to_string(), error })? {
+        Some(state) => state,
+        None => return Ok(None),
+    };
+    if !state.matches(input) || state.prefix_len != prefix_len as u64 {
+        warn!("INPUT changed since the encryption was interrupted: starting over");
+        return Ok(None);
+    }
+    let tmp = temporary_path(output, &state.partial);
+    let params = match File::open(&tmp) {
+        Ok(file) => {
+            let partial_len = file.metadata()?.len();
+            let mut reader = BufReader::new(file);
+            let mut magic_bytes = [0; MAGIC_BYTES.len()];
+            reader.read_exact(&mut magic_bytes).map_err(|error| Error::Path { path: tmp.display().to_string(), error })?;
+            let params = read_params(&magic_bytes, &mut reader)?;
+            if reader.stream_position()? != state.header_len || partial_len < state.output_len() {
+                warn!("the partial output doesn't match the saved progress: starting over");
+                return Ok(None);
+            }
+            params
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            warn!("the partial output was removed: starting over");
+            return Ok(None);
+        }
+        Err(error) => return Err(Error::Path { path: tmp.display().to_string(), error }),
+    };
+    if params.metadata != (prefix_len > 0) || params.plaintext_digest().is_some() != digest {
+        warn!("the options changed since the encryption was interrupted: starting over");
+        return Ok(None);
+    }
+    Ok(Some((state, params)))
+}
+
+/// Fails early if INPUT isn't signed by `verify_key`, and warns if it is signed but the signer isn't checked.
+fn check_input_signer(params: &EncryptionParams, verify_key: Option<&VerifyKey>) -> Result<(), Error> {
+    if let (Some(signer), None) = (check_signer(params, verify_key)?, verify_key) {
+        warn!("INPUT is signed by {}, but anyone could have signed it: use --verify-key to check the signer", signer);
+    }
+    Ok(())
+}
+
+fn extract<R: Read>(reader: R, cipher: DobyCipher, metadata: bool, output: WrappedWriter<String>) -> Result<(), Error> {
+    let dest = match output {
+        WrappedWriter::PATH { path } => path,
+        _ => return Err(Error::Usage("an OUTPUT directory is required to decrypt recursively")),
+    };
+    let mut reader = DecryptReader::with_cipher(reader, cipher);
+    if metadata {
+        Metadata::read(&mut reader)?;
+    }
+    extract_archive(&mut reader, &dest).map_err(|error| {
+        match error.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(Error::HmacMismatch) => Error::HmacMismatch,
+            Some(Error::Truncated { expected }) => Error::Truncated { expected: *expected },
+            _ => Error::Path { path: dest.clone(), error },
+        }
+    })
+}
+
+/// Asks for the password of an `openssl enc` input, after warning that it isn't authenticated.
+fn openssl_reader<R: Read>(reader: R, password: WrappedPassword, iterations: u32, prompt: &str) -> Result<OpenSslReader<R>, Error> {
+    warn!("INPUT was encrypted with openssl enc, which doesn't authenticate the ciphertext: alterations can't be detected");
+    let mut password = password.get_with_prompt(prompt, false)?;
+    let reader = OpenSslReader::new(reader, password.as_bytes(), iterations);
+    password.zeroize();
+    reader
+}
+
+/// Derives the cipher from the password, or opens one of the key slots with the identities or the password.
+fn decryption_cipher(params: &EncryptionParams, password: WrappedPassword, identities: &[Identity], pkcs11: Option<&Pkcs11Key>, raw_key: Option<&[u8; KEY_LEN]>, prompt: &str) -> Result<DobyCipher, Error> {
+    match (&params.key_derivation, raw_key) {
+        (KeyDerivation::Password(_), None) => {
+            let mut password = password.get_with_prompt(prompt, false)?;
+            let cipher = if params.yubikey_slot().is_some() {
+                DobyCipher::try_new_with_yubikey(password.as_bytes(), params, challenge_response)
+            } else {
+                DobyCipher::try_new(password.as_bytes(), params)
+            };
+            password.zeroize();
+            cipher
+        }
+        _ => DobyCipher::with_master_key(&*master_key(params, password, identities, pkcs11, raw_key, prompt)?, params),
+    }
+}
+
+/// Calls `f` with the password, then with a new prompt up to `retries` times while the password typed is wrong, like sudo. Passwords that weren't typed at a terminal are never retried.
+fn with_retries<T, F: FnMut(WrappedPassword) -> Result<T, Error>>(password: WrappedPassword, retries: u32, mut f: F) -> Result<T, Error> {
+    let retries = if password.is_provided() || !io::stdin().is_terminal() { 0 } else { retries };
+    let mut result = f(password);
+    for _ in 0..retries {
+        match result {
+            Err(e @ (Error::WrongPassword | Error::WrongPasswordOrYubiKey | Error::NoMatchingKeySlot)) => {
+                warn!("{}, try again", e);
+                result = f(WrappedPassword::default());
+            }
+            _ => break,
+        }
+    }
+    result
+}
+
+/// Stores the IDs of the X25519 recipients, or of the raw key, so that the identity or the key to decrypt with can be found.
+fn set_key_ids(params: &mut EncryptionParams, recipients: &[Recipient], raw_key: Option<&[u8; KEY_LEN]>) -> Result<(), Error> {
+    let key_ids: Vec<_> = match raw_key {
+        Some(raw_key) => vec![key_id(raw_key)],
+        None => recipients.iter().filter_map(Recipient::key_id).collect(),
+    };
+    if key_ids.is_empty() {
+        return Ok(());
+    }
+    params.set_key_ids(&key_ids)
+}
+
+/// Header fields of an output that don't come from the command line options.
+#[derive(Default)]
+struct HeaderOptions<'a> {
+    /// The plaintext will start with `Metadata`.
+    metadata: bool,
+    /// The plaintext, after the `Metadata`, will be encoded by `SparseReader`.
+    sparse: bool,
+    comment: Option<&'a str>,
+    digest: Option<&'a PlaintextDigest>,
+    signer: Option<&'a VerifyKey>,
+}
+
+/// Derives the key from the password, unless several passwords, recipients, SSH keys, a PKCS#11 key, a TPM policy or a KMS key are given: then a random key is wrapped in one key slot for each of them, the KMS generating it if used.
+///
+/// When `options` sets a YubiKey slot, its response is mixed with the password, which must then be the only one.
+fn encryption_cipher(options: &EncryptionOptions, key_slot_options: &KeySlotOptions, raw_key: Option<&[u8; KEY_LEN]>, header: HeaderOptions, password: WrappedPassword, salt_rng: &mut SaltRng, prompt: &str) -> Result<(EncryptionParams, DobyCipher), Error> {
+    let KeySlotOptions { argon2_params, nfc, additional_passwords, recipients, ssh_keys, pkcs11, tpm } = key_slot_options;
+    let EncryptionOptions { cipher, mac, ref kms_key_id, yubikey } = *options;
+    let single_password = raw_key.is_none() && recipients.is_empty() && ssh_keys.is_empty() && pkcs11.is_none() && tpm.is_none() && kms_key_id.is_none() && additional_passwords.is_empty();
+    if yubikey.is_some() && !single_password {
+        return Err(Error::Usage("--yubikey can only be used with a single password"));
+    }
+    let (mut params, master_key) = if let Some(raw_key) = raw_key {
+        (EncryptionParams::with_raw_key_and_rng(cipher, salt_rng), Locked::new(*raw_key))
+    } else if single_password {
+        let mut password = password.get_with_prompt(prompt, true)?;
+        let mut normalized = false;
+        let password_bytes = encryption_password(&password, *nfc, &mut normalized);
+        password.zeroize();
+        let (mut params, master_key) = match yubikey {
+            Some(slot) => EncryptionParams::with_password_and_yubikey(&password_bytes, argon2_params.clone(), cipher, slot, challenge_response),
+            None => EncryptionParams::with_password_and_rng(&password_bytes, argon2_params.clone(), cipher, salt_rng),
+        }?;
+        if normalized {
+            params.set_nfc_passwords()?;
+        }
+        (params, master_key)
+    } else {
+        let (master_key, kms_blob) = new_master_key(kms_key_id.as_deref())?;
+        let (key_slots, normalized) = key_slots(&master_key, key_slot_options, password, kms_blob.is_some(), prompt)?;
+        (wrapped_key_params(key_slots, kms_blob.as_deref(), cipher, normalized, salt_rng)?, master_key)
+    };
+    params.mac = mac;
+    params.metadata = header.metadata;
+    set_key_ids(&mut params, recipients, raw_key)?;
+    if header.sparse {
+        params.set_sparse()?;
+    }
+    if let Some(comment) = header.comment {
+        params.set_comment(comment)?;
+    }
+    if let Some(digest) = header.digest {
+        params.set_plaintext_digest(digest)?;
+    }
+    if let Some(signer) = header.signer {
+        params.set_signer(&signer.to_bytes())?;
+    }
+    let cipher = DobyCipher::with_master_key(&master_key, &params)?;
+    Ok((params, cipher))
+}
+
+/// Encrypts or decrypts each input, possibly several at once. Failures are reported once all the inputs have been processed.
+fn batch(mut args: BatchArgs) -> Result<(), Error> {
+    if let Some(dir) = &args.output_dir {
+        fs::create_dir_all(dir).map_err(|error| Error::Path { path: dir.clone(), error })?;
+    }
+    let results = if args.decrypt {
+        batch_decrypt(&mut args)?
+    } else {
+        batch_encrypt(&mut args)?
+    };
+    let mut failed = 0;
+    for (input, result) in args.inputs.iter().zip(results) {
+        if report::is_json() {
+            let outcome = Outcome {
+                operation: Some(if args.decrypt { "decrypt" } else { "encrypt" }),
+                input: Some(input.clone()),
+                output: args.name_template.output_path(input, args.output_dir.as_deref(), args.decrypt).ok(),
+                bytes: result.as_ref().ok().copied().flatten(),
+                verified: if args.decrypt { Some(true) } else { None },
+                skipped: matches!(result, Ok(None)),
+                ..Default::default()
+            };
+            outcome.report(result.as_ref().err());
+        }
+        if let Err(e) = result {
+            if !report::is_json() {
+                error!("{}: {}", input, e);
+            }
+            failed += 1;
+        }
+    }
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(Error::BatchFailed { failed, total: args.inputs.len(), decrypting: args.decrypt })
+    }
+}
+
+/// Outcome of the encryption, decryption or rekeying of a file, reported with `--json`, along with the measures of `--stats`. It is filled in as it becomes known.
+#[derive(Default)]
+struct Outcome {
+    operation: Option<&'static str>,
+    input: Option<String>,
+    output: Option<String>,
+    /// Bytes written to the output.
+    bytes: Option<u64>,
+    /// Whether the input was authenticated, when decrypting or rekeying.
+    verified: Option<bool>,
+    /// The user refused to overwrite the output.
+    skipped: bool,
+    /// Where the output of a failed decryption was kept (`--keep-unverified`).
+    unverified_output: Option<String>,
+    /// Print the measures below on completion (`--stats`).
+    stats: bool,
+    /// Bytes read from the input.
+    bytes_in: Option<u64>,
+    /// Time spent encrypting, decrypting or rekeying, once the keys were ready.
+    stream_time: Option<Duration>,
+}
+
+impl Outcome {
+    fn report(&self, error: Option<&Error>) {
+        let mut event = Event::new("result")
+            .string("operation", self.operation.unwrap_or_default())
+            .optional_string("input", self.input.as_deref())
+            .optional_string("output", self.output.as_deref());
+        if let Some(bytes) = self.bytes {
+            event = event.number("bytes", bytes);
+        }
+        let verified = match error {
+            Some(Error::HmacMismatch | Error::Truncated { .. } | Error::InvalidSignature) => Some(false),
+            Some(_) => None,
+            None => self.verified,
+        };
+        if let Some(verified) = verified {
+            event = event.boolean("verified", verified);
+        }
+        if let Some(path) = &self.unverified_output {
+            event = event.string("unverified_output", path);
+        }
+        match error {
+            Some(e) => event.string("status", "error").error(e),
+            None if self.skipped => event.string("status", "skipped"),
+            None => event.string("status", "ok"),
+        }.emit();
+    }
+
+    /// Prints the sizes, the time spent deriving the keys versus processing the data, and the throughput of the latter.
+    fn report_stats(&self, wall_time: Duration) {
+        let bytes_in = self.bytes_in.unwrap_or(0);
+        let bytes_out = self.bytes.unwrap_or(0);
+        let kdf_time = argon2_time();
+        let stream_time = self.stream_time.unwrap_or_default();
+        let bytes_per_second = if stream_time.is_zero() { 0. } else { bytes_in as f64 / stream_time.as_secs_f64() };
+        if report::is_json() {
+            Event::new("stats")
+                .number("bytes_in", bytes_in)
+                .number("bytes_out", bytes_out)
+                .number("wall_milliseconds", wall_time.as_millis() as u64)
+                .number("kdf_milliseconds", kdf_time.as_millis() as u64)
+                .number("stream_milliseconds", stream_time.as_millis() as u64)
+                .number("bytes_per_second", bytes_per_second as u64)
+                .emit();
+        } else {
+            info!("Read {}, wrote {} in {:.3}s", format_size(bytes_in), format_size(bytes_out), wall_time.as_secs_f64());
+            info!("Key derivation: {:.3}s, encryption/decryption: {:.3}s ({:.1} MiB/s)", kdf_time.as_secs_f64(), stream_time.as_secs_f64(), bytes_per_second / (1 << 20) as f64);
+        }
+    }
+}
+
+/// Outputs of the inputs of a batch, or `None` if the user refused to overwrite it. They are all determined (and confirmed) before processing any input.
+fn batch_outputs(args: &BatchArgs) -> Vec<Result<Option<WrappedWriter<String>>, Error>> {
+    let mut paths = HashSet::new();
+    args.inputs.iter().map(|input| {
+        let writer = WrappedWriter::derived(input, args.output_dir.as_deref(), &args.name_template, args.decrypt)?;
+        let path = writer.path().unwrap();
+        if !paths.insert(path.to_string()) {
+            return Err(Error::Usage("another input has the same output path"));
+        }
+        cli::check_output(input, path, args.remove_inputs)?;
+        Ok(if cli::confirm_overwrite(path, args.interactive)? {
+            Some(writer)
+        } else {
+            None
+        })
+    }).collect()
+}
+
+fn batch_encrypt(args: &mut BatchArgs) -> Result<Vec<Result<Option<u64>, Error>>, Error> {
+    let key = SharedKey::new(args)?;
+    let args = &*args;
+    Ok(run_batch(&args.inputs, batch_outputs(args), args.jobs, |input, writer| key.encrypt(input, writer, args, None)))
+}
+
+/// Encrypts the files appearing in the watched directory until interrupted. Failures are reported, and the files left in place, without stopping.
+fn watch(mut args: WatchArgs) -> Result<(), Error> {
+    for dir in args.batch.output_dir.iter().chain(&args.move_to) {
+        fs::create_dir_all(dir).map_err(|error| Error::Path { path: dir.clone(), error })?;
+    }
+    let mut watcher = DirWatcher::new(&args.dir).map_err(|error| Error::Path { path: args.dir.clone(), error })?;
+    let key = SharedKey::new(&mut args.batch)?;
+    info!("Watching {}", args.dir);
+    loop {
+        for path in watcher.next_files().map_err(|error| Error::Path { path: args.dir.clone(), error })? {
+            let input = path.display().to_string();
+            let (output, result) = match args.batch.name_template.output_path(&input, args.batch.output_dir.as_deref(), false) {
+                Ok(output) => (Some(output.clone()), encrypt_watched(&path, output, &key, &args)),
+                Err(e) => (None, Err(e)),
+            };
+            if report::is_json() {
+                Outcome {
+                    operation: Some("encrypt"),
+                    input: Some(input),
+                    bytes: result.as_ref().ok().copied().flatten(),
+                    skipped: matches!(result, Ok(None)),
+                    output,
+                    ..Default::default()
+                }.report(result.as_ref().err());
+            } else {
+                match (result, output) {
+                    (Err(e), _) => error!("{}: {}", input, e),
+                    (Ok(Some(_)), Some(output)) => info!("{} encrypted to {}", input, output),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Encrypts a file of the watched directory, then moves it if `--move-to` is given (it is otherwise deleted). Returns the number of bytes written, or `None` if the user refused to overwrite the output.
+fn encrypt_watched(path: &Path, output: String, key: &SharedKey, args: &WatchArgs) -> Result<Option<u64>, Error> {
+    let input = path.display().to_string();
+    cli::check_output(&input, &output, true)?;
+    if !cli::confirm_overwrite(&output, args.batch.interactive)? {
+        return Ok(None);
+    }
+    let written = key.encrypt(&input, WrappedWriter::from_path(output), &args.batch, None)?;
+    if let (Some(dir), Some(name)) = (&args.move_to, path.file_name()) {
+        let moved = Path::new(dir).join(name);
+        fs::rename(path, &moved).map_err(|error| Error::Path { path: moved.display().to_string(), error })?;
+    }
+    Ok(Some(written))
+}
+
+#[cfg(not(unix))]
+fn serve(_args: ServeArgs) -> Result<(), Error> {
+    Err(Error::Usage("serve requires Unix sockets"))
+}
+
+/// Sends INPUT to `doby serve` and writes what it returns to OUTPUT, only once the whole response was received.
+#[cfg(unix)]
+fn client(socket: String, decrypt: bool, input: Option<String>, output: Option<String>) -> Result<(), Error> {
+    let mut data = Zeroizing::new(Vec::new());
+    match &input {
+        Some(path) => File::open(path).and_then(|mut file| file.read_to_end(&mut data)).map_err(|error| Error::Path { path: path.clone(), error })?,
+        None => io::stdin().read_to_end(&mut data)?,
+    };
+    if data.len() > serve::MAX_DATA_LEN {
+        return Err(Error::Usage("INPUT is too large for serve (maximum: 1GiB)"));
+    }
+    let operation = if decrypt { Operation::Decrypt } else { Operation::Encrypt };
+    let response = serve::request(&socket, operation, &data).map_err(|error| Error::Path { path: socket, error })?;
+    let response = Zeroizing::new(response.map_err(Error::Server)?);
+    match output {
+        Some(path) => {
+            let mut writer = WrappedWriter::from_path(path).into_buf_writer()?;
+            writer.write_all(&response)?;
+            writer.finish(false)
+        }
+        None => Ok(io::stdout().write_all(&response)?),
+    }
+}
+
+#[cfg(not(unix))]
+fn client(_socket: String, _decrypt: bool, _input: Option<String>, _output: Option<String>) -> Result<(), Error> {
+    Err(Error::Usage("client requires Unix sockets"))
+}
+
+fn batch_decrypt(args: &mut BatchArgs) -> Result<Vec<Result<Option<u64>, Error>>, Error> {
+    //ask for the password only once, unless identities, stored keys, ssh-agent or the KMS may make it unnecessary
+    let password = if args.raw_key.is_none() && (args.identities.is_empty() && keys::key_ids(None).is_empty() && !agent_available() && !tpm_available() && !cfg!(feature = "kms") || args.password.is_provided()) {
+        Some(mem::take(&mut args.password).get(false)?)
+    } else {
+        None
+    };
+    let args = &*args;
+    Ok(run_batch(&args.inputs, batch_outputs(args), args.jobs, |input, writer| {
+        decrypt_batch_file(input, writer, password.as_ref().map(|password| password.as_str()), args)
+    }))
+}
+
+/// Returns the number of bytes written.
+fn decrypt_batch_file(input: &str, writer: WrappedWriter<String>, password: Option<&str>, args: &BatchArgs) -> Result<u64, Error> {
+    let file = File::open(input)?;
+    let block_size = args.block_size.unwrap_or_else(|| auto_block_size(file.metadata().ok().as_ref()));
+    let (params, reader) = open_encrypted(file)?;
+    args.argon2_limits.check(&params)?;
+    let mut reader = SignatureReader::new(rate_limited(reader, args.rate_limit.as_ref()), &params, args.verify_key.as_ref())?;
+    let cipher = decryption_cipher(&params, password.into(), &args.identities, args.key_slots.pkcs11.as_ref(), args.raw_key.as_deref(), "Password")?;
+    let output_path = writer.path().unwrap().to_string();
+    let mut writer = writer.into_buf_writer_with_block_size(block_size)?;
+    let metadata = decrypt_file(&mut reader, &mut writer, &params, cipher, block_size, args.threads, false)?;
+    reader.finish()?;
+    let written = writer.written();
+    writer.finish(args.fsync)?;
+    if let Some(metadata) = metadata.filter(|_| args.preserve) {
+        metadata.apply(&output_path).map_err(|error| Error::Path { path: output_path, error })?;
+    }
+    Ok(written)
+}
+
+/// Files to pack: `inputs`, and the regular files inside them if they are directories. Entries are named after the paths given on the command line.
+fn pack_files(inputs: &[String], archive: &str) -> Result<Vec<(String, PathBuf)>, Error> {
+    fn walk(path: PathBuf, files: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+        if path.is_dir() {
+            let mut children = fs::read_dir(&path)?.map(|entry| entry.map(|e| e.path())).collect::<io::Result<Vec<PathBuf>>>()?;
+            children.sort();
+            for child in children {
+                walk(child, files)?;
+            }
+        } else {
+            files.push((String::new(), path));
+        }
+        Ok(())
+    }
+    let mut files = Vec::new();
+    for input in inputs {
+        walk(PathBuf::from(input), &mut files).map_err(|error| Error::Path { path: input.clone(), error })?;
+    }
+    files.retain(|(_, path)| !is_same_file(path, archive));
+    for (name, path) in files.iter_mut() {
+        *name = entry_name(&path)?;
+    }
+    Ok(files)
+}
+
+/// Like batch, all the entries share the same master key and key slots, each one having its own salt.
+fn pack(mut args: ContainerArgs) -> Result<(), Error> {
+    let files = pack_files(&args.paths, &args.archive)?;
+    let (master_key, kms_blob) = match args.raw_key.as_deref() {
+        Some(raw_key) => (Locked::new(*raw_key), None),
+        None => new_master_key(args.encryption.kms_key_id.as_deref())?,
+    };
+    let mut params = match args.raw_key {
+        Some(_) => EncryptionParams::with_raw_key(args.encryption.cipher),
+        None => {
+            let (key_slots, nfc_passwords) = key_slots(&master_key, &args.key_slots, mem::take(&mut args.password), kms_blob.is_some(), "Password")?;
+            wrapped_key_params(key_slots, kms_blob.as_deref(), args.encryption.cipher, nfc_passwords, &mut OsRng)?
+        }
+    };
+    set_key_ids(&mut params, &args.key_slots.recipients, args.raw_key.as_deref())?;
+    params.mac = args.encryption.mac;
+    if let Some(comment) = &args.comment {
+        params.set_comment(comment)?;
+    }
+    let mut container = ContainerWriter::new(WrappedWriter::from_path(args.archive).into_buf_writer_with_block_size(args.block_size)?, params, &master_key, args.block_size)?;
+    for (name, path) in files {
+        let path_error = |error| Error::Path { path: path.display().to_string(), error };
+        let file = File::open(&path).map_err(path_error)?;
+        let metadata = Metadata::from_file(&file.metadata().map_err(path_error)?);
+        container.add(&name, metadata, &mut BufReader::new(file))?;
+    }
+    container.finish()?.finish(args.fsync)
+}
+
+/// Opens the container and returns its master key.
+fn open_container<R: Read + Seek>(reader: R, args: &mut ContainerArgs) -> Result<(Container<R>, MasterKey), Error> {
+    let container = Container::open(reader)?;
+    cli::confirm_argon2_costs(container.params(), &args.argon2_limits)?;
+    let master_key = with_retries(mem::take(&mut args.password), args.retries, |password| {
+        master_key(container.params(), password, &args.identities, args.key_slots.pkcs11.as_ref(), args.raw_key.as_deref(), "Password")
+    })?;
+    Ok((container, master_key))
+}
+
+fn open_archive(args: &ContainerArgs, write: bool) -> Result<File, Error> {
+    OpenOptions::new().read(true).write(write).open(&args.archive).map_err(|error| Error::Path { path: args.archive.clone(), error })
+}
+
+/// New segments are written after the current index. On failure, the archive is truncated back to its previous length so that the previous index is the last segment again.
+fn add(mut args: ContainerArgs) -> Result<(), Error> {
+    let files = pack_files(&args.paths, &args.archive)?;
+    let file = open_archive(&args, true)?;
+    let len = file.metadata()?.len();
+    let (container, master_key) = open_container(&file, &mut args)?;
+    let result = container.append(&master_key, args.block_size).and_then(|mut container| {
+        for (name, path) in files {
+            let path_error = |error| Error::Path { path: path.display().to_string(), error };
+            let input = File::open(&path).map_err(path_error)?;
+            let metadata = Metadata::from_file(&input.metadata().map_err(path_error)?);
+            container.add(&name, metadata, &mut BufReader::new(input))?;
+        }
+        container.finish()?.sync_all()?;
+        Ok(())
+    });
+    if result.is_err() {
+        file.set_len(len)?;
+    }
+    result
+}
+
+/// Opens the key of the file to edit, and returns its cipher along with the parameters and master key to encrypt it back with. These get a new salt, and password-derived ones a new Argon2 output from the same password (and YubiKey), so that the encryption keys and nonce aren't reused.
+fn edit_keys(params: &EncryptionParams, password: WrappedPassword, args: &EditArgs) -> Result<(DobyCipher, EncryptionParams, MasterKey), Error> {
+    match (&params.key_derivation, &args.raw_key) {
+        (KeyDerivation::Password(argon2_params), None) => {
+            let mut password = password.get_with_prompt("Password", false)?;
+            let result = match params.yubikey_slot() {
+                Some(slot) => DobyCipher::try_new_with_yubikey(password.as_bytes(), params, challenge_response).and_then(|cipher| {
+                    eprintln!("Touch the YubiKey again if it blinks");
+                    EncryptionParams::with_password_and_yubikey(&params.password_bytes(password.as_bytes()), argon2_params.clone(), params.cipher, slot, challenge_response).map(|new| (cipher, new))
+                }),
+                None => DobyCipher::try_new(password.as_bytes(), params).and_then(|cipher| {
+                    EncryptionParams::with_password(&params.password_bytes(password.as_bytes()), argon2_params.clone(), params.cipher).map(|new| (cipher, new))
+                }),
+            };
+            password.zeroize();
+            let (cipher, (mut new_params, master_key)) = result?;
+            new_params.mac = params.mac;
+            new_params.metadata = params.metadata;
+            for extension in params.extensions() {
+                new_params.set_extension(extension.kind, extension.value.clone())?;
+            }
+            Ok((cipher, new_params, master_key))
+        }
+        _ => {
+            let master_key = master_key(params, password, &args.identities, args.pkcs11.as_ref(), args.raw_key.as_deref(), "Password")?;
+            let mut new_params = params.clone();
+            new_params.renew_salt()?;
+            Ok((DobyCipher::with_master_key(&master_key, params)?, new_params, master_key))
+        }
+    }
+}
+
+/// Decrypts `args.path` to an `EditDir`, opens it in the editor, and encrypts it back over `args.path` with the same key and header options if it was changed.
+fn edit(mut args: EditArgs) -> Result<(), Error> {
+    let path_error = |path: &str, error| Error::Path { path: path.to_string(), error };
+    let file = File::open(&args.path).map_err(|e| path_error(&args.path, e))?;
+    let mut reader = BufReader::new(WrappedReader::from_file(file));
+    let armored = is_armored(reader.fill_buf()?);
+    if armored {
+        reader = BufReader::new(WrappedReader::from_reader(ArmorReader::new(reader)));
+    }
+    let buff = reader.fill_buf()?;
+    if is_ecc(buff) || is_qr_text(buff) || is_container(buff) {
+        return Err(Error::Usage("edit doesn't support files written with --ecc or --qr, nor containers"));
+    }
+    let mut magic_bytes = vec![0; MAGIC_BYTES.len()];
+    let n = reader.read(&mut magic_bytes)?;
+    if n < magic_bytes.len() || !is_doby_format(&magic_bytes) {
+        return Err(Error::UnknownFormat);
+    }
+    let params = read_params(&magic_bytes, &mut reader)?;
+    check_input_signer(&params, args.verify_key.as_ref())?;
+    if params.signer().is_some() && args.sign_key.is_none() {
+        return Err(Error::Usage("FILE is signed: --sign-key is required to sign it again"));
+    }
+    cli::confirm_argon2_costs(&params, &args.argon2_limits)?;
+    let password = mem::take(&mut args.password);
+    let (cipher, mut new_params, master_key) = with_retries(password, args.retries, |password| edit_keys(&params, password, &args))?;
+
+    let dir = EditDir::new()?;
+    if !dir.in_memory {
+        warn!("No RAM-backed directory found: the plaintext is written to {} and shredded afterwards", dir.path().display());
+    }
+    let name = Path::new(&args.path).file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.strip_suffix(".doby").unwrap_or(name))
+        .filter(|name| !name.is_empty())
+        .unwrap_or("plaintext");
+    let plaintext_path = dir.path().join(name);
+    let plaintext = plaintext_path.display().to_string();
+    let mut writer = WrappedWriter::from_path(plaintext.clone()).into_buf_writer_with_block_size(args.block_size)?;
+    let mut reader = SignatureReader::new(reader, &params, args.verify_key.as_ref())?;
+    let metadata = decrypt_file(&mut reader, &mut writer, &params, cipher, args.block_size, 1, false)?;
+    reader.finish()?;
+    writer.finish(false)?;
+
+    let hash = |path: &str| -> Result<blake3::Hash, Error> {
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut File::open(path).map_err(|e| path_error(path, e))?, &mut hasher)?;
+        Ok(hasher.finalize())
+    };
+    let before = hash(&plaintext)?;
+    run_editor(&plaintext_path)?;
+    if hash(&plaintext)? == before {
+        info!("{} wasn't changed", args.path);
+        return Ok(());
+    }
+
+    new_params.remove_extension(SIGNATURE_EXTENSION);
+    if let Some(sign_key) = &args.sign_key {
+        new_params.set_signer(&sign_key.verify_key().to_bytes())?;
+    }
+    if params.plaintext_digest().is_some() {
+        new_params.set_plaintext_digest(&PlaintextDigest::compute(&mut File::open(&plaintext_path)?)?)?;
+    }
+    let new_cipher = DobyCipher::with_master_key(&master_key, &new_params)?;
+    let already_read = match metadata {
+        Some(mut metadata) => {
+            if metadata.modified.is_some() {
+                metadata.modified = Some(SystemTime::now());
+            }
+            metadata.to_bytes()
+        }
+        None => Vec::new(),
+    };
+    let mut reader: Box<dyn Read + Send> = if new_params.sparse() {
+        Box::new(SparseReader::new(File::open(&plaintext_path)?)?)
+    } else {
+        Box::new(File::open(&plaintext_path)?)
+    };
+    let mut writer = WrappedWriter::from_path(args.path.clone()).into_buf_writer_with_block_size(args.block_size)?;
+    if armored {
+        let mut writer = ArmorWriter::new(&mut writer);
+        encrypt_to(&mut reader, &mut writer, None, &new_params, new_cipher, EncryptStream { block_size: args.block_size, threads: 1, already_read: &already_read, mapped: None }, args.sign_key.as_ref())?;
+        writer.finish()?;
+    } else {
+        encrypt_to(&mut reader, &mut writer, None, &new_params, new_cipher, EncryptStream { block_size: args.block_size, threads: 1, already_read: &already_read, mapped: None }, args.sign_key.as_ref())?;
+    }
+    writer.finish(true)?;
+    info!("Saved {}", args.path);
+    Ok(())
+}
+
+/// Files ending with `.doby` in the tree of `path`, sorted. Symbolic links aren't followed: the files they point to would be replaced by regular files.
+fn doby_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let file_type = fs::symlink_metadata(path)?.file_type();
+    if file_type.is_dir() {
+        let mut children = fs::read_dir(path)?.map(|entry| entry.map(|e| e.path())).collect::<io::Result<Vec<PathBuf>>>()?;
+        children.sort();
+        for child in children {
+            doby_files(&child, files)?;
+        }
+    } else if file_type.is_file() && path.extension().is_some_and(|extension| extension == "doby") {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+enum Rotated {
+    /// Number of bytes written.
+    Done(u64),
+    /// With `--dry-run`.
+    WouldRotate,
+    Skipped(&'static str),
+}
+
+/// Re-encrypts the files of `rotate`. Like with batch, the new master key is wrapped once with the new password and shared by all the files, each one having its own salt, and the master keys of the key slots already opened with the old password are kept.
+struct Rotation<'a> {
+    args: &'a RotateArgs,
+    old_password: Locked<String>,
+    new_password: Option<Locked<String>>,
+    /// New master key and its key slot, `None` with `--dry-run`.
+    new_key: Option<(MasterKey, KeySlot, bool)>,
+    opened: Vec<(Vec<KeySlot>, MasterKey)>,
+    /// Key slots opened with the new password.
+    rotated: Vec<Vec<KeySlot>>,
+}
+
+impl<'a> Rotation<'a> {
+    fn new(args: &'a mut RotateArgs) -> Result<Self, Error> {
+        let old_password = mem::take(&mut args.old_password).get_with_prompt("Old password", false)?;
+        let new_password = if args.dry_run && !args.new_password.is_provided() {
+            None
+        } else {
+            Some(mem::take(&mut args.new_password).get_with_prompt("New password", true)?)
+        };
+        if new_password.as_ref().is_some_and(|new_password| **new_password == *old_password) {
+            return Err(Error::Usage("the new password must be different from the old one"));
+        }
+        let new_key = match &new_password {
+            Some(new_password) if !args.dry_run => {
+                let (master_key, _) = new_master_key(None)?;
+                let mut normalized = false;
+                let key_slot = KeySlot::from_password(&encryption_password(new_password, args.nfc, &mut normalized), args.argon2_params.clone(), &master_key)?;
+                Some((master_key, key_slot, normalized))
+            }
+            _ => None,
+        };
+        let rotated = new_key.iter().map(|(_, key_slot, _)| vec![key_slot.clone()]).collect();
+        Ok(Self { args, old_password, new_password, new_key, opened: Vec::new(), rotated })
+    }
+
+    fn old_cipher(&mut self, params: &EncryptionParams) -> Result<DobyCipher, Error> {
+        match &params.key_derivation {
+            KeyDerivation::KeySlots(key_slots) => {
+                if let Some((_, master_key)) = self.opened.iter().find(|(opened, _)| opened == key_slots) {
+                    return DobyCipher::with_master_key(master_key, params);
+                }
+                let master_key = Locked::new(key_slots[0].unwrap_password(&params.password_bytes(self.old_password.as_bytes()))?.ok_or(Error::NoMatchingKeySlot)?);
+                let cipher = DobyCipher::with_master_key(&master_key, params);
+                self.opened.push((key_slots.clone(), master_key));
+                cipher
+            }
+            _ => DobyCipher::try_new(self.old_password.as_bytes(), params),
+        }
+    }
+
+    /// Whether a file that the old password doesn't open was already rotated, e.g. by an interrupted run.
+    fn opens_with_new_password(&mut self, params: &EncryptionParams) -> Result<bool, Error> {
+        let Some(new_password) = &self.new_password else {
+            return Ok(false);
+        };
+        match DobyCipher::try_with_password(new_password.as_bytes(), params) {
+            Ok(_) => {
+                if let KeyDerivation::KeySlots(key_slots) = &params.key_derivation {
+                    self.rotated.push(key_slots.clone());
+                }
+                Ok(true)
+            }
+            Err(Error::WrongPassword | Error::NoMatchingKeySlot) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Re-encrypts `path` in place.
+    fn rotate(&mut self, path: &str) -> Result<Rotated, Error> {
+        let file = File::open(path)?;
+        let block_size = self.args.block_size.unwrap_or_else(|| auto_block_size(file.metadata().ok().as_ref()));
+        let mut reader = BufReader::new(WrappedReader::from_file(file));
+        let armored = is_armored(reader.fill_buf()?);
+        if armored {
+            reader = BufReader::new(WrappedReader::from_reader(ArmorReader::new(reader)));
+        }
+        let buff = reader.fill_buf()?;
+        if is_ecc(buff) || is_qr_text(buff) || is_container(buff) {
+            return Ok(Rotated::Skipped("written with --ecc or --qr, or a container"));
+        }
+        let mut magic_bytes = vec![0; MAGIC_BYTES.len()];
+        let n = reader.read(&mut magic_bytes)?;
+        if n < magic_bytes.len() || !is_doby_format(&magic_bytes) {
+            return Err(Error::UnknownFormat);
+        }
+        let params = read_params(&magic_bytes, &mut reader)?;
+        let single_password = match &params.key_derivation {
+            KeyDerivation::Password(_) => params.yubikey_slot().is_none(),
+            KeyDerivation::KeySlots(key_slots) => matches!(key_slots.as_slice(), [KeySlot::Password { .. }]) && params.kms_blob().is_none(),
+            KeyDerivation::RawKey => false,
+        };
+        if !single_password {
+            return Ok(Rotated::Skipped("not encrypted with a single password"));
+        }
+        const ROTATED: &str = "already encrypted with the new password";
+        if matches!(&params.key_derivation, KeyDerivation::KeySlots(key_slots) if self.rotated.contains(key_slots)) {
+            return Ok(Rotated::Skipped(ROTATED));
+        }
+        self.args.argon2_limits.check(&params)?;
+        check_input_signer(&params, self.args.verify_key.as_ref())?;
+        if params.signer().is_some() && self.args.sign_key.is_none() {
+            return Err(Error::Usage("this file is signed: --sign-key is required to sign it again"));
+        }
+        let old_cipher = match self.old_cipher(&params) {
+            Err(e @ (Error::WrongPassword | Error::NoMatchingKeySlot)) => return if self.opens_with_new_password(&params)? { Ok(Rotated::Skipped(ROTATED)) } else { Err(e) },
+            result => result?,
+        };
+        let Some((master_key, key_slot, normalized)) = &self.new_key else {
+            return Ok(Rotated::WouldRotate);
+        };
+        let mut new_params = wrapped_key_params(vec![key_slot.clone()], None, params.cipher, *normalized, &mut OsRng)?;
+        new_params.mac = params.mac;
+        new_params.metadata = params.metadata;
+        if params.sparse() {
+            new_params.set_sparse()?;
+        }
+        if let Some(comment) = params.comment() {
+            new_params.set_comment(&comment)?;
+        }
+        if let Some(digest) = params.plaintext_digest() {
+            new_params.set_plaintext_digest(&digest)?;
+        }
+        let signing_key = self.args.sign_key.as_ref();
+        if let Some(signing_key) = signing_key {
+            new_params.set_signer(&signing_key.verify_key().to_bytes())?;
+        }
+        let new_cipher = DobyCipher::with_master_key(master_key, &new_params)?;
+        let mut reader = SignatureReader::new(reader, &params, self.args.verify_key.as_ref())?;
+        let mut writer = WrappedWriter::from_path(path.to_string()).into_buf_writer_with_block_size(block_size)?;
+        if armored {
+            let mut writer = ArmorWriter::new(&mut writer);
+            signed(&mut writer, signing_key, |mut writer| rekey(&mut reader, &mut writer, old_cipher, &new_params, new_cipher, block_size))?;
+            writer.finish()?;
+        } else {
+            signed(&mut writer, signing_key, |mut writer| rekey(&mut reader, &mut writer, old_cipher, &new_params, new_cipher, block_size))?;
+        }
+        //the original is only replaced once authenticated
+        reader.finish()?;
+        let written = writer.written();
+        writer.finish(true)?;
+        Ok(Rotated::Done(written))
+    }
+}
+
+/// Rotates the files found in the directories of `args` and prints a summary. Failures are reported without stopping.
+fn rotate(mut args: RotateArgs) -> Result<(), Error> {
+    let mut files = Vec::new();
+    for dir in &args.dirs {
+        doby_files(Path::new(dir), &mut files).map_err(|error| Error::Path { path: dir.clone(), error })?;
+    }
+    let dry_run = args.dry_run;
+    let mut rotation = Rotation::new(&mut args)?;
+    let (mut rotated, mut skipped, mut failed) = (0, 0, 0);
+    for path in &files {
+        let path = path.display().to_string();
+        let result = rotation.rotate(&path);
+        if report::is_json() {
+            let event = Event::new("result").string("operation", "rotate").string("input", &path).boolean("dry_run", dry_run);
+            match &result {
+                Ok(Rotated::Done(bytes)) => event.number("bytes", *bytes).string("status", "ok"),
+                Ok(Rotated::WouldRotate) => event.string("status", "ok"),
+                Ok(Rotated::Skipped(reason)) => event.string("reason", reason).string("status", "skipped"),
+                Err(e) => event.string("status", "error").error(e),
+            }.emit();
+        } else {
+            match &result {
+                Ok(Rotated::Done(_)) => info!("Rotated {}", path),
+                Ok(Rotated::WouldRotate) => info!("Would rotate {}", path),
+                Ok(Rotated::Skipped(reason)) => info!("Skipped {}: {}", path, reason),
+                Err(e) => error!("{}: {}", path, e),
+            }
+        }
+        match result {
+            Ok(Rotated::Done(_) | Rotated::WouldRotate) => rotated += 1,
+            Ok(Rotated::Skipped(_)) => skipped += 1,
+            Err(_) => failed += 1,
+        }
+    }
+    if report::is_json() {
+        Event::new("summary").string("operation", "rotate").boolean("dry_run", dry_run).number("rotated", rotated).number("skipped", skipped).number("failed", failed).emit();
+    } else {
+        info!("{} {}, {} skipped, {} failed", rotated, if dry_run { "to rotate" } else { "rotated" }, skipped, failed);
+    }
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(Error::RotationFailed { failed: failed as usize, total: files.len() })
+    }
+}
+
+/// Encrypts a directory tree into a mirror, or restores it. Like with batch, failures are reported without stopping. Updates end with a summary of the encrypted, unchanged and failed files.
+fn mirror(mut args: MirrorArgs) -> Result<(), Error> {
+    let (src, dst) = (Path::new(&args.src), Path::new(&args.dst));
+    let files = if args.decrypt {
+        restore_mirror(src, dst, &mut args.batch)?
+    } else {
+        update_mirror(src, dst, args.encrypt_names, &mut args.batch)?
+    };
+    let (mut unchanged, mut failed) = (0, 0);
+    for file in &files {
+        if report::is_json() {
+            Outcome {
+                operation: Some(if args.decrypt { "decrypt" } else { "encrypt" }),
+                input: Some(file.input.clone()),
+                output: file.output.clone(),
+                bytes: file.result.as_ref().ok().copied().flatten(),
+                verified: if args.decrypt { Some(true) } else { None },
+                skipped: matches!(file.result, Ok(None)),
+                ..Default::default()
+            }.report(file.result.as_ref().err());
+        } else if let Err(e) = &file.result {
+            error!("{}: {}", file.input, e);
+        }
+        match file.result {
+            Ok(Some(_)) => {}
+            Ok(None) => unchanged += 1,
+            Err(_) => failed += 1,
+        }
+    }
+    if !args.decrypt {
+        let encrypted = files.len() - unchanged - failed;
+        if report::is_json() {
+            Event::new("summary").string("operation", "mirror").number("encrypted", encrypted as u64).number("unchanged", unchanged as u64).number("failed", failed as u64).emit();
+        } else {
+            info!("{} encrypted, {} unchanged, {} failed", encrypted, unchanged, failed);
+        }
+    }
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(Error::BatchFailed { failed, total: files.len(), decrypting: args.decrypt })
+    }
+}
+
+/// Opens the files of `cat` and `grep`. Like with batch, the password is asked for only once, and the master keys of the key slots already opened are kept so that files sharing their key slots (e.g. the outputs of batch and watch) only cost one Argon2.
+struct InputOpener<'a> {
+    args: &'a CatArgs,
+    password: Option<Locked<String>>,
+    master_keys: Vec<(Vec<KeySlot>, MasterKey)>,
+}
+
+impl<'a> InputOpener<'a> {
+    /// `password` is taken out of `args` by the caller.
+    fn new(args: &'a CatArgs, password: WrappedPassword) -> Result<Self, Error> {
+        //unless identities, ssh-agent or the KMS may make it unnecessary
+        let password = if args.raw_key.is_none() && (args.identities.is_empty() && keys::key_ids(None).is_empty() && !agent_available() && !tpm_available() && !cfg!(feature = "kms") || password.is_provided()) {
+            Some(password.get(false)?)
+        } else {
+            None
+        };
+        Ok(Self { args, password, master_keys: Vec::new() })
+    }
+
+    /// Returns the parameters of `input`, the reader of its ciphertext, whose signature is checked by `finish`, and the block size to decrypt it with.
+    fn open(&self, input: &str) -> Result<(EncryptionParams, SignatureReader<BufReader<WrappedReader>>, usize), Error> {
+        let file = File::open(input)?;
+        let block_size = self.args.block_size.unwrap_or_else(|| auto_block_size(file.metadata().ok().as_ref()));
+        let (params, reader) = open_encrypted(file)?;
+        self.args.argon2_limits.check(&params)?;
+        let reader = SignatureReader::new(reader, &params, self.args.verify_key.as_ref())?;
+        Ok((params, reader, block_size))
+    }
+
+    fn cipher(&mut self, params: &EncryptionParams) -> Result<DobyCipher, Error> {
+        match &params.key_derivation {
+            KeyDerivation::KeySlots(key_slots) if self.args.raw_key.is_none() => {
+                if let Some((_, master_key)) = self.master_keys.iter().find(|(opened, _)| opened == key_slots) {
+                    return DobyCipher::with_master_key(master_key, params);
+                }
+                let master_key = master_key(params, self.password.clone().into(), &self.args.identities, self.args.pkcs11.as_ref(), None, "Password")?;
+                let cipher = DobyCipher::with_master_key(&master_key, params);
+                self.master_keys.push((key_slots.clone(), master_key));
+                cipher
+            }
+            _ => decryption_cipher(params, self.password.clone().into(), &self.args.identities, self.args.pkcs11.as_ref(), self.args.raw_key.as_deref(), "Password"),
+        }
+    }
+
+    /// Decrypts `input` to stdout as it's read, like when decrypting without OUTPUT.
+    fn decrypt_to_stdout(&mut self, input: &str) -> Result<(), Error> {
+        let (params, mut reader, block_size) = self.open(input)?;
+        let cipher = self.cipher(&params)?;
+        let mut writer = WrappedWriter::<String>::from_writer(io::stdout()).into_buf_writer_with_block_size(block_size)?;
+        decrypt_file(&mut reader, &mut writer, &params, cipher, block_size, self.args.threads, false)?;
+        reader.finish()?;
+        writer.finish(false)
+    }
+
+    /// Decrypts and authenticates `input` in memory.
+    fn decrypt_to_memory(&mut self, input: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let (params, mut reader, block_size) = self.open(input)?;
+        if params.sparse() {
+            return Err(Error::Usage("sparse files can't be decrypted in memory: decrypt them to a file instead"));
+        }
+        let cipher = self.cipher(&params)?;
+        let mut plaintext = Zeroizing::new(Vec::new());
+        decrypt_metadata(&mut reader, &mut *plaintext, &params, cipher, block_size, self.args.threads, false)?;
+        reader.finish()?;
+        Ok(plaintext)
+    }
+}
+
+/// Text of `plaintext` that can be printed to a terminal: invalid UTF-8 is replaced, and control characters other than tabs and line feeds are shown like `cat -v` does (e.g. ESC as "^["), or as Unicode escapes for the C1 ones.
+fn escape_controls(plaintext: &[u8]) -> Zeroizing<String> {
+    let text = String::from_utf8_lossy(plaintext);
+    let mut escaped = Zeroizing::new(String::with_capacity(text.len()));
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | '\t' => escaped.push(c),
+            //CRLF line endings
+            '\r' if chars.peek() == Some(&'\n') => escaped.push(c),
+            '\0'..='\x1f' => {
+                escaped.push('^');
+                escaped.push((c as u8 ^ 0x40) as char);
+            }
+            '\x7f' => escaped.push_str("^?"),
+            c if c.is_control() => escaped.extend(c.escape_unicode()),
+            c => escaped.push(c),
+        }
+    }
+    if let Cow::Owned(mut text) = text {
+        text.zeroize();
+    }
+    escaped
+}
+
+/// Error of a single input, or `BatchFailed` once all of them have been processed.
+fn inputs_failed(inputs: &[String], failed: usize, last_error: Option<Error>) -> Result<(), Error> {
+    match last_error {
+        Some(e) if inputs.len() == 1 => Err(e),
+        Some(_) => Err(Error::BatchFailed { failed, total: inputs.len(), decrypting: true }),
+        None => Ok(()),
+    }
+}
+
+fn cat(mut args: CatArgs) -> Result<(), Error> {
+    let terminal = !args.raw && io::stdout().is_terminal();
+    let password = mem::take(&mut args.password);
+    let mut opener = InputOpener::new(&args, password)?;
+    let inputs = &args.inputs;
+    let (mut failed, mut last_error) = (0, None);
+    for input in inputs {
+        let result = if terminal {
+            //nothing reaches the terminal before being authenticated
+            opener.decrypt_to_memory(input).and_then(|plaintext| {
+                if plaintext.contains(&0) {
+                    return Err(Error::Usage("binary plaintext not printed to the terminal: redirect stdout or use --raw"));
+                }
+                Ok(io::stdout().write_all(escape_controls(&plaintext).as_bytes())?)
+            })
+        } else {
+            opener.decrypt_to_stdout(input)
+        };
+        if let Err(e) = result {
+            if inputs.len() > 1 {
+                error!("{}: {}", input, e);
+            }
+            failed += 1;
+            last_error = Some(e);
+        }
+    }
+    inputs_failed(inputs, failed, last_error)
+}
+
+/// Writes the lines of `plaintext` selected by `args` to `output`, and returns how many there were.
+fn grep_plaintext(plaintext: &[u8], input: &str, several: bool, terminal: bool, args: &GrepArgs, output: &mut Vec<u8>) -> usize {
+    let binary = plaintext.contains(&0);
+    let lines = plaintext.strip_suffix(b"\n").unwrap_or(plaintext);
+    let mut selected = 0;
+    for (i, line) in lines.split(|b| *b == b'\n').enumerate() {
+        if plaintext.is_empty() || args.pattern.is_match(line) == args.invert {
+            continue;
+        }
+        selected += 1;
+        if binary || args.files_with_matches || args.count {
+            continue;
+        }
+        if several {
+            output.extend_from_slice(input.as_bytes());
+            output.push(b':');
+        }
+        if args.line_number {
+            output.extend_from_slice(format!("{}:", i + 1).as_bytes());
+        }
+        if terminal {
+            output.extend_from_slice(escape_controls(line).as_bytes());
+        } else {
+            output.extend_from_slice(line);
+        }
+        output.push(b'\n');
+    }
+    if args.count {
+        let count = if several { format!("{}:{}\n", input, selected) } else { format!("{}\n", selected) };
+        output.extend_from_slice(count.as_bytes());
+    } else if selected > 0 && args.files_with_matches {
+        output.extend_from_slice(format!("{}\n", input).as_bytes());
+    } else if selected > 0 && binary {
+        output.extend_from_slice(format!("Binary file {} matches\n", input).as_bytes());
+    }
+    selected
+}
+
+/// Prints the lines of each input selected by the pattern, once it has been decrypted and authenticated in memory. Fails if none was selected.
+fn grep(mut args: GrepArgs) -> Result<(), Error> {
+    let terminal = io::stdout().is_terminal();
+    let several = args.files.inputs.len() > 1;
+    let password = mem::take(&mut args.files.password);
+    let mut opener = InputOpener::new(&args.files, password)?;
+    let inputs = &args.files.inputs;
+    let (mut failed, mut last_error, mut selected) = (0, None, 0);
+    for input in inputs {
+        let result = opener.decrypt_to_memory(input).and_then(|plaintext| {
+            let mut output = Zeroizing::new(Vec::new());
+            selected += grep_plaintext(&plaintext, input, several, terminal, &args, &mut output);
+            Ok(io::stdout().write_all(&output)?)
+        });
+        if let Err(e) = result {
+            if several {
+                error!("{}: {}", input, e);
+            }
+            failed += 1;
+            last_error = Some(e);
+        }
+    }
+    inputs_failed(inputs, failed, last_error)?;
+    if selected == 0 {
+        return Err(Error::NoMatch);
+    }
+    Ok(())
+}
+
+fn list(mut args: ContainerArgs) -> Result<(), Error> {
+    let (mut container, master_key) = open_container(BufReader::new(open_archive(&args, false)?), &mut args)?;
+    for entry in container.entries(&master_key)? {
+        //names come from the ciphertext: don't let them output control characters
+        println!("{:>12} {}", entry.size, entry.name.escape_debug());
+    }
+    Ok(())
+}
+
+/// Extracts the entries matching `args.paths`. An entry matches a path if it has the same name or is inside it.
+fn extract_entries(mut args: ContainerArgs) -> Result<(), Error> {
+    let (mut container, master_key) = open_container(BufReader::new(open_archive(&args, false)?), &mut args)?;
+    let mut entries = container.entries(&master_key)?;
+    if !args.paths.is_empty() {
+        let names = args.paths.iter().map(entry_name).collect::<Result<Vec<String>, Error>>()?;
+        let matches = |entry: &ContainerEntry, name: &str| entry.name == name || entry.name.strip_prefix(name).is_some_and(|rest| rest.starts_with('/'));
+        if let Some(name) = names.iter().find(|name| !entries.iter().any(|entry| matches(entry, name))) {
+            return Err(Error::EntryNotFound(name.clone()));
+        }
+        entries.retain(|entry| names.iter().any(|name| matches(entry, name)));
+    }
+    for entry in entries {
+        //entry names only contain normal components, so this stays inside the output directory
+        let path = Path::new(&args.output_dir).join(&entry.name).to_string_lossy().into_owned();
+        if !cli::confirm_overwrite(&path, args.interactive)? {
+            continue;
+        }
+        if let Some(parent) = Path::new(&path).parent() {
+            fs::create_dir_all(parent).map_err(|error| Error::Path { path: parent.display().to_string(), error })?;
+        }
+        let mut writer = WrappedWriter::from_path(path.clone()).into_buf_writer_with_block_size(args.block_size)?;
+        let metadata = container.extract(&entry, &master_key, &mut writer, args.block_size)?;
+        writer.finish(args.fsync)?;
+        if args.preserve {
+            metadata.apply(&path).map_err(|error| Error::Path { path, error })?;
+        }
+    }
+    Ok(())
+}
+
+fn bench(args: BenchArgs) -> Result<(), Error> {
+    //random data is written to the current directory: it's likely on the disk files are encrypted on
+    let (input, output_dir, _random_file) = match args.file {
+        Some(path) => {
+            let dir = Path::new(&path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf();
+            (PathBuf::from(path), dir, None)
+        }
+        None => {
+            let file = bench::random_file(Path::new("."), args.size)?;
+            (file.path().to_path_buf(), PathBuf::from("."), Some(file))
+        }
+    };
+    let sample = bench::load_sample(&input).map_err(|error| Error::Path { path: input.display().to_string(), error })?;
+
+    let kdf_time = bench::kdf_time(&args.argon2_params)?;
+    let argon2 = format!("Argon2id (t={} m={}KiB p={})", args.argon2_params.t_cost(), args.argon2_params.m_cost(), args.argon2_params.p_cost());
+    if report::is_json() {
+        Event::new("bench").string("measure", "argon2").number("time_cost", args.argon2_params.t_cost().into()).number("memory_cost", args.argon2_params.m_cost().into()).number("parallelism", args.argon2_params.p_cost().into()).number("milliseconds", kdf_time.as_millis() as u64).emit();
+    } else {
+        println!("{}: {}ms", argon2, kdf_time.as_millis());
+    }
+
+    let mut fastest = None;
+    for (cipher, mac) in bench::BENCH_ALGORITHMS {
+        let throughput = bench::cipher_throughput(&sample, cipher, mac)?;
+        let name = if cipher.is_aead() { cipher.to_string() } else { format!("{} with {}", cipher, mac) };
+        if report::is_json() {
+            Event::new("bench").string("measure", "cipher").string("cipher", &cipher.to_string()).optional_string("mac", (!cipher.is_aead()).then(|| mac.to_string()).as_deref()).number("bytes_per_second", throughput as u64).emit();
+        } else {
+            println!("{}: {:.0} MiB/s", name, throughput / (1 << 20) as f64);
+        }
+        if fastest.is_none_or(|(_, _, best)| throughput > best) {
+            fastest = Some((cipher, mac, throughput));
+        }
+    }
+    let (cipher, mac, _) = fastest.unwrap();
+
+    let mut best_block_size = None;
+    for block_size in bench::BENCH_BLOCK_SIZES {
+        let time = bench::block_size_time(&input, &output_dir, cipher, mac, block_size)?;
+        if report::is_json() {
+            Event::new("bench").string("measure", "block_size").number("block_size", block_size as u64).number("milliseconds", time.as_millis() as u64).emit();
+        } else {
+            println!("Block size of {} bytes: {}ms", block_size, time.as_millis());
+        }
+        if best_block_size.is_none_or(|(_, best)| time < best) {
+            best_block_size = Some((block_size, time));
+        }
+    }
+    let (block_size, _) = best_block_size.unwrap();
+
+    let config = if args.write_config {
+        Some(bench::write_config(cipher, mac, block_size)?)
+    } else {
+        None
+    };
+    if report::is_json() {
+        Event::new("result").string("operation", "bench").string("cipher", &cipher.to_string()).optional_string("mac", (!cipher.is_aead()).then(|| mac.to_string()).as_deref()).number("block_size", block_size as u64).optional_string("config", config.as_ref().map(|path| path.display().to_string()).as_deref()).string("status", "ok").emit();
+    } else {
+        let cipher = if cipher.is_aead() { cipher.to_string() } else { format!("{} with {}", cipher, mac) };
+        println!("Fastest cipher: {}, fastest block size: {} bytes", cipher, block_size);
+        if let Some(path) = config {
+            info!("Saved to {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn selftest(export: bool) -> Result<(), Error> {
+    let vectors = test_vectors();
+    if export {
+        for vector in &vectors {
+            println!("{}", vector.to_json()?);
+        }
+        return Ok(());
+    }
+    let mut failure = None;
+    for vector in &vectors {
+        let result = vector.check();
+        if report::is_json() {
+            let event = Event::new("selftest").string("test", vector.name);
+            match &result {
+                Ok(()) => event.string("status", "ok"),
+                Err(e) => event.string("status", "failed").error(e),
+            }.emit();
+        } else {
+            info!("{}: {}", vector.name, if result.is_ok() { "ok" } else { "FAILED" });
+        }
+        if let Err(e) = result {
+            failure.get_or_insert(e);
+        }
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => {
+            if !report::is_json() {
+                info!("All {} known-answer tests passed", vectors.len());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Key file content of a new identity or signing key, with the JSON label and value of its public key.
+fn generate_key(sign: bool) -> (String, &'static str, String) {
+    if sign {
+        let signing_key = SigningKey::generate();
+        (signing_key.to_file_content(), "verify_key", signing_key.verify_key().to_string())
+    } else {
+        let identity = Identity::generate();
+        (identity.to_file_content(), "public_key", identity.recipient().unwrap().to_string())
+    }
+}
+
+fn keygen(output: Option<String>, sign: bool) -> Result<(), Error> {
+    let (mut content, label, public_key) = generate_key(sign);
+    match output {
+        Some(path) => {
+            write_identity_file(&path, content.as_bytes()).map_err(|error| Error::Path { path: path.clone(), error })?;
+            if report::is_json() {
+                Event::new("result").string("operation", "keygen").string("output", &path).string(label, &public_key).string("status", "ok").emit();
+            } else if sign {
+                info!("Verify key: {}", public_key);
+            } else {
+                info!("Public key: {}", public_key);
+            }
+        }
+        None => print!("{}", content),
+    }
+    content.zeroize();
+    Ok(())
+}
+
+/// Never overwrites an existing file and makes it readable by the owner only.
+fn write_identity_file(path: &str, content: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+/// Stores the unprotected key file content `content` as `name`, protecting it with `passphrase` if `Some`.
+fn store_key(name: &str, content: &str, passphrase: Option<WrappedPassword>) -> Result<(PathBuf, keys::KeyInfo), Error> {
+    keys::check_name(name)?;
+    let info = keys::parse_key(content)?;
+    let path = match passphrase {
+        Some(passphrase) => keys::store(name, keys::protect(content, &passphrase.get_with_prompt("Passphrase", true)?, &info)?.as_bytes())?,
+        None => keys::store(name, content.as_bytes())?,
+    };
+    Ok((path, info))
+}
+
+/// Public key of `info` if it has one, its key ID otherwise.
+fn key_description(info: &keys::KeyInfo) -> String {
+    match (&info.public_key, &info.key_id) {
+        (Some(public_key), _) => public_key.clone(),
+        (None, Some(key_id)) => format!("key ID {}", format_key_id(key_id)),
+        (None, None) => String::from("-"),
+    }
+}
+
+fn key_generate(name: String, sign: bool, passphrase: Option<WrappedPassword>) -> Result<(), Error> {
+    let (content, label, public_key) = generate_key(sign);
+    let content = Zeroizing::new(content);
+    let (path, _) = store_key(&name, &content, passphrase)?;
+    if report::is_json() {
+        Event::new("result").string("operation", "key generate").string("name", &name).string("output", &path.display().to_string()).string(label, &public_key).string("status", "ok").emit();
+    } else if sign {
+        info!("Verify key: {}", public_key);
+    } else {
+        info!("Public key: {}", public_key);
+    }
+    Ok(())
+}
+
+fn key_add(name: String, passphrase: Option<WrappedPassword>) -> Result<(), Error> {
+    let (path, info) = store_key(&name, &keys::generate_symmetric(), passphrase)?;
+    let key_id = format_key_id(&info.key_id.unwrap());
+    if report::is_json() {
+        Event::new("result").string("operation", "key add").string("name", &name).string("output", &path.display().to_string()).string("key_id", &key_id).string("status", "ok").emit();
+    } else {
+        info!("Key ID: {}", key_id);
+    }
+    Ok(())
+}
+
+fn key_list() -> Result<(), Error> {
+    let keys = keys::list()?;
+    if keys.is_empty() && !report::is_json() {
+        info!("No stored key");
+    }
+    let width = keys.iter().map(|(name, _)| name.len()).max().unwrap_or_default();
+    for (name, info) in keys {
+        match info {
+            Ok(info) if report::is_json() => {
+                let mut event = Event::new("key").string("name", &name).string("kind", info.kind.name()).boolean("protected", info.protected);
+                if let Some(public_key) = &info.public_key {
+                    event = event.string("public_key", public_key);
+                }
+                if let Some(key_id) = &info.key_id {
+                    event = event.string("key_id", &format_key_id(key_id));
+                }
+                event.emit();
+            }
+            Ok(info) => println!(
+                "{:<width$}  {:<9}  {}{}",
+                name,
+                info.kind.name(),
+                key_description(&info),
+                if info.protected { " (protected)" } else { "" },
+                width = width,
+            ),
+            Err(e) => warn!("{}: {}", name, e),
+        }
+    }
+    Ok(())
+}
+
+fn key_export(name: String, secret: bool, output: Option<String>) -> Result<(), Error> {
+    let path = keys::key_path(&name)?;
+    let content = match fs::read(&path) {
+        Ok(content) => Zeroizing::new(content),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(Error::KeyNotFound(name)),
+        Err(error) => return Err(Error::Path { path: path.display().to_string(), error }),
+    };
+    let exported = if secret {
+        content
+    } else {
+        let info = keys::key_info(&content)?;
+        if info.kind == KeyKind::Symmetric {
+            return Err(Error::Usage("symmetric keys have no public key: use --secret to export the key itself"));
+        }
+        let public_key = info.public_key.ok_or(Error::Usage("the public key of plugin identities is only known to their plugin"))?;
+        Zeroizing::new(format!("{}\n", public_key).into_bytes())
+    };
+    match output {
+        Some(path) => write_identity_file(&path, &exported).map_err(|error| Error::Path { path, error }),
+        None => Ok(io::stdout().write_all(&exported)?),
+    }
+}
+
+fn key_import(name: String, file: String, passphrase: Option<WrappedPassword>) -> Result<(), Error> {
+    keys::check_name(&name)?;
+    let content = Zeroizing::new(fs::read(&file).map_err(|error| Error::Path { path: file.clone(), error })?);
+    let info = keys::key_info(&content)?;
+    let path = match passphrase {
+        Some(passphrase) if !info.protected => {
+            let content = Zeroizing::new(String::from_utf8(content.to_vec()).map_err(|_| Error::InvalidIdentity)?);
+            store_key(&name, &content, Some(passphrase))?.0
+        }
+        _ => keys::store(&name, &content)?,
+    };
+    if report::is_json() {
+        let mut event = Event::new("result").string("operation", "key import").string("name", &name).string("output", &path.display().to_string());
+        if let Some(public_key) = &info.public_key {
+            event = event.string("public_key", public_key);
+        }
+        if let Some(key_id) = &info.key_id {
+            event = event.string("key_id", &format_key_id(key_id));
+        }
+        event.string("status", "ok").emit();
+    } else {
+        info!("Imported {} ({})", name, match key_description(&info).as_str() {
+            "-" => info.kind.name().to_string(),
+            description => description.to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn run() -> Result<(), Error> {
+    let start = Instant::now();
+    let mut progress_bar = None;
+    let mut outcome = Outcome::default();
+    let result = process(&mut progress_bar, &mut outcome);
+    if let Some(bar) = progress_bar {
+        bar.finish();
+    }
+    if report::is_json() && outcome.operation.is_some() {
+        outcome.report(result.as_ref().err());
+    }
+    if outcome.stats && result.is_ok() && !outcome.skipped {
+        outcome.report_stats(start.elapsed());
+    }
+    result
+}
+
+fn process(progress_bar: &mut Option<ProgressBar>, outcome: &mut Outcome) -> Result<(), Error> {
+    let mut cli_args = match cli::parse()? {
+        Some(Command::Run(cli_args)) => cli_args,
+        Some(Command::Batch(args)) => return batch(args),
+        Some(Command::Watch(args)) => return watch(args),
+        Some(Command::Mirror(args)) => return mirror(args),
+        Some(Command::Serve(args)) => return serve(args),
+        Some(Command::Send(args)) => return send(args),
+        Some(Command::Receive(args)) => return receive(args),
+        Some(Command::Client { socket, decrypt, input, output }) => return client(socket, decrypt, input, output),
+        Some(Command::Keygen { output, sign }) => return keygen(output, sign),
+        Some(Command::KeyGenerate { name, sign, passphrase }) => return key_generate(name, sign, passphrase),
+        Some(Command::KeyAdd { name, passphrase }) => return key_add(name, passphrase),
+        Some(Command::KeyList) => return key_list(),
+        Some(Command::KeyExport { name, secret, output }) => return key_export(name, secret, output),
+        Some(Command::KeyImport { name, file, passphrase }) => return key_import(name, file, passphrase),
+        Some(Command::Edit(args)) => return edit(args),
+        Some(Command::Rotate(args)) => return rotate(args),
+        Some(Command::Cat(args)) => return cat(args),
+        Some(Command::Grep(args)) => return grep(args),
+        Some(Command::Pack(args)) => return pack(args),
+        Some(Command::Add(args)) => return add(args),
+        Some(Command::List(args)) => return list(args),
+        Some(Command::Extract(args)) => return extract_entries(args),
+        Some(Command::HeaderBackup { input, output }) => {
+            let header = backup_header(input)?;
+            return match output {
+                Some(path) => {
+                    let mut writer = WrappedWriter::from_path(path).into_buf_writer()?;
+                    writer.write_all(&header)?;
+                    writer.finish(true)
+                }
+                None => Ok(io::stdout().write_all(&header)?),
+            };
+        }
+        Some(Command::HeaderRestore { backup, file, force }) => {
+            let backup = fs::read(&backup).map_err(|error| Error::Path { path: backup, error })?;
+            restore_header(&backup, file, force)?;
+            return Ok(());
+        }
+        Some(Command::Repair { path }) => {
+            match repair(&path)? {
+                damaged_shards if report::is_json() => Event::new("result").string("operation", "repair").string("input", &path).number("repaired_shards", damaged_shards).string("status", "ok").emit(),
+                0 => info!("No damaged shard found"),
+                damaged_shards => info!("Repaired {} damaged shards", damaged_shards),
+            }
+            return Ok(());
+        }
+        Some(Command::Plugins) => {
+            let plugins = discover_plugins();
+            if plugins.is_empty() {
+                info!("No plugin found in PATH");
+            }
+            for (name, path) in plugins {
+                println!("{}\t{}", name, path.display());
+            }
+            return Ok(());
+        }
+        Some(Command::Bench(args)) => return bench(args),
+        Some(Command::SelfTest { export }) => return selftest(export),
+        Some(Command::Inspect { path, json, verify_digest }) => {
+            let mut inspection = inspect(path)?;
+            inspection.key_names = keys::key_ids(None);
+            if json {
+                println!("{}", inspection.to_json());
+            } else {
+                println!("{}", inspection);
+            }
+            if let Some(plaintext) = verify_digest {
+                let digest = inspection.params.plaintext_digest().ok_or(Error::MissingDigest)?;
+                let mut file = File::open(&plaintext).map_err(|error| Error::Path { path: plaintext.clone(), error })?;
+                if !digest.matches(&mut file).map_err(|error| Error::Path { path: plaintext.clone(), error })? {
+                    return Err(Error::DigestMismatch);
+                }
+                info!("{} matches the digest of the plaintext", plaintext);
+            }
+            return Ok(());
+        }
+        None => return Ok(()),
+    };
+    outcome.input = cli_args.input.clone();
+    outcome.output = cli_args.writer.path().map(String::from);
+    outcome.stats = cli_args.stats;
+    debug!("block size: {} bytes, {} threads", cli_args.block_size, cli_args.threads);
+    //the timer starts once the password is known, so only keep the input size for now
+    let mut input_size = cli_args.reader.size();
+    let mut input_metadata = if cli_args.preserve && !cli_args.recursive {
+        cli_args.reader.file_metadata().map(|metadata| Metadata::from_file(&metadata))
+    } else {
+        None
+    };
+    if let Some(name) = &cli_args.store_name {
+        input_metadata.get_or_insert_with(Metadata::default).name = Some(name.clone());
+    }
+    let mut reader = BufReader::new(cli_args.reader);
+    if !cli_args.force_encrypt && is_armored(reader.fill_buf()?) {
+        reader = BufReader::new(WrappedReader::from_reader(ArmorReader::new(reader)));
+        input_size = None;
+    } else if !cli_args.force_encrypt && is_qr_text(reader.fill_buf()?) {
+        reader = BufReader::new(WrappedReader::from_reader(ArmorReader::new(io::Cursor::new(read_scanned(reader)?))));
+        input_size = None;
+    } else if !cli_args.force_encrypt && is_ecc(reader.fill_buf()?) {
+        reader = BufReader::new(WrappedReader::from_reader(EccReader::new(reader)?));
+        input_size = None;
+    }
+    if !cli_args.force_encrypt && cli_args.header.is_none() && is_container(reader.fill_buf()?) {
+        return Err(CONTAINER_INPUT);
+    }
+
+    //files of openssl enc can be decrypted, or converted with rekey, but never written
+    let openssl = !cli_args.force_encrypt && cli_args.header.is_none() && cli_args.mode != Mode::Encrypt && is_openssl(reader.fill_buf()?);
+    let mut magic_bytes = vec![0; MAGIC_BYTES.len()];
+    //with a detached header, INPUT only contains the ciphertext
+    let n = if cli_args.header.is_some() { 0 } else { reader.read(&mut magic_bytes)? };
+    if cli_args.mode == Mode::Rekey && cli_args.mmap {
+        return Err(Error::Usage("--mmap only applies to encryption"));
+    }
+    if cli_args.mode == Mode::Rekey && openssl {
+        outcome.operation = Some("rekey");
+        //openssl enc doesn't authenticate its ciphertexts
+        outcome.verified = Some(false);
+        if cli_args.verify_key.is_some() {
+            return Err(Error::Usage("--verify-key can't be used with OpenSSL inputs: they aren't signed"));
+        }
+        let mut bytes_in = 0;
+        let reader = ProgressReader::starting_at(rate_limited(reader, cli_args.rate_limit.as_ref()), n as u64, |n| bytes_in = n);
+        let mut reader = openssl_reader(magic_bytes[..n].chain(reader), cli_args.password, cli_args.openssl_iterations, "Current password")?;
+        let signing_key = cli_args.sign_key.as_ref();
+        let (params, cipher) = encryption_cipher(&cli_args.encryption, &cli_args.key_slots, cli_args.raw_key.as_deref(), HeaderOptions { comment: cli_args.comment.as_deref(), signer: signing_key.map(SigningKey::verify_key).as_ref(), ..Default::default() }, cli_args.new_password, &mut cli_args.salt_rng, "New password")?;
+        let mut writer = cli_args.writer.into_buf_writer_with_block_size(cli_args.block_size)?;
+        cli_args.sandbox.apply()?;
+        let stream_start = Instant::now();
+        if cli_args.armor {
+            let mut writer = ArmorWriter::new(&mut writer);
+            encrypt_to(&mut reader, &mut writer, None, &params, cipher, EncryptStream { block_size: cli_args.block_size, threads: cli_args.threads, already_read: &[], mapped: None }, signing_key)?;
+            writer.finish()?;
+        } else if let Some(parity_shards) = cli_args.ecc {
+            let mut writer = EccWriter::new(&mut writer, parity_shards)?;
+            encrypt_to(&mut reader, &mut writer, None, &params, cipher, EncryptStream { block_size: cli_args.block_size, threads: cli_args.threads, already_read: &[], mapped: None }, signing_key)?;
+            writer.finish()?;
+        } else {
+            encrypt_to(&mut reader, &mut writer, None, &params, cipher, EncryptStream { block_size: cli_args.block_size, threads: cli_args.threads, already_read: &[], mapped: None }, signing_key)?;
+        }
+        outcome.stream_time = Some(stream_start.elapsed());
+        drop(reader);
+        outcome.bytes_in = Some(bytes_in);
+        outcome.bytes = Some(writer.written());
+        return writer.finish(true);
+    }
+    if cli_args.mode == Mode::Rekey {
+        outcome.operation = Some("rekey");
+        if !is_doby_format(&magic_bytes) {
+            return Err(Error::UnknownFormat);
+        }
+        let old_params = read_params(&magic_bytes, &mut reader)?;
+        check_input_signer(&old_params, cli_args.verify_key.as_ref())?;
+        cli::confirm_argon2_costs(&old_params, &cli_args.argon2_limits)?;
+        let header_len = reader.stream_position().unwrap_or(0);
+        let mut bytes_in = 0;
+        let reader = ProgressReader::starting_at(rate_limited(reader, cli_args.rate_limit.as_ref()), header_len, |n| bytes_in = n);
+        let mut reader = SignatureReader::new(reader, &old_params, cli_args.verify_key.as_ref())?;
+        //the metadata, if any, is re-encrypted with the content
+        let old_cipher = with_retries(cli_args.password, cli_args.retries, |password| {
+            decryption_cipher(&old_params, password, &cli_args.identities, cli_args.key_slots.pkcs11.as_ref(), cli_args.raw_key.as_deref(), "Current password")
+        })?;
+        //keep the comment unless a new one is given. The old signature can't be kept: the output is only signed with --sign-key.
+        let comment = cli_args.comment.or_else(|| old_params.comment());
+        let signing_key = cli_args.sign_key.as_ref();
+        let (params, new_cipher) = encryption_cipher(&cli_args.encryption, &cli_args.key_slots, cli_args.raw_key.as_deref(), HeaderOptions {
+            metadata: old_params.metadata,
+            sparse: old_params.sparse(),
+            comment: comment.as_deref(),
+            digest: old_params.plaintext_digest().as_ref(),
+            signer: signing_key.map(SigningKey::verify_key).as_ref(),
+        }, cli_args.new_password, &mut cli_args.salt_rng, "New password")?;
+        let mut writer = cli_args.writer.into_buf_writer_with_block_size(cli_args.block_size)?;
+        cli_args.sandbox.apply()?;
+        let stream_start = Instant::now();
+        if cli_args.armor {
+            let mut writer = ArmorWriter::new(&mut writer);
+            signed(&mut writer, signing_key, |mut writer| rekey(&mut reader, &mut writer, old_cipher, &params, new_cipher, cli_args.block_size))?;
+            writer.finish()?;
+        } else if let Some(parity_shards) = cli_args.ecc {
+            let mut writer = EccWriter::new(&mut writer, parity_shards)?;
+            signed(&mut writer, signing_key, |mut writer| rekey(&mut reader, &mut writer, old_cipher, &params, new_cipher, cli_args.block_size))?;
+            writer.finish()?;
+        } else {
+            signed(&mut writer, signing_key, |mut writer| rekey(&mut reader, &mut writer, old_cipher, &params, new_cipher, cli_args.block_size))?;
+        }
+        reader.finish()?;
+        outcome.stream_time = Some(stream_start.elapsed());
+        outcome.bytes_in = Some(bytes_in);
+        outcome.verified = Some(true);
+        outcome.bytes = Some(writer.written());
+        return writer.finish(true);
+    }
+    let decrypting = match cli_args.mode {
+        _ if cli_args.header.is_some() => true,
+        Mode::Auto => (is_doby_format(&magic_bytes) || openssl) && !cli_args.force_encrypt,
+        Mode::Encrypt if is_doby_format(&magic_bytes) && !cli_args.force_encrypt => return Err(Error::AlreadyEncrypted),
+        Mode::Encrypt => false,
+        Mode::Decrypt if !is_doby_format(&magic_bytes) && !openssl => return Err(Error::UnknownFormat),
+        Mode::Decrypt | Mode::Rekey => true,
+    };
+    outcome.operation = Some(if decrypting { "decrypt" } else { "encrypt" });
+    if decrypting {
+        if cli_args.remove_input.is_some() {
+            return Err(Error::Usage("--rm and --shred only apply to encryption"));
+        }
+        if input_metadata.as_ref().is_some_and(|metadata| metadata.name.is_some()) {
+            return Err(Error::Usage("--store-name only applies to encryption"));
+        }
+        if cli_args.comment.is_some() || cli_args.digest || cli_args.detach_header.is_some() || cli_args.sign_key.is_some() {
+            return Err(Error::Usage("--comment, --digest, --detach-header and --sign-key only apply to encryption"));
+        }
+        if cli_args.mmap {
+            return Err(Error::Usage("--mmap only applies to encryption"));
+        }
+        if cli_args.resume {
+            return Err(Error::Usage("--resume only applies to encryption"));
+        }
+        if cli_args.encryption.yubikey.is_some() {
+            return Err(Error::Usage("--yubikey only applies to encryption: the slot is read from the header when decrypting"));
+        }
+        if cli_args.key_slots.tpm.is_some() {
+            return Err(Error::Usage("--tpm only applies to encryption: TPM key slots are opened automatically"));
+        }
+        if cli_args.qr.is_some() {
+            return Err(Error::Usage("--qr only applies to encryption"));
+        }
+        if openssl {
+            if cli_args.recursive || cli_args.restore_name.is_some() || cli_args.verify_key.is_some() || cli_args.verify_first {
+                return Err(Error::Usage("--recursive, --restore-name, --verify-key and --verify-first can't be used with OpenSSL inputs"));
+            }
+            let mut bytes_in = 0;
+            let reader = ProgressReader::starting_at(rate_limited(reader, cli_args.rate_limit.as_ref()), n as u64, |n| bytes_in = n);
+            let mut reader = openssl_reader(magic_bytes[..n].chain(reader), cli_args.password, cli_args.openssl_iterations, "Password")?;
+            let mut writer = cli_args.writer.into_buf_writer_with_block_size(cli_args.block_size)?;
+            cli_args.sandbox.apply()?;
+            let stream_start = Instant::now();
+            if cli_args.tar {
+                let mut writer = TarCheckWriter::new(&mut writer);
+                io::copy(&mut reader, &mut writer)?;
+                writer.finish()?;
+            } else {
+                io::copy(&mut reader, &mut writer)?;
+            }
+            outcome.stream_time = Some(stream_start.elapsed());
+            drop(reader);
+            outcome.bytes_in = Some(bytes_in);
+            outcome.verified = Some(false);
+            outcome.bytes = Some(writer.written());
+            return writer.finish(cli_args.fsync);
+        }
+        let params = match cli_args.header {
+            Some(params) => params,
+            None => read_params(&magic_bytes, &mut reader)?,
+        };
+        check_input_signer(&params, cli_args.verify_key.as_ref())?;
+        if cli_args.restore_name.is_some() && !params.metadata {
+            return Err(Error::MissingStoredName);
+        }
+        cli::confirm_argon2_costs(&params, &cli_args.argon2_limits)?;
+        let cipher = with_retries(cli_args.password, cli_args.retries, |password| {
+            decryption_cipher(&params, password, &cli_args.identities, cli_args.key_slots.pkcs11.as_ref(), cli_args.raw_key.as_deref(), "Password")
+        })?;
+        if cli_args.verify_first {
+            verify_first(&mut reader, &params, &cipher, cli_args.block_size, cli_args.verify_key.as_ref())?;
+        }
+        //the output, or the directory extracted with --recursive, is created afterwards
+        cli_args.sandbox.apply()?;
+        if cli_args.progress {
+            *progress_bar = Some(ProgressBar::new(input_size));
+        }
+        let header_len = reader.stream_position().unwrap_or(0);
+        let mut bytes_in = 0;
+        let stream_start = Instant::now();
+        let reader = ProgressReader::starting_at(rate_limited(&mut reader, cli_args.rate_limit.as_ref()), header_len, |n| {
+            bytes_in = n;
+            if let Some(bar) = progress_bar.as_mut() {
+                bar.update(n)
+            }
+        });
+        let mut reader = SignatureReader::new(reader, &params, cli_args.verify_key.as_ref())?;
+        if cli_args.recursive {
+            //like the HMAC, the signature can only be checked once the files are extracted
+            extract(&mut reader, cipher, params.metadata, cli_args.writer)?;
+            reader.finish()?;
+            outcome.stream_time = Some(stream_start.elapsed());
+            outcome.bytes_in = Some(bytes_in);
+            outcome.verified = Some(true);
+            return Ok(());
+        }
+        let output_path = cli_args.writer.path().map(String::from);
+        if cli_args.tar && output_path.is_none() && io::stdout().is_terminal() {
+            warn!("the decrypted tar archive is written to the terminal, not extracted (pipe it to tar x)");
+        }
+        let mut writer = cli_args.writer.into_buf_writer_with_block_size(cli_args.block_size)?;
+        let result = decrypt_file(&mut reader, &mut writer, &params, cipher, cli_args.block_size, cli_args.threads, cli_args.tar)
+            .and_then(|metadata| reader.finish().map(|_| metadata));
+        match result {
+            //otherwise, the partial output is deleted when the writer is dropped
+            Err(e @ (Error::HmacMismatch | Error::Truncated { .. })) if cli_args.keep_unverified => {
+                if let Some(path) = writer.quarantine()? {
+                    if !report::is_json() {
+                        info!("Unverified output kept in {}", path.display());
+                    }
+                    outcome.unverified_output = Some(path.display().to_string());
+                }
+                Err(e)
+            }
+            result => {
+                let metadata = result?;
+                outcome.stream_time = Some(stream_start.elapsed());
+                outcome.bytes_in = Some(bytes_in);
+                outcome.verified = Some(true);
+                let output_path = match cli_args.restore_name {
+                    Some(dir) => {
+                        let path = restored_path(&dir, metadata.as_ref())?;
+                        outcome.output = Some(path.clone());
+                        if !cli::confirm_overwrite(&path, cli_args.interactive)? {
+                            outcome.skipped = true;
+                            return Ok(());
                         }
+                        writer.set_destination(&path);
+                        Some(path)
                     }
+                    None => output_path,
+                };
+                outcome.bytes = Some(writer.written());
+                writer.finish(cli_args.fsync)?;
+                match (metadata, output_path) {
+                    (Some(metadata), Some(path)) if cli_args.preserve => metadata.apply(&path).map_err(|error| Error::Path { path, error }),
+                    _ => Ok(()),
                 }
-                Err(e) => eprintln!("I/O error while reading magic bytes: {}", e),
             }
+        }
+    } else { //otherwise, encrypt
+        if cli_args.restore_name.is_some() || cli_args.verify_key.is_some() {
+            return Err(Error::Usage("--restore-name and --verify-key only apply to decryption"));
+        }
+        let mut already_read = match &input_metadata {
+            Some(metadata) => metadata.to_bytes(),
+            None if cli_args.preserve => return Err(Error::Usage("--preserve requires INPUT to be a regular file when encrypting")),
+            None => Vec::new(),
+        };
+        let output_path = cli_args.writer.path().map(String::from);
+        let input_file = reader.get_ref().file_metadata();
+        //INPUT and OUTPUT are files with --resume
+        let interrupted = match (&output_path, &input_file) {
+            (Some(output), Some(input)) if cli_args.resume => interrupted_encryption(output, input, already_read.len(), cli_args.digest)?,
+            _ => None,
+        };
+        let prefix_len = already_read.len();
+        let digest = (cli_args.digest && interrupted.is_none()).then(|| plaintext_digest(&mut reader, &magic_bytes[..n])).transpose()?;
+        //the digest and the tar check need the plaintext as is, and the chunks to resume must be at the same offsets
+        let sparse = if cli_args.sparse && !cli_args.digest && !cli_args.tar && !cli_args.resume {
+            reader.get_ref().sparse_reader()?
+        } else {
+            None
+        };
+        //the map starts with the magic bytes already read. The tar check needs to read INPUT.
+        let map = if cli_args.mmap && !cli_args.tar && sparse.is_none() && reader.get_ref().is_seekable() {
+            let offset = reader.stream_position()? - n as u64;
+            reader.get_ref().map(offset)?
         } else {
-            success = !result.error;
+            None
+        };
+        if let Some(sparse) = &sparse {
+            debug!("INPUT is sparse: only its data is encrypted");
+            input_size = Some(sparse.encoded_len()?);
+        } else if map.is_some() {
+            debug!("INPUT mapped in memory");
+        } else {
+            already_read.extend_from_slice(&magic_bytes[..n]);
+        }
+        //continue the plaintext after the last chunk saved
+        let mut input_offset = n as u64;
+        if let Some((state, _)) = &interrupted {
+            let offset = state.plaintext_offset();
+            if offset <= already_read.len() as u64 {
+                already_read.drain(..offset as usize);
+            } else {
+                input_offset = offset - prefix_len as u64;
+                already_read.clear();
+                reader.seek(SeekFrom::Start(input_offset))?;
+            }
+            debug!("resuming encryption at chunk {}", state.chunks);
+        }
+        let (params, cipher) = match &interrupted {
+            Some((state, params)) => {
+                let mut cipher = with_retries(cli_args.password, cli_args.retries, |password| {
+                    decryption_cipher(params, password, &cli_args.identities, cli_args.key_slots.pkcs11.as_ref(), cli_args.raw_key.as_deref(), "Password")
+                })?;
+                cipher.seek_chunk(state.chunks)?;
+                (params.clone(), cipher)
+            }
+            None => encryption_cipher(&cli_args.encryption, &cli_args.key_slots, cli_args.raw_key.as_deref(), HeaderOptions {
+                metadata: input_metadata.is_some(),
+                sparse: sparse.is_some(),
+                comment: cli_args.comment.as_deref(),
+                digest: digest.as_ref(),
+                signer: cli_args.sign_key.as_ref().map(SigningKey::verify_key).as_ref(),
+            }, cli_args.password, &mut cli_args.salt_rng, "Password")?,
+        };
+        let mut header = cli_args.detach_header.map(|path| WrappedWriter::from_path(path).into_buf_writer()).transpose()?;
+        let mut writer = match &interrupted {
+            Some((state, _)) => cli_args.writer.into_resumed_buf_writer(cli_args.block_size, &state.partial, state.output_len())?,
+            None => cli_args.writer.into_buf_writer_with_block_size(cli_args.block_size)?,
+        };
+        let checkpoints = match (&output_path, &input_file) {
+            (Some(output), Some(input)) if cli_args.resume => {
+                let state_path = ResumeState::path(output);
+                writer.keep_on_failure();
+                let state = match interrupted {
+                    Some((state, _)) => state,
+                    None => {
+                        //progress saved for a previous output
+                        ResumeState::remove(&state_path).map_err(|error| Error::Path { path: state_path.display().to_string(), error })?;
+                        let mut header = MAGIC_BYTES.to_vec();
+                        params.write(&mut header)?;
+                        ResumeState::new(input, header.len() as u64, prefix_len as u64, writer.temporary_suffix().unwrap())
+                    }
+                };
+                Some((state, state_path))
+            }
+            _ => None,
+        };
+        cli_args.sandbox.apply()?;
+        if cli_args.progress {
+            *progress_bar = Some(ProgressBar::new(input_size));
+        }
+        let mut limit = mapped_rate_limit(cli_args.rate_limit.as_ref().filter(|_| map.is_some()));
+        let mut bytes_in = 0;
+        let stream_start = Instant::now();
+        let mut progress = |n| {
+            limit(n);
+            bytes_in = n;
+            if let Some(bar) = progress_bar.as_mut() {
+                bar.update(n)
+            }
+        };
+        let (mut reader, mapped): (Box<dyn Read + Send>, _) = match (map.as_ref(), sparse) {
+            (Some(map), _) => (Box::new(io::empty()), Some(MappedInput { plaintext: map, progress: &mut progress })),
+            (None, Some(sparse)) => (Box::new(ProgressReader::new(rate_limited(sparse, cli_args.rate_limit.as_ref()), &mut progress)), None),
+            (None, None) => {
+                let reader = ProgressReader::starting_at(rate_limited(&mut reader, cli_args.rate_limit.as_ref()), input_offset, &mut progress);
+                if cli_args.tar {
+                    (Box::new(TarCheckReader::starting_with(reader, &magic_bytes[..n])), None)
+                } else {
+                    (Box::new(reader), None)
+                }
+            }
+        };
+        if let Some(format) = cli_args.qr {
+            let mut qr = QrWriter::new(&mut writer, format);
+            let mut writer = ArmorWriter::new(&mut qr);
+            encrypt_to(&mut reader, &mut writer, header.as_mut(), &params, cipher, EncryptStream { block_size: cli_args.block_size, threads: cli_args.threads, already_read: &already_read, mapped }, cli_args.sign_key.as_ref())?;
+            writer.finish()?;
+            qr.finish()?;
+        } else if cli_args.armor {
+            let mut writer = ArmorWriter::new(&mut writer);
+            encrypt_to(&mut reader, &mut writer, header.as_mut(), &params, cipher, EncryptStream { block_size: cli_args.block_size, threads: cli_args.threads, already_read: &already_read, mapped }, cli_args.sign_key.as_ref())?;
+            writer.finish()?;
+        } else if let Some(parity_shards) = cli_args.ecc {
+            let mut writer = EccWriter::new(&mut writer, parity_shards)?;
+            encrypt_to(&mut reader, &mut writer, header.as_mut(), &params, cipher, EncryptStream { block_size: cli_args.block_size, threads: cli_args.threads, already_read: &already_read, mapped }, cli_args.sign_key.as_ref())?;
+            writer.finish()?;
+        } else if let Some((state, state_path)) = &checkpoints {
+            let header_len = state.header_len as usize;
+            let mut writer = ResumeWriter::new(&mut writer, state.clone(), state_path, CHECKPOINT_INTERVAL);
+            if state.chunks > 0 {
+                //the header is already in the partial output
+                encrypt_with_threads(&mut reader, &mut HeaderSplitter::new(io::sink(), &mut writer, header_len), &params, cipher, EncryptStream { block_size: cli_args.block_size, threads: cli_args.threads, already_read: &already_read, mapped })?;
+            } else {
+                encrypt_with_threads(&mut reader, &mut writer, &params, cipher, EncryptStream { block_size: cli_args.block_size, threads: cli_args.threads, already_read: &already_read, mapped })?;
+            }
+        } else {
+            encrypt_to(&mut reader, &mut writer, header.as_mut(), &params, cipher, EncryptStream { block_size: cli_args.block_size, threads: cli_args.threads, already_read: &already_read, mapped }, cli_args.sign_key.as_ref())?;
         }
+        outcome.stream_time = Some(stream_start.elapsed());
+        drop(reader);
+        outcome.bytes_in = Some(bytes_in);
+        if let Some(header) = header {
+            header.finish(cli_args.fsync)?;
+        }
+        outcome.bytes = Some(writer.written());
+        writer.finish(cli_args.fsync)?;
+        if let Some((_, state_path)) = checkpoints {
+            ResumeState::remove(&state_path).map_err(|error| Error::Path { path: state_path.display().to_string(), error })?;
+        }
+        if let Some(remove_input) = cli_args.remove_input {
+            if remove_input.shred {
+                shred(&remove_input.path)
+            } else {
+                fs::remove_file(&remove_input.path)
+            }.map_err(|error| Error::Path { path: remove_input.path, error })?;
+        }
+        Ok(())
     }
-    success
 }
 
+/// Aborts on panic, after erasing the keys and passwords still in memory and removing the partial outputs, instead of only unwinding the panicking thread while the others keep running.
+fn set_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        zeroize_secrets();
+        remove_temporary_outputs();
+        process::abort();
+    }));
+}
+
+/// On SIGINT or SIGTERM, removes the partial outputs and exits with 128 + the signal number, like shells report processes killed by a signal.
+///
+/// The signals are blocked in all threads but one, which waits for them: this must be called before spawning any other thread.
+#[cfg(unix)]
+fn handle_signals() {
+    //SAFETY: set is initialized by sigemptyset before use
+    let set = unsafe {
+        let mut set = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        if libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) != 0 {
+            return;
+        }
+        set
+    };
+    thread::spawn(move || {
+        let mut signal = 0;
+        //SAFETY: set and signal are valid
+        if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+            remove_temporary_outputs();
+            report::error(&Error::Interrupted(if signal == libc::SIGINT { "SIGINT" } else { "SIGTERM" }));
+            process::exit(128 + signal);
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn handle_signals() {}
+
 fn main() {
-    process::exit(if run() {
-        0
-    } else {
-        1
+    //keys are in memory for almost the whole run
+    disable_core_dumps();
+    set_panic_hook();
+    handle_signals();
+    process::exit(match run() {
+        Ok(()) => 0,
+        Err(e) => {
+            report::error(&e);
+            1
+        }
     });
 }
@@ -0,0 +1,154 @@
+//! Keeps keys and passwords out of swap and core dumps.
+//!
+//! Locking is best effort: it fails when `RLIMIT_MEMLOCK` is reached, and does nothing on platforms without `mlock` (e.g. WebAssembly). `mlock` works on whole pages, which are unlocked as soon as any `Locked` value on them is dropped.
+//!
+//! Live `Locked` values are also registered, so that they can all be erased at once by `zeroize_secrets` when the process is about to abort.
+
+use std::{ops::{Deref, DerefMut}, sync::{Mutex, MutexGuard, PoisonError, atomic::{AtomicBool, Ordering}}};
+use log::debug;
+use zeroize::Zeroize;
+
+/// Values whose bytes can be locked in memory.
+pub trait Lockable: Zeroize + 'static {
+    /// Address and length of the bytes to lock.
+    fn region(&self) -> (*const u8, usize);
+}
+
+impl<const N: usize> Lockable for [u8; N] {
+    fn region(&self) -> (*const u8, usize) {
+        (self.as_ptr(), N)
+    }
+}
+
+impl Lockable for String {
+    fn region(&self) -> (*const u8, usize) {
+        (self.as_ptr(), self.capacity())
+    }
+}
+
+/// A secret locked in memory, zeroized and unlocked when dropped.
+///
+/// Arrays are moved to the heap so that the locked region doesn't move with the value. Strings must not grow, or their bytes would be reallocated outside of the locked region.
+pub struct Locked<T: Lockable> {
+    value: Box<T>,
+    //stored as an integer to keep Locked Send and Sync
+    region: Option<(usize, usize)>,
+}
+
+//the boxed values of the live Locked
+struct Secret(*mut dyn Zeroize);
+
+//SAFETY: only dereferenced by zeroize_secrets, when the process is about to abort
+unsafe impl Send for Secret {}
+
+static SECRETS: Mutex<Vec<Secret>> = Mutex::new(Vec::new());
+
+fn secrets() -> MutexGuard<'static, Vec<Secret>> {
+    SECRETS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Zeroizes all the live `Locked` values, e.g. from a panic hook. They must not be used afterwards, so the process should abort right after.
+pub fn zeroize_secrets() {
+    for secret in secrets().iter() {
+        //SAFETY: values are unregistered before being dropped
+        unsafe { (*secret.0).zeroize(); }
+    }
+}
+
+impl<T: Lockable> Locked<T> {
+    pub fn new(value: T) -> Self {
+        let mut value = Box::new(value);
+        let (addr, len) = value.region();
+        let region = (len > 0 && lock(addr as *mut u8, len)).then_some((addr as usize, len));
+        secrets().push(Secret(&mut *value as *mut T as *mut dyn Zeroize));
+        Self { value, region }
+    }
+
+    /// Whether the value is actually locked in memory.
+    pub fn is_locked(&self) -> bool {
+        self.region.is_some()
+    }
+}
+
+impl<T: Lockable> Deref for Locked<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: Lockable> DerefMut for Locked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T: Lockable + Clone> Clone for Locked<T> {
+    fn clone(&self) -> Self {
+        Self::new((*self.value).clone())
+    }
+}
+
+impl<T: Lockable> Drop for Locked<T> {
+    fn drop(&mut self) {
+        let ptr = &*self.value as *const T as *const ();
+        let mut secrets = secrets();
+        if let Some(i) = secrets.iter().rposition(|secret| secret.0 as *const () == ptr) {
+            secrets.swap_remove(i);
+        }
+        drop(secrets);
+        self.value.zeroize();
+        if let Some((addr, len)) = self.region {
+            unlock(addr as *mut u8, len);
+        }
+    }
+}
+
+#[cfg(any(unix, windows))]
+fn lock(addr: *mut u8, len: usize) -> bool {
+    //SAFETY: the region belongs to a live allocation
+    let locked = unsafe { memsec::mlock(addr, len) };
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if !locked && !WARNED.swap(true, Ordering::Relaxed) {
+        debug!("failed to lock secrets in memory: {}", std::io::Error::last_os_error());
+    }
+    locked
+}
+
+#[cfg(any(unix, windows))]
+fn unlock(addr: *mut u8, len: usize) {
+    //SAFETY: the region was locked by Locked::new and its allocation is still alive
+    unsafe { memsec::munlock(addr, len); }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lock(_addr: *mut u8, _len: usize) -> bool {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        debug!("locking secrets in memory is not supported on this platform");
+    }
+    false
+}
+
+#[cfg(not(any(unix, windows)))]
+fn unlock(_addr: *mut u8, _len: usize) {}
+
+/// Prevents the process from writing core dumps, which would contain the keys in use. Returns whether it succeeded.
+///
+/// Only supported on Unix. `Locked` values are also excluded from core dumps on Linux and FreeBSD.
+pub fn disable_core_dumps() -> bool {
+    #[cfg(unix)]
+    {
+        let limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        //SAFETY: limit is a valid rlimit
+        let disabled = unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } == 0;
+        if !disabled {
+            debug!("failed to disable core dumps: {}", std::io::Error::last_os_error());
+        }
+        disabled
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
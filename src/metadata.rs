@@ -0,0 +1,173 @@
+use std::{fs::{self, OpenOptions}, io::{self, Read, Write}, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+pub(crate) const LEN_SIZE: usize = 2;
+const RECORD_MODIFIED: u8 = 1;
+const RECORD_MODE: u8 = 2;
+const RECORD_NAME: u8 = 3;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// File attributes stored at the beginning of the plaintext, so that they are encrypted and authenticated like the content.
+///
+/// Encoded as a 2 bytes length followed by (type, 2 bytes length, value) records. Unknown records are ignored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub modified: Option<SystemTime>,
+    /// Unix permission bits.
+    pub mode: Option<u32>,
+    /// Original file name, without any directory, or path of a container entry.
+    pub name: Option<String>,
+}
+
+impl Metadata {
+    pub fn from_file(metadata: &fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let mode = Some(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777);
+        #[cfg(not(unix))]
+        let mode = None;
+        Self {
+            modified: metadata.modified().ok(),
+            mode,
+            name: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        if let Some(modified) = self.modified {
+            let (secs, nanos) = match modified.duration_since(UNIX_EPOCH) {
+                Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+                //before 1970: round down to the previous second
+                Err(e) => {
+                    let d = e.duration();
+                    match d.subsec_nanos() {
+                        0 => (-(d.as_secs() as i64), 0),
+                        nanos => (-(d.as_secs() as i64)-1, 1_000_000_000-nanos),
+                    }
+                }
+            };
+            let mut value = secs.to_be_bytes().to_vec();
+            value.extend_from_slice(&nanos.to_be_bytes());
+            push_record(&mut records, RECORD_MODIFIED, &value);
+        }
+        if let Some(mode) = self.mode {
+            push_record(&mut records, RECORD_MODE, &mode.to_be_bytes());
+        }
+        if let Some(name) = &self.name {
+            push_record(&mut records, RECORD_NAME, name.as_bytes());
+        }
+        let mut bytes = (records.len() as u16).to_be_bytes().to_vec();
+        bytes.append(&mut records);
+        bytes
+    }
+
+    /// Length of the encoded metadata whose first `LEN_SIZE` bytes are `prefix`.
+    pub(crate) fn encoded_len(prefix: [u8; LEN_SIZE]) -> usize {
+        LEN_SIZE + u16::from_be_bytes(prefix) as usize
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut len = [0; LEN_SIZE];
+        reader.read_exact(&mut len)?;
+        let mut records = vec![0; u16::from_be_bytes(len) as usize];
+        reader.read_exact(&mut records)?;
+        Self::parse(&records)
+    }
+
+    /// Parses the records following the length.
+    fn parse(mut records: &[u8]) -> io::Result<Self> {
+        let mut metadata = Self::default();
+        while !records.is_empty() {
+            if records.len() < 3 {
+                return Err(invalid_data("truncated metadata"));
+            }
+            let len = u16::from_be_bytes([records[1], records[2]]) as usize;
+            let value = records.get(3..3+len).ok_or_else(|| invalid_data("truncated metadata"))?;
+            match records[0] {
+                RECORD_MODIFIED if len == 12 => {
+                    let secs = i64::from_be_bytes(value[..8].try_into().unwrap());
+                    let nanos = u32::from_be_bytes(value[8..].try_into().unwrap());
+                    metadata.modified = if secs >= 0 {
+                        UNIX_EPOCH.checked_add(Duration::new(secs as u64, nanos))
+                    } else {
+                        UNIX_EPOCH.checked_sub(Duration::new(secs.unsigned_abs(), 0)).and_then(|t| t.checked_add(Duration::new(0, nanos)))
+                    };
+                }
+                RECORD_MODE if len == 4 => metadata.mode = Some(u32::from_be_bytes(value.try_into().unwrap())),
+                RECORD_NAME => metadata.name = Some(String::from_utf8(value.to_vec()).map_err(|_| invalid_data("invalid file name in metadata"))?),
+                _ => {}
+            }
+            records = &records[3+len..];
+        }
+        Ok(metadata)
+    }
+
+    /// Sets the modification time and the permissions of `path`.
+    pub fn apply<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if let Some(modified) = self.modified {
+            //before the permissions, which could make the file read-only
+            OpenOptions::new().write(true).open(path.as_ref())?.set_modified(modified)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path.as_ref(), std::os::unix::fs::PermissionsExt::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
+
+fn push_record(records: &mut Vec<u8>, record_type: u8, value: &[u8]) {
+    records.push(record_type);
+    records.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    records.extend_from_slice(value);
+}
+
+/// Removes the `Metadata` from the beginning of a decrypted stream and writes the rest to the inner writer.
+pub struct MetadataWriter<W: Write> {
+    writer: W,
+    buff: Vec<u8>,
+    metadata: Option<Metadata>,
+}
+
+impl<W: Write> MetadataWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            buff: Vec::with_capacity(LEN_SIZE),
+            metadata: None,
+        }
+    }
+
+    fn metadata_len(&self) -> usize {
+        if self.buff.len() < LEN_SIZE {
+            LEN_SIZE
+        } else {
+            Metadata::encoded_len([self.buff[0], self.buff[1]])
+        }
+    }
+
+    /// Returns the metadata, which must not be trusted before the whole stream has been authenticated.
+    pub fn into_metadata(self) -> io::Result<Metadata> {
+        self.metadata.ok_or_else(|| invalid_data("truncated metadata"))
+    }
+}
+
+impl<W: Write> Write for MetadataWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.metadata.is_some() {
+            return self.writer.write(buf);
+        }
+        let n = (self.metadata_len() - self.buff.len()).min(buf.len());
+        self.buff.extend_from_slice(&buf[..n]);
+        if self.buff.len() == self.metadata_len() {
+            self.metadata = Some(Metadata::parse(&self.buff[LEN_SIZE..])?);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
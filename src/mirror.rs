@@ -0,0 +1,399 @@
+use std::{collections::BTreeMap, fs, io, path::{Path, PathBuf}, time::UNIX_EPOCH};
+use blake2::Blake2b;
+use hkdf::Hkdf;
+use zeroize::Zeroizing;
+use crate::{Error, PlaintextDigest, crypto::{KEY_LEN, decode_hex}, entry_name, is_same_file};
+#[cfg(feature = "cli")]
+use {
+    std::{fs::File, io::Seek, mem},
+    log::debug,
+    rand::rngs::OsRng,
+    crate::{
+        DEFAULT_BLOCK_SIZE,
+        WrappedWriter,
+        auto_block_size,
+        cli::BatchArgs,
+        crypto::{DobyCipher, KeyDerivation},
+        files::{EncryptStream, SharedKey, decrypt_file, decrypt_to, encrypt_to, open_encrypted, rate_limited, run_batch},
+        keying::{master_key, wrapped_key_params},
+        signature::SignatureReader,
+    },
+};
+
+/// File at the root of a mirror holding its manifest. It's encrypted with the key shared by all the files of the mirror, so that later runs can open it to reuse this key.
+pub const MIRROR_FILE: &str = ".doby-mirror";
+const MIRROR_MAGIC: &str = "doby-mirror 1";
+const ENCRYPTED_NAMES: &str = "encrypted-names";
+const FILE: &str = "file";
+//bytes of the keyed hash naming the files of mirrors with encrypted names
+const NAME_HASH_LEN: usize = 16;
+
+/// State of a source file when it was last encrypted into a mirror.
+#[derive(Clone, Debug)]
+pub struct MirrorEntry {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch, `None` if unknown or before 1970.
+    pub modified: Option<u128>,
+    /// Keyed digest of the content, to recognize files whose modification time changed but not their content.
+    pub digest: PlaintextDigest,
+}
+
+fn modified_nanos(metadata: &fs::Metadata) -> Option<u128> {
+    metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_nanos())
+}
+
+impl MirrorEntry {
+    pub fn new(metadata: &fs::Metadata, digest: PlaintextDigest) -> Self {
+        Self { size: metadata.len(), modified: modified_nanos(metadata), digest }
+    }
+
+    /// Whether the file of `metadata` has the size and the modification time recorded. Its content then isn't read, like with rsync.
+    pub fn unchanged(&self, metadata: &fs::Metadata) -> bool {
+        self.size == metadata.len() && self.modified.is_some() && self.modified == modified_nanos(metadata)
+    }
+}
+
+//paths are the last field of their line
+fn escape_path(path: &str) -> String {
+    path.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape_path(escaped: &str) -> Option<String> {
+    let mut path = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        path.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(path)
+}
+
+/// Settings of a mirror, chosen when it's created, and the state of its files when they were encrypted, so that the next runs skip those that didn't change. It's authenticated along with the key of the mirror in `MIRROR_FILE`.
+///
+/// Stored as lines of text, the first one identifying the format. Unknown lines are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct MirrorManifest {
+    /// Files are named after a keyed hash of their path, which is stored in their metadata.
+    pub encrypted_names: bool,
+    /// Source files by path relative to the root of the tree (see `entry_name`).
+    pub files: BTreeMap<String, MirrorEntry>,
+}
+
+impl MirrorManifest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = format!("{}\n", MIRROR_MAGIC);
+        if self.encrypted_names {
+            text.push_str(ENCRYPTED_NAMES);
+            text.push('\n');
+        }
+        for (path, entry) in &self.files {
+            let modified = entry.modified.map_or_else(|| "-".to_string(), |modified| modified.to_string());
+            let digest: String = entry.digest.to_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+            text.push_str(&format!("{} {} {} {} {}\n", FILE, entry.size, modified, digest, escape_path(path)));
+        }
+        text.into_bytes()
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let text = std::str::from_utf8(bytes).map_err(|_| Error::InvalidMirror)?;
+        let mut lines = text.lines();
+        if lines.next() != Some(MIRROR_MAGIC) {
+            return Err(Error::InvalidMirror);
+        }
+        let mut manifest = Self::default();
+        for line in lines {
+            if line == ENCRYPTED_NAMES {
+                manifest.encrypted_names = true;
+            } else if let Some(fields) = line.strip_prefix(FILE).and_then(|line| line.strip_prefix(' ')) {
+                let (path, entry) = parse_entry(fields).ok_or(Error::InvalidMirror)?;
+                manifest.files.insert(path, entry);
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+fn parse_entry(fields: &str) -> Option<(String, MirrorEntry)> {
+    let mut fields = fields.splitn(4, ' ');
+    let size = fields.next()?.parse().ok()?;
+    let modified = match fields.next()? {
+        "-" => None,
+        modified => Some(modified.parse().ok()?),
+    };
+    let digest = PlaintextDigest::from_bytes(decode_hex::<{ PlaintextDigest::ENCODED_LEN }>(fields.next()?)?.as_ref())?;
+    Some((unescape_path(fields.next()?)?, MirrorEntry { size, modified, digest }))
+}
+
+/// Path of the file `name` inside a mirror with encrypted names: a keyed BLAKE2b hash of `name`, so that it stays the same from one run to the next without revealing anything about `name`. Like git objects, the first 2 hexadecimal digits are used as a directory so that none gets too large.
+pub fn encrypted_path(master_key: &[u8; KEY_LEN], name: &str) -> String {
+    let mut key = Zeroizing::new([0; KEY_LEN]);
+    Hkdf::<Blake2b>::new(None, master_key).expand(b"doby_mirror_names", key.as_mut()).unwrap();
+    let hash = blake2b_simd::Params::new().hash_length(NAME_HASH_LEN).key(key.as_ref()).hash(name.as_bytes());
+    let hex: String = hash.as_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}/{}", &hex[..2], &hex[2..])
+}
+
+fn walk(root: &Path, relative: &Path, exclude: Option<&Path>, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut children = fs::read_dir(root.join(relative))?
+        .map(|entry| entry.map(|e| relative.join(e.file_name())))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    children.sort();
+    for child in children {
+        let path = root.join(&child);
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_dir() && !exclude.is_some_and(|exclude| is_same_file(&path, exclude)) {
+            walk(root, &child, exclude, files)?;
+        } else if file_type.is_file() && child != Path::new(MIRROR_FILE) {
+            files.push(child);
+        }
+    }
+    Ok(())
+}
+
+/// Regular files of the tree of `root`, as their path relative to it (see `entry_name`), sorted. Symbolic links aren't followed, and the `MIRROR_FILE` of `root` as well as the directory `exclude` (e.g. a mirror inside the tree) are left out.
+pub fn mirror_files(root: &Path, exclude: Option<&Path>) -> Result<Vec<String>, Error> {
+    let mut files = Vec::new();
+    walk(root, Path::new(""), exclude, &mut files).map_err(|error| Error::Path { path: root.display().to_string(), error })?;
+    files.iter().map(entry_name).collect()
+}
+
+/// Opens the `MIRROR_FILE` of `dir`, returning the key shared by the files of the mirror along with its manifest, or `None` if `dir` isn't a mirror.
+#[cfg(feature = "cli")]
+fn open_mirror(dir: &Path, args: &mut BatchArgs) -> Result<Option<(SharedKey, MirrorManifest)>, Error> {
+    let path = dir.join(MIRROR_FILE);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(Error::Path { path: path.display().to_string(), error }),
+    };
+    let (params, mut reader) = open_encrypted(file)?;
+    args.argon2_limits.check(&params)?;
+    let master_key = master_key(&params, mem::take(&mut args.password), &args.identities, args.key_slots.pkcs11.as_ref(), args.raw_key.as_deref(), "Password")?;
+    let mut manifest = Vec::new();
+    decrypt_to(&mut reader, &mut manifest, DobyCipher::with_master_key(&master_key, &params)?, DEFAULT_BLOCK_SIZE, 1, None)?;
+    let manifest = MirrorManifest::parse(&manifest)?;
+    let key_slots = match &params.key_derivation {
+        KeyDerivation::KeySlots(key_slots) => key_slots.clone(),
+        KeyDerivation::RawKey => Vec::new(),
+        KeyDerivation::Password(_) => return Err(Error::InvalidMirror),
+    };
+    let key = SharedKey {
+        master_key,
+        key_slots,
+        kms_blob: params.kms_blob().map(<[u8]>::to_vec),
+        nfc_passwords: params.nfc_passwords(),
+    };
+    Ok(Some((key, manifest)))
+}
+
+/// Writes the `MIRROR_FILE` of `dir`, encrypted with the key of the mirror.
+#[cfg(feature = "cli")]
+fn write_mirror_file(dir: &Path, key: &SharedKey, manifest: &MirrorManifest, args: &BatchArgs) -> Result<(), Error> {
+    let mut params = wrapped_key_params(key.key_slots.clone(), key.kms_blob.as_deref(), args.encryption.cipher, key.nfc_passwords, &mut OsRng)?;
+    params.mac = args.encryption.mac;
+    let cipher = DobyCipher::with_master_key(&key.master_key, &params)?;
+    let mut writer = WrappedWriter::from_path(dir.join(MIRROR_FILE).display().to_string()).into_buf_writer()?;
+    encrypt_to(&mut manifest.to_bytes().as_slice(), &mut writer, None, &params, cipher, EncryptStream { block_size: DEFAULT_BLOCK_SIZE, threads: 1, already_read: &[], mapped: None }, None)?;
+    writer.finish(true)
+}
+
+/// File processed by `mirror`. `output` is `None` when it's only known once decrypted, i.e. with encrypted names. `result` is `None` for unchanged files.
+#[cfg(feature = "cli")]
+pub struct Mirrored {
+    pub input: String,
+    pub output: Option<String>,
+    pub result: Result<Option<u64>, Error>,
+}
+
+#[cfg(feature = "cli")]
+fn mirrored(inputs: Vec<String>, outputs: Vec<Option<String>>, results: Vec<Result<Option<u64>, Error>>) -> Vec<Mirrored> {
+    inputs.into_iter().zip(outputs).zip(results).map(|((input, output), result)| Mirrored { input, output, result }).collect()
+}
+
+/// Writer of `path`, creating its parent directories.
+#[cfg(feature = "cli")]
+fn mirror_writer(path: &Path) -> Result<Option<WrappedWriter<String>>, Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|error| Error::Path { path: parent.display().to_string(), error })?;
+    }
+    Ok(Some(WrappedWriter::from_path(path.display().to_string())))
+}
+
+#[cfg(feature = "cli")]
+enum MirrorUpdate {
+    /// Number of bytes written.
+    Encrypted(u64, MirrorEntry),
+    /// Only the modification time changed.
+    Unchanged(MirrorEntry),
+}
+
+/// Times a file of the tree is read again when it was modified while being encrypted into the mirror.
+#[cfg(feature = "cli")]
+const MIRROR_RETRIES: usize = 3;
+
+/// Whether `before` and `after` are the same version of a file, by its size and modification time.
+#[cfg(feature = "cli")]
+fn same_version(before: &fs::Metadata, after: &fs::Metadata) -> bool {
+    before.len() == after.len() && before.modified().ok() == after.modified().ok()
+}
+
+/// Encrypts a file of the tree into the mirror, unless the digest recorded in `entry` shows that its content didn't change since it was last encrypted. `name` is its path in the tree, stored in the output if `args.store_name` is set.
+///
+/// The digest and the output come from two reads of the file: if it was modified in between, it's read again, and the output is removed if it never stayed the same long enough.
+#[cfg(feature = "cli")]
+fn update_mirror_file(input: &str, writer: WrappedWriter<String>, name: &str, entry: Option<&MirrorEntry>, key: &SharedKey, args: &BatchArgs) -> Result<MirrorUpdate, Error> {
+    let output = writer.path().unwrap().to_string();
+    let output_exists = Path::new(&output).is_file();
+    let mut writer = Some(writer);
+    for _ in 0..MIRROR_RETRIES {
+        let mut file = File::open(input)?;
+        let metadata = file.metadata()?;
+        //once written by a previous attempt, the output may not match the recorded digest anymore
+        let update = match entry.filter(|entry| writer.is_some() && output_exists && entry.size == metadata.len()) {
+            Some(entry) if entry.digest.matches(&mut file)? => MirrorUpdate::Unchanged(MirrorEntry::new(&metadata, entry.digest.clone())),
+            _ => {
+                file.rewind()?;
+                let digest = PlaintextDigest::compute(&mut file)?;
+                let writer = writer.take().unwrap_or_else(|| WrappedWriter::from_path(output.clone()));
+                let written = key.encrypt(input, writer, args, args.store_name.then_some(name))?;
+                MirrorUpdate::Encrypted(written, MirrorEntry::new(&metadata, digest))
+            }
+        };
+        if same_version(&metadata, &fs::metadata(input)?) {
+            return Ok(update);
+        }
+        debug!("{} was modified while being read: reading it again", input);
+    }
+    if writer.is_none() {
+        fs::remove_file(&output)?;
+    }
+    Err(Error::InputModified)
+}
+
+/// Encrypts the files of `src` into the mirror `dst`, with the key and the settings of its `MIRROR_FILE`. A new mirror is created if `dst` doesn't exist or is empty.
+///
+/// Like rsync, files whose size and modification time are those recorded in the manifest are skipped without being read, unless their output is missing. The manifest is updated once all the files have been processed. Entries of files removed from `src` are kept, along with their outputs.
+#[cfg(feature = "cli")]
+pub fn update_mirror(src: &Path, dst: &Path, encrypt_names: bool, args: &mut BatchArgs) -> Result<Vec<Mirrored>, Error> {
+    let (key, mut manifest) = match open_mirror(dst, args)? {
+        Some((_, manifest)) if encrypt_names && !manifest.encrypted_names => {
+            return Err(Error::Usage("DST is a mirror without encrypted names: --encrypt-names only applies to new mirrors"));
+        }
+        Some(mirror) => mirror,
+        None => {
+            if fs::read_dir(dst).is_ok_and(|mut entries| entries.next().is_some()) {
+                return Err(Error::Usage("DST isn't empty and isn't a mirror"));
+            }
+            fs::create_dir_all(dst).map_err(|error| Error::Path { path: dst.display().to_string(), error })?;
+            let key = SharedKey::new(args)?;
+            let manifest = MirrorManifest { encrypted_names: encrypt_names, ..Default::default() };
+            write_mirror_file(dst, &key, &manifest, args)?;
+            (key, manifest)
+        }
+    };
+    //the paths hidden by encrypted names are stored in the files
+    args.store_name = manifest.encrypted_names;
+    let files = mirror_files(src, Some(dst))?;
+    let inputs: Vec<String> = files.iter().map(|name| src.join(name).display().to_string()).collect();
+    let outputs: Vec<String> = files.iter().map(|name| {
+        let path = if manifest.encrypted_names { encrypted_path(&key.master_key, name) } else { name.clone() };
+        dst.join(format!("{}.doby", path)).display().to_string()
+    }).collect();
+    let writers = files.iter().zip(&inputs).zip(&outputs).map(|((name, input), output)| {
+        let unchanged = manifest.files.get(name).is_some_and(|entry| fs::metadata(input).is_ok_and(|metadata| entry.unchanged(&metadata)));
+        if unchanged && Path::new(output).is_file() {
+            Ok(None)
+        } else {
+            mirror_writer(Path::new(output))
+        }
+    }).collect();
+    let args = &*args;
+    let results = run_batch(&inputs, writers, args.jobs, |input, writer| {
+        let name = entry_name(Path::new(input).strip_prefix(src).unwrap())?;
+        update_mirror_file(input, writer, &name, manifest.files.get(&name), &key, args)
+    });
+    let mut updated = false;
+    let results = files.into_iter().zip(results).map(|(name, result)| {
+        let (written, entry) = match result? {
+            Some(MirrorUpdate::Encrypted(written, entry)) => (Some(written), entry),
+            Some(MirrorUpdate::Unchanged(entry)) => (None, entry),
+            None => return Ok(None),
+        };
+        manifest.files.insert(name, entry);
+        updated = true;
+        Ok(written)
+    }).collect();
+    if updated {
+        write_mirror_file(dst, &key, &manifest, args)?;
+    }
+    Ok(mirrored(inputs, outputs.into_iter().map(Some).collect(), results))
+}
+
+/// Decrypts the files of the mirror `src` into `dst`.
+#[cfg(feature = "cli")]
+pub fn restore_mirror(src: &Path, dst: &Path, args: &mut BatchArgs) -> Result<Vec<Mirrored>, Error> {
+    let (key, manifest) = open_mirror(src, args)?.ok_or(Error::Usage("SRC isn't a mirror: it has no .doby-mirror file"))?;
+    let files: Vec<String> = mirror_files(src, Some(dst))?.into_iter().filter(|name| name.ends_with(".doby")).collect();
+    let inputs: Vec<String> = files.iter().map(|name| src.join(name).display().to_string()).collect();
+    //with encrypted names, outputs are only known once decrypted
+    let outputs: Vec<Option<String>> = files.iter()
+        .map(|name| (!manifest.encrypted_names).then(|| dst.join(name.strip_suffix(".doby").unwrap()).display().to_string()))
+        .collect();
+    let writers = files.iter().zip(&outputs).map(|(name, output)| match output {
+        Some(output) => mirror_writer(Path::new(output)),
+        None => mirror_writer(&dst.join(name.replace('/', "-"))),
+    }).collect();
+    let args = &*args;
+    let results = run_batch(&inputs, writers, args.jobs, |input, writer| {
+        restore_mirror_file(input, writer, &key, manifest.encrypted_names.then_some(dst), args)
+    });
+    Ok(mirrored(inputs, outputs, results))
+}
+
+/// Decrypts a file of a mirror with the key of the mirror. With `names_dir`, the output is moved to the path stored in `input`, inside this directory. Returns the number of bytes written.
+#[cfg(feature = "cli")]
+fn restore_mirror_file(input: &str, writer: WrappedWriter<String>, key: &SharedKey, names_dir: Option<&Path>, args: &BatchArgs) -> Result<u64, Error> {
+    let file = File::open(input)?;
+    let block_size = args.block_size.unwrap_or_else(|| auto_block_size(file.metadata().ok().as_ref()));
+    let (params, reader) = open_encrypted(file)?;
+    let mirror_key = match &params.key_derivation {
+        KeyDerivation::KeySlots(key_slots) => *key_slots == key.key_slots,
+        KeyDerivation::RawKey => key.key_slots.is_empty(),
+        KeyDerivation::Password(_) => false,
+    };
+    if !mirror_key {
+        return Err(Error::NoMatchingKeySlot);
+    }
+    let mut reader = SignatureReader::new(rate_limited(reader, args.rate_limit.as_ref()), &params, args.verify_key.as_ref())?;
+    let cipher = DobyCipher::with_master_key(&key.master_key, &params)?;
+    let mut output_path = writer.path().unwrap().to_string();
+    let mut writer = writer.into_buf_writer_with_block_size(block_size)?;
+    let metadata = decrypt_file(&mut reader, &mut writer, &params, cipher, block_size, args.threads, false)?;
+    reader.finish()?;
+    if let Some(dir) = names_dir {
+        let name = metadata.as_ref().and_then(|metadata| metadata.name.as_deref()).ok_or(Error::MissingStoredName)?;
+        //never write outside of dir
+        if entry_name(name).ok().as_deref() != Some(name) {
+            return Err(Error::InvalidStoredName(name.to_string()));
+        }
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| Error::Path { path: parent.display().to_string(), error })?;
+        }
+        output_path = path.display().to_string();
+        writer.set_destination(&output_path);
+    }
+    let written = writer.written();
+    writer.finish(args.fsync)?;
+    if let Some(metadata) = metadata.filter(|_| args.preserve) {
+        metadata.apply(&output_path).map_err(|error| Error::Path { path: output_path, error })?;
+    }
+    Ok(written)
+}
@@ -0,0 +1,95 @@
+use std::path::Path;
+use crate::Error;
+
+const PLACEHOLDERS: [&str; 3] = ["{file}", "{name}", "{ext}"];
+
+/// Output file name of an input, like `{name}.{ext}.doby`.
+///
+/// `{file}` is replaced by the input file name, `{name}` by the file name without its extension and `{ext}` by the extension. When decrypting, the text before the first placeholder and after the last one is stripped from the input file name instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameTemplate(String);
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self, Error> {
+        let invalid = |reason| Err(Error::InvalidNameTemplate(format!("{}: {}", template, reason)));
+        if template.contains(['/', std::path::MAIN_SEPARATOR]) {
+            return invalid("it must be a file name, not a path");
+        }
+        let mut rest = template;
+        let mut has_placeholder = false;
+        while let Some(start) = rest.find('{') {
+            match PLACEHOLDERS.iter().find(|placeholder| rest[start..].starts_with(*placeholder)) {
+                Some(placeholder) => {
+                    has_placeholder = true;
+                    rest = &rest[start+placeholder.len()..];
+                }
+                None => return invalid("unknown placeholder (expected {file}, {name} or {ext})"),
+            }
+        }
+        if !has_placeholder {
+            return invalid("at least one placeholder is required");
+        }
+        Ok(Self(template.to_string()))
+    }
+
+    /// Template appending `suffix` to the input file name.
+    pub fn with_suffix(suffix: &str) -> Result<Self, Error> {
+        Self::parse(&format!("{{file}}{}", suffix.replace(['{', '}'], "")))
+    }
+
+    /// Text before the first placeholder and after the last one.
+    fn affixes(&self) -> (&str, &str) {
+        let start = self.0.find('{').unwrap();
+        let end = self.0.rfind('}').unwrap()+1;
+        (&self.0[..start], &self.0[end..])
+    }
+
+    /// Output file name when encrypting `file_name`.
+    pub fn apply(&self, file_name: &str) -> String {
+        let (name, ext) = match file_name.rsplit_once('.') {
+            Some((name, ext)) if !name.is_empty() => (name, Some(ext)),
+            _ => (file_name, None),
+        };
+        let template = match ext {
+            Some(_) => self.0.clone(),
+            //avoid a dangling dot
+            None => self.0.replace(".{ext}", ""),
+        };
+        template
+            .replace("{file}", file_name)
+            .replace("{name}", name)
+            .replace("{ext}", ext.unwrap_or(""))
+    }
+
+    /// Output file name when decrypting `file_name`, or `None` if it doesn't match the template.
+    pub fn strip(&self, file_name: &str) -> Option<String> {
+        let (prefix, suffix) = self.affixes();
+        file_name
+            .strip_prefix(prefix)
+            .and_then(|name| name.strip_suffix(suffix))
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+    }
+
+    /// Output path of `input`, in `output_dir` if given or next to `input` otherwise.
+    pub fn output_path(&self, input: &str, output_dir: Option<&str>, decrypting: bool) -> Result<String, Error> {
+        let path = Path::new(input);
+        let file_name = path.file_name().and_then(|name| name.to_str()).ok_or(Error::Usage("inputs must be files"))?;
+        let output_name = if decrypting {
+            self.strip(file_name).ok_or_else(|| Error::NameTemplateMismatch { name: file_name.to_string(), template: self.0.clone() })?
+        } else {
+            self.apply(file_name)
+        };
+        let dir = match output_dir {
+            Some(dir) => Path::new(dir),
+            None => path.parent().unwrap_or_else(|| Path::new("")),
+        };
+        Ok(dir.join(output_name).to_string_lossy().into_owned())
+    }
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        Self(String::from("{file}.doby"))
+    }
+}
@@ -0,0 +1,121 @@
+use std::io::{self, Read};
+use aes::{Aes256, BlockDecrypt, NewBlockCipher, cipher::generic_array::GenericArray};
+use sha2::Sha256;
+use zeroize::Zeroize;
+use crate::Error;
+
+/// Start of the files written by `openssl enc` with a password.
+pub const OPENSSL_MAGIC: &[u8; 8] = b"Salted__";
+/// Default of `openssl enc -pbkdf2` when `-iter` isn't given.
+pub const DEFAULT_PBKDF2_ITERATIONS: u32 = 10_000;
+const SALT_LEN: usize = 8;
+const BLOCK_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const READ_LEN: usize = 65536;
+
+pub fn is_openssl(first_bytes: &[u8]) -> bool {
+    first_bytes.starts_with(OPENSSL_MAGIC)
+}
+
+fn bad_decrypt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Error::OpenSslDecryption)
+}
+
+/// Decrypts a file written by `openssl enc -aes-256-cbc -pbkdf2`, the key and IV being derived with PBKDF2-HMAC-SHA256.
+///
+/// The format isn't authenticated: a wrong password or a corrupted file can only be detected by the padding, at the end of the stream.
+pub struct OpenSslReader<R: Read> {
+    reader: R,
+    cipher: Aes256,
+    previous_block: [u8; BLOCK_LEN],
+    //incomplete block read from `reader`
+    encrypted: Vec<u8>,
+    //the last block holds the padding, so it is only returned at the end of the stream
+    decrypted: Vec<u8>,
+    finished: bool,
+}
+
+impl<R: Read> OpenSslReader<R> {
+    pub fn new(mut reader: R, password: &[u8], iterations: u32) -> Result<Self, Error> {
+        let mut header = [0; OPENSSL_MAGIC.len()+SALT_LEN];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::UnknownFormat,
+            _ => e.into(),
+        })?;
+        if !is_openssl(&header) {
+            return Err(Error::UnknownFormat);
+        }
+        let mut key_iv = [0; KEY_LEN+BLOCK_LEN];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password, &header[OPENSSL_MAGIC.len()..], iterations, &mut key_iv);
+        let cipher = Aes256::new(GenericArray::from_slice(&key_iv[..KEY_LEN]));
+        let previous_block = key_iv[KEY_LEN..].try_into().unwrap();
+        key_iv.zeroize();
+        Ok(Self {
+            reader,
+            cipher,
+            previous_block,
+            encrypted: Vec::with_capacity(READ_LEN+BLOCK_LEN),
+            decrypted: Vec::new(),
+            finished: false,
+        })
+    }
+
+    fn decrypt_blocks(&mut self) {
+        let complete_len = self.encrypted.len() - self.encrypted.len() % BLOCK_LEN;
+        for block in self.encrypted[..complete_len].chunks_exact(BLOCK_LEN) {
+            let mut plaintext = GenericArray::clone_from_slice(block);
+            self.cipher.decrypt_block(&mut plaintext);
+            self.decrypted.extend(plaintext.iter().zip(self.previous_block).map(|(a, b)| a ^ b));
+            self.previous_block.copy_from_slice(block);
+        }
+        self.encrypted.drain(..complete_len);
+    }
+
+    //checks and removes the PKCS#7 padding
+    fn unpad(&mut self) -> io::Result<()> {
+        if !self.encrypted.is_empty() || self.decrypted.len() < BLOCK_LEN {
+            return Err(bad_decrypt());
+        }
+        let padding_len = *self.decrypted.last().unwrap() as usize;
+        let data_len = self.decrypted.len().wrapping_sub(padding_len);
+        if padding_len == 0 || padding_len > BLOCK_LEN || self.decrypted[data_len..].iter().any(|b| *b as usize != padding_len) {
+            return Err(bad_decrypt());
+        }
+        self.decrypted.truncate(data_len);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for OpenSslReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.finished && self.decrypted.len() <= BLOCK_LEN {
+            let start = self.encrypted.len();
+            self.encrypted.resize(start+READ_LEN, 0);
+            let n = match self.reader.read(&mut self.encrypted[start..]) {
+                Ok(n) => n,
+                Err(e) => {
+                    self.encrypted.truncate(start);
+                    return Err(e);
+                }
+            };
+            self.encrypted.truncate(start+n);
+            if n == 0 {
+                self.unpad()?;
+                self.finished = true;
+            } else {
+                self.decrypt_blocks();
+            }
+        }
+        let available = if self.finished { self.decrypted.len() } else { self.decrypted.len()-BLOCK_LEN };
+        let n = available.min(buf.len());
+        buf[..n].copy_from_slice(&self.decrypted[..n]);
+        self.decrypted.drain(..n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> Drop for OpenSslReader<R> {
+    fn drop(&mut self) {
+        self.decrypted.zeroize();
+    }
+}
@@ -0,0 +1,584 @@
+use std::{fmt::Display, fs::{self, File, OpenOptions}, io::{self, BufWriter, IoSlice, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, process::ChildStdin, sync::{Mutex, MutexGuard, PoisonError}};
+#[cfg(unix)]
+use memmap2::Advice;
+use memmap2::{Mmap, MmapOptions};
+use rand::{Rng, RngCore, distributions::Alphanumeric};
+use crate::{Error, NameTemplate, SparseReader, child::{ChildSink, abort_child_sinks}, crypto::CipherAlgorithm, has_holes};
+
+cpufeatures::new!(aes_ni, "aes");
+
+/// Cipher used when `--cipher` isn't given: AES-CTR if the CPU has AES instructions, XChaCha20 otherwise.
+pub fn default_cipher() -> CipherAlgorithm {
+    if aes_ni::get() {
+        CipherAlgorithm::AesCtr
+    } else {
+        CipherAlgorithm::XChaCha20
+    }
+}
+
+/// Block size used for inputs of unknown size, such as pipes, which rarely deliver more than 64KiB at once.
+pub const DEFAULT_BLOCK_SIZE: usize = 65536;
+/// Inputs up to this size are read into a single block of their own size.
+const SMALL_INPUT_SIZE: u64 = 1 << 20;
+/// Inputs from this size on get `LARGE_BLOCK_SIZE` blocks if they are stored on a non-rotational disk.
+const LARGE_INPUT_SIZE: u64 = 64 << 20;
+const LARGE_BLOCK_SIZE: usize = 4 << 20;
+//default capacity of BufWriter, kept for smaller block sizes
+const MIN_OUTPUT_BUFFER_SIZE: usize = 8192;
+
+/// Block size used when `--block-size` isn't given, according to the metadata of the input if it's a regular file.
+///
+/// Below 8KiB, encryption is slowed down by the per-block overhead, and from 32KiB to 512KiB the block size makes little difference once the input is cached. Larger blocks only pay off with fewer and larger reads from fast disks, and blocks larger than the input only waste memory.
+pub fn auto_block_size(input: Option<&fs::Metadata>) -> usize {
+    match input.filter(|metadata| metadata.is_file()) {
+        Some(metadata) if metadata.len() <= SMALL_INPUT_SIZE => (metadata.len() as usize).max(1),
+        Some(metadata) if metadata.len() >= LARGE_INPUT_SIZE && is_rotational(metadata) == Some(false) => LARGE_BLOCK_SIZE,
+        _ => DEFAULT_BLOCK_SIZE,
+    }
+}
+
+/// Whether the disk holding the file is rotational, according to sysfs. `None` if unknown, e.g. for files of virtual filesystems.
+#[cfg(target_os = "linux")]
+fn is_rotational(metadata: &fs::Metadata) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let device = format!("/sys/dev/block/{}:{}", libc::major(metadata.dev()), libc::minor(metadata.dev()));
+    //partitions don't have a queue directory, their disk does
+    ["queue/rotational", "../queue/rotational"].iter()
+        .find_map(|path| fs::read_to_string(Path::new(&device).join(path)).ok())
+        .map(|rotational| rotational.trim() != "0")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_rotational(_metadata: &fs::Metadata) -> Option<bool> {
+    None
+}
+
+pub enum WrappedReader {
+    FILE {
+        file: File
+    },
+    READER {
+        reader: Box<dyn Read + Send>
+    }
+}
+
+impl WrappedReader {
+    pub fn from_file(file: File) -> Self {
+        Self::FILE { file }
+    }
+
+    pub fn from_reader<T: 'static + Read + Send>(reader: T) -> Self {
+        Self::READER { reader: Box::new(reader) }
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        matches!(self, Self::FILE { .. })
+    }
+
+    /// Metadata of the input, if it's a regular file.
+    pub fn file_metadata(&self) -> Option<fs::Metadata> {
+        match self {
+            Self::FILE { file } => file.metadata().ok().filter(|m| m.is_file()),
+            Self::READER { .. } => None,
+        }
+    }
+
+    /// Size of the input, if it's a regular file.
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Self::FILE { file } => file.metadata().ok().filter(|m| m.is_file()).map(|m| m.len()),
+            Self::READER { .. } => None,
+        }
+    }
+
+    /// Read-only memory map of the input from `offset` to its end, if it's a regular file longer than `offset`.
+    ///
+    /// The file must not be truncated while mapped: accessing the missing pages would kill the process with SIGBUS.
+    pub fn map(&self, offset: u64) -> io::Result<Option<Mmap>> {
+        match self {
+            Self::FILE { file } if self.size().is_some_and(|size| size > offset) => {
+                //SAFETY: only truncating the file can invalidate the map (see above)
+                let map = unsafe { MmapOptions::new().offset(offset).map(file) }?;
+                #[cfg(unix)]
+                map.advise(Advice::Sequential)?;
+                Ok(Some(map))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// `SparseReader` of the input, if it's a regular file with holes.
+    pub fn sparse_reader(&self) -> io::Result<Option<SparseReader>> {
+        match self {
+            Self::FILE { file } if self.file_metadata().is_some() && has_holes(file)? => Ok(Some(SparseReader::new(file.try_clone()?)?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Read for WrappedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::FILE { file } => file.read(buf),
+            Self::READER { reader } => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for WrappedReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::FILE { file } => file.seek(pos),
+            Self::READER { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "input is not seekable")),
+        }
+    }
+}
+
+pub enum WrappedWriter<P: AsRef<Path>> {
+    PATH {
+        path: P
+    },
+    WRITER {
+        writer: Box<dyn Write + Send>
+    },
+    /// Program started by `from_child_sink`.
+    CHILD {
+        sink: ChildSink,
+        writer: ChildStdin,
+    },
+}
+
+impl WrappedWriter<String> {
+    /// Writes to the output path of `input` given by `template`, when processing several inputs.
+    pub fn derived(input: &str, output_dir: Option<&str>, template: &NameTemplate, decrypting: bool) -> Result<Self, Error> {
+        template.output_path(input, output_dir, decrypting).map(Self::from_path)
+    }
+
+    /// Output path, or `None` when writing to stdout or to a program.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Self::PATH { path } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl<P: AsRef<Path> + Display> WrappedWriter<P> {
+    pub fn from_path(path: P) -> Self {
+        Self::PATH { path }
+    }
+
+    pub fn from_writer<T: 'static + Write + Send>(writer: T) -> Self {
+        Self::WRITER { writer: Box::new(writer) }
+    }
+
+    /// Writes to a program, which only completes when the `OutputWriter` is finished.
+    pub fn from_child_sink(sink: ChildSink, writer: ChildStdin) -> Self {
+        Self::CHILD { sink, writer }
+    }
+
+    /// Uploads to an `http(s)://` or `s3://` URL. The upload starts right away, as `curl` and `aws` can't be run anymore once the sandbox is applied.
+    #[cfg(feature = "remote")]
+    pub fn from_url(url: &str) -> Result<Self, Error> {
+        let (sink, writer) = crate::remote::upload(url).map_err(|error| Error::Path { path: url.to_string(), error })?;
+        Ok(Self::from_child_sink(sink, writer))
+    }
+
+    pub fn into_buf_writer(self) -> Result<OutputWriter, Error> {
+        self.into_buf_writer_with_block_size(MIN_OUTPUT_BUFFER_SIZE)
+    }
+
+    /// Same as `into_buf_writer`, with a buffer as large as `block_size` (but at least 8KiB): whole blocks are written directly instead of being copied to the buffer first.
+    pub fn into_buf_writer_with_block_size(self, block_size: usize) -> Result<OutputWriter, Error> {
+        let capacity = block_size.max(MIN_OUTPUT_BUFFER_SIZE);
+        Ok(match self {
+            Self::PATH { path } => {
+                let dest = path.as_ref().to_path_buf();
+                let (file, tmp, suffix) = create_temporary(&dest).map_err(|error| Error::Path { path: dest.display().to_string(), error })?;
+                temporary_outputs().push(tmp.clone());
+                OutputWriter {
+                    file: Some(file.try_clone()?),
+                    writer: Some(BufWriter::with_capacity(capacity, Box::new(file))),
+                    suffix: Some(suffix),
+                    paths: Some((tmp, dest)),
+                    holes: false,
+                    keep: false,
+                    replace: true,
+                    written: 0,
+                    sink: None,
+                }
+            }
+            Self::WRITER { writer } => OutputWriter {
+                writer: Some(BufWriter::with_capacity(capacity, writer)),
+                paths: None,
+                suffix: None,
+                file: None,
+                holes: false,
+                keep: false,
+                replace: true,
+                written: 0,
+                sink: None,
+            },
+            Self::CHILD { sink, writer } => OutputWriter {
+                writer: Some(BufWriter::with_capacity(capacity, Box::new(writer))),
+                paths: None,
+                suffix: None,
+                file: None,
+                holes: false,
+                keep: false,
+                replace: true,
+                written: 0,
+                sink: Some(sink),
+            },
+        })
+    }
+
+    /// Same as `into_buf_writer_with_block_size`, but continues the temporary file left by an interrupted encryption, whose name ends with `suffix` (see `OutputWriter::temporary_suffix`): everything after its first `offset` bytes is dropped. Fails when not writing to a path, or if the temporary file was replaced by a link.
+    pub fn into_resumed_buf_writer(self, block_size: usize, suffix: &str, offset: u64) -> Result<OutputWriter, Error> {
+        let dest = match self {
+            Self::PATH { path } => path.as_ref().to_path_buf(),
+            _ => return Err(Error::Usage("--resume requires OUTPUT to be a file")),
+        };
+        let tmp = temporary_path(&dest, suffix);
+        let mut options = OpenOptions::new();
+        options.write(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+        let file = options.open(&tmp)
+            .and_then(|mut file| {
+                file.set_len(offset)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(file)
+            })
+            .map_err(|error| Error::Path { path: tmp.display().to_string(), error })?;
+        temporary_outputs().push(tmp.clone());
+        Ok(OutputWriter {
+            file: Some(file.try_clone()?),
+            writer: Some(BufWriter::with_capacity(block_size.max(MIN_OUTPUT_BUFFER_SIZE), Box::new(file))),
+            suffix: Some(suffix.to_string()),
+            paths: Some((tmp, dest)),
+            holes: false,
+            keep: false,
+            replace: true,
+            written: offset,
+            sink: None,
+        })
+    }
+}
+
+//temporary files of the live OutputWriter
+static TEMPORARY_OUTPUTS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+fn temporary_outputs() -> MutexGuard<'static, Vec<PathBuf>> {
+    TEMPORARY_OUTPUTS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn untrack(tmp: &Path) {
+    let mut outputs = temporary_outputs();
+    if let Some(i) = outputs.iter().rposition(|path| path == tmp) {
+        outputs.swap_remove(i);
+    }
+}
+
+/// Has `remove_temporary_outputs` remove the directory `dir` and its content, until `untrack_temporary_dir` is called.
+pub fn track_temporary_dir(dir: PathBuf) {
+    temporary_outputs().push(dir);
+}
+
+pub fn untrack_temporary_dir(dir: &Path) {
+    untrack(dir);
+}
+
+/// Removes the temporary files of all the `OutputWriter`s not finished yet, and the directories given to `track_temporary_dir`, and kills the programs they write to, e.g. from a panic hook, when they won't be dropped.
+pub fn remove_temporary_outputs() {
+    abort_child_sinks();
+    for tmp in temporary_outputs().drain(..) {
+        let _ = if tmp.is_dir() { fs::remove_dir_all(tmp) } else { fs::remove_file(tmp) };
+    }
+}
+
+/// Whether `s` is an `http://`, `https://` or `s3://` URL rather than a path. Such INPUT and OUTPUT require the `remote` feature.
+pub fn is_url(s: &str) -> bool {
+    ["http://", "https://", "s3://"].iter().any(|scheme| s.len() > scheme.len() && s.as_bytes()[..scheme.len()].eq_ignore_ascii_case(scheme.as_bytes()))
+}
+
+const TEMPORARY_SUFFIX_LEN: usize = 8;
+//attempts to find a free temporary name
+const TEMPORARY_RETRIES: usize = 16;
+
+/// Where the output is written before being moved to `dest`: `<dest>.<suffix>.tmp`.
+pub fn temporary_path<P: AsRef<Path>>(dest: P, suffix: &str) -> PathBuf {
+    let mut tmp = dest.as_ref().as_os_str().to_owned();
+    tmp.push(".");
+    tmp.push(suffix);
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// Whether `suffix` may have been generated by `create_temporary`, and thus can't point outside the directory of the output.
+pub fn is_temporary_suffix(suffix: &str) -> bool {
+    suffix.len() == TEMPORARY_SUFFIX_LEN && suffix.bytes().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Creates a file next to `dest` to write it before it is moved there, and returns it with its path and the random part of its name. The random name prevents anyone from preparing a file or a link at this path, and an existing file is never opened.
+pub fn create_temporary(dest: &Path) -> io::Result<(File, PathBuf, String)> {
+    for _ in 0..TEMPORARY_RETRIES {
+        let suffix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(TEMPORARY_SUFFIX_LEN).map(char::from).collect();
+        let tmp = temporary_path(dest, &suffix);
+        match OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(file) => return Ok((file, tmp, suffix)),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Err(io::ErrorKind::AlreadyExists.into())
+}
+
+/// Whether both paths point to the same existing file, even through links.
+#[cfg(unix)]
+pub fn is_same_file<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn is_same_file<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Buffered output returned by `WrappedWriter::into_buf_writer`.
+///
+/// When writing to a path, data first goes to a new temporary file with a random name next to it, `<path>.<suffix>.tmp`, which atomically replaces the destination on `finish`. If the `OutputWriter` is dropped before, or `remove_temporary_outputs` is called, the temporary file is removed so no truncated output is left behind, unless `keep_on_failure` was called.
+pub struct OutputWriter {
+    writer: Option<BufWriter<Box<dyn Write + Send>>>,
+    //(temporary file, destination)
+    paths: Option<(PathBuf, PathBuf)>,
+    //random part of the temporary file name
+    suffix: Option<String>,
+    //same open file as the writer, to skip holes
+    file: Option<File>,
+    //the file may end with a hole, which only setting its length creates
+    holes: bool,
+    //the temporary file outlives a failure
+    keep: bool,
+    //the destination is replaced if it exists
+    replace: bool,
+    written: u64,
+    //completed on finish, killed when dropped before
+    sink: Option<ChildSink>,
+}
+
+impl OutputWriter {
+    /// Number of bytes written so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Random part of the name of the temporary file, needed to resume writing it with `WrappedWriter::into_resumed_buf_writer`. `None` when not writing to a path.
+    pub fn temporary_suffix(&self) -> Option<&str> {
+        self.suffix.as_deref()
+    }
+
+    /// Flushes the output and commits what was written so far to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.as_mut().unwrap().flush()?;
+        match &self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps the temporary file if the output isn't finished, even on SIGINT or SIGTERM, so that an interrupted encryption can be resumed.
+    pub fn keep_on_failure(&mut self) {
+        if let Some((tmp, _)) = &self.paths {
+            untrack(tmp);
+        }
+        self.keep = true;
+    }
+
+    /// Makes `finish` fail instead of replacing the destination if it exists by then.
+    pub fn keep_existing(&mut self) {
+        self.replace = false;
+    }
+
+    /// Skips `len` zeros, leaving a hole when writing to a file (or writing them on filesystems without holes). Zeros are written to other outputs.
+    pub fn write_hole(&mut self, len: u64) -> io::Result<()> {
+        let writer = self.writer.as_mut().unwrap();
+        match self.file.as_mut() {
+            Some(file) => {
+                writer.flush()?;
+                let len = i64::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "hole too large"))?;
+                file.seek(SeekFrom::Current(len))?;
+                self.holes = true;
+            }
+            None => {
+                io::copy(&mut io::repeat(0).take(len), writer)?;
+            }
+        }
+        self.written += len;
+        Ok(())
+    }
+
+    /// Flushes the output and moves the temporary file to its destination. With `sync`, the file content and the rename are also committed to disk.
+    pub fn finish(mut self, sync: bool) -> Result<(), Error> {
+        self.flush()?;
+        drop(self.writer.take());
+        if let Some(sink) = self.sink.take() {
+            sink.finish()?;
+        }
+        let file = self.file.take();
+        if let Some(file) = file.as_ref().filter(|_| self.holes) {
+            file.set_len(self.written)?;
+        }
+        if let Some((tmp, dest)) = self.paths.take() {
+            untrack(&tmp);
+            //committed through the handle it was written with, which is closed before the rename
+            let result = match file {
+                Some(file) if sync => file.sync_all(),
+                _ => Ok(()),
+            }.and_then(|_| if self.replace { fs::rename(&tmp, &dest) } else { move_new(&tmp, &dest) });
+            if let Err(error) = result {
+                let _ = fs::remove_file(&tmp);
+                return Err(Error::Path { path: dest.display().to_string(), error });
+            }
+            if sync {
+                sync_parent_dir(&dest).map_err(|error| Error::Path { path: dest.display().to_string(), error })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the path the output is moved to on `finish`, e.g. once the file name stored in the ciphertext is known. The temporary file stays where it is, so `dest` should be in the same directory. Does nothing when not writing to a path.
+    pub fn set_destination<P: Into<PathBuf>>(&mut self, dest: P) {
+        if let Some((_, current)) = self.paths.as_mut() {
+            *current = dest.into();
+        }
+    }
+
+    /// Moves the temporary file to `<path>.unverified` instead of removing it, e.g. to inspect the output of a failed decryption. If this file already exists, `<path>.unverified.1`, `<path>.unverified.2`... is used instead, so that an earlier output isn't replaced. Returns the new path, or `None` when not writing to a path.
+    pub fn quarantine(mut self) -> Result<Option<PathBuf>, Error> {
+        self.writer.take().unwrap().flush()?;
+        let (tmp, dest) = match self.paths.take() {
+            Some(paths) => paths,
+            None => return Ok(None),
+        };
+        untrack(&tmp);
+        let mut unverified = dest.into_os_string();
+        unverified.push(".unverified");
+        let mut quarantined = PathBuf::from(&unverified);
+        for i in 1.. {
+            match move_new(&tmp, &quarantined) {
+                Ok(()) => break,
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                    let mut numbered = unverified.clone();
+                    numbered.push(format!(".{}", i));
+                    quarantined = PathBuf::from(numbered);
+                }
+                Err(error) => {
+                    let _ = fs::remove_file(&tmp);
+                    return Err(Error::Path { path: quarantined.display().to_string(), error });
+                }
+            }
+        }
+        Ok(Some(quarantined))
+    }
+}
+
+/// Moves `tmp` to `dest` unless `dest` exists. Linking fails atomically if it does, but isn't supported by every filesystem.
+fn move_new(tmp: &Path, dest: &Path) -> io::Result<()> {
+    match fs::hard_link(tmp, dest) {
+        Ok(()) => fs::remove_file(tmp),
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => Err(error),
+        Err(_) if dest.symlink_metadata().is_ok() => Err(io::ErrorKind::AlreadyExists.into()),
+        Err(_) => fs::rename(tmp, dest),
+    }
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl OutputWriter {
+    fn explain(&mut self, error: io::Error) -> io::Error {
+        match self.sink.as_mut() {
+            Some(sink) => sink.explain(error),
+            None => error,
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.as_mut().unwrap().write(buf).map_err(|e| self.explain(e))?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let n = self.writer.as_mut().unwrap().write_vectored(bufs).map_err(|e| self.explain(e))?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.as_mut().unwrap().flush().map_err(|e| self.explain(e))
+    }
+}
+
+impl Drop for OutputWriter {
+    fn drop(&mut self) {
+        //kill the program before closing its input, which would complete it
+        drop(self.sink.take());
+        //close the file before removing it
+        self.writer = None;
+        self.file = None;
+        if let Some((tmp, _)) = self.paths.take().filter(|_| !self.keep) {
+            untrack(&tmp);
+            let _ = fs::remove_file(tmp);
+        }
+    }
+}
+
+/// Overwrites a file with random data, commits it to disk and then removes it.
+///
+/// This doesn't guarantee that the original content can't be recovered on copy-on-write filesystems, SSDs or when backups/snapshots exist.
+pub fn shred<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path.as_ref())?;
+    let mut remaining = file.metadata()?.len();
+    let mut buff = vec![0; 65536];
+    while remaining > 0 {
+        let n = buff.len().min(remaining as usize);
+        rand::thread_rng().fill_bytes(&mut buff[..n]);
+        file.write_all(&buff[..n])?;
+        remaining -= n as u64;
+    }
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)
+}
+
+/// Copies the rest of a non-seekable encrypted input into an anonymous temporary file, rewound and ready to be read. Plaintext must never be spooled, as the file lands on disk.
+pub fn spool<R: Read>(reader: &mut R) -> io::Result<File> {
+    let mut file = tempfile::tempfile()?;
+    io::copy(reader, &mut file)?;
+    file.rewind()?;
+    Ok(file)
+}
@@ -0,0 +1,143 @@
+use blake2::Blake2b;
+use curve25519_dalek::{ristretto::{CompressedRistretto, RistrettoPoint}, scalar::Scalar, traits::IsIdentity};
+use hkdf::Hkdf;
+use rand::{Rng, RngCore, rngs::OsRng};
+use sha2::{Digest, Sha512};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
+use crate::{Error, crypto::KEY_LEN};
+
+pub const PAKE_MESSAGE_LEN: usize = 32;
+pub const CONFIRMATION_LEN: usize = 32;
+//domain separation of the generator and of the intermediate session key, as in CPace
+const DSI: &[u8] = b"CPaceRistretto255";
+const CHANNEL_ID: &[u8] = b"doby send/receive";
+const CODE_WORDS: usize = 2;
+
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "alarm", "album", "amber", "angle", "ankle", "apple", "apron", "arena", "armor", "arrow",
+    "aspen", "atlas", "attic", "autumn", "award", "bacon", "badge", "baker", "bamboo", "banjo", "barn", "basin",
+    "beach", "beard", "berry", "bison", "blade", "blaze", "bloom", "board", "bonus", "boots", "bread", "brick",
+    "bridge", "brook", "broom", "bubble", "bucket", "cabin", "cactus", "camel", "canal", "candle", "canoe", "canyon",
+    "cargo", "carpet", "castle", "cedar", "chalk", "cherry", "chess", "cider", "circus", "clam", "cliff", "clock",
+    "cloud", "clover", "cobra", "comet", "coral", "cotton", "cougar", "crane", "crayon", "creek", "cricket", "crown",
+    "cycle", "daisy", "dance", "delta", "denim", "desert", "diary", "dolphin", "donkey", "dragon", "drum", "eagle",
+    "echo", "elbow", "ember", "engine", "falcon", "fern", "ferry", "fiddle", "flame", "flute", "forest", "fossil",
+    "fox", "frost", "garden", "garlic", "geyser", "ginger", "glacier", "globe", "goose", "grape", "gravel", "guitar",
+    "hammer", "harbor", "hazel", "helmet", "heron", "hippo", "honey", "hornet", "island", "ivory", "jacket", "jaguar",
+    "jelly", "jungle", "kayak", "kettle", "kiwi", "koala", "ladder", "lagoon", "lantern", "lava", "lemon", "lily",
+    "lizard", "llama", "lobster", "magnet", "mango", "maple", "marble", "meadow", "melon", "mirror", "mitten", "moose",
+    "mosaic", "motor", "nectar", "needle", "nest", "noodle", "oasis", "ocean", "olive", "onion", "orbit", "orchid",
+    "otter", "oven", "owl", "paddle", "palace", "panda", "paper", "parrot", "pasta", "peach", "pebble", "pelican",
+    "pepper", "piano", "pickle", "pigeon", "pillow", "pilot", "pine", "pirate", "planet", "plum", "pocket", "polar",
+    "poppy", "potato", "prism", "pumpkin", "puzzle", "quail", "quartz", "quilt", "rabbit", "radar", "radish", "rain",
+    "raven", "reef", "rhino", "ribbon", "river", "robin", "rocket", "rose", "ruby", "saddle", "salmon", "scarf",
+    "shadow", "shark", "shell", "sierra", "silver", "sketch", "snail", "socket", "spider", "sponge", "spruce", "squid",
+    "stamp", "star", "stone", "storm", "sugar", "summit", "sunset", "swan", "table", "teapot", "tiger", "timber",
+    "toast", "tomato", "topaz", "torch", "tower", "tractor", "trumpet", "tulip", "tundra", "turtle", "umbrella",
+    "unicorn", "valley", "velvet", "violin", "volcano", "wagon", "walnut", "walrus", "whale", "willow", "window",
+    "wizard", "yacht", "yogurt", "zebra", "zipper",
+];
+
+/// Random code like "42-falcon-river": a number below 100 and two words, about 23 bits. This is enough as each guess requires a whole exchange with the sender, which gives up after a failed one.
+pub fn generate_code() -> String {
+    let mut code = OsRng.gen_range(0..100).to_string();
+    for _ in 0..CODE_WORDS {
+        code.push('-');
+        code.push_str(WORDS[OsRng.gen_range(0..WORDS.len())]);
+    }
+    code
+}
+
+fn prepend_len(hash: &mut Sha512, data: &[u8]) {
+    hash.update((data.len() as u64).to_le_bytes());
+    hash.update(data);
+}
+
+/// One side of a CPace key exchange over ristretto255: both sides derive the same session key only if they used the same code, and an eavesdropper or an active attacker can't check guesses of the code offline.
+pub struct Pake {
+    secret: Scalar,
+    message: [u8; PAKE_MESSAGE_LEN],
+    /// The side that sends its message first.
+    initiator: bool,
+}
+
+impl Pake {
+    pub fn new(code: &str, initiator: bool) -> Self {
+        let mut hash = Sha512::new();
+        prepend_len(&mut hash, DSI);
+        prepend_len(&mut hash, code.trim().as_bytes());
+        prepend_len(&mut hash, CHANNEL_ID);
+        let generator = RistrettoPoint::from_uniform_bytes(&hash.finalize().into());
+        let mut random = [0; 64];
+        OsRng.fill_bytes(&mut random);
+        let secret = Scalar::from_bytes_mod_order_wide(&random);
+        random.zeroize();
+        let message = (secret * generator).compress().to_bytes();
+        Self { secret, message, initiator }
+    }
+
+    /// Message to send to the other side.
+    pub fn message(&self) -> [u8; PAKE_MESSAGE_LEN] {
+        self.message
+    }
+
+    /// Derives the session keys from the message of the other side. They still have to be confirmed with `SessionKeys::confirmation`.
+    pub fn finish(mut self, peer_message: &[u8; PAKE_MESSAGE_LEN]) -> Result<SessionKeys, Error> {
+        let peer_point = CompressedRistretto(*peer_message).decompress().filter(|point| !point.is_identity()).ok_or(Error::PakeFailed)?;
+        let shared_point = self.secret * peer_point;
+        self.secret.zeroize();
+        if shared_point.is_identity() {
+            return Err(Error::PakeFailed);
+        }
+        let (initiator_message, responder_message) = if self.initiator { (&self.message, peer_message) } else { (peer_message, &self.message) };
+        let mut hash = Sha512::new();
+        prepend_len(&mut hash, DSI);
+        prepend_len(&mut hash, b"_ISK");
+        prepend_len(&mut hash, shared_point.compress().as_bytes());
+        prepend_len(&mut hash, initiator_message);
+        prepend_len(&mut hash, responder_message);
+        let session_key: Zeroizing<[u8; 64]> = Zeroizing::new(hash.finalize().into());
+        let hkdf = Hkdf::<Blake2b>::new(None, &*session_key);
+        let mut keys = SessionKeys {
+            key: Zeroizing::new([0; KEY_LEN]),
+            initiator_confirmation: [0; CONFIRMATION_LEN],
+            responder_confirmation: [0; CONFIRMATION_LEN],
+            initiator: self.initiator,
+        };
+        hkdf.expand(b"doby_pake_key", &mut *keys.key).unwrap();
+        hkdf.expand(b"doby_pake_initiator_confirmation", &mut keys.initiator_confirmation).unwrap();
+        hkdf.expand(b"doby_pake_responder_confirmation", &mut keys.responder_confirmation).unwrap();
+        Ok(keys)
+    }
+}
+
+/// Outcome of a `Pake` exchange. Each side sends its confirmation and checks the one of the other side before using the key, so that a wrong code is detected before sending anything.
+pub struct SessionKeys {
+    key: Zeroizing<[u8; KEY_LEN]>,
+    initiator_confirmation: [u8; CONFIRMATION_LEN],
+    responder_confirmation: [u8; CONFIRMATION_LEN],
+    initiator: bool,
+}
+
+impl SessionKeys {
+    /// Key shared by both sides, to be used as a raw key.
+    pub fn key(&self) -> &[u8; KEY_LEN] {
+        &self.key
+    }
+
+    /// Confirmation to send to the other side.
+    pub fn confirmation(&self) -> [u8; CONFIRMATION_LEN] {
+        if self.initiator { self.initiator_confirmation } else { self.responder_confirmation }
+    }
+
+    /// Fails if the other side used another code.
+    pub fn check_confirmation(&self, peer_confirmation: &[u8; CONFIRMATION_LEN]) -> Result<(), Error> {
+        let expected = if self.initiator { &self.responder_confirmation } else { &self.initiator_confirmation };
+        if bool::from(expected.ct_eq(peer_confirmation)) {
+            Ok(())
+        } else {
+            Err(Error::PakeFailed)
+        }
+    }
+}
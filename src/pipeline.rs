@@ -0,0 +1,122 @@
+use std::{io::{self, Read, Write}, sync::mpsc::{self, Receiver, SyncSender}, thread};
+use crate::{
+    Error,
+    MAGIC_BYTES,
+    crypto::{DobyCipher, EncryptionParams},
+    debug_blocks,
+    debug_header,
+};
+
+//number of blocks that can be waiting between two stages
+const PIPELINE_DEPTH: usize = 4;
+
+fn read_blocks<R: Read>(reader: &mut R, block_size: usize, sender: SyncSender<Vec<u8>>) -> io::Result<()> {
+    loop {
+        let mut buff = vec![0; block_size];
+        let n = reader.read(&mut buff)?;
+        if n == 0 {
+            return Ok(());
+        }
+        buff.truncate(n);
+        if sender.send(buff).is_err() { //the crypto stage stopped
+            return Ok(());
+        }
+    }
+}
+
+fn write_blocks<W: Write>(writer: &mut W, receiver: Receiver<Vec<u8>>) -> io::Result<()> {
+    for buff in receiver {
+        writer.write_all(&buff)?;
+    }
+    writer.flush()
+}
+
+/// Runs `process` on the calling thread while reading and writing happen in two other threads.
+/// `process` receives the input blocks and sends output blocks. A read error always takes precedence so that a truncated input can't produce a valid output.
+fn run<R, W, F>(reader: &mut R, writer: &mut W, block_size: usize, process: F) -> Result<(), Error>
+where
+    R: Read + Send,
+    W: Write + Send,
+    F: FnOnce(Receiver<Vec<u8>>, &SyncSender<Vec<u8>>) -> Result<(), Error>,
+{
+    thread::scope(|scope| {
+        let (input_sender, input_receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let (output_sender, output_receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let reader_thread = scope.spawn(move || read_blocks(reader, block_size, input_sender));
+        let writer_thread = scope.spawn(move || write_blocks(writer, output_receiver));
+
+        let result = process(input_receiver, &output_sender);
+        drop(output_sender);
+        reader_thread.join().unwrap()?;
+        writer_thread.join().unwrap()?;
+        result
+    })
+}
+
+pub fn encrypt_pipelined<R: Read + Send, W: Write + Send>(reader: &mut R, writer: &mut W, params: &EncryptionParams, mut cipher: DobyCipher, block_size: usize, already_read: Option<&[u8]>) -> Result<(), Error> {
+    debug_header(params);
+    let mut header = MAGIC_BYTES.to_vec();
+    params.write(&mut header)?;
+    let mut already_read = already_read.map(|b| b.to_vec());
+    let (mut bytes, mut blocks) = (0, 0);
+    run(reader, writer, block_size, |input, output| {
+        if output.send(header).is_err() {
+            return Ok(()); //the writer failed, its error will be returned
+        }
+        if let Some(buff) = already_read.as_mut() {
+            let mut encrypted = Vec::with_capacity(buff.len());
+            cipher.encrypt_chunk(buff, &mut encrypted)?;
+            if output.send(encrypted).is_err() {
+                return Ok(());
+            }
+        }
+        for mut buff in input {
+            let mut encrypted = Vec::with_capacity(buff.len());
+            cipher.encrypt_chunk(&mut buff, &mut encrypted)?;
+            bytes += buff.len() as u64;
+            blocks += 1;
+            if output.send(encrypted).is_err() {
+                return Ok(());
+            }
+        }
+        let chunked = cipher.is_chunked();
+        let mut hmac = Vec::new();
+        cipher.write_hmac(&mut hmac)?;
+        let _ = output.send(hmac);
+        debug_blocks("encrypted", bytes, blocks, block_size, chunked);
+        Ok(())
+    })
+}
+
+/// `reader` must be positioned right after the header the cipher was created from.
+pub fn decrypt_pipelined<R: Read + Send, W: Write + Send>(reader: &mut R, writer: &mut W, mut cipher: DobyCipher, block_size: usize) -> Result<(), Error> {
+    let mut verified = false;
+    let mut truncated = None;
+    let (mut bytes, mut blocks) = (0, 0);
+    run(reader, writer, block_size, |input, output| {
+        for buff in input {
+            let mut plaintext = Vec::with_capacity(buff.len());
+            let authentic = cipher.decrypt_update(&buff, &mut plaintext);
+            bytes += plaintext.len() as u64;
+            blocks += 1;
+            if output.send(plaintext).is_err() || !authentic {
+                return Ok(());
+            }
+        }
+        let mut plaintext = Vec::new();
+        let chunked = cipher.is_chunked();
+        truncated = cipher.truncated();
+        verified = cipher.decrypt_finalize(&mut plaintext);
+        bytes += plaintext.len() as u64;
+        let _ = output.send(plaintext);
+        debug_blocks("decrypted", bytes, blocks, block_size, chunked);
+        Ok(())
+    })?;
+    if let Some(expected) = truncated {
+        Err(Error::Truncated { expected })
+    } else if verified {
+        Ok(())
+    } else {
+        Err(Error::HmacMismatch)
+    }
+}
@@ -0,0 +1,83 @@
+use std::{io::Write, process::{Command, Stdio}};
+use rand::rngs::OsRng;
+use rsa::{Oaep, RsaPublicKey, pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey};
+use sha2::Sha256;
+use zeroize::Zeroize;
+use crate::{Error, crypto::{KEY_LEN, KeySlot, MAX_PKCS11_KEY_ID_LEN}};
+
+/// RSA key pair stored in a PKCS#11 token (smartcard, HSM), used through `pkcs11-tool` from OpenSC. The private key never leaves the token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pkcs11Key {
+    module: String,
+    key_id: Vec<u8>,
+}
+
+impl Pkcs11Key {
+    /// `key_id` is the hexadecimal CKA_ID of the key pair, as listed by `pkcs11-tool --list-objects`.
+    pub fn new(module: &str, key_id: &str) -> Result<Self, Error> {
+        let hex = key_id.as_bytes();
+        if hex.is_empty() || !hex.len().is_multiple_of(2) || hex.len() > MAX_PKCS11_KEY_ID_LEN*2 || !hex.iter().all(u8::is_ascii_hexdigit) {
+            return Err(Error::InvalidPkcs11KeyId(key_id.to_string()));
+        }
+        let key_id = hex.chunks(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap()).collect();
+        Ok(Self { module: module.to_string(), key_id })
+    }
+
+    pub fn format_id(key_id: &[u8]) -> String {
+        key_id.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    //stdin is left to pkcs11-tool to prompt for the PIN
+    fn pkcs11_tool(&self, args: &[&str]) -> Result<Vec<u8>, Error> {
+        let output = Command::new("pkcs11-tool")
+            .arg("--module")
+            .arg(&self.module)
+            .arg("--id")
+            .arg(Self::format_id(&self.key_id))
+            .args(args)
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| Error::Pkcs11(format!("can't run pkcs11-tool: {}", e)))?;
+        if !output.status.success() {
+            return Err(Error::Pkcs11(format!("pkcs11-tool {}", output.status)));
+        }
+        Ok(output.stdout)
+    }
+
+    /// Encrypts the master key to the public key of the token. No PIN is needed.
+    pub fn wrap(&self, master_key: &[u8; KEY_LEN]) -> Result<KeySlot, Error> {
+        let der = self.pkcs11_tool(&["--read-object", "--type", "pubkey"])?;
+        //older versions of OpenSC export PKCS#1 instead of SubjectPublicKeyInfo
+        let public_key = RsaPublicKey::from_public_key_der(&der)
+            .or_else(|_| RsaPublicKey::from_pkcs1_der(&der))
+            .map_err(|_| Error::Pkcs11(String::from("only RSA keys are supported")))?;
+        let wrapped_key = public_key.encrypt(&mut OsRng, Oaep::new::<Sha256>(), master_key)
+            .map_err(|e| Error::Pkcs11(e.to_string()))?;
+        Ok(KeySlot::Pkcs11 { key_id: self.key_id.clone(), wrapped_key })
+    }
+
+    /// Returns the master key of the first slot encrypted to this key, decrypted by the token. `pkcs11-tool` prompts for the PIN.
+    pub fn unwrap(&self, key_slots: &[KeySlot]) -> Result<Option<[u8; KEY_LEN]>, Error> {
+        let wrapped_key = match key_slots.iter().find_map(|key_slot| match key_slot {
+            KeySlot::Pkcs11 { key_id, wrapped_key } if *key_id == self.key_id => Some(wrapped_key),
+            _ => None,
+        }) {
+            Some(wrapped_key) => wrapped_key,
+            None => return Ok(None),
+        };
+        //the wrapped key is public, it can go through a temporary file
+        let mut input = tempfile::NamedTempFile::new()?;
+        input.write_all(wrapped_key)?;
+        let mut master_key = self.pkcs11_tool(&[
+            "--login",
+            "--decrypt",
+            "--mechanism", "RSA-PKCS-OAEP",
+            "--hash-algorithm", "SHA256",
+            "--mgf", "MGF1-SHA256",
+            "--input-file", &input.path().to_string_lossy(),
+        ])?;
+        let result = master_key.as_slice().try_into().map_err(|_| Error::Pkcs11(String::from("invalid decrypted key")));
+        master_key.zeroize();
+        result.map(Some)
+    }
+}
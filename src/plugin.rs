@@ -0,0 +1,211 @@
+use std::{env, fmt::{self, Display, Formatter}, fs, io::{self, Write}, path::{Path, PathBuf}, process::{Command, Stdio}};
+use zeroize::Zeroize;
+use crate::{Error, crypto::{KEY_LEN, KeySlot}};
+pub use crate::crypto::is_valid_plugin_name as is_valid_name;
+
+pub const PLUGIN_RECIPIENT_PREFIX: &str = "doby-plugin-pk-";
+pub const PLUGIN_IDENTITY_PREFIX: &str = "doby-plugin-sk-";
+/// Plugin programs are named after this prefix followed by the plugin name, and looked up in PATH.
+pub const PLUGIN_PROGRAM_PREFIX: &str = "doby-plugin-";
+const PROTOCOL_VERSION: u8 = 1;
+
+//"<prefix><name>:<data>", the data being opaque to doby
+fn split<'a>(s: &'a str, prefix: &str) -> Option<(&'a str, &'a str)> {
+    let (name, data) = s.strip_prefix(prefix)?.split_once(':')?;
+    (is_valid_name(name) && !data.is_empty() && !data.contains(char::is_whitespace)).then_some((name, data))
+}
+
+fn program(plugin: &str) -> String {
+    format!("{}{}", PLUGIN_PROGRAM_PREFIX, plugin)
+}
+
+fn plugin_error(plugin: &str, message: String) -> Error {
+    Error::Plugin { name: plugin.to_string(), message }
+}
+
+/// Runs `doby-plugin-<plugin> <operation>`, writes `request` to its stdin and returns its stdout.
+///
+/// Its stderr is inherited so that it can report progress, and it can interact with the user through the terminal.
+fn run(plugin: &str, operation: &str, request: &[u8]) -> Result<String, Error> {
+    let mut child = Command::new(program(plugin))
+        .arg(operation)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| plugin_error(plugin, match e.kind() {
+            io::ErrorKind::NotFound => format!("{} not found in PATH", program(plugin)),
+            _ => format!("can't run {}: {}", program(plugin), e),
+        }))?;
+    //plugins read the whole request before answering
+    let written = child.stdin.take().unwrap().write_all(request);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(plugin_error(plugin, format!("{} {}", program(plugin), output.status)));
+    }
+    written?;
+    String::from_utf8(output.stdout).map_err(|e| {
+        e.into_bytes().zeroize();
+        plugin_error(plugin, String::from("invalid response"))
+    })
+}
+
+/// Value of the first `<field> <value>` line of a response. An `error <message>` line makes the operation fail.
+fn response_field<'a>(plugin: &str, response: &'a str, field: &str) -> Result<Option<&'a str>, Error> {
+    for line in response.lines() {
+        let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+        if name == "error" {
+            return Err(plugin_error(plugin, value.to_string()));
+        }
+        if name == field {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+/// Recipient handled by a plugin, e.g. a key stored in a TPM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PluginRecipient {
+    plugin: String,
+    data: String,
+}
+
+impl PluginRecipient {
+    /// Parses `doby-plugin-pk-<name>:<data>`.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        split(s, PLUGIN_RECIPIENT_PREFIX)
+            .map(|(plugin, data)| Self { plugin: plugin.to_string(), data: data.to_string() })
+            .ok_or_else(|| Error::InvalidRecipient(s.to_string()))
+    }
+
+    pub fn plugin(&self) -> &str {
+        &self.plugin
+    }
+
+    /// Has the plugin wrap `master_key` in a new key slot.
+    pub fn wrap(&self, master_key: &[u8; KEY_LEN]) -> Result<KeySlot, Error> {
+        let mut request = format!("version {}\nrecipient {}\nfile-key {}\n", PROTOCOL_VERSION, self.data, base64::encode(master_key));
+        let response = run(&self.plugin, "wrap", request.as_bytes());
+        request.zeroize();
+        let response = response?;
+        let data = response_field(&self.plugin, &response, "slot")?
+            .and_then(|encoded| base64::decode(encoded).ok())
+            .filter(|data| data.len() <= u16::MAX as usize)
+            .ok_or_else(|| plugin_error(&self.plugin, String::from("invalid response")))?;
+        Ok(KeySlot::Plugin { plugin: self.plugin.clone(), data })
+    }
+}
+
+impl Display for PluginRecipient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}:{}", PLUGIN_RECIPIENT_PREFIX, self.plugin, self.data)
+    }
+}
+
+/// Identity handled by a plugin, which may be a secret or a reference to a key held elsewhere.
+pub struct PluginIdentity {
+    plugin: String,
+    data: String,
+}
+
+impl PluginIdentity {
+    /// Parses `doby-plugin-sk-<name>:<data>`.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        //the invalid value isn't included in the error as it may be a secret
+        split(s, PLUGIN_IDENTITY_PREFIX)
+            .map(|(plugin, data)| Self { plugin: plugin.to_string(), data: data.to_string() })
+            .ok_or(Error::InvalidIdentity)
+    }
+
+    pub fn plugin(&self) -> &str {
+        &self.plugin
+    }
+
+    pub fn to_file_content(&self) -> String {
+        format!("{}{}:{}\n", PLUGIN_IDENTITY_PREFIX, self.plugin, self.data)
+    }
+}
+
+impl Drop for PluginIdentity {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+/// Runs each plugin that has identities once, with all its identities and key slots, until one of them returns the master key.
+pub fn unwrap_with_plugins(identities: &[&PluginIdentity], key_slots: &[KeySlot]) -> Result<Option<[u8; KEY_LEN]>, Error> {
+    let mut plugins: Vec<&str> = Vec::new();
+    for identity in identities {
+        if !plugins.contains(&identity.plugin()) {
+            plugins.push(identity.plugin());
+        }
+    }
+    for plugin in plugins {
+        let slots: Vec<&[u8]> = key_slots.iter().filter_map(|key_slot| match key_slot {
+            KeySlot::Plugin { plugin: name, data } if name == plugin => Some(data.as_slice()),
+            _ => None,
+        }).collect();
+        if slots.is_empty() {
+            continue;
+        }
+        let mut request = format!("version {}\n", PROTOCOL_VERSION);
+        for identity in identities.iter().filter(|identity| identity.plugin() == plugin) {
+            request += &format!("identity {}\n", identity.data);
+        }
+        for data in slots {
+            request += &format!("slot {}\n", base64::encode(data));
+        }
+        let response = run(plugin, "unwrap", request.as_bytes());
+        request.zeroize();
+        let mut response = response?;
+        //no file-key line: none of the slots could be opened
+        let master_key = response_field(plugin, &response, "file-key").and_then(|encoded| encoded.map(|encoded| {
+            let mut key = base64::decode(encoded).map_err(|_| plugin_error(plugin, String::from("invalid response")))?;
+            let master_key = key.as_slice().try_into().map_err(|_| plugin_error(plugin, String::from("invalid file key length")));
+            key.zeroize();
+            master_key
+        }).transpose());
+        response.zeroize();
+        if let Some(master_key) = master_key? {
+            return Ok(Some(master_key));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Plugins installed in PATH with the path of their program, the first directory taking precedence like when running them.
+pub fn discover() -> Vec<(String, PathBuf)> {
+    let mut plugins: Vec<(String, PathBuf)> = Vec::new();
+    for dir in env::split_paths(&env::var_os("PATH").unwrap_or_default()) {
+        let mut found: Vec<(String, PathBuf)> = match fs::read_dir(&dir) {
+            Ok(entries) => entries.filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let name = path.file_name()?.to_str()?
+                    .strip_suffix(env::consts::EXE_SUFFIX)?
+                    .strip_prefix(PLUGIN_PROGRAM_PREFIX)?
+                    .to_string();
+                (is_valid_name(&name) && is_executable(&path)).then_some((name, path))
+            }).collect(),
+            Err(_) => continue,
+        };
+        found.sort();
+        for (name, path) in found {
+            if !plugins.iter().any(|(plugin, _)| *plugin == name) {
+                plugins.push((name, path));
+            }
+        }
+    }
+    plugins
+}
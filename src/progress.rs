@@ -0,0 +1,103 @@
+use std::{io::{self, Read, Write}, time::{Duration, Instant}};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Calls `callback` with the total number of bytes read so far after each read.
+pub struct ProgressReader<R: Read, F: FnMut(u64)> {
+    reader: R,
+    callback: F,
+    processed: u64,
+}
+
+impl<R: Read, F: FnMut(u64)> ProgressReader<R, F> {
+    pub fn new(reader: R, callback: F) -> Self {
+        Self::starting_at(reader, 0, callback)
+    }
+
+    /// Useful when some bytes have already been consumed from `reader`.
+    pub fn starting_at(reader: R, processed: u64, callback: F) -> Self {
+        Self { reader, callback, processed }
+    }
+}
+
+impl<R: Read, F: FnMut(u64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.processed += n as u64;
+        (self.callback)(self.processed);
+        Ok(n)
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len()-1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn format_duration(seconds: u64) -> String {
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Draws a single progress line on stderr.
+pub struct ProgressBar {
+    total: Option<u64>,
+    processed: u64,
+    start: Instant,
+    last_draw: Option<Instant>,
+}
+
+impl ProgressBar {
+    pub fn new(total: Option<u64>) -> Self {
+        Self {
+            total,
+            processed: 0,
+            start: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    pub fn update(&mut self, processed: u64) {
+        self.processed = processed;
+        let now = Instant::now();
+        if self.last_draw.map(|t| now.duration_since(t) >= REFRESH_INTERVAL).unwrap_or(true) {
+            self.last_draw = Some(now);
+            self.draw(processed, now);
+        }
+    }
+
+    pub fn finish(&self) {
+        self.draw(self.processed, Instant::now());
+        eprintln!();
+    }
+
+    fn draw(&self, processed: u64, now: Instant) {
+        let elapsed = now.duration_since(self.start).as_secs_f64();
+        let speed = if elapsed > 0. { processed as f64 / elapsed } else { 0. };
+        let mut line = format_size(processed);
+        if let Some(total) = self.total {
+            line += &format!(" / {}", format_size(total));
+            if let Some(percent) = (processed.min(total) * 100).checked_div(total) {
+                line += &format!(" ({}%)", percent);
+            }
+        }
+        line += &format!("  {}/s", format_size(speed as u64));
+        if let Some(total) = self.total {
+            if speed > 0. {
+                line += &format!("  ETA {}", format_duration((total.saturating_sub(processed) as f64 / speed) as u64));
+            }
+        }
+        //clear the end of the line in case the previous one was longer
+        eprint!("\r{}\x1b[K", line);
+        let _ = io::stderr().flush();
+    }
+}
@@ -0,0 +1,643 @@
+//! QR codes (ISO/IEC 18004) of armored ciphertexts, for paper backups of keys and short secrets.
+//!
+//! The armored text is split in parts of at most `MAX_PART_LEN` bytes, each one starting with a "DOBY-QR i/n" line and encoded in byte mode with the medium (M) error correction level. The text scanned from the codes, in any order, is turned back into the armored ciphertext by `reassemble`.
+
+use std::io::{self, Read, Write};
+use crate::Error;
+
+pub const QR_MARKER: &str = "DOBY-QR ";
+/// Largest armored text, above which QR codes would hardly be practical.
+pub const MAX_QR_INPUT_LEN: usize = 64 << 10;
+//armor lines of a part, keeping codes small enough to be printed and scanned reliably (version 25 at most)
+const MAX_PART_LEN: usize = 960;
+//modules of light border around each code
+const QUIET_ZONE: usize = 4;
+//pixels per module in PNG images
+const PNG_SCALE: usize = 8;
+
+const ECC_CODEWORDS_PER_BLOCK: [usize; 41] = [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28];
+const NUM_ECC_BLOCKS: [usize; 41] = [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21, 23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49];
+
+/// Whether the input is text scanned from the QR codes written by `--qr`.
+pub fn is_qr_text(first_bytes: &[u8]) -> bool {
+    let first_bytes = first_bytes.strip_prefix(b"QR-Code:").unwrap_or(first_bytes);
+    first_bytes.starts_with(QR_MARKER.as_bytes())
+}
+
+/// How `--qr` renders the codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QrFormat {
+    /// Unicode half blocks, black on white with ANSI colors, each code followed by its "DOBY-QR i/n" line.
+    Text,
+    /// A PNG image with the codes one below the other.
+    Png,
+}
+
+/// A QR code in byte mode with the medium error correction level.
+pub struct QrCode {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+    //finder, timing and alignment patterns, format and version information
+    function: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encodes `data` in the smallest version that fits, or fails if it's longer than 2331 bytes.
+    pub fn encode(data: &[u8]) -> Result<Self, Error> {
+        let version = (1..=40)
+            .find(|&version| 4 + count_bits(version) + data.len() * 8 <= num_data_codewords(version) * 8)
+            .ok_or_else(|| Error::Qr(String::from("data too long for a QR code")))?;
+
+        let capacity = num_data_codewords(version) * 8;
+        let mut bits = BitBuffer::default();
+        bits.push(0b0100, 4);
+        bits.push(data.len() as u32, count_bits(version));
+        for byte in data {
+            bits.push(*byte as u32, 8);
+        }
+        bits.push(0, (capacity - bits.len).min(4));
+        bits.push(0, (8 - bits.len % 8) % 8);
+        for pad in [0xEC, 0x11].iter().cycle() {
+            if bits.len == capacity {
+                break;
+            }
+            bits.push(*pad, 8);
+        }
+
+        let size = version * 4 + 17;
+        let mut qr = Self {
+            version,
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        };
+        qr.draw_function_patterns();
+        qr.draw_codewords(&interleave(version, &bits.bytes));
+        let mask = (0..8).min_by_key(|&mask| {
+            qr.apply_mask(mask);
+            qr.draw_format_bits(mask);
+            let penalty = qr.penalty();
+            qr.apply_mask(mask);
+            penalty
+        }).unwrap();
+        qr.apply_mask(mask);
+        qr.draw_format_bits(mask);
+        Ok(qr)
+    }
+
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// Number of modules per side, without quiet zone.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the module at column `x` and row `y` is dark.
+    pub fn module(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    //same as module, but light outside the code, in the quiet zone
+    fn module_or_light(&self, x: isize, y: isize) -> bool {
+        (0..self.size as isize).contains(&x) && (0..self.size as isize).contains(&y) && self.module(x as usize, y as usize)
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            //finder pattern and its separator
+            for dy in -4..=4isize {
+                for dx in -4..=4isize {
+                    let (xx, yy) = (x as isize + dx, y as isize + dy);
+                    if (0..size as isize).contains(&xx) && (0..size as isize).contains(&yy) {
+                        let distance = dx.abs().max(dy.abs());
+                        self.set_function(xx as usize, yy as usize, distance != 2 && distance != 4);
+                    }
+                }
+            }
+        }
+        let positions = alignment_positions(self.version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                //the corners of the finder patterns
+                if (i, j) == (0, 0) || (i, j) == (0, last) || (i, j) == (last, 0) {
+                    continue;
+                }
+                for dy in -2..=2isize {
+                    for dx in -2..=2isize {
+                        self.set_function((x as isize + dx) as usize, (y as isize + dy) as usize, dx.abs().max(dy.abs()) != 1);
+                    }
+                }
+            }
+        }
+        //reserved until the mask is chosen
+        self.draw_format_bits(0);
+        if self.version >= 7 {
+            let mut rem = self.version as u32;
+            for _ in 0..12 {
+                rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+            }
+            let bits = (self.version as u32) << 12 | rem;
+            for i in 0..18 {
+                let dark = bits >> i & 1 != 0;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u32) {
+        //the medium error correction level is 00
+        let data = mask;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = (data << 10 | rem) ^ 0x5412;
+        let bit = |i: usize| bits >> i & 1 != 0;
+        let size = self.size;
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    //in pairs of columns from the right, going up and down alternately, skipping the vertical timing pattern
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size as isize - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vert in 0..size {
+                for j in 0..2 {
+                    let x = right as usize - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vert } else { vert };
+                    if !self.function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = codewords[i / 8] >> (7 - i % 8) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let i = y * self.size + x;
+                self.modules[i] ^= invert && !self.function[i];
+            }
+        }
+    }
+
+    //penalty rules of the standard, which the chosen mask minimizes
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+        let lines = |column: bool| (0..size).map(move |i| (0..size).map(move |j| if column { self.module(i, j) } else { self.module(j, i) }).collect::<Vec<bool>>());
+        for line in lines(false).chain(lines(true)) {
+            let mut run = 1;
+            for j in 1..=size {
+                if j < size && line[j] == line[j - 1] {
+                    run += 1;
+                } else {
+                    if run >= 5 {
+                        penalty += run - 2;
+                    }
+                    run = 1;
+                }
+            }
+            //1:1:3:1:1 patterns next to 4 light modules, which look like finder patterns
+            const FINDER_LIKE: [bool; 11] = [true, false, true, true, true, false, true, false, false, false, false];
+            for window in line.windows(11) {
+                if window == FINDER_LIKE || window.iter().rev().eq(FINDER_LIKE.iter()) {
+                    penalty += 40;
+                }
+            }
+        }
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.module(x, y);
+                if dark == self.module(x + 1, y) && dark == self.module(x, y + 1) && dark == self.module(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+        let dark = self.modules.iter().filter(|dark| **dark).count();
+        let total = size * size;
+        penalty + (dark * 20).abs_diff(total * 10).div_ceil(total).saturating_sub(1) * 10
+    }
+}
+
+#[derive(Default)]
+struct BitBuffer {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitBuffer {
+    fn push(&mut self, value: u32, bits: usize) {
+        for i in (0..bits).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if value >> i & 1 != 0 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+//length of the character count field in byte mode
+fn count_bits(version: usize) -> usize {
+    if version <= 9 { 8 } else { 16 }
+}
+
+fn num_raw_data_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+fn num_data_codewords(version: usize) -> usize {
+    num_raw_data_modules(version) / 8 - ECC_CODEWORDS_PER_BLOCK[version] * NUM_ECC_BLOCKS[version]
+}
+
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let alignments = version / 7 + 2;
+    let step = if version == 32 { 26 } else { (version * 4 + alignments * 2 + 1) / (alignments * 2 - 2) * 2 };
+    let mut positions = vec![6];
+    let mut position = version * 4 + 10;
+    for _ in 0..alignments - 1 {
+        positions.insert(1, position);
+        position -= step;
+    }
+    positions
+}
+
+//splits the data codewords in blocks, appends their error correction codewords and interleaves them
+fn interleave(version: usize, data: &[u8]) -> Vec<u8> {
+    let num_blocks = NUM_ECC_BLOCKS[version];
+    let ecc_len = ECC_CODEWORDS_PER_BLOCK[version];
+    let raw_codewords = num_raw_data_modules(version) / 8;
+    let num_short_blocks = num_blocks - raw_codewords % num_blocks;
+    let short_block_len = raw_codewords / num_blocks;
+    let divisor = reed_solomon_divisor(ecc_len);
+    let mut blocks = Vec::with_capacity(num_blocks);
+    let mut k = 0;
+    for i in 0..num_blocks {
+        let len = short_block_len - ecc_len + usize::from(i >= num_short_blocks);
+        let mut block = data[k..k + len].to_vec();
+        k += len;
+        let ecc = reed_solomon_remainder(&block, &divisor);
+        if i < num_short_blocks {
+            //placeholder, skipped when interleaving
+            block.push(0);
+        }
+        block.extend_from_slice(&ecc);
+        blocks.push(block);
+    }
+    let mut codewords = Vec::with_capacity(raw_codewords);
+    for i in 0..blocks[0].len() {
+        for (j, block) in blocks.iter().enumerate() {
+            if i != short_block_len - ecc_len || j >= num_short_blocks {
+                codewords.push(block[i]);
+            }
+        }
+    }
+    codewords
+}
+
+//multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_mul(x: u8, y: u8) -> u8 {
+    let mut z: u16 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y >> i) & 1) as u16 * x as u16;
+    }
+    z as u8
+}
+
+//coefficients of (x - 1)(x - 2)(x - 2^2)...(x - 2^(degree-1)), highest first, without the leading 1
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0; degree];
+    result[degree - 1] = 1;
+    let mut root = 1;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_mul(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_mul(root, 0x02);
+    }
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0; divisor.len()];
+    for byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (x, y) in result.iter_mut().zip(divisor) {
+            *x ^= gf_mul(*y, factor);
+        }
+    }
+    result
+}
+
+/// Splits an armored ciphertext in the texts of its QR codes, each one starting with its "DOBY-QR i/n" line. Parts end at line breaks, unless a line is too long.
+pub fn split(armored: &[u8]) -> Vec<Vec<u8>> {
+    let mut parts: Vec<Vec<u8>> = Vec::new();
+    let mut current = Vec::new();
+    for line in armored.split_inclusive(|b| *b == b'\n') {
+        for piece in line.chunks(MAX_PART_LEN) {
+            if !current.is_empty() && current.len() + piece.len() > MAX_PART_LEN {
+                parts.push(std::mem::take(&mut current));
+            }
+            current.extend_from_slice(piece);
+        }
+    }
+    if !current.is_empty() || parts.is_empty() {
+        parts.push(current);
+    }
+    let n = parts.len();
+    parts.into_iter().enumerate().map(|(i, part)| {
+        let mut text = format!("{}{}/{}\n", QR_MARKER, i + 1, n).into_bytes();
+        text.extend_from_slice(&part);
+        text
+    }).collect()
+}
+
+/// Turns the text scanned from all the QR codes of a ciphertext, in any order, back into the armored ciphertext. The "QR-Code:" prefixes of zbarimg are ignored.
+pub fn reassemble(scanned: &[u8]) -> Result<Vec<u8>, Error> {
+    let invalid = |message: &str| Error::Qr(message.to_string());
+    let text = std::str::from_utf8(scanned).map_err(|_| invalid("the scanned text isn't valid UTF-8"))?;
+    let mut parts: Vec<Option<String>> = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+    let store = |parts: &mut Vec<Option<String>>, current: Option<(usize, String)>| match current {
+        Some((i, content)) => match parts.get_mut(i) {
+            Some(slot @ None) => {
+                *slot = Some(content);
+                Ok(())
+            }
+            Some(Some(existing)) if existing.trim_end() == content.trim_end() => Ok(()),
+            _ => Err(invalid("the same part was scanned twice with different contents")),
+        },
+        None => Ok(()),
+    };
+    for line in text.split_inclusive('\n') {
+        let line = line.strip_prefix("QR-Code:").unwrap_or(line);
+        match line.trim_end().strip_prefix(QR_MARKER) {
+            Some(numbers) => {
+                store(&mut parts, current.take())?;
+                let (i, n) = numbers.split_once('/')
+                    .and_then(|(i, n)| Some((i.parse::<usize>().ok()?, n.parse::<usize>().ok()?)))
+                    .filter(|(i, n)| (1..=*n).contains(i))
+                    .ok_or_else(|| invalid("invalid DOBY-QR line"))?;
+                if parts.is_empty() {
+                    parts = vec![None; n];
+                } else if parts.len() != n {
+                    return Err(invalid("the codes belong to different ciphertexts"));
+                }
+                current = Some((i - 1, String::new()));
+            }
+            None => match current.as_mut() {
+                Some((_, content)) => content.push_str(line),
+                None if line.trim().is_empty() => {}
+                None => return Err(invalid("text before the first DOBY-QR line")),
+            },
+        }
+    }
+    store(&mut parts, current)?;
+    let missing: Vec<String> = parts.iter().enumerate().filter(|(_, part)| part.is_none()).map(|(i, _)| (i + 1).to_string()).collect();
+    if !missing.is_empty() {
+        return Err(Error::Qr(format!("missing part {} of {}", missing.join(", "), parts.len())));
+    }
+    let mut armored = String::new();
+    for part in parts.into_iter().flatten() {
+        armored.push_str(&part);
+        //a scanner may have dropped the final line break
+        if !armored.ends_with('\n') {
+            armored.push('\n');
+        }
+    }
+    Ok(armored.into_bytes())
+}
+
+/// Reads the text scanned from QR codes (see `is_qr_text`) and reassembles the armored ciphertext.
+pub fn read_scanned<R: Read>(reader: R) -> Result<Vec<u8>, Error> {
+    //the headers and the scanner prefixes come in addition to the armored text
+    let limit = 2 * MAX_QR_INPUT_LEN as u64;
+    let mut scanned = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut scanned)?;
+    if scanned.len() as u64 > limit {
+        return Err(Error::Qr(String::from("the scanned text is too large")));
+    }
+    reassemble(&scanned)
+}
+
+/// Renders the codes as text: two rows of modules per line, with Unicode half blocks in black on white.
+pub fn render_text<W: Write>(mut writer: W, codes: &[QrCode], captions: &[String]) -> io::Result<()> {
+    for (qr, caption) in codes.iter().zip(captions) {
+        let start = -(QUIET_ZONE as isize);
+        let end = (qr.size() + QUIET_ZONE) as isize;
+        for y in (start..end).step_by(2) {
+            let line: String = (start..end).map(|x| match (qr.module_or_light(x, y), qr.module_or_light(x, y + 1)) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            }).collect();
+            writeln!(writer, "\x1b[30;47m{}\x1b[0m", line)?;
+        }
+        writeln!(writer, "{}", caption)?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Renders the codes as a black and white PNG image, one below the other.
+pub fn render_png<W: Write>(mut writer: W, codes: &[QrCode]) -> io::Result<()> {
+    let width_modules = codes.iter().map(|qr| qr.size() + 2 * QUIET_ZONE).max().unwrap_or(0);
+    let width = width_modules * PNG_SCALE;
+    let row_len = width.div_ceil(8) + 1;
+    let mut raw = Vec::new();
+    for qr in codes {
+        for y in -(QUIET_ZONE as isize)..(qr.size() + QUIET_ZONE) as isize {
+            let mut row = vec![0xFF; row_len];
+            //no filter
+            row[0] = 0;
+            for x in 0..width_modules {
+                if qr.module_or_light(x as isize - QUIET_ZONE as isize, y) {
+                    for px in x * PNG_SCALE..(x + 1) * PNG_SCALE {
+                        row[1 + px / 8] &= !(0x80 >> (px % 8));
+                    }
+                }
+            }
+            for _ in 0..PNG_SCALE {
+                raw.extend_from_slice(&row);
+            }
+        }
+    }
+    let height = raw.len() / row_len;
+
+    writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    //1 bit grayscale, deflate, no interlacing
+    ihdr.extend_from_slice(&[1, 0, 0, 0, 0]);
+    write_chunk(&mut writer, b"IHDR", &ihdr)?;
+    write_chunk(&mut writer, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(&mut writer, b"IEND", &[])?;
+    writer.flush()
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let mut crc = Crc32::default();
+    crc.update(kind);
+    crc.update(data);
+    writer.write_all(&crc.finish().to_be_bytes())
+}
+
+//zlib stream of uncompressed deflate blocks: the images are small and mostly made of 0xFF and 0x00 runs anyway
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        output.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        output.push(u8::from(blocks.peek().is_none()));
+        output.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        output.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    output.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    output
+}
+
+struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self(0xFFFFFFFF)
+    }
+}
+
+impl Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= *byte as u32;
+            for _ in 0..8 {
+                self.0 = (self.0 >> 1) ^ (0xEDB88320 & (self.0 & 1).wrapping_neg());
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// Collects an armored ciphertext, and writes it as QR codes on `finish`.
+pub struct QrWriter<W: Write> {
+    writer: W,
+    format: QrFormat,
+    armored: Vec<u8>,
+}
+
+impl<W: Write> QrWriter<W> {
+    pub fn new(writer: W, format: QrFormat) -> Self {
+        Self { writer, format, armored: Vec::new() }
+    }
+
+    /// Renders the codes and returns the inner writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        let parts = split(&self.armored);
+        let codes = parts.iter().map(|part| QrCode::encode(part)).collect::<Result<Vec<QrCode>, Error>>()?;
+        match self.format {
+            QrFormat::Text => {
+                let captions: Vec<String> = (1..=codes.len()).map(|i| format!("{}{}/{}", QR_MARKER, i, codes.len())).collect();
+                render_text(&mut self.writer, &codes, &captions)?;
+            }
+            QrFormat::Png => render_png(&mut self.writer, &codes)?,
+        }
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for QrWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.armored.len() + buf.len() > MAX_QR_INPUT_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the ciphertext is too large for QR codes (64KiB at most once armored)"));
+        }
+        self.armored.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
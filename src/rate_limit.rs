@@ -0,0 +1,83 @@
+use std::{io::{self, Read}, sync::{Arc, Mutex, PoisonError}, thread, time::{Duration, Instant}};
+
+//after an idle period, at most this much of the unused rate can be spent at once
+const MAX_BURST: Duration = Duration::from_secs(1);
+//reads are split so that throughput stays smooth instead of alternating large reads and long sleeps
+const READS_PER_SECOND: u64 = 10;
+
+struct Window {
+    start: Instant,
+    consumed: u64,
+}
+
+/// Limits the throughput of one or more readers (e.g. the inputs processed concurrently by a batch) to `bytes_per_second`.
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    window: Mutex<Window>,
+}
+
+impl RateLimiter {
+    /// `bytes_per_second` must not be 0.
+    pub fn new(bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second > 0, "the rate limit must not be 0");
+        Self {
+            bytes_per_second,
+            window: Mutex::new(Window { start: Instant::now(), consumed: 0 }),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Accounts for `n` bytes processed, sleeping as long as needed to stay under the limit.
+    pub fn consume(&self, n: u64) {
+        let mut window = self.window.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if now.duration_since(window.start) > self.duration_of(window.consumed) + MAX_BURST {
+            *window = Window { start: now, consumed: 0 };
+        }
+        window.consumed += n;
+        let deadline = window.start + self.duration_of(window.consumed);
+        //the other threads can take their turn while this one sleeps
+        drop(window);
+        if let Some(delay) = deadline.checked_duration_since(now) {
+            thread::sleep(delay);
+        }
+    }
+
+    fn duration_of(&self, bytes: u64) -> Duration {
+        Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64)
+    }
+}
+
+/// Reads from `reader` no faster than the rate of its `RateLimiter`.
+pub struct RateLimitedReader<R: Read> {
+    reader: R,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<R: Read> RateLimitedReader<R> {
+    pub fn new(reader: R, bytes_per_second: u64) -> Self {
+        Self::with_limiter(reader, Arc::new(RateLimiter::new(bytes_per_second)))
+    }
+
+    /// Shares the limit with the other readers of `limiter`.
+    pub fn with_limiter(reader: R, limiter: Arc<RateLimiter>) -> Self {
+        Self { reader, limiter }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Read for RateLimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max_len = (self.limiter.bytes_per_second / READS_PER_SECOND).max(1);
+        let len = buf.len().min(usize::try_from(max_len).unwrap_or(usize::MAX));
+        let n = self.reader.read(&mut buf[..len])?;
+        self.limiter.consume(n as u64);
+        Ok(n)
+    }
+}
@@ -0,0 +1,176 @@
+use std::{fmt::{self, Display, Formatter}, fs, path::Path};
+use blake2::Blake2b;
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+use crate::{Error, crypto::{KEY_ID_LEN, KEY_LEN, KeySlot, X25519_KEY_LEN, key_id, open_key, seal_key}, plugin::{PLUGIN_IDENTITY_PREFIX, PLUGIN_RECIPIENT_PREFIX, PluginIdentity, PluginRecipient, unwrap_with_plugins}};
+
+pub const PUBLIC_KEY_PREFIX: &str = "doby-pk-";
+pub const SECRET_KEY_PREFIX: &str = "doby-sk-";
+
+fn decode_key(s: &str, prefix: &str) -> Option<[u8; X25519_KEY_LEN]> {
+    s.strip_prefix(prefix)
+        .and_then(|encoded| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok())
+        .and_then(|decoded| decoded.try_into().ok())
+}
+
+/// Key wrapping key for one recipient. Each slot uses a fresh ephemeral key so the nonce can be constant.
+fn wrapping_key(shared_secret: &[u8], ephemeral_public: &[u8], recipient: &[u8]) -> [u8; KEY_LEN] {
+    let mut salt = Vec::with_capacity(X25519_KEY_LEN*2);
+    salt.extend_from_slice(ephemeral_public);
+    salt.extend_from_slice(recipient);
+    let hkdf = Hkdf::<Blake2b>::new(Some(&salt), shared_secret);
+    let mut key = [0; KEY_LEN];
+    hkdf.expand(b"doby_x25519_wrapping_key", &mut key).unwrap();
+    key
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum RecipientKind {
+    X25519(PublicKey),
+    Plugin(PluginRecipient),
+}
+
+/// X25519 public key files can be encrypted to, or recipient handled by a plugin (`doby-plugin-pk-<name>:...`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recipient(RecipientKind);
+
+impl Recipient {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let s = s.trim();
+        if s.starts_with(PLUGIN_RECIPIENT_PREFIX) {
+            return PluginRecipient::parse(s).map(|recipient| Self(RecipientKind::Plugin(recipient)));
+        }
+        decode_key(s, PUBLIC_KEY_PREFIX)
+            .map(|key| Self(RecipientKind::X25519(PublicKey::from(key))))
+            .ok_or_else(|| Error::InvalidRecipient(s.to_string()))
+    }
+
+    /// ID stored in the header to find the identity of this recipient. `None` for plugin recipients.
+    pub fn key_id(&self) -> Option<[u8; KEY_ID_LEN]> {
+        match &self.0 {
+            RecipientKind::X25519(public_key) => Some(key_id(public_key.as_bytes())),
+            RecipientKind::Plugin(_) => None,
+        }
+    }
+
+    /// Encrypts `master_key` in a new key slot. Only fails for plugin recipients, whose plugin is run.
+    pub fn wrap(&self, master_key: &[u8; KEY_LEN]) -> Result<KeySlot, Error> {
+        let public_key = match &self.0 {
+            RecipientKind::X25519(public_key) => public_key,
+            RecipientKind::Plugin(recipient) => return recipient.wrap(master_key),
+        };
+        let ephemeral_secret = StaticSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral_secret);
+        let shared_secret = ephemeral_secret.diffie_hellman(public_key);
+        let mut wrapping_key = wrapping_key(shared_secret.as_bytes(), ephemeral_public.as_bytes(), public_key.as_bytes());
+        let wrapped_key = seal_key(&wrapping_key, master_key);
+        wrapping_key.zeroize();
+        Ok(KeySlot::X25519 {
+            ephemeral_public: ephemeral_public.to_bytes(),
+            wrapped_key,
+        })
+    }
+}
+
+impl Display for Recipient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            RecipientKind::X25519(public_key) => write!(f, "{}{}", PUBLIC_KEY_PREFIX, base64::encode_config(public_key.as_bytes(), base64::URL_SAFE_NO_PAD)),
+            RecipientKind::Plugin(recipient) => recipient.fmt(f),
+        }
+    }
+}
+
+enum IdentityKind {
+    X25519(StaticSecret),
+    Plugin(PluginIdentity),
+}
+
+/// X25519 secret key, able to decrypt files encrypted to the corresponding `Recipient`, or identity handled by a plugin (`doby-plugin-sk-<name>:...`).
+pub struct Identity(IdentityKind);
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self(IdentityKind::X25519(StaticSecret::random_from_rng(OsRng)))
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let s = s.trim();
+        if s.starts_with(PLUGIN_IDENTITY_PREFIX) {
+            return PluginIdentity::parse(s).map(|identity| Self(IdentityKind::Plugin(identity)));
+        }
+        //the invalid value isn't included in the error as it may be a secret
+        let mut key = decode_key(s, SECRET_KEY_PREFIX).ok_or(Error::InvalidIdentity)?;
+        let identity = Self(IdentityKind::X25519(StaticSecret::from(key)));
+        key.zeroize();
+        Ok(identity)
+    }
+
+    /// Reads all identities of an identity file, ignoring empty lines and comments starting with '#'.
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, Error> {
+        let mut content = fs::read_to_string(path.as_ref())
+            .map_err(|error| Error::Path { path: path.as_ref().display().to_string(), error })?;
+        let identities = Self::parse_file_content(&content);
+        content.zeroize();
+        identities
+    }
+
+    /// Same as `read_file`, for identity file content that is already in memory.
+    pub fn parse_file_content(content: &str) -> Result<Vec<Self>, Error> {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Self::parse)
+            .collect()
+    }
+
+    /// `None` for plugin identities, whose recipient is only known to the plugin.
+    pub fn recipient(&self) -> Option<Recipient> {
+        match &self.0 {
+            IdentityKind::X25519(secret) => Some(Recipient(RecipientKind::X25519(PublicKey::from(secret)))),
+            IdentityKind::Plugin(_) => None,
+        }
+    }
+
+    /// Identity file content: the public key as a comment followed by the secret key.
+    pub fn to_file_content(&self) -> String {
+        match &self.0 {
+            IdentityKind::X25519(secret) => format!(
+                "# public key: {}\n{}{}\n",
+                Recipient(RecipientKind::X25519(PublicKey::from(secret))),
+                SECRET_KEY_PREFIX,
+                base64::encode_config(secret.as_bytes(), base64::URL_SAFE_NO_PAD)
+            ),
+            IdentityKind::Plugin(identity) => identity.to_file_content(),
+        }
+    }
+
+    /// Returns the master key if `key_slot` was encrypted to this X25519 identity. Plugin identities are only used by `unwrap_with_identities`.
+    pub fn unwrap(&self, key_slot: &KeySlot) -> Option<[u8; KEY_LEN]> {
+        match (&self.0, key_slot) {
+            (IdentityKind::X25519(secret), KeySlot::X25519 { ephemeral_public, wrapped_key }) => {
+                let shared_secret = secret.diffie_hellman(&PublicKey::from(*ephemeral_public));
+                let mut wrapping_key = wrapping_key(shared_secret.as_bytes(), ephemeral_public, PublicKey::from(secret).as_bytes());
+                let master_key = open_key(&wrapping_key, wrapped_key);
+                wrapping_key.zeroize();
+                master_key
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Tries every X25519 identity on every key slot, then has the plugins of the plugin identities try theirs.
+pub fn unwrap_with_identities(identities: &[Identity], key_slots: &[KeySlot]) -> Result<Option<[u8; KEY_LEN]>, Error> {
+    if let Some(master_key) = identities.iter().find_map(|identity| key_slots.iter().find_map(|key_slot| identity.unwrap(key_slot))) {
+        return Ok(Some(master_key));
+    }
+    let plugin_identities: Vec<&PluginIdentity> = identities.iter().filter_map(|identity| match &identity.0 {
+        IdentityKind::Plugin(identity) => Some(identity),
+        IdentityKind::X25519(_) => None,
+    }).collect();
+    unwrap_with_plugins(&plugin_identities, key_slots)
+}
@@ -0,0 +1,26 @@
+//! HTTP(S) and S3 inputs and outputs, streamed through `curl` and `aws s3 cp` (AWS CLI) so that nothing touches the local disk. Credentials, proxies and regions come from the usual configuration of these programs.
+
+use std::{io, process::{ChildStdin, Command}};
+use crate::child::{ChildReader, ChildSink};
+
+fn is_s3(url: &str) -> bool {
+    url.as_bytes().get(..5).is_some_and(|scheme| scheme.eq_ignore_ascii_case(b"s3://"))
+}
+
+/// Starts downloading `url`. Reaching the end of the `ChildReader` fails if the download failed.
+pub fn download(url: &str) -> io::Result<ChildReader> {
+    if is_s3(url) {
+        ChildReader::spawn(Command::new("aws").args(["s3", "cp", "--quiet", url, "-"]))
+    } else {
+        ChildReader::spawn(Command::new("curl").args(["--fail", "--silent", "--show-error", "--location", "--url", url]))
+    }
+}
+
+/// Starts uploading to `url` what is written to the `ChildStdin`: an HTTP PUT for `http(s)://` URLs, a (multipart) S3 upload for `s3://` URLs. The upload only completes on `ChildSink::finish`.
+pub fn upload(url: &str) -> io::Result<(ChildSink, ChildStdin)> {
+    if is_s3(url) {
+        ChildSink::spawn(Command::new("aws").args(["s3", "cp", "--quiet", "-", url]))
+    } else {
+        ChildSink::spawn(Command::new("curl").args(["--fail", "--silent", "--show-error", "--upload-file", "-", "--url", url]))
+    }
+}
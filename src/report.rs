@@ -0,0 +1,110 @@
+//! Messages written on stderr through the `log` facade: human-readable by default, or one JSON object per line with `--json` so that wrappers can parse outcomes.
+//!
+//! Each JSON object has an `event` field: `warning` (with a `message`), `log` (other messages, with their `level`), `result` (the outcome of an operation on one file), `stats` (with `--stats`) or `error` (why the command failed, always last).
+
+use std::{sync::atomic::{AtomicBool, Ordering}, time::Instant};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use crate::{Error, inspect::json_string};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+struct Logger {
+    start: Instant,
+}
+
+impl Log for Logger {
+    //debug messages of dependencies aren't useful to users
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info || metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        if is_json() {
+            match record.level() {
+                Level::Warn => Event::new("warning"),
+                level => Event::new("log").string("level", &level.as_str().to_lowercase()),
+            }.string("message", &message).emit();
+        } else {
+            match record.level() {
+                Level::Error => eprintln!("Error: {}", message),
+                Level::Warn => eprintln!("Warning: {}", message),
+                Level::Info => eprintln!("{}", message),
+                //timestamps show where the time goes
+                Level::Debug | Level::Trace => eprintln!("[{:8.3}s] {}", self.start.elapsed().as_secs_f64(), message),
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, called by the command line parser. `level` is set by `-v` and `-q`.
+pub fn init(json: bool, level: LevelFilter) {
+    JSON.store(json, Ordering::Relaxed);
+    if log::set_boxed_logger(Box::new(Logger { start: Instant::now() })).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// A JSON object written on a single line.
+pub struct Event {
+    fields: Vec<String>,
+}
+
+impl Event {
+    pub fn new(event: &str) -> Self {
+        Self { fields: Vec::new() }.string("event", event)
+    }
+
+    fn field(mut self, key: &str, value: String) -> Self {
+        self.fields.push(format!("{}:{}", json_string(key), value));
+        self
+    }
+
+    pub fn string(self, key: &str, value: &str) -> Self {
+        self.field(key, json_string(value))
+    }
+
+    /// `null` if `value` is `None`, e.g. for stdin and stdout.
+    pub fn optional_string(self, key: &str, value: Option<&str>) -> Self {
+        self.field(key, value.map(json_string).unwrap_or_else(|| String::from("null")))
+    }
+
+    pub fn number(self, key: &str, value: u64) -> Self {
+        self.field(key, value.to_string())
+    }
+
+    pub fn boolean(self, key: &str, value: bool) -> Self {
+        self.field(key, value.to_string())
+    }
+
+    /// Adds the `kind` and the `message` of `e`.
+    pub fn error(self, e: &Error) -> Self {
+        self.string("kind", e.kind()).string("message", &e.to_string())
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+
+    pub fn emit(&self) {
+        eprintln!("{}", self.to_json());
+    }
+}
+
+/// Reports why the command failed. It is never silenced by `-q`.
+pub fn error(e: &Error) {
+    if is_json() {
+        Event::new("error").error(e).emit();
+    } else {
+        eprintln!("Error: {}", e);
+    }
+}
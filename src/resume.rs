@@ -0,0 +1,167 @@
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}, time::{Duration, Instant, UNIX_EPOCH}};
+use crate::{OutputWriter, crypto::{AEAD_CHUNK_SIZE, AEAD_ENCRYPTED_CHUNK_LEN}, os::{create_temporary, is_temporary_suffix}};
+
+const STATE_VERSION: &str = "doby-resume 2";
+
+/// Progress of an encryption started with `--resume`, saved next to the output as `<output>.resume` so that it can continue after an interruption.
+///
+/// Only whole AEAD chunks are recorded: encryption continues at `chunks*AEAD_CHUNK_SIZE` bytes of the plaintext, which are `header_len + chunks*(AEAD_CHUNK_SIZE+AEAD_TAG_LEN)` bytes into the partial output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResumeState {
+    pub input_len: u64,
+    //nanoseconds since the Unix epoch
+    pub input_modified: u128,
+    /// Length of the magic bytes and encoded parameters at the beginning of the output.
+    pub header_len: u64,
+    /// Length of the plaintext preceding INPUT, i.e. of the encoded metadata.
+    pub prefix_len: u64,
+    /// Number of chunks committed to disk.
+    pub chunks: u32,
+    /// Random part of the name of the partial output, given by `OutputWriter::temporary_suffix`.
+    pub partial: String,
+}
+
+fn modified(input: &fs::Metadata) -> u128 {
+    input.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map_or(0, |time| time.as_nanos())
+}
+
+fn invalid_state() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid resume state")
+}
+
+impl ResumeState {
+    pub fn new(input: &fs::Metadata, header_len: u64, prefix_len: u64, partial: &str) -> Self {
+        Self {
+            input_len: input.len(),
+            input_modified: modified(input),
+            header_len,
+            prefix_len,
+            chunks: 0,
+            partial: partial.to_string(),
+        }
+    }
+
+    /// Where the state of the encryption to `output` is saved.
+    pub fn path<P: AsRef<Path>>(output: P) -> PathBuf {
+        let mut path = output.as_ref().as_os_str().to_owned();
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    /// `None` if there is no state at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Option<Self>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut lines = content.lines();
+        if lines.next() != Some(STATE_VERSION) {
+            return Err(invalid_state());
+        }
+        let mut field = |name: &str| lines.next()
+            .and_then(|line| line.strip_prefix(name))
+            .and_then(|value| value.strip_prefix(' '))
+            .ok_or_else(invalid_state);
+        Ok(Some(Self {
+            input_len: field("input_len")?.parse().map_err(|_| invalid_state())?,
+            input_modified: field("input_modified")?.parse().map_err(|_| invalid_state())?,
+            header_len: field("header_len")?.parse().map_err(|_| invalid_state())?,
+            prefix_len: field("prefix_len")?.parse().map_err(|_| invalid_state())?,
+            chunks: field("chunks")?.parse().map_err(|_| invalid_state())?,
+            partial: Some(field("partial")?).filter(|suffix| is_temporary_suffix(suffix)).ok_or_else(invalid_state)?.to_string(),
+        }))
+    }
+
+    /// Replaces the state at `path` atomically, committing it to disk.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let (mut file, tmp, _) = create_temporary(path.as_ref())?;
+        let result = write!(file, "{}\ninput_len {}\ninput_modified {}\nheader_len {}\nprefix_len {}\nchunks {}\npartial {}\n", STATE_VERSION, self.input_len, self.input_modified, self.header_len, self.prefix_len, self.chunks, self.partial)
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::rename(&tmp, path));
+        if result.is_err() {
+            let _ = fs::remove_file(tmp);
+        }
+        result
+    }
+
+    /// Removes the state at `path` if any, e.g. once the encryption succeeded.
+    pub fn remove<P: AsRef<Path>>(path: P) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `input` looks unchanged since the state was created.
+    pub fn matches(&self, input: &fs::Metadata) -> bool {
+        self.input_len == input.len() && self.input_modified == modified(input)
+    }
+
+    /// Offset of the plaintext where encryption continues.
+    pub fn plaintext_offset(&self) -> u64 {
+        self.chunks as u64 * AEAD_CHUNK_SIZE as u64
+    }
+
+    /// Length of the partial output to keep.
+    pub fn output_len(&self) -> u64 {
+        self.header_len + self.chunks as u64 * AEAD_ENCRYPTED_CHUNK_LEN as u64
+    }
+}
+
+/// Saves a `ResumeState` every `interval` while writing the output of an AEAD encryption, after committing the chunks written so far to disk.
+pub struct ResumeWriter<'a> {
+    writer: &'a mut OutputWriter,
+    state: ResumeState,
+    path: PathBuf,
+    interval: Duration,
+    last_checkpoint: Instant,
+}
+
+impl<'a> ResumeWriter<'a> {
+    /// `writer` must be positioned at `state.output_len()`.
+    pub fn new<P: Into<PathBuf>>(writer: &'a mut OutputWriter, state: ResumeState, path: P, interval: Duration) -> Self {
+        Self {
+            writer,
+            state,
+            path: path.into(),
+            interval,
+            last_checkpoint: Instant::now(),
+        }
+    }
+
+    fn checkpoint(&mut self) -> io::Result<()> {
+        //a chunk not followed by anything yet may be the last one, after which nothing can be appended
+        let chunks = self.writer.written().saturating_sub(self.state.header_len + 1) / AEAD_ENCRYPTED_CHUNK_LEN as u64;
+        let chunks = u32::try_from(chunks).map_err(|_| io::Error::other("too many chunks"))?;
+        if chunks > self.state.chunks {
+            self.writer.sync()?;
+            self.state.chunks = chunks;
+            self.state.save(&self.path)?;
+        }
+        self.last_checkpoint = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for ResumeWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        if self.last_checkpoint.elapsed() >= self.interval {
+            self.checkpoint()?;
+        }
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let n = self.writer.write_vectored(bufs)?;
+        if self.last_checkpoint.elapsed() >= self.interval {
+            self.checkpoint()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,132 @@
+//! Restricts the process once its inputs, outputs and keys are ready, so that a bug in the parsing of the ciphertext can't be used to read other files or send them away.
+//!
+//! On Linux, Landlock limits filesystem access to the allowed paths and a seccomp filter denies network sockets, program execution and access to other processes. On OpenBSD, unveil and pledge do the same. Sandboxing is best effort: older kernels only get the restrictions they support, and other platforms none.
+
+use std::path::{Path, PathBuf};
+use log::debug;
+use crate::Error;
+
+/// Paths the process can still access once sandboxed. Files already open are not affected.
+#[derive(Default)]
+pub struct Sandbox {
+    disabled: bool,
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+    children: bool,
+}
+
+impl Sandbox {
+    /// A sandbox that doesn't restrict anything (`--no-sandbox`).
+    pub fn disabled() -> Self {
+        Self { disabled: true, ..Default::default() }
+    }
+
+    /// Allows reading `path`, and everything beneath it if it's a directory.
+    pub fn allow_read<P: Into<PathBuf>>(&mut self, path: P) {
+        self.read.push(path.into());
+    }
+
+    /// Allows creating, writing, renaming and removing files and directories beneath the directory `path`, but not reading them.
+    pub fn allow_write<P: Into<PathBuf>>(&mut self, path: P) {
+        self.write.push(path.into());
+    }
+
+    /// Allows writing to the parent directory of `path`, e.g. to create a temporary file next to it and rename it.
+    pub fn allow_write_next_to<P: AsRef<Path>>(&mut self, path: P) {
+        self.allow_write(match path.as_ref().parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        });
+    }
+
+    /// Allows waiting for and killing the child processes already running, such as the `curl` of a URL INPUT or OUTPUT. No new ones can be started.
+    pub fn allow_child_processes(&mut self) {
+        self.children = true;
+    }
+
+    /// Restricts the calling thread and the threads it spawns afterwards. The seccomp filter also applies to the threads already running.
+    ///
+    /// Paths that don't exist are ignored.
+    pub fn apply(&self) -> Result<(), Error> {
+        if self.disabled {
+            return Ok(());
+        }
+        self.restrict()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn restrict(&self) -> Result<(), Error> {
+        use std::{collections::BTreeMap, convert::TryInto};
+        use landlock::{ABI, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, path_beneath_rules};
+        use seccompiler::{SeccompAction, SeccompFilter};
+
+        let abi = ABI::V5;
+        let status = Ruleset::default()
+            .handle_access(AccessFs::from_all(abi))
+            .and_then(|ruleset| ruleset.create())
+            .and_then(|ruleset| ruleset.add_rules(path_beneath_rules(&self.read, AccessFs::from_read(abi))))
+            //listing directories is needed to commit them to disk
+            .and_then(|ruleset| ruleset.add_rules(path_beneath_rules(&self.write, AccessFs::from_write(abi) | AccessFs::ReadDir)))
+            .and_then(|ruleset| ruleset.restrict_self())
+            .map_err(|e| Error::Sandbox(e.to_string()))?;
+        debug!("Landlock: {:?}", status.ruleset);
+
+        let mut syscalls = vec![
+            libc::SYS_socket, libc::SYS_socketpair, libc::SYS_connect, libc::SYS_bind, libc::SYS_listen, libc::SYS_accept4,
+            libc::SYS_sendto, libc::SYS_sendmsg, libc::SYS_sendmmsg,
+            libc::SYS_execve, libc::SYS_execveat,
+            libc::SYS_ptrace, libc::SYS_process_vm_readv, libc::SYS_process_vm_writev,
+        ];
+        #[cfg(target_arch = "x86_64")]
+        syscalls.extend_from_slice(&[libc::SYS_accept, libc::SYS_fork, libc::SYS_vfork]);
+        let rules = syscalls.into_iter().map(|syscall| (syscall, Vec::new())).collect::<BTreeMap<_, _>>();
+        let arch = match std::env::consts::ARCH.try_into() {
+            Ok(arch) => arch,
+            Err(_) => {
+                debug!("seccomp: unsupported architecture");
+                return Ok(());
+            }
+        };
+        let filter: seccompiler::BpfProgram = SeccompFilter::new(rules, SeccompAction::Allow, SeccompAction::Errno(libc::EPERM as u32), arch)
+            .and_then(TryInto::try_into)
+            .map_err(|e| Error::Sandbox(e.to_string()))?;
+        match seccompiler::apply_filter_all_threads(&filter) {
+            Ok(()) => debug!("seccomp: filter installed"),
+            //kernels without seccomp
+            Err(e) => debug!("seccomp: {}", e),
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "openbsd")]
+    fn restrict(&self) -> Result<(), Error> {
+        use std::{ffi::CString, io, os::unix::ffi::OsStrExt, ptr};
+        let c_string = |s: &[u8]| CString::new(s).map_err(|_| Error::Sandbox(String::from("path contains a NUL byte")));
+        let unveil = |path: &Path, permissions: &str| {
+            //SAFETY: both strings are NUL-terminated
+            match unsafe { libc::unveil(c_string(path.as_os_str().as_bytes())?.as_ptr(), c_string(permissions.as_bytes())?.as_ptr()) } {
+                0 => Ok(()),
+                _ if io::Error::last_os_error().kind() == io::ErrorKind::NotFound => Ok(()),
+                _ => Err(Error::Sandbox(format!("unveil: {}", io::Error::last_os_error()))),
+            }
+        };
+        for path in &self.read {
+            unveil(path, "r")?;
+        }
+        for path in &self.write {
+            unveil(path, "rwc")?;
+        }
+        let promises = c_string(if self.children { b"stdio rpath wpath cpath fattr proc" } else { b"stdio rpath wpath cpath fattr" })?;
+        //SAFETY: unveil(NULL, NULL) locks the unveiled paths, and promises is NUL-terminated
+        if unsafe { libc::unveil(ptr::null(), ptr::null()) } != 0 || unsafe { libc::pledge(promises.as_ptr(), ptr::null()) } != 0 {
+            return Err(Error::Sandbox(io::Error::last_os_error().to_string()));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "openbsd")))]
+    fn restrict(&self) -> Result<(), Error> {
+        debug!("sandboxing is not supported on this platform");
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+use std::{io::{self, Read, Write}, net::Shutdown, os::unix::net::UnixStream, path::Path};
+#[cfg(feature = "cli")]
+use {
+    std::{fs, mem, os::unix::net::UnixListener, sync::{Condvar, Mutex}, thread},
+    log::{debug, error, info, warn},
+    zeroize::Zeroizing,
+    crate::{
+        Error,
+        MAGIC_BYTES,
+        PlaintextDigest,
+        WrappedPassword,
+        auto_block_size,
+        cli::{BatchArgs, ServeArgs},
+        crypto::{DobyCipher, EncryptionParams, KeyDerivation, KeySlot, MasterKey},
+        encrypt_slice,
+        files::{SharedKey, decrypt_metadata},
+        is_armored,
+        is_doby_format,
+        is_ecc,
+        keying::master_key,
+        memlock::Locked,
+        qr::is_qr_text,
+        read_header,
+        recipient::unwrap_with_identities,
+        signature::SignatureReader,
+    },
+};
+
+/// Largest frame: longer data is split into several frames.
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+/// Largest data accepted in a request or a response, as it is held in memory.
+pub const MAX_DATA_LEN: usize = 1 << 30;
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+/// What a client asks `doby serve` to do with the data of its request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Encrypt,
+    Decrypt,
+}
+
+impl Operation {
+    fn to_byte(self) -> u8 {
+        match self {
+            Operation::Encrypt => b'E',
+            Operation::Decrypt => b'D',
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b'E' => Some(Operation::Encrypt),
+            b'D' => Some(Operation::Decrypt),
+            _ => None,
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Writes `data` preceded by its length as a 32-bit big-endian integer.
+pub fn write_frame<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    if data.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too long"));
+    }
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(data)
+}
+
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data("frame too long"));
+    }
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Writes `data` as frames of up to `MAX_FRAME_LEN` bytes, followed by an empty frame.
+pub fn write_data<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    for frame in data.chunks(MAX_FRAME_LEN) {
+        write_frame(writer, frame)?;
+    }
+    write_frame(writer, &[])
+}
+
+/// Reads frames until an empty one, failing if they hold more than `MAX_DATA_LEN` bytes.
+pub fn read_data<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let frame = read_frame(reader)?;
+        if frame.is_empty() {
+            return Ok(data);
+        }
+        if data.len() + frame.len() > MAX_DATA_LEN {
+            return Err(invalid_data("data too long"));
+        }
+        data.extend_from_slice(&frame);
+    }
+}
+
+/// A request is a frame holding the operation, followed by the data.
+pub fn write_request<W: Write>(writer: &mut W, operation: Operation, data: &[u8]) -> io::Result<()> {
+    write_frame(writer, &[operation.to_byte()])?;
+    write_data(writer, data)?;
+    writer.flush()
+}
+
+pub fn read_request<R: Read>(reader: &mut R) -> io::Result<(Operation, Vec<u8>)> {
+    let operation = match read_frame(reader)?.as_slice() {
+        [byte] => Operation::from_byte(*byte),
+        _ => None,
+    }.ok_or_else(|| invalid_data("unknown operation"))?;
+    Ok((operation, read_data(reader)?))
+}
+
+/// A response is a status frame, followed by the output if it succeeded. The status frame of a failure also holds the error message.
+pub fn write_response<W: Write>(writer: &mut W, result: Result<&[u8], &str>) -> io::Result<()> {
+    match result {
+        Ok(data) => {
+            write_frame(writer, &[STATUS_OK])?;
+            write_data(writer, data)?;
+        }
+        Err(message) => {
+            let mut frame = vec![STATUS_ERROR];
+            frame.extend_from_slice(&message.as_bytes()[..message.len().min(MAX_FRAME_LEN - 1)]);
+            write_frame(writer, &frame)?;
+        }
+    }
+    writer.flush()
+}
+
+/// Returns the output, or the error message of the server.
+pub fn read_response<R: Read>(reader: &mut R) -> io::Result<Result<Vec<u8>, String>> {
+    let status = read_frame(reader)?;
+    match status.split_first() {
+        Some((&STATUS_OK, [])) => read_data(reader).map(Ok),
+        Some((&STATUS_ERROR, message)) => Ok(Err(String::from_utf8_lossy(message).into_owned())),
+        _ => Err(invalid_data("invalid status")),
+    }
+}
+
+/// Sends a request to the `doby serve` listening at `socket` and waits for its response.
+pub fn request<P: AsRef<Path>>(socket: P, operation: Operation, data: &[u8]) -> io::Result<Result<Vec<u8>, String>> {
+    let mut stream = UnixStream::connect(socket)?;
+    write_request(&mut stream, operation, data)?;
+    stream.shutdown(Shutdown::Write)?;
+    read_response(&mut stream)
+}
+
+//keys opened by serve kept in memory along with those of its own key slots
+#[cfg(feature = "cli")]
+const MAX_CACHED_KEYS: usize = 256;
+/// Requests handled at once by `doby serve`, each one holding up to `MAX_DATA_LEN` bytes of input and its output in memory. Further connections wait in the backlog of the socket.
+#[cfg(feature = "cli")]
+pub const MAX_CONNECTIONS: usize = 8;
+
+/// Keys of `doby serve`, kept in locked memory until it exits.
+#[cfg(feature = "cli")]
+struct Server {
+    key: SharedKey,
+    //opens the key slots of the files to decrypt along with the identities and the PKCS#11 key
+    password: Option<Locked<String>>,
+    args: BatchArgs,
+    //master keys of the key slots opened so far, starting with those of the outputs
+    master_keys: Mutex<Vec<(Vec<KeySlot>, MasterKey)>>,
+    //whether the first master key is the one of the outputs, which must never be evicted
+    pinned: bool,
+}
+
+#[cfg(feature = "cli")]
+impl Server {
+    fn new(mut args: BatchArgs) -> Result<Self, Error> {
+        let use_password = args.raw_key.is_none() && (args.password.is_provided() || args.key_slots.recipients.is_empty() && args.key_slots.ssh_keys.is_empty() && args.key_slots.pkcs11.is_none() && args.encryption.kms_key_id.is_none());
+        let password = if use_password { Some(mem::take(&mut args.password).get(true)?) } else { None };
+        args.password = password.clone().into();
+        let key = SharedKey::new(&mut args)?;
+        let pinned = !key.key_slots.is_empty();
+        let master_keys = if pinned { vec![(key.key_slots.clone(), key.master_key.clone())] } else { Vec::new() };
+        Ok(Self { key, password, args, master_keys: Mutex::new(master_keys), pinned })
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        if !self.args.force_encrypt && (is_armored(plaintext) || is_qr_text(plaintext) || is_ecc(plaintext) || is_doby_format(&plaintext[..plaintext.len().min(MAGIC_BYTES.len())])) {
+            return Err(Error::AlreadyEncrypted);
+        }
+        let digest = if self.args.digest { Some(PlaintextDigest::compute(&mut &*plaintext)?) } else { None };
+        let params = self.key.params(&self.args, digest.as_ref(), false)?;
+        let cipher = DobyCipher::with_master_key(&self.key.master_key, &params)?;
+        let mut ciphertext = Vec::new();
+        encrypt_slice(plaintext, &mut ciphertext, &params, cipher, self.args.block_size.unwrap_or_else(|| auto_block_size(None)), None)?;
+        Ok(ciphertext)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let mut reader = ciphertext;
+        let params = read_header(&mut reader)?;
+        if params.sparse() {
+            return Err(Error::Usage("sparse files can't be decrypted by serve"));
+        }
+        self.args.argon2_limits.check(&params)?;
+        let cipher = self.cipher(&params)?;
+        let mut reader = SignatureReader::new(reader, &params, self.args.verify_key.as_ref())?;
+        let mut plaintext = Zeroizing::new(Vec::new());
+        decrypt_metadata(&mut reader, &mut *plaintext, &params, cipher, self.args.block_size.unwrap_or_else(|| auto_block_size(None)), 1, false)?;
+        reader.finish()?;
+        Ok(plaintext)
+    }
+
+    /// Never prompts: key slots are opened with what was given at startup, and their master key is kept for the next files.
+    fn cipher(&self, params: &EncryptionParams) -> Result<DobyCipher, Error> {
+        match (&params.key_derivation, &self.password) {
+            (KeyDerivation::KeySlots(key_slots), password) => {
+                if let Some((_, master_key)) = self.master_keys.lock().unwrap().iter().find(|(opened, _)| opened == key_slots) {
+                    return DobyCipher::with_master_key(master_key, params);
+                }
+                let master_key = match password {
+                    Some(password) => master_key(params, Some(password.clone()).into(), &self.args.identities, self.args.key_slots.pkcs11.as_ref(), None, "Password")?,
+                    None => {
+                        let mut master_key = unwrap_with_identities(&self.args.identities, key_slots)?;
+                        if let (None, Some(pkcs11)) = (master_key, &self.args.key_slots.pkcs11) {
+                            master_key = pkcs11.unwrap(key_slots)?;
+                        }
+                        master_key.map(Locked::new).ok_or(Error::NoMatchingIdentity)?
+                    }
+                };
+                let cipher = DobyCipher::with_master_key(&master_key, params);
+                let mut master_keys = self.master_keys.lock().unwrap();
+                if master_keys.len() >= MAX_CACHED_KEYS {
+                    //oldest key, after the one of the outputs if pinned
+                    master_keys.remove(self.pinned as usize);
+                }
+                master_keys.push((key_slots.clone(), master_key));
+                cipher
+            }
+            (KeyDerivation::Password(_), _) if params.yubikey_slot().is_some() => Err(Error::Usage("files encrypted with a YubiKey can't be decrypted by serve")),
+            //the key derived from the password is specific to the salt of each file
+            (KeyDerivation::Password(_), Some(password)) => DobyCipher::try_new(password.as_bytes(), params),
+            (KeyDerivation::Password(_), None) => Err(Error::Usage("this file is encrypted with a password, but serve was started without one")),
+            (KeyDerivation::RawKey, _) => DobyCipher::with_master_key(&*master_key(params, WrappedPassword::default(), &[], None, self.args.raw_key.as_deref(), "Password")?, params),
+        }
+    }
+
+    /// Answers the request of a client. Failures are logged and sent to the client.
+    fn handle(&self, mut stream: UnixStream) {
+        let (operation, data) = match read_request(&mut stream) {
+            Ok(request) => request,
+            Err(e) => return warn!("invalid request: {}", e),
+        };
+        let data = Zeroizing::new(data);
+        let result = match operation {
+            Operation::Encrypt => self.encrypt(&data).map(Zeroizing::new),
+            Operation::Decrypt => self.decrypt(&data),
+        };
+        let operation = if operation == Operation::Encrypt { "encrypt" } else { "decrypt" };
+        let sent = match &result {
+            Ok(output) => {
+                debug!("{}: {} bytes in, {} bytes out", operation, data.len(), output.len());
+                write_response(&mut stream, Ok(output))
+            }
+            Err(e) => {
+                error!("{}: {}", operation, e);
+                write_response(&mut stream, Err(&e.to_string()))
+            }
+        };
+        if let Err(e) = sent {
+            warn!("{}: couldn't send the response: {}", operation, e);
+        }
+    }
+}
+
+/// Listens on `path`, replacing the socket left by a server that isn't running anymore. Only the owner can connect.
+#[cfg(feature = "cli")]
+fn bind(path: &str) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "not a socket"));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening"));
+        }
+        fs::remove_file(path)?;
+    }
+    //SAFETY: umask can't fail, and the mode is restored right after
+    let mask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(mask) };
+    listener
+}
+
+/// Serves the requests of `doby client` until interrupted, each one in its own thread, up to `MAX_CONNECTIONS` at once.
+#[cfg(feature = "cli")]
+pub fn serve(args: ServeArgs) -> Result<(), Error> {
+    let server = Server::new(args.batch)?;
+    let listener = bind(&args.socket).map_err(|error| Error::Path { path: args.socket.clone(), error })?;
+    info!("Listening on {}", args.socket);
+    let (connections, closed) = (Mutex::new(0), Condvar::new());
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    *closed.wait_while(connections.lock().unwrap(), |n| *n >= MAX_CONNECTIONS).unwrap() += 1;
+                    let (server, connections, closed) = (&server, &connections, &closed);
+                    scope.spawn(move || {
+                        server.handle(stream);
+                        *connections.lock().unwrap() -= 1;
+                        closed.notify_one();
+                    });
+                }
+                Err(e) => warn!("{}: {}", args.socket, e),
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use std::{fmt::{self, Display, Formatter}, fs, io::{self, Read, Write}, path::Path};
+use ed25519_dalek::{Signature, Signer, SigningKey as Ed25519SigningKey, VerifyingKey, SECRET_KEY_LENGTH, SIGNATURE_LENGTH};
+use rand::rngs::OsRng;
+use zeroize::Zeroize;
+use crate::{Error, MAGIC_BYTES, crypto::{EncryptionParams, SIGNATURE_EXTENSION, SIGNER_KEY_LEN}};
+pub use crate::crypto::SIGNATURE_LEN;
+
+pub const VERIFY_KEY_PREFIX: &str = "doby-sign-pk-";
+pub const SIGNING_KEY_PREFIX: &str = "doby-sign-sk-";
+const _: () = assert!(SIGNATURE_LEN == SIGNATURE_LENGTH);
+//the BLAKE3 hash of the ciphertext is signed rather than the ciphertext itself, so that it can be streamed
+const SIGNATURE_CONTEXT: &[u8] = b"doby_ed25519_signature";
+
+fn decode_key(s: &str, prefix: &str) -> Option<[u8; SIGNER_KEY_LEN]> {
+    s.strip_prefix(prefix)
+        .and_then(|encoded| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok())
+        .and_then(|decoded| decoded.try_into().ok())
+}
+
+fn signed_message(hasher: &blake3::Hasher) -> Vec<u8> {
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(hasher.finalize().as_bytes());
+    message
+}
+
+/// Ed25519 public key checking that ciphertexts were produced by the corresponding `SigningKey`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifyKey(VerifyingKey);
+
+impl VerifyKey {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        decode_key(s.trim(), VERIFY_KEY_PREFIX)
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+            .map(Self)
+            .ok_or_else(|| Error::InvalidVerifyKey(s.to_string()))
+    }
+
+    pub fn from_bytes(bytes: &[u8; SIGNER_KEY_LEN]) -> Result<Self, Error> {
+        VerifyingKey::from_bytes(bytes).map(Self).map_err(|_| Error::InvalidHeader)
+    }
+
+    pub fn to_bytes(&self) -> [u8; SIGNER_KEY_LEN] {
+        self.0.to_bytes()
+    }
+}
+
+impl Display for VerifyKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", VERIFY_KEY_PREFIX, base64::encode_config(self.0.as_bytes(), base64::URL_SAFE_NO_PAD))
+    }
+}
+
+/// Ed25519 secret key used to sign ciphertexts with `--sign-key`.
+pub struct SigningKey(Ed25519SigningKey);
+
+impl SigningKey {
+    pub fn generate() -> Self {
+        Self(Ed25519SigningKey::generate(&mut OsRng))
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        //the invalid value isn't included in the error as it may be a secret
+        let mut key: [u8; SECRET_KEY_LENGTH] = decode_key(s.trim(), SIGNING_KEY_PREFIX).ok_or(Error::InvalidSigningKey)?;
+        let signing_key = Self(Ed25519SigningKey::from_bytes(&key));
+        key.zeroize();
+        Ok(signing_key)
+    }
+
+    /// Reads the first key of a signing key file, ignoring empty lines and comments starting with '#'.
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut content = fs::read_to_string(path.as_ref())
+            .map_err(|error| Error::Path { path: path.as_ref().display().to_string(), error })?;
+        let key = Self::parse_file_content(&content);
+        content.zeroize();
+        key
+    }
+
+    /// Same as `read_file`, for signing key file content that is already in memory.
+    pub fn parse_file_content(content: &str) -> Result<Self, Error> {
+        content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .ok_or(Error::InvalidSigningKey)
+            .and_then(Self::parse)
+    }
+
+    pub fn verify_key(&self) -> VerifyKey {
+        VerifyKey(self.0.verifying_key())
+    }
+
+    /// Signing key file content: the public key as a comment followed by the secret key.
+    pub fn to_file_content(&self) -> String {
+        format!(
+            "# verify key: {}\n{}{}\n",
+            self.verify_key(),
+            SIGNING_KEY_PREFIX,
+            base64::encode_config(self.0.as_bytes(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+}
+
+/// Hashes a whole doby output (header included) and appends its signature with `finish`.
+pub struct SigningWriter<W: Write> {
+    writer: W,
+    hasher: blake3::Hasher,
+}
+
+impl<W: Write> SigningWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, hasher: blake3::Hasher::new() }
+    }
+
+    pub fn finish(mut self, signing_key: &SigningKey) -> io::Result<W> {
+        let signature = signing_key.0.sign(&signed_message(&self.hasher));
+        self.writer.write_all(&signature.to_bytes())?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for SigningWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Signer of the ciphertext following the header `params`, which must be `expected` if given.
+pub fn check_signer(params: &EncryptionParams, expected: Option<&VerifyKey>) -> Result<Option<VerifyKey>, Error> {
+    let signer = match params.extension(SIGNATURE_EXTENSION) {
+        Some(_) => Some(VerifyKey::from_bytes(&params.signer().ok_or(Error::InvalidHeader)?)?),
+        None => None,
+    };
+    match (&signer, expected) {
+        (None, Some(_)) => Err(Error::MissingSignature),
+        (Some(signer), Some(expected)) if signer != expected => Err(Error::SignerMismatch(signer.to_string())),
+        _ => Ok(signer),
+    }
+}
+
+/// Reads the ciphertext following a header, removing the signature at its end if the header has a signer.
+///
+/// Without signer, the ciphertext is passed through untouched.
+pub struct SignatureReader<R: Read> {
+    reader: R,
+    //hasher and signer if the ciphertext is signed
+    signed: Option<(blake3::Hasher, VerifyKey)>,
+    //read but not yet returned, holding back the signature at the end
+    pending: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> SignatureReader<R> {
+    /// `reader` must be positioned right after the header `params` were read from. Fails like `check_signer` if `expected` is given.
+    pub fn new(reader: R, params: &EncryptionParams, expected: Option<&VerifyKey>) -> Result<Self, Error> {
+        let signed = match check_signer(params, expected)? {
+            Some(signer) => {
+                //the header is encoded the same way when written back
+                let mut header = MAGIC_BYTES.to_vec();
+                params.write(&mut header)?;
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(&header);
+                Some((hasher, signer))
+            }
+            None => None,
+        };
+        Ok(Self { reader, signed, pending: Vec::new(), eof: false })
+    }
+
+    /// Reads the rest of the ciphertext and checks its signature, if any. Like with the HMAC, everything read before must be discarded if it fails.
+    pub fn finish(mut self) -> Result<(), Error> {
+        io::copy(&mut self, &mut io::sink())?;
+        if let Some((hasher, signer)) = self.signed {
+            let signature = Signature::from_slice(&self.pending).map_err(|_| Error::InvalidSignature)?;
+            signer.0.verify_strict(&signed_message(&hasher), &signature).map_err(|_| Error::InvalidSignature)?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for SignatureReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let hasher = match &mut self.signed {
+            Some((hasher, _)) => hasher,
+            None => return self.reader.read(buf),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.pending.len() <= SIGNATURE_LEN && !self.eof {
+            let start = self.pending.len();
+            self.pending.resize(start + buf.len() + SIGNATURE_LEN, 0);
+            let result = self.reader.read(&mut self.pending[start..]);
+            let n = *result.as_ref().unwrap_or(&0);
+            self.pending.truncate(start + n);
+            if result? == 0 {
+                self.eof = true;
+            }
+        }
+        let n = self.pending.len().saturating_sub(SIGNATURE_LEN).min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        hasher.update(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
@@ -0,0 +1,174 @@
+use std::{fs::File, io::{self, Read, Seek, SeekFrom, Write}};
+use crate::OutputWriter;
+
+//hole length then data length
+const RECORD_LEN: usize = 16;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Offset of the first byte of data (`data` set) or of the first hole from `offset`, `None` if there is no data after `offset`. The end of the file counts as a hole.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn seek(file: &File, offset: u64, data: bool) -> io::Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+    //SAFETY: the file descriptor is valid as long as file is borrowed
+    let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, if data { libc::SEEK_DATA } else { libc::SEEK_HOLE }) };
+    if result >= 0 {
+        Ok(Some(result as u64))
+    } else {
+        match io::Error::last_os_error() {
+            e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+            e => Err(e),
+        }
+    }
+}
+
+//the whole file is data
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn seek(file: &File, offset: u64, data: bool) -> io::Result<Option<u64>> {
+    let len = file.metadata()?.len();
+    Ok(if offset >= len { None } else if data { Some(offset) } else { Some(len) })
+}
+
+/// Whether the file has holes, i.e. ranges of zeros not stored on disk. Filesystems that don't report them never have any.
+pub fn has_holes(mut file: &File) -> io::Result<bool> {
+    let len = file.metadata()?.len();
+    //looking for holes moves the file offset
+    let position = file.stream_position()?;
+    let hole = seek(file, 0, false)?;
+    file.seek(SeekFrom::Start(position))?;
+    Ok(hole.is_some_and(|hole| hole < len))
+}
+
+/// Reads a file as a sequence of (hole length, data length, data) records, both lengths being 8 bytes big-endian, so that the holes are neither read nor stored. `SparseWriter` decodes it.
+pub struct SparseReader {
+    file: File,
+    len: u64,
+    //end of the last extent
+    offset: u64,
+    record: [u8; RECORD_LEN],
+    record_pos: usize,
+    data_left: u64,
+}
+
+impl SparseReader {
+    /// Reads `file` from its beginning, whatever its current position.
+    pub fn new(file: File) -> io::Result<Self> {
+        Ok(Self {
+            len: file.metadata()?.len(),
+            file,
+            offset: 0,
+            record: [0; RECORD_LEN],
+            record_pos: RECORD_LEN,
+            data_left: 0,
+        })
+    }
+
+    //(start, end) of the next data after offset, empty at the end of the file
+    fn next_extent(&self, offset: u64) -> io::Result<(u64, u64)> {
+        let start = seek(&self.file, offset, true)?.unwrap_or(self.len).min(self.len);
+        let end = if start < self.len {
+            seek(&self.file, start, false)?.unwrap_or(self.len).min(self.len)
+        } else {
+            start
+        };
+        Ok((start, end))
+    }
+
+    /// Size of the encoded stream, e.g. to show the progress.
+    pub fn encoded_len(&self) -> io::Result<u64> {
+        let mut encoded_len = 0;
+        let mut offset = 0;
+        while offset < self.len {
+            let (start, end) = self.next_extent(offset)?;
+            encoded_len += RECORD_LEN as u64 + end - start;
+            offset = end;
+        }
+        Ok(encoded_len)
+    }
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.record_pos < RECORD_LEN {
+                let n = (RECORD_LEN - self.record_pos).min(buf.len());
+                buf[..n].copy_from_slice(&self.record[self.record_pos..self.record_pos+n]);
+                self.record_pos += n;
+                return Ok(n);
+            }
+            if self.data_left > 0 {
+                let len = buf.len().min(usize::try_from(self.data_left).unwrap_or(usize::MAX));
+                let n = self.file.read(&mut buf[..len])?;
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "INPUT was truncated while being read"));
+                }
+                self.data_left -= n as u64;
+                return Ok(n);
+            }
+            if self.offset >= self.len || buf.is_empty() {
+                return Ok(0);
+            }
+            let (start, end) = self.next_extent(self.offset)?;
+            self.record[..8].copy_from_slice(&(start - self.offset).to_be_bytes());
+            self.record[8..].copy_from_slice(&(end - start).to_be_bytes());
+            self.record_pos = 0;
+            self.data_left = end - start;
+            self.file.seek(SeekFrom::Start(start))?;
+            self.offset = end;
+        }
+    }
+}
+
+/// Decodes the stream of `SparseReader`, leaving holes in the output where the input had them.
+pub struct SparseWriter<'a> {
+    writer: &'a mut OutputWriter,
+    record: [u8; RECORD_LEN],
+    record_len: usize,
+    data_left: u64,
+}
+
+impl<'a> SparseWriter<'a> {
+    pub fn new(writer: &'a mut OutputWriter) -> Self {
+        Self {
+            writer,
+            record: [0; RECORD_LEN],
+            record_len: 0,
+            data_left: 0,
+        }
+    }
+
+    /// Fails if the stream stopped in the middle of a record.
+    pub fn finish(self) -> io::Result<()> {
+        if self.record_len == 0 && self.data_left == 0 {
+            Ok(())
+        } else {
+            Err(invalid_data("truncated sparse stream"))
+        }
+    }
+}
+
+impl Write for SparseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.data_left > 0 {
+            let len = buf.len().min(usize::try_from(self.data_left).unwrap_or(usize::MAX));
+            let n = self.writer.write(&buf[..len])?;
+            self.data_left -= n as u64;
+            return Ok(n);
+        }
+        let n = (RECORD_LEN - self.record_len).min(buf.len());
+        self.record[self.record_len..self.record_len+n].copy_from_slice(&buf[..n]);
+        self.record_len += n;
+        if self.record_len == RECORD_LEN {
+            self.writer.write_hole(u64::from_be_bytes(self.record[..8].try_into().unwrap()))?;
+            self.data_left = u64::from_be_bytes(self.record[8..].try_into().unwrap());
+            self.record_len = 0;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
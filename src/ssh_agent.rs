@@ -0,0 +1,213 @@
+use std::{env, ffi::OsString, fs, io::{Read, Write}, path::Path};
+use blake2::Blake2b;
+use hkdf::Hkdf;
+use rand::{Rng, rngs::OsRng};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+use crate::{Error, crypto::{KEY_LEN, KeySlot, SSH_CHALLENGE_LEN, open_key, seal_key}};
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+//ask for rsa-sha2-256 signatures instead of the deprecated SHA-1 ones
+const SSH_AGENT_RSA_SHA2_256: u32 = 2;
+const MAX_MESSAGE_LEN: usize = 256*1024;
+//signed along with the challenge, so that signatures made for other protocols can't be reused
+const CHALLENGE_CONTEXT: &[u8] = b"doby_ssh_agent_challenge";
+
+#[cfg(unix)]
+type AgentStream = std::os::unix::net::UnixStream;
+//never constructed: connecting fails first
+#[cfg(not(unix))]
+type AgentStream = std::io::Empty;
+
+//SSH wire encoding: u32 length followed by the bytes
+fn read_string<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u32::from_be_bytes(buf.get(..4)?.try_into().unwrap()) as usize;
+    let string = buf.get(4..4+len)?;
+    *buf = &buf[4+len..];
+    Some(string)
+}
+
+fn write_string(buf: &mut Vec<u8>, string: &[u8]) {
+    buf.extend_from_slice(&(string.len() as u32).to_be_bytes());
+    buf.extend_from_slice(string);
+}
+
+fn invalid_response() -> Error {
+    Error::SshAgent(String::from("invalid response"))
+}
+
+fn agent_socket() -> Option<OsString> {
+    env::var_os("SSH_AUTH_SOCK").filter(|path| !path.is_empty())
+}
+
+/// Whether an agent may be able to open SSH key slots, so that the password doesn't need to be asked upfront.
+pub fn agent_available() -> bool {
+    cfg!(unix) && agent_socket().is_some()
+}
+
+/// SSH public key whose private key is held by ssh-agent, stored in its SSH wire encoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SshKey(Vec<u8>);
+
+impl SshKey {
+    /// Parses a public key in the format of OpenSSH `.pub` files: type, base64 encoded key and optional comment.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut fields = s.split_whitespace();
+        fields.next()
+            .zip(fields.next())
+            .and_then(|(kind, encoded)| {
+                let public_key = base64::decode(encoded).ok()?;
+                //the encoded key starts with its type
+                (read_string(&mut public_key.as_slice())? == kind.as_bytes()).then_some(Self(public_key))
+            })
+            .ok_or_else(|| Error::InvalidSshKey(s.trim().to_string()))
+    }
+
+    /// Reads the first key of a public key file, ignoring empty lines and comments starting with '#'.
+    pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let content = fs::read_to_string(path.as_ref())
+            .map_err(|error| Error::Path { path: path.as_ref().display().to_string(), error })?;
+        //the path is reported rather than the content, in case a private key was given by mistake
+        content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .and_then(|line| Self::parse(line).ok())
+            .ok_or_else(|| Error::InvalidSshKey(path.as_ref().display().to_string()))
+    }
+
+    /// OpenSSH fingerprint of an encoded public key, as printed by `ssh-add -l`.
+    pub fn fingerprint(public_key: &[u8]) -> String {
+        format!("SHA256:{}", base64::encode_config(Sha256::digest(public_key), base64::STANDARD_NO_PAD))
+    }
+
+    /// Encrypts `master_key` in a new key slot. The key must be loaded in the agent, which signs a random challenge.
+    pub fn wrap(&self, master_key: &[u8; KEY_LEN]) -> Result<KeySlot, Error> {
+        let mut agent = Agent::connect()?;
+        if !agent.identities()?.contains(&self.0) {
+            return Err(Error::SshAgent(format!("{} isn't loaded in the agent", Self::fingerprint(&self.0))));
+        }
+        let mut challenge = [0; SSH_CHALLENGE_LEN];
+        OsRng.fill(&mut challenge);
+        let mut wrapping_key = agent.wrapping_key(&self.0, &challenge)?;
+        //ECDSA and FIDO keys don't sign the same way twice: the key couldn't be derived again
+        let mut second_key = agent.wrapping_key(&self.0, &challenge)?;
+        let deterministic = wrapping_key == second_key;
+        second_key.zeroize();
+        if !deterministic {
+            wrapping_key.zeroize();
+            return Err(Error::SshAgent(String::from("the signatures of this key aren't deterministic (use an Ed25519 or RSA key)")));
+        }
+        let wrapped_key = seal_key(&wrapping_key, master_key);
+        wrapping_key.zeroize();
+        Ok(KeySlot::SshAgent { public_key: self.0.clone(), challenge, wrapped_key })
+    }
+}
+
+/// Returns the master key of the first SSH key slot that a key of the agent can open, without connecting to it if there is no such slot.
+pub fn unwrap_with_agent(key_slots: &[KeySlot]) -> Result<Option<[u8; KEY_LEN]>, Error> {
+    let ssh_slots: Vec<_> = key_slots.iter().filter_map(|key_slot| match key_slot {
+        KeySlot::SshAgent { public_key, challenge, wrapped_key } => Some((public_key, challenge, wrapped_key)),
+        _ => None,
+    }).collect();
+    if ssh_slots.is_empty() {
+        return Ok(None);
+    }
+    let mut agent = Agent::connect()?;
+    let identities = agent.identities()?;
+    for (public_key, challenge, wrapped_key) in ssh_slots {
+        if identities.contains(public_key) {
+            let mut wrapping_key = agent.wrapping_key(public_key, challenge)?;
+            let master_key = open_key(&wrapping_key, wrapped_key);
+            wrapping_key.zeroize();
+            if master_key.is_some() {
+                return Ok(master_key);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Connection to the agent listening on `SSH_AUTH_SOCK`.
+struct Agent(AgentStream);
+
+impl Agent {
+    #[cfg(unix)]
+    fn connect() -> Result<Self, Error> {
+        let path = agent_socket().ok_or_else(|| Error::SshAgent(String::from("SSH_AUTH_SOCK is not set")))?;
+        AgentStream::connect(&path)
+            .map(Self)
+            .map_err(|e| Error::SshAgent(format!("can't connect to {}: {}", Path::new(&path).display(), e)))
+    }
+
+    #[cfg(not(unix))]
+    fn connect() -> Result<Self, Error> {
+        Err(Error::SshAgent(String::from("only supported on Unix")))
+    }
+
+    fn request(&mut self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut framed = Vec::with_capacity(4 + message.len());
+        write_string(&mut framed, message);
+        let io_error = |e: std::io::Error| Error::SshAgent(e.to_string());
+        self.0.write_all(&framed).map_err(io_error)?;
+        let mut len = [0; 4];
+        self.0.read_exact(&mut len).map_err(io_error)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 || len > MAX_MESSAGE_LEN {
+            return Err(invalid_response());
+        }
+        let mut response = vec![0; len];
+        self.0.read_exact(&mut response).map_err(io_error)?;
+        Ok(response)
+    }
+
+    /// Encoded public keys of the agent.
+    fn identities(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let response = self.request(&[SSH_AGENTC_REQUEST_IDENTITIES])?;
+        if response[0] != SSH_AGENT_IDENTITIES_ANSWER {
+            return Err(invalid_response());
+        }
+        let mut buf = &response[1..];
+        let count = u32::from_be_bytes(buf.get(..4).ok_or_else(invalid_response)?.try_into().unwrap());
+        buf = &buf[4..];
+        (0..count).map(|_| {
+            let public_key = read_string(&mut buf).ok_or_else(invalid_response)?;
+            read_string(&mut buf).ok_or_else(invalid_response)?; //comment
+            Ok(public_key.to_vec())
+        }).collect()
+    }
+
+    /// Key wrapping key derived from the signature of the challenge.
+    fn wrapping_key(&mut self, public_key: &[u8], challenge: &[u8; SSH_CHALLENGE_LEN]) -> Result<[u8; KEY_LEN], Error> {
+        let mut data = CHALLENGE_CONTEXT.to_vec();
+        data.extend_from_slice(challenge);
+        let flags = if read_string(&mut &public_key[..]) == Some(b"ssh-rsa") { SSH_AGENT_RSA_SHA2_256 } else { 0 };
+        let mut message = vec![SSH_AGENTC_SIGN_REQUEST];
+        write_string(&mut message, public_key);
+        write_string(&mut message, &data);
+        message.extend_from_slice(&flags.to_be_bytes());
+        let mut response = self.request(&message)?;
+        let result = match response[0] {
+            SSH_AGENT_SIGN_RESPONSE => {
+                //signature format followed by the signature itself
+                let mut buf = &response[1..];
+                read_string(&mut buf)
+                    .and_then(|mut signature| read_string(&mut signature).and(read_string(&mut signature)))
+                    .map(|signature| {
+                        let mut key = [0; KEY_LEN];
+                        Hkdf::<Blake2b>::new(Some(challenge), signature).expand(b"doby_ssh_agent_wrapping_key", &mut key).unwrap();
+                        key
+                    })
+                    .ok_or_else(invalid_response)
+            }
+            SSH_AGENT_FAILURE => Err(Error::SshAgent(String::from("the agent refused to sign the challenge"))),
+            _ => Err(invalid_response()),
+        };
+        response.zeroize();
+        result
+    }
+}
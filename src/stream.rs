@@ -0,0 +1,181 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use crate::{
+    Error,
+    MAGIC_BYTES,
+    crypto::{AEAD_CHUNK_SIZE, AEAD_TAG_LEN, DobyCipher, EncryptionParams},
+    read_header,
+};
+
+const BUFFER_SIZE: usize = 65536;
+const ENCRYPTED_CHUNK_LEN: u64 = (AEAD_CHUNK_SIZE+AEAD_TAG_LEN) as u64;
+
+/// Encrypts everything written to it. The header is written on the first write (or on `finish`) and the HMAC on `finish`.
+pub struct EncryptWriter<W: Write> {
+    writer: W,
+    params: EncryptionParams,
+    cipher: DobyCipher,
+    header_written: bool,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(writer: W, params: EncryptionParams, cipher: DobyCipher) -> Self {
+        Self {
+            writer,
+            params,
+            cipher,
+            header_written: false,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.writer.write_all(MAGIC_BYTES)?;
+            self.params.write(&mut self.writer)?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    /// Writes the authentication data and returns the inner writer. Dropping an `EncryptWriter` without calling `finish` produces an incomplete ciphertext.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.write_header()?;
+        self.cipher.write_hmac(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_header()?;
+        let n = buf.len().min(BUFFER_SIZE);
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&buf[..n]);
+        self.cipher.encrypt_chunk(&mut self.buffer, &mut self.writer)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decrypts a doby ciphertext. The header is consumed on creation and the HMAC is verified when reaching EOF: if it doesn't match or if the ciphertext is truncated, the last `read` fails with `io::ErrorKind::InvalidData`.
+///
+/// With a seekable reader and a chunked cipher (AES-GCM or XChaCha20-Poly1305), it also implements `Seek`: the fixed size chunks act as an index of the plaintext, so only the chunk containing the new position is read and authenticated, along with the following ones as reading goes on. Seeking fails with `io::ErrorKind::Unsupported` for stream ciphers, whose HMAC covers the whole ciphertext.
+pub struct DecryptReader<R: Read> {
+    reader: R,
+    cipher: DobyCipher,
+    buffer: Vec<u8>,
+    pos: usize,
+    len: usize,
+    eof: bool,
+    //ciphertext bytes read since the header, to find where it starts when seeking for the first time
+    consumed: u64,
+    data_start: Option<u64>,
+    //plaintext bytes before the current position
+    position: u64,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut reader: R, password: &[u8]) -> Result<Self, Error> {
+        let params = read_header(&mut reader)?;
+        let cipher = DobyCipher::new(password, &params)?;
+        Ok(Self::with_cipher(reader, cipher))
+    }
+
+    /// `reader` must be positioned right after the header the cipher was created from.
+    pub fn with_cipher(reader: R, cipher: DobyCipher) -> Self {
+        Self {
+            reader,
+            cipher,
+            buffer: vec![0; BUFFER_SIZE],
+            pos: 0,
+            len: 0,
+            eof: false,
+            consumed: 0,
+            data_start: None,
+            position: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+struct CountingReader<'a, R: Read> {
+    reader: &'a mut R,
+    count: &'a mut u64,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        *self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.len {
+            if self.eof {
+                return Ok(0);
+            }
+            self.pos = 0;
+            let mut reader = CountingReader { reader: &mut self.reader, count: &mut self.consumed };
+            self.len = self.cipher.decrypt_chunk(&mut reader, &mut self.buffer)?;
+            if self.len == 0 {
+                self.eof = true;
+                //kept for seeking
+                if let Err(e) = self.cipher.clone().verify() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.len - self.pos);
+        buf[..n].copy_from_slice(&self.buffer[self.pos..self.pos+n]);
+        self.pos += n;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for DecryptReader<R> {
+    //like files, seeking beyond the end is allowed: reads then return 0 bytes
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if !self.cipher.is_chunked() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "random access requires a chunked cipher (aes-gcm or xchacha20-poly1305)"));
+        }
+        let data_start = match self.data_start {
+            Some(data_start) => data_start,
+            None => self.reader.stream_position()? - self.consumed,
+        };
+        self.data_start = Some(data_start);
+        let ciphertext_len = self.reader.seek(SeekFrom::End(0))?.saturating_sub(data_start);
+        //the last chunk can be empty, but not missing
+        let chunks = ciphertext_len.div_ceil(ENCRYPTED_CHUNK_LEN).max(1);
+        let plaintext_len = ciphertext_len.saturating_sub(chunks*AEAD_TAG_LEN as u64);
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => plaintext_len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        let chunk = (target/AEAD_CHUNK_SIZE as u64).min(chunks-1);
+        self.reader.seek(SeekFrom::Start(data_start + chunk*ENCRYPTED_CHUNK_LEN))?;
+        self.cipher.seek_chunk(chunk.try_into().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many chunks"))?)?;
+        self.pos = 0;
+        self.len = 0;
+        self.eof = false;
+        self.position = chunk*AEAD_CHUNK_SIZE as u64;
+        //skip the beginning of the chunk, authenticating it in the process
+        let skip = target - self.position;
+        io::copy(&mut self.by_ref().take(skip), &mut io::sink())?;
+        self.position = target;
+        Ok(target)
+    }
+}
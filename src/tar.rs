@@ -0,0 +1,112 @@
+use std::io::{self, Read, Write};
+use crate::Error;
+
+pub const TAR_BLOCK_SIZE: usize = 512;
+const CHECKSUM: std::ops::Range<usize> = 148..156;
+
+/// Whether `block` is a valid tar header (checked with its checksum), or an end of archive block.
+pub fn is_tar_header(block: &[u8]) -> bool {
+    if block.len() != TAR_BLOCK_SIZE {
+        return false;
+    }
+    if block.iter().all(|b| *b == 0) {
+        return true;
+    }
+    let field = match std::str::from_utf8(&block[CHECKSUM]) {
+        Ok(field) => field.trim_matches(|c| c == ' ' || c == '\0'),
+        Err(_) => return false,
+    };
+    let checksum = match u32::from_str_radix(field, 8) {
+        Ok(checksum) => checksum,
+        Err(_) => return false,
+    };
+    //the checksum field itself counts as spaces. Some old implementations used signed bytes.
+    let unsigned: u32 = block.iter().enumerate().map(|(i, b)| if CHECKSUM.contains(&i) { b' ' as u32 } else { *b as u32 }).sum();
+    let signed: i32 = block.iter().enumerate().map(|(i, b)| if CHECKSUM.contains(&i) { b' ' as i32 } else { *b as i8 as i32 }).sum();
+    checksum == unsigned || checksum as i32 == signed
+}
+
+/// Collects the first block of a stream.
+#[derive(Default)]
+struct FirstBlock(Vec<u8>);
+
+impl FirstBlock {
+    fn update(&mut self, data: &[u8]) {
+        let n = (TAR_BLOCK_SIZE - self.0.len()).min(data.len());
+        self.0.extend_from_slice(&data[..n]);
+    }
+
+    fn is_complete(&self) -> bool {
+        self.0.len() == TAR_BLOCK_SIZE
+    }
+}
+
+/// Fails reading if the stream doesn't start with a tar header, e.g. because `tar c` was forgotten before encrypting stdin.
+pub struct TarCheckReader<R: Read> {
+    reader: R,
+    first_block: FirstBlock,
+    checked: bool,
+}
+
+impl<R: Read> TarCheckReader<R> {
+    /// `already_read` is the beginning of the stream, consumed from `reader` before.
+    pub fn starting_with(reader: R, already_read: &[u8]) -> Self {
+        let mut first_block = FirstBlock::default();
+        first_block.update(already_read);
+        Self { reader, first_block, checked: false }
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        self.checked = true;
+        if is_tar_header(&self.first_block.0) {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, Error::NotTarArchive { decrypted: false }))
+        }
+    }
+}
+
+impl<R: Read> Read for TarCheckReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        if !self.checked {
+            self.first_block.update(&buf[..n]);
+            if self.first_block.is_complete() || n == 0 {
+                self.check()?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Passes everything through to the inner writer, and tells with `finish` whether it was a tar stream. This way, authentication errors are reported first when decrypting.
+pub struct TarCheckWriter<W: Write> {
+    writer: W,
+    first_block: FirstBlock,
+}
+
+impl<W: Write> TarCheckWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, first_block: FirstBlock::default() }
+    }
+
+    pub fn finish(self) -> Result<W, Error> {
+        if is_tar_header(&self.first_block.0) {
+            Ok(self.writer)
+        } else {
+            Err(Error::NotTarArchive { decrypted: true })
+        }
+    }
+}
+
+impl<W: Write> Write for TarCheckWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.first_block.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
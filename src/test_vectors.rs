@@ -0,0 +1,132 @@
+//! Known-answer tests checked by `doby selftest`, and exported with `doby selftest --export` for other implementations of the format.
+//!
+//! Each vector encrypts the same plaintext with a fixed key (or password) and salt. `TestVector::check` encrypts the plaintext again with the parameters read from the expected ciphertext, which must give exactly the same bytes, and decrypts the expected ciphertext back.
+
+use crate::{
+    Error,
+    crypto::{AEAD_TAG_LEN, DobyCipher, HMAC_LEN, KEY_LEN},
+    decrypt,
+    encrypt,
+    read_header,
+};
+
+const PLAINTEXT_LEN: usize = 100;
+const BLOCK_SIZE: usize = 4096;
+
+/// Master key of the raw key vectors: 0x00, 0x01... 0x1f. Their salt is 0x00, 0x01... 0x3f.
+pub const TEST_KEY: [u8; KEY_LEN] = {
+    let mut key = [0; KEY_LEN];
+    let mut i = 0;
+    while i < KEY_LEN {
+        key[i] = i as u8;
+        i += 1;
+    }
+    key
+};
+/// Password of the Argon2 vector (m=8KB, t=1, p=1), with the same salt.
+pub const TEST_PASSWORD: &str = "doby test password";
+
+#[derive(Clone, Copy, Debug)]
+pub enum TestKey {
+    /// `TEST_KEY`, used as is (`--key-hex`).
+    Raw,
+    /// `TEST_PASSWORD`, derived with the Argon2 parameters of the header.
+    Password,
+}
+
+#[derive(Clone, Debug)]
+pub struct TestVector {
+    pub name: &'static str,
+    pub key: TestKey,
+    /// 0x00, 0x01... 0x63.
+    pub plaintext: Vec<u8>,
+    /// Whole file: magic bytes, header, ciphertext and HMAC or tags.
+    pub ciphertext: Vec<u8>,
+}
+
+const VECTORS: [(&str, TestKey, &str); 7] = [
+    ("aes-ctr-blake2b", TestKey::Raw, "646f627903000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f002b19bf740aff12cb2fd77596b864d3df121d70ef03e8b84317509bb7b4db962e5b3eef75be6e7bb5ccad34c9e5f015b3e77d3ddf2231538ffbcd8feb0cce8e5195d9cff0d0e9cf3c1d3cf7f62cbbfd73daa25b663431cf12d0f58078e9b81b11edb99db045baecd87df74d5c71d7e9fa88fd1629aa206edc8866c74d685cc0b670439008"),
+    ("aes-ctr-blake3", TestKey::Raw, "646f627903000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f10809f83b3d02a44d57a596d00f9ba2d166761ff1c13142bbff962385d959b618ca479e09a2bba96a3c40fa69b4fc05f3725a50d6cab16e5d4fb236dd22758ea332c1a49c8f6de30dabf3823fa5c3c3630b6785554bde2a52d79fdd7cf823c88b3e823272397bffea9ab1f09d2fedb895d2e1c8532de7e9a55fc27ec734f86a8570ad1b122"),
+    ("xchacha20-blake2b", TestKey::Raw, "646f627903000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f018efd7ada433be2e78a61e7fe94f6bc7fe37e4a8351ac96ea7569a88b7459398a3df78aea00302b6d989540c331f14f4996c087913b4bceaa7d394b9832315343d7c61cc1e065c04ca17e3034215f785464c24f2d33a7fbf8ab460f30964ef9dc8c1118c95109dd53b33806494dbbfbfdfe19676a89f11cea8898cc59422bb2ade7f057d0"),
+    ("xchacha20-blake3", TestKey::Raw, "646f627903000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f119b63e4230d6116c524bc78237e2fb8bf77cfb67d9ed63ab2f2027fbe6f6957943b4399922ced627000c89c6048b64937600afe79a46e26c8536a20d188e3cd093e833460383632f8fbed25303e3523a4f6675c2bedfa521dc7bb590c2c50154b32350cba3f596de9bdae9d2e0469b5e5b75a21a4e5ad7f0954612823e9c23ed031b739cf"),
+    ("aes-gcm", TestKey::Raw, "646f627903000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f032a243059167c669ae6c4ad42cb845e3f30666bd440f23edd86b13a6afa7af2077a4fefc903d62bc615a912efc2cd2dd5ed1de84a5bf97f14bf6291144ccd66536b8f34e19dd438ba1037e49fd289f1ad5afd542b05fb3304e980b724503b831616c15a361cdfa825ff5bd921a848eeefb07a9f8e"),
+    ("xchacha20-poly1305", TestKey::Raw, "646f627903000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f026122efa5cd688664904674679f5461999b9eb0dc26cfab45531510b09ad96c5c5793ce2b5430f0b4d04d7be3c86c367aa01c8a2b8363d2507a578c514e69a1d9be660962d15811d17658c674497cf1618210ad06d197c02ed68fea183b828058dcf67def8816b951c4bb0b075f0ce2c89c6ff205"),
+    ("argon2id-xchacha20-poly1305", TestKey::Password, "646f627904000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f00000001000000080000000102f6ac2ac53f21398a201a4d3602e9fc2b7a2db8c65bba62427d6962cbd5dc8a026b4cddc282d2f1b0a65e4b5218cb53f8e51201387886afd3f0a8a8722b10933e75ab033bbac32b6fa3616e2a0e6bfe1612c6d20d5b36be5369094f248802fefe3fd1dc4ee56a28fb128cdc884e78728e3b20a2a3497d1855978703cd"),
+];
+
+fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i+2], 16).unwrap()).collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Vectors of the MAC algorithms enabled in this build: the BLAKE3 ones are left out without the `blake3` feature.
+pub fn test_vectors() -> Vec<TestVector> {
+    VECTORS.iter().filter(|(name, _, _)| cfg!(feature = "blake3") || !name.ends_with("-blake3")).map(|(name, key, ciphertext)| TestVector {
+        name,
+        key: *key,
+        plaintext: (0..PLAINTEXT_LEN).map(|i| i as u8).collect(),
+        ciphertext: decode_hex(ciphertext),
+    }).collect()
+}
+
+impl TestVector {
+    fn cipher(&self) -> Result<(crate::crypto::EncryptionParams, DobyCipher, usize), Error> {
+        let mut reader = self.ciphertext.as_slice();
+        let params = read_header(&mut reader)?;
+        let cipher = match self.key {
+            TestKey::Raw => DobyCipher::with_master_key(&TEST_KEY, &params)?,
+            TestKey::Password => DobyCipher::try_new(TEST_PASSWORD.as_bytes(), &params)?,
+        };
+        Ok((params, cipher, self.ciphertext.len() - reader.len()))
+    }
+
+    /// Fails with `Error::SelfTestFailed` if encrypting the plaintext doesn't give the expected ciphertext, or if decrypting it doesn't give the plaintext back.
+    pub fn check(&self) -> Result<(), Error> {
+        let failed = || Error::SelfTestFailed(self.name.to_string());
+        let (params, cipher, header_len) = self.cipher().map_err(|_| failed())?;
+        let mut ciphertext = Vec::new();
+        encrypt(&mut self.plaintext.as_slice(), &mut ciphertext, &params, cipher.clone(), BLOCK_SIZE, None)?;
+        if ciphertext != self.ciphertext {
+            return Err(failed());
+        }
+        let mut plaintext = Vec::new();
+        decrypt(&mut &self.ciphertext[header_len..], &mut plaintext, cipher, BLOCK_SIZE).map_err(|_| failed())?;
+        if plaintext != self.plaintext {
+            return Err(failed());
+        }
+        Ok(())
+    }
+
+    /// Hexadecimal fields: `key` or `password`, the Argon2 parameters, `salt`, `plaintext`, the whole `ciphertext` file and its trailing `mac` (HMAC or last AEAD tag).
+    pub fn to_json(&self) -> Result<String, Error> {
+        let (params, _, _) = self.cipher()?;
+        let mac_len = if params.cipher.is_aead() { AEAD_TAG_LEN } else { HMAC_LEN };
+        let key = match self.key {
+            TestKey::Raw => format!("\"key\":\"{}\"", encode_hex(&TEST_KEY)),
+            TestKey::Password => {
+                let argon2 = params.argon2_params()[0];
+                format!(
+                    "\"password\":\"{}\",\"argon2\":{{\"time_cost\":{},\"memory_cost\":{},\"parallelism\":{}}}",
+                    TEST_PASSWORD,
+                    argon2.t_cost(),
+                    argon2.m_cost(),
+                    argon2.p_cost(),
+                )
+            }
+        };
+        Ok(format!(
+            "{{\"name\":\"{}\",\"cipher\":\"{}\",{}{},\"salt\":\"{}\",\"plaintext\":\"{}\",\"ciphertext\":\"{}\",\"mac\":\"{}\"}}",
+            self.name,
+            params.cipher,
+            if params.cipher.is_aead() { String::new() } else { format!("\"mac_algorithm\":\"{}\",", params.mac) },
+            key,
+            encode_hex(params.salt()),
+            encode_hex(&self.plaintext),
+            encode_hex(&self.ciphertext),
+            encode_hex(&self.ciphertext[self.ciphertext.len()-mac_len..]),
+        ))
+    }
+}
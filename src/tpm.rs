@@ -0,0 +1,134 @@
+use std::{ffi::OsStr, fs, io::Write, path::Path, process::{Command, Stdio}};
+use zeroize::Zeroize;
+use crate::{Error, crypto::{KEY_LEN, KeySlot, MAX_TPM_PCR}};
+
+/// Firmware code and Secure Boot state: the key can't be unsealed once the machine boots something else.
+pub const DEFAULT_TPM_PCRS: &[u8] = &[0, 7];
+const PCR_BANK: &str = "sha256";
+
+/// Whether a TPM may be able to open TPM key slots, so that the password doesn't need to be asked upfront.
+pub fn tpm_available() -> bool {
+    cfg!(target_os = "linux") && (Path::new("/dev/tpmrm0").exists() || Path::new("/dev/tpm0").exists())
+}
+
+/// Runs a program of tpm2-tools and returns its stdout. Its stderr is only shown in the error if it fails, as failing to unseal isn't fatal when a password can be used.
+fn tpm2<S: AsRef<OsStr>>(tool: &str, args: &[S], input: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+    let mut child = Command::new(tool)
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Tpm(format!("can't run {}: {}", tool, e)))?;
+    let written = match (input, child.stdin.take()) {
+        (Some(input), Some(mut stdin)) => stdin.write_all(input),
+        _ => Ok(()),
+    };
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Tpm(match stderr.lines().rev().find(|line| !line.trim().is_empty()) {
+            Some(line) => format!("{}: {}", tool, line.trim()),
+            None => format!("{} {}", tool, output.status),
+        }));
+    }
+    written?;
+    Ok(output.stdout)
+}
+
+//the primary key is derived from the owner seed of the TPM, so it is the same every time with the same template
+fn create_primary(path: &Path) -> Result<(), Error> {
+    tpm2("tpm2_createprimary", &[OsStr::new("-C"), OsStr::new("o"), OsStr::new("-g"), OsStr::new("sha256"), OsStr::new("-G"), OsStr::new("ecc"), OsStr::new("-c"), path.as_os_str()], None).map(|_| ())
+}
+
+fn pcr_list(pcrs: &[u8]) -> String {
+    format!("{}:{}", PCR_BANK, pcrs.iter().map(u8::to_string).collect::<Vec<String>>().join(","))
+}
+
+/// PCRs of the sha256 bank whose current values the master key is sealed to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TpmPolicy {
+    pcrs: Vec<u8>,
+}
+
+impl Default for TpmPolicy {
+    fn default() -> Self {
+        Self { pcrs: DEFAULT_TPM_PCRS.to_vec() }
+    }
+}
+
+impl TpmPolicy {
+    /// Parses PCR indices separated by commas, e.g. "0,2,7".
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut pcrs = s.split(',')
+            .map(|pcr| pcr.trim().parse::<u8>().ok().filter(|pcr| *pcr <= MAX_TPM_PCR))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| Error::InvalidTpmPcrs(s.to_string()))?;
+        pcrs.sort_unstable();
+        pcrs.dedup();
+        Ok(Self { pcrs })
+    }
+
+    pub fn pcrs(&self) -> &[u8] {
+        &self.pcrs
+    }
+
+    /// Seals `master_key` in a new key slot with tpm2-tools. Only this TPM can unseal it, and only while the PCRs keep their current values.
+    pub fn seal(&self, master_key: &[u8; KEY_LEN]) -> Result<KeySlot, Error> {
+        let dir = tempfile::tempdir()?;
+        let primary = dir.path().join("primary.ctx");
+        let policy = dir.path().join("policy.dat");
+        let public = dir.path().join("seal.pub");
+        let private = dir.path().join("seal.priv");
+        create_primary(&primary)?;
+        tpm2("tpm2_createpolicy", &[OsStr::new("--policy-pcr"), OsStr::new("-l"), OsStr::new(&pcr_list(&self.pcrs)), OsStr::new("-L"), policy.as_os_str()], None)?;
+        //without userwithauth, the policy is the only way to unseal
+        tpm2("tpm2_create", &[
+            OsStr::new("-C"), primary.as_os_str(),
+            OsStr::new("-L"), policy.as_os_str(),
+            OsStr::new("-a"), OsStr::new("fixedtpm|fixedparent"),
+            OsStr::new("-i"), OsStr::new("-"),
+            OsStr::new("-u"), public.as_os_str(),
+            OsStr::new("-r"), private.as_os_str(),
+        ], Some(master_key))?;
+        Ok(KeySlot::Tpm {
+            pcrs: self.pcrs.clone(),
+            public: fs::read(&public)?,
+            private: fs::read(&private)?,
+        })
+    }
+}
+
+/// Returns the master key of the first TPM key slot that this machine can unseal, without running tpm2-tools if there is no such slot.
+pub fn unseal(key_slots: &[KeySlot]) -> Result<Option<[u8; KEY_LEN]>, Error> {
+    let tpm_slots: Vec<_> = key_slots.iter().filter_map(|key_slot| match key_slot {
+        KeySlot::Tpm { pcrs, public, private } => Some((pcrs, public, private)),
+        _ => None,
+    }).collect();
+    if tpm_slots.is_empty() {
+        return Ok(None);
+    }
+    let dir = tempfile::tempdir()?;
+    let primary = dir.path().join("primary.ctx");
+    let public_path = dir.path().join("seal.pub");
+    let private_path = dir.path().join("seal.priv");
+    let object = dir.path().join("seal.ctx");
+    create_primary(&primary)?;
+    let mut last_error = None;
+    for (pcrs, public, private) in tpm_slots {
+        fs::write(&public_path, public)?;
+        fs::write(&private_path, private)?;
+        //slots sealed by another TPM can't be loaded
+        let unsealed = tpm2("tpm2_load", &[OsStr::new("-C"), primary.as_os_str(), OsStr::new("-u"), public_path.as_os_str(), OsStr::new("-r"), private_path.as_os_str(), OsStr::new("-c"), object.as_os_str()], None)
+            .and_then(|_| tpm2("tpm2_unseal", &[OsStr::new("-c"), object.as_os_str(), OsStr::new("-p"), OsStr::new(&format!("pcr:{}", pcr_list(pcrs)))], None));
+        match unsealed {
+            Ok(mut key) => {
+                let master_key = key.as_slice().try_into().map_err(|_| Error::Tpm(String::from("invalid unsealed key")));
+                key.zeroize();
+                return master_key.map(Some);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    last_error.map_or(Ok(None), Err)
+}
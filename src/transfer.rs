@@ -0,0 +1,145 @@
+//! Transfer of a file between two machines by `doby send` and `doby receive`, encrypted with the key agreed on from a short code (see `pake`).
+
+use std::{fs::File, io::{self, BufReader, BufWriter, IsTerminal, Read, Write}, net::{Shutdown, TcpListener, TcpStream}, path::Path};
+use log::{debug, info};
+use zeroize::Zeroizing;
+use crate::{
+    Error,
+    Metadata,
+    WrappedReader,
+    WrappedWriter,
+    auto_block_size,
+    cli::{self, ReceiveArgs, SendArgs},
+    crypto::{DobyCipher, EncryptionParams, KeyDerivation},
+    encrypt,
+    files::{decrypt_file, restored_path},
+    pake::{CONFIRMATION_LEN, PAKE_MESSAGE_LEN, Pake, SessionKeys, generate_code},
+    read_header,
+    report::{self, Event},
+};
+
+/// Runs the key exchange of `receive` (the initiator) or `send`, and checks that the other side used the same code before returning the key.
+pub fn exchange_keys<S: Read + Write>(stream: &mut S, code: &str, initiator: bool) -> Result<SessionKeys, Error> {
+    let pake = Pake::new(code, initiator);
+    let message = pake.message();
+    let mut peer_message = [0; PAKE_MESSAGE_LEN];
+    let mut peer_confirmation = [0; CONFIRMATION_LEN];
+    if initiator {
+        stream.write_all(&message)?;
+        stream.read_exact(&mut peer_message)?;
+        let keys = pake.finish(&peer_message)?;
+        stream.read_exact(&mut peer_confirmation)?;
+        keys.check_confirmation(&peer_confirmation)?;
+        stream.write_all(&keys.confirmation())?;
+        Ok(keys)
+    } else {
+        stream.read_exact(&mut peer_message)?;
+        let keys = pake.finish(&peer_message)?;
+        stream.write_all(&message)?;
+        stream.write_all(&keys.confirmation())?;
+        match stream.read_exact(&mut peer_confirmation) {
+            //the receiver hangs up if our confirmation is wrong
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(Error::PakeFailed),
+            result => result?,
+        }
+        keys.check_confirmation(&peer_confirmation)?;
+        Ok(keys)
+    }
+}
+
+/// Waits for a single `doby receive` on `args.listen`.
+pub fn send(args: SendArgs) -> Result<(), Error> {
+    let listener = TcpListener::bind(&args.listen).map_err(|error| Error::Path { path: args.listen.clone(), error })?;
+    send_on(listener, args)
+}
+
+/// Same as `send`, on a listener already bound. A failed key exchange isn't retried, so that the code can only be guessed once.
+pub fn send_on(listener: TcpListener, args: SendArgs) -> Result<(), Error> {
+    let code = Zeroizing::new(args.code.unwrap_or_else(generate_code));
+    let (mut reader, input_metadata, name) = match &args.input {
+        Some(path) => {
+            let file = File::open(path).map_err(|error| Error::Path { path: path.clone(), error })?;
+            let name = Path::new(path).file_name().and_then(|name| name.to_str()).ok_or(Error::Usage("INPUT must be a file with a valid UTF-8 name"))?;
+            let metadata = file.metadata().ok();
+            (WrappedReader::from_file(file), metadata, Some(name.to_string()))
+        }
+        None => (WrappedReader::from_reader(io::stdin()), None, None),
+    };
+    let port = listener.local_addr()?.port();
+    if report::is_json() {
+        Event::new("send").string("code", &code).number("port", port as u64).emit();
+    } else {
+        //even with --quiet, as the receiver needs it
+        eprintln!("Code: {}", *code);
+        eprintln!("On the receiving machine, run: doby receive <address of this machine>:{}", port);
+    }
+    let (mut stream, peer) = listener.accept()?;
+    drop(listener);
+    debug!("connection from {}", peer);
+    let keys = exchange_keys(&mut stream, &code, false)?;
+    let mut params = EncryptionParams::with_raw_key(args.cipher);
+    params.mac = args.mac;
+    params.metadata = name.is_some();
+    let metadata = name.map(|name| Metadata { name: Some(name), ..Default::default() }.to_bytes());
+    let cipher = DobyCipher::with_master_key(keys.key(), &params)?;
+    let block_size = args.block_size.unwrap_or_else(|| auto_block_size(input_metadata.as_ref()));
+    let mut writer = BufWriter::with_capacity(block_size, &stream);
+    encrypt(&mut reader, &mut writer, &params, cipher, block_size, metadata.as_deref())?;
+    writer.flush()?;
+    drop(writer);
+    stream.shutdown(Shutdown::Write)?;
+    //sent once the whole file was authenticated and written
+    let mut confirmation = [0; 1];
+    if stream.read(&mut confirmation)? != 1 {
+        return Err(Error::TransferNotConfirmed);
+    }
+    info!("{} received by {}", args.input.as_deref().unwrap_or("stdin"), peer);
+    Ok(())
+}
+
+/// Receives a file from `doby send` at `args.address`, under the name it was sent with unless OUTPUT is given.
+pub fn receive(args: ReceiveArgs) -> Result<(), Error> {
+    let code = args.code.get_with_prompt("Code", false)?;
+    let mut stream = TcpStream::connect(&args.address).map_err(|error| Error::Path { path: args.address.clone(), error })?;
+    let keys = exchange_keys(&mut stream, &code, true)?;
+    let mut reader = BufReader::new(&stream);
+    let params = read_header(&mut reader)?;
+    if params.key_derivation != KeyDerivation::RawKey {
+        return Err(Error::InvalidHeader);
+    }
+    if args.output.is_none() && !params.metadata {
+        return Err(Error::Usage("the file was sent from stdin, without its name: OUTPUT is required"));
+    }
+    let cipher = DobyCipher::with_master_key(keys.key(), &params)?;
+    let restore_name = args.output.is_none();
+    //written in the current directory until the name is known
+    let writer = args.output.unwrap_or_else(|| WrappedWriter::from_path(String::from("doby-receive")));
+    let mut output_path = writer.path().map(String::from);
+    let block_size = auto_block_size(None);
+    let mut writer = writer.into_buf_writer_with_block_size(block_size)?;
+    let metadata = decrypt_file(&mut reader, &mut writer, &params, cipher, block_size, 1, false)?;
+    if restore_name {
+        let path = restored_path(".", metadata.as_ref())?;
+        //the name is chosen by the sender: an existing file is only replaced if the user agrees
+        if !args.force {
+            if Path::new(&path).symlink_metadata().is_ok() {
+                if !io::stdin().is_terminal() {
+                    return Err(Error::ReceivedFileExists(path));
+                }
+                if !cli::confirm_overwrite(&path, true)? {
+                    return Ok(());
+                }
+            } else {
+                writer.keep_existing();
+            }
+        }
+        writer.set_destination(&path);
+        output_path = Some(path);
+    }
+    writer.finish(args.fsync)?;
+    (&stream).write_all(&[1])?;
+    if let Some(path) = output_path {
+        info!("Received {}", path);
+    }
+    Ok(())
+}
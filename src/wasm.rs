@@ -0,0 +1,66 @@
+//! JavaScript bindings, built for wasm32-unknown-unknown with `--no-default-features --features wasm`.
+//!
+//! Errors are thrown as JavaScript `Error`s whose message is the one the command line would print.
+
+use std::io::{Read, Write};
+use wasm_bindgen::prelude::*;
+use zeroize::Zeroize;
+use crate::{
+    DecryptReader,
+    EncryptWriter,
+    Error,
+    crypto::{CipherAlgorithm, DobyCipher, EncryptionParams, default_argon2_params, nfc_password},
+    read_header,
+    recipient::{Identity, unwrap_with_identities},
+};
+
+fn js_error(e: Error) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+//authentication errors are only detected at the end: don't leave a partial plaintext in memory
+fn decrypt_with_cipher(ciphertext: &[u8], cipher: DobyCipher) -> Result<Vec<u8>, Error> {
+    let mut reader = DecryptReader::with_cipher(ciphertext, cipher);
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    if let Err(e) = reader.read_to_end(&mut plaintext) {
+        plaintext.zeroize();
+        return Err(e.into());
+    }
+    Ok(plaintext)
+}
+
+/// Encrypts `plaintext` with `password`, normalized to NFC, using the default Argon2 costs. WebAssembly has no AES instructions, so XChaCha20 is used, as by the command line on CPUs without AES-NI.
+#[wasm_bindgen]
+pub fn encrypt(password: &str, plaintext: &[u8]) -> Result<Vec<u8>, JsError> {
+    let (mut params, master_key) = EncryptionParams::with_password(&nfc_password(password.as_bytes()), default_argon2_params(), CipherAlgorithm::XChaCha20).map_err(js_error)?;
+    if !password.is_ascii() {
+        params.set_nfc_passwords().map_err(js_error)?;
+    }
+    let cipher = DobyCipher::with_master_key(&master_key, &params).map_err(js_error)?;
+    let mut writer = EncryptWriter::new(Vec::with_capacity(plaintext.len()+EncryptionParams::LEN+64), params, cipher);
+    writer.write_all(plaintext).map_err(Error::from).and_then(|_| writer.finish()).map_err(js_error)
+}
+
+/// Decrypts `ciphertext` with `password`. It must have been encrypted with a password, possibly among others.
+#[wasm_bindgen]
+pub fn decrypt(password: &str, mut ciphertext: &[u8]) -> Result<Vec<u8>, JsError> {
+    read_header(&mut ciphertext)
+        .and_then(|params| DobyCipher::try_with_password(password.as_bytes(), &params))
+        .and_then(|cipher| decrypt_with_cipher(ciphertext, cipher))
+        .map_err(js_error)
+}
+
+/// Decrypts `ciphertext`, encrypted with `--recipient`, with the identities of the identity file content `identities`.
+#[wasm_bindgen(js_name = decryptWithIdentities)]
+pub fn decrypt_with_identities(identities: &str, mut ciphertext: &[u8]) -> Result<Vec<u8>, JsError> {
+    let identities = Identity::parse_file_content(identities).map_err(js_error)?;
+    read_header(&mut ciphertext)
+        .and_then(|params| {
+            let mut master_key = unwrap_with_identities(&identities, params.key_slots())?.ok_or(Error::NoMatchingIdentity)?;
+            let cipher = DobyCipher::with_master_key(&master_key, &params);
+            master_key.zeroize();
+            cipher
+        })
+        .and_then(|cipher| decrypt_with_cipher(ciphertext, cipher))
+        .map_err(js_error)
+}
@@ -0,0 +1,143 @@
+use std::{fs, io, path::{Path, PathBuf}};
+#[cfg(target_os = "linux")]
+use std::{ffi::{CString, OsStr}, fs::File, io::Read, os::unix::{ffi::OsStrExt, io::{AsRawFd, FromRawFd, OwnedFd}}};
+#[cfg(not(target_os = "linux"))]
+use std::{collections::HashMap, thread, time::{Duration, SystemTime}};
+
+#[cfg(not(target_os = "linux"))]
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+//regular files not hidden, such as the temporary files of many programs
+fn is_candidate(path: &Path) -> bool {
+    !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        && fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file())
+}
+
+fn scan(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_candidate(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Reports the files of a directory once they have been written, so that they can be processed as they appear. Subdirectories, symbolic links and hidden files (whose name starts with a dot) are ignored.
+///
+/// On Linux, files are complete when they are closed after being written or moved into the directory (inotify). On other platforms, the directory is scanned every second and files are complete once their size and modification time stop changing.
+pub struct DirWatcher {
+    dir: PathBuf,
+    //the files already in the directory are reported first
+    scanned: bool,
+    #[cfg(target_os = "linux")]
+    inotify: File,
+    //size and modification time of the files found by the last scan, and whether they were reported
+    #[cfg(not(target_os = "linux"))]
+    files: HashMap<PathBuf, ((u64, Option<SystemTime>), bool)>,
+}
+
+impl DirWatcher {
+    #[cfg(target_os = "linux")]
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        let path = CString::new(dir.as_os_str().as_bytes()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+        //SAFETY: the descriptor is owned by nothing else
+        let inotify = match unsafe { libc::inotify_init1(libc::IN_CLOEXEC) } {
+            fd if fd >= 0 => File::from(unsafe { OwnedFd::from_raw_fd(fd) }),
+            _ => return Err(io::Error::last_os_error()),
+        };
+        //SAFETY: path is a valid C string
+        if unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), path.as_ptr(), libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_ONLYDIR) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { dir, scanned: false, inotify })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        if !fs::metadata(&dir)?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a directory"));
+        }
+        Ok(Self { dir, scanned: false, files: HashMap::new() })
+    }
+
+    /// Blocks until files are complete, and returns their paths. The first call returns the files already in the directory. A file is reported again if it's written again.
+    #[cfg(target_os = "linux")]
+    pub fn next_files(&mut self) -> io::Result<Vec<PathBuf>> {
+        if !self.scanned {
+            self.scanned = true;
+            let files = scan(&self.dir)?;
+            if !files.is_empty() {
+                return Ok(files);
+            }
+        }
+        //large enough for at least one event with a file name of NAME_MAX bytes
+        let mut buff = [0; 4096];
+        loop {
+            let n = self.inotify.read(&mut buff)?;
+            let mut files = Vec::new();
+            let mut offset = 0;
+            //struct inotify_event: wd, mask, cookie and len, followed by the name padded with NUL bytes
+            while offset + 16 <= n {
+                let field = |i: usize| u32::from_ne_bytes(buff[offset+4*i..offset+4*i+4].try_into().unwrap());
+                let (mask, len) = (field(1), field(3) as usize);
+                if mask & libc::IN_Q_OVERFLOW != 0 {
+                    return scan(&self.dir);
+                }
+                if mask & libc::IN_IGNORED != 0 {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, "the watched directory was removed"));
+                }
+                let name = &buff[offset+16..(offset+16+len).min(n)];
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                if !name.is_empty() {
+                    let path = self.dir.join(OsStr::from_bytes(name));
+                    if is_candidate(&path) && !files.contains(&path) {
+                        files.push(path);
+                    }
+                }
+                offset += 16 + len;
+            }
+            if !files.is_empty() {
+                return Ok(files);
+            }
+        }
+    }
+
+    /// Blocks until files are complete, and returns their paths. A file is reported again if it's written again.
+    #[cfg(not(target_os = "linux"))]
+    pub fn next_files(&mut self) -> io::Result<Vec<PathBuf>> {
+        loop {
+            if self.scanned {
+                thread::sleep(POLL_INTERVAL);
+            }
+            self.scanned = true;
+            let mut complete = Vec::new();
+            let mut files = HashMap::new();
+            for path in scan(&self.dir)? {
+                let metadata = match fs::metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                let stamp = (metadata.len(), metadata.modified().ok());
+                let reported = match self.files.get(&path) {
+                    Some((previous, reported)) if *previous == stamp => {
+                        if !reported {
+                            complete.push(path.clone());
+                        }
+                        true
+                    }
+                    _ => false,
+                };
+                files.insert(path, (stamp, reported));
+            }
+            self.files = files;
+            if !complete.is_empty() {
+                return Ok(complete);
+            }
+        }
+    }
+}
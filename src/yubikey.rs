@@ -0,0 +1,30 @@
+use std::process::{Command, Stdio};
+use crate::{Error, crypto::YUBIKEY_CHALLENGE_LEN};
+
+pub const DEFAULT_YUBIKEY_SLOT: u8 = 2;
+const RESPONSE_LEN: usize = 20;
+
+/// Sends `challenge` to a YubiKey slot configured for HMAC-SHA1 challenge-response and returns the response, using `ykchalresp` from yubikey-personalization. The token may wait to be touched.
+pub fn challenge_response(slot: u8, challenge: &[u8; YUBIKEY_CHALLENGE_LEN]) -> Result<Vec<u8>, Error> {
+    let hex: String = challenge.iter().map(|b| format!("{:02x}", b)).collect();
+    let output = Command::new("ykchalresp")
+        .arg(format!("-{}", slot))
+        .arg("-x")
+        .arg(hex)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| Error::YubiKey(format!("can't run ykchalresp: {}", e)))?;
+    if !output.status.success() {
+        return Err(Error::YubiKey(format!("ykchalresp {}", output.status)));
+    }
+    let response = String::from_utf8_lossy(&output.stdout);
+    let response = response.trim();
+    if response.len() != RESPONSE_LEN*2 || !response.is_ascii() {
+        return Err(Error::YubiKey(String::from("invalid response")));
+    }
+    (0..RESPONSE_LEN)
+        .map(|i| u8::from_str_radix(&response[i*2..i*2+2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| Error::YubiKey(String::from("invalid response")))
+}
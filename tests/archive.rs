@@ -0,0 +1,50 @@
+#![cfg(unix)]
+
+use std::{fs::{self, File}, io::{self, Read, Write}, os::unix::fs::{PermissionsExt, symlink}};
+use tempfile::TempDir;
+use doby::{ArchiveReader, extract_archive};
+
+#[test]
+fn round_trip() -> io::Result<()> {
+    let src = TempDir::new()?;
+    fs::create_dir_all(src.path().join("sub/empty"))?;
+    File::create(src.path().join("a"))?.write_all(b"file a")?;
+    File::create(src.path().join("sub/b"))?.write_all(&[42; 100_000])?;
+    fs::set_permissions(src.path().join("sub/b"), fs::Permissions::from_mode(0o600))?;
+    symlink("../a", src.path().join("sub/link"))?;
+
+    let mut archive = Vec::new();
+    ArchiveReader::new(src.path())?.read_to_end(&mut archive)?;
+
+    let dest = TempDir::new()?;
+    let dest_path = dest.path().join("extracted");
+    extract_archive(&mut archive.as_slice(), &dest_path)?;
+
+    assert_eq!(fs::read(dest_path.join("a"))?, b"file a");
+    assert_eq!(fs::read(dest_path.join("sub/b"))?, vec![42; 100_000]);
+    assert_eq!(fs::metadata(dest_path.join("sub/b"))?.permissions().mode() & 0o777, 0o600);
+    assert!(fs::metadata(dest_path.join("sub/empty"))?.is_dir());
+    assert_eq!(fs::read_link(dest_path.join("sub/link"))?.to_str(), Some("../a"));
+
+    //existing files are never overwritten
+    assert!(extract_archive(&mut archive.as_slice(), &dest_path).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn unsafe_paths() -> io::Result<()> {
+    let dest = TempDir::new()?;
+    for path in ["../escape", "/escape", ""] {
+        let mut archive = b"dbyarch\x01\x02".to_vec();
+        archive.extend_from_slice(&(path.len() as u16).to_be_bytes());
+        archive.extend_from_slice(path.as_bytes());
+        archive.extend_from_slice(&0o644u32.to_be_bytes());
+        archive.extend_from_slice(&0u64.to_be_bytes());
+        archive.push(0);
+        let error = extract_archive(&mut archive.as_slice(), dest.path().join("out")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+    assert!(!dest.path().parent().unwrap().join("escape").exists());
+    Ok(())
+}
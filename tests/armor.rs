@@ -0,0 +1,47 @@
+use std::io::{Read, Write};
+use doby::{ArmorReader, ArmorWriter, is_armored};
+
+fn armor(data: &[u8]) -> String {
+    let mut writer = ArmorWriter::new(Vec::new());
+    for chunk in data.chunks(100) {
+        writer.write_all(chunk).unwrap();
+    }
+    String::from_utf8(writer.finish().unwrap()).unwrap()
+}
+
+fn dearmor(text: &str) -> std::io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    ArmorReader::new(text.as_bytes()).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+#[test]
+fn round_trip() {
+    for len in [0, 1, 47, 48, 49, 1000] {
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let armored = armor(&data);
+        assert!(is_armored(armored.as_bytes()));
+        assert!(armored.lines().all(|line| line.len() <= 64 || line.starts_with("-----")));
+        assert_eq!(dearmor(&armored).unwrap(), data);
+    }
+}
+
+#[test]
+fn rewrapped() {
+    let data = [42; 500];
+    let armored = armor(&data);
+    let mut lines = armored.lines();
+    let begin = lines.next().unwrap();
+    let body: String = lines.clone().take_while(|l| !l.starts_with("-----")).collect();
+    let end = lines.last().unwrap();
+    let rewrapped = body.as_bytes().chunks(76).map(|l| std::str::from_utf8(l).unwrap()).collect::<Vec<&str>>().join("\r\n");
+    assert_eq!(dearmor(&format!("\n{}\r\n{}\r\n{}\r\n", begin, rewrapped, end)).unwrap(), data);
+}
+
+#[test]
+fn invalid() {
+    let armored = armor(b"some data");
+    assert!(dearmor(&armored.replace("-----END DOBY ENCRYPTED FILE-----\n", "")).is_err());
+    assert!(dearmor(&armored.replace("-----BEGIN DOBY ENCRYPTED FILE-----\n", "")).is_err());
+    assert!(dearmor(&armored.replacen('c', "!", 1)).is_err());
+}
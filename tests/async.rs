@@ -0,0 +1,43 @@
+#![cfg(feature = "async")]
+use doby::{
+    crypto::{CipherAlgorithm, EncryptionParams, DobyCipher},
+    Error,
+    decrypt,
+    decrypt_async,
+    encrypt_async,
+    read_header,
+    read_header_async,
+};
+
+const PASSWORD: &str = "the password";
+
+#[tokio::test]
+async fn round_trip() {
+    let plaintext: Vec<u8> = (0..150_000).map(|i| i as u8).collect();
+    for cipher in [CipherAlgorithm::XChaCha20, CipherAlgorithm::AesGcm] {
+        let params = EncryptionParams::new(
+            argon2::Params::new(8, 1, 1, None).unwrap(),
+            cipher
+        );
+        let mut ciphertext = Vec::new();
+        encrypt_async(&mut plaintext.as_slice(), &mut ciphertext, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 4096).await.unwrap();
+
+        //readable by the sync API
+        let mut reader = ciphertext.as_slice();
+        let sync_params = read_header(&mut reader).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt(&mut reader, &mut decrypted, DobyCipher::new(PASSWORD.as_bytes(), &sync_params).unwrap(), 4096).unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let mut reader = ciphertext.as_slice();
+        let params = read_header_async(&mut reader).await.unwrap();
+        let mut decrypted = Vec::new();
+        decrypt_async(&mut reader, &mut decrypted, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 1000).await.unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let mut reader = &ciphertext[..ciphertext.len()-1];
+        let params = read_header_async(&mut reader).await.unwrap();
+        let result = decrypt_async(&mut reader, &mut Vec::new(), DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 1000).await;
+        assert!(matches!(result, Err(Error::HmacMismatch)));
+    }
+}
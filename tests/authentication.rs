@@ -5,27 +5,29 @@ use doby::{
         EncryptionParams,
         DobyCipher,
     },
+    Error,
     encrypt,
     decrypt,
+    decrypt_with_report,
 };
 
-fn different_elements<T: Eq>(v1: &Vec<T>, v2: &Vec<T>) -> usize {
+fn different_elements<T: Eq>(v1: &[T], v2: &[T]) -> usize {
     assert_eq!(v1.len(), v2.len());
-    v1.into_iter().enumerate().filter(|x| v2[x.0] != *x.1).count()
+    v1.iter().enumerate().filter(|x| v2[x.0] != *x.1).count()
 }
 
 #[test]
 fn authentication() {
     const BLOCK_SIZE: usize = 65536;
     const PLAINTEXT: &[u8; 13] = b"the plaintext";
-    const CIPHERTEXT_SIZE: usize = PLAINTEXT.len()+113;
+    const CIPHERTEXT_SIZE: usize = PLAINTEXT.len()+114;
     const PASSWORD: &str = "the password";
     let params = EncryptionParams::new(
         argon2::Params::new(8, 1, 1, None).unwrap(),
         CipherAlgorithm::AesCtr
     );
 
-    let encrypter = DobyCipher::new(PASSWORD.as_bytes(), &params);
+    let encrypter = DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap();
     let mut ciphertext = Vec::with_capacity(CIPHERTEXT_SIZE);
     encrypt(&mut &PLAINTEXT[..], &mut ciphertext, &params, encrypter, BLOCK_SIZE, None).unwrap();
     assert_eq!(ciphertext.len(), CIPHERTEXT_SIZE);
@@ -36,15 +38,22 @@ fn authentication() {
             compromised[i] = rand::thread_rng().gen();
         }
         assert_eq!(different_elements(&compromised, &ciphertext), 1);
-        let decrypter = DobyCipher::new(PASSWORD.as_bytes(), &params);
+        let decrypter = DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap();
         let mut decrypted = Vec::with_capacity(PLAINTEXT.len());
-        let verified = decrypt(&mut &compromised[..], &mut decrypted, decrypter, BLOCK_SIZE).unwrap();
-        assert_eq!(verified, false);
+        let result = decrypt(&mut &compromised[..], &mut decrypted, decrypter.clone(), BLOCK_SIZE);
+        assert!(matches!(result, Err(Error::HmacMismatch)));
+        let report = decrypt_with_report(&mut &compromised[..], &mut Vec::new(), decrypter, BLOCK_SIZE).unwrap();
+        assert!(!report.verified);
+        assert_ne!(report.mac, report.expected_mac);
     }
 
-    let decrypter = DobyCipher::new(PASSWORD.as_bytes(), &params);
+    let decrypter = DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap();
     let mut decrypted = Vec::with_capacity(PLAINTEXT.len());
-    let verified = decrypt(&mut &ciphertext[4+EncryptionParams::LEN..], &mut decrypted, decrypter, BLOCK_SIZE).unwrap();
+    decrypt(&mut &ciphertext[4+EncryptionParams::LEN..], &mut decrypted, decrypter.clone(), BLOCK_SIZE).unwrap();
     assert_eq!(decrypted, PLAINTEXT);
-    assert_eq!(verified, true);
+    let report = decrypt_with_report(&mut &ciphertext[4+EncryptionParams::LEN..], &mut Vec::new(), decrypter, BLOCK_SIZE).unwrap();
+    assert!(report.verified);
+    assert_eq!(report.bytes, PLAINTEXT.len() as u64);
+    assert_eq!(report.mac.as_deref(), Some(&ciphertext[ciphertext.len()-32..]));
+    assert_eq!(report.mac, report.expected_mac);
 }
\ No newline at end of file
@@ -0,0 +1,146 @@
+use std::{fs, io::{self, Write}};
+use tempfile::TempDir;
+use doby::{
+    crypto::{
+        Argon2Limits,
+        CipherAlgorithm,
+        DobyCipher,
+        EncryptionParams,
+        MacAlgorithm,
+    },
+    Decryptor,
+    Encryptor,
+    Error,
+    PlaintextDigest,
+    decrypt,
+    decrypt_file,
+    encrypt,
+    encrypt_file,
+    read_header,
+};
+
+const PASSWORD: &str = "the password";
+
+fn argon2_params() -> argon2::Params {
+    argon2::Params::new(8, 1, 1, None).unwrap()
+}
+
+#[test]
+fn round_trip() {
+    let plaintext: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+    for cipher in [CipherAlgorithm::AesCtr, CipherAlgorithm::XChaCha20Poly1305] {
+        let encryptor = Encryptor::builder().cipher(cipher).mac(MacAlgorithm::Blake3).argon2_params(argon2_params()).block_size(4096).build();
+        let mut ciphertext = Vec::new();
+        encryptor.encrypt(PASSWORD.as_bytes(), &mut plaintext.as_slice(), &mut ciphertext).unwrap();
+
+        //compatible with the low-level API
+        let mut reader = ciphertext.as_slice();
+        let params = read_header(&mut reader).unwrap();
+        assert_eq!(params.cipher, cipher);
+        let mut decrypted = Vec::new();
+        decrypt(&mut reader, &mut decrypted, DobyCipher::try_new(PASSWORD.as_bytes(), &params).unwrap(), 4096).unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let decryptor = Decryptor::builder().block_size(1000).build();
+        let mut decrypted = Vec::new();
+        decryptor.decrypt(PASSWORD.as_bytes(), &mut ciphertext.as_slice(), &mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext);
+        assert!(matches!(decryptor.decrypt(b"wrong password", &mut ciphertext.as_slice(), &mut Vec::new()), Err(Error::WrongPassword)));
+
+        let mut writer = encryptor.writer(PASSWORD.as_bytes(), Vec::new()).unwrap();
+        for chunk in plaintext.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        let ciphertext = writer.finish().unwrap();
+        let mut decrypted = Vec::new();
+        io::copy(&mut decryptor.reader(PASSWORD.as_bytes(), ciphertext.as_slice()).unwrap(), &mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+}
+
+#[test]
+fn nfc_passwords() {
+    let encryptor = Encryptor::builder().argon2_params(argon2_params()).build();
+    let mut ciphertext = Vec::new();
+    encryptor.encrypt("cafe\u{301}".as_bytes(), &mut &b"plaintext"[..], &mut ciphertext).unwrap();
+    let mut decrypted = Vec::new();
+    Decryptor::builder().build().decrypt("caf\u{e9}".as_bytes(), &mut ciphertext.as_slice(), &mut decrypted).unwrap();
+    assert_eq!(decrypted, b"plaintext");
+
+    let encryptor = Encryptor::builder().argon2_params(argon2_params()).nfc(false).build();
+    let mut ciphertext = Vec::new();
+    encryptor.encrypt("cafe\u{301}".as_bytes(), &mut &b"plaintext"[..], &mut ciphertext).unwrap();
+    assert!(Decryptor::builder().build().decrypt("caf\u{e9}".as_bytes(), &mut ciphertext.as_slice(), &mut Vec::new()).is_err());
+}
+
+#[test]
+fn argon2_limits() {
+    let encryptor = Encryptor::builder().argon2_params(argon2::Params::new(64, 3, 1, None).unwrap()).build();
+    let mut ciphertext = Vec::new();
+    encryptor.encrypt(PASSWORD.as_bytes(), &mut &b"plaintext"[..], &mut ciphertext).unwrap();
+
+    let decryptor = Decryptor::builder().argon2_limits(Argon2Limits { time_cost: 2, memory_cost: 64 }).build();
+    assert!(matches!(
+        decryptor.decrypt(PASSWORD.as_bytes(), &mut ciphertext.as_slice(), &mut Vec::new()),
+        Err(Error::Argon2LimitExceeded { time_cost: 3, memory_cost: 64 })
+    ));
+    let decryptor = Decryptor::builder().argon2_limits(Argon2Limits { time_cost: 3, memory_cost: 64 }).build();
+    decryptor.decrypt(PASSWORD.as_bytes(), &mut ciphertext.as_slice(), &mut Vec::new()).unwrap();
+}
+
+#[test]
+fn files() {
+    let dir = TempDir::new().unwrap();
+    let (src, dst) = (dir.path().join("plaintext"), dir.path().join("ciphertext"));
+    fs::write(&src, b"plaintext").unwrap();
+    let encryptor = Encryptor::builder().argon2_params(argon2_params()).build();
+    let decryptor = Decryptor::builder().build();
+    encrypt_file(&src, &dst, PASSWORD.as_bytes(), &encryptor).unwrap();
+    assert_ne!(fs::read(&dst).unwrap(), b"plaintext");
+
+    //nothing is written when authentication fails
+    let output = dir.path().join("output");
+    assert!(matches!(decrypt_file(&dst, &output, b"wrong password", &decryptor), Err(Error::WrongPassword)));
+    let mut ciphertext = fs::read(&dst).unwrap();
+    *ciphertext.last_mut().unwrap() ^= 1;
+    fs::write(dir.path().join("tampered"), &ciphertext).unwrap();
+    assert!(decrypt_file(dir.path().join("tampered"), &output, PASSWORD.as_bytes(), &decryptor).is_err());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+    //in place
+    decrypt_file(&dst, &dst, PASSWORD.as_bytes(), &decryptor).unwrap();
+    assert_eq!(fs::read(&dst).unwrap(), b"plaintext");
+    //a file at the former temporary path is left untouched
+    let tmp = dir.path().join("plaintext.tmp");
+    fs::write(&tmp, b"plaintext").unwrap();
+    encrypt_file(&tmp, &src, PASSWORD.as_bytes(), &encryptor).unwrap();
+    assert_eq!(fs::read(&tmp).unwrap(), b"plaintext");
+    assert!(matches!(encrypt_file(dir.path().join("missing"), &src, PASSWORD.as_bytes(), &encryptor), Err(Error::Path { .. })));
+}
+
+#[test]
+fn plaintext_digest() {
+    let plaintext = b"the plaintext";
+    let digest = PlaintextDigest::compute(&mut &plaintext[..]).unwrap();
+    assert!(digest.matches(&mut &plaintext[..]).unwrap());
+    assert!(!digest.matches(&mut &b"another plaintext"[..]).unwrap());
+    //keyed: the same plaintext gets different digests
+    assert_ne!(digest.to_string(), PlaintextDigest::compute(&mut &plaintext[..]).unwrap().to_string());
+
+    let encrypt_with_digest = |digest: &PlaintextDigest| {
+        let (mut params, master_key) = EncryptionParams::with_password(PASSWORD.as_bytes(), argon2_params(), CipherAlgorithm::XChaCha20Poly1305).unwrap();
+        params.set_plaintext_digest(digest).unwrap();
+        let mut ciphertext = Vec::new();
+        encrypt(&mut &plaintext[..], &mut ciphertext, &params, DobyCipher::with_master_key(&master_key, &params).unwrap(), 4096, None).unwrap();
+        ciphertext
+    };
+    let decryptor = Decryptor::builder().build();
+    let mut decrypted = Vec::new();
+    decryptor.decrypt(PASSWORD.as_bytes(), &mut encrypt_with_digest(&digest).as_slice(), &mut decrypted).unwrap();
+    assert_eq!(decrypted, plaintext);
+    let wrong_digest = PlaintextDigest::compute(&mut &b"another plaintext"[..]).unwrap();
+    assert!(matches!(
+        decryptor.decrypt(PASSWORD.as_bytes(), &mut encrypt_with_digest(&wrong_digest).as_slice(), &mut Vec::new()),
+        Err(Error::DigestMismatch)
+    ));
+}
@@ -0,0 +1,118 @@
+#![cfg(feature = "capi")]
+use std::{ffi::{CStr, c_int, c_void}, ptr, slice};
+use doby::capi::*;
+
+const PASSWORD: &[u8] = b"the password";
+
+fn encrypt(plaintext: &[u8]) -> Vec<u8> {
+    let mut output = ptr::null_mut();
+    let mut output_len = 0;
+    assert_eq!(unsafe { doby_encrypt(PASSWORD.as_ptr(), PASSWORD.len(), plaintext.as_ptr(), plaintext.len(), &mut output, &mut output_len) }, DobyStatus::Ok);
+    let ciphertext = unsafe { slice::from_raw_parts(output, output_len) }.to_vec();
+    unsafe { doby_free(output, output_len) };
+    ciphertext
+}
+
+fn decrypt(password: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, DobyStatus> {
+    let mut output = ptr::null_mut();
+    let mut output_len = 0;
+    match unsafe { doby_decrypt(password.as_ptr(), password.len(), ciphertext.as_ptr(), ciphertext.len(), &mut output, &mut output_len) } {
+        DobyStatus::Ok => {
+            let plaintext = unsafe { slice::from_raw_parts(output, output_len) }.to_vec();
+            unsafe { doby_free(output, output_len) };
+            Ok(plaintext)
+        }
+        status => Err(status),
+    }
+}
+
+fn last_error() -> String {
+    unsafe { CStr::from_ptr(doby_last_error()) }.to_str().unwrap().to_string()
+}
+
+#[test]
+fn buffers() {
+    let plaintext: Vec<u8> = (0..150_000).map(|i| i as u8).collect();
+    let ciphertext = encrypt(&plaintext);
+    assert!(ciphertext.starts_with(doby::MAGIC_BYTES));
+    assert_eq!(decrypt(PASSWORD, &ciphertext), Ok(plaintext));
+    assert_eq!(decrypt(b"wrong password", &ciphertext), Err(DobyStatus::WrongPassword));
+    assert_eq!(last_error(), "wrong password");
+
+    let ciphertext = encrypt(&[]);
+    assert_eq!(decrypt(PASSWORD, &ciphertext), Ok(Vec::new()));
+    let mut altered = ciphertext.clone();
+    *altered.last_mut().unwrap() ^= 1;
+    assert_eq!(decrypt(PASSWORD, &altered), Err(DobyStatus::Authentication));
+    assert_eq!(decrypt(PASSWORD, b"not a ciphertext"), Err(DobyStatus::UnknownFormat));
+
+    let mut output_len = 0;
+    assert_eq!(unsafe { doby_encrypt(PASSWORD.as_ptr(), PASSWORD.len(), ptr::null(), 1, &mut ptr::null_mut(), &mut output_len) }, DobyStatus::InvalidArgument);
+    assert_eq!(unsafe { doby_decrypt(PASSWORD.as_ptr(), PASSWORD.len(), ciphertext.as_ptr(), ciphertext.len(), ptr::null_mut(), &mut output_len) }, DobyStatus::InvalidArgument);
+    assert_eq!(last_error(), "output is NULL");
+}
+
+unsafe extern "C" fn write_to_vec(user_data: *mut c_void, data: *const u8, len: usize) -> c_int {
+    (*(user_data as *mut Vec<u8>)).extend_from_slice(slice::from_raw_parts(data, len));
+    0
+}
+
+unsafe extern "C" fn failing_write(_user_data: *mut c_void, _data: *const u8, _len: usize) -> c_int {
+    -1
+}
+
+unsafe extern "C" fn read_from_slice(user_data: *mut c_void, buffer: *mut u8, len: usize) -> isize {
+    let input = &mut *(user_data as *mut &[u8]);
+    let n = len.min(input.len());
+    slice::from_raw_parts_mut(buffer, n).copy_from_slice(&input[..n]);
+    *input = &input[n..];
+    n as isize
+}
+
+fn decrypt_stream(password: &[u8], mut ciphertext: &[u8]) -> Result<Vec<u8>, DobyStatus> {
+    let mut decryptor = ptr::null_mut();
+    let status = unsafe { doby_decryptor_new(password.as_ptr(), password.len(), Some(read_from_slice), &mut ciphertext as *mut &[u8] as *mut c_void, &mut decryptor) };
+    if status != DobyStatus::Ok {
+        return Err(status);
+    }
+    let mut plaintext = Vec::new();
+    let mut buffer = [0; 1000];
+    let result = loop {
+        let mut n = 0;
+        match unsafe { doby_decryptor_read(decryptor, buffer.as_mut_ptr(), buffer.len(), &mut n) } {
+            DobyStatus::Ok if n == 0 => break Ok(plaintext),
+            DobyStatus::Ok => plaintext.extend_from_slice(&buffer[..n]),
+            status => break Err(status),
+        }
+    };
+    unsafe { doby_decryptor_free(decryptor) };
+    result
+}
+
+#[test]
+fn streaming() {
+    let plaintext: Vec<u8> = (0..150_000).map(|i| i as u8).collect();
+    let mut ciphertext = Vec::new();
+    let mut encryptor = ptr::null_mut();
+    assert_eq!(unsafe { doby_encryptor_new(PASSWORD.as_ptr(), PASSWORD.len(), Some(write_to_vec), &mut ciphertext as *mut Vec<u8> as *mut c_void, &mut encryptor) }, DobyStatus::Ok);
+    for chunk in plaintext.chunks(7000) {
+        assert_eq!(unsafe { doby_encryptor_write(encryptor, chunk.as_ptr(), chunk.len()) }, DobyStatus::Ok);
+    }
+    assert_eq!(unsafe { doby_encryptor_finish(encryptor) }, DobyStatus::Ok);
+
+    //both APIs produce the same format
+    assert_eq!(decrypt(PASSWORD, &ciphertext), Ok(plaintext.clone()));
+    assert_eq!(decrypt_stream(PASSWORD, &ciphertext), Ok(plaintext.clone()));
+    assert_eq!(decrypt_stream(PASSWORD, &encrypt(&plaintext)), Ok(plaintext.clone()));
+    assert_eq!(decrypt_stream(b"wrong password", &ciphertext), Err(DobyStatus::WrongPassword));
+    *ciphertext.last_mut().unwrap() ^= 1;
+    assert_eq!(decrypt_stream(PASSWORD, &ciphertext), Err(DobyStatus::Authentication));
+    assert_eq!(decrypt_stream(PASSWORD, &ciphertext[..10]), Err(DobyStatus::Io));
+
+    let mut encryptor = ptr::null_mut();
+    assert_eq!(unsafe { doby_encryptor_new(PASSWORD.as_ptr(), PASSWORD.len(), Some(failing_write), ptr::null_mut(), &mut encryptor) }, DobyStatus::Ok);
+    assert_eq!(unsafe { doby_encryptor_write(encryptor, plaintext.as_ptr(), 10) }, DobyStatus::Io);
+    assert_eq!(last_error(), "I/O error: write callback failed");
+    unsafe { doby_encryptor_free(encryptor) };
+    assert_eq!(unsafe { doby_encryptor_new(PASSWORD.as_ptr(), PASSWORD.len(), None, ptr::null_mut(), &mut encryptor) }, DobyStatus::InvalidArgument);
+}
@@ -0,0 +1,24 @@
+#![cfg(feature = "os")]
+//zeroize_secrets and remove_temporary_outputs act on the whole process: keep them in their own test binary
+
+use std::io::{self, Write};
+use tempfile::TempDir;
+use doby::{WrappedWriter, memlock::{Locked, zeroize_secrets}, remove_temporary_outputs};
+
+#[test]
+fn abort_cleanup() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("output").display().to_string();
+    let mut writer = WrappedWriter::from_path(path.clone()).into_buf_writer().unwrap();
+    writer.write_all(b"partial output")?;
+    writer.flush()?;
+    let key = Locked::new([42; 32]);
+    let password = Locked::new(String::from("the password"));
+
+    zeroize_secrets();
+    remove_temporary_outputs();
+    assert_eq!(*key, [0; 32]);
+    assert!(password.is_empty());
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+    Ok(())
+}
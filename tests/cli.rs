@@ -1,12 +1,21 @@
-use std::{convert::TryInto, fs::{self, File, create_dir}, io::{self, Read, Write}, path::PathBuf};
+use std::{convert::TryInto, fs::{self, File, create_dir}, io::{self, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, time::{Duration, Instant, SystemTime}};
 use assert_cmd::{Command, cargo::{CargoError, cargo_bin}};
 use tempfile::TempDir;
-use doby::crypto::{CipherAlgorithm, SALT_LEN, HMAC_LEN};
+use doby::{
+    crypto::{CipherAlgorithm, EncryptionParams, DobyCipher, KeyDerivation, MacAlgorithm, KEY_SLOTS_FORMAT_VERSION, LATEST_FORMAT_VERSION, KEY_CHECK_FORMAT_VERSION, KEY_CHECK_LEN, SALT_LEN, HMAC_LEN, AEAD_TAG_LEN, format_key_id, key_id},
+    recipient::Recipient,
+    MAGIC_BYTES,
+    LEGACY_MAGIC_BYTES,
+};
 
 const PLAINTEXT: &[u8] = b"the plaintext";
 const PASSWORD: &str = "the password";
 
-fn setup_files<'a>() -> io::Result<(PathBuf, PathBuf, PathBuf)> {
+fn recipient_key_id(public_key: &str) -> String {
+    format_key_id(&Recipient::parse(public_key).unwrap().key_id().unwrap())
+}
+
+fn setup_files() -> io::Result<(PathBuf, PathBuf, PathBuf)> {
     let tmp_dir = TempDir::new()?;
     let tmp_path = PathBuf::from(tmp_dir.path());
     drop(tmp_dir);
@@ -20,6 +29,18 @@ fn setup_files<'a>() -> io::Result<(PathBuf, PathBuf, PathBuf)> {
     Ok((tmp_path, tmp_plaintext, tmp_ciphertext))
 }
 
+//the output is written to <OUTPUT>.<random suffix>.tmp before being renamed
+fn temporary_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
 fn doby_cmd() -> Result<Command, CargoError> {
     let mut cmd = Command::cargo_bin("doby")?;
     cmd.arg("--password").arg(PASSWORD);
@@ -85,7 +106,7 @@ fn force_encrypt() -> io::Result<()> {
     let buff_ciphertext_2 = fs::read(&tmp_ciphertext_2)?;
     assert_ne!(buff_ciphertext_1, buff_ciphertext_2);
     assert_ne!(buff_ciphertext_2, PLAINTEXT);
-    assert!(buff_ciphertext_2.len() >= buff_ciphertext_1.len()+113);
+    assert!(buff_ciphertext_2.len() >= buff_ciphertext_1.len()+114);
 
     let tmp_decrypted_1 = tmp_path.join("decrypted_1");
     doby_cmd().unwrap().arg(tmp_ciphertext_2).arg(&tmp_decrypted_1).assert().success().stdout("").stderr("");
@@ -101,42 +122,2260 @@ fn force_encrypt() -> io::Result<()> {
     Ok(())
 }
 
-fn test_cipher(cipher_str: &str, cipher_algorithm: CipherAlgorithm) -> io::Result<()> {
+fn test_cipher(cipher_str: &str, cipher_algorithm: CipherAlgorithm, tag_len: usize) -> io::Result<()> {
     let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
 
     doby_cmd().unwrap().arg("-c").arg(cipher_str).arg(tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
 
     let ciphertext = fs::read(&tmp_ciphertext)?;
-    assert_eq!(ciphertext[4+SALT_LEN+4*3], cipher_algorithm as u8);
-    assert_eq!(ciphertext.len(), PLAINTEXT.len()+17+SALT_LEN+HMAC_LEN);
+    assert_eq!(ciphertext[5+SALT_LEN+4*3], cipher_algorithm as u8);
+    assert_eq!(ciphertext.len(), PLAINTEXT.len()+18+SALT_LEN+KEY_CHECK_LEN+tag_len);
 
     doby_cmd().unwrap().arg(tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
 
     Ok(())
 }
 
+#[test]
+fn tiny_block_sizes() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    for cipher in ["aes", "xchacha20", "aes-gcm", "xchacha20-poly1305"] {
+        for block_size in ["1", "8"] {
+            doby_cmd().unwrap().arg("-c").arg(cipher).arg("-b").arg(block_size).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+            doby_cmd().unwrap().arg("-b").arg(block_size).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+            fs::remove_file(&tmp_ciphertext)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mmap() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let mapped = |cmd: &mut Command| String::from_utf8(cmd.arg("-v").assert().success().get_output().stderr.clone()).unwrap().contains(" mapped in memory");
+
+    for (cipher, option) in [("aes-gcm", "--preserve"), ("xchacha20-poly1305", "--digest")] {
+        assert!(mapped(doby_cmd().unwrap().arg("--mmap").arg("-c").arg(cipher).arg("-b").arg("5").arg(option).arg(&tmp_plaintext).arg(&tmp_ciphertext)));
+        doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT);
+        fs::remove_file(&tmp_ciphertext)?;
+    }
+    assert!(mapped(doby_cmd().unwrap().arg("--mmap").arg("batch").arg(&tmp_plaintext)));
+    let batch_ciphertext = tmp_path.join("plaintext.doby");
+    doby_cmd().unwrap().arg(&batch_ciphertext).assert().success().stdout(PLAINTEXT);
+
+    //stdin and empty files are read as usual
+    assert!(!mapped(doby_cmd().unwrap().arg("--mmap").write_stdin(PLAINTEXT)));
+    let empty = tmp_path.join("empty");
+    File::create(&empty)?;
+    assert!(!mapped(doby_cmd().unwrap().arg("--mmap").arg(&empty).arg(&tmp_ciphertext)));
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout("");
+
+    doby_cmd().unwrap().arg("--mmap").arg(&tmp_ciphertext).assert().failure().stderr("Error: --mmap only applies to encryption\n");
+    doby_cmd().unwrap().arg("--mmap").arg("-c").arg("aes").arg(&tmp_plaintext).assert().failure().stderr("Error: --mmap requires a chunked cipher (aes-gcm or xchacha20-poly1305)\n");
+
+    Ok(())
+}
+
+#[test]
+fn rate_limit() -> io::Result<()> {
+    let (tmp_path, _, tmp_ciphertext) = setup_files()?;
+    let input = tmp_path.join("input");
+    fs::write(&input, vec![0x42; 30_000])?;
+
+    let start = Instant::now();
+    doby_cmd().unwrap().arg("--rate-limit").arg("100K").arg(&input).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert!(start.elapsed() >= Duration::from_millis(250));
+    doby_cmd().unwrap().arg("--rate-limit").arg("100K").arg("--mmap").arg("-f").arg(&input).assert().success();
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    doby_cmd().unwrap().arg("--rate-limit").arg("1G").arg(&tmp_ciphertext).assert().success().stdout(vec![0x42; 30_000]);
+
+    doby_cmd().unwrap().arg("--rate-limit").arg("0").arg(&input).assert().failure().stdout("").stderr("Error: '0' is not a number\n");
+
+    Ok(())
+}
+
+#[test]
+fn auto_block_size() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let block_size = |cmd: &mut Command| {
+        let stderr = String::from_utf8(cmd.arg("-v").assert().success().get_output().stderr.clone()).unwrap();
+        stderr.split("] block size: ").nth(1).unwrap().split(' ').next().unwrap().parse::<usize>().unwrap()
+    };
+
+    //small files are read at once
+    assert_eq!(block_size(doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext)), PLAINTEXT.len());
+    assert_eq!(block_size(doby_cmd().unwrap().arg(&tmp_ciphertext)), fs::metadata(&tmp_ciphertext)?.len() as usize);
+    assert_eq!(block_size(doby_cmd().unwrap().write_stdin(PLAINTEXT)), doby::DEFAULT_BLOCK_SIZE);
+    assert_eq!(block_size(doby_cmd().unwrap().arg("-b").arg("4096").arg(&tmp_ciphertext)), 4096);
+
+    doby_cmd().unwrap().arg("-b").arg("0").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: --block-size must not be 0\n");
+
+    Ok(())
+}
+
 #[test]
 fn xchacha20_cipher() -> io::Result<()> {
-    test_cipher("xchacha20", CipherAlgorithm::XChaCha20)?;
+    test_cipher("xchacha20", CipherAlgorithm::XChaCha20, HMAC_LEN)?;
     Ok(())
 }
 
 #[test]
 fn aes_cipher() -> io::Result<()> {
-    test_cipher("aes", CipherAlgorithm::AesCtr)?;
+    test_cipher("aes", CipherAlgorithm::AesCtr, HMAC_LEN)?;
+    Ok(())
+}
+
+#[test]
+fn aes_gcm_cipher() -> io::Result<()> {
+    test_cipher("aes-gcm", CipherAlgorithm::AesGcm, AEAD_TAG_LEN)?;
+    Ok(())
+}
+
+#[test]
+fn xchacha20_poly1305_cipher() -> io::Result<()> {
+    test_cipher("xchacha20-poly1305", CipherAlgorithm::XChaCha20Poly1305, AEAD_TAG_LEN)?;
     Ok(())
 }
 
 #[test]
 fn argon2_params() -> io::Result<()> {
-    Command::cargo_bin("doby").unwrap().arg("-t").arg("0").assert().failure().stderr("Invalid Argon2 parameters: time cost is too small\n");
-    Command::cargo_bin("doby").unwrap().arg("-m").arg("0").assert().failure().stderr("Invalid Argon2 parameters: memory cost is too small\n");
-    Command::cargo_bin("doby").unwrap().arg("-p").arg("0").assert().failure().stderr("Invalid Argon2 parameters: not enough threads\n");
+    Command::cargo_bin("doby").unwrap().arg("-t").arg("0").assert().failure().stderr("Error: invalid Argon2 parameters: time cost is too small\n");
+    Command::cargo_bin("doby").unwrap().arg("-m").arg("0").assert().failure().stderr("Error: invalid Argon2 parameters: memory cost is too small\n");
+    Command::cargo_bin("doby").unwrap().arg("-p").arg("0").assert().failure().stderr("Error: invalid Argon2 parameters: not enough threads\n");
 
     let ciphertext = doby_cmd().unwrap().arg("-t").arg("8").arg("-m").arg("2048").arg("-p").arg("8").assert().success().stderr("").get_output().stdout.clone();
-    assert_eq!(u32::from_be_bytes(ciphertext[4+SALT_LEN..4+SALT_LEN+4].try_into().unwrap()), 8); //time cost
-    assert_eq!(u32::from_be_bytes(ciphertext[4+SALT_LEN+4..4+SALT_LEN+8].try_into().unwrap()), 2048); //memory cost
-    assert_eq!(u32::from_be_bytes(ciphertext[4+SALT_LEN+8..4+SALT_LEN+12].try_into().unwrap()), 8); //parallelism
+    assert_eq!(u32::from_be_bytes(ciphertext[5+SALT_LEN..5+SALT_LEN+4].try_into().unwrap()), 8); //time cost
+    assert_eq!(u32::from_be_bytes(ciphertext[5+SALT_LEN+4..5+SALT_LEN+8].try_into().unwrap()), 2048); //memory cost
+    assert_eq!(u32::from_be_bytes(ciphertext[5+SALT_LEN+8..5+SALT_LEN+12].try_into().unwrap()), 8); //parallelism
+
+    Ok(())
+}
+
+#[test]
+fn legacy_format() -> io::Result<()> {
+    let (_, _, tmp_ciphertext) = setup_files()?;
+
+    let mut header = vec![0x42; SALT_LEN];
+    header.extend_from_slice(&[0, 0, 0, 0x01, 0, 0, 0, 0x08, 0, 0, 0, 0x01, CipherAlgorithm::XChaCha20 as u8]);
+    let params = EncryptionParams::read_legacy(&mut header.as_slice()).unwrap();
+    let mut cipher = DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap();
+    let mut ciphertext = LEGACY_MAGIC_BYTES.to_vec();
+    params.write(&mut ciphertext)?;
+    cipher.encrypt_chunk(&mut PLAINTEXT.to_vec(), &mut ciphertext)?;
+    cipher.write_hmac(&mut ciphertext)?;
+    fs::write(&tmp_ciphertext, ciphertext)?;
+
+    doby_cmd().unwrap().arg(tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    Ok(())
+}
+
+#[test]
+fn unknown_format_version() -> io::Result<()> {
+    let (_, _, tmp_ciphertext) = setup_files()?;
+
+    let mut ciphertext = MAGIC_BYTES.to_vec();
+    ciphertext.push(LATEST_FORMAT_VERSION+1);
+    ciphertext.extend_from_slice(&[0; 128]);
+    fs::write(&tmp_ciphertext, ciphertext)?;
+
+    doby_cmd().unwrap().arg(tmp_ciphertext).assert().failure().stdout("").stderr(format!("Error: unsupported format version {} (latest supported: {})\n", LATEST_FORMAT_VERSION+1, LATEST_FORMAT_VERSION));
+
+    Ok(())
+}
+
+#[test]
+fn truncated() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("-c").arg("xchacha20").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let ciphertext = fs::read(&tmp_ciphertext)?;
+    let header_len = ciphertext.len()-PLAINTEXT.len()-HMAC_LEN;
+
+    fs::write(&tmp_ciphertext, &ciphertext[..40])?;
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().failure().stdout("").stderr(format!("Error: file appears truncated (expected at least {} bytes)\n", 5+SALT_LEN));
+
+    fs::write(&tmp_ciphertext, &ciphertext[..header_len+10])?;
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().failure().stdout("").stderr(format!("Error: file appears truncated (expected at least {} bytes)\n", header_len+HMAC_LEN));
+
+    Ok(())
+}
+
+#[test]
+fn verify_first() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg("--verify-first").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    let shell_cmd = format!("cat {} | {} --password \"{}\" --verify-first", tmp_ciphertext.to_str().unwrap(), cargo_bin("doby").to_str().unwrap(), PASSWORD);
+    bash_cmd().arg(shell_cmd).assert().success().stdout(PLAINTEXT).stderr("");
+
+    let mut ciphertext = fs::read(&tmp_ciphertext)?;
+    let last = ciphertext.len()-1;
+    ciphertext[last] ^= 1;
+    fs::write(&tmp_ciphertext, ciphertext)?;
+
+    let tmp_decrypted = tmp_path.join("decrypted");
+    doby_cmd().unwrap().arg("--verify-first").arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().failure().stdout("");
+    assert!(!tmp_decrypted.exists());
+
+    Ok(())
+}
+
+#[test]
+fn threads() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("--threads").arg("4").arg(tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg("--threads").arg("4").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    doby_cmd().unwrap().arg("--threads").arg("0").arg(tmp_ciphertext).assert().failure().stdout("").stderr("Error: --threads must not be 0\n");
+
+    Ok(())
+}
+
+#[test]
+fn progress() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    let output = doby_cmd().unwrap().arg("--progress").arg(tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").get_output().clone();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("13 B / 13 B (100%)"));
+    let output = doby_cmd().unwrap().arg("--progress").arg(tmp_ciphertext).assert().success().stdout(PLAINTEXT).get_output().clone();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("(100%)"));
+
+    Ok(())
+}
+
+#[test]
+fn stats() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    let output = doby_cmd().unwrap().arg("--stats").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").get_output().clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with(&format!("Read 13 B, wrote {} B in ", fs::metadata(&tmp_ciphertext)?.len())));
+    assert!(stderr.contains("\nKey derivation: "));
+
+    let output = doby_cmd().unwrap().arg("--stats").arg("--json").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).get_output().clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("{\"event\":\"result\""));
+    assert!(lines[1].starts_with(&format!("{{\"event\":\"stats\",\"bytes_in\":{},\"bytes_out\":13,\"wall_milliseconds\":", fs::metadata(&tmp_ciphertext)?.len())));
+    assert!(lines[1].contains(",\"kdf_milliseconds\":"));
+
+    //nothing is measured on failure
+    doby_cmd().unwrap().arg("--stats").arg("-d").arg(&tmp_plaintext).assert().failure().stderr("Error: doby format not recognized\n");
+
+    Ok(())
+}
+
+#[test]
+fn sparse() -> io::Result<()> {
+    let (tmp_path, _, tmp_ciphertext) = setup_files()?;
+    let input = tmp_path.join("sparse");
+    let mut file = File::create(&input)?;
+    file.set_len(1 << 22)?;
+    file.seek(SeekFrom::Start(1 << 20))?;
+    file.write_all(PLAINTEXT)?;
+    let content = fs::read(&input)?;
+    //not all filesystems support holes
+    if !doby::has_holes(&file)? {
+        return Ok(());
+    }
+
+    doby_cmd().unwrap().arg(&input).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert!(fs::metadata(&tmp_ciphertext)?.len() < 1 << 16);
+    let inspection = doby_cmd().unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().get_output().stdout.clone();
+    assert!(String::from_utf8(inspection).unwrap().ends_with("\nSparse: holes of the plaintext not stored\n"));
+    let output = tmp_path.join("output");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).arg(&output).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&output)?, content);
+    assert!(doby::has_holes(&File::open(&output)?)?);
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(content.clone());
+
+    doby_cmd().unwrap().arg("--no-sparse").arg("-f").arg(&input).arg(&tmp_ciphertext).assert().success();
+    assert!(fs::metadata(&tmp_ciphertext)?.len() > 1 << 22);
+    let inspection = doby_cmd().unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().get_output().stdout.clone();
+    assert!(!String::from_utf8(inspection).unwrap().contains("Sparse"));
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(content);
+
+    Ok(())
+}
+
+#[test]
+fn resume() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let input = tmp_path.join("input");
+    let content: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+    fs::write(&input, &content)?;
+    let state = tmp_path.join("ciphertext.resume");
+
+    let mut child = std::process::Command::new(cargo_bin("doby")).arg("--password").arg(PASSWORD).arg("--resume").arg("--rate-limit").arg("256K").arg(&input).arg(&tmp_ciphertext).spawn()?;
+    let start = Instant::now();
+    while !state.exists() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    child.kill()?;
+    child.wait()?;
+    assert!(!tmp_ciphertext.exists());
+    assert_eq!(temporary_files(&tmp_path)?.len(), 1);
+
+    let output = doby_cmd().unwrap().arg("--resume").arg("-v").arg(&input).arg(&tmp_ciphertext).assert().success().stdout("").get_output().clone();
+    assert!(String::from_utf8(output.stderr).unwrap().contains("] resuming encryption at chunk "));
+    assert!(!state.exists());
+    assert!(temporary_files(&tmp_path)?.is_empty());
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(content);
+
+    doby_cmd().unwrap().arg("--resume").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: --resume requires INPUT to be a regular file and OUTPUT a file\n");
+    doby_cmd().unwrap().arg("--resume").arg("--armor").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stderr("Error: --recursive, --armor, --qr, --ecc, --detach-header, --sign-key, --mmap and --format can't be used with --resume\n");
+    doby_cmd().unwrap().arg("--resume").arg("-c").arg("xchacha20").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stderr("Error: --resume requires a chunked cipher (aes-gcm or xchacha20-poly1305)\n");
+
+    Ok(())
+}
+
+#[test]
+fn watch() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, _) = setup_files()?;
+    let dir = tmp_path.join("incoming");
+    let output_dir = tmp_path.join("encrypted");
+    let processed = tmp_path.join("processed");
+    create_dir(&dir)?;
+    fs::copy(&tmp_plaintext, dir.join("existing"))?;
+
+    let mut child = std::process::Command::new(cargo_bin("doby")).arg("watch").arg("-q").arg("--password").arg(PASSWORD).arg(&dir).arg("--output-dir").arg(&output_dir).arg("--move-to").arg(&processed).spawn()?;
+    let wait_for = |path: PathBuf| {
+        let start = Instant::now();
+        while !path.exists() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    };
+    wait_for(processed.join("existing"));
+    fs::write(dir.join(".dropped"), b"dropped later")?;
+    fs::rename(dir.join(".dropped"), dir.join("dropped"))?;
+    wait_for(processed.join("dropped"));
+    child.kill()?;
+    child.wait()?;
+    assert_eq!(fs::read_dir(&dir)?.count(), 0);
+    doby_cmd().unwrap().arg(output_dir.join("existing.doby")).assert().success().stdout(PLAINTEXT);
+    doby_cmd().unwrap().arg(output_dir.join("dropped.doby")).assert().success().stdout("dropped later");
+
+    doby_cmd().unwrap().arg("watch").arg(&dir).arg("--output-dir").arg(&dir).assert().failure().stderr("Error: --output-dir and --move-to must be different from DIR\n");
+    doby_cmd().unwrap().arg("watch").arg(&tmp_plaintext).arg("--output-dir").arg(&output_dir).assert().failure().stderr("Error: DIR must be a directory\n");
+
+    Ok(())
+}
+
+#[test]
+fn serve() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let socket = tmp_path.join("doby.sock");
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+
+    let mut child = std::process::Command::new(cargo_bin("doby")).arg("serve").arg("-q").arg("--password").arg(PASSWORD).arg("--socket").arg(&socket).spawn()?;
+    let start = Instant::now();
+    while !socket.exists() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let client = || {
+        let mut cmd = Command::cargo_bin("doby").unwrap();
+        cmd.arg("client").arg("--socket").arg(&socket);
+        cmd
+    };
+    let ciphertext = client().write_stdin(PLAINTEXT).assert().success().get_output().stdout.clone();
+    assert!(ciphertext.starts_with(MAGIC_BYTES));
+    client().arg("-d").write_stdin(ciphertext.clone()).assert().success().stdout(PLAINTEXT);
+    doby_cmd().unwrap().write_stdin(ciphertext).assert().success().stdout(PLAINTEXT);
+    //not encrypted by the server
+    let tmp_decrypted = tmp_path.join("decrypted");
+    client().arg("-d").arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().success().stdout("");
+    assert_eq!(fs::read(&tmp_decrypted)?, PLAINTEXT);
+    client().arg("-d").arg(&tmp_plaintext).assert().failure().stderr("Error: doby serve: doby format not recognized\n");
+    client().arg(&tmp_ciphertext).assert().failure().stderr("Error: doby serve: input is already in doby format (use -f to encrypt it anyway)\n");
+    doby_cmd().unwrap().arg("serve").arg("--socket").arg(&socket).assert().failure().stderr(format!("Error: {}: another server is listening\n", socket.display()));
+    child.kill()?;
+    child.wait()?;
+
+    doby_cmd().unwrap().arg("serve").arg("--socket").arg(&socket).arg("--armor").assert().failure().stderr("Error: --recursive, --armor, --ecc, --verify-first, --keep-unverified, --progress, --rm, --shred, --mmap, --new-password, --preserve, --store-name, --restore-name, --detach-header, --header, --format, --openssl-iter, --retries, --sign-key, --yubikey and --test-salt-hex can't be used with serve\n");
+    doby_cmd().unwrap().arg("serve").arg("--socket").arg(&tmp_plaintext).assert().failure().stderr(format!("Error: {}: not a socket\n", tmp_plaintext.display()));
+
+    Ok(())
+}
+
+#[test]
+fn send_receive() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, _) = setup_files()?;
+    let send = |code: &str| -> io::Result<(std::process::Child, String)> {
+        let mut child = std::process::Command::new(cargo_bin("doby")).arg("send").arg("--listen").arg("127.0.0.1:0").arg("--code").arg(code).arg(&tmp_plaintext).stderr(std::process::Stdio::piped()).spawn()?;
+        let mut stderr = io::BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        while !line.starts_with("On the receiving machine") {
+            line.clear();
+            assert_ne!(io::BufRead::read_line(&mut stderr, &mut line)?, 0);
+        }
+        let port = line.trim().rsplit(':').next().unwrap().to_string();
+        child.stderr = Some(stderr.into_inner());
+        Ok((child, format!("127.0.0.1:{}", port)))
+    };
+
+    let (child, address) = send("7-amber-falcon")?;
+    let received = tmp_path.join("received");
+    create_dir(&received)?;
+    Command::cargo_bin("doby").unwrap().current_dir(&received).arg("receive").arg(&address).arg("--code").arg("7-amber-falcon").assert().success().stdout("").stderr("Received ./plaintext\n");
+    assert_eq!(fs::read(received.join("plaintext"))?, PLAINTEXT);
+    let output = child.wait_with_output()?;
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("plaintext received by 127.0.0.1:"));
+
+    //the name is chosen by the sender: existing files are only replaced with --force
+    fs::write(received.join("plaintext"), b"existing file")?;
+    let (child, address) = send("7-amber-falcon")?;
+    Command::cargo_bin("doby").unwrap().current_dir(&received).arg("receive").arg(&address).arg("--code").arg("7-amber-falcon").assert().failure().stdout("")
+        .stderr("Error: the file was sent as ./plaintext, which already exists: use --force to replace it, or give OUTPUT\n");
+    assert!(!child.wait_with_output()?.status.success());
+    assert_eq!(fs::read(received.join("plaintext"))?, b"existing file");
+    assert_eq!(fs::read_dir(&received)?.count(), 1);
+    let (child, address) = send("7-amber-falcon")?;
+    Command::cargo_bin("doby").unwrap().current_dir(&received).arg("receive").arg("--force").arg(&address).arg("--code").arg("7-amber-falcon").assert().success().stdout("").stderr("Received ./plaintext\n");
+    assert!(child.wait_with_output()?.status.success());
+    assert_eq!(fs::read(received.join("plaintext"))?, PLAINTEXT);
+
+    let (child, address) = send("7-amber-falcon")?;
+    let error = "Error: the key exchange failed: the code is wrong, or someone tried to guess it (start again with a new code)\n";
+    Command::cargo_bin("doby").unwrap().arg("receive").arg(&address).arg("--code").arg("7-amber-eagle").arg("-").assert().failure().stdout("").stderr(error);
+    let output = child.wait_with_output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with(error));
+    //the sender gave up
+    Command::cargo_bin("doby").unwrap().arg("receive").arg(&address).arg("--code").arg("7-amber-falcon").arg("-").assert().failure();
+
+    Ok(())
+}
+
+#[test]
+fn recursive() -> io::Result<()> {
+    let (tmp_path, _, tmp_ciphertext) = setup_files()?;
+    let tmp_dir = tmp_path.join("dir");
+    fs::create_dir_all(tmp_dir.join("sub"))?;
+    File::create(tmp_dir.join("sub").join("file"))?.write_all(PLAINTEXT)?;
+
+    doby_cmd().unwrap().arg("-r").arg(&tmp_dir).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg("-r").arg(&tmp_ciphertext).assert().failure().stderr("Error: an OUTPUT directory is required to decrypt recursively\n");
+
+    let tmp_decrypted = tmp_path.join("decrypted");
+    doby_cmd().unwrap().arg("-r").arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(tmp_decrypted.join("sub").join("file"))?, PLAINTEXT);
+
+    //the HMAC of stream ciphers is only checked after the files are written
+    doby_cmd().unwrap().arg("-r").arg("-f").arg("--cipher").arg("xchacha20").arg(&tmp_dir).arg(&tmp_ciphertext).assert().success();
+    let mut ciphertext = fs::read(&tmp_ciphertext)?;
+    *ciphertext.last_mut().unwrap() ^= 1;
+    fs::write(&tmp_ciphertext, ciphertext)?;
+    let tmp_tampered = tmp_path.join("tampered");
+    doby_cmd().unwrap().arg("-r").arg(&tmp_ciphertext).arg(&tmp_tampered).assert().failure();
+    assert!(!tmp_tampered.exists());
+
+    Ok(())
+}
+
+#[test]
+fn armor() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("--armor").arg(tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let armored = fs::read_to_string(&tmp_ciphertext)?;
+    assert!(armored.starts_with("-----BEGIN DOBY ENCRYPTED FILE-----\n"));
+    assert!(armored.ends_with("-----END DOBY ENCRYPTED FILE-----\n"));
+
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    doby_cmd().unwrap().write_stdin(armored).assert().success().stdout(PLAINTEXT).stderr("");
+
+    Ok(())
+}
+
+#[test]
+fn qr() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    let output = doby_cmd().unwrap().arg("--qr").arg(&tmp_plaintext).output()?;
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().ends_with("DOBY-QR 1/1\n\n"));
+    let tmp_png = tmp_path.join("ciphertext.png");
+    doby_cmd().unwrap().arg("--qr=png").arg(&tmp_plaintext).arg(&tmp_png).assert().success().stdout("").stderr("");
+    assert!(fs::read(&tmp_png)?.starts_with(b"\x89PNG\r\n\x1a\n"));
+
+    //what a scanner reads from the codes, as zbarimg prints it
+    doby_cmd().unwrap().arg("--armor").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+    let scanned: String = doby::qr::split(&fs::read(&tmp_ciphertext)?).iter().map(|part| format!("QR-Code:{}", String::from_utf8_lossy(part))).collect();
+    doby_cmd().unwrap().write_stdin(scanned.clone()).assert().success().stdout(PLAINTEXT).stderr("");
+    doby_cmd().unwrap().arg("--force-encrypt").write_stdin(scanned).assert().success();
+
+    doby_cmd().unwrap().arg("--qr").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: --qr only applies to encryption\n");
+    doby_cmd().unwrap().arg("-d").arg("--qr").arg(&tmp_ciphertext).assert().failure().stderr("Error: --qr only applies to encryption\n");
+    doby_cmd().unwrap().arg("--qr=svg").arg(&tmp_plaintext).assert().failure();
+    doby_cmd().unwrap().arg("--qr").arg("--ecc").arg(&tmp_plaintext).assert().failure();
+    doby_cmd().unwrap().write_stdin("DOBY-QR 2/2\nABCD\n").assert().failure().stderr("Error: QR code: missing part 1 of 2\n");
+
+    Ok(())
+}
+
+#[test]
+fn atomic_output() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert!(temporary_files(&tmp_path)?.is_empty());
+
+    let mut ciphertext = fs::read(&tmp_ciphertext)?;
+    let last = ciphertext.len()-1;
+    ciphertext[last] ^= 1;
+    fs::write(&tmp_ciphertext, ciphertext)?;
+
+    //a failed decryption leaves an existing output untouched
+    let tmp_decrypted = tmp_path.join("decrypted");
+    fs::write(&tmp_decrypted, "previous content")?;
+    doby_cmd().unwrap().arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().failure().stdout("");
+    assert_eq!(fs::read(&tmp_decrypted)?, b"previous content");
+    assert!(temporary_files(&tmp_path)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn remove_input() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("--rm").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: --rm and --shred require OUTPUT to be a file\n");
+    doby_cmd().unwrap().arg("--shred").arg("-").arg(&tmp_ciphertext).assert().failure().stderr("Error: --rm and --shred require INPUT to be a regular file\n");
+    assert!(tmp_plaintext.exists());
+
+    doby_cmd().unwrap().arg("--rm").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert!(!tmp_plaintext.exists());
+    doby_cmd().unwrap().arg("--rm").arg(&tmp_ciphertext).arg(&tmp_plaintext).assert().failure().stderr("Error: --rm and --shred only apply to encryption\n");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).arg(&tmp_plaintext).assert().success().stdout("").stderr("");
+
+    let tmp_ciphertext = tmp_path.join("shredded");
+    doby_cmd().unwrap().arg("--shred").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert!(!tmp_plaintext.exists());
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    Ok(())
+}
+
+#[test]
+fn fsync() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("--fsync").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).arg("--fsync").assert().success().stdout(PLAINTEXT).stderr("");
+    let tmp_decrypted = tmp_path.join("decrypted");
+    doby_cmd().unwrap().arg("decrypt").arg("--fsync").arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&tmp_decrypted)?, PLAINTEXT);
+
+    doby_cmd().unwrap().arg("batch").arg("--fsync").arg(&tmp_plaintext).assert().success().stdout("").stderr("");
+    let archive = tmp_path.join("archive.dobybox");
+    doby_cmd().unwrap().current_dir(&tmp_path).arg("pack").arg("--fsync").arg(&archive).arg("plaintext").assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg("extract").arg("--fsync").arg("--output-dir").arg(tmp_path.join("extracted")).arg(&archive).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(tmp_path.join("extracted").join("plaintext"))?, PLAINTEXT);
+
+    Ok(())
+}
+
+#[test]
+fn sandbox() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    let output_dir = tmp_path.join("output");
+    fs::create_dir(&output_dir)?;
+    let tmp_moved = output_dir.join("ciphertext");
+    doby_cmd().unwrap().arg("--rm").arg(&tmp_plaintext).arg(&tmp_moved).assert().success().stdout("").stderr("");
+    assert!(!tmp_plaintext.exists());
+    let tmp_decrypted = output_dir.join("decrypted");
+    doby_cmd().unwrap().arg(&tmp_moved).arg(&tmp_decrypted).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&tmp_decrypted)?, PLAINTEXT);
+
+    let tmp_dir = tmp_path.join("dir");
+    fs::create_dir(&tmp_dir)?;
+    fs::write(tmp_dir.join("file"), PLAINTEXT)?;
+    doby_cmd().unwrap().arg("-r").arg(&tmp_dir).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let tmp_extracted = output_dir.join("extracted");
+    doby_cmd().unwrap().arg("-r").arg(&tmp_ciphertext).arg(&tmp_extracted).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(tmp_extracted.join("file"))?, PLAINTEXT);
+
+    doby_cmd().unwrap().arg("--no-sandbox").arg(&tmp_moved).assert().success().stdout(PLAINTEXT).stderr("");
+
+    Ok(())
+}
+
+#[test]
+fn explicit_mode() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("-d").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: doby format not recognized\n");
+    doby_cmd().unwrap().arg("decrypt").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: doby format not recognized\n");
+
+    Command::cargo_bin("doby").unwrap().arg("encrypt").arg("--password").arg(PASSWORD).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    Command::cargo_bin("doby").unwrap().arg("encrypt").arg("--password").arg(PASSWORD).arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: input is already in doby format (use -f to encrypt it anyway)\n");
+    Command::cargo_bin("doby").unwrap().arg("decrypt").arg("--password").arg(PASSWORD).arg("-f").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: --force-encrypt can't be used when decrypting\n");
+
+    Command::cargo_bin("doby").unwrap().arg("decrypt").arg("--password").arg(PASSWORD).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    doby_cmd().unwrap().arg("-d").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    let double_encrypted = Command::cargo_bin("doby").unwrap().arg("encrypt").arg("-f").arg("--password").arg(PASSWORD).arg(&tmp_ciphertext).assert().success().get_output().stdout.clone();
+    assert!(double_encrypted.starts_with(MAGIC_BYTES));
+
+    Ok(())
+}
+
+#[test]
+fn rekey() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let old_ciphertext = fs::read(&tmp_ciphertext)?;
+
+    Command::cargo_bin("doby").unwrap().arg("rekey").arg("--password").arg("wrong password").arg("--new-password").arg("new password").arg(&tmp_ciphertext).assert().failure().stdout("");
+    assert_eq!(fs::read(&tmp_ciphertext)?, old_ciphertext);
+    assert!(temporary_files(&tmp_path)?.is_empty());
+
+    Command::cargo_bin("doby").unwrap().arg("rekey").arg("--password").arg(PASSWORD).arg("--new-password").arg("new password").arg("-c").arg("xchacha20-poly1305").arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().failure().stdout("");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg("new password").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[test]
+fn inspect() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("-t").arg("3").arg("-m").arg("16").arg("-p").arg("2").arg("-c").arg("aes-gcm").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let ciphertext = fs::read(&tmp_ciphertext)?;
+
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with(&format!("Format version: {}\nFile size: {} bytes\nSalt fingerprint: ", KEY_CHECK_FORMAT_VERSION, ciphertext.len())));
+    assert!(output.ends_with("Argon2 time cost: 3\nArgon2 memory cost: 16KB\nArgon2 parallelism cost: 2\nEncryption cipher: AES-GCM\n"));
+
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg("--json").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with(&format!("{{\"format_version\":{},\"armored\":false,\"file_size\":{},\"salt_fingerprint\":\"", KEY_CHECK_FORMAT_VERSION, ciphertext.len())));
+    assert!(output.ends_with("\"argon2\":{\"time_cost\":3,\"memory_cost\":16,\"parallelism\":2},\"cipher\":\"AES-GCM\",\"authenticated_encryption\":true}\n"));
+
+    Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: doby format not recognized\n");
+
+    Ok(())
+}
+
+#[test]
+fn recipients() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let identities = [tmp_path.join("identity1"), tmp_path.join("identity2"), tmp_path.join("identity3")];
+    let mut public_keys = Vec::new();
+    for identity in &identities {
+        let output = Command::cargo_bin("doby").unwrap().arg("keygen").arg(identity).assert().success().stdout("").get_output().stderr.clone();
+        let output = String::from_utf8(output).unwrap();
+        public_keys.push(output.strip_prefix("Public key: ").unwrap().trim_end().to_string());
+    }
+    Command::cargo_bin("doby").unwrap().arg("keygen").arg(&identities[0]).assert().failure().stdout("");
+
+    Command::cargo_bin("doby").unwrap().arg("-R").arg(&public_keys[0]).arg("--recipient").arg(&public_keys[1]).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    for identity in &identities[..2] {
+        Command::cargo_bin("doby").unwrap().arg("-I").arg(identity).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    }
+    Command::cargo_bin("doby").unwrap().arg("--identity").arg(&identities[2]).arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: no identity matches the recipients of this file\n");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().failure().stdout("");
+
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with(&format!("Format version: {}\n", KEY_SLOTS_FORMAT_VERSION)));
+    assert!(output.contains(&format!("Key slot 0: X25519 recipient (key ID {})\nKey slot 1: X25519 recipient (key ID {})\n", recipient_key_id(&public_keys[0]), recipient_key_id(&public_keys[1]))));
+
+    Command::cargo_bin("doby").unwrap().arg("-R").arg("doby-pk-invalid").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: invalid recipient: doby-pk-invalid\n");
+
+    Ok(())
+}
+
+#[test]
+fn key_slots() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let identity = tmp_path.join("identity");
+    let output = Command::cargo_bin("doby").unwrap().arg("keygen").arg(&identity).assert().success().stdout("").get_output().stderr.clone();
+    let public_key = String::from_utf8(output).unwrap().strip_prefix("Public key: ").unwrap().trim_end().to_string();
+
+    doby_cmd().unwrap().arg("--password").arg("second password").arg("-R").arg(&public_key).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    for password in [PASSWORD, "second password"] {
+        Command::cargo_bin("doby").unwrap().arg("--password").arg(password).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    }
+    Command::cargo_bin("doby").unwrap().arg("-I").arg(&identity).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg("wrong password").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: no key slot can be opened with this password or these identities\n");
+
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains(&format!("Key slot 0: X25519 recipient (key ID {})\nKey slot 1: password (Argon2 time cost: 10, memory cost: 4096KB, parallelism cost: 4)\nKey slot 2: password", recipient_key_id(&public_key))));
+
+    //drop the recipient and the second password
+    Command::cargo_bin("doby").unwrap().arg("rekey").arg("-I").arg(&identity).arg("--new-password").arg("new password").arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg("second password").arg(&tmp_ciphertext).assert().failure().stdout("");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg("new password").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    Ok(())
+}
+
+#[test]
+fn argon2_profile() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("--profile").arg("fast").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains("Argon2 time cost: 2\nArgon2 memory cost: 4096KB\nArgon2 parallelism cost: 4\n"));
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    //explicit costs take precedence over the profile
+    fs::remove_file(&tmp_ciphertext)?;
+    doby_cmd().unwrap().arg("--profile").arg("paranoid").arg("-t").arg("1").arg("-m").arg("64").arg("-p").arg("1").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains("Argon2 time cost: 1\nArgon2 memory cost: 64KB\nArgon2 parallelism cost: 1\n"));
+
+    doby_cmd().unwrap().arg("--profile").arg("unknown").arg(&tmp_plaintext).assert().failure().stdout("");
+
+    Ok(())
+}
+
+#[test]
+fn argon2_limits() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("-t").arg("3").arg("-m").arg("2048").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg("--max-time-cost").arg("2").arg(&tmp_ciphertext).assert().failure().stdout("")
+        .stderr("Error: opening this file requires Argon2 with a time cost of 3 and 2048KiB of memory, above --max-time-cost or --max-memory-cost\n");
+    doby_cmd().unwrap().arg("--max-memory-cost").arg("1024").arg("rekey").arg("--new-password").arg("new").arg(&tmp_ciphertext).assert().failure().stdout("");
+    doby_cmd().unwrap().arg("--max-time-cost").arg("3").arg("--max-memory-cost").arg("2048").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    let tmp_batch = tmp_path.join("batch.doby");
+    fs::copy(&tmp_ciphertext, &tmp_batch)?;
+    doby_cmd().unwrap().arg("batch").arg("-d").arg("--max-memory-cost").arg("1024").arg(&tmp_batch).assert().failure().stdout("");
+    assert!(!tmp_path.join("batch").exists());
+
+    Ok(())
+}
+
+#[test]
+fn raw_key() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let key = [0x42; 32];
+    let key_hex = "42".repeat(32);
+    let key_file = tmp_path.join("key");
+    fs::write(&key_file, key)?;
+
+    Command::cargo_bin("doby").unwrap().arg("--key-hex").arg(&key_hex).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    Command::cargo_bin("doby").unwrap().arg("--key-file-raw").arg(&key_file).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    //detected before decrypting thanks to the key ID
+    Command::cargo_bin("doby").unwrap().arg("--key-hex").arg("43".repeat(32)).arg(&tmp_ciphertext).assert().failure().stdout("")
+        .stderr(format!("Error: wrong key: this file was encrypted with the key of ID {}\n", format_key_id(&key_id(&key))));
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: this file is encrypted with a raw key: --key, --key-hex or --key-file-raw is required\n");
+
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains(&format!("Key derivation: none (raw key, key ID {})\n", format_key_id(&key_id(&key)))));
+
+    Command::cargo_bin("doby").unwrap().arg("--key-hex").arg("42").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: raw keys must be 32 bytes long (64 hexadecimal characters)\n");
+    Command::cargo_bin("doby").unwrap().arg("--key-hex").arg(&key_hex).arg("--password").arg(PASSWORD).arg(&tmp_plaintext).assert().failure().stdout("");
+
+    Ok(())
+}
+
+#[test]
+fn nfc_passwords() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    //"é" precomposed (as typed on Linux) and decomposed (as typed on macOS)
+    let (precomposed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+
+    Command::cargo_bin("doby").unwrap().arg("--password").arg(decomposed).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg(precomposed).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains("\nPasswords: normalized to Unicode NFC\n"));
+
+    //key slots
+    Command::cargo_bin("doby").unwrap().arg("--password").arg(decomposed).arg("--password").arg(PASSWORD).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg(precomposed).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    //the header tells whether to normalize, not --no-nfc
+    let tmp_raw = tmp_path.join("raw");
+    Command::cargo_bin("doby").unwrap().arg("--no-nfc").arg("--password").arg(decomposed).arg(&tmp_plaintext).arg(&tmp_raw).assert().success().stdout("").stderr("");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg(precomposed).arg(&tmp_raw).assert().failure().stdout("").stderr("Error: wrong password\n");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg(decomposed).arg(&tmp_raw).assert().success().stdout(PLAINTEXT).stderr("");
+    Command::cargo_bin("doby").unwrap().arg("--no-nfc").arg("--password").arg(precomposed).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    Ok(())
+}
+
+#[test]
+fn wrong_password() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let tmp_decrypted = tmp_path.join("decrypted");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg("wrong password").arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().failure().stdout("").stderr("Error: wrong password\n");
+    assert!(!tmp_decrypted.exists());
+    //passwords given on the command line aren't retried
+    Command::cargo_bin("doby").unwrap().arg("--retries").arg("5").arg("--password").arg("wrong password").arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().failure().stdout("").stderr("Error: wrong password\n");
+    Command::cargo_bin("doby").unwrap().arg("--retries").arg("many").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: 'many' is not a number\n");
+
+    //corruption is still reported by the HMAC
+    let mut ciphertext = fs::read(&tmp_ciphertext)?;
+    let last = ciphertext.len()-1;
+    ciphertext[last] ^= 1;
+    fs::write(&tmp_ciphertext, ciphertext)?;
+    let output = doby_cmd().unwrap().arg(&tmp_ciphertext).assert().failure().get_output().stderr.clone();
+    assert!(String::from_utf8_lossy(&output).starts_with("Error: HMAC verification failed"));
+
+    Ok(())
+}
+
+#[test]
+fn unverified_output() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let mut ciphertext = fs::read(&tmp_ciphertext)?;
+    let last = ciphertext.len()-1;
+    ciphertext[last] ^= 1;
+    fs::write(&tmp_ciphertext, ciphertext)?;
+
+    let tmp_decrypted = tmp_path.join("decrypted");
+    let tmp_unverified = tmp_path.join("decrypted.unverified");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().failure().stdout("");
+    assert!(!tmp_decrypted.exists());
+    assert!(!tmp_unverified.exists());
+    assert!(temporary_files(&tmp_path)?.is_empty());
+
+    let output = doby_cmd().unwrap().arg("--keep-unverified").arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().failure().stdout("").get_output().stderr.clone();
+    assert!(String::from_utf8_lossy(&output).starts_with(&format!("Unverified output kept in {}\nError: HMAC verification failed", tmp_unverified.display())));
+    assert!(!tmp_decrypted.exists());
+    assert_eq!(fs::read(&tmp_unverified)?, PLAINTEXT);
+
+    //an earlier unverified output isn't replaced
+    fs::write(&tmp_unverified, "earlier")?;
+    let output = doby_cmd().unwrap().arg("--keep-unverified").arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().failure().stdout("").get_output().stderr.clone();
+    let tmp_numbered = tmp_path.join("decrypted.unverified.1");
+    assert!(String::from_utf8_lossy(&output).starts_with(&format!("Unverified output kept in {}\n", tmp_numbered.display())));
+    assert_eq!(fs::read(&tmp_unverified)?, b"earlier");
+    assert_eq!(fs::read(tmp_numbered)?, PLAINTEXT);
+    assert!(temporary_files(&tmp_path)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn blake3_mac() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    for cipher in ["aes", "xchacha20"] {
+        doby_cmd().unwrap().arg("-f").arg("--mac").arg("blake3").arg("-c").arg(cipher).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+        let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().get_output().stdout.clone();
+        assert!(String::from_utf8(output).unwrap().ends_with("Authentication: BLAKE3 HMAC\n"));
+        doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    }
+
+    doby_cmd().unwrap().arg("--mac").arg("blake3").arg("-c").arg("aes-gcm").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: --mac can't be used with AEAD ciphers\n");
+
+    Ok(())
+}
+
+#[test]
+fn password_sources() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    Command::cargo_bin("doby").unwrap().env("DOBY_TEST_PASSWORD", PASSWORD).arg("--password-env").arg("DOBY_TEST_PASSWORD").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+
+    let password_file = tmp_path.join("password");
+    fs::write(&password_file, format!("{}\nignored\n", PASSWORD))?;
+    Command::cargo_bin("doby").unwrap().arg("--password-file").arg(&password_file).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT);
+
+    Command::cargo_bin("doby").unwrap().env_remove("DOBY_TEST_PASSWORD").arg("--password-env").arg("DOBY_TEST_PASSWORD").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: environment variable DOBY_TEST_PASSWORD is not set\n");
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn password_fd() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    let shell_cmd = format!("{} --password-fd 3 {} {} 3<<< \"{}\"", cargo_bin("doby").to_str().unwrap(), tmp_plaintext.to_str().unwrap(), tmp_ciphertext.to_str().unwrap(), PASSWORD);
+    bash_cmd().arg(shell_cmd).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT);
+
+    Command::cargo_bin("doby").unwrap().arg("--password-fd").arg("42").arg(&tmp_ciphertext).assert().failure().stdout("");
+
+    Ok(())
+}
+
+#[test]
+fn password_command() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    Command::cargo_bin("doby").unwrap().arg("--password-command").arg(format!("echo '{}'", PASSWORD)).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT);
+
+    Command::cargo_bin("doby").unwrap().arg("--password-command").arg("exit 1").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: password command failed: exit 1\n");
+
+    Ok(())
+}
+
+#[test]
+fn batch() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, _) = setup_files()?;
+    let tmp_plaintext2 = tmp_path.join("plaintext2");
+    fs::write(&tmp_plaintext2, PLAINTEXT)?;
+
+    doby_cmd().unwrap().arg("batch").arg(&tmp_plaintext).arg(&tmp_plaintext2).assert().success().stdout("").stderr("");
+
+    let ciphertext = fs::read(tmp_path.join("plaintext.doby"))?;
+    let ciphertext2 = fs::read(tmp_path.join("plaintext2.doby"))?;
+    assert_eq!(ciphertext[4], KEY_SLOTS_FORMAT_VERSION);
+    //each output has its own salt
+    assert_ne!(ciphertext[5..5+SALT_LEN], ciphertext2[5..5+SALT_LEN]);
+    //but they share the key slot: cipher, slot count, slot type, slot salt, Argon2 parameters and wrapped key
+    let header_len = 5+SALT_LEN+2+1+SALT_LEN+12+48;
+    assert_eq!(ciphertext[5+SALT_LEN..header_len], ciphertext2[5+SALT_LEN..header_len]);
+    for path in ["plaintext.doby", "plaintext2.doby"] {
+        doby_cmd().unwrap().arg(tmp_path.join(path)).assert().success().stdout(PLAINTEXT);
+    }
+
+    //NUL separated list, with an input already encrypted
+    let list = format!("{}\0{}\0", tmp_plaintext.to_str().unwrap(), tmp_path.join("plaintext2.doby").to_str().unwrap());
+    doby_cmd().unwrap().arg("--rm").arg("batch").arg("--files-from").arg("-").write_stdin(list).assert().failure().stdout("").stderr(format!(
+        "Error: {}.doby: input is already in doby format (use -f to encrypt it anyway)\nError: 1 out of 2 files couldn't be encrypted\n",
+        tmp_plaintext2.to_str().unwrap()
+    ));
+    assert!(!tmp_plaintext.exists());
+    doby_cmd().unwrap().arg(tmp_path.join("plaintext.doby")).assert().success().stdout(PLAINTEXT);
+
+    Ok(())
+}
+
+#[test]
+fn batch_output_names() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, _) = setup_files()?;
+    let tmp_plaintext2 = tmp_path.join("plaintext2.txt");
+    fs::write(&tmp_plaintext2, PLAINTEXT)?;
+    let encrypted_dir = tmp_path.join("encrypted");
+    let decrypted_dir = tmp_path.join("decrypted");
+
+    doby_cmd().unwrap().arg("batch").arg("--output-dir").arg(&encrypted_dir).arg("--name-template").arg("{name}.{ext}.enc").arg(&tmp_plaintext).arg(&tmp_plaintext2).assert().success().stdout("").stderr("");
+    assert!(encrypted_dir.join("plaintext.enc").exists());
+    assert!(encrypted_dir.join("plaintext2.txt.enc").exists());
+
+    doby_cmd().unwrap().arg("batch").arg("-d").arg("--suffix").arg(".enc").arg("--output-dir").arg(&decrypted_dir).arg(encrypted_dir.join("plaintext.enc")).arg(encrypted_dir.join("plaintext2.txt.enc")).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(decrypted_dir.join("plaintext"))?, PLAINTEXT);
+    assert_eq!(fs::read(decrypted_dir.join("plaintext2.txt"))?, PLAINTEXT);
+
+    //the suffix doesn't match
+    doby_cmd().unwrap().arg("batch").arg("-d").arg("--output-dir").arg(&decrypted_dir).arg(encrypted_dir.join("plaintext.enc")).assert().failure().stdout("").stderr(format!(
+        "Error: {}: plaintext.enc doesn't match the name template {{file}}.doby\nError: 1 out of 1 files couldn't be decrypted\n",
+        encrypted_dir.join("plaintext.enc").to_str().unwrap()
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn batch_jobs() -> io::Result<()> {
+    let (tmp_path, _, _) = setup_files()?;
+    let inputs: Vec<PathBuf> = (0..6).map(|i| tmp_path.join(format!("input{}", i))).collect();
+    for input in &inputs[1..] {
+        fs::write(input, PLAINTEXT)?;
+    }
+
+    //failures are reported in the same order as the inputs
+    doby_cmd().unwrap().arg("batch").arg("-j").arg("3").args(&inputs).assert().failure().stdout("").stderr(format!(
+        "Error: {}: I/O error: No such file or directory (os error 2)\nError: 1 out of 6 files couldn't be encrypted\n",
+        inputs[0].to_str().unwrap()
+    ));
+    let encrypted: Vec<PathBuf> = inputs[1..].iter().map(|input| tmp_path.join(format!("{}.doby", input.file_name().unwrap().to_str().unwrap()))).collect();
+    doby_cmd().unwrap().arg("batch").arg("-d").arg("-j").arg("3").arg("--output-dir").arg(tmp_path.join("decrypted")).args(&encrypted).assert().success().stdout("").stderr("");
+    for input in &inputs[1..] {
+        assert_eq!(fs::read(tmp_path.join("decrypted").join(input.file_name().unwrap()))?, PLAINTEXT);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn same_file() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, _) = setup_files()?;
+
+    //in place
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_plaintext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_plaintext).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&tmp_plaintext)?, PLAINTEXT);
+
+    doby_cmd().unwrap().arg("--rm").arg(&tmp_plaintext).arg(&tmp_plaintext).assert().failure().stdout("").stderr(format!(
+        "Error: {} is both INPUT and OUTPUT: --rm and --shred would delete the output\n",
+        tmp_plaintext.to_str().unwrap()
+    ));
+    assert_eq!(fs::read(&tmp_plaintext)?, PLAINTEXT);
+
+    //a file at the former temporary path is neither read as the output nor destroyed
+    let tmp_input = tmp_path.join("output.tmp");
+    fs::rename(&tmp_plaintext, &tmp_input)?;
+    doby_cmd().unwrap().arg(&tmp_input).arg(tmp_path.join("output")).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&tmp_input)?, PLAINTEXT);
+    #[cfg(unix)]
+    {
+        let precious = tmp_path.join("precious");
+        fs::write(&precious, PLAINTEXT)?;
+        std::os::unix::fs::symlink(&precious, tmp_path.join("link.tmp"))?;
+        doby_cmd().unwrap().arg(&tmp_input).arg(tmp_path.join("link")).assert().success().stdout("").stderr("");
+        assert_eq!(fs::read(&precious)?, PLAINTEXT);
+        assert!(fs::symlink_metadata(tmp_path.join("link.tmp"))?.file_type().is_symlink());
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn preserve() -> io::Result<()> {
+    use std::{os::unix::fs::PermissionsExt, time::{Duration, UNIX_EPOCH}};
+
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let modified = UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+    File::options().write(true).open(&tmp_plaintext)?.set_modified(modified)?;
+    fs::set_permissions(&tmp_plaintext, fs::Permissions::from_mode(0o640))?;
+
+    doby_cmd().unwrap().arg("--preserve").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().ends_with("Metadata: stored (encrypted)\n"));
+
+    //the metadata isn't part of the plaintext
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT);
+
+    let tmp_decrypted = tmp_path.join("decrypted");
+    doby_cmd().unwrap().arg("--preserve").arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().success().stdout("").stderr("");
+    let metadata = fs::metadata(&tmp_decrypted)?;
+    assert_eq!(fs::read(&tmp_decrypted)?, PLAINTEXT);
+    assert_eq!(metadata.modified()?, modified);
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+
+    //carried over by rekey
+    doby_cmd().unwrap().arg("rekey").arg("-c").arg("xchacha20-poly1305").arg("--new-password").arg(PASSWORD).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    fs::remove_file(&tmp_decrypted)?;
+    doby_cmd().unwrap().arg("--preserve").arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&tmp_decrypted)?, PLAINTEXT);
+    assert_eq!(fs::metadata(&tmp_decrypted)?.modified()?, modified);
+
+    Ok(())
+}
+
+#[test]
+fn store_name() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let restored = tmp_path.join("restored");
+    fs::create_dir(&restored)?;
+
+    //no name stored
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg("--restore-name").arg(&tmp_ciphertext).arg(&restored).assert().failure().stderr("Error: INPUT doesn't contain its original file name (it wasn't encrypted with --store-name)\n");
+
+    doby_cmd().unwrap().arg("--store-name").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT);
+    doby_cmd().unwrap().arg("--restore-name").arg(&tmp_ciphertext).arg(&restored).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(restored.join("plaintext"))?, PLAINTEXT);
+    assert_eq!(fs::read_dir(&restored)?.count(), 1);
+
+    //OUTPUT must be a directory
+    doby_cmd().unwrap().arg("--restore-name").arg(&tmp_ciphertext).arg(restored.join("plaintext")).assert().failure().stderr("Error: --restore-name requires OUTPUT to be a directory\n");
+    doby_cmd().unwrap().arg("--store-name").assert().failure().stderr("Error: --store-name requires INPUT to be a regular file\n");
+
+    //batch
+    doby_cmd().unwrap().arg("batch").arg("--store-name").arg("--suffix").arg(".enc").arg(&tmp_plaintext).assert().success().stdout("").stderr("");
+    fs::remove_file(restored.join("plaintext"))?;
+    doby_cmd().unwrap().arg("--restore-name").arg("decrypt").arg(tmp_path.join("plaintext.enc")).arg(&restored).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(restored.join("plaintext"))?, PLAINTEXT);
+
+    Ok(())
+}
+
+#[test]
+fn comment() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("--comment").arg("2024 \"tax\" archive").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains(" bytes\nComment: 2024 \\\"tax\\\" archive\nSalt fingerprint: "));
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg("--json").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains(",\"comment\":\"2024 \\\"tax\\\" archive\",\"salt_fingerprint\":"));
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT);
+
+    //kept by rekey
+    doby_cmd().unwrap().arg("rekey").arg("--new-password").arg(PASSWORD).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains("\nComment: 2024 \\\"tax\\\" archive\n"));
+
+    //authenticated: magic bytes, header, key check, extensions length, type and length
+    let mut ciphertext = fs::read(&tmp_ciphertext)?;
+    ciphertext[4+EncryptionParams::LEN+KEY_CHECK_LEN+5] = b'3';
+    fs::write(&tmp_ciphertext, ciphertext)?;
+    let output = doby_cmd().unwrap().arg(&tmp_ciphertext).assert().failure().get_output().stderr.clone();
+    assert!(String::from_utf8_lossy(&output).starts_with("Error: HMAC verification failed"));
+
+    doby_cmd().unwrap().arg("--comment").arg("a".repeat(256)).arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: --comment can't be longer than 255 bytes\n");
+
+    Ok(())
+}
+
+#[test]
+fn digest() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("--digest").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains("\nPlaintext digest: "));
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg("--json").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains(",\"plaintext_digest\":\""));
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    Command::cargo_bin("doby").unwrap().arg("inspect").arg("--verify-digest").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success()
+        .stderr(format!("{} matches the digest of the plaintext\n", tmp_plaintext.display()));
+    let other = tmp_path.join("other");
+    fs::write(&other, b"another plaintext")?;
+    Command::cargo_bin("doby").unwrap().arg("inspect").arg("--verify-digest").arg(&other).arg(&tmp_ciphertext).assert().failure()
+        .stderr("Error: the decrypted data doesn't match the digest of the plaintext stored in the header\n");
+
+    //INPUT read from stdin would have to be copied to disk to be read twice
+    doby_cmd().unwrap().arg("--digest").write_stdin(PLAINTEXT).assert().failure().stdout("")
+        .stderr("Error: --digest requires INPUT to be a regular file when encrypting\n");
+
+    //kept by rekey
+    doby_cmd().unwrap().arg("rekey").arg("--new-password").arg(PASSWORD).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    Command::cargo_bin("doby").unwrap().arg("inspect").arg("--verify-digest").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+
+    //the metadata stored with --preserve isn't part of the digest
+    doby_cmd().unwrap().arg("--digest").arg("--preserve").arg("-f").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    Command::cargo_bin("doby").unwrap().arg("inspect").arg("--verify-digest").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+
+    doby_cmd().unwrap().arg("--digest").arg(&tmp_ciphertext).assert().failure().stdout("")
+        .stderr("Error: --comment, --digest, --detach-header and --sign-key only apply to encryption\n");
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+    Command::cargo_bin("doby").unwrap().arg("inspect").arg("--verify-digest").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure()
+        .stderr("Error: INPUT doesn't contain a digest of its plaintext (it wasn't encrypted with --digest)\n");
+
+    Ok(())
+}
+
+#[cfg(feature = "test-salt")]
+#[test]
+fn test_salt() -> io::Result<()> {
+    let (_, tmp_plaintext, _) = setup_files()?;
+    let salt = "ab".repeat(SALT_LEN);
+    let encrypt = || Command::cargo_bin("doby").unwrap().arg("--key-hex").arg("00".repeat(32)).arg("--test-salt-hex").arg(&salt).arg(&tmp_plaintext).assert().success().get_output().stdout.clone();
+    let ciphertext = encrypt();
+    assert_eq!(ciphertext, encrypt());
+    assert!(ciphertext.windows(SALT_LEN).any(|window| window == [0xab; SALT_LEN]));
+
+    doby_cmd().unwrap().arg("--test-salt-hex").arg("ab").arg(&tmp_plaintext).assert().failure().stderr("Error: --test-salt-hex must be 64 bytes in hexadecimal\n");
+    doby_cmd().unwrap().arg("--test-salt-hex").arg(&salt).arg("batch").arg(&tmp_plaintext).assert().failure()
+        .stderr("Error: --test-salt-hex can't be used with batch: the outputs would share the same salt\n");
+
+    Ok(())
+}
+
+#[test]
+fn detached_header() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let tmp_header = tmp_path.join("header.doby");
+
+    doby_cmd().unwrap().arg("--detach-header").arg(&tmp_header).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let ciphertext = fs::read(&tmp_ciphertext)?;
+    assert_eq!(ciphertext.len(), PLAINTEXT.len()+HMAC_LEN);
+    let header = fs::read(&tmp_header)?;
+    assert_eq!(&header[..4], MAGIC_BYTES);
+    assert_eq!(header.len(), 4+EncryptionParams::LEN+KEY_CHECK_LEN);
+    Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_header).assert().success();
+
+    doby_cmd().unwrap().arg("--header").arg(&tmp_header).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    doby_cmd().unwrap().arg("decrypt").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: doby format not recognized\n");
+
+    //armored and from stdin
+    let output = doby_cmd().unwrap().arg("--armor").arg("--detach-header").arg(&tmp_header).write_stdin(PLAINTEXT).assert().success().get_output().stdout.clone();
+    doby_cmd().unwrap().arg("--header").arg(&tmp_header).write_stdin(output).assert().success().stdout(PLAINTEXT).stderr("");
+
+    doby_cmd().unwrap().arg("--detach-header").arg(&tmp_ciphertext).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stderr("Error: the --detach-header file must be different from INPUT and OUTPUT\n");
+    doby_cmd().unwrap().arg("encrypt").arg("--header").arg(&tmp_header).arg(&tmp_plaintext).assert().failure().stderr("Error: --header only applies to decryption\n");
+
+    Ok(())
+}
+
+#[test]
+fn header_backup() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let tmp_backup = tmp_path.join("header.bak");
+
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    Command::cargo_bin("doby").unwrap().arg("header").arg("backup").arg(&tmp_ciphertext).arg(&tmp_backup).assert().success().stdout("").stderr("");
+    let ciphertext = fs::read(&tmp_ciphertext)?;
+    let backup = fs::read(&tmp_backup)?;
+    assert_eq!(backup, ciphertext[..4+EncryptionParams::LEN+KEY_CHECK_LEN]);
+    Command::cargo_bin("doby").unwrap().arg("header").arg("backup").arg(&tmp_ciphertext).assert().success().stdout(backup.clone());
+
+    //damaged salt
+    let mut damaged = ciphertext.clone();
+    damaged[10] ^= 1;
+    fs::write(&tmp_ciphertext, &damaged)?;
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().failure();
+    Command::cargo_bin("doby").unwrap().arg("header").arg("restore").arg(&tmp_backup).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&tmp_ciphertext)?, ciphertext);
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT);
+
+    //unreadable header
+    damaged = ciphertext.clone();
+    damaged[0] = 0;
+    fs::write(&tmp_ciphertext, &damaged)?;
+    Command::cargo_bin("doby").unwrap().arg("header").arg("restore").arg(&tmp_backup).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&tmp_ciphertext)?, ciphertext);
+
+    //the backup of another file
+    let tmp_other = tmp_path.join("other.doby");
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_other).assert().success();
+    Command::cargo_bin("doby").unwrap().arg("header").arg("restore").arg(&tmp_backup).arg(&tmp_other).assert().failure().stdout("").stderr(format!(
+        "Error: the header of {} has a different salt: the backup seems to belong to another file (use --force to restore it anyway)\n",
+        tmp_other.to_str().unwrap()
+    ));
+    Command::cargo_bin("doby").unwrap().arg("header").arg("restore").arg("--force").arg(&tmp_backup).arg(&tmp_other).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&tmp_other)?[..backup.len()], backup);
+
+    Command::cargo_bin("doby").unwrap().arg("header").arg("backup").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: doby format not recognized\n");
+
+    Ok(())
+}
+
+#[test]
+fn ecc() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    //several error correction groups
+    let plaintext: Vec<u8> = (0..200_000u32).map(|i| (i*7 % 251) as u8).collect();
+    fs::write(&tmp_plaintext, &plaintext)?;
+    let shard = |group: usize, shard: usize| 90+group*18*(4096+16)+shard*4096;
+
+    doby_cmd().unwrap().arg("--ecc").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let ciphertext = fs::read(&tmp_ciphertext)?;
+    assert!(ciphertext.starts_with(b"dobyecc"));
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(plaintext.clone());
+    let inspection = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).output()?;
+    assert!(String::from_utf8_lossy(&inspection.stdout).contains("Error correction: 2 parity shards per 16 data shards\n"));
+
+    let mut damaged = ciphertext.clone();
+    damaged[0] = 0; //first copy of the parameters
+    damaged[shard(0, 0)+5] ^= 1;
+    damaged[shard(0, 17)] ^= 1;
+    damaged[shard(1, 3)+100] ^= 0xff;
+    fs::write(&tmp_ciphertext, &damaged)?;
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(plaintext.clone());
+    Command::cargo_bin("doby").unwrap().arg("repair").arg(&tmp_ciphertext).assert().success().stdout("").stderr("Repaired 3 damaged shards\n");
+    assert_eq!(fs::read(&tmp_ciphertext)?, ciphertext);
+    Command::cargo_bin("doby").unwrap().arg("repair").arg(&tmp_ciphertext).assert().success().stdout("").stderr("No damaged shard found\n");
+
+    //more damaged shards than parity shards
+    damaged = ciphertext.clone();
+    for i in 0..3 {
+        damaged[shard(1, i)] ^= 1;
+    }
+    fs::write(&tmp_ciphertext, &damaged)?;
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().failure();
+    Command::cargo_bin("doby").unwrap().arg("repair").arg(&tmp_ciphertext).assert().failure().stdout("");
+    assert_eq!(fs::read(&tmp_ciphertext)?, damaged);
+
+    doby_cmd().unwrap().arg("--ecc").arg("--ecc-parity").arg("3").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(plaintext);
+    doby_cmd().unwrap().arg("--ecc").arg("--ecc-parity").arg("0").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: --ecc-parity must be between 1 and 239\n");
+    Command::cargo_bin("doby").unwrap().arg("repair").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: the file doesn't contain error correction data (see --ecc)\n");
+
+    Ok(())
+}
+
+#[test]
+fn container() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, _) = setup_files()?;
+    let dir = tmp_path.join("dir");
+    fs::create_dir_all(dir.join("sub"))?;
+    let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("sub").join("big"), &big)?;
+    fs::write(dir.join("small"), PLAINTEXT)?;
+    let archive = tmp_path.join("archive.doby");
+
+    doby_cmd().unwrap().current_dir(&tmp_path).arg("pack").arg(&archive).arg("dir").arg("plaintext").assert().success().stdout("").stderr("");
+    assert!(fs::read(&archive)?.starts_with(b"dobybox"));
+    doby_cmd().unwrap().arg("list").arg(&archive).assert().success().stdout("          13 dir/small\n      100000 dir/sub/big\n          13 plaintext\n").stderr("");
+    let inspection = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&archive).output()?;
+    assert!(String::from_utf8_lossy(&inspection.stdout).starts_with(&format!("Format version: {} (container)\n", KEY_SLOTS_FORMAT_VERSION)));
+
+    let output_dir = tmp_path.join("extracted");
+    doby_cmd().unwrap().arg("extract").arg("--output-dir").arg(&output_dir).arg(&archive).arg("dir/sub").assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(output_dir.join("dir/sub/big"))?, big);
+    assert!(!output_dir.join("dir/small").exists());
+    doby_cmd().unwrap().arg("extract").arg("--output-dir").arg(&output_dir).arg(&archive).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(output_dir.join("dir/small"))?, PLAINTEXT);
+    assert_eq!(fs::read(output_dir.join("plaintext"))?, fs::read(&tmp_plaintext)?);
+
+    doby_cmd().unwrap().arg("extract").arg(&archive).arg("missing").assert().failure().stdout("").stderr("Error: missing not found in the container\n");
+    doby_cmd().unwrap().arg("extract").arg(&archive).arg("../plaintext").assert().failure().stdout("");
+    doby_cmd().unwrap().arg(&archive).assert().failure().stdout("").stderr("Error: containers can't be decrypted as a whole: use the list and extract subcommands\n");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg("wrong").arg("list").arg(&archive).assert().failure().stdout("");
+
+    //only the extracted entry is decrypted
+    let mut archive_content = fs::read(&archive)?;
+    archive_content[1000] ^= 1;
+    fs::write(&archive, &archive_content)?;
+    doby_cmd().unwrap().arg("extract").arg("--output-dir").arg(&output_dir).arg(&archive).arg("dir/sub/big").assert().failure().stdout("");
+    fs::remove_file(output_dir.join("plaintext"))?;
+    doby_cmd().unwrap().arg("extract").arg("--output-dir").arg(&output_dir).arg(&archive).arg("plaintext").assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(output_dir.join("plaintext"))?, fs::read(&tmp_plaintext)?);
+
+    Ok(())
+}
+
+#[test]
+fn container_add() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, _) = setup_files()?;
+    let archive = tmp_path.join("archive.doby");
+    let new_file = tmp_path.join("new");
+    fs::write(&new_file, b"new entry")?;
+
+    doby_cmd().unwrap().current_dir(&tmp_path).arg("pack").arg(&archive).arg("plaintext").assert().success().stdout("").stderr("");
+    let packed = fs::read(&archive)?;
+    doby_cmd().unwrap().current_dir(&tmp_path).arg("add").arg(&archive).arg("new").assert().success().stdout("").stderr("");
+    //existing segments aren't rewritten
+    assert!(fs::read(&archive)?.starts_with(&packed));
+    doby_cmd().unwrap().arg("list").arg(&archive).assert().success().stdout("          13 plaintext\n           9 new\n").stderr("");
+    let output_dir = tmp_path.join("extracted");
+    doby_cmd().unwrap().arg("extract").arg("--output-dir").arg(&output_dir).arg(&archive).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(output_dir.join("plaintext"))?, fs::read(&tmp_plaintext)?);
+    assert_eq!(fs::read(output_dir.join("new"))?, b"new entry");
+
+    //failures leave the container as it was
+    let added = fs::read(&archive)?;
+    doby_cmd().unwrap().current_dir(&tmp_path).arg("add").arg(&archive).arg("new").assert().failure().stdout("").stderr("Error: new is already in the container\n");
+    Command::cargo_bin("doby").unwrap().arg("--password").arg("wrong").current_dir(&tmp_path).arg("add").arg(&archive).arg("plaintext").assert().failure().stdout("");
+    assert_eq!(fs::read(&archive)?, added);
+    doby_cmd().unwrap().arg("add").arg(&tmp_plaintext).arg(&new_file).assert().failure().stdout("").stderr("Error: doby format not recognized\n");
+
+    Ok(())
+}
+
+#[test]
+fn tar_format() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let dir = tmp_path.join("dir");
+    fs::create_dir_all(dir.join("sub"))?;
+    fs::write(dir.join("sub").join("file"), PLAINTEXT)?;
+    let doby = cargo_bin("doby");
+    let doby = doby.to_str().unwrap();
+
+    bash_cmd().arg(format!("tar c -C {} . | {} --password \"{}\" --format tar - {}", dir.to_str().unwrap(), doby, PASSWORD, tmp_ciphertext.to_str().unwrap())).assert().success().stdout("").stderr("");
+    let extracted = tmp_path.join("extracted");
+    create_dir(&extracted)?;
+    bash_cmd().arg(format!("{} --password \"{}\" --format tar {} | tar x -C {}", doby, PASSWORD, tmp_ciphertext.to_str().unwrap(), extracted.to_str().unwrap())).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(extracted.join("sub").join("file"))?, PLAINTEXT);
+
+    //e.g. tar c forgotten
+    doby_cmd().unwrap().arg("--format").arg("tar").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: INPUT isn't a tar archive (--format tar)\n");
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+    doby_cmd().unwrap().arg("--format").arg("tar").arg(&tmp_ciphertext).assert().failure().stderr("Error: the decrypted data isn't a tar archive (--format tar)\n");
+    doby_cmd().unwrap().arg("--format").arg("tar").arg("-r").arg(&tmp_ciphertext).assert().failure().stdout("");
+
+    Ok(())
+}
+
+#[test]
+fn signature() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let signing_keys = [tmp_path.join("signing_key1"), tmp_path.join("signing_key2")];
+    let mut verify_keys = Vec::new();
+    for signing_key in &signing_keys {
+        let output = Command::cargo_bin("doby").unwrap().arg("keygen").arg("--sign").arg(signing_key).assert().success().stdout("").get_output().stderr.clone();
+        verify_keys.push(String::from_utf8(output).unwrap().strip_prefix("Verify key: ").unwrap().trim_end().to_string());
+    }
+
+    for args in [&[][..], &["--armor"], &["--threads", "2"]] {
+        doby_cmd().unwrap().args(args).arg("--sign-key").arg(&signing_keys[0]).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+        doby_cmd().unwrap().args(args).arg("--verify-key").arg(&verify_keys[0]).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+        doby_cmd().unwrap().args(args).arg("--verify-key").arg(&verify_keys[0]).arg("--verify-first").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    }
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr(format!("Warning: INPUT is signed by {}, but anyone could have signed it: use --verify-key to check the signer\n", verify_keys[0]));
+    doby_cmd().unwrap().arg("--verify-key").arg(&verify_keys[1]).arg(&tmp_ciphertext).assert().failure().stdout("").stderr(format!("Error: INPUT was signed by another key: {}\n", verify_keys[0]));
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains(&format!("Signed by: {}\n", verify_keys[0])));
+
+    //the HMAC is still valid but not the signature
+    doby_cmd().unwrap().arg("--sign-key").arg(&signing_keys[0]).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+    let mut ciphertext = fs::read(&tmp_ciphertext)?;
+    *ciphertext.last_mut().unwrap() ^= 1;
+    fs::write(&tmp_ciphertext, &ciphertext)?;
+    let tmp_decrypted = tmp_path.join("decrypted");
+    doby_cmd().unwrap().arg("--verify-key").arg(&verify_keys[0]).arg(&tmp_ciphertext).arg(&tmp_decrypted).assert().failure().stdout("").stderr("Error: signature verification failed !\nThe ciphertext has been altered since it was signed.\n");
+    assert!(!tmp_decrypted.exists());
+
+    //rekeying changes the signer
+    doby_cmd().unwrap().arg("--sign-key").arg(&signing_keys[0]).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+    doby_cmd().unwrap().arg("rekey").arg("--verify-key").arg(&verify_keys[0]).arg("--sign-key").arg(&signing_keys[1]).arg("--new-password").arg(PASSWORD).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().arg("--verify-key").arg(&verify_keys[1]).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+    doby_cmd().unwrap().arg("--verify-key").arg(&verify_keys[0]).arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: INPUT isn't signed (--verify-key)\n");
+    doby_cmd().unwrap().arg("--verify-key").arg(&verify_keys[0]).arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: --restore-name and --verify-key only apply to decryption\n");
+    doby_cmd().unwrap().arg("--sign-key").arg(&tmp_plaintext).arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: invalid signing key\n");
+
+    Ok(())
+}
+
+#[test]
+fn pkcs11() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    Command::cargo_bin("doby").unwrap().arg("--pkcs11-module").arg("module.so").arg("--pkcs11-key").arg("0g").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: invalid PKCS#11 key ID: 0g (expected the hexadecimal CKA_ID)\n");
+    Command::cargo_bin("doby").unwrap().arg("--pkcs11-key").arg("01").arg(&tmp_plaintext).assert().failure().stdout("");
+    Command::cargo_bin("doby").unwrap().arg("batch").arg("-d").arg("--pkcs11-module").arg("module.so").arg("--pkcs11-key").arg("01").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: --pkcs11-key can't be used to decrypt with batch: the token would ask for its PIN for each file\n");
+
+    let output = Command::cargo_bin("doby").unwrap().arg("keygen").arg(tmp_path.join("identity")).assert().success().get_output().stderr.clone();
+    let public_key = String::from_utf8(output).unwrap().strip_prefix("Public key: ").unwrap().trim_end().to_string();
+    Command::cargo_bin("doby").unwrap().arg("-R").arg(&public_key).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+    Command::cargo_bin("doby").unwrap().arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: this file is encrypted to recipients: an --identity or --pkcs11-key is required\n");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let pkcs11_tool = tmp_path.join("pkcs11-tool");
+        fs::write(&pkcs11_tool, "#!/bin/sh\nexit 1\n")?;
+        fs::set_permissions(&pkcs11_tool, fs::Permissions::from_mode(0o755))?;
+        Command::cargo_bin("doby").unwrap().env("PATH", &tmp_path).arg("--pkcs11-module").arg("module.so").arg("--pkcs11-key").arg("01").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: PKCS#11 operation failed: pkcs11-tool exit status: 1\n");
+    }
+
+    Ok(())
+}
+
+/// Minimal ssh-agent holding one Ed25519 key, answering the requests of one connection at a time.
+#[cfg(unix)]
+fn fake_ssh_agent(socket: &std::path::Path) -> io::Result<String> {
+    use std::os::unix::net::UnixListener;
+    use ed25519_dalek::{Signer, SigningKey};
+    fn string(bytes: &[u8]) -> Vec<u8> {
+        [&(bytes.len() as u32).to_be_bytes()[..], bytes].concat()
+    }
+    let signing_key = SigningKey::from_bytes(&[1; 32]);
+    let public_key = [string(b"ssh-ed25519"), string(signing_key.verifying_key().as_bytes())].concat();
+    let listener = UnixListener::bind(socket)?;
+    let agent_public_key = public_key.clone();
+    std::thread::spawn(move || for mut stream in listener.incoming().flatten() {
+        let mut len = [0; 4];
+        while stream.read_exact(&mut len).is_ok() {
+            let mut request = vec![0; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut request).unwrap();
+            let response = match request[0] {
+                11 => [&[12, 0, 0, 0, 1][..], &string(&agent_public_key), &string(b"comment")].concat(),
+                13 => {
+                    let data_start = 1+4+agent_public_key.len();
+                    let data = &request[data_start+4..request.len()-4];
+                    [&[14][..], &string(&[string(b"ssh-ed25519"), string(&signing_key.sign(data).to_bytes())].concat())].concat()
+                }
+                _ => vec![5],
+            };
+            stream.write_all(&string(&response)).unwrap();
+        }
+    });
+    Ok(format!("ssh-ed25519 {} comment\n", base64::encode(&public_key)))
+}
+
+#[cfg(unix)]
+#[test]
+fn ssh_agent() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let socket = tmp_path.join("agent.sock");
+    let public_key_file = tmp_path.join("id_ed25519.pub");
+    fs::write(&public_key_file, fake_ssh_agent(&socket)?)?;
+
+    Command::cargo_bin("doby").unwrap().env("SSH_AUTH_SOCK", &socket).arg("--ssh-key").arg(&public_key_file).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    Command::cargo_bin("doby").unwrap().env("SSH_AUTH_SOCK", &socket).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    Command::cargo_bin("doby").unwrap().env_remove("SSH_AUTH_SOCK").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: ssh-agent: SSH_AUTH_SOCK is not set\n");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains("Key slot 0: SSH key SHA256:"));
+
+    //the password still works without the agent
+    doby_cmd().unwrap().env("SSH_AUTH_SOCK", &socket).arg("--ssh-key").arg(&public_key_file).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().env_remove("SSH_AUTH_SOCK").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    Command::cargo_bin("doby").unwrap().env("SSH_AUTH_SOCK", &socket).arg("--ssh-key").arg(&tmp_plaintext).arg(&tmp_plaintext).assert().failure().stdout("").stderr(format!("Error: invalid SSH public key: {}\n", tmp_plaintext.display()));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn kms() -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let (tmp_path, _, tmp_ciphertext) = setup_files()?;
+    let mut params = EncryptionParams::with_raw_key(CipherAlgorithm::XChaCha20Poly1305);
+    params.set_kms_blob(b"encrypted data key".to_vec()).unwrap();
+    let mut ciphertext = Vec::new();
+    doby::encrypt(&mut &PLAINTEXT[..], &mut ciphertext, &params, DobyCipher::with_master_key(&[1; 32], &params).unwrap(), 65536, None).unwrap();
+    fs::write(&tmp_ciphertext, ciphertext)?;
+
+    //the AWS CLI answers with the base64 encoded data key
+    let aws = tmp_path.join("aws");
+    fs::write(&aws, "#!/bin/sh\necho AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=\n")?;
+    fs::set_permissions(&aws, fs::Permissions::from_mode(0o755))?;
+    let assert = Command::cargo_bin("doby").unwrap().env("PATH", &tmp_path).arg(&tmp_ciphertext).assert();
+    if cfg!(feature = "kms") {
+        assert.success().stdout(PLAINTEXT).stderr("");
+    } else {
+        assert.failure().stdout("").stderr("Error: the master key of this file is encrypted by a KMS, which this build of doby doesn't support (enable the kms feature)\n");
+    }
+    Command::cargo_bin("doby").unwrap().arg("--key-hex").arg("01".repeat(32)).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains("KMS: master key encrypted by a KMS key\n"));
+
+    Ok(())
+}
+
+#[test]
+fn openssl() -> io::Result<()> {
+    let (tmp_path, _, tmp_ciphertext) = setup_files()?;
+    //printf 'the plaintext' | openssl enc -aes-256-cbc -pbkdf2 -pass 'pass:the password'
+    fs::write(&tmp_ciphertext, b"Salted__\xc3\x55\x0e\x7d\x8a\x97\xda\xa8\x30\xd0\x2d\x27\x4f\xf1\xe6\x20\x76\xf6\x36\x4b\xf4\x16\x0a\x20")?;
+    let warning = "Warning: INPUT was encrypted with openssl enc, which doesn't authenticate the ciphertext: alterations can't be detected\n";
+
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr(warning);
+    doby_cmd().unwrap().arg("decrypt").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr(warning);
+    let bad_decrypt = format!("{}Error: OpenSSL decryption failed: wrong password, wrong iteration count (--openssl-iter) or corrupted file\n", warning);
+    Command::cargo_bin("doby").unwrap().arg("--password").arg("wrong password").arg(&tmp_ciphertext).assert().failure().stdout("").stderr(bad_decrypt.clone());
+    doby_cmd().unwrap().arg("--openssl-iter").arg("1000").arg(&tmp_ciphertext).assert().failure().stdout("").stderr(bad_decrypt);
+    doby_cmd().unwrap().arg("--openssl-iter").arg("0").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: --openssl-iter must be at least 1\n");
+    //never written: encrypting treats it as any other data
+    let output = doby_cmd().unwrap().arg("encrypt").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(output.starts_with(MAGIC_BYTES));
+
+    //rekey converts it to the doby format
+    Command::cargo_bin("doby").unwrap().arg("rekey").arg("--password").arg(PASSWORD).arg("--new-password").arg("new password").arg(&tmp_ciphertext).assert().success().stdout("").stderr(warning);
+    assert!(fs::read(&tmp_ciphertext)?.starts_with(MAGIC_BYTES));
+    assert!(temporary_files(&tmp_path)?.is_empty());
+    Command::cargo_bin("doby").unwrap().arg("--password").arg("new password").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn plugins() -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    //stores the file key as is, and only gives it back to the "right" identity
+    let plugin = tmp_path.join("doby-plugin-test");
+    fs::write(&plugin, "#!/bin/sh
+request=$(cat)
+echo \"$request\" | grep -qx 'version 1' || exit 1
+case \"$1\" in
+wrap) echo \"$request\" | sed -n 's/^file-key /slot /p' ;;
+unwrap) echo \"$request\" | grep -qx 'identity right' && echo \"$request\" | sed -n 's/^slot /file-key /p' ;;
+esac
+exit 0
+")?;
+    fs::set_permissions(&plugin, fs::Permissions::from_mode(0o755))?;
+    let path = format!("{}:{}", tmp_path.display(), std::env::var("PATH").unwrap());
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).arg("plugins").assert().success().stdout(format!("test\t{}\n", plugin.display())).stderr("");
+
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).arg("-R").arg("doby-plugin-pk-test:recipient").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains("Key slot 0: plugin test\n"));
+    fs::write(tmp_path.join("identity"), "doby-plugin-sk-test:right\n")?;
+    fs::write(tmp_path.join("wrong_identity"), "doby-plugin-sk-test:wrong\n")?;
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).arg("-I").arg(tmp_path.join("identity")).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).arg("-I").arg(tmp_path.join("wrong_identity")).arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: no identity matches the recipients of this file\n");
+    Command::cargo_bin("doby").unwrap().env("PATH", "").arg("-I").arg(tmp_path.join("identity")).arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: plugin test: doby-plugin-test not found in PATH\n");
+
+    Command::cargo_bin("doby").unwrap().arg("-R").arg("doby-plugin-pk-Test:recipient").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: invalid recipient: doby-plugin-pk-Test:recipient\n");
+    fs::write(&plugin, "#!/bin/sh\ncat > /dev/null\necho 'error no such key'\n")?;
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).arg("-R").arg("doby-plugin-pk-test:recipient").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: plugin test: no such key\n");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn tpm() -> io::Result<()> {
+    use std::os::unix::fs::{PermissionsExt, symlink};
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    //fake tpm2-tools: sealed objects only unseal while PCR_STATE is unchanged
+    let tools = tmp_path.join("tpm2-tools");
+    create_dir(&tools)?;
+    let script = tools.join("tpm2");
+    fs::write(&script, "#!/bin/sh
+tool=$(basename \"$0\")
+while [ $# -gt 0 ]; do
+    case \"$1\" in
+    -[CcgGLlaipur]) eval \"opt_${1#-}=\\\"\\$2\\\"\"; shift 2 ;;
+    *) shift ;;
+    esac
+done
+case $tool in
+tpm2_createprimary) echo primary > \"$opt_c\" ;;
+tpm2_createpolicy) echo \"$PCR_STATE $opt_l\" > \"$opt_L\" ;;
+tpm2_create) cp \"$opt_L\" \"$opt_u\" && cat > \"$opt_r\" ;;
+tpm2_load) cp \"$opt_u\" \"$opt_c\" && cp \"$opt_r\" \"$opt_c.key\" ;;
+tpm2_unseal)
+    [ \"$(cat \"$opt_c\")\" = \"$PCR_STATE ${opt_p#pcr:}\" ] || { echo 'ERROR: policy check failed' >&2; exit 1; }
+    cat \"$opt_c.key\" ;;
+esac
+")?;
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+    for tool in ["tpm2_createprimary", "tpm2_createpolicy", "tpm2_create", "tpm2_load", "tpm2_unseal"] {
+        symlink(&script, tools.join(tool))?;
+    }
+    let path = format!("{}:{}", tools.display(), std::env::var("PATH").unwrap());
+
+    doby_cmd().unwrap().env("PATH", &path).env("PCR_STATE", "boot").arg("--tpm").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains("Key slot 0: TPM (PCRs 0,7)\nKey slot 1: password"));
+    //no password needed in the same boot configuration
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).env("PCR_STATE", "boot").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    doby_cmd().unwrap().env("PATH", &path).env("PCR_STATE", "other boot").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    doby_cmd().unwrap().env("PATH", "").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    let output = Command::cargo_bin("doby").unwrap().arg("keygen").arg(tmp_path.join("identity")).assert().success().stdout("").get_output().stderr.clone();
+    let public_key = String::from_utf8(output).unwrap().strip_prefix("Public key: ").unwrap().trim_end().to_string();
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).env("PCR_STATE", "boot").arg("--tpm=7,23,7").arg("-R").arg(&public_key).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    let output = Command::cargo_bin("doby").unwrap().arg("inspect").arg("--json").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains(&format!("\"key_slots\":[{{\"type\":\"x25519\",\"key_id\":\"{}\"}},{{\"type\":\"tpm\",\"pcrs\":[7,23]}}]", recipient_key_id(&public_key))));
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).env("PCR_STATE", "boot").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).env("PCR_STATE", "other boot").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: TPM operation failed: tpm2_unseal: ERROR: policy check failed\n");
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).env("PCR_STATE", "other boot").arg("-I").arg(tmp_path.join("identity")).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    doby_cmd().unwrap().env("PATH", "").arg("--tpm").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: TPM operation failed: can't run tpm2_createprimary: No such file or directory (os error 2)\n");
+    doby_cmd().unwrap().arg("--tpm=24").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: invalid TPM PCR list: 24 (expected indices from 0 to 23 separated by commas)\n");
+    doby_cmd().unwrap().arg("--tpm=").arg(&tmp_plaintext).assert().failure().stdout("");
+    doby_cmd().unwrap().arg("decrypt").arg("--tpm").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: --tpm only applies to encryption: TPM key slots are opened automatically\n");
+
+    Ok(())
+}
+
+#[test]
+fn config() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let config = tmp_path.join("config.toml");
+    fs::write(&config, "# defaults\ncipher = \"xchacha20-poly1305\"\nprofile = \"fast\"\ntime_cost = 3 # overrides the profile\nblock_size = 4_096\n")?;
+    let argon2 = |path: &PathBuf| -> io::Result<(CipherAlgorithm, u32, u32)> {
+        let params = doby::read_header(&mut File::open(path)?).unwrap();
+        match params.key_derivation {
+            KeyDerivation::Password(argon2) => Ok((params.cipher, argon2.t_cost(), argon2.m_cost())),
+            _ => panic!("not a password file"),
+        }
+    };
+
+    doby_cmd().unwrap().env("DOBY_CONFIG", &config).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(argon2(&tmp_ciphertext)?, (CipherAlgorithm::XChaCha20Poly1305, 3, 4096));
+    doby_cmd().unwrap().env("DOBY_CONFIG", &config).arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    //command line options take precedence, and a profile replaces the costs of the config
+    doby_cmd().unwrap().env("DOBY_CONFIG", &config).arg("-c").arg("aes").arg("-m").arg("8192").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(argon2(&tmp_ciphertext)?, (CipherAlgorithm::AesCtr, 3, 8192));
+    doby_cmd().unwrap().env("DOBY_CONFIG", &config).arg("--profile").arg("balanced").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(argon2(&tmp_ciphertext)?, (CipherAlgorithm::XChaCha20Poly1305, 10, 4096));
+
+    //interactive: declining leaves the existing output untouched
+    fs::write(&config, "interactive = true\n")?;
+    let before = fs::read(&tmp_ciphertext)?;
+    doby_cmd().unwrap().env("DOBY_CONFIG", &config).arg(&tmp_plaintext).arg(&tmp_ciphertext).write_stdin("n\n").assert().success().stdout("")
+        .stderr(format!("Warning: {} already exists. Overwrite [y/N]? ", tmp_ciphertext.display()));
+    assert_eq!(fs::read(&tmp_ciphertext)?, before);
+
+    fs::write(&config, "cipher = \"aes\"\nblock_size = \"big\"\n")?;
+    doby_cmd().unwrap().env("DOBY_CONFIG", &config).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stdout("")
+        .stderr(format!("Error: {}:2: block_size: expected an integer\n", config.display()));
+    fs::write(&config, "chiper = \"aes\"\n")?;
+    doby_cmd().unwrap().env("DOBY_CONFIG", &config).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stdout("")
+        .stderr(format!("Error: {}:1: unknown option \"chiper\"\n", config.display()));
+    let missing = tmp_path.join("missing.toml");
+    doby_cmd().unwrap().env("DOBY_CONFIG", &missing).arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stdout("")
+        .stderr(format!("Error: {}: No such file or directory (os error 2)\n", missing.display()));
+
+    Ok(())
+}
+
+#[test]
+fn env_defaults() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let config = tmp_path.join("config.toml");
+    fs::write(&config, "cipher = \"aes\"\nmac = \"blake3\"\ntime_cost = 3\n")?;
+    let header = |path: &PathBuf| -> io::Result<(CipherAlgorithm, MacAlgorithm, u32, u32)> {
+        let params = doby::read_header(&mut File::open(path)?).unwrap();
+        match params.key_derivation {
+            KeyDerivation::Password(argon2) => Ok((params.cipher, params.mac, argon2.t_cost(), argon2.m_cost())),
+            _ => panic!("not a password file"),
+        }
+    };
+
+    //environment variables override the config file
+    doby_cmd().unwrap().env("DOBY_CONFIG", &config).env("DOBY_ARGON2_TIME_COST", "2").env("DOBY_ARGON2_MEMORY_COST", "8192").env("DOBY_BLOCK_SIZE", "4096")
+        .arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(header(&tmp_ciphertext)?, (CipherAlgorithm::AesCtr, MacAlgorithm::Blake3, 2, 8192));
+    //the default hash function is ignored with AEAD ciphers
+    doby_cmd().unwrap().env("DOBY_CONFIG", &config).env("DOBY_CIPHER", "XChaCha20-Poly1305").env("DOBY_ARGON2_PROFILE", "fast")
+        .arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(header(&tmp_ciphertext)?, (CipherAlgorithm::XChaCha20Poly1305, MacAlgorithm::Blake2b, 3, 4096));
+    //and command line options override them
+    doby_cmd().unwrap().env("DOBY_CIPHER", "xchacha20-poly1305").env("DOBY_ARGON2_TIME_COST", "2").arg("-c").arg("xchacha20").arg("-t").arg("1")
+        .arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(header(&tmp_ciphertext)?.0, CipherAlgorithm::XChaCha20);
+    assert_eq!(header(&tmp_ciphertext)?.2, 1);
+
+    doby_cmd().unwrap().env("DOBY_THREADS", "many").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: DOBY_THREADS: expected an integer\n");
+    doby_cmd().unwrap().env("DOBY_CIPHER", "rot13").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: DOBY_CIPHER: unknown cipher \"rot13\"\n");
+
+    Ok(())
+}
+
+#[test]
+fn json() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    doby_cmd().unwrap().arg("--json").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr(format!(
+        "{{\"event\":\"result\",\"operation\":\"encrypt\",\"input\":\"{}\",\"output\":\"{}\",\"bytes\":{},\"status\":\"ok\"}}\n",
+        tmp_plaintext.display(), tmp_ciphertext.display(), fs::metadata(&tmp_ciphertext)?.len()
+    ));
+    //JSON goes to stderr, so the plaintext can still be written to stdout
+    doby_cmd().unwrap().arg(&tmp_ciphertext).arg("--json").assert().success().stdout(PLAINTEXT).stderr(format!(
+        "{{\"event\":\"result\",\"operation\":\"decrypt\",\"input\":\"{}\",\"output\":null,\"bytes\":{},\"verified\":true,\"status\":\"ok\"}}\n",
+        tmp_ciphertext.display(), PLAINTEXT.len()
+    ));
+
+    let mut ciphertext = fs::read(&tmp_ciphertext)?;
+    *ciphertext.last_mut().unwrap() ^= 1;
+    fs::write(&tmp_ciphertext, ciphertext)?;
+    let output = doby_cmd().unwrap().arg("--json").arg("decrypt").arg(&tmp_ciphertext).arg(tmp_path.join("decrypted")).assert().failure().stdout("").get_output().stderr.clone();
+    let lines: Vec<&str> = std::str::from_utf8(&output).unwrap().lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("{\"event\":\"result\",\"operation\":\"decrypt\""));
+    assert!(lines[0].contains("\"verified\":false,\"status\":\"error\",\"kind\":\"hmac_mismatch\""));
+    assert!(lines[1].starts_with("{\"event\":\"error\",\"kind\":\"hmac_mismatch\",\"message\":\"HMAC verification failed !\\u000a"));
+
+    //one result per file in batches, then the error
+    let missing = tmp_path.join("missing");
+    doby_cmd().unwrap().arg("batch").arg("--json").arg(&tmp_plaintext).arg(&missing).assert().failure().stdout("").stderr(format!(
+        concat!(
+            "{{\"event\":\"result\",\"operation\":\"encrypt\",\"input\":\"{}\",\"output\":\"{}.doby\",\"bytes\":{},\"status\":\"ok\"}}\n",
+            "{{\"event\":\"result\",\"operation\":\"encrypt\",\"input\":\"{}\",\"output\":\"{}.doby\",\"status\":\"error\",\"kind\":\"io\",\"message\":\"I/O error: No such file or directory (os error 2)\"}}\n",
+            "{{\"event\":\"error\",\"kind\":\"batch_failed\",\"message\":\"1 out of 2 files couldn't be encrypted\"}}\n",
+        ),
+        tmp_plaintext.display(), tmp_plaintext.display(), fs::metadata(tmp_path.join("plaintext.doby"))?.len(),
+        missing.display(), missing.display()
+    ));
+
+    doby_cmd().unwrap().arg("--json").arg("--progress").arg(&tmp_plaintext).assert().failure().stdout("");
+
+    Ok(())
+}
+
+#[test]
+fn verbosity() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+
+    let output = doby_cmd().unwrap().arg("-v").arg("-t").arg("1").arg("-m").arg("8192").arg("-c").arg("xchacha20").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").get_output().stderr.clone();
+    let stderr = String::from_utf8(output).unwrap();
+    assert!(stderr.contains("] running Argon2id (t=1 m=8192KiB p=4)\n"));
+    assert!(stderr.contains(&format!("] header: {} bytes, format version {}, XChaCha20 with BLAKE2b, password (Argon2id t=1 m=8192KiB p=4)\n", EncryptionParams::LEN + MAGIC_BYTES.len() + KEY_CHECK_LEN, LATEST_FORMAT_VERSION)));
+    assert!(stderr.contains(&format!("] encrypted {} bytes in 1 blocks of up to {} bytes\n", PLAINTEXT.len(), PLAINTEXT.len())));
+    //flags are global, and the default level prints nothing when everything goes well
+    doby_cmd().unwrap().arg(&tmp_ciphertext).arg("--verbose").assert().success().stdout(PLAINTEXT);
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    let identity = tmp_path.join("identity");
+    let output = Command::cargo_bin("doby").unwrap().arg("keygen").arg(&identity).assert().success().stdout("").get_output().stderr.clone();
+    assert!(output.starts_with(b"Public key: "));
+    fs::remove_file(&identity)?;
+    Command::cargo_bin("doby").unwrap().arg("keygen").arg("-q").arg(&identity).assert().success().stdout("").stderr("");
+    //errors are never silenced
+    Command::cargo_bin("doby").unwrap().arg("keygen").arg("-q").arg(&identity).assert().failure().stdout("").stderr(format!("Error: {}: File exists (os error 17)\n", identity.display()));
+    doby_cmd().unwrap().arg("-v").arg("-q").arg(&tmp_ciphertext).assert().failure().stdout("");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupted() -> io::Result<()> {
+    use std::{os::unix::process::ExitStatusExt, process::Stdio, thread, time::Duration};
+    let (tmp_path, _, tmp_ciphertext) = setup_files()?;
+
+    //the input never ends: doby waits for more data with the output file open
+    let mut child = std::process::Command::new(cargo_bin("doby"))
+        .arg("--password").arg(PASSWORD).arg("-").arg(&tmp_ciphertext)
+        .stdin(Stdio::piped()).stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&[42; 100_000])?;
+    for _ in 0..100 {
+        if !temporary_files(&tmp_path)?.is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(temporary_files(&tmp_path)?.len(), 1);
+    Command::new("kill").arg("-TERM").arg(child.id().to_string()).assert().success();
+    let output = child.wait_with_output()?;
+    assert_eq!(output.status.code(), Some(143));
+    assert_eq!(output.status.signal(), None);
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "Error: interrupted by SIGTERM\n");
+    assert!(temporary_files(&tmp_path)?.is_empty());
+    assert!(!tmp_ciphertext.exists());
+    drop(stdin);
+
+    Ok(())
+}
+
+#[test]
+fn selftest() -> io::Result<()> {
+    let (_, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let output = Command::cargo_bin("doby").unwrap().arg("selftest").assert().success().stdout("").get_output().stderr.clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("aes-ctr-blake2b: ok\n"));
+    assert!(output.ends_with("All 7 known-answer tests passed\n"));
+
+    let output = Command::cargo_bin("doby").unwrap().arg("selftest").arg("--export").assert().success().stderr("").get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(output.lines().count(), 7);
+    //the exported ciphertexts can be decrypted like any other file
+    let vector = output.lines().next().unwrap();
+    let field = |name: &str| vector.split(&format!("\"{}\":\"", name)).nth(1).unwrap().split('"').next().unwrap().to_string();
+    let decode = |hex: String| (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i+2], 16).unwrap()).collect::<Vec<u8>>();
+    fs::write(&tmp_ciphertext, decode(field("ciphertext")))?;
+    fs::remove_file(&tmp_plaintext)?;
+    Command::cargo_bin("doby").unwrap().arg("--key-hex").arg(field("key")).arg(&tmp_ciphertext).arg(&tmp_plaintext).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&tmp_plaintext)?, decode(field("plaintext")));
+
+    Ok(())
+}
+
+#[test]
+fn bench() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, _) = setup_files()?;
+    let config = tmp_path.join("config.toml");
+    fs::write(&config, "# mine\nthreads = 2\n")?;
+
+    let output = Command::cargo_bin("doby").unwrap().current_dir(&tmp_path).env("DOBY_CONFIG", &config)
+        .arg("bench").arg("--size").arg("64K").arg("-t").arg("1").arg("-m").arg("8").arg("-p").arg("1").arg("--write-config")
+        .assert().success().get_output().clone();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Argon2id (t=1 m=8KiB p=1): "));
+    assert!(stdout.contains("\nXChaCha20 with BLAKE3: "));
+    assert!(stdout.contains("\nBlock size of 65536 bytes: "));
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), format!("Saved to {}\n", config.display()));
+    //the random data and the encrypted outputs are removed
+    assert_eq!(fs::read_dir(&tmp_path)?.count(), 2);
+    let content = fs::read_to_string(&config)?;
+    assert!(content.starts_with("# mine\nthreads = 2\ncipher = \""));
+    assert!(content.contains("\nblock_size = "));
+    doby_cmd().unwrap().env("DOBY_CONFIG", &config).arg(&tmp_plaintext).assert().success();
+
+    Command::cargo_bin("doby").unwrap().arg("bench").arg("--file").arg(&tmp_plaintext).arg("--size").arg("1M").assert().failure().stdout("");
+    Command::cargo_bin("doby").unwrap().arg("bench").arg("--size").arg("1X").assert().failure().stdout("").stderr("Error: '1X' is not a number\n");
+
+    Ok(())
+}
+
+#[test]
+#[cfg(not(feature = "remote"))]
+fn remote() -> io::Result<()> {
+    let (_, tmp_plaintext, _) = setup_files()?;
+    doby_cmd().unwrap().arg(&tmp_plaintext).arg("https://example.com/ciphertext").assert().failure().stdout("").stderr("Error: http(s):// and s3:// INPUT and OUTPUT aren't supported by this build of doby (enable the remote feature)\n");
+    doby_cmd().unwrap().arg("s3://bucket/ciphertext").assert().failure().stdout("").stderr("Error: http(s):// and s3:// INPUT and OUTPUT aren't supported by this build of doby (enable the remote feature)\n");
+
+    Ok(())
+}
+
+#[test]
+#[cfg(all(feature = "remote", unix))]
+fn remote() -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let (tmp_path, tmp_plaintext, _) = setup_files()?;
+    let tools = tmp_path.join("tools");
+    let store = tmp_path.join("store");
+    create_dir(&tools)?;
+    create_dir(&store)?;
+    //objects are stored under $STORE, named after the end of their URL, once their upload completes
+    let curl = "#!/bin/sh\nfor arg; do url=$arg; done\ncase \" $* \" in\n*\" --upload-file \"*) cat > \"$STORE/.part\" && mv \"$STORE/.part\" \"$STORE/${url##*/}\";;\n*) cat \"$STORE/${url##*/}\";;\nesac\n";
+    let aws = "#!/bin/sh\nif [ \"$4\" = - ]; then cat > \"$STORE/.part\" && mv \"$STORE/.part\" \"$STORE/${5##*/}\"; else cat \"$STORE/${4##*/}\"; fi\n";
+    for (name, script) in [("curl", curl), ("aws", aws)] {
+        fs::write(tools.join(name), script)?;
+        fs::set_permissions(tools.join(name), fs::Permissions::from_mode(0o755))?;
+    }
+    let path = format!("{}:{}", tools.display(), std::env::var("PATH").unwrap());
+
+    doby_cmd().unwrap().env("PATH", &path).env("STORE", &store).arg(&tmp_plaintext).arg("https://example.com/ciphertext").assert().success().stdout("").stderr("");
+    doby_cmd().unwrap().env("PATH", &path).env("STORE", &store).arg("https://example.com/ciphertext").assert().success().stdout(PLAINTEXT).stderr("");
+    doby_cmd().unwrap().env("PATH", &path).env("STORE", &store).arg("https://example.com/ciphertext").arg("s3://bucket/dir/plaintext").assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(store.join("plaintext"))?, PLAINTEXT);
+    doby_cmd().unwrap().env("PATH", &path).env("STORE", &store).arg("-f").arg("s3://bucket/plaintext").assert().success();
+
+    //a failed download isn't mistaken for the end of the file
+    doby_cmd().unwrap().env("PATH", &path).env("STORE", &store).arg("-f").arg("https://example.com/missing").assert().failure().stdout("");
+    //nor is a failure mistaken for the end of the upload
+    Command::cargo_bin("doby").unwrap().env("PATH", &path).env("STORE", &store).arg("--password").arg("wrong").arg("https://example.com/ciphertext").arg("https://example.com/wrong").assert().failure().stdout("");
+    assert!(!store.join("wrong").exists());
+
+    doby_cmd().unwrap().env("PATH", &path).arg("rekey").arg("https://example.com/ciphertext").assert().failure().stdout("").stderr("Error: rekey rewrites INPUT in place, which can't be a URL\n");
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn clipboard() -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let (tmp_path, _, tmp_ciphertext) = setup_files()?;
+    let tools = tmp_path.join("tools");
+    let clip = tmp_path.join("clipboard");
+    create_dir(&tools)?;
+    //the content of the clipboard is only replaced once wl-copy has read all of it
+    let wl_copy = "#!/bin/sh\nif [ \"$1\" = --clear ]; then : > \"$CLIP\"; else cat > \"$CLIP.part\" && mv \"$CLIP.part\" \"$CLIP\"; fi\n";
+    let wl_paste = "#!/bin/sh\ncat \"$CLIP\"\n";
+    for (name, script) in [("wl-copy", wl_copy), ("wl-paste", wl_paste)] {
+        fs::write(tools.join(name), script)?;
+        fs::set_permissions(tools.join(name), fs::Permissions::from_mode(0o755))?;
+    }
+    let path = format!("{}:{}", tools.display(), std::env::var("PATH").unwrap());
+    let clipboard_cmd = |password: &str| {
+        let mut cmd = Command::cargo_bin("doby").unwrap();
+        cmd.env("PATH", &path).env("WAYLAND_DISPLAY", "wayland-0").env("CLIP", &clip).arg("--password").arg(password);
+        cmd
+    };
+    let wait_for = |content: &[u8]| {
+        let start = Instant::now();
+        while fs::read(&clip).unwrap() != content {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    };
+
+    fs::write(&clip, PLAINTEXT)?;
+    clipboard_cmd(PASSWORD).arg("--clipboard-in").arg("-").arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&clip)?, PLAINTEXT);
+    fs::write(&clip, b"something else")?;
+    clipboard_cmd(PASSWORD).arg("--clipboard-out=0").arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&clip)?, PLAINTEXT);
+
+    //cleared after the delay
+    clipboard_cmd(PASSWORD).arg("--clipboard-out=1").arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    assert_eq!(fs::read(&clip)?, PLAINTEXT);
+    wait_for(b"");
+    //unless something else was copied meanwhile
+    clipboard_cmd(PASSWORD).arg("--clipboard-out=1").arg(&tmp_ciphertext).assert().success();
+    fs::write(&clip, b"copied meanwhile")?;
+    std::thread::sleep(Duration::from_secs(2));
+    assert_eq!(fs::read(&clip)?, b"copied meanwhile");
+
+    //a failed decryption leaves the clipboard untouched
+    clipboard_cmd("wrong").arg("--clipboard-out").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: wrong password\n");
+    assert_eq!(fs::read(&clip)?, b"copied meanwhile");
+
+    clipboard_cmd(PASSWORD).arg("--clipboard-in").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: --clipboard-in reads INPUT from the clipboard: INPUT must be omitted or \"-\"\n");
+    clipboard_cmd(PASSWORD).arg("--clipboard-out").arg(&tmp_ciphertext).arg(tmp_path.join("plaintext")).assert().failure().stdout("").stderr("Error: --clipboard-out writes OUTPUT to the clipboard: OUTPUT must be omitted or \"-\"\n");
+    clipboard_cmd(PASSWORD).env_remove("WAYLAND_DISPLAY").env_remove("DISPLAY").arg("--clipboard-out").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: clipboard: no clipboard found (neither WAYLAND_DISPLAY nor DISPLAY is set)\n");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn edit() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let seen = tmp_path.join("seen");
+    let edit_cmd = |editor: &str| {
+        let mut cmd = doby_cmd().unwrap();
+        cmd.env_remove("VISUAL").env("EDITOR", editor).env("SEEN", &seen).arg("edit").arg(&tmp_ciphertext);
+        cmd
+    };
+
+    doby_cmd().unwrap().arg("-t").arg("1").arg("-m").arg("8192").arg("--armor").arg("--comment").arg("notes").arg("--digest").arg("--preserve").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success();
+    let original = fs::read(&tmp_ciphertext)?;
+
+    //the editor gets the plaintext, named after FILE, in a private directory
+    edit_cmd("printf '%s\\n' \"$1\" > \"$SEEN\"; cat \"$1\" >> \"$SEEN\"; true").assert().success().stdout("");
+    let seen_content = fs::read_to_string(&seen)?;
+    let (edited_path, content) = seen_content.split_once('\n').unwrap();
+    assert!(edited_path.ends_with("/ciphertext"));
+    assert_eq!(content.as_bytes(), PLAINTEXT);
+    assert!(!PathBuf::from(edited_path).parent().unwrap().exists());
+    //unchanged: FILE isn't rewritten
+    assert_eq!(fs::read(&tmp_ciphertext)?, original);
+
+    edit_cmd("printf ' edited' >> \"$1\"").assert().success().stdout("");
+    let edited = fs::read(&tmp_ciphertext)?;
+    assert_ne!(edited, original);
+    assert!(edited.starts_with(b"-----BEGIN DOBY"));
+    doby_cmd().unwrap().arg(&tmp_ciphertext).assert().success().stdout("the plaintext edited").stderr("");
+    fs::write(&tmp_plaintext, b"the plaintext edited")?;
+    let inspection = Command::cargo_bin("doby").unwrap().arg("inspect").arg("--verify-digest").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().get_output().stdout.clone();
+    let inspection = String::from_utf8(inspection).unwrap();
+    assert!(inspection.contains("notes"));
+    assert!(inspection.contains("Argon2 time cost: 1\nArgon2 memory cost: 8192KB\n"));
+    //the key is the same, but not the salt
+    assert_ne!(edited[..200], original[..200]);
+
+    //failures leave FILE as it was
+    edit_cmd("printf lost > \"$1\"; exit 1").assert().failure().stdout("").stderr("Error: editor: printf lost > \"$1\"; exit 1 exit status: 1\n");
+    Command::cargo_bin("doby").unwrap().env("EDITOR", "printf lost > \"$1\"").arg("--password").arg("wrong").arg("edit").arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: wrong password\n");
+    assert_eq!(fs::read(&tmp_ciphertext)?, edited);
+    edit_cmd("true").arg("--cipher").arg("aes").assert().failure().stdout("").stderr("Error: edit keeps the key and the options of FILE: only the options needed to open it can be given\n");
+    doby_cmd().unwrap().env("EDITOR", "true").arg("edit").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: doby format not recognized\n");
+
+    Ok(())
+}
+
+#[test]
+fn rotate() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, _) = setup_files()?;
+    let tree = tmp_path.join("tree");
+    fs::create_dir_all(tree.join("sub"))?;
+    fs::write(tmp_path.join("old"), format!("{}\n", PASSWORD))?;
+    fs::write(tmp_path.join("new"), "new password\n")?;
+    let encrypt = |password: &str, args: &[&str], output: &str| {
+        Command::cargo_bin("doby").unwrap().arg("--password").arg(password).arg("-t").arg("1").arg("-m").arg("8192").args(args).arg(&tmp_plaintext).arg(tree.join(output)).assert().success();
+    };
+    encrypt(PASSWORD, &[], "a.doby");
+    encrypt(PASSWORD, &["--password", "other"], "c.doby");
+    //only files ending with .doby are considered
+    encrypt(PASSWORD, &[], "d");
+    encrypt("another password", &[], "e.doby");
+    encrypt(PASSWORD, &["--armor", "--comment", "notes"], "sub/b.doby");
+    let rotate_cmd = || {
+        let mut cmd = Command::cargo_bin("doby").unwrap();
+        cmd.current_dir(&tmp_path).arg("rotate").arg("-t").arg("1").arg("-m").arg("8192").arg("--old-password-file").arg("old");
+        cmd
+    };
+    let read_tree = || -> io::Result<Vec<Vec<u8>>> {
+        ["a.doby", "c.doby", "d", "e.doby", "sub/b.doby"].iter().map(|name| fs::read(tree.join(name))).collect()
+    };
+
+    let original = read_tree()?;
+    rotate_cmd().arg("--dry-run").arg("tree").assert().failure().stdout("").stderr(
+        "Would rotate tree/a.doby\nSkipped tree/c.doby: not encrypted with a single password\nError: tree/e.doby: wrong password\nWould rotate tree/sub/b.doby\n2 to rotate, 1 skipped, 1 failed\nError: 1 out of 4 files couldn't be rotated\n"
+    );
+    assert_eq!(read_tree()?, original);
+    rotate_cmd().arg("--new-password-file").arg("old").arg("tree").assert().failure().stdout("").stderr("Error: the new password must be different from the old one\n");
+    rotate_cmd().arg("--cipher").arg("aes").arg("tree").assert().failure().stdout("").stderr("Error: rotate keeps the options of each file: only the passwords, the Argon2 costs and the signing options can be given\n");
+
+    rotate_cmd().arg("--new-password-file").arg("new").arg("tree").assert().failure().stdout("").stderr(
+        "Rotated tree/a.doby\nSkipped tree/c.doby: not encrypted with a single password\nError: tree/e.doby: wrong password\nRotated tree/sub/b.doby\n2 rotated, 1 skipped, 1 failed\nError: 1 out of 4 files couldn't be rotated\n"
+    );
+    let rotated = read_tree()?;
+    assert_ne!(rotated[0], original[0]);
+    assert_eq!(rotated[1..4], original[1..4]);
+    assert!(rotated[4].starts_with(b"-----BEGIN DOBY"));
+    for name in ["a.doby", "sub/b.doby"] {
+        Command::cargo_bin("doby").unwrap().arg("--password").arg(PASSWORD).arg(tree.join(name)).assert().failure().stdout("");
+        Command::cargo_bin("doby").unwrap().arg("--password").arg("new password").arg(tree.join(name)).assert().success().stdout(PLAINTEXT).stderr("");
+    }
+    let inspection = Command::cargo_bin("doby").unwrap().arg("inspect").arg(tree.join("sub/b.doby")).assert().success().get_output().stdout.clone();
+    assert!(String::from_utf8(inspection).unwrap().contains("notes"));
+
+    //running again after fixing the failure only rotates what is left
+    fs::remove_file(tree.join("e.doby"))?;
+    encrypt(PASSWORD, &[], "e.doby");
+    rotate_cmd().arg("--new-password").arg("new password").arg("tree").assert().success().stdout("").stderr(
+        "Skipped tree/a.doby: already encrypted with the new password\nSkipped tree/c.doby: not encrypted with a single password\nRotated tree/e.doby\nSkipped tree/sub/b.doby: already encrypted with the new password\n1 rotated, 3 skipped, 0 failed\n"
+    );
+    Command::cargo_bin("doby").unwrap().arg("--password").arg("new password").arg(tree.join("e.doby")).assert().success().stdout(PLAINTEXT);
+
+    Ok(())
+}
+
+#[test]
+fn mirror() -> io::Result<()> {
+    let (tmp_path, _, _) = setup_files()?;
+    let tree = tmp_path.join("tree");
+    fs::create_dir_all(tree.join("sub/dir"))?;
+    fs::write(tree.join("a"), "first")?;
+    fs::write(tree.join("sub/dir/b.txt"), "second")?;
+    let mirror_cmd = || {
+        let mut cmd = doby_cmd().unwrap();
+        cmd.current_dir(&tmp_path).arg("mirror").arg("-t").arg("1").arg("-m").arg("8192");
+        cmd
+    };
+    let files = |dir: &str| -> io::Result<Vec<String>> {
+        let mut files = Vec::new();
+        let mut dirs = vec![tmp_path.join(dir)];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    files.push(path.strip_prefix(&tmp_path).unwrap().display().to_string());
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
+    };
+
+    mirror_cmd().arg("tree").arg("plain").assert().success().stdout("").stderr("2 encrypted, 0 unchanged, 0 failed\n");
+    assert_eq!(files("plain")?, ["plain/.doby-mirror", "plain/a.doby", "plain/sub/dir/b.txt.doby"]);
+    //only the files that changed are encrypted again
+    let b = fs::read(tmp_path.join("plain/sub/dir/b.txt.doby"))?;
+    fs::write(tree.join("a"), "changed")?;
+    mirror_cmd().arg("tree").arg("plain").assert().success().stdout("").stderr("1 encrypted, 1 unchanged, 0 failed\n");
+    Command::cargo_bin("doby").unwrap().arg("cat").arg("--password").arg(PASSWORD).arg(tmp_path.join("plain/a.doby")).assert().success().stdout("changed");
+    assert_eq!(fs::read(tmp_path.join("plain/sub/dir/b.txt.doby"))?, b);
+    //a new modification time alone doesn't change the digest
+    let a = fs::read(tmp_path.join("plain/a.doby"))?;
+    File::options().write(true).open(tree.join("a"))?.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000))?;
+    mirror_cmd().arg("tree").arg("plain").assert().success().stdout("").stderr("0 encrypted, 2 unchanged, 0 failed\n");
+    assert_eq!(fs::read(tmp_path.join("plain/a.doby"))?, a);
+    //missing outputs are written again
+    fs::remove_file(tmp_path.join("plain/sub/dir/b.txt.doby"))?;
+    mirror_cmd().arg("tree").arg("plain").assert().success().stdout("").stderr("1 encrypted, 1 unchanged, 0 failed\n");
+    mirror_cmd().arg("--encrypt-names").arg("tree").arg("plain").assert().failure().stdout("").stderr("Error: DST is a mirror without encrypted names: --encrypt-names only applies to new mirrors\n");
+    Command::cargo_bin("doby").unwrap().current_dir(&tmp_path).arg("mirror").arg("--password").arg("wrong").arg("tree").arg("plain").assert().failure().stdout("");
+    mirror_cmd().arg("tree").arg(".").assert().failure().stdout("").stderr("Error: DST isn't empty and isn't a mirror\n");
+
+    mirror_cmd().arg("--encrypt-names").arg("tree").arg("hidden").assert().success().stdout("").stderr("2 encrypted, 0 unchanged, 0 failed\n");
+    let hidden = files("hidden")?;
+    assert_eq!(hidden.len(), 3);
+    assert!(hidden.iter().all(|path| !path.contains("sub") && !path.contains("b.txt")));
+    //encrypted names stay the same from one run to the next
+    for path in &hidden[1..] {
+        fs::remove_file(tmp_path.join(path))?;
+    }
+    mirror_cmd().arg("-j").arg("2").arg("tree").arg("hidden").assert().success().stdout("").stderr("2 encrypted, 0 unchanged, 0 failed\n");
+    assert_eq!(files("hidden")?, hidden);
+
+    for mirror in ["plain", "hidden"] {
+        let restored = format!("{}-restored", mirror);
+        mirror_cmd().arg("-d").arg(mirror).arg(&restored).assert().success().stdout("").stderr("");
+        assert_eq!(files(&restored)?, [format!("{}/a", restored), format!("{}/sub/dir/b.txt", restored)]);
+        assert_eq!(fs::read(tmp_path.join(&restored).join("a"))?, b"changed");
+        assert_eq!(fs::read(tmp_path.join(&restored).join("sub/dir/b.txt"))?, b"second");
+    }
+    mirror_cmd().arg("-d").arg("tree").arg("restored").assert().failure().stdout("").stderr("Error: SRC isn't a mirror: it has no .doby-mirror file\n");
+
+    Ok(())
+}
+
+#[test]
+fn cat_grep() -> io::Result<()> {
+    let (tmp_path, _, _) = setup_files()?;
+    fs::write(tmp_path.join("app.log"), b"started\nerror: disk full\nstopped\n")?;
+    fs::write(tmp_path.join("db.log"), b"Error: timeout\nok")?;
+    let mut binary = vec![0; 10];
+    binary.extend_from_slice(b"\nan error\n");
+    fs::write(tmp_path.join("binary"), binary)?;
+    doby_cmd().unwrap().current_dir(&tmp_path).arg("batch").arg("app.log").arg("db.log").arg("binary").assert().success();
+    let doby_in = |dir: &PathBuf| {
+        let mut cmd = doby_cmd().unwrap();
+        cmd.current_dir(dir);
+        cmd
+    };
+
+    doby_in(&tmp_path).arg("cat").arg("app.log.doby").arg("db.log.doby").assert().success().stdout("started\nerror: disk full\nstopped\nError: timeout\nok").stderr("");
+
+    doby_in(&tmp_path).arg("grep").arg("error").arg("app.log.doby").assert().success().stdout("error: disk full\n").stderr("");
+    doby_in(&tmp_path).arg("grep").arg("-n").arg("--ignore-case").arg("^error").arg("app.log.doby").arg("db.log.doby").assert().success().stdout("app.log.doby:2:error: disk full\ndb.log.doby:1:Error: timeout\n").stderr("");
+    doby_in(&tmp_path).arg("grep").arg("--invert-match").arg("error").arg("app.log.doby").assert().success().stdout("started\nstopped\n");
+    doby_in(&tmp_path).arg("grep").arg("-F").arg("o").arg("app.log.doby").arg("db.log.doby").arg("--count").assert().success().stdout("app.log.doby:2\ndb.log.doby:2\n");
+    doby_in(&tmp_path).arg("grep").arg("-l").arg("timeout").arg("app.log.doby").arg("db.log.doby").assert().success().stdout("db.log.doby\n");
+    doby_in(&tmp_path).arg("grep").arg("error").arg("binary.doby").assert().success().stdout("Binary file binary.doby matches\n");
+    doby_in(&tmp_path).arg("grep").arg("-F").arg("(").arg("app.log.doby").assert().failure().stdout("").stderr("Error: no line matches PATTERN\n");
+    doby_in(&tmp_path).arg("grep").arg("(").arg("app.log.doby").assert().failure().stdout("");
+
+    //the other files are still processed
+    doby_in(&tmp_path).arg("grep").arg("timeout").arg("missing.doby").arg("db.log.doby").assert().failure()
+        .stdout("db.log.doby:Error: timeout\n")
+        .stderr("Error: missing.doby: I/O error: No such file or directory (os error 2)\nError: 1 out of 2 files couldn't be decrypted\n");
+    Command::cargo_bin("doby").unwrap().current_dir(&tmp_path).arg("--password").arg("wrong").arg("cat").arg("app.log.doby").assert().failure().stdout("").stderr("Error: no key slot can be opened with this password or these identities\n");
+    doby_in(&tmp_path).arg("cat").arg("--cipher").arg("aes").arg("app.log.doby").assert().failure().stdout("").stderr("Error: cat and grep only decrypt to stdout: only the options needed to open FILE can be given\n");
+    doby_in(&tmp_path).arg("cat").arg("app.log").assert().failure().stdout("").stderr("Error: doby format not recognized\n");
+
+    Ok(())
+}
+
+#[test]
+fn keys() -> io::Result<()> {
+    let (tmp_path, tmp_plaintext, tmp_ciphertext) = setup_files()?;
+    let key_cmd = || {
+        let mut cmd = Command::cargo_bin("doby").unwrap();
+        cmd.env("XDG_CONFIG_HOME", &tmp_path).env_remove("DOBY_KEY_PASSPHRASE");
+        cmd
+    };
+
+    key_cmd().arg("key").arg("list").assert().success().stdout("").stderr("No stored key\n");
+    let output = key_cmd().arg("key").arg("generate").arg("alice").assert().success().stdout("").get_output().stderr.clone();
+    let public_key = String::from_utf8(output).unwrap().strip_prefix("Public key: ").unwrap().trim_end().to_string();
+    let output = key_cmd().arg("key").arg("generate").arg("--sign").arg("--protect").arg("--password").arg("passphrase").arg("signer").assert().success().stdout("").get_output().stderr.clone();
+    let verify_key = String::from_utf8(output).unwrap().strip_prefix("Verify key: ").unwrap().trim_end().to_string();
+    key_cmd().arg("key").arg("generate").arg("alice").assert().failure().stdout("").stderr("Error: a key named alice already exists\n");
+    key_cmd().arg("key").arg("generate").arg("../alice").assert().failure().stdout("");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(tmp_path.join("doby/keys/alice"))?.permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(tmp_path.join("doby/keys"))?.permissions().mode() & 0o777, 0o700);
+    }
+    //the public key of protected keys is readable without the passphrase
+    key_cmd().arg("key").arg("list").assert().success().stderr("").stdout(format!("alice   identity   {}\nsigner  signing    {} (protected)\n", public_key, verify_key));
+    key_cmd().arg("key").arg("export").arg("alice").assert().success().stdout(format!("{}\n", public_key));
+    key_cmd().arg("key").arg("export").arg("bob").assert().failure().stdout("").stderr("Error: no key named bob (see doby key list)\n");
+    let exported = tmp_path.join("signer");
+    key_cmd().arg("key").arg("export").arg("--secret").arg("signer").arg(&exported).assert().success().stdout("");
+    assert!(fs::read_to_string(&exported)?.starts_with("-----BEGIN DOBY ENCRYPTED FILE-----"));
+
+    //stored keys can be given by name
+    key_cmd().arg("--password").arg(PASSWORD).arg("--recipient").arg("alice").arg("--sign-key").arg("signer").arg(&tmp_plaintext).arg(&tmp_ciphertext)
+        .env("DOBY_KEY_PASSPHRASE", "passphrase").assert().success().stdout("").stderr("");
+    key_cmd().arg("--identity").arg("alice").arg("--verify-key").arg("signer").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    key_cmd().arg("--password").arg(PASSWORD).arg("--sign-key").arg("signer").arg(&tmp_plaintext).arg(tmp_path.join("other"))
+        .env("DOBY_KEY_PASSPHRASE", "wrong").assert().failure().stdout("").stderr("Error: wrong password\n");
+
+    //import the unprotected key under another name, protecting it
+    let identity = tmp_path.join("identity");
+    key_cmd().arg("key").arg("export").arg("--secret").arg("alice").arg(&identity).assert().success();
+    key_cmd().arg("key").arg("import").arg("--protect").arg("--password").arg("passphrase").arg("bob").arg(&identity).assert().success().stdout("").stderr(format!("Imported bob ({})\n", public_key));
+    key_cmd().arg("key").arg("import").arg("carol").arg(&tmp_plaintext).assert().failure().stdout("");
+    key_cmd().arg("--identity").arg("bob").arg("--verify-key").arg(&verify_key).arg(&tmp_ciphertext).env("DOBY_KEY_PASSPHRASE", "passphrase").assert().success().stdout(PLAINTEXT).stderr("");
+    key_cmd().arg("key").arg("list").assert().success().stdout(format!("alice   identity   {0}\nbob     identity   {0} (protected)\nsigner  signing    {1} (protected)\n", public_key, verify_key));
+
+    //the stored identities matching the key IDs of the header are used without --identity, unprotected ones first (no passphrase asked for bob)
+    key_cmd().arg("--verify-key").arg("signer").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    let output = key_cmd().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains(&format!("Key slot 0: X25519 recipient (key ID {}: alice, bob)\n", recipient_key_id(&public_key))));
+
+    //symmetric keys
+    let output = key_cmd().arg("key").arg("add").arg("backup-2024").assert().success().stdout("").get_output().stderr.clone();
+    let old_key_id = String::from_utf8(output).unwrap().strip_prefix("Key ID: ").unwrap().trim_end().to_string();
+    let output = key_cmd().arg("key").arg("list").assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains(&format!("backup-2024  symmetric  key ID {}\n", old_key_id)));
+    key_cmd().arg("key").arg("export").arg("backup-2024").assert().failure().stdout("").stderr("Error: symmetric keys have no public key: use --secret to export the key itself\n");
+    key_cmd().arg("--key").arg("backup-2023").arg(&tmp_plaintext).assert().failure().stdout("").stderr("Error: no key named backup-2023 (see doby key list)\n");
+    key_cmd().arg("--key").arg("backup-2024").arg("-f").arg(&tmp_plaintext).arg(&tmp_ciphertext).assert().success().stdout("").stderr("");
+    key_cmd().arg("--key").arg("backup-2024").arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    let output = key_cmd().arg("inspect").arg(&tmp_ciphertext).assert().success().stderr("").get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains(&format!("Key derivation: none (raw key, key ID {}: backup-2024)\n", old_key_id)));
+    //after a rotation, files encrypted with the previous key are decrypted by the same command, or without --key
+    let output = key_cmd().arg("key").arg("add").arg("--protect").arg("--password").arg("passphrase").arg("backup-2025").assert().success().stdout("").get_output().stderr.clone();
+    let new_key_id = String::from_utf8(output).unwrap().strip_prefix("Key ID: ").unwrap().trim_end().to_string();
+    assert_ne!(old_key_id, new_key_id);
+    key_cmd().arg("--key").arg("backup-2025").arg(&tmp_ciphertext).env("DOBY_KEY_PASSPHRASE", "passphrase").assert().success().stdout(PLAINTEXT).stderr("");
+    key_cmd().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+    let exported = tmp_path.join("backup");
+    key_cmd().arg("key").arg("export").arg("--secret").arg("backup-2024").arg(&exported).assert().success().stdout("");
+    fs::remove_file(tmp_path.join("doby/keys/backup-2024"))?;
+    key_cmd().arg(&tmp_ciphertext).assert().failure().stdout("").stderr("Error: this file is encrypted with a raw key: --key, --key-hex or --key-file-raw is required\n");
+    key_cmd().arg("key").arg("import").arg("backup-2024").arg(&exported).assert().success().stdout("").stderr(format!("Imported backup-2024 (key ID {})\n", old_key_id));
+    key_cmd().arg(&tmp_ciphertext).assert().success().stdout(PLAINTEXT).stderr("");
+
+    Ok(())
+}
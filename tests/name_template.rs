@@ -0,0 +1,23 @@
+use doby::NameTemplate;
+
+#[test]
+fn name_template() {
+    let template = NameTemplate::parse("{name}.{ext}.doby").unwrap();
+    assert_eq!(template.apply("report.pdf"), "report.pdf.doby");
+    assert_eq!(template.apply("README"), "README.doby");
+    assert_eq!(template.apply(".bashrc"), ".bashrc.doby");
+    assert_eq!(template.strip("report.pdf.doby").unwrap(), "report.pdf");
+    assert_eq!(template.strip("report.pdf"), None);
+
+    let template = NameTemplate::parse("encrypted-{name}.bin").unwrap();
+    assert_eq!(template.apply("archive.tar.gz"), "encrypted-archive.tar.bin");
+    assert_eq!(template.strip("encrypted-archive.tar.bin").unwrap(), "archive.tar");
+
+    assert_eq!(NameTemplate::with_suffix(".enc").unwrap().apply("notes.txt"), "notes.txt.enc");
+    assert_eq!(NameTemplate::default().output_path("dir/notes.txt", Some("out"), false).unwrap(), "out/notes.txt.doby");
+    assert_eq!(NameTemplate::default().output_path("dir/notes.txt.doby", None, true).unwrap(), "dir/notes.txt");
+
+    assert!(NameTemplate::parse("no placeholder").is_err());
+    assert!(NameTemplate::parse("{unknown}.doby").is_err());
+    assert!(NameTemplate::parse("dir/{file}").is_err());
+}
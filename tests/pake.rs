@@ -0,0 +1,34 @@
+use doby::{Error, pake::{Pake, generate_code}};
+
+#[test]
+fn key_exchange() {
+    let code = generate_code();
+    let parts: Vec<&str> = code.split('-').collect();
+    assert_eq!(parts.len(), 3);
+    assert!(parts[0].parse::<u8>().unwrap() < 100);
+
+    let initiator = Pake::new(&code, true);
+    let responder = Pake::new(&code, false);
+    let (initiator_message, responder_message) = (initiator.message(), responder.message());
+    let initiator_keys = initiator.finish(&responder_message).unwrap();
+    let responder_keys = responder.finish(&initiator_message).unwrap();
+    assert_eq!(initiator_keys.key(), responder_keys.key());
+    assert_ne!(initiator_keys.confirmation(), responder_keys.confirmation());
+    initiator_keys.check_confirmation(&responder_keys.confirmation()).unwrap();
+    responder_keys.check_confirmation(&initiator_keys.confirmation()).unwrap();
+    //a confirmation sent back can't be accepted
+    assert!(matches!(initiator_keys.check_confirmation(&initiator_keys.confirmation()), Err(Error::PakeFailed)));
+
+    let initiator = Pake::new(&code, true);
+    let responder = Pake::new("12-wrong-code", false);
+    let (initiator_message, responder_message) = (initiator.message(), responder.message());
+    let initiator_keys = initiator.finish(&responder_message).unwrap();
+    let responder_keys = responder.finish(&initiator_message).unwrap();
+    assert_ne!(initiator_keys.key(), responder_keys.key());
+    assert!(matches!(initiator_keys.check_confirmation(&responder_keys.confirmation()), Err(Error::PakeFailed)));
+    assert!(matches!(responder_keys.check_confirmation(&initiator_keys.confirmation()), Err(Error::PakeFailed)));
+
+    //the identity, and bytes that aren't a point
+    assert!(matches!(Pake::new(&code, true).finish(&[0; 32]), Err(Error::PakeFailed)));
+    assert!(matches!(Pake::new(&code, true).finish(&[0xff; 32]), Err(Error::PakeFailed)));
+}
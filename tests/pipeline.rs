@@ -0,0 +1,73 @@
+use doby::{
+    crypto::{CipherAlgorithm, EncryptionParams, DobyCipher},
+    Error,
+    decrypt,
+    decrypt_pipelined,
+    encrypt,
+    encrypt_slice,
+    encrypt_pipelined,
+    read_header,
+};
+
+const PASSWORD: &str = "the password";
+
+#[test]
+fn pipelined_round_trip() {
+    let plaintext: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    for cipher in [CipherAlgorithm::AesCtr, CipherAlgorithm::XChaCha20Poly1305] {
+        let params = EncryptionParams::new(
+            argon2::Params::new(8, 1, 1, None).unwrap(),
+            cipher
+        );
+        let mut ciphertext = Vec::new();
+        encrypt_pipelined(&mut &plaintext[4..], &mut ciphertext, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 4096, Some(&plaintext[..4])).unwrap();
+
+        //same output as the serial implementation
+        let mut serial_ciphertext = Vec::new();
+        encrypt(&mut plaintext.as_slice(), &mut serial_ciphertext, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 65536, None).unwrap();
+        assert_eq!(ciphertext, serial_ciphertext);
+
+        let mut reader = ciphertext.as_slice();
+        let params = read_header(&mut reader).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt_pipelined(&mut reader, &mut decrypted, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 1000).unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let mut reader = &ciphertext[..ciphertext.len()-1];
+        read_header(&mut reader).unwrap();
+        let result = decrypt_pipelined(&mut reader, &mut Vec::new(), DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 1000);
+        assert!(matches!(result, Err(Error::HmacMismatch)));
+
+        let mut reader = ciphertext.as_slice();
+        read_header(&mut reader).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt(&mut reader, &mut decrypted, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 65536).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+}
+
+#[test]
+fn slice() {
+    let plaintext: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    for cipher in [CipherAlgorithm::AesCtr, CipherAlgorithm::XChaCha20Poly1305] {
+        let params = EncryptionParams::new(
+            argon2::Params::new(8, 1, 1, None).unwrap(),
+            cipher
+        );
+        let mut ciphertext = Vec::new();
+        encrypt_slice(&plaintext[4..], &mut ciphertext, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 4096, Some(&plaintext[..4])).unwrap();
+
+        let mut serial_ciphertext = Vec::new();
+        encrypt(&mut plaintext.as_slice(), &mut serial_ciphertext, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 65536, None).unwrap();
+        assert_eq!(ciphertext, serial_ciphertext);
+
+        //empty plaintexts still get a MAC
+        let mut ciphertext = Vec::new();
+        encrypt_slice(&[], &mut ciphertext, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 4096, None).unwrap();
+        let mut reader = ciphertext.as_slice();
+        read_header(&mut reader).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt(&mut reader, &mut decrypted, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 4096).unwrap();
+        assert!(decrypted.is_empty());
+    }
+}
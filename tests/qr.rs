@@ -0,0 +1,236 @@
+use std::io::Write;
+use doby::{ArmorWriter, qr::{QrCode, QrFormat, QrWriter, is_qr_text, reassemble, split}};
+
+//format information strings of the medium error correction level for masks 0 to 7, from the standard
+const FORMAT_M: [u16; 8] = [0b101010000010010, 0b101000100100101, 0b101111001111100, 0b101101101001011, 0b100010111111001, 0b100000011001110, 0b100111110010111, 0b100101010100000];
+//(version, alignment pattern centers, number of error correction blocks, error correction codewords per block) at the medium level
+const VERSIONS: [(usize, &[usize], usize, usize); 6] = [
+    (1, &[], 1, 10),
+    (2, &[6, 18], 1, 16),
+    (7, &[6, 22, 38], 4, 18),
+    (10, &[6, 28, 50], 5, 26),
+    (14, &[6, 26, 46, 66], 9, 24),
+    (25, &[6, 32, 58, 84, 110], 21, 28),
+];
+
+struct Gf {
+    exp: [u8; 512],
+    log: [usize; 256],
+}
+
+impl Gf {
+    fn new() -> Self {
+        let mut gf = Self { exp: [0; 512], log: [0; 256] };
+        let mut x = 1u16;
+        for i in 0..255 {
+            gf.exp[i] = x as u8;
+            gf.exp[i + 255] = x as u8;
+            gf.log[x as usize] = i;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11D;
+            }
+        }
+        gf
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 { 0 } else { self.exp[self.log[a as usize] + self.log[b as usize]] }
+    }
+}
+
+//reads a code the way a scanner would, checking its structure along the way, and returns its content
+fn decode(qr: &QrCode) -> Vec<u8> {
+    let size = qr.size();
+    let version = (size - 17) / 4;
+    assert_eq!(qr.version(), version);
+    let &(_, alignments, num_blocks, ecc_len) = VERSIONS.iter().find(|v| v.0 == version).expect("version not covered by the test decoder");
+    //(row, column) as in the standard
+    let dark = |i: usize, j: usize| qr.module(j, i);
+
+    //both copies of the format information
+    let first: Vec<(usize, usize)> = (0..=5).map(|j| (8, j)).chain([(8, 7), (8, 8), (7, 8)]).chain((0..=5).rev().map(|i| (i, 8))).collect();
+    let second: Vec<(usize, usize)> = (0..7).map(|k| (size - 1 - k, 8)).chain((0..8).map(|k| (8, size - 8 + k))).collect();
+    let read = |positions: &[(usize, usize)]| positions.iter().fold(0u16, |bits, &(i, j)| bits << 1 | dark(i, j) as u16);
+    assert_eq!(read(&first), read(&second));
+    let mask = FORMAT_M.iter().position(|format| *format == read(&first)).expect("not a format string of the M level");
+    assert!(dark(size - 8, 8));
+
+    if version == 7 {
+        let bits: u32 = (0..18).rev().fold(0, |bits, k| bits << 1 | qr.module(k / 3, size - 11 + k % 3) as u32);
+        assert_eq!(bits, 0x07C94);
+    }
+
+    let mut function = vec![vec![false; size]; size];
+    let mut reserve = |i0: usize, j0: usize, h: usize, w: usize| {
+        for row in function.iter_mut().skip(i0).take(h) {
+            row[j0..j0 + w].iter_mut().for_each(|f| *f = true);
+        }
+    };
+    reserve(0, 0, 9, 9);
+    reserve(0, size - 8, 9, 8);
+    reserve(size - 8, 0, 8, 9);
+    reserve(6, 0, 1, size);
+    reserve(0, 6, size, 1);
+    if version >= 7 {
+        reserve(0, size - 11, 6, 3);
+        reserve(size - 11, 0, 3, 6);
+    }
+    for &i in alignments {
+        for &j in alignments {
+            let overlaps_finder = (i == 6 && (j == 6 || j == size - 7)) || (i == size - 7 && j == 6);
+            if !overlaps_finder {
+                reserve(i - 2, j - 2, 5, 5);
+            }
+        }
+    }
+    //finder patterns and timing patterns
+    for k in 0..7 {
+        assert!(dark(0, k) && dark(6, k) && dark(k, 0) && dark(k, 6));
+    }
+    for k in 8..size - 8 {
+        assert_eq!(dark(6, k), k % 2 == 0);
+        assert_eq!(dark(k, 6), k % 2 == 0);
+    }
+
+    let masked = |i: usize, j: usize| match mask {
+        0 => (i + j).is_multiple_of(2),
+        1 => i.is_multiple_of(2),
+        2 => j.is_multiple_of(3),
+        3 => (i + j).is_multiple_of(3),
+        4 => (i / 2 + j / 3).is_multiple_of(2),
+        5 => i * j % 2 + i * j % 3 == 0,
+        6 => (i * j % 2 + i * j % 3).is_multiple_of(2),
+        _ => ((i + j) % 2 + i * j % 3).is_multiple_of(2),
+    };
+    let mut bits = Vec::new();
+    let mut j = size - 1;
+    let mut upward = true;
+    loop {
+        for step in 0..size {
+            let i = if upward { size - 1 - step } else { step };
+            for jj in [j, j - 1] {
+                if !function[i][jj] {
+                    bits.push(dark(i, jj) ^ masked(i, jj));
+                }
+            }
+        }
+        upward = !upward;
+        if j < 3 {
+            break;
+        }
+        j -= 2;
+        if j == 6 {
+            j = 5;
+        }
+    }
+    let codewords: Vec<u8> = bits.chunks_exact(8).map(|byte| byte.iter().fold(0, |b, bit| b << 1 | *bit as u8)).collect();
+
+    let total = codewords.len();
+    let short_blocks = num_blocks - total % num_blocks;
+    let data_lens: Vec<usize> = (0..num_blocks).map(|b| total / num_blocks - ecc_len + usize::from(b >= short_blocks)).collect();
+    let mut blocks = vec![Vec::new(); num_blocks];
+    let mut codewords = codewords.into_iter();
+    for k in 0..data_lens[num_blocks - 1] {
+        for (block, len) in blocks.iter_mut().zip(&data_lens) {
+            if k < *len {
+                block.push(codewords.next().unwrap());
+            }
+        }
+    }
+    let data: Vec<u8> = blocks.iter().flatten().copied().collect();
+    for _ in 0..ecc_len {
+        for block in blocks.iter_mut() {
+            block.push(codewords.next().unwrap());
+        }
+    }
+    let gf = Gf::new();
+    for block in &blocks {
+        for k in 0..ecc_len {
+            let syndrome = block.iter().fold(0, |s, c| gf.mul(s, gf.exp[k]) ^ c);
+            assert_eq!(syndrome, 0);
+        }
+    }
+
+    let bit = |n: usize| data[n / 8] >> (7 - n % 8) & 1;
+    let field = |start: usize, len: usize| (start..start + len).fold(0usize, |v, n| v << 1 | bit(n) as usize);
+    assert_eq!(field(0, 4), 0b0100);
+    let count_len = if version <= 9 { 8 } else { 16 };
+    let len = field(4, count_len);
+    (0..len).map(|k| field(4 + count_len + 8 * k, 8) as u8).collect()
+}
+
+#[test]
+fn round_trip() {
+    for (len, version) in [(0, 1), (14, 1), (15, 2), (26, 2), (107, 7), (122, 7), (200, 10), (213, 10), (362, 14), (997, 25)] {
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 + 3) as u8).collect();
+        let qr = QrCode::encode(&data).unwrap();
+        assert_eq!(qr.version(), version);
+        assert_eq!(decode(&qr), data);
+    }
+}
+
+#[test]
+fn capacity() {
+    //byte mode capacities of the medium level, from the standard
+    for (version, capacity) in [(1, 14), (2, 26), (3, 42), (4, 62), (5, 84), (6, 106), (7, 122), (8, 152), (9, 180), (10, 213), (40, 2331)] {
+        assert_eq!(QrCode::encode(&vec![0; capacity]).unwrap().version(), version);
+        if version < 40 {
+            assert_eq!(QrCode::encode(&vec![0; capacity + 1]).unwrap().version(), version + 1);
+        }
+    }
+    assert!(QrCode::encode(&[0; 2332]).is_err());
+}
+
+fn armor(data: &[u8]) -> Vec<u8> {
+    let mut writer = ArmorWriter::new(Vec::new());
+    writer.write_all(data).unwrap();
+    writer.finish().unwrap()
+}
+
+#[test]
+fn parts() {
+    let armored = armor(&[42; 2000]);
+    let parts = split(&armored);
+    assert_eq!(parts.len(), 3);
+    for (i, part) in parts.iter().enumerate() {
+        assert!(part.starts_with(format!("DOBY-QR {}/3\n", i + 1).as_bytes()));
+        assert!(is_qr_text(part));
+        assert_eq!(decode(&QrCode::encode(part).unwrap()), *part);
+    }
+    assert_eq!(reassemble(&parts.concat()).unwrap(), armored);
+
+    //zbarimg output: in any order, prefixed, without the final line breaks
+    let scanned: String = parts.iter().rev().map(|part| format!("QR-Code:{}\n", String::from_utf8_lossy(part).trim_end())).collect();
+    assert!(is_qr_text(scanned.as_bytes()));
+    assert_eq!(reassemble(scanned.as_bytes()).unwrap(), armored);
+    assert_eq!(reassemble(&[&parts[0][..], &parts[0][..], &parts[1][..], &parts[2][..]].concat()).unwrap(), armored);
+
+    assert_eq!(reassemble(&[&parts[0][..], &parts[2][..]].concat()).unwrap_err().to_string(), "QR code: missing part 2 of 3");
+    assert!(reassemble(&[&parts[0][..], &split(&armor(b"other"))[0][..]].concat()).is_err());
+    assert!(!is_qr_text(&armored));
+}
+
+#[test]
+fn render() {
+    let armored = armor(&[0; 1000]);
+
+    let mut writer = QrWriter::new(Vec::new(), QrFormat::Text);
+    writer.write_all(&armored).unwrap();
+    let text = String::from_utf8(writer.finish().unwrap()).unwrap();
+    assert!(text.contains("DOBY-QR 1/2\n") && text.contains("DOBY-QR 2/2\n"));
+
+    let mut writer = QrWriter::new(Vec::new(), QrFormat::Png);
+    writer.write_all(&armored).unwrap();
+    let png = writer.finish().unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"));
+    let width = u32::from_be_bytes(png[16..20].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(png[20..24].try_into().unwrap()) as usize;
+    let sizes: Vec<usize> = split(&armored).iter().map(|part| QrCode::encode(part).unwrap().size() + 8).collect();
+    assert_eq!(width, sizes.iter().max().unwrap() * 8);
+    assert_eq!(height, sizes.iter().sum::<usize>() * 8);
+    assert!(png.ends_with(b"\0\0\0\0IEND\xae\x42\x60\x82"));
+
+    let mut writer = QrWriter::new(Vec::new(), QrFormat::Text);
+    assert!(writer.write_all(&vec![b'A'; 65 << 10]).is_err());
+}
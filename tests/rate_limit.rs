@@ -0,0 +1,31 @@
+use std::{io::{self, Read}, sync::Arc, thread, time::{Duration, Instant}};
+use doby::{RateLimitedReader, RateLimiter};
+
+#[test]
+fn rate_limit() {
+    let data = vec![0x42; 30_000];
+    let start = Instant::now();
+    let mut output = Vec::new();
+    RateLimitedReader::new(data.as_slice(), 100_000).read_to_end(&mut output).unwrap();
+    assert_eq!(output, data);
+    assert!(start.elapsed() >= Duration::from_millis(250));
+
+    //reads are split to stay smooth
+    let mut reader = RateLimitedReader::new(data.as_slice(), 100_000);
+    let mut buff = vec![0; data.len()];
+    assert_eq!(reader.read(&mut buff).unwrap(), 10_000);
+}
+
+#[test]
+fn shared_limiter() {
+    let limiter = Arc::new(RateLimiter::new(100_000));
+    let start = Instant::now();
+    let threads: Vec<_> = (0..3).map(|_| {
+        let limiter = limiter.clone();
+        thread::spawn(move || io::copy(&mut RateLimitedReader::with_limiter(&[0; 10_000][..], limiter), &mut io::sink()).unwrap())
+    }).collect();
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), 10_000);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(250));
+}
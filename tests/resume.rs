@@ -0,0 +1,73 @@
+#![cfg(feature = "os")]
+
+use std::{fs, io::{self, Write}, time::Duration};
+use tempfile::TempDir;
+use doby::{ResumeState, ResumeWriter, WrappedWriter, crypto::{AEAD_CHUNK_SIZE, AEAD_TAG_LEN}, temporary_path};
+
+const ENCRYPTED_CHUNK_LEN: usize = AEAD_CHUNK_SIZE + AEAD_TAG_LEN;
+
+#[test]
+fn state_round_trip() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let input = dir.path().join("input");
+    fs::write(&input, b"the plaintext")?;
+    let path = ResumeState::path(dir.path().join("output"));
+    assert_eq!(path, dir.path().join("output.resume"));
+    assert_eq!(ResumeState::load(&path)?, None);
+
+    let mut state = ResumeState::new(&fs::metadata(&input)?, 90, 24, "aB3dE5gH");
+    state.chunks = 3;
+    state.save(&path)?;
+    assert_eq!(ResumeState::load(&path)?, Some(state.clone()));
+    assert!(state.matches(&fs::metadata(&input)?));
+    assert_eq!(state.plaintext_offset(), 3 * AEAD_CHUNK_SIZE as u64);
+    assert_eq!(state.output_len(), 90 + 3 * ENCRYPTED_CHUNK_LEN as u64);
+
+    fs::write(&input, b"another plaintext")?;
+    assert!(!state.matches(&fs::metadata(&input)?));
+
+    fs::write(&path, b"doby-resume 2\nchunks 3\n")?;
+    assert_eq!(ResumeState::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    //the partial output must be next to the output
+    fs::write(&path, b"doby-resume 2\ninput_len 13\ninput_modified 0\nheader_len 90\nprefix_len 24\nchunks 3\npartial ../../etc\n")?;
+    assert_eq!(ResumeState::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    ResumeState::remove(&path)?;
+    ResumeState::remove(&path)?;
+    assert!(!path.exists());
+    Ok(())
+}
+
+#[test]
+fn checkpoints() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let input = dir.path().join("input");
+    fs::write(&input, b"the plaintext")?;
+    let output = dir.path().join("output").display().to_string();
+    let path = ResumeState::path(&output);
+    let mut writer = WrappedWriter::from_path(output.clone()).into_buf_writer().unwrap();
+    writer.keep_on_failure();
+    let partial = writer.temporary_suffix().unwrap().to_string();
+    let state = ResumeState::new(&fs::metadata(&input)?, 10, 0, &partial);
+    let mut resume_writer = ResumeWriter::new(&mut writer, state, &path, Duration::ZERO);
+    resume_writer.write_all(&[0; 10])?;
+    assert_eq!(ResumeState::load(&path)?, None);
+    //the third chunk might be the last one until something follows it
+    resume_writer.write_all(&[1; 3 * ENCRYPTED_CHUNK_LEN])?;
+    assert_eq!(ResumeState::load(&path)?.unwrap().chunks, 2);
+    resume_writer.write_all(&[2; 100])?;
+    drop(resume_writer);
+    let state = ResumeState::load(&path)?.unwrap();
+    assert_eq!(state.chunks, 3);
+    //interrupted
+    drop(writer);
+    assert_eq!(fs::metadata(temporary_path(&output, &partial))?.len(), 10 + 3 * ENCRYPTED_CHUNK_LEN as u64 + 100);
+
+    let mut writer = WrappedWriter::from_path(output.clone()).into_resumed_buf_writer(AEAD_CHUNK_SIZE, &state.partial, state.output_len()).unwrap();
+    assert_eq!(writer.written(), state.output_len());
+    writer.write_all(b"end")?;
+    writer.finish(false).unwrap();
+    let content = fs::read(&output)?;
+    assert_eq!(content.len() as u64, state.output_len() + 3);
+    assert!(content.ends_with(&[1, 1, b'e', b'n', b'd']));
+    Ok(())
+}
@@ -0,0 +1,31 @@
+#![cfg(all(feature = "os", unix))]
+
+use std::io;
+use doby::serve::{MAX_FRAME_LEN, Operation, read_frame, read_request, read_response, write_frame, write_request, write_response};
+
+#[test]
+fn protocol() -> io::Result<()> {
+    let data = vec![7; MAX_FRAME_LEN + 10];
+    let mut request = Vec::new();
+    write_request(&mut request, Operation::Decrypt, &data)?;
+    //operation, two data frames and the empty one
+    assert_eq!(request.len(), 4 * 4 + 1 + data.len());
+    assert_eq!(read_request(&mut request.as_slice())?, (Operation::Decrypt, data.clone()));
+
+    let mut response = Vec::new();
+    write_response(&mut response, Ok(&data))?;
+    write_response(&mut response, Err("wrong password"))?;
+    let mut reader = response.as_slice();
+    assert_eq!(read_response(&mut reader)?, Ok(data));
+    assert_eq!(read_response(&mut reader)?, Err(String::from("wrong password")));
+    assert!(reader.is_empty());
+
+    let mut frame = Vec::new();
+    write_frame(&mut frame, b"X")?;
+    write_frame(&mut frame, &[])?;
+    assert_eq!(read_request(&mut frame.as_slice()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert!(write_frame(&mut Vec::new(), &vec![0; MAX_FRAME_LEN + 1]).is_err());
+    let too_long = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+    assert_eq!(read_frame(&mut &too_long[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    Ok(())
+}
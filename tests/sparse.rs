@@ -0,0 +1,70 @@
+#![cfg(feature = "os")]
+
+use std::{fs::{self, File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}};
+use tempfile::TempDir;
+use doby::{SparseReader, SparseWriter, WrappedWriter, has_holes};
+
+const LEN: u64 = 4 << 20;
+
+//data in the middle, ending with a hole
+fn sparse_file(dir: &TempDir) -> io::Result<(String, Vec<u8>)> {
+    let path = dir.path().join("sparse").display().to_string();
+    let mut file = File::create(&path)?;
+    file.set_len(LEN)?;
+    file.seek(SeekFrom::Start(LEN / 4))?;
+    file.write_all(b"some data")?;
+    let mut content = vec![0; LEN as usize];
+    content[LEN as usize / 4..][..9].copy_from_slice(b"some data");
+    Ok((path, content))
+}
+
+#[test]
+fn sparse_round_trip() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let (input, content) = sparse_file(&dir)?;
+    let input = File::open(input)?;
+    //not all filesystems support holes
+    let holes = has_holes(&input)?;
+
+    let mut encoded = Vec::new();
+    let mut reader = SparseReader::new(input)?;
+    let encoded_len = reader.encoded_len()?;
+    reader.read_to_end(&mut encoded)?;
+    assert_eq!(encoded.len() as u64, encoded_len);
+    if holes {
+        assert!(encoded_len < LEN);
+    }
+
+    let output = dir.path().join("output").display().to_string();
+    let mut writer = WrappedWriter::from_path(output.clone()).into_buf_writer().unwrap();
+    let mut sparse_writer = SparseWriter::new(&mut writer);
+    sparse_writer.write_all(&encoded)?;
+    sparse_writer.finish()?;
+    assert_eq!(writer.written(), LEN);
+    writer.finish(false).unwrap();
+    assert_eq!(fs::read(&output)?, content);
+    assert_eq!(has_holes(&File::open(&output)?)?, holes);
+
+    //zeros are written to other outputs
+    let mut writer = WrappedWriter::<String>::from_writer(OpenOptions::new().append(true).create(true).open(&output)?).into_buf_writer().unwrap();
+    SparseWriter::new(&mut writer).write_all(&encoded)?;
+    writer.finish(false).unwrap();
+    assert_eq!(fs::metadata(&output)?.len(), 2 * LEN);
+
+    Ok(())
+}
+
+#[test]
+fn truncated_sparse_stream() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    let (input, _) = sparse_file(&dir)?;
+    let mut encoded = Vec::new();
+    SparseReader::new(File::open(input)?)?.read_to_end(&mut encoded)?;
+
+    let mut writer = WrappedWriter::<String>::from_writer(io::sink()).into_buf_writer().unwrap();
+    let mut sparse_writer = SparseWriter::new(&mut writer);
+    sparse_writer.write_all(&encoded[..encoded.len()-1])?;
+    assert_eq!(sparse_writer.finish().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    Ok(())
+}
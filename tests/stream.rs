@@ -0,0 +1,217 @@
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use doby::{
+    crypto::{
+        AEAD_CHUNK_SIZE,
+        AEAD_TAG_LEN,
+        CipherAlgorithm,
+        EncryptionParams,
+        DobyCipher,
+    },
+    EncryptWriter,
+    DecryptReader,
+    Metadata,
+    decrypt,
+    decrypt_range,
+    decrypt_verify_first,
+    encrypt,
+};
+
+const PASSWORD: &str = "the password";
+
+fn encrypt_with_writer(plaintext: &[u8], cipher: CipherAlgorithm) -> Vec<u8> {
+    let params = EncryptionParams::new(
+        argon2::Params::new(8, 1, 1, None).unwrap(),
+        cipher
+    );
+    let cipher = DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap();
+    let mut writer = EncryptWriter::new(Vec::new(), params, cipher);
+    for chunk in plaintext.chunks(1000) {
+        writer.write_all(chunk).unwrap();
+    }
+    writer.finish().unwrap()
+}
+
+#[test]
+fn round_trip() {
+    let plaintext: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+    for cipher in [CipherAlgorithm::AesCtr, CipherAlgorithm::XChaCha20Poly1305] {
+        let ciphertext = encrypt_with_writer(&plaintext, cipher);
+
+        //compatible with the non-streaming API
+        let mut reader = ciphertext.as_slice();
+        let params = doby::read_header(&mut reader).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt(&mut reader, &mut decrypted, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 4096).unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let mut decrypter = DecryptReader::new(ciphertext.as_slice(), PASSWORD.as_bytes()).unwrap();
+        let mut decrypted = Vec::new();
+        io::copy(&mut decrypter, &mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+}
+
+#[test]
+fn empty_plaintext() {
+    let ciphertext = encrypt_with_writer(b"", CipherAlgorithm::XChaCha20);
+    let mut decrypted = Vec::new();
+    DecryptReader::new(ciphertext.as_slice(), PASSWORD.as_bytes()).unwrap().read_to_end(&mut decrypted).unwrap();
+    assert!(decrypted.is_empty());
+}
+
+#[test]
+fn tampered() {
+    let mut ciphertext = encrypt_with_writer(b"the plaintext", CipherAlgorithm::XChaCha20);
+    let last = ciphertext.len()-1;
+    ciphertext[last] ^= 1;
+    let mut decrypted = Vec::new();
+    let error = DecryptReader::new(ciphertext.as_slice(), PASSWORD.as_bytes()).unwrap().read_to_end(&mut decrypted).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn not_doby_format() {
+    assert!(matches!(DecryptReader::new(&b"not a doby file"[..], PASSWORD.as_bytes()), Err(doby::Error::UnknownFormat)));
+}
+
+#[test]
+fn seek() {
+    let plaintext: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    for cipher in [CipherAlgorithm::XChaCha20Poly1305, CipherAlgorithm::AesGcm] {
+        let ciphertext = encrypt_with_writer(&plaintext, cipher);
+        let mut decrypter = DecryptReader::new(Cursor::new(ciphertext.as_slice()), PASSWORD.as_bytes()).unwrap();
+        let mut buff = [0; 1000];
+        decrypter.read_exact(&mut buff).unwrap();
+        for (pos, expected) in [
+            (SeekFrom::Start(150_000), 150_000),
+            (SeekFrom::Current(-149_990), 1010),
+            (SeekFrom::Start(AEAD_CHUNK_SIZE as u64 - 500), AEAD_CHUNK_SIZE - 500),
+            (SeekFrom::End(-1000), plaintext.len() - 1000),
+        ] {
+            assert_eq!(decrypter.seek(pos).unwrap(), expected as u64);
+            decrypter.read_exact(&mut buff).unwrap();
+            assert_eq!(buff, plaintext[expected..expected+1000]);
+        }
+        assert_eq!(decrypter.read(&mut buff).unwrap(), 0);
+        assert_eq!(decrypter.seek(SeekFrom::Start(300_000)).unwrap(), 300_000);
+        assert_eq!(decrypter.read(&mut buff).unwrap(), 0);
+        assert_eq!(decrypter.seek(SeekFrom::Current(-300_001)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let mut decrypted = Vec::new();
+        decrypter.seek(SeekFrom::Start(123_456)).unwrap();
+        decrypter.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext[123_456..]);
+
+        //only the chunks that are read are authenticated
+        let mut tampered = ciphertext.clone();
+        let header_len = ciphertext.len() - plaintext.len() - 4*AEAD_TAG_LEN;
+        tampered[header_len + 10] ^= 1;
+        let mut decrypter = DecryptReader::new(Cursor::new(tampered.as_slice()), PASSWORD.as_bytes()).unwrap();
+        decrypter.seek(SeekFrom::Start(AEAD_CHUNK_SIZE as u64)).unwrap();
+        decrypted.clear();
+        decrypter.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, plaintext[AEAD_CHUNK_SIZE..]);
+        assert_eq!(decrypter.seek(SeekFrom::Start(10)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    let ciphertext = encrypt_with_writer(&plaintext, CipherAlgorithm::XChaCha20);
+    let mut decrypter = DecryptReader::new(Cursor::new(ciphertext.as_slice()), PASSWORD.as_bytes()).unwrap();
+    assert_eq!(decrypter.seek(SeekFrom::Start(10)).unwrap_err().kind(), io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn range() {
+    let plaintext: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    for cipher in [CipherAlgorithm::AesCtr, CipherAlgorithm::XChaCha20, CipherAlgorithm::XChaCha20Poly1305, CipherAlgorithm::AesGcm] {
+        let ciphertext = encrypt_with_writer(&plaintext, cipher);
+        let decrypt_range = |ciphertext: &[u8], offset, len| {
+            let mut reader = Cursor::new(ciphertext);
+            let params = doby::read_header(&mut reader).unwrap();
+            let mut range = Vec::new();
+            decrypt_range(&mut reader, &mut range, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), offset, len, 4096).map(|n| {
+                assert_eq!(n, range.len() as u64);
+                range
+            })
+        };
+        for (offset, len) in [(0, 10), (65_000, 1000), (100_000, 100_000), (199_990, 100), (250_000, 10)] {
+            let start = plaintext.len().min(offset);
+            let end = plaintext.len().min(offset+len);
+            assert_eq!(decrypt_range(&ciphertext, offset as u64, len as u64).unwrap(), plaintext[start..end]);
+        }
+
+        let mut tampered = ciphertext.clone();
+        let last = tampered.len()-1;
+        tampered[last] ^= 1;
+        let result = decrypt_range(&tampered, 0, 10);
+        if cipher.is_aead() {
+            //the last chunk isn't read
+            assert_eq!(result.unwrap(), plaintext[..10]);
+        } else {
+            assert!(matches!(result, Err(doby::Error::HmacMismatch)));
+        }
+        assert!(matches!(decrypt_range(&tampered, 199_990, 10), Err(doby::Error::HmacMismatch)));
+    }
+}
+
+/// Offsets start after the stored metadata, and the signature isn't part of the ciphertext.
+#[test]
+fn range_with_metadata() {
+    let plaintext: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    let metadata = Metadata { name: Some(String::from("notes.txt")), ..Default::default() }.to_bytes();
+    for cipher in [CipherAlgorithm::XChaCha20, CipherAlgorithm::XChaCha20Poly1305] {
+        for signed in [false, cfg!(feature = "signatures")] {
+            let mut params = EncryptionParams::new(argon2::Params::new(8, 1, 1, None).unwrap(), cipher);
+            params.metadata = true;
+            #[cfg(feature = "signatures")]
+            let signing_key = doby::signature::SigningKey::generate();
+            #[cfg(feature = "signatures")]
+            if signed {
+                params.set_signer(&signing_key.verify_key().to_bytes()).unwrap();
+            }
+            let mut ciphertext = Vec::new();
+            encrypt(&mut plaintext.as_slice(), &mut ciphertext, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 4096, Some(&metadata)).unwrap();
+            #[cfg(feature = "signatures")]
+            if signed {
+                let mut writer = doby::signature::SigningWriter::new(Vec::new());
+                writer.write_all(&ciphertext).unwrap();
+                ciphertext = writer.finish(&signing_key).unwrap();
+            }
+            for (offset, len) in [(0, 10), (100_000, 100_000), (199_990, 100), (250_000, 10)] {
+                let mut reader = Cursor::new(ciphertext.as_slice());
+                let params = doby::read_header(&mut reader).unwrap();
+                let mut range = Vec::new();
+                let n = decrypt_range(&mut reader, &mut range, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), offset as u64, len as u64, 4096).unwrap();
+                assert_eq!(n, range.len() as u64);
+                assert_eq!(range, plaintext[plaintext.len().min(offset)..plaintext.len().min(offset+len)]);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "os")]
+#[test]
+fn verify_first() {
+    let plaintext: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    for cipher in [CipherAlgorithm::AesCtr, CipherAlgorithm::XChaCha20Poly1305] {
+        let ciphertext = encrypt_with_writer(&plaintext, cipher);
+        let mut tampered = ciphertext.clone();
+        let last = tampered.len()-1;
+        tampered[last] ^= 1;
+        for (ciphertext, verified) in [(&ciphertext, true), (&tampered, false)] {
+            let mut reader = Cursor::new(ciphertext.as_slice());
+            let params = doby::read_header(&mut reader).unwrap();
+            let mut decrypted = Vec::new();
+            let result = decrypt_verify_first(&mut reader, &mut decrypted, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 4096, None);
+            assert_eq!(result.is_ok(), verified);
+            //nothing is written before the verification succeeds
+            assert_eq!(decrypted, if verified { plaintext.as_slice() } else { &[] });
+
+            let mut reader = ciphertext.as_slice();
+            let params = doby::read_header(&mut reader).unwrap();
+            decrypted.clear();
+            let result = doby::decrypt_verify_first_spooled(&mut reader, &mut decrypted, &params, DobyCipher::new(PASSWORD.as_bytes(), &params).unwrap(), 4096, None);
+            assert_eq!(result.is_ok(), verified);
+            assert_eq!(decrypted, if verified { plaintext.as_slice() } else { &[] });
+        }
+    }
+}
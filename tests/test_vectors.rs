@@ -0,0 +1,29 @@
+use doby::{
+    Error,
+    test_vectors::test_vectors,
+};
+
+#[test]
+fn known_answers() {
+    let vectors = test_vectors();
+    assert_eq!(vectors.len(), 7);
+    for vector in vectors {
+        vector.check().unwrap();
+        assert!(vector.to_json().unwrap().starts_with(&format!("{{\"name\":\"{}\",", vector.name)));
+    }
+}
+
+#[test]
+fn altered_vectors() {
+    for mut vector in test_vectors() {
+        let last = vector.ciphertext.len()-1;
+        vector.ciphertext[last] ^= 1;
+        match vector.check() {
+            Err(Error::SelfTestFailed(name)) => assert_eq!(name, vector.name),
+            _ => panic!("{} passed with an altered ciphertext", vector.name),
+        }
+    }
+    let mut vector = test_vectors().remove(0);
+    vector.plaintext[0] ^= 1;
+    assert!(matches!(vector.check(), Err(Error::SelfTestFailed(_))));
+}
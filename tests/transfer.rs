@@ -0,0 +1,62 @@
+use std::{fs, net::{TcpListener, TcpStream}, thread};
+use doby::{
+    Error,
+    WrappedWriter,
+    cli::{ReceiveArgs, SendArgs},
+    crypto::{CipherAlgorithm, MacAlgorithm},
+    transfer::{exchange_keys, receive, send_on},
+};
+
+/// Runs `exchange_keys` on both ends of a local connection.
+fn exchange(code: &'static str, peer_code: &'static str) -> (Result<[u8; 32], Error>, Result<[u8; 32], Error>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let responder = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        exchange_keys(&mut stream, peer_code, false).map(|keys| *keys.key())
+    });
+    let mut stream = TcpStream::connect(address).unwrap();
+    let initiator = exchange_keys(&mut stream, code, true).map(|keys| *keys.key());
+    drop(stream);
+    (initiator, responder.join().unwrap())
+}
+
+#[test]
+fn key_exchange() {
+    let (initiator, responder) = exchange("7-otter-piano", "7-otter-piano");
+    assert_eq!(initiator.unwrap(), responder.unwrap());
+
+    let (initiator, responder) = exchange("7-otter-piano", "8-otter-piano");
+    assert!(matches!(initiator, Err(Error::PakeFailed)));
+    assert!(matches!(responder, Err(Error::PakeFailed)));
+}
+
+#[test]
+fn send_and_receive() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input");
+    let output = dir.path().join("output");
+    let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+    fs::write(&input, &data).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let sender = thread::spawn(move || send_on(listener, SendArgs {
+        input: Some(input.to_string_lossy().into_owned()),
+        listen: String::new(),
+        code: Some(String::from("42-falcon-river")),
+        cipher: CipherAlgorithm::XChaCha20,
+        mac: MacAlgorithm::Blake2b,
+        block_size: Some(4096),
+    }));
+    receive(ReceiveArgs {
+        address,
+        code: Some("42-falcon-river").into(),
+        output: Some(WrappedWriter::from_path(output.to_string_lossy().into_owned())),
+        interactive: false,
+        force: false,
+        fsync: false,
+    }).unwrap();
+    sender.join().unwrap().unwrap();
+    assert_eq!(fs::read(&output).unwrap(), data);
+}
@@ -0,0 +1,45 @@
+#![cfg(feature = "wasm")]
+//errors are thrown as JavaScript exceptions, which only exist on wasm32: only the success paths can be tested natively
+use std::io::Write;
+use doby::{
+    EncryptWriter,
+    crypto::{CipherAlgorithm, DobyCipher, EncryptionParams, KeySlot, generate_master_key},
+    recipient::Identity,
+    wasm::{decrypt, decrypt_with_identities, encrypt},
+};
+
+const PASSWORD: &str = "the password";
+
+#[test]
+fn password() {
+    let plaintext: Vec<u8> = (0..150_000).map(|i| i as u8).collect();
+    let ciphertext = encrypt(PASSWORD, &plaintext).unwrap();
+    assert!(ciphertext.starts_with(doby::MAGIC_BYTES));
+    assert_eq!(decrypt(PASSWORD, &ciphertext).unwrap(), plaintext);
+    assert_eq!(decrypt(PASSWORD, &encrypt(PASSWORD, &[]).unwrap()).unwrap(), Vec::<u8>::new());
+
+    //several passwords
+    let master_key = generate_master_key();
+    let key_slots = vec![
+        KeySlot::from_password(b"another password", argon2::Params::new(8, 1, 1, None).unwrap(), &master_key).unwrap(),
+        KeySlot::from_password(PASSWORD.as_bytes(), argon2::Params::new(8, 1, 1, None).unwrap(), &master_key).unwrap(),
+    ];
+    let params = EncryptionParams::with_key_slots(key_slots, CipherAlgorithm::AesCtr).unwrap();
+    let cipher = DobyCipher::with_master_key(&master_key, &params).unwrap();
+    let mut writer = EncryptWriter::new(Vec::new(), params, cipher);
+    writer.write_all(&plaintext).unwrap();
+    assert_eq!(decrypt(PASSWORD, &writer.finish().unwrap()).unwrap(), plaintext);
+}
+
+#[test]
+fn identities() {
+    let identity = Identity::generate();
+    let master_key = generate_master_key();
+    let key_slots = vec![Identity::generate().recipient().unwrap().wrap(&master_key).unwrap(), identity.recipient().unwrap().wrap(&master_key).unwrap()];
+    let params = EncryptionParams::with_key_slots(key_slots, CipherAlgorithm::XChaCha20Poly1305).unwrap();
+    let cipher = DobyCipher::with_master_key(&master_key, &params).unwrap();
+    let mut writer = EncryptWriter::new(Vec::new(), params, cipher);
+    writer.write_all(b"for a recipient").unwrap();
+    let ciphertext = writer.finish().unwrap();
+    assert_eq!(decrypt_with_identities(&identity.to_file_content(), &ciphertext).unwrap(), b"for a recipient");
+}
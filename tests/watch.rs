@@ -0,0 +1,30 @@
+#![cfg(feature = "os")]
+
+use std::{fs, io, thread, time::Duration};
+use tempfile::TempDir;
+use doby::DirWatcher;
+
+#[test]
+fn watch_dir() -> io::Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("existing"), b"already there")?;
+    fs::write(dir.path().join(".hidden"), b"ignored")?;
+    fs::create_dir(dir.path().join("subdir"))?;
+
+    let mut watcher = DirWatcher::new(dir.path())?;
+    assert_eq!(watcher.next_files()?, vec![dir.path().join("existing")]);
+    fs::remove_file(dir.path().join("existing"))?;
+
+    let path = dir.path().to_path_buf();
+    let writer = thread::spawn(move || -> io::Result<()> {
+        thread::sleep(Duration::from_millis(100));
+        fs::write(path.join(".partial"), b"written then renamed")?;
+        fs::rename(path.join(".partial"), path.join("dropped"))?;
+        fs::write(path.join("subdir").join("nested"), b"ignored")
+    });
+    assert_eq!(watcher.next_files()?, vec![dir.path().join("dropped")]);
+    writer.join().unwrap()?;
+
+    assert!(DirWatcher::new(dir.path().join("dropped")).is_err());
+    Ok(())
+}